@@ -152,7 +152,10 @@ impl Target {
 
     fn build_both(&self) {
         self.build_official();
-        self.build_fairy();
+        // Chess-only builds leave out Fairy-Stockfish.
+        if env::var_os("CARGO_FEATURE_ALL_VARIANTS").is_some() {
+            self.build_fairy();
+        }
     }
 }
 
@@ -289,10 +292,20 @@ fn hooks() {
 
 fn main() {
     hooks();
+
+    // Without engines, only the protocol types, validation and PGN output
+    // are built, for example for wasm32.
+    if env::var_os("CARGO_FEATURE_ENGINE").is_none() {
+        return;
+    }
+
     stockfish_build();
     compress("Stockfish/src", EVAL_FILE);
     auditable_build::collect_dependency_list();
 
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/fishnet.proto").expect("compile protos");
+
     // Resource compilation may fail when toolchain does not match target,
     // e.g. windows-msvc toolchain with windows-gnu target.
     #[cfg(target_family = "windows")]
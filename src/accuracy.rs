@@ -0,0 +1,375 @@
+use std::{cmp::min, fmt};
+
+use serde::Serialize;
+use shakmaty::Color;
+
+use crate::protocol::{AnalysisPart, Score};
+
+/// Centipawn evaluations are capped at this magnitude, and mates count as
+/// this many centipawns.
+const CP_CEILING: i64 = 1000;
+
+/// Minimum loss of winning chances (from -1 to 1) for each judgement, as
+/// used by lila for all variants.
+const BLUNDER: f64 = 0.3;
+const MISTAKE: f64 = 0.2;
+const INACCURACY: f64 = 0.1;
+
+/// Minimum winning chances (from -1 to 1) of the solver of a puzzle after
+/// each move of the solution.
+const PUZZLE_WINNING: f64 = 0.6;
+
+/// Minimum gain of winning chances for the solver, caused by the move before
+/// the puzzle. Matches the blunder threshold of lila.
+const PUZZLE_SWING: f64 = BLUNDER;
+
+/// Minimum difference of winning chances between the best and the second
+/// best move, for the best move to count as unique.
+const PUZZLE_UNIQUE_MARGIN: f64 = 0.5;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Judgement {
+    Inaccuracy,
+    Mistake,
+    Blunder,
+}
+
+/// Metrics of the move leading from one analysed position to the next.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveMetrics {
+    /// Ply of the position after the move.
+    pub ply: usize,
+    #[serde(skip)]
+    pub color: Color,
+    pub cp_loss: u32,
+    pub accuracy: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub judgement: Option<Judgement>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerMetrics {
+    /// Average centipawn loss.
+    pub acpl: Option<u32>,
+    /// Game accuracy in percent, computed like lila.
+    pub accuracy: Option<f64>,
+    pub inaccuracies: usize,
+    pub mistakes: usize,
+    pub blunders: usize,
+}
+
+impl fmt::Display for PlayerMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.accuracy, self.acpl) {
+            (Some(accuracy), Some(acpl)) => write!(
+                f,
+                "{:.1}% accuracy, {} acpl, {} inaccuracies, {} mistakes, {} blunders",
+                accuracy, acpl, self.inaccuracies, self.mistakes, self.blunders
+            ),
+            _ => f.write_str("no analysed moves"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GameMetrics {
+    pub moves: Vec<MoveMetrics>,
+    pub white: PlayerMetrics,
+    pub black: PlayerMetrics,
+}
+
+impl GameMetrics {
+    /// Computes metrics from the evaluations of consecutive positions of a
+    /// game, each from the point of view of the side to move, as reported
+    /// by the engines. Moves from or to positions without evaluation are
+    /// not judged.
+    pub fn compute(root_turn: Color, evals: &[Option<Score>]) -> GameMetrics {
+        let white_cps: Vec<Option<i64>> = plies(root_turn, evals)
+            .map(|(turn, eval)| eval.map(|eval| sign(turn) * capped_cp(eval)))
+            .collect();
+        let win_percents: Vec<Option<f64>> =
+            white_cps.iter().map(|cp| cp.map(win_percent)).collect();
+
+        let mut moves = Vec::new();
+        for (ply, turn) in plies(root_turn, evals)
+            .map(|(turn, _)| turn)
+            .enumerate()
+            .skip(1)
+        {
+            let color = !turn;
+            let (before, after) = match (white_cps[ply - 1], white_cps[ply]) {
+                (Some(before), Some(after)) => (before, after),
+                _ => continue,
+            };
+            let (win_before, win_after) = match color {
+                Color::White => (win_percent(before), win_percent(after)),
+                Color::Black => (100.0 - win_percent(before), 100.0 - win_percent(after)),
+            };
+            // Loss of winning chances, on a scale from -1 to 1.
+            let delta = (win_before - win_after) / 50.0;
+            moves.push(MoveMetrics {
+                ply,
+                color,
+                cp_loss: (sign(color) * (before - after)).max(0) as u32,
+                accuracy: move_accuracy(win_before, win_after),
+                judgement: if delta >= BLUNDER {
+                    Some(Judgement::Blunder)
+                } else if delta >= MISTAKE {
+                    Some(Judgement::Mistake)
+                } else if delta >= INACCURACY {
+                    Some(Judgement::Inaccuracy)
+                } else {
+                    None
+                },
+            });
+        }
+
+        let weights = volatility_weights(&win_percents);
+        GameMetrics {
+            white: player_metrics(Color::White, &moves, &weights),
+            black: player_metrics(Color::Black, &moves, &weights),
+            moves,
+        }
+    }
+}
+
+/// Adds the judgement of each move to the analysis of the position after
+/// it, so that the server does not have to compute them.
+pub fn annotate(analysis: &mut [Option<AnalysisPart>], metrics: &GameMetrics) {
+    for m in &metrics.moves {
+        if let Some(Some(
+            AnalysisPart::Best { judgement, .. } | AnalysisPart::Matrix { judgement, .. },
+        )) = analysis.get_mut(m.ply)
+        {
+            *judgement = m.judgement;
+        }
+    }
+}
+
+/// Whether the move before a position lost enough for the position to start
+/// a puzzle, given the evaluations before and after the move, each from the
+/// point of view of the side to move.
+pub fn is_puzzle_swing(before: Score, after: Score) -> bool {
+    let (before, after) = (-winning_chances(before), winning_chances(after));
+    after >= PUZZLE_WINNING && after - before >= PUZZLE_SWING
+}
+
+/// Whether the best move wins, and the second best move (if there is one)
+/// does not come close.
+pub fn is_unique_win(best: Score, second: Option<Score>) -> bool {
+    winning_chances(best) >= PUZZLE_WINNING
+        && second.map_or(true, |second| {
+            winning_chances(best) - winning_chances(second) >= PUZZLE_UNIQUE_MARGIN
+        })
+}
+
+/// Evaluations with the side to move in each position.
+fn plies(
+    root_turn: Color,
+    evals: &[Option<Score>],
+) -> impl Iterator<Item = (Color, Option<Score>)> + '_ {
+    evals.iter().enumerate().map(move |(ply, eval)| {
+        let turn = if ply % 2 == 0 { root_turn } else { !root_turn };
+        (turn, *eval)
+    })
+}
+
+fn sign(color: Color) -> i64 {
+    if color == Color::White {
+        1
+    } else {
+        -1
+    }
+}
+
+/// Centipawns from the point of view of the side to move, with mates and
+/// large advantages capped.
+pub fn capped_cp(score: Score) -> i64 {
+    match score {
+        Score::Cp(cp) => cp.clamp(-CP_CEILING, CP_CEILING),
+        Score::Mate(mate) if mate > 0 => CP_CEILING,
+        Score::Mate(_) => -CP_CEILING,
+    }
+}
+
+/// Winning chances from -1 to 1, from the point of view of the side to
+/// move, like lila.
+pub fn winning_chances(score: Score) -> f64 {
+    (win_percent(capped_cp(score)) - 50.0) / 50.0
+}
+
+fn win_percent(cp: i64) -> f64 {
+    let winning_chances = 2.0 / (1.0 + (-0.00368208 * cp as f64).exp()) - 1.0;
+    50.0 + 50.0 * winning_chances.clamp(-1.0, 1.0)
+}
+
+fn move_accuracy(win_before: f64, win_after: f64) -> f64 {
+    if win_after >= win_before {
+        100.0
+    } else {
+        let raw = 103.1668100711649 * (-0.04354415386753951 * (win_before - win_after)).exp()
+            - 3.166924740191411;
+        // Bonus for the uncertainty of the evaluations.
+        (raw + 1.0).clamp(0.0, 100.0)
+    }
+}
+
+/// Weighs each move by the volatility of the evaluations around it, so that
+/// mistakes in critical positions count more.
+fn volatility_weights(win_percents: &[Option<f64>]) -> Vec<f64> {
+    let window_size = (win_percents.len() / 10).clamp(2, 8);
+    (1..win_percents.len())
+        .map(|ply| {
+            // The first windows are all at the start of the game.
+            let start = (ply - 1).saturating_sub(window_size - 2);
+            let end = min(start + window_size, win_percents.len());
+            let window: Vec<f64> = win_percents[start..end].iter().flatten().copied().collect();
+            standard_deviation(&window).clamp(0.5, 12.0)
+        })
+        .collect()
+}
+
+fn standard_deviation(xs: &[f64]) -> f64 {
+    if xs.is_empty() {
+        return 0.0;
+    }
+    let mean = xs.iter().sum::<f64>() / xs.len() as f64;
+    (xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / xs.len() as f64).sqrt()
+}
+
+fn player_metrics(color: Color, moves: &[MoveMetrics], weights: &[f64]) -> PlayerMetrics {
+    let own: Vec<&MoveMetrics> = moves.iter().filter(|m| m.color == color).collect();
+    if own.is_empty() {
+        return PlayerMetrics::default();
+    }
+    let n = own.len() as f64;
+
+    // Average of the volatility weighted mean and the harmonic mean.
+    let weighted = {
+        let total: f64 = own.iter().map(|m| weights[m.ply - 1]).sum();
+        own.iter()
+            .map(|m| m.accuracy * weights[m.ply - 1])
+            .sum::<f64>()
+            / total
+    };
+    let harmonic = n / own.iter().map(|m| 1.0 / m.accuracy).sum::<f64>();
+
+    PlayerMetrics {
+        acpl: Some((own.iter().map(|m| f64::from(m.cp_loss)).sum::<f64>() / n).round() as u32),
+        accuracy: Some((weighted + harmonic) / 2.0),
+        inaccuracies: own
+            .iter()
+            .filter(|m| m.judgement == Some(Judgement::Inaccuracy))
+            .count(),
+        mistakes: own
+            .iter()
+            .filter(|m| m.judgement == Some(Judgement::Mistake))
+            .count(),
+        blunders: own
+            .iter()
+            .filter(|m| m.judgement == Some(Judgement::Blunder))
+            .count(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capped_cp() {
+        assert_eq!(capped_cp(Score::Cp(35)), 35);
+        assert_eq!(capped_cp(Score::Cp(5000)), CP_CEILING);
+        assert_eq!(capped_cp(Score::Cp(-5000)), -CP_CEILING);
+        assert_eq!(capped_cp(Score::Mate(3)), CP_CEILING);
+        assert_eq!(capped_cp(Score::Mate(-1)), -CP_CEILING);
+    }
+
+    #[test]
+    fn test_win_percent() {
+        assert_eq!(win_percent(0), 50.0);
+        assert!((win_percent(300) + win_percent(-300) - 100.0).abs() < 1e-9);
+        assert!(win_percent(CP_CEILING) > 95.0);
+        assert!(winning_chances(Score::Cp(0)).abs() < 1e-9);
+        assert!(winning_chances(Score::Mate(1)) > 0.9);
+    }
+
+    #[test]
+    fn test_move_accuracy() {
+        assert_eq!(move_accuracy(50.0, 50.0), 100.0);
+        assert_eq!(move_accuracy(40.0, 60.0), 100.0);
+        assert!(move_accuracy(60.0, 50.0) < 70.0);
+        assert!(move_accuracy(60.0, 55.0) > move_accuracy(60.0, 50.0));
+        assert_eq!(move_accuracy(100.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_judgements() {
+        // White plays a quiet move, then Black blunders a piece. Evaluations
+        // are from the point of view of the side to move.
+        let metrics = GameMetrics::compute(
+            Color::White,
+            &[
+                Some(Score::Cp(20)),
+                Some(Score::Cp(-20)),
+                Some(Score::Cp(900)),
+            ],
+        );
+        assert_eq!(metrics.moves.len(), 2);
+
+        let white = &metrics.moves[0];
+        assert_eq!((white.ply, white.color), (1, Color::White));
+        assert_eq!(white.cp_loss, 0);
+        assert_eq!(white.accuracy, 100.0);
+        assert_eq!(white.judgement, None);
+
+        let black = &metrics.moves[1];
+        assert_eq!((black.ply, black.color), (2, Color::Black));
+        assert_eq!(black.cp_loss, 880);
+        assert_eq!(black.judgement, Some(Judgement::Blunder));
+
+        assert_eq!(metrics.white.acpl, Some(0));
+        assert_eq!(metrics.white.blunders, 0);
+        assert_eq!(metrics.black.acpl, Some(880));
+        assert_eq!(metrics.black.blunders, 1);
+        assert!(metrics.black.accuracy.unwrap() < metrics.white.accuracy.unwrap());
+    }
+
+    #[test]
+    fn test_black_to_move_at_root() {
+        let metrics =
+            GameMetrics::compute(Color::Black, &[Some(Score::Cp(0)), Some(Score::Cp(-400))]);
+        assert_eq!(metrics.moves[0].color, Color::Black);
+        assert_eq!(metrics.moves[0].cp_loss, 0);
+        assert_eq!(metrics.black.acpl, Some(0));
+        assert_eq!(metrics.white.acpl, None);
+    }
+
+    #[test]
+    fn test_missing_evals_are_not_judged() {
+        let metrics = GameMetrics::compute(
+            Color::White,
+            &[Some(Score::Cp(0)), None, Some(Score::Cp(0))],
+        );
+        assert!(metrics.moves.is_empty());
+        assert_eq!(metrics.white.accuracy, None);
+        assert_eq!(metrics.white.to_string(), "no analysed moves");
+    }
+
+    #[test]
+    fn test_puzzles() {
+        // After a blunder, the solver is clearly winning.
+        assert!(is_puzzle_swing(Score::Cp(0), Score::Cp(600)));
+        assert!(!is_puzzle_swing(Score::Cp(0), Score::Cp(50)));
+        // Already winning before the move.
+        assert!(!is_puzzle_swing(Score::Cp(-600), Score::Cp(700)));
+
+        assert!(is_unique_win(Score::Mate(2), None));
+        assert!(is_unique_win(Score::Mate(2), Some(Score::Cp(0))));
+        assert!(!is_unique_win(Score::Mate(2), Some(Score::Mate(3))));
+        assert!(!is_unique_win(Score::Cp(50), None));
+    }
+}
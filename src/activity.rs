@@ -0,0 +1,225 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::mpsc, time};
+
+use crate::logger::Logger;
+
+/// Check for activity this often.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Report the machine as idle only after no activity for this long, so
+/// that workers do not start and stop during short breaks.
+const IDLE_AFTER: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UserActivity {
+    Active,
+    Idle,
+}
+
+impl fmt::Display for UserActivity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UserActivity::Active => "in use",
+            UserActivity::Idle => "idle",
+        })
+    }
+}
+
+/// Reports when the machine starts or stops being used by someone else
+/// than fishnet, starting with the current state. Nothing is reported if
+/// activity can not be determined on this platform.
+pub fn spawn_watcher(logger: Logger) -> mpsc::UnboundedReceiver<UserActivity> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut probe = match imp::Probe::new() {
+        Some(probe) => probe,
+        None => {
+            logger.warn("Could not determine activity on this platform. Will not pause when the machine is in use.");
+            return rx;
+        }
+    };
+    tokio::spawn(async move {
+        let mut last = None;
+        let mut last_active: Option<Instant> = None;
+        let mut interval = time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = tx.closed() => break,
+                _ = interval.tick() => (),
+            }
+            match probe.active(POLL_INTERVAL) {
+                Some(true) => last_active = Some(Instant::now()),
+                Some(false) => (),
+                None => logger.debug("Activity temporarily unknown"),
+            }
+            let activity = match last_active {
+                Some(at) if at.elapsed() < IDLE_AFTER => UserActivity::Active,
+                _ => UserActivity::Idle,
+            };
+            if last != Some(activity) {
+                last = Some(activity);
+                if tx.send(activity).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Measures CPU time used by other processes than fishnet and its engines,
+/// from `/proc`.
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{fs, time::Duration};
+
+    /// Someone else is considered active when other processes use more than
+    /// this many cores.
+    const ACTIVE_CORES: f64 = 0.5;
+
+    #[derive(Copy, Clone)]
+    struct Sample {
+        total: u64,
+        busy: u64,
+        own: u64,
+    }
+
+    pub struct Probe {
+        last: Sample,
+        cores: f64,
+    }
+
+    impl Probe {
+        pub fn new() -> Option<Probe> {
+            Some(Probe {
+                last: sample()?,
+                cores: num_cpus::get() as f64,
+            })
+        }
+
+        pub fn active(&mut self, _interval: Duration) -> Option<bool> {
+            let sample = sample()?;
+            let total = sample.total.saturating_sub(self.last.total);
+            let busy = sample.busy.saturating_sub(self.last.busy);
+            // Engines that exited in the meantime are no longer counted.
+            let own = sample.own.saturating_sub(self.last.own);
+            self.last = sample;
+            if total == 0 {
+                return None;
+            }
+            let others = busy.saturating_sub(own) as f64 / total as f64 * self.cores;
+            Some(others > ACTIVE_CORES)
+        }
+    }
+
+    fn sample() -> Option<Sample> {
+        // cpu user nice system idle iowait irq softirq steal ...
+        let stat = fs::read_to_string("/proc/stat").ok()?;
+        let ticks: Vec<u64> = stat
+            .lines()
+            .next()?
+            .strip_prefix("cpu ")?
+            .split_whitespace()
+            .take(8)
+            .map(|t| t.parse().ok())
+            .collect::<Option<_>>()?;
+        let total = ticks.iter().sum();
+        let idle = ticks.get(3)? + ticks.get(4)?;
+        Some(Sample {
+            total,
+            busy: total - idle,
+            own: tree_ticks(std::process::id()),
+        })
+    }
+
+    /// CPU time of the process and its descendants, in clock ticks like
+    /// `/proc/stat`.
+    fn tree_ticks(pid: u32) -> u64 {
+        let mut ticks = process_ticks(pid).unwrap_or(0);
+        if let Ok(tasks) = fs::read_dir(format!("/proc/{}/task", pid)) {
+            for task in tasks.flatten() {
+                if let Ok(children) = fs::read_to_string(task.path().join("children")) {
+                    for child in children.split_whitespace().filter_map(|c| c.parse().ok()) {
+                        ticks += tree_ticks(child);
+                    }
+                }
+            }
+        }
+        ticks
+    }
+
+    fn process_ticks(pid: u32) -> Option<u64> {
+        // pid (comm) state ppid ... utime stime ...
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        let (_, rest) = stat.rsplit_once(')')?;
+        let mut fields = rest.split_whitespace().skip(11);
+        let utime: u64 = fields.next()?.parse().ok()?;
+        let stime: u64 = fields.next()?.parse().ok()?;
+        Some(utime + stime)
+    }
+}
+
+/// Uses the time since the last keyboard or mouse input.
+#[cfg(windows)]
+mod imp {
+    use std::{mem, time::Duration};
+
+    #[repr(C)]
+    struct LastInputInfo {
+        size: u32,
+        time: u32,
+    }
+
+    #[link(name = "user32")]
+    extern "system" {
+        fn GetLastInputInfo(info: *mut LastInputInfo) -> i32;
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetTickCount() -> u32;
+    }
+
+    pub struct Probe;
+
+    impl Probe {
+        pub fn new() -> Option<Probe> {
+            Some(Probe)
+        }
+
+        pub fn active(&mut self, interval: Duration) -> Option<bool> {
+            let mut info = LastInputInfo {
+                size: mem::size_of::<LastInputInfo>() as u32,
+                time: 0,
+            };
+            // Safety: The structure is initialized with its size.
+            let idle = unsafe {
+                if GetLastInputInfo(&mut info) == 0 {
+                    return None;
+                }
+                GetTickCount().wrapping_sub(info.time)
+            };
+            Some(u128::from(idle) < interval.as_millis())
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod imp {
+    use std::time::Duration;
+
+    pub struct Probe;
+
+    impl Probe {
+        pub fn new() -> Option<Probe> {
+            None
+        }
+
+        pub fn active(&mut self, _interval: Duration) -> Option<bool> {
+            None
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::cmp::{max, min};
+#[cfg(target_os = "linux")]
+use std::{fs, mem, path::Path};
+
+use tokio::process::Command;
+
+/// Number of logical cores that this process can actually use. On Linux,
+/// this is restricted by the CPU affinity mask and the cgroup v2 CPU
+/// quota, as set by container runtimes and systemd. Without these, fishnet
+/// would start an engine process for every core of the host.
+pub fn available() -> usize {
+    let mut cores = num_cpus::get();
+    if let Some(allowed) = allowed() {
+        cores = min(cores, allowed.len());
+    }
+    if let Some(quota) = cgroup_quota() {
+        cores = min(cores, quota);
+    }
+    max(1, cores)
+}
+
+/// Logical cores in the CPU affinity mask of this process.
+#[cfg(target_os = "linux")]
+pub fn allowed() -> Option<Vec<usize>> {
+    // Safety: cpu_set_t is a plain bit array, for which all zeros is valid.
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    if unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) } != 0 {
+        return None;
+    }
+    let cores: Vec<usize> = (0..libc::CPU_SETSIZE as usize)
+        .filter(|&core| unsafe { libc::CPU_ISSET(core, &set) })
+        .collect();
+    (!cores.is_empty()).then(|| cores)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn allowed() -> Option<Vec<usize>> {
+    None
+}
+
+/// Smallest CPU quota of the cgroup of this process and its ancestors,
+/// rounded up to whole cores.
+#[cfg(target_os = "linux")]
+fn cgroup_quota() -> Option<usize> {
+    let root = Path::new("/sys/fs/cgroup");
+    let cgroup = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = cgroup.lines().find_map(|line| line.strip_prefix("0::"))?;
+    let mut dir = root.join(path.trim_start_matches('/'));
+    let mut quota: Option<usize> = None;
+    while dir.starts_with(root) {
+        if let Some(cores) = fs::read_to_string(dir.join("cpu.max"))
+            .ok()
+            .and_then(|cpu_max| parse_cpu_max(&cpu_max))
+        {
+            quota = Some(quota.map_or(cores, |quota| min(quota, cores)));
+        }
+        if !dir.pop() {
+            break;
+        }
+    }
+    quota
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cgroup_quota() -> Option<usize> {
+    None
+}
+
+/// Parses `cpu.max`, for example `200000 100000` for two cores, or
+/// `max 100000` for no quota at all.
+#[cfg(target_os = "linux")]
+fn parse_cpu_max(cpu_max: &str) -> Option<usize> {
+    let mut parts = cpu_max.split_whitespace();
+    let quota: u64 = parts.next()?.parse().ok()?;
+    let period: u64 = parts.next()?.parse().ok()?;
+    (period > 0).then(|| max(1, (quota + period - 1) / period) as usize)
+}
+
+/// Logical cores that this process may use, or all cores where the
+/// affinity mask is not available.
+pub fn allowed_or_all() -> Vec<usize> {
+    allowed().unwrap_or_else(|| (0..num_cpus::get()).collect())
+}
+
+/// Dedicated logical cores out of the given cores, for the engine process
+/// of the given worker. Wraps around if the workers need more cores than
+/// there are, for example when a quota allows fewer cores than the
+/// affinity mask.
+pub fn core_set(cores: &[usize], worker: usize, threads: usize) -> Vec<usize> {
+    (0..threads)
+        .map(|thread| cores[(worker * threads + thread) % cores.len()])
+        .collect()
+}
+
+/// Restricts the process started by the command to the given logical
+/// cores. The mask is set before exec, so that it also applies to all
+/// threads of the engine.
+#[cfg(target_os = "linux")]
+pub fn pin<'a>(command: &'a mut Command, cores: &[usize]) -> &'a mut Command {
+    // Safety: cpu_set_t is a plain bit array, for which all zeros is valid.
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for &core in cores {
+        unsafe { libc::CPU_SET(core, &mut set) };
+    }
+    unsafe {
+        // Safety: The closure is run in a fork, and only makes a system call
+        // with a mask that was prepared before.
+        command.pre_exec(move || {
+            if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) == -1 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin<'a>(command: &'a mut Command, _cores: &[usize]) -> &'a mut Command {
+    command
+}
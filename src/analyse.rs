@@ -0,0 +1,354 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write as _},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use shakmaty::{
+    fen::{fen, Fen},
+    san::San,
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    CastlingMode, Position as _,
+};
+use tokio::io::AsyncReadExt as _;
+
+use crate::{
+    analyse_epd::uci_line,
+    api::{LichessVariant, NodeLimit, Work},
+    assets::{Assets, EngineFlavor},
+    configure::{AnalyseOpt, Cores, Opt},
+    ipc::{Position, PositionId},
+    logger::Logger,
+    pgn::variant_from_name,
+    pool::EnginePool,
+    puzzles::{mainline_sans, split_games},
+    record::{Flag, PositionRecord},
+    validate::{validate_game, ValidGame},
+};
+
+pub struct Game {
+    pub id: String,
+    pub variant: LichessVariant,
+    pub valid: ValidGame,
+}
+
+/// Lines like `<fen> [moves <uci>...]` or `startpos [moves <uci>...]`,
+/// as opposed to PGN.
+fn is_fen_list(text: &str) -> bool {
+    text.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .and_then(|line| line.split_whitespace().next())
+        .map_or(false, |token| token == "startpos" || token.contains('/'))
+}
+
+fn parse_fen_line(line: &str, variant: LichessVariant) -> Result<ValidGame, String> {
+    let (fen, moves) = line.split_once(" moves ").unwrap_or((line, ""));
+    let fen: Fen = match fen.trim() {
+        "startpos" => Fen::from_setup(&VariantPosition::new(Variant::from(variant))),
+        fen => fen.parse().map_err(|err| format!("invalid fen: {}", err))?,
+    };
+    let moves = moves
+        .split_whitespace()
+        .map(|uci| {
+            uci.parse::<Uci>()
+                .map_err(|err| format!("invalid uci {}: {}", uci, err))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    validate_game(variant, &fen, moves).map_err(|err| err.to_string())
+}
+
+fn parse_pgn_game(
+    tags: &[(String, String)],
+    movetext: &str,
+    default_variant: LichessVariant,
+) -> Result<(LichessVariant, ValidGame), String> {
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let variant = match tag("Variant") {
+        Some(name) => {
+            variant_from_name(name).ok_or_else(|| format!("unsupported variant: {}", name))?
+        }
+        None => default_variant,
+    };
+    let fen = match tag("FEN") {
+        Some(fen) => fen.parse().map_err(|err| format!("invalid fen: {}", err))?,
+        None => Fen::from_setup(&VariantPosition::new(Variant::from(variant))),
+    };
+    let mut pos = VariantPosition::from_setup(Variant::from(variant), &fen, CastlingMode::Chess960)
+        .or_else(|err| err.ignore_impossible_material())
+        .map_err(|err| format!("illegal position: {}", err))?;
+
+    let mut moves = Vec::new();
+    for token in mainline_sans(movetext) {
+        let m = token
+            .parse::<San>()
+            .ok()
+            .and_then(|san| san.to_move(&pos).ok())
+            .ok_or_else(|| format!("illegal move {} after {} plies", token, moves.len()))?;
+        moves.push(m.to_uci(CastlingMode::Chess960));
+        pos.play_unchecked(&m);
+    }
+
+    validate_game(variant, &fen, moves)
+        .map(|valid| (variant, valid))
+        .map_err(|err| err.to_string())
+}
+
+/// Parses all games of the input, keeping invalid games as errors, so that
+/// they can be reported in order.
+pub fn parse_games(
+    text: &str,
+    default_variant: LichessVariant,
+) -> Vec<Result<Game, (String, String)>> {
+    if is_fen_list(text) {
+        text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|(i, line)| {
+                let id = (i + 1).to_string();
+                match parse_fen_line(line.trim(), default_variant) {
+                    Ok(valid) => Ok(Game {
+                        id,
+                        variant: default_variant,
+                        valid,
+                    }),
+                    Err(err) => Err((id, err)),
+                }
+            })
+            .collect()
+    } else {
+        split_games(text)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (tags, movetext))| {
+                let id = tags
+                    .iter()
+                    .find(|(name, _)| name == "Site")
+                    .and_then(|(_, site)| site.strip_prefix("https://lichess.org/"))
+                    .or_else(|| {
+                        tags.iter()
+                            .find(|(name, _)| name == "GameId")
+                            .map(|(_, id)| id.as_str())
+                    })
+                    .map_or_else(|| format!("game{}", i + 1), str::to_owned);
+                match parse_pgn_game(&tags, &movetext, default_variant) {
+                    Ok((variant, valid)) => Ok(Game { id, variant, valid }),
+                    Err(err) => Err((id, err)),
+                }
+            })
+            .collect()
+    }
+}
+
+fn flavor(assets: &Assets, game: &Game) -> Option<EngineFlavor> {
+    match game.valid.root {
+        VariantPosition::Chess(_)
+            if !game.valid.impossible_material && assets.stockfish.official.is_some() =>
+        {
+            Some(EngineFlavor::Official)
+        }
+        _ if assets.stockfish.multi_variant.is_some() => Some(EngineFlavor::MultiVariant),
+        _ => None,
+    }
+}
+
+fn write_text(
+    out: &mut impl io::Write,
+    record: &PositionRecord,
+    logger: &Logger,
+) -> io::Result<()> {
+    match (&record.eval, &record.error) {
+        (Some(eval), _) => writeln!(
+            out,
+            "{} {} depth {} {}",
+            record.id,
+            logger.score(eval),
+            record.depth.unwrap_or_default(),
+            record.pv.join(" ")
+        ),
+        (None, Some(err)) => writeln!(out, "{} error: {}", record.id, err),
+        (None, None) => writeln!(out, "{} no result", record.id),
+    }
+}
+
+/// Analyses every position of the games with the same engines as `run`, and
+/// prints the results in the order of the input.
+pub async fn analyse(opt: &Opt, analyse_opt: &AnalyseOpt, logger: &Logger) {
+    let mut text = String::new();
+    let read = if analyse_opt.file.as_os_str() == "-" {
+        tokio::io::stdin().read_to_string(&mut text).await
+    } else {
+        match tokio::fs::File::open(&analyse_opt.file).await {
+            Ok(mut file) => file.read_to_string(&mut text).await,
+            Err(err) => Err(err),
+        }
+    };
+    if let Err(err) = read {
+        logger.error(&format!("Failed to read {:?}: {}", analyse_opt.file, err));
+        std::process::exit(1);
+    }
+
+    let games = parse_games(&text, analyse_opt.variant);
+    if games.is_empty() {
+        logger.warn(&format!("No games in {:?}", analyse_opt.file));
+        return;
+    }
+
+    let concurrency = analyse_opt.concurrency.map_or_else(
+        || usize::from(opt.cores.unwrap_or(Cores::Auto)),
+        usize::from,
+    );
+
+    let cpu = opt.cpu();
+    let mut assets =
+        Assets::prepare(cpu, opt.enabled_engines()).expect("prepared bundled stockfish");
+    if let Some(ref path) = opt.syzygy_path {
+        if let Err(err) = assets.set_syzygy_path(path) {
+            logger.error(&format!("Invalid syzygy path {:?}: {}", path, err));
+            std::process::exit(1);
+        }
+    }
+    let assets = Arc::new(assets);
+
+    logger.headline(&format!(
+        "Analysing {} games with {} engine processes",
+        games.len(),
+        concurrency
+    ));
+    let started_at = Instant::now();
+
+    let (pool, pool_join_handle) = EnginePool::spawn(assets.clone(), concurrency, logger.clone());
+
+    let mut out = io::stdout();
+    let mut analysed = 0;
+    let mut failed = 0;
+    for game in games {
+        let game = match game {
+            Ok(game) => game,
+            Err((id, err)) => {
+                logger.warn(&format!("{}: {}", id, err));
+                failed += 1;
+                let record = PositionRecord {
+                    id,
+                    flags: vec![Flag::Invalid],
+                    error: Some(err),
+                    ..PositionRecord::default()
+                };
+                if opt.json {
+                    record.write_line(&mut out).expect("write record");
+                }
+                continue;
+            }
+        };
+
+        let flavor = match flavor(&assets, &game) {
+            Some(flavor) => flavor,
+            None => {
+                logger.warn(&format!("{}: no engine enabled for this variant", game.id));
+                failed += 1;
+                continue;
+            }
+        };
+
+        let castling_mode = if game.variant == LichessVariant::Chess960 {
+            CastlingMode::Chess960
+        } else {
+            CastlingMode::Standard
+        };
+
+        // Submit all positions of the game, so that the pool can analyse
+        // them in parallel, then collect the results in order.
+        let mut pending = Vec::with_capacity(game.valid.moves.len() + 1);
+        let mut pos = game.valid.root.clone();
+        for ply in 0..=game.valid.moves.len() {
+            if ply > 0 {
+                let m = game.valid.moves[ply - 1]
+                    .to_move(&pos)
+                    .expect("validated move");
+                pos.play_unchecked(&m);
+            }
+            if pos.is_game_over() {
+                continue;
+            }
+            let position = Position {
+                work: Work::Analysis {
+                    id: "analyse".parse().expect("batch id"),
+                    nodes: NodeLimit::fixed(analyse_opt.nodes),
+                    depth: analyse_opt.depth,
+                    multipv: None,
+                    timeout: Duration::default(),
+                    movetime: None,
+                    wdl: false,
+                    annotations: false,
+                    tier: None,
+                    options: BTreeMap::new(),
+                },
+                position_id: PositionId(ply),
+                flavor,
+                url: None,
+                variant: game.variant,
+                root_fen: Fen::from_setup(&game.valid.root),
+                moves: game.valid.moves[..ply].to_vec(),
+            };
+            pending.push((ply, pos.clone(), pool.submit(position).await));
+        }
+
+        for (ply, pos, response) in pending {
+            let id = format!("{}#{}", game.id, ply);
+            let record = match response.await {
+                Ok(res) => PositionRecord {
+                    id,
+                    fen: fen(&pos),
+                    best_move: res.best_move.map(|uci| match uci.to_move(&pos) {
+                        Ok(m) => m.to_uci(castling_mode).to_string(),
+                        Err(_) => uci.to_string(),
+                    }),
+                    eval: res.scores.best().copied(),
+                    depth: Some(res.depth),
+                    nodes: Some(res.nodes),
+                    pv: uci_line(
+                        pos.clone(),
+                        castling_mode,
+                        res.pvs.best().map_or(&[], Vec::as_slice),
+                    ),
+                    ..PositionRecord::default()
+                },
+                Err(_) => {
+                    failed += 1;
+                    PositionRecord {
+                        id,
+                        fen: fen(&pos),
+                        flags: vec![Flag::EngineError],
+                        error: Some("engine error".to_owned()),
+                        ..PositionRecord::default()
+                    }
+                }
+            };
+            analysed += 1;
+            if opt.json {
+                record.write_line(&mut out).expect("write record");
+            } else {
+                write_text(&mut out, &record, logger).expect("write result");
+            }
+            // Stream results, so that consumers can follow the output.
+            out.flush().expect("flush results");
+        }
+    }
+
+    drop(pool);
+    pool_join_handle.await.expect("join");
+
+    logger.fishnet_info(&format!(
+        "Analysed {} positions ({} failed) in {:.1?}",
+        analysed,
+        failed,
+        started_at.elapsed()
+    ));
+}
@@ -0,0 +1,458 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufWriter, Write as _},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use shakmaty::{
+    fen::{fen, Fen},
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    CastlingMode, Position as _,
+};
+use tokio::{
+    io::{AsyncBufReadExt as _, BufReader},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    api::{LichessVariant, NodeLimit, Score, Work},
+    assets::{Assets, EngineFlavor},
+    book::Book,
+    configure::{Cores, EpdOpt, Opt, OutputFormat},
+    ipc::{Position, PositionId, PositionResponse},
+    logger::Logger,
+    pgn::{after_best_move, AnnotatedGame, AnnotatedMove},
+    pool::EnginePool,
+    record::{Flag, PositionRecord},
+};
+
+/// A line of the suite, with the pending result of the engine.
+struct Record {
+    id: String,
+    epd: String,
+    pending: Result<(VariantPosition, oneshot::Receiver<PositionResponse>), (Flag, String)>,
+}
+
+struct Outcome {
+    fen: String,
+    score: Score,
+    best_move: Option<String>,
+    pv: Vec<String>,
+    depth: u8,
+    nodes: u64,
+}
+
+/// Splits EPD operations like `bm e4; id "opening 1";` into opcodes and
+/// operands, without splitting quoted operands.
+fn operations(s: &str) -> Vec<(&str, &str)> {
+    let mut ops = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, ch) in s.char_indices() {
+        match ch {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                ops.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    ops.push(&s[start..]);
+    ops.into_iter()
+        .map(str::trim)
+        .filter(|op| !op.is_empty())
+        .map(|op| {
+            let (opcode, operand) = op.split_once(char::is_whitespace).unwrap_or((op, ""));
+            (opcode, operand.trim().trim_matches('"'))
+        })
+        .collect()
+}
+
+fn parse_record(
+    line: &str,
+    variant: LichessVariant,
+) -> Result<(Option<String>, String, VariantPosition), String> {
+    let mut rest = line.trim();
+    let mut fields = Vec::with_capacity(4);
+    for _ in 0..4 {
+        let (field, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        if field.is_empty() {
+            return Err("expected board, turn, castling and en passant fields".to_owned());
+        }
+        fields.push(field);
+        rest = tail.trim_start();
+    }
+    let epd = fields.join(" ");
+
+    let mut id = None;
+    let mut halfmoves = "0";
+    let mut fullmoves = "1";
+    for (opcode, operand) in operations(rest) {
+        match opcode {
+            "id" => id = Some(operand.to_owned()),
+            "hmvc" => halfmoves = operand,
+            "fmvn" => fullmoves = operand,
+            _ => (),
+        }
+    }
+
+    let fen: Fen = format!("{} {} {}", epd, halfmoves, fullmoves)
+        .parse()
+        .map_err(|err| format!("invalid position: {}", err))?;
+    let pos = VariantPosition::from_setup(Variant::from(variant), &fen, CastlingMode::Chess960)
+        .or_else(|err| err.ignore_impossible_material())
+        .map_err(|err| format!("illegal position: {}", err))?;
+    if pos.legal_moves().is_empty() {
+        return Err("no legal moves".to_owned());
+    }
+    Ok((id, epd, pos))
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(|ch| matches!(ch, ',' | '"' | '\n' | '\r')) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn write_row(
+    out: &mut impl io::Write,
+    id: &str,
+    epd: &str,
+    outcome: &Result<Outcome, (Flag, String)>,
+) -> io::Result<()> {
+    let (cp, mate, best_move, depth, nodes, error) = match *outcome {
+        Ok(ref outcome) => {
+            let (cp, mate) = match outcome.score {
+                Score::Cp(cp) => (cp.to_string(), String::new()),
+                Score::Mate(mate) => (String::new(), mate.to_string()),
+            };
+            (
+                cp,
+                mate,
+                outcome.best_move.clone().unwrap_or_default(),
+                outcome.depth.to_string(),
+                outcome.nodes.to_string(),
+                "",
+            )
+        }
+        Err((_, ref err)) => (
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            err.as_str(),
+        ),
+    };
+    writeln!(
+        out,
+        "{},{},{},{},{},{},{},{}",
+        csv_field(id),
+        csv_field(epd),
+        cp,
+        mate,
+        best_move,
+        depth,
+        nodes,
+        csv_field(error)
+    )
+}
+
+fn write_record(
+    out: &mut impl io::Write,
+    id: &str,
+    epd: &str,
+    outcome: Result<Outcome, (Flag, String)>,
+) -> io::Result<()> {
+    let record = match outcome {
+        Ok(outcome) => PositionRecord {
+            id: id.to_owned(),
+            fen: outcome.fen,
+            best_move: outcome.best_move,
+            eval: Some(outcome.score),
+            depth: Some(outcome.depth),
+            nodes: Some(outcome.nodes),
+            pv: outcome.pv,
+            ..PositionRecord::default()
+        },
+        Err((flag, err)) => PositionRecord {
+            id: id.to_owned(),
+            fen: epd.to_owned(),
+            flags: vec![flag],
+            error: Some(err),
+            ..PositionRecord::default()
+        },
+    };
+    record.write_line(out)?;
+    // Stream records, so that consumers can follow the file.
+    out.flush()
+}
+
+async fn read_suite(
+    epd_opt: EpdOpt,
+    assets: Arc<Assets>,
+    book: Option<Book>,
+    pool: EnginePool,
+    records: mpsc::Sender<Record>,
+    logger: Logger,
+) -> io::Result<()> {
+    let file = tokio::fs::File::open(&epd_opt.file).await?;
+    let mut lines = BufReader::new(file).lines();
+    let mut line_number = 0;
+    while let Some(line) = lines.next_line().await? {
+        line_number += 1;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parsed = parse_record(&line, epd_opt.variant).and_then(|(id, epd, pos)| {
+            let flavor = match pos {
+                VariantPosition::Chess(_) if assets.stockfish.official.is_some() => {
+                    EngineFlavor::Official
+                }
+                _ if assets.stockfish.multi_variant.is_some() => EngineFlavor::MultiVariant,
+                _ => return Err("no engine enabled for this variant".to_owned()),
+            };
+            let position = Position {
+                work: Work::Analysis {
+                    id: "epd".parse().expect("batch id"),
+                    nodes: NodeLimit::fixed(epd_opt.nodes),
+                    depth: epd_opt.depth,
+                    multipv: None,
+                    timeout: Duration::default(),
+                    movetime: None,
+                    wdl: false,
+                    annotations: false,
+                    tier: None,
+                    options: BTreeMap::new(),
+                },
+                position_id: PositionId(line_number),
+                flavor,
+                url: None,
+                variant: epd_opt.variant,
+                root_fen: Fen::from_setup(&pos),
+                moves: Vec::new(),
+            };
+            Ok((
+                id.unwrap_or_else(|| line_number.to_string()),
+                epd,
+                pos,
+                position,
+            ))
+        });
+
+        let record = match parsed {
+            Ok((id, epd, pos, _)) if book.as_ref().map_or(false, |book| book.contains(&pos)) => {
+                Record {
+                    id,
+                    epd,
+                    pending: Err((Flag::Book, "book position".to_owned())),
+                }
+            }
+            Ok((id, epd, pos, position)) => Record {
+                id,
+                epd,
+                pending: Ok((pos, pool.submit(position).await)),
+            },
+            Err(err) => {
+                logger.warn(&format!("Line {}: {}", line_number, err));
+                Record {
+                    id: line_number.to_string(),
+                    epd: line.trim().to_owned(),
+                    pending: Err((Flag::Invalid, err)),
+                }
+            }
+        };
+        if records.send(record).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Converts moves from the engine to the castling notation of the variant.
+pub fn uci_line(
+    mut pos: VariantPosition,
+    castling_mode: CastlingMode,
+    line: &[Uci],
+) -> Vec<String> {
+    let mut converted = Vec::with_capacity(line.len());
+    for uci in line {
+        let m = match uci.to_move(&pos) {
+            Ok(m) => m,
+            Err(_) => break,
+        };
+        converted.push(m.to_uci(castling_mode).to_string());
+        pos.play_unchecked(&m);
+    }
+    converted
+}
+
+/// Analyses a test suite with the same engines and settings as `run`,
+/// writing rows in the order of the suite as soon as they are available.
+pub async fn analyse_epd(opt: &Opt, epd_opt: &EpdOpt, logger: &Logger) {
+    let concurrency = epd_opt.concurrency.map_or_else(
+        || usize::from(opt.cores.unwrap_or(Cores::Auto)),
+        usize::from,
+    );
+
+    let cpu = opt.cpu();
+    let mut assets =
+        Assets::prepare(cpu, opt.enabled_engines()).expect("prepared bundled stockfish");
+    if let Some(ref path) = opt.syzygy_path {
+        if let Err(err) = assets.set_syzygy_path(path) {
+            logger.error(&format!("Invalid syzygy path {:?}: {}", path, err));
+            std::process::exit(1);
+        }
+    }
+    let assets = Arc::new(assets);
+
+    let book = match opt.lookup.book.as_deref().map(Book::open).transpose() {
+        Ok(book) => book,
+        Err(err) => {
+            logger.error(&format!(
+                "Failed to open book {:?}: {}",
+                opt.lookup.book, err
+            ));
+            std::process::exit(1);
+        }
+    };
+
+    let mut out = match File::create(&epd_opt.out) {
+        Ok(file) => BufWriter::new(file),
+        Err(err) => {
+            logger.error(&format!("Failed to create {:?}: {}", epd_opt.out, err));
+            std::process::exit(1);
+        }
+    };
+    if epd_opt.format == OutputFormat::Csv {
+        writeln!(out, "id,epd,cp,mate,bestmove,depth,nodes,error").expect("write csv header");
+    }
+
+    let mut pgn_out = epd_opt.pgn.as_ref().map(|path| match File::create(path) {
+        Ok(file) => BufWriter::new(file),
+        Err(err) => {
+            logger.error(&format!("Failed to create {:?}: {}", path, err));
+            std::process::exit(1);
+        }
+    });
+
+    logger.headline(&format!(
+        "Analysing {:?} with {} engine processes",
+        epd_opt.file, concurrency
+    ));
+    let started_at = Instant::now();
+
+    let (pool, pool_join_handle) = EnginePool::spawn(assets.clone(), concurrency, logger.clone());
+    let (records_tx, mut records_rx) = mpsc::channel(concurrency);
+    let reader = tokio::spawn(read_suite(
+        epd_opt.clone(),
+        assets,
+        book,
+        pool,
+        records_tx,
+        logger.clone(),
+    ));
+
+    let castling_mode = if epd_opt.variant == LichessVariant::Chess960 {
+        CastlingMode::Chess960
+    } else {
+        CastlingMode::Standard
+    };
+
+    // Records arrive in the order of the suite, while the pool analyses the
+    // following positions.
+    let mut analysed = 0;
+    let mut failed = 0;
+    let mut skipped = 0;
+    while let Some(record) = records_rx.recv().await {
+        let mut game = None;
+        let outcome = match record.pending {
+            Ok((pos, pending)) => match pending.await {
+                Ok(res) => {
+                    let score = res.scores.best().copied().expect("got score");
+                    if pgn_out.is_some() {
+                        let moves = res.pvs.best().cloned().unwrap_or_default();
+                        game = Some(AnnotatedGame {
+                            headers: vec![("Event".to_owned(), record.id.clone())],
+                            variant: epd_opt.variant,
+                            root: pos.clone(),
+                            moves: moves
+                                .into_iter()
+                                .enumerate()
+                                .map(|(ply, uci)| AnnotatedMove {
+                                    uci,
+                                    eval: Some(after_best_move(score)).filter(|_| ply == 0),
+                                    variation: Vec::new(),
+                                })
+                                .collect(),
+                            outcome: None,
+                        });
+                    }
+                    Ok(Outcome {
+                        fen: fen(&pos),
+                        score,
+                        best_move: res.best_move.map(|uci| match uci.to_move(&pos) {
+                            Ok(m) => m.to_uci(castling_mode).to_string(),
+                            Err(_) => uci.to_string(),
+                        }),
+                        pv: uci_line(
+                            pos.clone(),
+                            castling_mode,
+                            res.pvs.best().map_or(&[], Vec::as_slice),
+                        ),
+                        depth: res.depth,
+                        nodes: res.nodes,
+                    })
+                }
+                Err(_) => Err((Flag::EngineError, "engine error".to_owned())),
+            },
+            Err(err) => Err(err),
+        };
+
+        match outcome {
+            Err((Flag::Book, _)) => skipped += 1,
+            Err(_) => failed += 1,
+            Ok(_) => (),
+        }
+        analysed += 1;
+        logger.debug(&format!("Analysed {}: {}", record.id, record.epd));
+        match epd_opt.format {
+            OutputFormat::Csv => {
+                write_row(&mut out, &record.id, &record.epd, &outcome).expect("write csv row")
+            }
+            OutputFormat::JsonLines => {
+                write_record(&mut out, &record.id, &record.epd, outcome).expect("write record")
+            }
+        }
+        if let (Some(pgn_out), Some(game)) = (pgn_out.as_mut(), game) {
+            writeln!(pgn_out, "{}", game).expect("write pgn game");
+        }
+    }
+    out.flush().expect("flush results");
+    if let Some(mut pgn_out) = pgn_out {
+        pgn_out.flush().expect("flush pgn");
+    }
+
+    let read = reader.await.expect("join");
+    pool_join_handle.await.expect("join");
+    if let Err(err) = read {
+        logger.error(&format!("Failed to read {:?}: {}", epd_opt.file, err));
+        std::process::exit(1);
+    }
+
+    logger.fishnet_info(&format!(
+        "Analysed {} positions ({} failed, {} skipped in book) in {:.1?}. Results written to {:?}.",
+        analysed,
+        failed,
+        skipped,
+        started_at.elapsed(),
+        epd_opt.out
+    ));
+}
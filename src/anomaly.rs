@@ -0,0 +1,213 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+use url::Url;
+
+use crate::{
+    api::Work,
+    assets::{ByEngineFlavor, EngineFlavor},
+    ipc::PositionResponse,
+    logger::Logger,
+};
+
+/// Establish the usual speed from this many positions, before judging.
+const MIN_SAMPLES: u32 = 20;
+
+/// Report positions that were searched this many times slower than usual.
+const NPS_DROP_FACTOR: u32 = 10;
+
+/// Report when this many consecutive positions stopped at the same depth,
+/// far below the requested number of nodes.
+const DEPTH_CEILING_REPEATS: u32 = 5;
+
+/// Report when this many consecutive positions filled the hash table at a
+/// low node count.
+const HASHFULL_REPEATS: u32 = 3;
+
+/// Node counts below this are too low to fill the hash table.
+const HASHFULL_LOW_NODES: u64 = 1_000_000;
+
+/// Suspicious engine behavior, like thermal throttling or a broken build,
+/// recorded in the session stats.
+#[derive(Debug, Clone, Serialize)]
+pub struct Anomaly {
+    pub kind: &'static str,
+    pub engine: &'static str,
+    pub detail: String,
+    /// Unix timestamp.
+    pub at: u64,
+}
+
+impl Anomaly {
+    pub fn new(kind: &'static str, flavor: EngineFlavor, detail: String) -> Anomaly {
+        Anomaly {
+            kind,
+            engine: flavor.name(),
+            detail,
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct EngineHistory {
+    samples: u32,
+    nps: f64,
+    nps_dropped: bool,
+    depth_ceiling: Option<(u8, u32)>,
+    hashfull_repeats: u32,
+}
+
+impl EngineHistory {
+    fn check_nps(&mut self, flavor: EngineFlavor, res: &PositionResponse) -> Option<Anomaly> {
+        // Very short searches have unreliable speed.
+        let nps = match res.nps {
+            Some(nps) if res.time >= Duration::from_millis(100) => f64::from(nps),
+            _ => return None,
+        };
+        let usual = self.nps;
+        self.samples = self.samples.saturating_add(1);
+        if self.samples <= MIN_SAMPLES {
+            self.nps += (nps - self.nps) / f64::from(self.samples);
+            return None;
+        }
+        if nps * f64::from(NPS_DROP_FACTOR) < usual {
+            // Keep the baseline, so that a persistent drop is not learned.
+            if !self.nps_dropped {
+                self.nps_dropped = true;
+                return Some(Anomaly::new(
+                    "nps-drop",
+                    flavor,
+                    format!("{:.0} nps, usually {:.0} nps", nps, usual),
+                ));
+            }
+        } else {
+            self.nps_dropped = false;
+            self.nps = self.nps * 0.95 + nps * 0.05;
+        }
+        None
+    }
+
+    fn check_depth(&mut self, flavor: EngineFlavor, res: &PositionResponse) -> Option<Anomaly> {
+        let target = match res.work {
+            Work::Analysis { nodes, depth, .. } if depth != Some(res.depth) => {
+                nodes.get(flavor.eval_flavor())
+            }
+            _ => return None,
+        };
+        if res.nodes.saturating_mul(10) >= target {
+            self.depth_ceiling = None;
+            return None;
+        }
+        let repeats = match self.depth_ceiling {
+            Some((depth, repeats)) if depth == res.depth => repeats + 1,
+            _ => 1,
+        };
+        self.depth_ceiling = Some((res.depth, repeats));
+        if repeats == DEPTH_CEILING_REPEATS {
+            Some(Anomaly::new(
+                "depth-ceiling",
+                flavor,
+                format!(
+                    "{} positions stopped at depth {} with at most a tenth of {} nodes",
+                    repeats, res.depth, target
+                ),
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn check_hashfull(&mut self, flavor: EngineFlavor, res: &PositionResponse) -> Option<Anomaly> {
+        if res.hashfull.map_or(false, |h| h >= 1000) && res.nodes < HASHFULL_LOW_NODES {
+            self.hashfull_repeats += 1;
+        } else {
+            self.hashfull_repeats = 0;
+        }
+        if self.hashfull_repeats == HASHFULL_REPEATS {
+            Some(Anomaly::new(
+                "hashfull",
+                flavor,
+                format!(
+                    "{} positions filled the hash table with less than {} nodes",
+                    self.hashfull_repeats, HASHFULL_LOW_NODES
+                ),
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Watches completed positions for engine behavior that is not explained
+/// by the work itself.
+pub struct AnomalyDetector {
+    history: ByEngineFlavor<EngineHistory>,
+}
+
+impl Default for AnomalyDetector {
+    fn default() -> AnomalyDetector {
+        AnomalyDetector {
+            history: ByEngineFlavor {
+                official: EngineHistory::default(),
+                multi_variant: EngineHistory::default(),
+            },
+        }
+    }
+}
+
+impl AnomalyDetector {
+    pub fn record(&mut self, flavor: EngineFlavor, res: &PositionResponse) -> Vec<Anomaly> {
+        let history = self.history.get_mut(flavor);
+        [
+            history.check_nps(flavor, res),
+            history.check_depth(flavor, res),
+            history.check_hashfull(flavor, res),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+/// Posts anomaly records as JSON to a webhook.
+#[derive(Clone)]
+pub struct Webhook {
+    tx: mpsc::UnboundedSender<Anomaly>,
+}
+
+impl Webhook {
+    pub fn notify(&self, anomaly: Anomaly) {
+        // Notifications during shutdown may be lost.
+        let _ = self.tx.send(anomaly);
+    }
+}
+
+/// Spawns the notifier. Records are posted one at a time, in order.
+pub fn spawn_webhook(url: Url, logger: Logger) -> Webhook {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Anomaly>();
+    tokio::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("client");
+        while let Some(anomaly) = rx.recv().await {
+            match client.post(url.clone()).json(&anomaly).send().await {
+                Ok(res) if res.status().is_success() => {
+                    logger.debug(&format!("Notified {} about {} anomaly", url, anomaly.kind));
+                }
+                Ok(res) => logger.warn(&format!(
+                    "Anomaly webhook at {} responded with {}",
+                    url,
+                    res.status()
+                )),
+                Err(err) => logger.warn(&format!("Failed to notify anomaly webhook: {}", err)),
+            }
+        }
+    });
+    Webhook { tx }
+}
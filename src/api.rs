@@ -1,57 +1,128 @@
-use std::{env, fmt, num::NonZeroU8, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    cmp::min,
+    collections::BTreeMap,
+    env, fmt,
+    fs::File,
+    io,
+    io::{BufReader, Write as _},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use arrayvec::ArrayString;
+use flate2::{write::GzEncoder, Compression};
+use futures_util::{SinkExt as _, StreamExt as _};
+use rand::Rng as _;
 use reqwest::{
-    header::{HeaderName, HeaderValue, AUTHORIZATION},
+    header::{
+        HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE, DATE,
+        RETRY_AFTER, USER_AGENT,
+    },
     StatusCode,
 };
 use serde::{Deserialize, Serialize};
-use serde_repr::Deserialize_repr as DeserializeRepr;
+use serde_json::Value;
 use serde_with::{
-    serde_as, DisplayFromStr, DurationMilliSeconds, DurationSeconds, NoneAsEmptyString,
-    SpaceSeparator, StringWithSeparator,
+    serde_as, CommaSeparator, DisplayFromStr, DurationSeconds, NoneAsEmptyString, SpaceSeparator,
+    StringWithSeparator,
 };
-use shakmaty::{fen::Fen, uci::Uci, variant::Variant};
+use shakmaty::uci::Uci;
 use tokio::{
-    sync::{mpsc, oneshot},
+    net::TcpStream,
+    sync::{broadcast, mpsc, oneshot, watch},
     time,
 };
+use tokio_tungstenite::{
+    tungstenite::{
+        client::IntoClientRequest as _, error::UrlError, protocol::frame::coding::CloseCode,
+        Error as WsError, Message,
+    },
+    Connector, MaybeTlsStream, WebSocketStream,
+};
 use url::Url;
 
 use crate::{
-    assets::EvalFlavor,
-    configure::{Endpoint, Key, KeyError},
+    assets::{ByEngineFlavor, EngineFlavor, EvalFlavor},
+    calibration::Calibration,
+    configure::{Endpoint, Key, KeyError, Labels, Proxy, VariantFilter, VariantList},
+    happy_eyeballs::{self, IpPreference, Resolver},
     logger::Logger,
+    metrics::METRICS,
+    outbox::{Outbox, Submission},
     util::{NevermindExt as _, RandomizedBackoff},
 };
 
-pub fn channel(endpoint: Endpoint, key: Option<Key>, logger: Logger) -> (ApiStub, ApiActor) {
+pub use crate::protocol::{
+    AnalysisPart, BatchId, Centis, Clock, LichessVariant, NodeLimit, NodeScaleBounds, PlayoutPly,
+    Priority, Score, SkillLevel, Tier, UnknownVariant, VariantCapability, Wdl, Work,
+    MAX_PLAYOUT_PLIES,
+};
+
+pub fn channel(
+    endpoint: Endpoint,
+    key: Option<Key>,
+    proxy: Option<Proxy>,
+    logger: Logger,
+) -> (ApiStub, ApiActor) {
     let (tx, rx) = mpsc::unbounded_channel();
+    let (quota_tx, quota) = watch::channel(None);
+    let cancellations = Cancellations::new();
     (
         ApiStub {
             tx,
             endpoint: endpoint.clone(),
+            quota,
+            cancellations: cancellations.clone(),
         },
-        ApiActor::new(rx, endpoint, key, logger),
+        ApiActor::new(rx, quota_tx, cancellations, endpoint, key, proxy, logger),
     )
 }
 
-pub fn spawn(endpoint: Endpoint, key: Option<Key>, logger: Logger) -> ApiStub {
-    let (stub, actor) = channel(endpoint, key, logger);
+pub fn spawn(
+    endpoint: Endpoint,
+    key: Option<Key>,
+    proxy: Option<Proxy>,
+    logger: Logger,
+) -> ApiStub {
+    let (stub, actor) = channel(endpoint, key, proxy, logger);
     tokio::spawn(async move {
         actor.run().await;
     });
     stub
 }
 
+/// Creates a stub for another actor than [`ApiActor`], which receives the
+/// messages itself.
+pub(crate) fn detached_channel(
+    endpoint: Endpoint,
+) -> (ApiStub, mpsc::UnboundedReceiver<ApiMessage>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (_, quota) = watch::channel(None);
+    (
+        ApiStub {
+            tx,
+            endpoint,
+            quota,
+            cancellations: Cancellations::new(),
+        },
+        rx,
+    )
+}
+
 #[derive(Debug)]
-enum ApiMessage {
+pub(crate) enum ApiMessage {
     CheckKey {
         callback: oneshot::Sender<Result<(), KeyError>>,
     },
     Status {
         callback: oneshot::Sender<AnalysisStatus>,
     },
+    Probe {
+        callback: oneshot::Sender<Probe>,
+    },
+    Standing {
+        callback: oneshot::Sender<Standing>,
+    },
     Abort {
         batch_id: BatchId,
     },
@@ -62,6 +133,8 @@ enum ApiMessage {
     SubmitAnalysis {
         batch_id: BatchId,
         flavor: EvalFlavor,
+        node_scale: Option<f64>,
+        cpu_time: Option<Duration>,
         analysis: Vec<Option<AnalysisPart>>,
     },
     SubmitMove {
@@ -69,11 +142,25 @@ enum ApiMessage {
         best_move: Option<Uci>,
         callback: oneshot::Sender<Acquired>,
     },
+    SubmitPlayout {
+        batch_id: BatchId,
+        flavor: EvalFlavor,
+        playout: Vec<PlayoutPly>,
+    },
+    /// Sent by the actor itself, for submissions from its outbox.
+    RetryAnalysis {
+        submission: Submission,
+    },
 }
 
+#[serde_as]
 #[derive(Debug, Deserialize)]
 struct StatusResponseBody {
     analysis: AnalysisStatus,
+    /// Batches of this client that the server cancelled.
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    abort: Vec<BatchId>,
 }
 
 #[derive(Debug, Default, Deserialize)]
@@ -94,220 +181,117 @@ pub struct QueueStatus {
     pub oldest: Duration,
 }
 
+/// Outcome of a plain request to the endpoint, regardless of the status.
+#[derive(Debug)]
+pub struct Probe {
+    pub status: StatusCode,
+    pub latency: Duration,
+    pub server_time: Option<SystemTime>,
+}
+
+/// Standing of this key among all providers, according to the server.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Standing {
+    pub rank: u32,
+    pub providers: u32,
+    #[serde(default)]
+    pub positions: u64,
+}
+
 #[derive(Debug, Serialize)]
 pub struct VoidRequestBody {
     fishnet: Fishnet,
 }
 
+#[derive(Debug, Serialize)]
+struct AcquireRequestBody<'a> {
+    fishnet: Fishnet,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    variants: &'a [VariantCapability],
+}
+
 #[derive(Debug, Serialize)]
 struct Fishnet {
     version: &'static str,
     apikey: String,
+    /// Optional protocol features that the server may request.
+    capabilities: &'static [&'static str],
+    /// Name of this machine, if configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
 }
 
-impl Fishnet {
-    fn authenticated(key: Option<Key>) -> Fishnet {
-        Fishnet {
-            version: env!("CARGO_PKG_VERSION"),
-            apikey: key.map_or("".to_owned(), |k| k.0),
-        }
-    }
-}
+/// Work can ask for win/draw/loss statistics with `work.wdl`, for the
+/// judgement of each move with `work.annotations`, and override engine
+/// options with `work.options`. Work of type `playout` is supported.
+const CAPABILITIES: &[&str] = &["wdl", "annotations", "options", "playout"];
 
 #[derive(Debug, Serialize)]
 struct Stockfish {
     flavor: EvalFlavor,
+    /// Factor applied to the node targets of the server, if any.
+    #[serde(rename = "nodeScale", skip_serializing_if = "Option::is_none")]
+    node_scale: Option<f64>,
+    /// Measured speed of the engines on this machine, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    benchmark: Option<Calibration>,
+    /// SHA-256 of the engine executable, with deterministic analysis.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    build: Option<String>,
+    /// CPU time of the engine processes for the batch, if measured.
+    #[serde(rename = "cpuSeconds", skip_serializing_if = "Option::is_none")]
+    cpu_seconds: Option<f64>,
 }
 
+#[serde_as]
 #[derive(Debug, Serialize)]
 pub struct AcquireQuery {
     pub slow: bool,
+    /// Analysis tiers that the server may select, separated by commas.
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, Tier>")]
+    pub tiers: Vec<Tier>,
+    /// Variants that will be accepted, separated by commas, if not all.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variants: Option<String>,
+    /// Work types that will be accepted, separated by commas, if not all.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub work: Option<String>,
 }
 
 #[serde_as]
-#[derive(Debug, Deserialize, Clone)]
-#[serde(tag = "type")]
-pub enum Work {
-    #[serde(rename = "analysis")]
-    Analysis {
-        #[serde_as(as = "DisplayFromStr")]
-        id: BatchId,
-        nodes: NodeLimit,
-        #[serde(default)]
-        depth: Option<u8>,
-        #[serde(default)]
-        multipv: Option<NonZeroU8>,
-        #[serde_as(as = "DurationMilliSeconds<u64>")]
-        timeout: Duration,
-    },
-    #[serde(rename = "move")]
-    Move {
-        #[serde_as(as = "DisplayFromStr")]
-        id: BatchId,
-        level: SkillLevel,
-        #[serde(default)]
-        clock: Option<Clock>,
-    },
-}
-
-impl Work {
-    pub fn id(&self) -> BatchId {
-        match *self {
-            Work::Analysis { id, .. } | Work::Move { id, .. } => id,
-        }
-    }
-
-    pub fn timeout(&self) -> Duration {
-        match *self {
-            Work::Analysis { timeout, .. } => timeout,
-            Work::Move { .. } => Duration::from_secs(2),
-        }
-    }
-
-    pub fn is_analysis(&self) -> bool {
-        matches!(self, Work::Analysis { .. })
-    }
-
-    pub fn multipv(&self) -> NonZeroU8 {
-        match *self {
-            Work::Analysis { multipv, .. } => multipv,
-            Work::Move { .. } => None,
-        }
-        .unwrap_or_else(|| NonZeroU8::new(1).unwrap())
-    }
-
-    pub fn matrix_wanted(&self) -> bool {
-        matches!(
-            *self,
-            Work::Analysis {
-                multipv: Some(_),
-                ..
-            }
-        )
-    }
-}
-
-#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
-pub struct BatchId(ArrayString<24>);
-
-impl FromStr for BatchId {
-    type Err = arrayvec::CapacityError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(BatchId(s.parse()?))
-    }
-}
-
-impl fmt::Display for BatchId {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(&self.0, f)
-    }
-}
-
-#[derive(Debug, Copy, Clone, Deserialize)]
-pub struct NodeLimit {
-    classical: u64,
-    sf15: u64,
-}
-
-impl NodeLimit {
-    pub fn get(&self, flavor: EvalFlavor) -> u64 {
-        match flavor {
-            EvalFlavor::Hce => self.classical,
-            EvalFlavor::Nnue => self.sf15,
-        }
-    }
-}
-
-#[derive(DeserializeRepr, Debug, Copy, Clone)]
-#[repr(u32)]
-pub enum SkillLevel {
-    One = 1,
-    Two = 2,
-    Three = 3,
-    Four = 4,
-    Five = 5,
-    Six = 6,
-    Seven = 7,
-    Eight = 8,
-}
-
-impl SkillLevel {
-    pub fn time(self) -> Duration {
-        use SkillLevel::*;
-        Duration::from_millis(match self {
-            One => 50,
-            Two => 100,
-            Three => 150,
-            Four => 200,
-            Five => 300,
-            Six => 400,
-            Seven => 500,
-            Eight => 1000,
-        })
-    }
-
-    pub fn skill_level(self) -> i32 {
-        use SkillLevel::*;
-        match self {
-            One => -9,
-            Two => -5,
-            Three => -1,
-            Four => 3,
-            Five => 7,
-            Six => 11,
-            Seven => 16,
-            Eight => 20,
-        }
-    }
-
-    pub fn depth(self) -> u8 {
-        use SkillLevel::*;
-        match self {
-            One | Two | Three | Four | Five => 5,
-            Six => 8,
-            Seven => 13,
-            Eight => 22,
-        }
-    }
-}
-
-#[serde_as]
-#[derive(Debug, Deserialize, Clone)]
-pub struct Clock {
-    pub wtime: Centis,
-    pub btime: Centis,
-    #[serde_as(as = "DurationSeconds<u64>")]
-    pub inc: Duration,
-}
-
-#[derive(Debug, Copy, Clone, Deserialize)]
-pub struct Centis(u32);
-
-impl From<Centis> for Duration {
-    fn from(Centis(centis): Centis) -> Duration {
-        Duration::from_millis(u64::from(centis) * 10)
-    }
-}
-
-#[serde_as]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AcquireResponseBody {
     pub work: Work,
     #[serde_as(as = "NoneAsEmptyString")]
     #[serde(default)]
     pub game_id: Option<String>,
-    #[serde_as(as = "DisplayFromStr")]
-    pub position: Fen,
+    /// FEN of the root position, parsed during validation.
+    pub position: String,
     #[serde(default)]
     pub variant: LichessVariant,
     #[serde_as(as = "StringWithSeparator::<SpaceSeparator, Uci>")]
     pub moves: Vec<Uci>,
     #[serde(rename = "skipPositions", default)]
     pub skip_positions: Vec<usize>,
+    /// Allows clients to scale the node targets within these bounds.
+    #[serde(rename = "nodeScale", default)]
+    pub node_scale: Option<NodeScaleBounds>,
+    /// Only for analysis. Moves are always user requests.
+    #[serde(default)]
+    pub priority: Priority,
 }
 
 impl AcquireResponseBody {
+    pub fn priority(&self) -> Priority {
+        if self.work.is_analysis() {
+            self.priority
+        } else {
+            Priority::User
+        }
+    }
+
     pub fn batch_url(&self, endpoint: &Endpoint) -> Option<Url> {
         self.game_id.as_ref().map(|g| {
             let mut url = endpoint.url.clone();
@@ -317,61 +301,6 @@ impl AcquireResponseBody {
     }
 }
 
-#[derive(Debug, Deserialize, Copy, Clone, Eq, PartialEq)]
-#[serde(rename_all = "camelCase")]
-pub enum LichessVariant {
-    Antichess,
-    Atomic,
-    Chess960,
-    Crazyhouse,
-    FromPosition,
-    Horde,
-    KingOfTheHill,
-    RacingKings,
-    Standard,
-    ThreeCheck,
-}
-
-impl LichessVariant {
-    pub fn short_name(self) -> Option<&'static str> {
-        Some(match self {
-            LichessVariant::Antichess => "anti",
-            LichessVariant::Atomic => "atomic",
-            LichessVariant::Chess960 => "chess960",
-            LichessVariant::Crazyhouse => "zh",
-            LichessVariant::FromPosition => "setup",
-            LichessVariant::Horde => "horde",
-            LichessVariant::KingOfTheHill => "koth",
-            LichessVariant::RacingKings => "race",
-            LichessVariant::ThreeCheck => "3check",
-            LichessVariant::Standard => return None,
-        })
-    }
-}
-
-impl From<LichessVariant> for Variant {
-    fn from(lichess: LichessVariant) -> Variant {
-        match lichess {
-            LichessVariant::Antichess => Variant::Antichess,
-            LichessVariant::Atomic => Variant::Atomic,
-            LichessVariant::Chess960 | LichessVariant::Standard | LichessVariant::FromPosition => {
-                Variant::Chess
-            }
-            LichessVariant::Crazyhouse => Variant::Crazyhouse,
-            LichessVariant::Horde => Variant::Horde,
-            LichessVariant::KingOfTheHill => Variant::KingOfTheHill,
-            LichessVariant::RacingKings => Variant::RacingKings,
-            LichessVariant::ThreeCheck => Variant::ThreeCheck,
-        }
-    }
-}
-
-impl Default for LichessVariant {
-    fn default() -> LichessVariant {
-        LichessVariant::Standard
-    }
-}
-
 #[must_use = "Acquired work should be processed or cancelled"]
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
@@ -382,12 +311,27 @@ pub enum Acquired {
 }
 
 #[derive(Debug, Serialize)]
-struct AnalysisRequestBody {
+struct AnalysisRequestBody<'a> {
     fishnet: Fishnet,
+    #[serde(flatten)]
+    submission: &'a Value,
+}
+
+/// Body of an analysis submission, except for the fishnet section, as kept
+/// in the outbox.
+#[derive(Debug, Serialize)]
+struct AnalysisSubmission {
     stockfish: Stockfish,
     analysis: Vec<Option<AnalysisPart>>,
 }
 
+#[derive(Debug, Serialize)]
+struct PlayoutRequestBody {
+    fishnet: Fishnet,
+    stockfish: Stockfish,
+    playout: Vec<PlayoutPly>,
+}
+
 #[derive(Debug, Serialize)]
 struct MoveRequestBody {
     fishnet: Fishnet,
@@ -403,54 +347,45 @@ struct BestMove {
     best_move: Option<Uci>,
 }
 
-#[serde_as]
 #[derive(Debug, Serialize)]
-#[serde(untagged)]
-pub enum AnalysisPart {
-    Skipped {
-        skipped: bool,
-    },
-    Best {
-        #[serde_as(as = "StringWithSeparator::<SpaceSeparator, Uci>")]
-        #[serde(skip_serializing_if = "Vec::is_empty")]
-        pv: Vec<Uci>,
-        score: Score,
-        depth: u8,
-        nodes: u64,
-        time: u64,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        nps: Option<u32>,
-    },
-    Matrix {
-        #[serde_as(as = "Vec<Vec<Option<Vec<DisplayFromStr>>>>")]
-        pv: Vec<Vec<Option<Vec<Uci>>>>,
-        score: Vec<Vec<Option<Score>>>,
-        depth: u8,
-        nodes: u64,
-        time: u64,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        nps: Option<u32>,
-    },
+struct SubmitQuery {
+    slow: bool,
+    stop: bool,
 }
 
-#[derive(Debug, Serialize, Copy, Clone)]
-pub enum Score {
-    #[serde(rename = "cp")]
-    Cp(i64),
-    #[serde(rename = "mate")]
-    Mate(i64),
+/// Keep this many cancellations for subscribers that are busy.
+const MAX_PENDING_CANCELLATIONS: usize = 64;
+
+/// Batches that the server cancelled while they were in progress, for
+/// example because the analysis is no longer needed. Subscribing does not
+/// keep the actor alive.
+#[derive(Debug, Clone)]
+pub struct Cancellations {
+    tx: broadcast::Sender<BatchId>,
 }
 
-#[derive(Debug, Serialize)]
-struct SubmitQuery {
-    slow: bool,
-    stop: bool,
+impl Cancellations {
+    fn new() -> Cancellations {
+        let (tx, _) = broadcast::channel(MAX_PENDING_CANCELLATIONS);
+        Cancellations { tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<BatchId> {
+        self.tx.subscribe()
+    }
+
+    pub(crate) fn cancel(&self, batch_id: BatchId) {
+        // Nobody to tell, if there are no subscribers.
+        self.tx.send(batch_id).nevermind("no subscribers");
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ApiStub {
     tx: mpsc::UnboundedSender<ApiMessage>,
     endpoint: Endpoint,
+    quota: watch::Receiver<Option<Quota>>,
+    cancellations: Cancellations,
 }
 
 impl ApiStub {
@@ -458,6 +393,16 @@ impl ApiStub {
         &self.endpoint
     }
 
+    pub fn cancellations(&self) -> Cancellations {
+        self.cancellations.clone()
+    }
+
+    /// Request quota announced by the server with the latest response, if
+    /// any, and until it resets.
+    pub fn quota(&self) -> Option<Quota> {
+        self.quota.borrow().filter(Quota::is_current)
+    }
+
     pub async fn check_key(&mut self) -> Option<Result<(), KeyError>> {
         let (req, res) = oneshot::channel();
         self.tx
@@ -474,6 +419,22 @@ impl ApiStub {
         res.await.ok()
     }
 
+    pub async fn probe(&mut self) -> Option<Probe> {
+        let (req, res) = oneshot::channel();
+        self.tx
+            .send(ApiMessage::Probe { callback: req })
+            .expect("api actor alive");
+        res.await.ok()
+    }
+
+    pub async fn standing(&mut self) -> Option<Standing> {
+        let (req, res) = oneshot::channel();
+        self.tx
+            .send(ApiMessage::Standing { callback: req })
+            .expect("api actor alive");
+        res.await.ok()
+    }
+
     pub fn abort(&mut self, batch_id: BatchId) {
         self.tx
             .send(ApiMessage::Abort { batch_id })
@@ -495,17 +456,36 @@ impl ApiStub {
         &mut self,
         batch_id: BatchId,
         flavor: EvalFlavor,
+        node_scale: Option<f64>,
+        cpu_time: Option<Duration>,
         analysis: Vec<Option<AnalysisPart>>,
     ) {
         self.tx
             .send(ApiMessage::SubmitAnalysis {
                 batch_id,
                 flavor,
+                node_scale,
+                cpu_time,
                 analysis,
             })
             .expect("api actor alive");
     }
 
+    pub fn submit_playout(
+        &mut self,
+        batch_id: BatchId,
+        flavor: EvalFlavor,
+        playout: Vec<PlayoutPly>,
+    ) {
+        self.tx
+            .send(ApiMessage::SubmitPlayout {
+                batch_id,
+                flavor,
+                playout,
+            })
+            .expect("api actor alive");
+    }
+
     pub async fn submit_move_and_acquire(
         &mut self,
         batch_id: BatchId,
@@ -523,85 +503,746 @@ impl ApiStub {
     }
 }
 
+fn user_agent() -> String {
+    format!(
+        "{}-{}-{}/{}",
+        env!("CARGO_PKG_NAME"),
+        env::consts::OS,
+        env::consts::ARCH,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+async fn reload_requested(reload: &mut Option<watch::Receiver<()>>) -> Option<()> {
+    reload.as_mut()?.changed().await.ok()
+}
+
+/// Warn if the local clock differs from the server clock by more than this.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+fn server_time(res: &reqwest::Response) -> Option<SystemTime> {
+    res.headers()
+        .get(DATE)
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| httpdate::parse_http_date(date).ok())
+}
+
+/// Converts a time of the server clock to the local clock, given the skew
+/// observed with the last response (positive if the local clock is ahead),
+/// so that hosts with a broken clock still honor the intended delays.
+fn to_local_time(server_time: SystemTime, skew: i64) -> SystemTime {
+    let offset = Duration::from_secs(skew.unsigned_abs());
+    if skew >= 0 {
+        server_time.checked_add(offset)
+    } else {
+        server_time.checked_sub(offset)
+    }
+    .unwrap_or(server_time)
+}
+
+/// Requested delay of a `Retry-After` header, capped to this, in case the
+/// server sends something unreasonable.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Quota of requests announced by the server with `RateLimit-Limit`,
+/// `RateLimit-Remaining` and `RateLimit-Reset` headers, or the same with an
+/// `X-` prefix.
+#[derive(Debug, Copy, Clone)]
+pub struct Quota {
+    pub limit: Option<u64>,
+    pub remaining: u64,
+    /// Remaining requests when the window was first seen, to tell when the
+    /// quota is running low if the server does not announce the limit.
+    pub first_remaining: u64,
+    pub reset_at: Instant,
+}
+
+impl Quota {
+    fn from_response(res: &reqwest::Response, skew: i64) -> Option<Quota> {
+        let header = |name: &str| {
+            [name.to_owned(), format!("x-{}", name)]
+                .iter()
+                .find_map(|name| res.headers().get(name.as_str()))
+                .and_then(|value| value.to_str().ok()?.trim().parse::<u64>().ok())
+        };
+        let remaining = header("ratelimit-remaining")?;
+        let reset = header("ratelimit-reset")?;
+        // Some servers send the time of the reset, rather than the delay.
+        let reset = if reset > 1_000_000_000 {
+            to_local_time(UNIX_EPOCH + Duration::from_secs(reset), skew)
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        } else {
+            Duration::from_secs(reset)
+        };
+        Some(Quota {
+            limit: header("ratelimit-limit"),
+            remaining,
+            first_remaining: remaining,
+            reset_at: Instant::now() + min(reset, MAX_RETRY_AFTER),
+        })
+    }
+
+    /// Carries over what was first seen of the window, if this is a later
+    /// response in the same window.
+    fn following(self, previous: Option<Quota>) -> Quota {
+        match previous {
+            Some(previous)
+                if previous.is_current() && self.remaining <= previous.first_remaining =>
+            {
+                Quota {
+                    first_remaining: previous.first_remaining,
+                    ..self
+                }
+            }
+            _ => self,
+        }
+    }
+
+    pub fn is_current(&self) -> bool {
+        Instant::now() < self.reset_at
+    }
+
+    /// Delay before the next acquire request, so that what is left of the
+    /// quota is spread evenly until it resets. `None` while more than a
+    /// quarter of the limit is left, or of the first remaining requests seen
+    /// in the window, if the limit is not announced.
+    pub fn pace(&self) -> Option<Duration> {
+        let left = self.reset_at.checked_duration_since(Instant::now())?;
+        if self.remaining == 0 {
+            return Some(left);
+        }
+        let low = self.remaining.saturating_mul(4) <= self.limit.unwrap_or(self.first_remaining);
+        low.then(|| left / u32::try_from(self.remaining.saturating_add(1)).unwrap_or(u32::MAX))
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.limit {
+            Some(limit) => write!(f, "{}/{} requests", self.remaining, limit)?,
+            None => write!(f, "{} requests", self.remaining)?,
+        }
+        write!(
+            f,
+            " left for {}s",
+            self.reset_at
+                .saturating_duration_since(Instant::now())
+                .as_secs()
+        )
+    }
+}
+
+fn retry_after(res: &reqwest::Response, skew: i64) -> Option<Duration> {
+    let value = res.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = match value.parse() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => to_local_time(httpdate::parse_http_date(value).ok()?, skew)
+            .duration_since(SystemTime::now())
+            .unwrap_or_default(),
+    };
+    Some(min(delay, MAX_RETRY_AFTER))
+}
+
+/// Random extra delay of up to half the given delay, so that clients that
+/// failed at the same time, for example during a server deploy, do not
+/// all come back at the same time.
+/// Analysis submissions of at least this size are compressed, if the
+/// server accepts it.
+const MIN_COMPRESSED_SIZE: usize = 16 * 1024;
+
+/// Encoding of request bodies. Servers advertise that they accept
+/// compressed request bodies with an Accept-Encoding header in responses
+/// (RFC 7694), and reject them with 415 Unsupported Media Type otherwise.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum RequestEncoding {
+    Identity,
+    Gzip,
+}
+
+impl RequestEncoding {
+    fn advertised(res: &reqwest::Response) -> Option<RequestEncoding> {
+        let accept = res.headers().get(ACCEPT_ENCODING)?.to_str().ok()?;
+        Some(
+            if accept.split(',').any(|coding| {
+                let mut params = coding.split(';').map(str::trim);
+                params.next() == Some("gzip") && params.all(|param| param != "q=0")
+            }) {
+                RequestEncoding::Gzip
+            } else {
+                RequestEncoding::Identity
+            },
+        )
+    }
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).expect("gzip in memory");
+    encoder.finish().expect("gzip in memory")
+}
+
+fn jittered(delay: Duration) -> Duration {
+    delay + rand::thread_rng().gen_range(Duration::ZERO..=delay / 2)
+}
+
+/// Consecutive server errors (5xx) after which all requests are paused.
+const CIRCUIT_BREAKER_THRESHOLD: u32 = 5;
+
+/// Pause when the circuit breaker opens. Doubles every time the first
+/// request after a pause fails again.
+const CIRCUIT_BREAKER_PAUSE: Duration = Duration::from_secs(60);
+
+const CIRCUIT_BREAKER_MAX_PAUSE: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug)]
+struct CircuitBreaker {
+    server_errors: u32,
+    pause: Duration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker {
+            server_errors: 0,
+            pause: CIRCUIT_BREAKER_PAUSE,
+        }
+    }
+}
+
+impl CircuitBreaker {
+    fn success(&mut self) {
+        *self = CircuitBreaker::default();
+    }
+
+    /// Records a server error, and returns how long to pause all requests
+    /// if the circuit opens. After the pause, a single failed request is
+    /// enough to open it again.
+    fn server_error(&mut self) -> Option<Duration> {
+        self.server_errors += 1;
+        if self.server_errors < CIRCUIT_BREAKER_THRESHOLD {
+            return None;
+        }
+        let pause = self.pause;
+        self.pause = min(self.pause * 2, CIRCUIT_BREAKER_MAX_PAUSE);
+        Some(jittered(pause))
+    }
+}
+
+/// Wait this long for a batch pushed through the websocket, before
+/// reporting that no job was received.
+const PUSH_WAIT: Duration = Duration::from_secs(25);
+
+/// Use long polling for this long after the websocket could not be
+/// established or failed.
+const WEBSOCKET_RETRY: Duration = Duration::from_secs(10 * 60);
+
+type PushSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Websocket for acquiring work, where the server pushes a batch as soon as
+/// one is available, instead of answering repeated long polling requests.
+enum PushState {
+    Disabled,
+    Disconnected {
+        retry_at: Instant,
+    },
+    Connected {
+        socket: PushSocket,
+        /// An acquire request was sent, but no batch has been received for
+        /// it yet.
+        waiting: bool,
+    },
+}
+
+/// Message pushed by the server through the websocket: a batch for the
+/// outstanding acquire request, or the cancellation of a batch in progress.
+#[serde_as]
+#[derive(Deserialize)]
+#[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
+enum PushMessage {
+    Abort {
+        #[serde_as(as = "DisplayFromStr")]
+        abort: BatchId,
+    },
+    Work(AcquireResponseBody),
+}
+
+#[derive(Serialize)]
+struct PushRequestBody<'a> {
+    fishnet: Fishnet,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    variants: &'a [VariantCapability],
+    #[serde(flatten)]
+    query: &'a AcquireQuery,
+}
+
+/// Sends the acquire request (unless one is still outstanding from an
+/// earlier call) and waits for a pushed batch.
+async fn wait_for_push(
+    socket: &mut PushSocket,
+    waiting: &mut bool,
+    request: String,
+    cancellations: &Cancellations,
+    logger: &Logger,
+) -> Result<Acquired, WsError> {
+    if !*waiting {
+        socket.send(Message::Text(request)).await?;
+        *waiting = true;
+    }
+    let deadline = time::sleep(PUSH_WAIT);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => return Ok(Acquired::NoContent),
+            msg = socket.next() => match msg {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(PushMessage::Abort { abort }) => {
+                        logger.debug(&format!("Server cancelled batch {}", abort));
+                        cancellations.cancel(abort);
+                    }
+                    Ok(PushMessage::Work(body)) => {
+                        *waiting = false;
+                        return Ok(Acquired::Accepted(body));
+                    }
+                    Err(err) => return Err(WsError::Io(io::Error::new(io::ErrorKind::InvalidData, err))),
+                },
+                Some(Ok(Message::Close(Some(frame)))) if frame.code == CloseCode::Policy => {
+                    logger.error(&format!("Server rejected request: {}", frame.reason));
+                    return Ok(Acquired::Rejected);
+                }
+                Some(Ok(Message::Close(_))) | None => return Err(WsError::ConnectionClosed),
+                Some(Ok(_)) => (), // Pings are answered while reading.
+                Some(Err(err)) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Client certificate and private key for mutual TLS, as PEM files.
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl ClientCert {
+    fn load(&self) -> io::Result<(Vec<rustls::Certificate>, rustls::PrivateKey)> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let certs: Vec<_> = rustls_pemfile::certs(&mut BufReader::new(File::open(&self.cert)?))?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+        if certs.is_empty() {
+            return Err(invalid(format!("no certificate in {:?}", self.cert)));
+        }
+        let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(&self.key)?))?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .ok_or_else(|| invalid(format!("no private key in {:?}", self.key)))?;
+        Ok((certs, rustls::PrivateKey(key)))
+    }
+}
+
+/// Builds the HTTP client, and the TLS configuration for the websocket.
+fn build_client(
+    key: &Option<Key>,
+    proxy: &Option<Proxy>,
+    client_cert: Option<&ClientCert>,
+    ip_preference: IpPreference,
+) -> io::Result<(reqwest::Client, Arc<rustls::ClientConfig>)> {
+    // Build TLS backend that supports SSLKEYLOGFILE.
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let tls = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store);
+    let mut tls = match client_cert {
+        Some(client_cert) => {
+            let (certs, private_key) = client_cert.load()?;
+            tls.with_single_cert(certs, private_key)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+        }
+        None => tls.with_no_client_auth(),
+    };
+    tls.alpn_protocols = vec!["h2".into(), "http/1.1".into()];
+    tls.key_log = Arc::new(rustls::KeyLogFile::new());
+
+    let mut client = reqwest::Client::builder()
+        .default_headers(
+            key.iter()
+                .map(|Key(k)| {
+                    (AUTHORIZATION, {
+                        let mut value = HeaderValue::from_str(&format!("Bearer {}", k))
+                            .expect("bearer authorization");
+                        value.set_sensitive(true);
+                        value
+                    })
+                })
+                .collect(),
+        )
+        .user_agent(user_agent())
+        .timeout(Duration::from_secs(30))
+        .pool_idle_timeout(Duration::from_secs(25))
+        .dns_resolver(Arc::new(Resolver {
+            preference: ip_preference,
+        }))
+        .use_preconfigured_tls(tls.clone());
+    // Replaces proxies from HTTP_PROXY and HTTPS_PROXY. Long polling
+    // requests go through the proxy as well.
+    if let Some(Proxy { ref url }) = proxy {
+        client = client.proxy(reqwest::Proxy::all(url.clone()).expect("supported proxy"));
+    }
+
+    // The websocket handshake needs HTTP/1.1.
+    let mut ws_tls = tls;
+    ws_tls.alpn_protocols = vec!["http/1.1".into()];
+
+    Ok((
+        client
+            .build()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?,
+        Arc::new(ws_tls),
+    ))
+}
+
 pub struct ApiActor {
     rx: mpsc::UnboundedReceiver<ApiMessage>,
     endpoint: Endpoint,
     key: Option<Key>,
     client: reqwest::Client,
+    tls: Arc<rustls::ClientConfig>,
+    proxy: Option<Proxy>,
+    proxied: bool,
+    client_cert: Option<ClientCert>,
+    /// Reloads the client certificate when changed, for example on SIGHUP.
+    client_cert_reload: Option<watch::Receiver<()>>,
+    ip_preference: IpPreference,
+    push: PushState,
+    calibration: Option<Calibration>,
     error_backoff: RandomizedBackoff,
+    /// Delay requested by the server with the last response, if any.
+    retry_after: Option<Duration>,
+    /// Request quota announced by the server, shared with the stubs.
+    quota: watch::Sender<Option<Quota>>,
+    cancellations: Cancellations,
+    circuit_breaker: CircuitBreaker,
+    /// Seconds that the local clock was ahead of the server clock (or
+    /// behind, if negative), as of the last response with a Date header.
+    clock_skew: i64,
+    worker_name: Option<String>,
+    labels: BTreeMap<String, String>,
+    /// Sent with acquire requests, if known.
+    variants: Vec<VariantCapability>,
+    /// Variants enabled for this endpoint. Narrows the variants of acquire
+    /// requests.
+    variant_filter: VariantFilter,
+    /// Failed analysis submissions to retry.
+    outbox: Outbox,
+    /// Engine builds to report with analysis, if deterministic.
+    engine_builds: Option<ByEngineFlavor<Option<String>>>,
+    request_encoding: RequestEncoding,
     logger: Logger,
 }
 
 impl ApiActor {
     fn new(
         rx: mpsc::UnboundedReceiver<ApiMessage>,
+        quota: watch::Sender<Option<Quota>>,
+        cancellations: Cancellations,
         endpoint: Endpoint,
         key: Option<Key>,
+        proxy: Option<Proxy>,
         logger: Logger,
     ) -> ApiActor {
-        // Build TLS backend that supports SSLKEYLOGFILE.
-        let mut root_store = rustls::RootCertStore::empty();
-        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject,
-                ta.spki,
-                ta.name_constraints,
-            )
-        }));
-        let mut tls = rustls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-        tls.alpn_protocols = vec!["h2".into(), "http/1.1".into()];
-        tls.key_log = Arc::new(rustls::KeyLogFile::new());
-
+        let (client, tls) =
+            build_client(&key, &proxy, None, IpPreference::default()).expect("client");
         ApiActor {
             rx,
+            outbox: Outbox::new(&endpoint),
+            engine_builds: None,
+            request_encoding: RequestEncoding::Identity,
             endpoint,
-            client: reqwest::Client::builder()
-                .default_headers(
-                    key.iter()
-                        .map(|Key(k)| {
-                            (AUTHORIZATION, {
-                                let mut value = HeaderValue::from_str(&format!("Bearer {}", k))
-                                    .expect("bearer authorization");
-                                value.set_sensitive(true);
-                                value
-                            })
-                        })
-                        .collect(),
-                )
-                .user_agent(format!(
-                    "{}-{}-{}/{}",
-                    env!("CARGO_PKG_NAME"),
-                    env::consts::OS,
-                    env::consts::ARCH,
-                    env!("CARGO_PKG_VERSION")
-                ))
-                .timeout(Duration::from_secs(30))
-                .pool_idle_timeout(Duration::from_secs(25))
-                .use_preconfigured_tls(tls)
-                .build()
-                .expect("client"),
+            client,
+            tls,
+            proxied: proxy.is_some(),
+            proxy,
+            client_cert: None,
+            client_cert_reload: None,
+            ip_preference: IpPreference::default(),
+            push: PushState::Disabled,
+            calibration: None,
             key,
             error_backoff: RandomizedBackoff::default(),
+            retry_after: None,
+            quota,
+            cancellations,
+            circuit_breaker: CircuitBreaker::default(),
+            clock_skew: 0,
+            worker_name: None,
+            labels: BTreeMap::new(),
+            variants: Vec::new(),
+            variant_filter: VariantFilter::default(),
             logger,
         }
     }
 
+    /// Compares the local clock with the Date header of the response.
+    fn observe_clock(&mut self, res: &reqwest::Response) {
+        let server_time = match server_time(res) {
+            Some(server_time) => server_time,
+            None => return,
+        };
+        let skew = match SystemTime::now().duration_since(server_time) {
+            Ok(ahead) => ahead.as_secs() as i64,
+            Err(behind) => -(behind.duration().as_secs() as i64),
+        };
+        METRICS.clock_skew.set(skew);
+        let skewed = skew.unsigned_abs() > MAX_CLOCK_SKEW.as_secs();
+        let was_skewed = self.clock_skew.unsigned_abs() > MAX_CLOCK_SKEW.as_secs();
+        if skewed && !was_skewed {
+            self.logger.warn(&format!(
+                "Local clock is {}s {} the server clock. Correcting times sent by the server, but enable time synchronization (for example NTP).",
+                skew.unsigned_abs(),
+                if skew > 0 { "ahead of" } else { "behind" }
+            ));
+        } else if !skewed && was_skewed {
+            self.logger
+                .info("Local clock is in sync with the server clock again.");
+        }
+        self.clock_skew = skew;
+    }
+
+    /// Acquires work through a websocket, if the server supports it, and
+    /// falls back to long polling otherwise.
+    pub fn websocket(mut self, enabled: bool) -> ApiActor {
+        if enabled && self.proxied {
+            self.logger
+                .warn("Websocket is not supported through a proxy. Using long polling.");
+        } else if enabled {
+            self.push = PushState::Disconnected {
+                retry_at: Instant::now(),
+            };
+        }
+        self
+    }
+
+    /// Tells the server which variants can be analysed, and how, with each
+    /// acquire request.
+    pub fn variants(mut self, variants: Vec<VariantCapability>) -> ApiActor {
+        self.variants = variants;
+        self
+    }
+
+    /// Asks only for variants that are enabled for this endpoint, so that
+    /// batches of other variants are not acquired just to be aborted.
+    pub fn variant_filter(mut self, variant_filter: VariantFilter) -> ApiActor {
+        self.variant_filter = variant_filter;
+        self
+    }
+
+    fn endpoint_query(&self, mut query: AcquireQuery) -> AcquireQuery {
+        let mut filter = self.variant_filter.clone();
+        if let Some(variants) = query
+            .variants
+            .as_ref()
+            .and_then(|variants| variants.parse::<VariantList>().ok())
+        {
+            filter.restrict(Some(&variants), None);
+        }
+        query.variants = filter.accepted().map(|variants| variants.to_string());
+        query
+    }
+
+    /// Reports the engine builds with analysis, so that deterministic
+    /// results can be reproduced with the same builds.
+    pub fn engine_builds(
+        mut self,
+        engine_builds: Option<ByEngineFlavor<Option<String>>>,
+    ) -> ApiActor {
+        self.engine_builds = engine_builds;
+        self
+    }
+
+    /// Reports the speed measured by `fishnet benchmark` with analysis.
+    pub fn calibration(mut self, calibration: Option<Calibration>) -> ApiActor {
+        self.calibration = calibration;
+        self
+    }
+
+    /// Identifies this machine to the server, with each request and in the
+    /// user agent.
+    pub fn worker(mut self, name: Option<String>, labels: Option<Labels>) -> ApiActor {
+        self.worker_name = name;
+        self.labels = labels
+            .map(|Labels(labels)| {
+                labels
+                    .into_iter()
+                    .map(|label| (label.key, label.value))
+                    .collect()
+            })
+            .unwrap_or_default();
+        self
+    }
+
+    fn fishnet(&self) -> Fishnet {
+        Fishnet {
+            version: env!("CARGO_PKG_VERSION"),
+            apikey: self.key.as_ref().map_or("".to_owned(), |k| k.0.clone()),
+            capabilities: CAPABILITIES,
+            name: self.worker_name.clone(),
+            labels: self.labels.clone(),
+        }
+    }
+
+    fn stockfish(
+        &self,
+        flavor: EvalFlavor,
+        node_scale: Option<f64>,
+        cpu_time: Option<Duration>,
+    ) -> Stockfish {
+        Stockfish {
+            flavor,
+            node_scale,
+            cpu_seconds: cpu_time.map(|cpu_time| cpu_time.as_secs_f64()),
+            benchmark: self.calibration,
+            build: self.engine_builds.as_ref().and_then(|builds| {
+                builds
+                    .get(match flavor {
+                        EvalFlavor::Nnue => EngineFlavor::Official,
+                        EvalFlavor::Hce => EngineFlavor::MultiVariant,
+                    })
+                    .clone()
+            }),
+        }
+    }
+
+    /// Authenticates with a client certificate. If it cannot be loaded, the
+    /// error is logged and the previous client is kept, so that requests
+    /// fail visibly rather than silently.
+    pub fn client_cert(
+        mut self,
+        client_cert: Option<ClientCert>,
+        reload: Option<watch::Receiver<()>>,
+    ) -> ApiActor {
+        self.client_cert = client_cert;
+        self.client_cert_reload = reload.filter(|_| self.client_cert.is_some());
+        if self.client_cert.is_some() {
+            self.rebuild_client();
+        }
+        self
+    }
+
+    /// Address family to try first, for requests and the websocket.
+    pub fn ip_preference(mut self, ip_preference: IpPreference) -> ApiActor {
+        self.ip_preference = ip_preference;
+        if ip_preference != IpPreference::default() {
+            self.rebuild_client();
+        }
+        self
+    }
+
+    fn rebuild_client(&mut self) {
+        match build_client(
+            &self.key,
+            &self.proxy,
+            self.client_cert.as_ref(),
+            self.ip_preference,
+        ) {
+            Ok((client, tls)) => {
+                self.client = client;
+                self.tls = tls;
+                // Reconnect with the new certificate.
+                if let PushState::Connected { .. } = self.push {
+                    self.push = PushState::Disconnected {
+                        retry_at: Instant::now(),
+                    };
+                }
+            }
+            Err(err) => self.logger.error(&format!(
+                "Failed to load client certificate {:?}: {}",
+                self.client_cert.as_ref().map(|c| &c.cert),
+                err
+            )),
+        }
+    }
+
+    /// Maximum backoff after failed requests, if not the default.
+    pub fn max_error_backoff(mut self, max_error_backoff: Option<Duration>) -> ApiActor {
+        if let Some(max_error_backoff) = max_error_backoff {
+            self.error_backoff = RandomizedBackoff::new(max_error_backoff);
+        }
+        self
+    }
+
+    /// Spills failed analysis submissions to this directory, once too many
+    /// are waiting for a retry, and retries those left by a previous run.
+    pub fn outbox_dir(mut self, dir: PathBuf) -> ApiActor {
+        self.outbox = Outbox::new(&self.endpoint).spill_dir(dir, &self.logger);
+        self
+    }
+
     pub async fn run(mut self) {
         self.logger.debug("Api actor started");
-        while let Some(msg) = self.rx.recv().await {
-            self.handle_message(msg).await;
+        loop {
+            tokio::select! {
+                msg = self.rx.recv() => match msg {
+                    Some(msg) => self.handle_message(msg).await,
+                    None => break,
+                },
+                submission = self.outbox.next(&self.logger) => {
+                    self.handle_message(ApiMessage::RetryAnalysis { submission }).await;
+                }
+                Some(()) = reload_requested(&mut self.client_cert_reload) => {
+                    self.logger.fishnet_info("Reloading client certificate");
+                    self.rebuild_client();
+                }
+            }
         }
+        self.outbox.persist(&self.logger);
         self.logger.debug("Api actor exited");
     }
 
     async fn handle_message(&mut self, msg: ApiMessage) {
-        if let Err(err) = self.handle_message_inner(msg).await {
-            if err.status().map_or(false, |s| s.is_success()) {
+        let started = Instant::now();
+        let res = self.handle_message_inner(msg).await;
+        METRICS.api_latency.observe(started.elapsed());
+        let retry_after = self.retry_after.take();
+        if let Err(err) = res {
+            METRICS.api_errors.inc();
+            let status = err.status();
+            if status.map_or(false, |s| s.is_server_error()) {
+                if let Some(pause) = self.circuit_breaker.server_error() {
+                    let pause = retry_after.map_or(pause, |retry_after| retry_after.max(pause));
+                    self.logger.error(&format!(
+                        "{}. Server failed {} times in a row. Suspending requests for {:?}.",
+                        err, CIRCUIT_BREAKER_THRESHOLD, pause
+                    ));
+                    time::sleep(pause).await;
+                    return;
+                }
+            }
+            if status.map_or(false, |s| s.is_success()) {
                 self.error_backoff.reset();
-            } else if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) {
+            } else if let Some(retry_after) = retry_after {
+                let backoff = jittered(retry_after);
+                self.logger.error(&format!(
+                    "{}. Server asked to retry later. Suspending requests for {:?}.",
+                    err, backoff
+                ));
+                time::sleep(backoff).await;
+            } else if status == Some(StatusCode::TOO_MANY_REQUESTS) {
                 let backoff = Duration::from_secs(60) + self.error_backoff.next();
                 self.logger.error(&format!(
                     "Too many requests. Suspending requests for {:?}.",
@@ -616,19 +1257,122 @@ impl ApiActor {
             }
         } else {
             self.error_backoff.reset();
+            self.circuit_breaker.success();
+        }
+    }
+
+    /// Sends a request, observes the server clock, and remembers if the
+    /// server asks to retry later.
+    async fn send(
+        &mut self,
+        request: reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        let request = match self.worker_name {
+            Some(ref name) => request.header(USER_AGENT, format!("{} ({})", user_agent(), name)),
+            None => request,
+        };
+        let res = request.send().await?;
+        self.observe_clock(&res);
+        if matches!(
+            res.status(),
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        ) {
+            self.retry_after = retry_after(&res, self.clock_skew);
+        }
+        if let Some(quota) = Quota::from_response(&res, self.clock_skew) {
+            let quota = quota.following(*self.quota.borrow());
+            self.quota.send(Some(quota)).nevermind("no stubs");
+        }
+        if let Some(encoding) = RequestEncoding::advertised(&res) {
+            if encoding != self.request_encoding {
+                self.logger
+                    .debug(&format!("Server accepts {:?} request bodies", encoding));
+            }
+            self.request_encoding = encoding;
+        }
+        Ok(res)
+    }
+
+    /// Submits analysis. If the request fails in a way that may be
+    /// temporary, the submission is kept in the outbox for a retry.
+    async fn submit_analysis(&mut self, submission: Submission) -> reqwest::Result<()> {
+        let url = format!("{}/analysis/{}", self.endpoint, submission.batch_id);
+        let body = serde_json::to_vec(&AnalysisRequestBody {
+            fishnet: self.fishnet(),
+            submission: &submission.body,
+        })
+        .expect("serialize analysis");
+        let res = loop {
+            let request = self
+                .client
+                .post(&url)
+                .query(&SubmitQuery {
+                    stop: true,
+                    slow: false,
+                })
+                .header(CONTENT_TYPE, "application/json");
+            let compressed =
+                self.request_encoding == RequestEncoding::Gzip && body.len() >= MIN_COMPRESSED_SIZE;
+            let request = if compressed {
+                let gzipped = gzip(&body);
+                self.logger.debug(&format!(
+                    "Compressed submission of batch {} from {} to {} bytes",
+                    submission.batch_id,
+                    body.len(),
+                    gzipped.len()
+                ));
+                request.header(CONTENT_ENCODING, "gzip").body(gzipped)
+            } else {
+                request.body(body.clone())
+            };
+            match self.send(request).await {
+                Ok(res) if compressed && res.status() == StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+                    self.logger.warn(
+                        "Server does not accept compressed submissions. Sending them uncompressed.",
+                    );
+                    self.request_encoding = RequestEncoding::Identity;
+                }
+                res => break res.and_then(|res| res.error_for_status()),
+            }
+        };
+        let res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                let temporary = err.status().map_or(true, |status| {
+                    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+                });
+                if temporary {
+                    self.logger.warn(&format!(
+                        "Failed to submit batch {}. Will retry ({} waiting).",
+                        submission.batch_id,
+                        self.outbox.len() + 1
+                    ));
+                    self.outbox.push(submission, &self.logger);
+                }
+                return Err(err);
+            }
+        };
+
+        // Earlier submissions of the batch that are still waiting for a
+        // retry, like progress reports, are now obsolete.
+        self.outbox.remove(submission.batch_id);
+
+        if res.status() != StatusCode::NO_CONTENT {
+            self.logger.warn(&format!(
+                "Unexpected status for submitting analysis: {}",
+                res.status()
+            ));
         }
+        Ok(())
     }
 
     async fn abort(&mut self, batch_id: BatchId) -> reqwest::Result<()> {
         let url = format!("{}/abort/{}", self.endpoint, batch_id);
         self.logger.warn(&format!("Aborting batch {}.", batch_id));
         let res = self
-            .client
-            .post(&url)
-            .json(&VoidRequestBody {
-                fishnet: Fishnet::authenticated(self.key.clone()),
-            })
-            .send()
+            .send(self.client.post(&url).json(&VoidRequestBody {
+                fishnet: self.fishnet(),
+            }))
             .await?;
 
         if res.status() == StatusCode::NOT_FOUND {
@@ -642,11 +1386,139 @@ impl ApiActor {
         }
     }
 
+    async fn connect_websocket(&self) -> Result<PushSocket, WsError> {
+        let url = format!("{}/acquire/socket", self.endpoint).replacen("http", "ws", 1);
+        let mut request = url.into_client_request()?;
+        if let Some(Key(ref key)) = self.key {
+            let mut value =
+                HeaderValue::from_str(&format!("Bearer {}", key)).expect("bearer authorization");
+            value.set_sensitive(true);
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+        let uri = request.uri();
+        let host = uri
+            .host()
+            .ok_or(WsError::Url(UrlError::NoHostName))?
+            .to_owned();
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("wss") => 443,
+            _ => 80,
+        });
+        let connect = async {
+            let stream = happy_eyeballs::connect(&host, port, self.ip_preference).await?;
+            tokio_tungstenite::client_async_tls_with_config(
+                request,
+                stream,
+                None,
+                Some(Connector::Rustls(self.tls.clone())),
+            )
+            .await
+        };
+        match time::timeout(Duration::from_secs(10), connect).await {
+            Ok(res) => res.map(|(socket, _)| socket),
+            Err(_) => Err(WsError::Io(io::ErrorKind::TimedOut.into())),
+        }
+    }
+
+    /// Returns `None` if the websocket is disabled or unavailable, so that
+    /// the caller should use long polling instead.
+    async fn acquire_via_websocket(&mut self, query: &AcquireQuery) -> Option<Acquired> {
+        if let PushState::Disconnected { retry_at } = self.push {
+            if Instant::now() < retry_at {
+                return None;
+            }
+            match self.connect_websocket().await {
+                Ok(socket) => {
+                    self.logger.info("Acquiring work through websocket");
+                    self.push = PushState::Connected {
+                        socket,
+                        waiting: false,
+                    };
+                }
+                Err(err) => {
+                    self.logger.debug(&format!(
+                        "Websocket unavailable: {}. Using long polling for {:?}.",
+                        err, WEBSOCKET_RETRY
+                    ));
+                    self.push = PushState::Disconnected {
+                        retry_at: Instant::now() + WEBSOCKET_RETRY,
+                    };
+                    return None;
+                }
+            }
+        }
+
+        let request = serde_json::to_string(&PushRequestBody {
+            fishnet: self.fishnet(),
+            variants: &self.variants,
+            query,
+        })
+        .expect("serialize acquire request");
+        let res = match self.push {
+            PushState::Connected {
+                ref mut socket,
+                ref mut waiting,
+            } => wait_for_push(socket, waiting, request, &self.cancellations, &self.logger).await,
+            _ => return None,
+        };
+        match res {
+            Ok(acquired) => Some(acquired),
+            Err(err) => {
+                self.logger.warn(&format!(
+                    "Websocket failed: {}. Using long polling for {:?}.",
+                    err, WEBSOCKET_RETRY
+                ));
+                self.push = PushState::Disconnected {
+                    retry_at: Instant::now() + WEBSOCKET_RETRY,
+                };
+                None
+            }
+        }
+    }
+
+    async fn acquire_via_long_poll(
+        &mut self,
+        query: &AcquireQuery,
+    ) -> reqwest::Result<Option<Acquired>> {
+        let url = format!("{}/acquire", self.endpoint);
+        let res = self
+            .send(
+                self.client
+                    .post(&url)
+                    .query(query)
+                    .json(&AcquireRequestBody {
+                        fishnet: self.fishnet(),
+                        variants: &self.variants,
+                    }),
+            )
+            .await?;
+
+        Ok(match res.status() {
+            StatusCode::NO_CONTENT => Some(Acquired::NoContent),
+            StatusCode::BAD_REQUEST
+            | StatusCode::UNAUTHORIZED
+            | StatusCode::FORBIDDEN
+            | StatusCode::NOT_ACCEPTABLE => {
+                let text = res.text().await?;
+                self.logger
+                    .error(&format!("Server rejected request: {}", text));
+                Some(Acquired::Rejected)
+            }
+            StatusCode::OK | StatusCode::ACCEPTED => Some(Acquired::Accepted(res.json().await?)),
+            status => {
+                self.logger
+                    .warn(&format!("Unexpected status for acquire: {}", status));
+                res.error_for_status()?;
+                None
+            }
+        })
+    }
+
     async fn handle_message_inner(&mut self, msg: ApiMessage) -> reqwest::Result<()> {
         match msg {
             ApiMessage::CheckKey { callback } => {
                 let url = format!("{}/key", self.endpoint);
-                let res = self.client.get(&url).send().await?;
+                let res = self.send(self.client.get(&url)).await?;
                 match res.status() {
                     StatusCode::NO_CONTENT | StatusCode::OK => {
                         callback.send(Ok(())).nevermind("callback dropped");
@@ -664,7 +1536,7 @@ impl ApiActor {
                             self.endpoint,
                             self.key.as_ref().map_or("", |k| &k.0)
                         );
-                        let res = self.client.get(&url).send().await?;
+                        let res = self.send(self.client.get(&url)).await?;
                         match res.status() {
                             StatusCode::NOT_FOUND => callback
                                 .send(Err(KeyError::AccessDenied))
@@ -688,11 +1560,17 @@ impl ApiActor {
             }
             ApiMessage::Status { callback } => {
                 let url = format!("{}/status", self.endpoint);
-                let res = self.client.get(&url).send().await?;
+                let res = self.send(self.client.get(&url)).await?;
                 match res.status() {
-                    StatusCode::OK => callback
-                        .send(res.json::<StatusResponseBody>().await?.analysis)
-                        .nevermind("callback dropped"),
+                    StatusCode::OK => {
+                        let body = res.json::<StatusResponseBody>().await?;
+                        for batch_id in body.abort {
+                            self.logger
+                                .debug(&format!("Server cancelled batch {}", batch_id));
+                            self.cancellations.cancel(batch_id);
+                        }
+                        callback.send(body.analysis).nevermind("callback dropped");
+                    }
                     StatusCode::NOT_FOUND => (),
                     status => {
                         self.logger
@@ -701,80 +1579,82 @@ impl ApiActor {
                     }
                 }
             }
-            ApiMessage::Abort { batch_id } => {
-                self.abort(batch_id).await?;
-            }
-            ApiMessage::Acquire { callback, query } => {
-                let url = format!("{}/acquire", self.endpoint);
-                let res = self
-                    .client
-                    .post(&url)
-                    .query(&query)
-                    .json(&VoidRequestBody {
-                        fishnet: Fishnet::authenticated(self.key.clone()),
+            ApiMessage::Probe { callback } => {
+                let url = format!("{}/status", self.endpoint);
+                let started = Instant::now();
+                let res = self.send(self.client.get(&url)).await?;
+                callback
+                    .send(Probe {
+                        status: res.status(),
+                        latency: started.elapsed(),
+                        server_time: server_time(&res),
                     })
-                    .send()
-                    .await?;
-
+                    .nevermind("callback dropped");
+            }
+            ApiMessage::Standing { callback } => {
+                let url = format!("{}/providers", self.endpoint);
+                let res = self.send(self.client.get(&url)).await?;
                 match res.status() {
-                    StatusCode::NO_CONTENT => callback
-                        .send(Acquired::NoContent)
+                    StatusCode::OK => callback
+                        .send(res.json().await?)
                         .nevermind("callback dropped"),
-                    StatusCode::BAD_REQUEST
-                    | StatusCode::UNAUTHORIZED
-                    | StatusCode::FORBIDDEN
-                    | StatusCode::NOT_ACCEPTABLE => {
-                        let text = res.text().await?;
+                    StatusCode::NOT_FOUND => {
                         self.logger
-                            .error(&format!("Server rejected request: {}", text));
-                        callback
-                            .send(Acquired::Rejected)
-                            .nevermind("callback dropped");
+                            .debug("Fishnet server does not provide a leaderboard");
                     }
-                    StatusCode::OK | StatusCode::ACCEPTED => {
-                        if let Err(Acquired::Accepted(res)) =
-                            callback.send(Acquired::Accepted(res.json().await?))
+                    status => {
+                        self.logger
+                            .warn(&format!("Unexpected status for leaderboard: {}", status));
+                        res.error_for_status()?;
+                    }
+                }
+            }
+            ApiMessage::Abort { batch_id } => {
+                self.abort(batch_id).await?;
+            }
+            ApiMessage::Acquire { callback, query } => {
+                let query = self.endpoint_query(query);
+                let acquired = match self.acquire_via_websocket(&query).await {
+                    Some(acquired) => Some(acquired),
+                    None => self.acquire_via_long_poll(&query).await?,
+                };
+                match acquired {
+                    Some(Acquired::Accepted(body)) => {
+                        if let Err(Acquired::Accepted(body)) =
+                            callback.send(Acquired::Accepted(body))
                         {
                             self.logger
                                 .error("Acquired a batch, but callback dropped. Aborting.");
-                            self.abort(res.work.id()).await?;
+                            self.abort(body.work.id()).await?;
                         }
                     }
-                    status => {
-                        self.logger
-                            .warn(&format!("Unexpected status for acquire: {}", status));
-                        res.error_for_status()?;
-                    }
+                    Some(acquired) => callback.send(acquired).nevermind("callback dropped"),
+                    None => (),
                 }
             }
             ApiMessage::SubmitAnalysis {
                 batch_id,
                 flavor,
+                node_scale,
+                cpu_time,
                 analysis,
             } => {
-                let url = format!("{}/analysis/{}", self.endpoint, batch_id);
-                let res = self
-                    .client
-                    .post(&url)
-                    .query(&SubmitQuery {
-                        stop: true,
-                        slow: false,
-                    })
-                    .json(&AnalysisRequestBody {
-                        fishnet: Fishnet::authenticated(self.key.clone()),
-                        stockfish: Stockfish { flavor },
-                        analysis,
-                    })
-                    .send()
-                    .await?
-                    .error_for_status()?;
-
-                if res.status() != StatusCode::NO_CONTENT {
-                    self.logger.warn(&format!(
-                        "Unexpected status for submitting analysis: {}",
-                        res.status()
-                    ));
-                }
+                let body = serde_json::to_value(AnalysisSubmission {
+                    stockfish: self.stockfish(flavor, node_scale, cpu_time),
+                    analysis,
+                })
+                .expect("serialize analysis");
+                let submission = Submission::new(&self.endpoint, batch_id, body);
+                self.submit_analysis(submission).await?;
+            }
+            ApiMessage::RetryAnalysis { submission } => {
+                let (batch_id, attempts) = (submission.batch_id, submission.attempts);
+                self.submit_analysis(submission).await?;
+                self.outbox.succeeded();
+                self.logger.info(&format!(
+                    "Submitted batch {} after {} failed attempts",
+                    batch_id, attempts
+                ));
             }
             ApiMessage::SubmitMove {
                 batch_id,
@@ -783,15 +1663,12 @@ impl ApiActor {
             } => {
                 let url = format!("{}/move/{}", self.endpoint, batch_id);
                 let res = self
-                    .client
-                    .post(&url)
-                    .json(&MoveRequestBody {
-                        fishnet: Fishnet::authenticated(self.key.clone()),
+                    .send(self.client.post(&url).json(&MoveRequestBody {
+                        fishnet: self.fishnet(),
                         m: BestMove {
                             best_move: best_move.clone(),
                         },
-                    })
-                    .send()
+                    }))
                     .await?;
 
                 match res.status() {
@@ -817,8 +1694,188 @@ impl ApiActor {
                     }
                 }
             }
+            ApiMessage::SubmitPlayout {
+                batch_id,
+                flavor,
+                playout,
+            } => {
+                // Play-outs are explored interactively, so there is no
+                // point in retrying them later.
+                let url = format!("{}/playout/{}", self.endpoint, batch_id);
+                let res = self
+                    .send(self.client.post(&url).json(&PlayoutRequestBody {
+                        fishnet: self.fishnet(),
+                        stockfish: self.stockfish(flavor, None, None),
+                        playout,
+                    }))
+                    .await?;
+                if res.status() != StatusCode::NO_CONTENT {
+                    self.logger.warn(&format!(
+                        "Unexpected status for submitting play-out {}: {}",
+                        batch_id,
+                        res.status()
+                    ));
+                    res.error_for_status()?;
+                }
+            }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(headers: &[(&str, &str)]) -> reqwest::Response {
+        let mut builder = hyper::Response::builder();
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        reqwest::Response::from(builder.body("").unwrap())
+    }
+
+    fn quota(limit: Option<u64>, remaining: u64, reset: Duration) -> Quota {
+        Quota {
+            limit,
+            remaining,
+            first_remaining: remaining,
+            reset_at: Instant::now() + reset,
+        }
+    }
+
+    #[test]
+    fn test_quota_from_response() {
+        let quota = Quota::from_response(
+            &response(&[
+                ("RateLimit-Limit", "100"),
+                ("RateLimit-Remaining", "40"),
+                ("RateLimit-Reset", "30"),
+            ]),
+            0,
+        )
+        .unwrap();
+        assert_eq!(quota.limit, Some(100));
+        assert_eq!(quota.remaining, 40);
+        assert!(quota.reset_at > Instant::now() + Duration::from_secs(29));
+        assert!(quota.is_current());
+
+        let quota = Quota::from_response(
+            &response(&[("X-RateLimit-Remaining", "5"), ("X-RateLimit-Reset", "10")]),
+            0,
+        )
+        .unwrap();
+        assert_eq!(quota.limit, None);
+        assert_eq!(quota.remaining, 5);
+
+        // Time of the reset rather than the delay, capped.
+        let reset_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+            + Duration::from_secs(7 * 24 * 60 * 60);
+        let quota = Quota::from_response(
+            &response(&[
+                ("RateLimit-Remaining", "5"),
+                ("RateLimit-Reset", &reset_at.as_secs().to_string()),
+            ]),
+            0,
+        )
+        .unwrap();
+        assert!(quota.reset_at <= Instant::now() + MAX_RETRY_AFTER);
+
+        assert!(Quota::from_response(&response(&[("RateLimit-Remaining", "5")]), 0).is_none());
+        assert!(Quota::from_response(
+            &response(&[("RateLimit-Remaining", "many"), ("RateLimit-Reset", "10"),]),
+            0
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_quota_pace() {
+        let reset = Duration::from_secs(100);
+        assert_eq!(quota(Some(100), 50, reset).pace(), None);
+        assert_eq!(quota(Some(100), 26, reset).pace(), None);
+
+        let pace = quota(Some(100), 9, reset).pace().unwrap();
+        assert!(pace <= Duration::from_secs(10) && pace > Duration::from_secs(9));
+
+        // Wait for the reset once the quota is used up.
+        let pace = quota(Some(100), 0, reset).pace().unwrap();
+        assert!(pace <= reset && pace > Duration::from_secs(99));
+
+        // Nothing to wait for after the reset.
+        let expired = Quota {
+            limit: Some(100),
+            remaining: 0,
+            first_remaining: 0,
+            reset_at: Instant::now() - Duration::from_secs(1),
+        };
+        assert!(!expired.is_current());
+        assert_eq!(expired.pace(), None);
+
+        // Does not overflow.
+        assert!(quota(Some(u64::MAX), u64::MAX, reset).pace().is_some());
+    }
+
+    #[test]
+    fn test_quota_pace_without_limit() {
+        let reset = Duration::from_secs(100);
+        let first = quota(None, 100, reset);
+        assert_eq!(first.pace(), None);
+
+        let later = quota(None, 30, reset).following(Some(first));
+        assert_eq!(later.first_remaining, 100);
+        assert_eq!(later.pace(), None);
+
+        let later = quota(None, 20, reset).following(Some(later));
+        let pace = later.pace().unwrap();
+        assert!(pace <= Duration::from_secs(5) && pace > Duration::from_secs(4));
+
+        // More requests than before, so a new window.
+        let next = quota(None, 200, reset).following(Some(later));
+        assert_eq!(next.first_remaining, 200);
+        assert_eq!(next.pace(), None);
+
+        // The previous window has reset.
+        let expired = Quota {
+            reset_at: Instant::now() - Duration::from_secs(1),
+            ..first
+        };
+        let next = quota(None, 20, reset).following(Some(expired));
+        assert_eq!(next.first_remaining, 20);
+        assert_eq!(next.pace(), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(
+            retry_after(&response(&[("Retry-After", "120")]), 0),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after(&response(&[("Retry-After", "999999")]), 0),
+            Some(MAX_RETRY_AFTER)
+        );
+        assert_eq!(retry_after(&response(&[("Retry-After", "soon")]), 0), None);
+        assert_eq!(retry_after(&response(&[]), 0), None);
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let server_time = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        assert_eq!(
+            to_local_time(server_time, 30),
+            server_time + Duration::from_secs(30)
+        );
+        assert_eq!(
+            to_local_time(server_time, -30),
+            server_time - Duration::from_secs(30)
+        );
+
+        // An HTTP date in 100s by the server clock is in 100s locally, even
+        // if the local clock is an hour behind.
+        let in_100s = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(3700));
+        let delay = retry_after(&response(&[("Retry-After", &in_100s)]), -3600).unwrap();
+        assert!(delay > Duration::from_secs(98) && delay <= Duration::from_secs(100));
+    }
+}
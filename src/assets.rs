@@ -1,15 +1,39 @@
 use std::{
-    fmt,
+    cmp::{max, min},
+    env, fmt, fs,
     fs::{File, OpenOptions},
-    io,
+    io::{self, Write as _},
+    iter,
     path::{Path, PathBuf},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use bitflags::bitflags;
-use serde::Serialize;
+use reqwest::{header::RANGE, StatusCode};
+use ring::{
+    digest,
+    signature::{UnparsedPublicKey, ED25519},
+};
+use serde::{Deserialize, Serialize};
 use tempfile::TempDir;
+use tokio::time;
+use url::Url;
 use xz2::read::XzDecoder;
 
+use crate::{
+    affinity,
+    api::LichessVariant,
+    configure::{EngineOpt, ParsedSize, Sandbox, VariantNet},
+    disk,
+    ipc::EarlyStop,
+    limits::EngineLimits,
+    memory::HashSizer,
+    numa,
+    util::decode_hex,
+};
+
 struct Asset {
     name: &'static str,
     data: &'static [u8],
@@ -24,6 +48,7 @@ impl Asset {
         OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .mode(0o700)
             .open(path)
     }
@@ -34,25 +59,78 @@ impl Asset {
     }
 
     fn open_file(path: &Path) -> io::Result<File> {
-        OpenOptions::new().create(true).write(true).open(path)
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
     }
 
     fn create(&self, base: &Path) -> io::Result<PathBuf> {
         let path = base.join(self.name);
+        self.unpack_to(&path)?;
+        Ok(path)
+    }
+
+    fn unpack_to(&self, path: &Path) -> io::Result<()> {
         let mut file = if self.executable {
-            Asset::open_executable_file(&path)
+            Asset::open_executable_file(path)
         } else {
-            Asset::open_file(&path)
+            Asset::open_file(path)
         }?;
 
         let mut decoder = XzDecoder::new(self.data);
         io::copy(&mut decoder, &mut file)?;
 
-        file.sync_all()?;
+        file.sync_all()
+    }
+
+    /// Like [`Asset::create()`], but keeps the unpacked file if its manifest
+    /// says that it was unpacked from the same data, and the file still has
+    /// the recorded checksum.
+    fn create_cached(&self, base: &Path) -> io::Result<PathBuf> {
+        let path = base.join(self.name);
+        let manifest_path = base.join(format!("{}.manifest", self.name));
+        let source = sha256_hex(self.data);
+
+        let manifest: Option<Manifest> = fs::read(&manifest_path)
+            .ok()
+            .and_then(|buf| serde_json::from_slice(&buf).ok());
+        if let Some(manifest) = manifest.filter(|m| m.source == source) {
+            if matches!(fs::read(&path), Ok(data) if sha256_hex(&data) == manifest.sha256) {
+                return Ok(path);
+            }
+        }
+
+        // Unpack under a temporary name and replace the file at once, so
+        // that concurrent processes never run a partially written file.
+        let tmp = base.join(format!("{}.{}.tmp", self.name, std::process::id()));
+        self.unpack_to(&tmp)?;
+        let manifest = Manifest {
+            source,
+            sha256: sha256_hex(&fs::read(&tmp)?),
+        };
+        fs::rename(&tmp, &path)?;
+        let tmp = base.join(format!("{}.manifest.{}.tmp", self.name, std::process::id()));
+        fs::write(
+            &tmp,
+            serde_json::to_string_pretty(&manifest).expect("serialize manifest"),
+        )?;
+        fs::rename(&tmp, &manifest_path)?;
         Ok(path)
     }
 }
 
+/// Records where an unpacked asset came from, to verify it on the next
+/// start.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    /// SHA-256 of the compressed asset, as bundled in the executable.
+    source: String,
+    /// SHA-256 of the unpacked file.
+    sha256: String,
+}
+
 impl fmt::Debug for Asset {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Asset")
@@ -384,7 +462,8 @@ const STOCKFISH_MV: &[Asset] = &[
     },
 ];
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum EngineFlavor {
     Official,
     MultiVariant,
@@ -397,9 +476,16 @@ impl EngineFlavor {
             EngineFlavor::MultiVariant => EvalFlavor::Hce,
         }
     }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EngineFlavor::Official => "official",
+            EngineFlavor::MultiVariant => "multi-variant",
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct ByEngineFlavor<T> {
     pub official: T,
     pub multi_variant: T,
@@ -439,37 +525,714 @@ impl EvalFlavor {
     }
 }
 
+/// Directory in the home directory with downloaded NNUE networks. Unlike
+/// the bundled assets, they are kept across runs.
+const VARIANT_NET_DIR: &str = ".fishnet-nets";
+
+/// Directory in the home directory with unpacked bundled assets, in a
+/// subdirectory for each version of fishnet.
+const ASSET_CACHE_DIR: &str = ".fishnet-assets";
+
+/// Subdirectory of the asset cache with engine builds from an update
+/// manifest.
+const ENGINE_UPDATE_DIR: &str = "engine-updates";
+
+/// Hex encoded Ed25519 key, used to verify the detached signatures of engine
+/// update manifests. The same key signs the release binaries.
+const UPDATE_PUBLIC_KEY: Option<&str> = option_env!("FISHNET_UPDATE_PUBLIC_KEY");
+
+/// Newer builds of the bundled assets, published between releases of
+/// fishnet, with a detached signature at `<url>.sig`.
+#[derive(Debug, Deserialize)]
+struct EngineManifest {
+    version: String,
+    assets: Vec<ManifestAsset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManifestAsset {
+    /// Name of the bundled asset that is replaced, for example
+    /// fairy-stockfish-x86-64-bmi2. Only builds for this CPU are used.
+    replaces: String,
+    url: Url,
+    /// Tried in order when downloading from `url` fails.
+    #[serde(default)]
+    mirrors: Vec<Url>,
+    sha256: String,
+}
+
+/// Downloaded file that replaces a bundled asset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdatedFile {
+    pub replaces: String,
+    pub path: PathBuf,
+    sha256: String,
+}
+
+/// Engine builds and network from an update manifest, used instead of the
+/// bundled assets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EngineUpdate {
+    pub version: String,
+    pub official: Option<UpdatedFile>,
+    pub multi_variant: Option<UpdatedFile>,
+    pub nnue: Option<UpdatedFile>,
+}
+
+impl EngineUpdate {
+    pub fn engine(&self, flavor: EngineFlavor) -> Option<&PathBuf> {
+        match flavor {
+            EngineFlavor::Official => self.official.as_ref(),
+            EngineFlavor::MultiVariant => self.multi_variant.as_ref(),
+        }
+        .map(|file| &file.path)
+    }
+
+    pub fn nnue(&self) -> Option<String> {
+        self.nnue
+            .as_ref()
+            .and_then(|file| file.path.to_str())
+            .map(str::to_owned)
+    }
+
+    fn files(&self) -> impl Iterator<Item = &UpdatedFile> {
+        self.official
+            .iter()
+            .chain(self.multi_variant.iter())
+            .chain(self.nnue.iter())
+    }
+}
+
+/// Reads the engine update in the directory, if all of its files still have
+/// the recorded checksums.
+fn load_engine_update(dir: &Path) -> Option<EngineUpdate> {
+    let update: EngineUpdate = fs::read(dir.join("update.json"))
+        .ok()
+        .and_then(|buf| serde_json::from_slice(&buf).ok())?;
+    let intact = update
+        .files()
+        .all(|file| matches!(fs::read(&file.path), Ok(data) if sha256_hex(&data) == file.sha256));
+    intact.then(|| update)
+}
+
+/// Path of the downloaded file, if it replaces the unpacked bundled asset.
+/// Only files that were downloaded for the same bundled assets are used,
+/// for example not after moving the cache to another CPU.
+fn updated_path(
+    unpacked: Option<PathBuf>,
+    file: Option<&UpdatedFile>,
+    bundled: Option<&str>,
+) -> Option<PathBuf> {
+    match file {
+        Some(file) if unpacked.is_some() && Some(file.replaces.as_str()) == bundled => {
+            Some(file.path.clone())
+        }
+        _ => unpacked,
+    }
+}
+
+/// Downloads the build of the manifest that replaces the named bundled
+/// asset, if any, unless a file with the expected checksum is already there.
+async fn fetch_updated_file(
+    dir: &Path,
+    manifest: &EngineManifest,
+    replaces: Option<&str>,
+    executable: bool,
+    rate_limit: Option<u64>,
+) -> io::Result<Option<UpdatedFile>> {
+    let asset = match manifest
+        .assets
+        .iter()
+        .find(|a| Some(a.replaces.as_str()) == replaces)
+    {
+        Some(asset) => asset,
+        None => return Ok(None),
+    };
+    let name = format!(
+        "{}-{}",
+        asset.replaces,
+        &asset.sha256[..min(12, asset.sha256.len())]
+    );
+    let path = dir.join(&name);
+    if !matches!(fs::read(&path), Ok(data) if sha256_hex(&data) == asset.sha256) {
+        let urls: Vec<&Url> = iter::once(&asset.url).chain(&asset.mirrors).collect();
+        let part = dir.join(format!("{}.part", name));
+        let data = download_resumable(&urls, &asset.sha256, &part, rate_limit)
+            .await
+            .map_err(|err| io::Error::new(err.kind(), format!("{} for {}", err, asset.replaces)))?;
+        let tmp = dir.join(format!("{}.tmp", name));
+        let mut file = if executable {
+            Asset::open_executable_file(&tmp)
+        } else {
+            Asset::open_file(&tmp)
+        }?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        let _ = fs::remove_file(&part);
+    }
+    Ok(Some(UpdatedFile {
+        replaces: asset.replaces.clone(),
+        path,
+        sha256: asset.sha256.clone(),
+    }))
+}
+
+/// Directory of the unpacked assets.
+#[derive(Debug)]
+enum AssetDir {
+    /// Deleted when dropped.
+    Temp(TempDir),
+    /// Kept for the next start.
+    Cache(PathBuf),
+}
+
+impl AssetDir {
+    fn path(&self) -> &Path {
+        match self {
+            AssetDir::Temp(dir) => dir.path(),
+            AssetDir::Cache(dir) => dir,
+        }
+    }
+}
+
+/// Prepares the cache directory of this version, and removes those of other
+/// versions.
+fn asset_cache_dir() -> io::Result<PathBuf> {
+    let root = home::home_dir()
+        .map(|dir| dir.join(ASSET_CACHE_DIR))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Could not resolve ~/{}", ASSET_CACHE_DIR),
+            )
+        })?;
+    let version = env!("CARGO_PKG_VERSION");
+    let dir = root.join(version);
+    fs::create_dir_all(&dir)?;
+    for entry in fs::read_dir(&root)?.flatten() {
+        if entry.file_name() != version {
+            // Other instances may still use old versions, but unlinked
+            // files stay available to them.
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+    Ok(dir)
+}
+
+/// Whether the executable is in one of the directories that engines are
+/// unpacked or downloaded to, even if it was removed in the meantime.
+pub fn is_engine_path(exe: &Path) -> bool {
+    home::home_dir().map_or(false, |home| exe.starts_with(home.join(ASSET_CACHE_DIR)))
+        || exe
+            .strip_prefix(env::temp_dir())
+            .ok()
+            .and_then(|rest| rest.iter().next())
+            .and_then(|dir| dir.to_str())
+            .map_or(false, |dir| dir.starts_with("fishnet-"))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    digest::digest(&digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Attempts to download a large file, across all mirrors, before giving up.
+const DOWNLOAD_ATTEMPTS: usize = 6;
+
+/// Give up on a mirror if no data arrives for this long.
+const DOWNLOAD_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Downloads a large file, like an engine build or NNUE network, trying the
+/// mirrors in turn. Data is appended to `part` as it arrives, so that
+/// interrupted downloads are resumed with a range request, even after a
+/// restart. Returns the complete data once it matches the checksum.
+async fn download_resumable(
+    urls: &[&Url],
+    sha256: &str,
+    part: &Path,
+    rate_limit: Option<u64>,
+) -> io::Result<Vec<u8>> {
+    let to_io = |err: reqwest::Error| io::Error::new(io::ErrorKind::Other, err);
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(30))
+        .build()
+        .map_err(to_io)?;
+
+    let mut last_err = None;
+    for attempt in 0..DOWNLOAD_ATTEMPTS {
+        if attempt > 0 {
+            time::sleep(Duration::from_secs(attempt as u64 * 5)).await;
+        }
+        let url = urls[attempt % urls.len()];
+        if let Err(err) = download_part(&client, url, part, rate_limit).await {
+            last_err = Some(err);
+            continue;
+        }
+        let data = fs::read(part)?;
+        let actual = sha256_hex(&data);
+        if actual == sha256 {
+            return Ok(data);
+        }
+        // Corrupt or from a different file. Start over.
+        let _ = fs::remove_file(part);
+        last_err = Some(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("checksum mismatch: expected {}, got {}", sha256, actual),
+        ));
+    }
+    Err(last_err.expect("download attempted"))
+}
+
+/// Downloads the rest of the file at `url`, after what is already in `part`.
+async fn download_part(
+    client: &reqwest::Client,
+    url: &Url,
+    part: &Path,
+    rate_limit: Option<u64>,
+) -> io::Result<()> {
+    let to_io = |err: reqwest::Error| io::Error::new(io::ErrorKind::Other, err);
+    let offset = fs::metadata(part).map_or(0, |meta| meta.len());
+    let mut req = client.get(url.clone());
+    if offset > 0 {
+        req = req.header(RANGE, format!("bytes={}-", offset));
+    }
+    let mut res = req.send().await.map_err(to_io)?;
+    let mut file = match res.status() {
+        StatusCode::PARTIAL_CONTENT => OpenOptions::new().append(true).open(part)?,
+        // Already complete, or the part is longer than the file.
+        StatusCode::RANGE_NOT_SATISFIABLE => return Ok(()),
+        // The server ignored the range. Start over.
+        _ => {
+            res = res.error_for_status().map_err(to_io)?;
+            File::create(part)?
+        }
+    };
+
+    let started_at = Instant::now();
+    let mut received: u64 = 0;
+    loop {
+        let chunk = match time::timeout(DOWNLOAD_STALL_TIMEOUT, res.chunk()).await {
+            Ok(chunk) => chunk.map_err(to_io)?,
+            Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+        };
+        let chunk = match chunk {
+            Some(chunk) => chunk,
+            None => break,
+        };
+        file.write_all(&chunk)?;
+        received += chunk.len() as u64;
+        if let Some(rate_limit) = rate_limit.filter(|&r| r > 0) {
+            let due = Duration::from_secs_f64(received as f64 / rate_limit as f64);
+            if let Some(ahead) = due.checked_sub(started_at.elapsed()) {
+                time::sleep(ahead).await;
+            }
+        }
+    }
+    file.sync_all()
+}
+
+async fn download(url: &Url) -> io::Result<Vec<u8>> {
+    let to_io = |err: reqwest::Error| io::Error::new(io::ErrorKind::Other, err);
+    let res = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5 * 60))
+        .build()
+        .map_err(to_io)?
+        .get(url.clone())
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(to_io)?;
+    Ok(res.bytes().await.map_err(to_io)?.to_vec())
+}
+
 #[derive(Debug)]
 pub struct Assets {
     pub sf_name: &'static str,
     pub nnue: String,
-    pub stockfish: ByEngineFlavor<PathBuf>,
-    _dir: TempDir, // Will be deleted when dropped
+    pub stockfish: ByEngineFlavor<Option<PathBuf>>,
+    /// Directories with Syzygy tablebases, as passed to official Stockfish.
+    pub syzygy_path: Option<String>,
+    /// Downloaded NNUE networks of Fairy-Stockfish variants.
+    pub variant_nets: Vec<(LichessVariant, String)>,
+    /// Bytes per second for downloads of engine builds and networks.
+    pub download_rate_limit: Option<u64>,
+    /// Third-party engines that replace the bundled engines for a variant,
+    /// once they passed the handshake.
+    pub external_engines: Vec<(LichessVariant, PathBuf)>,
+    /// Hash table size in MiB of each engine process, if not the engine
+    /// default.
+    pub hash_mib: ByEngineFlavor<Option<u64>>,
+    /// Shrinks the hash tables while memory is low.
+    pub hash_sizer: Arc<HashSizer>,
+    /// Search threads of each engine process.
+    pub threads: usize,
+    /// Pin each engine process to its own logical cores.
+    pub pin_cores: bool,
+    /// Conditions to stop analysis before the node limit.
+    pub early_stop: EarlyStop,
+    /// Keep the hash table between adjacent plies of the same game.
+    pub warm_hash: bool,
+    /// NUMA nodes to spread engine processes across. Empty on single node
+    /// machines.
+    pub numa_nodes: Vec<numa::Node>,
+    /// Restrictions for engine processes.
+    pub sandbox: Sandbox,
+    /// Memory and CPU limits for engine processes.
+    pub engine_limits: EngineLimits,
+    /// Downloaded engine update in use instead of the bundled engines.
+    pub engine_update: Option<EngineUpdate>,
+    /// Names of the bundled engines for this CPU.
+    bundled: ByEngineFlavor<Option<&'static str>>,
+    dir: AssetDir,
 }
 
 impl Assets {
-    pub fn prepare(cpu: Cpu) -> io::Result<Assets> {
-        let dir = tempfile::Builder::new().prefix("fishnet-").tempdir()?;
+    /// Name of the bundled Fairy-Stockfish build for this CPU, if enabled.
+    pub fn multi_variant_name(&self) -> Option<&'static str> {
+        self.bundled.multi_variant
+    }
+
+    /// Bytes used by the unpacked files and downloaded engine updates.
+    pub fn disk_usage(&self) -> io::Result<u64> {
+        disk::usage(self.dir.path())
+    }
+
+    /// SHA-256 of the unpacked engine executables, hex encoded.
+    pub fn engine_hashes(&self) -> io::Result<ByEngineFlavor<Option<String>>> {
+        fn sha256(path: &Path) -> io::Result<String> {
+            Ok(sha256_hex(&fs::read(path)?))
+        }
+
+        Ok(ByEngineFlavor {
+            official: self.stockfish.official.as_deref().map(sha256).transpose()?,
+            multi_variant: self
+                .stockfish
+                .multi_variant
+                .as_deref()
+                .map(sha256)
+                .transpose()?,
+        })
+    }
+
+    /// Uses Syzygy tablebases from the given directories (separated like
+    /// PATH), after checking that they contain tablebase files. Returns the
+    /// number of tablebase files.
+    pub fn set_syzygy_path(&mut self, path: &Path) -> io::Result<usize> {
+        let printable = path.to_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "syzygy path not printable")
+        })?;
+        let mut files = 0;
+        for dir in env::split_paths(path) {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if matches!(
+                    path.extension().and_then(|ext| ext.to_str()),
+                    Some("rtbw" | "rtbz")
+                ) {
+                    files += 1;
+                }
+            }
+        }
+        if files == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no tablebase files (*.rtbw, *.rtbz)",
+            ));
+        }
+        self.syzygy_path = Some(printable.to_owned());
+        Ok(files)
+    }
+
+    /// Applies the configured hash table sizes and threads to engine
+    /// processes started from now on.
+    pub fn set_engine_options(&mut self, opt: &EngineOpt) {
+        let mib = |size: ParsedSize| max(1, u64::from(size) >> 20);
+        self.hash_mib = ByEngineFlavor {
+            official: opt.hash_official.map(mib),
+            multi_variant: opt.hash_multivariant.map(mib),
+        };
+        self.threads = opt.threads_per_job();
+        self.pin_cores = opt.pin_cores;
+        self.early_stop = EarlyStop {
+            mate: opt.early_stop_mate,
+            cp_window: opt.early_stop_window,
+        };
+        self.warm_hash = !opt.deterministic;
+        self.numa_nodes = if opt.no_numa {
+            Vec::new()
+        } else {
+            numa::detect()
+        };
+        self.sandbox = opt.sandbox.unwrap_or_default();
+    }
+
+    /// Third-party engine for the variant, if any.
+    pub fn external_engine(&self, variant: LichessVariant) -> Option<&PathBuf> {
+        self.external_engines
+            .iter()
+            .find(|(v, _)| *v == variant)
+            .map(|(_, path)| path)
+    }
+
+    /// NUMA node for the engine process of the given worker. Workers are
+    /// spread across nodes round robin.
+    pub fn numa_node(&self, worker: usize) -> Option<&numa::Node> {
+        if self.numa_nodes.is_empty() {
+            None
+        } else {
+            Some(&self.numa_nodes[worker % self.numa_nodes.len()])
+        }
+    }
+
+    /// Logical cores for the engine process of the given worker: its own
+    /// cores with `--pin-cores`, otherwise all cores of its NUMA node, if
+    /// any.
+    pub fn engine_cores(&self, worker: usize) -> Option<Vec<usize>> {
+        match self.numa_node(worker) {
+            Some(node) if self.pin_cores => Some(affinity::core_set(
+                &node.cores,
+                worker / self.numa_nodes.len(),
+                self.threads,
+            )),
+            Some(node) => Some(node.cores.clone()),
+            None if self.pin_cores => Some(affinity::core_set(
+                &affinity::allowed_or_all(),
+                worker,
+                self.threads,
+            )),
+            None => None,
+        }
+    }
+
+    /// Uses the NNUE network for its variant, after downloading it to the
+    /// cache, unless a file with the expected checksum is already there.
+    /// Returns the path of the network.
+    pub async fn fetch_variant_net(&mut self, net: &VariantNet) -> io::Result<PathBuf> {
+        let dir = home::home_dir()
+            .map(|dir| dir.join(VARIANT_NET_DIR))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Could not resolve ~/{}", VARIANT_NET_DIR),
+                )
+            })?;
+        let path = dir.join(format!("{}-{}.nnue", net.variant, &net.sha256[..12]));
+        let printable = path
+            .to_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "net path not printable"))?
+            .to_owned();
+
+        if !matches!(fs::read(&path), Ok(data) if sha256_hex(&data) == net.sha256) {
+            fs::create_dir_all(&dir)?;
+            // Download next to the network and rename when complete, so that
+            // an interrupted download is not mistaken for a network.
+            let part = path.with_extension("part");
+            let urls: Vec<&Url> = iter::once(&net.url).chain(&net.mirrors).collect();
+            download_resumable(&urls, &net.sha256, &part, self.download_rate_limit).await?;
+            fs::rename(&part, &path)?;
+        }
+
+        self.variant_nets
+            .retain(|(variant, _)| *variant != net.variant);
+        self.variant_nets.push((net.variant, printable));
+        Ok(path)
+    }
+
+    /// Downloads the builds from the engine update manifest at `url` that
+    /// replace the bundled assets in use, after verifying the signature of
+    /// the manifest and the checksums of the builds. Returns the update,
+    /// unless it is the one that was downloaded before. It is used from the
+    /// next start, or by workers that pick it up between batches.
+    pub async fn fetch_engine_update(&self, url: &Url) -> io::Result<Option<EngineUpdate>> {
+        let dir = match self.dir {
+            AssetDir::Cache(ref dir) => dir.join(ENGINE_UPDATE_DIR),
+            AssetDir::Temp(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "asset cache not available",
+                ))
+            }
+        };
+        let public_key = UPDATE_PUBLIC_KEY.and_then(decode_hex).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "built without update signing key")
+        })?;
+
+        let data = download(url).await?;
+        let mut signature_url = url.clone();
+        signature_url.set_path(&format!("{}.sig", url.path()));
+        let signature = download(&signature_url).await?;
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(&data, &signature)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "bad signature for engine manifest",
+                )
+            })?;
+        let manifest: EngineManifest = serde_json::from_slice(&data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        if load_engine_update(&dir).map_or(false, |current| current.version == manifest.version) {
+            return Ok(None);
+        }
+
+        fs::create_dir_all(&dir)?;
+        let update = EngineUpdate {
+            official: fetch_updated_file(
+                &dir,
+                &manifest,
+                self.bundled.official,
+                true,
+                self.download_rate_limit,
+            )
+            .await?,
+            multi_variant: fetch_updated_file(
+                &dir,
+                &manifest,
+                self.bundled.multi_variant,
+                true,
+                self.download_rate_limit,
+            )
+            .await?,
+            nnue: fetch_updated_file(
+                &dir,
+                &manifest,
+                Some(NNUE.name),
+                false,
+                self.download_rate_limit,
+            )
+            .await?,
+            version: manifest.version,
+        };
+        let tmp = dir.join(format!("update.json.{}.tmp", std::process::id()));
+        fs::write(
+            &tmp,
+            serde_json::to_string_pretty(&update).expect("serialize engine update"),
+        )?;
+        fs::rename(&tmp, dir.join("update.json"))?;
+
+        // Running engine processes keep unlinked files available.
+        for entry in fs::read_dir(&dir)?.flatten() {
+            let path = entry.path();
+            if entry.file_name() != "update.json" && !update.files().any(|f| f.path == path) {
+                let _ = fs::remove_file(path);
+            }
+        }
+        Ok(Some(update))
+    }
+
+    /// Unpacks the engines for the given CPU. Disabled engines are not
+    /// unpacked at all. Each file is decompressed on its own thread, which
+    /// noticeably shortens startup on small machines with several cores.
+    ///
+    /// Files are kept in `~/.fishnet-assets`, and only unpacked again if
+    /// they fail verification against their manifest. Falls back to a
+    /// temporary directory if the cache can not be used, for example with
+    /// a read-only home directory. A previously downloaded engine update is
+    /// used instead of the bundled files it replaces.
+    pub fn prepare(cpu: Cpu, enabled: ByEngineFlavor<bool>) -> io::Result<Assets> {
+        match asset_cache_dir()
+            .and_then(|dir| Assets::prepare_in(AssetDir::Cache(dir), cpu, enabled))
+        {
+            Ok(assets) => Ok(assets),
+            Err(_) => Assets::prepare_in(
+                AssetDir::Temp(tempfile::Builder::new().prefix("fishnet-").tempdir()?),
+                cpu,
+                enabled,
+            ),
+        }
+    }
+
+    fn prepare_in(dir: AssetDir, cpu: Cpu, enabled: ByEngineFlavor<bool>) -> io::Result<Assets> {
         let sf = STOCKFISH
             .iter()
             .find(|a| cpu.contains(a.needs))
             .expect("compatible stockfish");
+        // Not included in chess-only builds.
+        let sf_mv = enabled.multi_variant.then(|| {
+            STOCKFISH_MV
+                .iter()
+                .find(|a| cpu.contains(a.needs))
+                .expect("compatible stockfish")
+        });
+
+        let cached = matches!(dir, AssetDir::Cache(_));
+        let unpack = |asset: Option<&'static Asset>| {
+            let base = dir.path().to_owned();
+            asset.map(|asset| {
+                thread::spawn(move || {
+                    if cached {
+                        asset.create_cached(&base)
+                    } else {
+                        asset.create(&base)
+                    }
+                })
+            })
+        };
+        let nnue = unpack(Some(&NNUE));
+        let official = unpack(enabled.official.then(|| sf));
+        let multi_variant = unpack(sf_mv);
+        let join = |handle: Option<JoinHandle<io::Result<PathBuf>>>| {
+            handle.map(|h| h.join().expect("join")).transpose()
+        };
+
+        let bundled = ByEngineFlavor {
+            official: enabled.official.then(|| sf.name),
+            multi_variant: sf_mv.map(|a| a.name),
+        };
+        let engine_update = if cached {
+            load_engine_update(&dir.path().join(ENGINE_UPDATE_DIR))
+        } else {
+            None
+        };
+        let update = engine_update.as_ref();
+
         Ok(Assets {
-            nnue: NNUE
-                .create(dir.path())?
-                .to_str()
-                .expect("nnue path printable")
-                .to_owned(),
+            nnue: updated_path(
+                join(nnue)?,
+                update.and_then(|u| u.nnue.as_ref()),
+                Some(NNUE.name),
+            )
+            .expect("nnue unpacked")
+            .to_str()
+            .expect("nnue path printable")
+            .to_owned(),
             sf_name: sf.name,
             stockfish: ByEngineFlavor {
-                official: sf.create(dir.path())?,
-                multi_variant: STOCKFISH_MV
-                    .iter()
-                    .find(|a| cpu.contains(a.needs))
-                    .expect("compatible stockfish")
-                    .create(dir.path())?,
+                official: updated_path(
+                    join(official)?,
+                    update.and_then(|u| u.official.as_ref()),
+                    bundled.official,
+                ),
+                multi_variant: updated_path(
+                    join(multi_variant)?,
+                    update.and_then(|u| u.multi_variant.as_ref()),
+                    bundled.multi_variant,
+                ),
+            },
+            syzygy_path: None,
+            variant_nets: Vec::new(),
+            download_rate_limit: None,
+            external_engines: Vec::new(),
+            hash_mib: ByEngineFlavor {
+                official: None,
+                multi_variant: None,
             },
-            _dir: dir,
+            hash_sizer: Arc::default(),
+            threads: 1,
+            pin_cores: false,
+            early_stop: EarlyStop::default(),
+            warm_hash: false,
+            numa_nodes: Vec::new(),
+            sandbox: Sandbox::default(),
+            engine_limits: EngineLimits::default(),
+            engine_update,
+            bundled,
+            dir,
         })
     }
 }
@@ -0,0 +1,64 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    io::Write as _,
+    path::Path,
+};
+
+use serde::Serialize;
+
+use crate::assets::{ByEngineFlavor, EngineFlavor};
+
+/// Provenance of a submitted batch, for deployments that need to retain
+/// what was analysed, and how.
+#[derive(Debug, Serialize)]
+pub struct Provenance {
+    pub batch: String,
+    pub kind: &'static str,
+    pub variant: String,
+    pub engine: &'static str,
+    pub engine_sha256: Option<String>,
+    /// Nodes requested per position, if limited by nodes.
+    pub node_target: Option<u64>,
+    /// Unix timestamp of the submission.
+    pub submitted_at: u64,
+    /// Milliseconds from acquiring to submitting the batch.
+    pub duration_ms: u64,
+    /// Milliseconds spent by the engine, summed over all positions.
+    pub engine_time_ms: u64,
+    pub positions: u64,
+    pub skipped: u64,
+    /// Positions where the search stopped before reaching the node target,
+    /// for example due to a forced mate or a depth limit.
+    pub truncated: Vec<usize>,
+}
+
+/// Appends one JSON line per submitted batch to a local file.
+pub struct AuditLog {
+    file: File,
+    engine_hashes: ByEngineFlavor<Option<String>>,
+}
+
+impl AuditLog {
+    pub fn open(
+        path: &Path,
+        engine_hashes: ByEngineFlavor<Option<String>>,
+    ) -> io::Result<AuditLog> {
+        Ok(AuditLog {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            engine_hashes,
+        })
+    }
+
+    pub fn engine_hash(&self, flavor: EngineFlavor) -> Option<String> {
+        self.engine_hashes.get(flavor).clone()
+    }
+
+    pub fn append(&mut self, record: &Provenance) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record).expect("serialize provenance");
+        line.push(b'\n');
+        // Single write, so that concurrent readers never see partial lines.
+        self.file.write_all(&line)?;
+        self.file.flush()
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    env,
+    error::Error,
+    fmt, fs, io,
+    path::Path,
+    process,
+    time::{Duration, Instant},
+};
+
+use configparser::ini::Ini;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use tokio::time;
+
+use crate::{
+    configure::{AuthOpt, Endpoint, Key, KeyError, Opt, Proxy},
+    logger::Logger,
+};
+
+/// OAuth 2.0 device authorization grant, RFC 8628.
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+const CLIENT_ID: &str = "fishnet";
+
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    #[serde(default)]
+    verification_uri_complete: Option<String>,
+    /// Seconds until the device code expires.
+    expires_in: u64,
+    /// Seconds to wait between polling for the token.
+    #[serde(default = "default_interval")]
+    interval: u64,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    error: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyResponse {
+    key: String,
+}
+
+#[derive(Debug)]
+enum AuthError {
+    Io(io::Error),
+    Http(reqwest::Error),
+    Denied,
+    Expired,
+    Unsupported,
+    Server(String),
+    Key(KeyError),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Io(err) => write!(f, "failed to read token: {}", err),
+            AuthError::Http(err) => err.fmt(f),
+            AuthError::Denied => f.write_str("authorization denied"),
+            AuthError::Expired => f.write_str("device code expired before authorization"),
+            AuthError::Unsupported => f.write_str("endpoint does not support key provisioning"),
+            AuthError::Server(err) => write!(f, "unexpected response: {}", err),
+            AuthError::Key(err) => write!(f, "invalid key from server: {}", err),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+impl From<reqwest::Error> for AuthError {
+    fn from(err: reqwest::Error) -> AuthError {
+        AuthError::Http(err)
+    }
+}
+
+fn client(proxy: Option<Proxy>) -> reqwest::Client {
+    let mut client = reqwest::Client::builder()
+        .user_agent(format!(
+            "{}-{}-{}/{}",
+            env!("CARGO_PKG_NAME"),
+            env::consts::OS,
+            env::consts::ARCH,
+            env!("CARGO_PKG_VERSION")
+        ))
+        .timeout(Duration::from_secs(30));
+    if let Some(Proxy { url }) = proxy {
+        client = client.proxy(reqwest::Proxy::all(url).expect("supported proxy"));
+    }
+    client.build().expect("client")
+}
+
+/// Asks the user to authorize this device in the browser, and polls until
+/// they did.
+async fn device_flow(
+    client: &reqwest::Client,
+    endpoint: &Endpoint,
+    logger: &Logger,
+) -> Result<String, AuthError> {
+    let res = client
+        .post(format!("{}/auth/device", endpoint))
+        .form(&[("client_id", CLIENT_ID)])
+        .send()
+        .await?;
+    if res.status() == StatusCode::NOT_FOUND {
+        return Err(AuthError::Unsupported);
+    }
+    let device: DeviceAuthorization = res.error_for_status()?.json().await?;
+
+    logger.headline("Authorization");
+    match device.verification_uri_complete {
+        Some(ref uri) => logger.fishnet_info(&format!("Open {} to authorize fishnet", uri)),
+        None => logger.fishnet_info(&format!(
+            "Open {} and enter the code {} to authorize fishnet",
+            device.verification_uri, device.user_code
+        )),
+    }
+
+    let expires_at = Instant::now() + Duration::from_secs(device.expires_in);
+    let mut interval = Duration::from_secs(device.interval);
+    loop {
+        time::sleep(interval).await;
+        if Instant::now() >= expires_at {
+            return Err(AuthError::Expired);
+        }
+        let res = client
+            .post(format!("{}/auth/token", endpoint))
+            .form(&[
+                ("grant_type", DEVICE_CODE_GRANT),
+                ("device_code", &device.device_code),
+                ("client_id", CLIENT_ID),
+            ])
+            .send()
+            .await?;
+        if res.status().is_success() {
+            return Ok(res.json::<TokenResponse>().await?.access_token);
+        }
+        let status = res.status();
+        match res.json::<TokenError>().await.map(|err| err.error) {
+            Ok(err) if err == "authorization_pending" => (),
+            Ok(err) if err == "slow_down" => interval += Duration::from_secs(5),
+            Ok(err) if err == "access_denied" => return Err(AuthError::Denied),
+            Ok(err) if err == "expired_token" => return Err(AuthError::Expired),
+            Ok(err) => return Err(AuthError::Server(err)),
+            Err(_) => return Err(AuthError::Server(status.to_string())),
+        }
+    }
+}
+
+/// Exchanges an access token for a new fishnet key.
+async fn provision_key(
+    client: &reqwest::Client,
+    endpoint: &Endpoint,
+    token: &str,
+) -> Result<Key, AuthError> {
+    let res = client
+        .post(format!("{}/auth/key", endpoint))
+        .bearer_auth(token)
+        .send()
+        .await?;
+    match res.status() {
+        StatusCode::NOT_FOUND => Err(AuthError::Unsupported),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(AuthError::Denied),
+        _ => res
+            .error_for_status()?
+            .json::<KeyResponse>()
+            .await?
+            .key
+            .parse()
+            .map_err(AuthError::Key),
+    }
+}
+
+fn save_key(conf: &Path, Key(key): Key) -> io::Result<()> {
+    let mut ini = Ini::new();
+    ini.set_default_section("Fishnet");
+    match fs::read_to_string(conf) {
+        Ok(contents) => {
+            ini.read(contents)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err),
+    }
+    ini.set("Fishnet", "Key", Some(key));
+    fs::write(conf, ini.writes())
+}
+
+/// Obtains a key from the endpoint, either interactively with a device code
+/// or with an existing access token, and writes it to the configuration
+/// file.
+pub async fn auth(opt: &Opt, auth_opt: &AuthOpt, logger: &Logger) {
+    let conf = opt
+        .endpoint_confs
+        .first()
+        .cloned()
+        .expect("endpoint configured");
+    let client = client(conf.proxy);
+
+    let token = match auth_opt.auth_token_file {
+        Some(ref path) => fs::read_to_string(path)
+            .map(|token| token.trim().to_owned())
+            .map_err(AuthError::Io),
+        None => device_flow(&client, &conf.endpoint, logger).await,
+    };
+    let key = match token {
+        Ok(token) => provision_key(&client, &conf.endpoint, &token).await,
+        Err(err) => Err(err),
+    };
+
+    match key {
+        Ok(key) => match save_key(&opt.conf, key) {
+            Ok(()) => logger.fishnet_info(&format!("Key saved to {:?}", opt.conf)),
+            Err(err) => {
+                logger.error(&format!("Failed to write {:?}: {}", opt.conf, err));
+                process::exit(1);
+            }
+        },
+        Err(err) => {
+            logger.error(&format!(
+                "Failed to obtain key from {}: {}",
+                conf.endpoint, err
+            ));
+            process::exit(1);
+        }
+    }
+}
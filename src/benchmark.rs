@@ -0,0 +1,178 @@
+use std::{
+    cmp::max,
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use shakmaty::{
+    fen::Fen,
+    variant::{Variant, VariantPosition},
+};
+
+use crate::{
+    api::{LichessVariant, NodeLimit, Work},
+    assets::{Assets, EngineFlavor},
+    calibration::Calibration,
+    configure::{BenchmarkOpt, Cores, Opt},
+    ipc::{Position, PositionId},
+    logger::Logger,
+    pool::EnginePool,
+};
+
+/// Positions of the bench command of Stockfish, covering openings,
+/// middlegames and endgames.
+const STANDARD_SUITE: &[&str] = &[
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 10",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 11",
+    "4rrk1/pp1n3p/3q2pQ/2p1pb2/2PP4/2P3N1/P2B2PP/4RRK1 b - - 7 19",
+    "r3r1k1/2p2ppp/p1p1bn2/8/1q2P3/2NPQN2/PPP3PP/R4RK1 b - - 2 15",
+    "r1bbk1nr/pp3p1p/2n5/1N4p1/2Np1B2/8/PPP2PPP/2KR1B1R w kq - 0 13",
+    "rnbqkb1r/ppp1pppp/5n2/3p4/3P4/2N5/PPP1PPPP/R1BQKBNR w KQkq - 3 3",
+    "6k1/6p1/6Pp/ppp5/3pn2P/1P3K2/1PP2P2/3N4 b - - 0 1",
+];
+
+/// Fairy-Stockfish is measured on the starting positions of the variants
+/// it analyses for lichess.
+const VARIANT_SUITE: &[LichessVariant] = &[
+    LichessVariant::Antichess,
+    LichessVariant::Atomic,
+    LichessVariant::Crazyhouse,
+    LichessVariant::Horde,
+    LichessVariant::KingOfTheHill,
+    LichessVariant::RacingKings,
+    LichessVariant::ThreeCheck,
+];
+
+fn suite(flavor: EngineFlavor) -> Vec<(LichessVariant, Fen)> {
+    match flavor {
+        EngineFlavor::Official => STANDARD_SUITE
+            .iter()
+            .map(|fen| (LichessVariant::Standard, fen.parse().expect("valid fen")))
+            .collect(),
+        EngineFlavor::MultiVariant => VARIANT_SUITE
+            .iter()
+            .map(|&variant| {
+                (
+                    variant,
+                    Fen::from_setup(&VariantPosition::new(Variant::from(variant))),
+                )
+            })
+            .collect(),
+    }
+}
+
+/// Nodes per second of each engine process, while all of them are busy.
+async fn measure(
+    pool: &EnginePool,
+    flavor: EngineFlavor,
+    positions: usize,
+    nodes: u64,
+) -> Option<u32> {
+    let suite = suite(flavor);
+    let mut pending = Vec::with_capacity(positions);
+    for (i, (variant, fen)) in suite.into_iter().cycle().take(positions).enumerate() {
+        pending.push(
+            pool.submit(Position {
+                work: Work::Analysis {
+                    id: "benchmark".parse().expect("batch id"),
+                    nodes: NodeLimit::fixed(nodes),
+                    depth: None,
+                    multipv: None,
+                    timeout: Duration::default(),
+                    movetime: None,
+                    wdl: false,
+                    annotations: false,
+                    tier: None,
+                    options: BTreeMap::new(),
+                },
+                position_id: PositionId(i),
+                flavor,
+                url: None,
+                variant,
+                root_fen: fen,
+                moves: Vec::new(),
+            })
+            .await,
+        );
+    }
+
+    // Engine time excludes starting the processes and waiting for the pool.
+    let mut total_nodes: u64 = 0;
+    let mut total_time = Duration::default();
+    for pending in pending {
+        if let Ok(res) = pending.await {
+            total_nodes += res.nodes;
+            total_time += res.time;
+        }
+    }
+    (u128::from(total_nodes) * 1000)
+        .checked_div(total_time.as_millis())
+        .and_then(|nps| nps.try_into().ok())
+}
+
+/// Measures both engine flavors with the same engine processes as `run`,
+/// and saves the result for the next sessions.
+pub async fn benchmark(opt: &Opt, benchmark_opt: &BenchmarkOpt, logger: &Logger) {
+    let cpu = opt.cpu();
+    let mut assets =
+        Assets::prepare(cpu, opt.enabled_engines()).expect("prepared bundled stockfish");
+    assets.set_engine_options(&opt.engine);
+    let processes = max(
+        1,
+        usize::from(opt.cores.unwrap_or(Cores::Auto)) / assets.threads,
+    );
+    let mut calibration = Calibration::new(processes, assets.threads);
+    let assets = Arc::new(assets);
+
+    // Every process gets a few positions, so that all cores stay busy.
+    let positions = max(STANDARD_SUITE.len(), 3 * processes);
+
+    for flavor in [EngineFlavor::Official, EngineFlavor::MultiVariant] {
+        if assets.stockfish.get(flavor).is_none() {
+            continue;
+        }
+        let name = match flavor {
+            EngineFlavor::Official => "Official Stockfish",
+            EngineFlavor::MultiVariant => "Fairy-Stockfish",
+        };
+        logger.headline(&format!(
+            "Benchmarking {} with {} engine processes",
+            name, processes
+        ));
+        let started_at = Instant::now();
+        let (pool, pool_join_handle) = EnginePool::spawn(assets.clone(), processes, logger.clone());
+        let nps = measure(&pool, flavor, positions, benchmark_opt.nodes).await;
+        drop(pool);
+        pool_join_handle.await.expect("join");
+        match nps {
+            Some(nps) => {
+                logger.fishnet_info(&format!(
+                    "{}: {} per process, {} in total ({:.1?})",
+                    name,
+                    logger.nps(nps),
+                    logger.nps(nps.saturating_mul(processes as u32)),
+                    started_at.elapsed()
+                ));
+                calibration.set_nps(flavor, nps);
+            }
+            None => logger.error(&format!("{}: no positions analysed", name)),
+        }
+    }
+
+    if benchmark_opt.dry_run {
+        return;
+    }
+    if calibration.official.is_none() && calibration.multi_variant.is_none() {
+        logger.error("Nothing measured. Calibration not saved.");
+        std::process::exit(1);
+    }
+    match calibration.save() {
+        Ok(path) => logger.fishnet_info(&format!("Calibration saved to {:?}", path)),
+        Err(err) => {
+            logger.error(&format!("Failed to save calibration: {}", err));
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use std::{fs, io, path::Path};
+
+use rand::Rng as _;
+use shakmaty::{
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    zobrist::Zobrist,
+    CastlingMode, Role, Square,
+};
+
+/// Marks books in the extended format. The header has the size of an entry,
+/// followed by entries like in Polyglot books.
+const MAGIC: &[u8; 12] = b"fishnet-book";
+
+const FORMAT_VERSION: u8 = 1;
+
+/// Variants of extended books, as stored in the header.
+const VARIANTS: [Variant; 8] = [
+    Variant::Chess,
+    Variant::Antichess,
+    Variant::Atomic,
+    Variant::Crazyhouse,
+    Variant::Horde,
+    Variant::KingOfTheHill,
+    Variant::RacingKings,
+    Variant::ThreeCheck,
+];
+
+/// A move of the book, with its weight.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BookMove {
+    /// Move in UCI notation with Chess960 castling, as sent to the engines.
+    pub uci: Uci,
+    pub weight: u16,
+}
+
+struct Entry {
+    key: u64,
+    raw_move: u16,
+    weight: u16,
+}
+
+/// An opening book, either a Polyglot book for standard chess, or a book in
+/// the extended format for one variant.
+///
+/// Extended books start with a header of 16 bytes: `fishnet-book`, the
+/// format version, the index of the variant in antichess, atomic,
+/// crazyhouse, horde, kingOfTheHill, racingKings, threeCheck (starting at 1,
+/// with 0 for standard chess), and two zero bytes. Keys are the Zobrist
+/// hashes of shakmaty, which include pockets and remaining checks, and are
+/// the same as Polyglot keys for standard chess. Moves are encoded like in
+/// Polyglot books, with promotion 5 for kings, and drops with the highest
+/// bit set, the role in bits 12 to 14 (like promotions, with 6 for pawns)
+/// and the target square in the lowest bits.
+pub struct Book {
+    variant: Variant,
+    entries: Vec<Entry>,
+}
+
+impl Book {
+    pub fn open(path: &Path) -> io::Result<Book> {
+        Book::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(mut data: &[u8]) -> io::Result<Book> {
+        if data.len() % 16 != 0 {
+            return Err(invalid_data("expected entries of 16 bytes"));
+        }
+        let mut variant = Variant::Chess;
+        if data.starts_with(MAGIC) {
+            if data[12] != FORMAT_VERSION {
+                return Err(invalid_data("unsupported book format version"));
+            }
+            variant = *VARIANTS
+                .get(usize::from(data[13]))
+                .ok_or_else(|| invalid_data("unknown book variant"))?;
+            data = &data[16..];
+        }
+        let mut entries: Vec<_> = data
+            .chunks_exact(16)
+            .map(|entry| Entry {
+                key: u64::from_be_bytes(entry[0..8].try_into().expect("key")),
+                raw_move: u16::from_be_bytes(entry[8..10].try_into().expect("move")),
+                weight: u16::from_be_bytes(entry[10..12].try_into().expect("weight")),
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.key);
+        Ok(Book { variant, entries })
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    /// All legal book moves in the position, in the order of the book.
+    pub fn probe(&self, pos: &VariantPosition) -> Vec<BookMove> {
+        if pos.variant() != self.variant {
+            return Vec::new();
+        }
+        let key: u64 = Zobrist::<VariantPosition, u64>::new(pos.clone()).zobrist_hash();
+        let start = self.entries.partition_point(|entry| entry.key < key);
+        self.entries[start..]
+            .iter()
+            .take_while(|entry| entry.key == key)
+            .filter_map(|entry| {
+                // Castling is encoded as king moves onto the rook, so that
+                // collisions of keys show up as illegal moves.
+                let m = decode_move(entry.raw_move)?.to_move(pos).ok()?;
+                Some(BookMove {
+                    uci: m.to_uci(CastlingMode::Chess960),
+                    weight: entry.weight,
+                })
+            })
+            .collect()
+    }
+
+    pub fn contains(&self, pos: &VariantPosition) -> bool {
+        !self.probe(pos).is_empty()
+    }
+
+    /// Picks one of the book moves, with probability proportional to its
+    /// weight.
+    pub fn pick(&self, pos: &VariantPosition) -> Option<Uci> {
+        let candidates: Vec<_> = self
+            .probe(pos)
+            .into_iter()
+            .filter(|m| m.weight > 0)
+            .collect();
+        let total: u32 = candidates.iter().map(|m| u32::from(m.weight)).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut choice = rand::thread_rng().gen_range(0..total);
+        for m in candidates {
+            match choice.checked_sub(u32::from(m.weight)) {
+                Some(rest) => choice = rest,
+                None => return Some(m.uci),
+            }
+        }
+        None
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn decode_role(raw: u16) -> Option<Role> {
+    Some(match raw {
+        1 => Role::Knight,
+        2 => Role::Bishop,
+        3 => Role::Rook,
+        4 => Role::Queen,
+        5 => Role::King,
+        6 => Role::Pawn,
+        _ => return None,
+    })
+}
+
+fn decode_move(raw: u16) -> Option<Uci> {
+    let to = Square::new(u32::from(raw & 0o77));
+    let role = (raw >> 12) & 7;
+    Some(if raw & 0x8000 != 0 {
+        Uci::Put {
+            role: decode_role(role)?,
+            to,
+        }
+    } else {
+        Uci::Normal {
+            from: Square::new(u32::from((raw >> 6) & 0o77)),
+            to,
+            promotion: match role {
+                0 => None,
+                6 => return None,
+                promotion => Some(decode_role(promotion)?),
+            },
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{fen::Fen, Chess, Position as _};
+
+    use super::*;
+
+    fn entry(key: u64, raw_move: u16, weight: u16) -> Vec<u8> {
+        let mut entry = Vec::with_capacity(16);
+        entry.extend_from_slice(&key.to_be_bytes());
+        entry.extend_from_slice(&raw_move.to_be_bytes());
+        entry.extend_from_slice(&weight.to_be_bytes());
+        entry.extend_from_slice(&[0; 4]); // learn
+        entry
+    }
+
+    fn raw_move(from: Square, to: Square, promotion: u16) -> u16 {
+        promotion << 12 | (u16::from(from) << 6) | u16::from(to)
+    }
+
+    fn pos(variant: Variant, fen: &str) -> VariantPosition {
+        let fen: Fen = fen.parse().unwrap();
+        VariantPosition::from_setup(variant, &fen, CastlingMode::Chess960).unwrap()
+    }
+
+    fn play(mut pos: VariantPosition, moves: &[&str]) -> VariantPosition {
+        for uci in moves {
+            let m = uci.parse::<Uci>().unwrap().to_move(&pos).unwrap();
+            pos.play_unchecked(&m);
+        }
+        pos
+    }
+
+    fn key(pos: &VariantPosition) -> u64 {
+        Zobrist::<VariantPosition, u64>::new(pos.clone()).zobrist_hash()
+    }
+
+    #[test]
+    fn test_polyglot_keys() {
+        // From the Polyglot book format specification.
+        let start = VariantPosition::Chess(Chess::default());
+        assert_eq!(key(&start), 0x463b96181691fc9c);
+        assert_eq!(key(&play(start.clone(), &["e2e4"])), 0x823c9b50fd114196);
+        assert_eq!(
+            key(&play(start.clone(), &["e2e4", "d7d5", "e4e5", "f7f5"])),
+            0x22a48b5a8e47ff78
+        );
+        assert_eq!(
+            key(&play(start, &["e2e4", "d7d5", "e4e5", "f7f5", "e1e2"])),
+            0x652a607ca3f242c1
+        );
+    }
+
+    #[test]
+    fn test_decode_move() {
+        assert_eq!(
+            decode_move(raw_move(Square::E2, Square::E4, 0)),
+            Some("e2e4".parse().unwrap())
+        );
+        assert_eq!(
+            decode_move(raw_move(Square::A7, Square::A8, 4)),
+            Some("a7a8q".parse().unwrap())
+        );
+        assert_eq!(
+            decode_move(raw_move(Square::B7, Square::B8, 5)),
+            Some("b7b8k".parse().unwrap())
+        );
+        assert_eq!(decode_move(raw_move(Square::A7, Square::A8, 6)), None);
+        assert_eq!(
+            decode_move(0x8000 | 6 << 12 | u16::from(Square::E4)),
+            Some("P@e4".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_probe() {
+        let castling = pos(
+            Variant::Chess,
+            "r3k2r/pppppppp/8/8/8/8/PPPPPPPP/R3K2R w KQkq - 0 1",
+        );
+        let start = VariantPosition::Chess(Chess::default());
+        let mut data = Vec::new();
+        data.extend(entry(
+            key(&castling),
+            raw_move(Square::E1, Square::H1, 0),
+            3,
+        ));
+        data.extend(entry(key(&start), raw_move(Square::E2, Square::E4, 0), 10));
+        data.extend(entry(key(&start), raw_move(Square::D2, Square::D4, 0), 0));
+        // Collision with an illegal move.
+        data.extend(entry(key(&start), raw_move(Square::E2, Square::E5, 0), 5));
+        let book = Book::from_bytes(&data).unwrap();
+        assert_eq!(book.variant(), Variant::Chess);
+
+        assert_eq!(
+            book.probe(&start),
+            [
+                BookMove {
+                    uci: "e2e4".parse().unwrap(),
+                    weight: 10,
+                },
+                BookMove {
+                    uci: "d2d4".parse().unwrap(),
+                    weight: 0,
+                },
+            ]
+        );
+        assert_eq!(book.pick(&start), Some("e2e4".parse().unwrap()));
+
+        // Castling is encoded as the king moving onto the rook.
+        assert_eq!(book.probe(&castling)[0].uci, "e1h1".parse::<Uci>().unwrap());
+
+        assert!(!book.contains(&play(start, &["e2e4"])));
+    }
+
+    #[test]
+    fn test_extended_header() {
+        let atomic = pos(
+            Variant::Atomic,
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        );
+        let header = |version: u8, variant: u8| {
+            let mut data = MAGIC.to_vec();
+            data.extend_from_slice(&[version, variant, 0, 0]);
+            data
+        };
+
+        let mut data = header(FORMAT_VERSION, 2);
+        data.extend(entry(key(&atomic), raw_move(Square::G1, Square::F3, 0), 1));
+        let book = Book::from_bytes(&data).unwrap();
+        assert_eq!(book.variant(), Variant::Atomic);
+        assert!(book.contains(&atomic));
+        // Other variants are not looked up, even with the same key.
+        assert!(!book.contains(&VariantPosition::Chess(Chess::default())));
+
+        assert!(Book::from_bytes(&header(FORMAT_VERSION + 1, 2)).is_err());
+        assert!(Book::from_bytes(&header(FORMAT_VERSION, 8)).is_err());
+        assert!(Book::from_bytes(&data[..20]).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{api::LichessVariant, assets::EngineFlavor};
+
+/// Only consider outcomes within this window.
+const WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Do not judge before there are enough outcomes in the window.
+const MIN_SAMPLES: usize = 10;
+
+/// Degrade when at least this fraction of outcomes failed.
+const MAX_ERROR_RATE: f64 = 0.5;
+
+/// How long to stay degraded before trying again at full speed.
+const COOLDOWN: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// Requests to acquire work.
+    Api,
+    /// Engine errors and timeouts.
+    Engine(EngineFlavor),
+    /// Batches that could not be validated.
+    Validation(LichessVariant),
+}
+
+impl ErrorCategory {
+    fn action(self) -> &'static str {
+        match self {
+            ErrorCategory::Api => "pause acquiring",
+            ErrorCategory::Engine(_) => "abort batches for engine",
+            ErrorCategory::Validation(_) => "abort batches for variant",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCategory::Api => f.write_str("api"),
+            ErrorCategory::Engine(flavor) => write!(f, "engine ({})", flavor.name()),
+            ErrorCategory::Validation(variant) => write!(f, "validation ({})", variant),
+        }
+    }
+}
+
+/// A decision to degrade, recorded in the session stats.
+#[derive(Debug, Clone, Serialize)]
+pub struct Degradation {
+    pub category: String,
+    pub action: &'static str,
+    pub error_rate: f64,
+    /// Unix timestamp.
+    pub at: u64,
+    /// Seconds.
+    pub cooldown: u64,
+}
+
+struct Budget {
+    category: ErrorCategory,
+    outcomes: VecDeque<(Instant, bool)>,
+    degraded_until: Option<Instant>,
+}
+
+impl Budget {
+    fn prune(&mut self, now: Instant) {
+        while let Some(&(at, _)) = self.outcomes.front() {
+            if now.duration_since(at) <= WINDOW {
+                break;
+            }
+            self.outcomes.pop_front();
+        }
+    }
+
+    fn error_rate(&self) -> f64 {
+        let errors = self.outcomes.iter().filter(|(_, ok)| !ok).count();
+        errors as f64 / self.outcomes.len().max(1) as f64
+    }
+}
+
+/// Tracks rolling error rates per category, so that fishnet can back off
+/// instead of failing repeatedly at full speed.
+#[derive(Default)]
+pub struct ErrorBudget {
+    budgets: Vec<Budget>,
+}
+
+impl ErrorBudget {
+    fn budget(&mut self, category: ErrorCategory) -> &mut Budget {
+        let index = match self.budgets.iter().position(|b| b.category == category) {
+            Some(index) => index,
+            None => {
+                self.budgets.push(Budget {
+                    category,
+                    outcomes: VecDeque::new(),
+                    degraded_until: None,
+                });
+                self.budgets.len() - 1
+            }
+        };
+        &mut self.budgets[index]
+    }
+
+    /// Records an outcome. Returns a degradation if the category just
+    /// exceeded its error budget.
+    pub fn record(&mut self, category: ErrorCategory, ok: bool) -> Option<Degradation> {
+        let now = Instant::now();
+        let budget = self.budget(category);
+        if budget.degraded_until.map_or(false, |until| now < until) {
+            return None;
+        }
+        budget.prune(now);
+        budget.outcomes.push_back((now, ok));
+        if budget.outcomes.len() < MIN_SAMPLES || budget.error_rate() < MAX_ERROR_RATE {
+            return None;
+        }
+
+        let error_rate = budget.error_rate();
+        budget.degraded_until = Some(now + COOLDOWN);
+        budget.outcomes.clear();
+        Some(Degradation {
+            category: category.to_string(),
+            action: category.action(),
+            error_rate,
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            cooldown: COOLDOWN.as_secs(),
+        })
+    }
+
+    /// Time left until the category may be used at full speed again.
+    pub fn degraded(&self, category: ErrorCategory) -> Option<Duration> {
+        let now = Instant::now();
+        self.budgets
+            .iter()
+            .find(|b| b.category == category)
+            .and_then(|b| b.degraded_until)
+            .and_then(|until| until.checked_duration_since(now))
+            .filter(|left| *left > Duration::default())
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMilliSeconds};
+
+use crate::{
+    api::{LichessVariant, NodeLimit, Work},
+    assets::EngineFlavor,
+    cluster::WireResponse,
+    ipc::{Position, PositionResponse},
+    san,
+};
+
+/// Identifies a search request, independent of the game and batch it came
+/// from. Positions are compared by Zobrist hash, so that move counters and
+/// impossible en passant squares do not matter.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct CacheKey {
+    variant: LichessVariant,
+    zobrist: u64,
+    flavor: EngineFlavor,
+    nodes: NodeLimit,
+    depth: Option<u8>,
+    multipv: u8,
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    movetime: Option<Duration>,
+    wdl: bool,
+}
+
+impl CacheKey {
+    /// Only analysis is cached. Moves depend on the clock and skill level.
+    /// Engine option overrides are experiments, and not cached either.
+    fn new(position: &Position) -> Option<CacheKey> {
+        if !position.work.engine_options().is_empty() {
+            return None;
+        }
+        match position.work {
+            Work::Analysis {
+                nodes,
+                depth,
+                movetime,
+                wdl,
+                ..
+            } => Some(CacheKey {
+                variant: position.variant,
+                zobrist: san::position_key(position.variant, &position.root_fen, &position.moves)?,
+                flavor: position.flavor,
+                nodes,
+                depth,
+                multipv: position.work.multipv().get(),
+                movetime,
+                wdl,
+            }),
+            Work::Move { .. } | Work::Playout { .. } => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    key: CacheKey,
+    response: WireResponse,
+}
+
+/// Least recently used entries are evicted first.
+struct Lru {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<CacheKey, (u64, WireResponse)>,
+    order: BTreeMap<u64, CacheKey>,
+    hits: u64,
+    misses: u64,
+}
+
+impl Lru {
+    fn get(&mut self, key: &CacheKey) -> Option<WireResponse> {
+        match self.entries.get_mut(key) {
+            Some((used, response)) => {
+                self.order.remove(used);
+                self.tick += 1;
+                *used = self.tick;
+                self.order.insert(self.tick, key.clone());
+                self.hits += 1;
+                Some(response.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, key: CacheKey, response: WireResponse) {
+        if let Some((used, _)) = self.entries.remove(&key) {
+            self.order.remove(&used);
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (self.tick, response));
+        while self.entries.len() > self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            if let Some(key) = self.order.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+/// Results of analysed positions, so that positions repeated across
+/// batches, for example in the opening, are answered without searching
+/// again. Optionally kept in a file between sessions.
+pub struct ResultCache {
+    lru: Mutex<Lru>,
+    file: Option<PathBuf>,
+}
+
+impl ResultCache {
+    pub fn new(capacity: usize, file: Option<PathBuf>) -> ResultCache {
+        ResultCache {
+            lru: Mutex::new(Lru {
+                capacity,
+                tick: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                hits: 0,
+                misses: 0,
+            }),
+            file,
+        }
+    }
+
+    /// Reads the entries from the cache file, if any.
+    pub fn load(&self) -> io::Result<()> {
+        if let Some(ref file) = self.file {
+            let entries = load(file)?;
+            let mut lru = self.lru.lock().expect("result cache");
+            // Stored from least to most recently used.
+            for entry in entries {
+                lru.insert(entry.key, entry.response);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.lru.lock().expect("result cache").entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Hits and misses in this session.
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        let lru = self.lru.lock().expect("result cache");
+        (lru.hits, lru.misses)
+    }
+
+    pub fn get(&self, position: &Position) -> Option<PositionResponse> {
+        let started_at = Instant::now();
+        let key = CacheKey::new(position)?;
+        let response = self.lru.lock().expect("result cache").get(&key)?;
+        let mut res = response.into_response(position.clone());
+        res.cached = true;
+        res.latency = started_at.elapsed();
+        Some(res)
+    }
+
+    pub fn insert(&self, position: &Position, res: &PositionResponse) {
+        if let Some(key) = CacheKey::new(position) {
+            self.lru
+                .lock()
+                .expect("result cache")
+                .insert(key, WireResponse::from(res.clone()));
+        }
+    }
+
+    /// Writes the entries to the cache file, if any.
+    pub fn save(&self) -> io::Result<()> {
+        let file = match self.file {
+            Some(ref file) => file,
+            None => return Ok(()),
+        };
+        let entries: Vec<CacheEntry> = {
+            let lru = self.lru.lock().expect("result cache");
+            lru.order
+                .values()
+                .filter_map(|key| {
+                    lru.entries.get(key).map(|(_, response)| CacheEntry {
+                        key: key.clone(),
+                        response: response.clone(),
+                    })
+                })
+                .collect()
+        };
+        let data = serde_json::to_vec(&entries).expect("serialize result cache");
+        let tmp = file.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, file)
+    }
+}
+
+fn load(path: &Path) -> io::Result<Vec<CacheEntry>> {
+    match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
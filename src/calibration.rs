@@ -0,0 +1,90 @@
+use std::{
+    fs, io,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::assets::EngineFlavor;
+
+const CALIBRATION_FILENAME: &str = ".fishnet-calibration";
+
+fn calibration_path() -> io::Result<PathBuf> {
+    home::home_dir()
+        .map(|dir| dir.join(CALIBRATION_FILENAME))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Could not resolve ~/{}", CALIBRATION_FILENAME),
+            )
+        })
+}
+
+/// Speed of the engines on this machine, as measured by `fishnet
+/// benchmark` with all cores busy.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Calibration {
+    /// Nodes per second of each official Stockfish process.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub official: Option<u32>,
+    /// Nodes per second of each Fairy-Stockfish process.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multi_variant: Option<u32>,
+    /// Engine processes that were running in parallel.
+    pub processes: usize,
+    /// Search threads of each engine process.
+    pub threads: usize,
+    /// Unix timestamp of the measurement.
+    pub at: u64,
+}
+
+impl Calibration {
+    pub fn new(processes: usize, threads: usize) -> Calibration {
+        Calibration {
+            official: None,
+            multi_variant: None,
+            processes,
+            threads,
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    pub fn nps(&self, flavor: EngineFlavor) -> Option<u32> {
+        match flavor {
+            EngineFlavor::Official => self.official,
+            EngineFlavor::MultiVariant => self.multi_variant,
+        }
+    }
+
+    pub fn set_nps(&mut self, flavor: EngineFlavor, nps: u32) {
+        match flavor {
+            EngineFlavor::Official => self.official = Some(nps),
+            EngineFlavor::MultiVariant => self.multi_variant = Some(nps),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<PathBuf> {
+        let path = calibration_path()?;
+        fs::write(
+            &path,
+            serde_json::to_string_pretty(self).expect("serialize calibration"),
+        )?;
+        Ok(path)
+    }
+}
+
+/// Reads the calibration file, if `fishnet benchmark` has written one.
+pub fn load() -> io::Result<Option<Calibration>> {
+    match fs::read(calibration_path()?) {
+        Ok(buf) => serde_json::from_slice(&buf)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{api::AcquireResponseBody, cluster::WireResponse};
+
+const CHECKPOINT_FILENAME: &str = ".fishnet-checkpoint";
+
+/// Discard checkpoints older than this. By then the server has handed the
+/// batches to other clients.
+const MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Analysis that was interrupted by a shutdown, with the results of the
+/// positions that were already analysed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchCheckpoint {
+    /// URL of the endpoint that assigned the batch.
+    pub endpoint: String,
+    /// Batch as acquired, before scaling node limits.
+    pub body: AcquireResponseBody,
+    pub node_scale: Option<f64>,
+    /// Results by position id.
+    pub completed: Vec<Option<WireResponse>>,
+    /// Seconds since the Unix epoch.
+    pub saved_at: u64,
+}
+
+impl BatchCheckpoint {
+    pub fn new(
+        endpoint: String,
+        body: AcquireResponseBody,
+        node_scale: Option<f64>,
+        completed: Vec<Option<WireResponse>>,
+    ) -> BatchCheckpoint {
+        BatchCheckpoint {
+            endpoint,
+            body,
+            node_scale,
+            completed,
+            saved_at: unix_now(),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        unix_now().saturating_sub(self.saved_at) > MAX_AGE.as_secs()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn checkpoint_path() -> io::Result<PathBuf> {
+    home::home_dir()
+        .map(|dir| dir.join(CHECKPOINT_FILENAME))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Could not resolve ~/{}", CHECKPOINT_FILENAME),
+            )
+        })
+}
+
+fn load(path: &Path) -> io::Result<Vec<BatchCheckpoint>> {
+    match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+fn store(path: &Path, batches: &[BatchCheckpoint]) -> io::Result<()> {
+    if batches.is_empty() {
+        return match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        };
+    }
+    // Replace the file at once, so that a crash while writing does not
+    // leave a truncated checkpoint behind.
+    let tmp = path.with_extension("tmp");
+    fs::write(
+        &tmp,
+        serde_json::to_vec(batches).expect("serialize checkpoint"),
+    )?;
+    fs::rename(tmp, path)
+}
+
+/// Adds batches to the checkpoint file in the home directory, next to
+/// checkpoints of other instances.
+pub fn save(batches: Vec<BatchCheckpoint>) -> io::Result<PathBuf> {
+    let path = checkpoint_path()?;
+    let mut all = load(&path)?;
+    all.retain(|batch| !batch.is_expired());
+    all.extend(batches);
+    store(&path, &all)?;
+    Ok(path)
+}
+
+/// Removes and returns the checkpointed batches of the endpoint. Expired
+/// batches of all endpoints are dropped.
+pub fn take(endpoint: &str) -> io::Result<Vec<BatchCheckpoint>> {
+    let path = checkpoint_path()?;
+    let all = load(&path)?;
+    if all.is_empty() {
+        return Ok(all);
+    }
+    let (taken, others): (Vec<_>, Vec<_>) = all
+        .into_iter()
+        .filter(|batch| !batch.is_expired())
+        .partition(|batch| batch.endpoint == endpoint);
+    store(&path, &others)?;
+    Ok(taken)
+}
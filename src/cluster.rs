@@ -0,0 +1,464 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr, DurationMilliSeconds};
+use shakmaty::{
+    fen::{fen, Fen},
+    uci::Uci,
+};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    signal,
+    sync::{mpsc, oneshot},
+    time,
+};
+
+use crate::{
+    api::{LichessVariant, PlayoutPly, Score, Wdl, Work},
+    assets::{Assets, EngineFlavor},
+    ipc::{FailureReason, Matrix, Position, PositionFailed, PositionId, PositionResponse, Pull},
+    logger::Logger,
+    pool::EnginePool,
+    util::{NevermindExt as _, RandomizedBackoff},
+};
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+struct WirePosition {
+    work: Work,
+    flavor: EngineFlavor,
+    variant: LichessVariant,
+    root_fen: String,
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    moves: Vec<Uci>,
+}
+
+impl From<&Position> for WirePosition {
+    fn from(position: &Position) -> WirePosition {
+        WirePosition {
+            work: position.work.clone(),
+            flavor: position.flavor,
+            variant: position.variant,
+            root_fen: fen(&position.root_fen),
+            moves: position.moves.clone(),
+        }
+    }
+}
+
+/// Result of a position, without the position itself.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WireResponse {
+    scores: Matrix<Score>,
+    pvs: Matrix<Vec<String>>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    best_move: Option<Uci>,
+    depth: u8,
+    nodes: u64,
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    time: Duration,
+    nps: Option<u32>,
+    #[serde(default)]
+    wdl: Option<Wdl>,
+    hashfull: Option<u16>,
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    latency: Duration,
+    #[serde(default)]
+    cached: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    playout: Vec<PlayoutPly>,
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    #[serde(default)]
+    cpu_time: Option<Duration>,
+}
+
+impl From<PositionResponse> for WireResponse {
+    fn from(res: PositionResponse) -> WireResponse {
+        WireResponse {
+            scores: res.scores,
+            pvs: res
+                .pvs
+                .map(|pv| pv.into_iter().map(|uci| uci.to_string()).collect()),
+            best_move: res.best_move,
+            depth: res.depth,
+            nodes: res.nodes,
+            time: res.time,
+            nps: res.nps,
+            wdl: res.wdl,
+            hashfull: res.hashfull,
+            latency: res.latency,
+            cached: res.cached,
+            playout: res.playout,
+            cpu_time: res.cpu_time,
+        }
+    }
+}
+
+impl WireResponse {
+    pub fn into_response(self, position: Position) -> PositionResponse {
+        PositionResponse {
+            work: position.work,
+            position_id: position.position_id,
+            url: position.url,
+            scores: self.scores,
+            pvs: self.pvs.map(|pv| {
+                pv.iter()
+                    .map(|uci| uci.parse())
+                    .collect::<Result<_, _>>()
+                    .unwrap_or_default()
+            }),
+            best_move: self.best_move,
+            depth: self.depth,
+            nodes: self.nodes,
+            time: self.time,
+            nps: self.nps,
+            wdl: self.wdl,
+            hashfull: self.hashfull,
+            latency: self.latency,
+            cached: self.cached,
+            playout: self.playout,
+            cpu_time: self.cpu_time,
+        }
+    }
+}
+
+/// Sent from the coordinator to a follower.
+#[derive(Debug, Serialize, Deserialize)]
+struct Job {
+    id: u64,
+    position: WirePosition,
+}
+
+/// Sent from a follower to the coordinator.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum FollowerMessage {
+    Hello { workers: usize, version: String },
+    Result { id: u64, response: WireResponse },
+    Failed { id: u64 },
+}
+
+async fn write_line<T: Serialize>(write: &mut OwnedWriteHalf, msg: &T) -> io::Result<()> {
+    let mut line = serde_json::to_vec(msg).expect("serialize cluster message");
+    line.push(b'\n');
+    write.write_all(&line).await
+}
+
+/// Accepts followers while alive. Drop to stop accepting new followers.
+pub struct Coordinator {
+    _stop: oneshot::Sender<()>,
+}
+
+/// Listens for followers on the local network. The coordinator acquires
+/// batches from the fishnet API as usual, and each worker of a follower
+/// sends results to `tx` and requests more work, just like local workers.
+/// Messages are JSON lines over TCP.
+pub async fn spawn_coordinator(
+    bind: SocketAddr,
+    tx: mpsc::Sender<Pull>,
+    logger: Logger,
+) -> io::Result<Coordinator> {
+    let listener = TcpListener::bind(bind).await?;
+    let (stop, mut stopped) = oneshot::channel();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = &mut stopped => break,
+                _ = tx.closed() => break,
+                res = listener.accept() => match res {
+                    Ok((stream, peer)) => {
+                        tokio::spawn(serve_follower(stream, peer, tx.clone(), logger.clone()));
+                    }
+                    Err(err) => logger.warn(&format!("Failed to accept follower: {}", err)),
+                }
+            }
+        }
+        logger.debug("No longer accepting followers");
+    });
+    Ok(Coordinator { _stop: stop })
+}
+
+/// Connection to a follower, shared by its workers.
+#[derive(Clone)]
+struct Link {
+    out: mpsc::Sender<Job>,
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<WireResponse>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Link {
+    async fn go(&self, position: Position) -> Result<PositionResponse, PositionFailed> {
+        let failed = PositionFailed::new(&position, FailureReason::Disconnected);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (callback, response) = oneshot::channel();
+        self.pending
+            .lock()
+            .expect("pending follower jobs")
+            .insert(id, callback);
+        let job = Job {
+            id,
+            position: WirePosition::from(&position),
+        };
+        if self.out.send(job).await.is_err() {
+            return Err(failed);
+        }
+        tokio::select! {
+            _ = self.out.closed() => Err(failed),
+            res = response => match res {
+                Ok(wire) => Ok(wire.into_response(position)),
+                Err(_) => Err(failed),
+            },
+        }
+    }
+}
+
+async fn serve_follower(
+    stream: TcpStream,
+    peer: SocketAddr,
+    tx: mpsc::Sender<Pull>,
+    logger: Logger,
+) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    let workers = match lines.next_line().await {
+        Ok(Some(line)) => match serde_json::from_str(&line) {
+            Ok(FollowerMessage::Hello { workers, version }) => {
+                if version != env!("CARGO_PKG_VERSION") {
+                    logger.warn(&format!(
+                        "Follower {} runs fishnet {}, coordinator runs {}",
+                        peer,
+                        version,
+                        env!("CARGO_PKG_VERSION")
+                    ));
+                }
+                workers
+            }
+            _ => {
+                logger.warn(&format!("Unexpected hello from {}", peer));
+                return;
+            }
+        },
+        _ => return,
+    };
+    logger.fishnet_info(&format!(
+        "Follower {} connected with {} workers",
+        peer, workers
+    ));
+
+    let (out_tx, mut out_rx) = mpsc::channel::<Job>(workers.max(1));
+    let writer = tokio::spawn(async move {
+        while let Some(job) = out_rx.recv().await {
+            if write_line(&mut write, &job).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let link = Link {
+        out: out_tx,
+        pending: Arc::new(Mutex::new(HashMap::new())),
+        next_id: Arc::new(AtomicU64::new(0)),
+    };
+    for _ in 0..workers {
+        tokio::spawn(remote_worker(tx.clone(), link.clone()));
+    }
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        match serde_json::from_str(&line) {
+            Ok(FollowerMessage::Result { id, response }) => {
+                if let Some(callback) = link
+                    .pending
+                    .lock()
+                    .expect("pending follower jobs")
+                    .remove(&id)
+                {
+                    callback.send(response).nevermind("follower worker gone");
+                }
+            }
+            Ok(FollowerMessage::Failed { id }) => {
+                link.pending
+                    .lock()
+                    .expect("pending follower jobs")
+                    .remove(&id);
+            }
+            Ok(FollowerMessage::Hello { .. }) | Err(_) => {
+                logger.warn(&format!("Unexpected message from follower {}", peer));
+                break;
+            }
+        }
+    }
+
+    // Fail all pending and future jobs of this follower.
+    writer.abort();
+    link.pending.lock().expect("pending follower jobs").clear();
+    logger.warn(&format!("Follower {} disconnected", peer));
+}
+
+/// Pulls work from the queue for a single worker of a follower.
+async fn remote_worker(tx: mpsc::Sender<Pull>, link: Link) {
+    let mut response = None;
+    loop {
+        let (callback, waiter) = oneshot::channel();
+        if tx
+            .send(Pull {
+                response: response.take(),
+                callback: Some(callback),
+                reserved: false,
+            })
+            .await
+            .is_err()
+        {
+            return;
+        }
+        let position = tokio::select! {
+            _ = tx.closed() => return,
+            res = waiter => match res {
+                Ok(position) => position,
+                Err(_) => return,
+            },
+        };
+        let res = link.go(position).await;
+        let disconnected = res.is_err() && link.out.is_closed();
+        response = Some(res);
+        if disconnected {
+            break;
+        }
+    }
+
+    // Hand in the failure, so that the position can be retried.
+    tx.send(Pull {
+        response,
+        callback: None,
+        reserved: false,
+    })
+    .await
+    .nevermind("queue dropped");
+}
+
+/// Runs engines for a coordinator, reconnecting until interrupted.
+pub async fn follow(coordinator: String, workers: usize, assets: Arc<Assets>, logger: Logger) {
+    let (pool, pool_join_handle) = EnginePool::spawn(assets.clone(), workers, logger.clone());
+    let mut backoff = RandomizedBackoff::default();
+    loop {
+        tokio::select! {
+            res = signal::ctrl_c() => {
+                res.expect("ctrl+c handler installed");
+                break;
+            }
+            res = follow_connection(&coordinator, &pool, workers, &assets, &logger) => {
+                match res {
+                    Ok(()) => {
+                        backoff.reset();
+                        logger.warn(&format!("Coordinator {} closed the connection", coordinator));
+                    }
+                    Err(err) => logger.warn(&format!("Connection to coordinator {} failed: {}", coordinator, err)),
+                }
+            }
+        }
+        let wait = backoff.next();
+        logger.info(&format!("Reconnecting in {:?}", wait));
+        tokio::select! {
+            res = signal::ctrl_c() => {
+                res.expect("ctrl+c handler installed");
+                break;
+            }
+            _ = time::sleep(wait) => (),
+        }
+    }
+
+    logger.headline("Stopping engines ...");
+    drop(pool);
+    pool_join_handle.await.expect("join");
+}
+
+async fn follow_connection(
+    coordinator: &str,
+    pool: &EnginePool,
+    workers: usize,
+    assets: &Assets,
+    logger: &Logger,
+) -> io::Result<()> {
+    let (read, mut write) = TcpStream::connect(coordinator).await?.into_split();
+    write_line(
+        &mut write,
+        &FollowerMessage::Hello {
+            workers,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+        },
+    )
+    .await?;
+    logger.fishnet_info(&format!(
+        "Connected to coordinator {} with {} workers",
+        coordinator, workers
+    ));
+
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<FollowerMessage>();
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = out_rx.recv().await {
+            if write_line(&mut write, &msg).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut lines = BufReader::new(read).lines();
+    let res = loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break Ok(()),
+            Err(err) => break Err(err),
+        };
+        let job: Job = match serde_json::from_str(&line) {
+            Ok(job) => job,
+            Err(err) => break Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        };
+
+        let id = job.id;
+        let root_fen: Fen = match job.position.root_fen.parse() {
+            Ok(root_fen) if assets.stockfish.get(job.position.flavor).is_some() => root_fen,
+            _ => {
+                logger.warn(&format!("Cannot analyse job {} from coordinator", id));
+                out_tx
+                    .send(FollowerMessage::Failed { id })
+                    .nevermind("writer gone");
+                continue;
+            }
+        };
+        let pending = pool
+            .submit(Position {
+                work: job.position.work,
+                position_id: PositionId(0),
+                flavor: job.position.flavor,
+                url: None,
+                variant: job.position.variant,
+                root_fen,
+                moves: job.position.moves,
+            })
+            .await;
+        let out_tx = out_tx.clone();
+        tokio::spawn(async move {
+            out_tx
+                .send(match pending.await {
+                    Ok(res) => FollowerMessage::Result {
+                        id,
+                        response: WireResponse::from(res),
+                    },
+                    Err(_) => FollowerMessage::Failed { id },
+                })
+                .nevermind("writer gone");
+        });
+    };
+    writer.abort();
+    res
+}
@@ -1,22 +1,37 @@
 use std::{
-    cmp::max,
+    cmp::{max, Reverse},
+    env,
     error::Error,
     fmt, fs, io,
     io::Write,
-    num::{NonZeroUsize, ParseIntError},
+    net::SocketAddr,
+    num::{NonZeroU32, NonZeroUsize, ParseIntError},
     path::PathBuf,
     str::FromStr,
     time::Duration,
 };
 
-use clap::Parser;
+use clap::{IntoApp as _, Parser};
 use configparser::ini::Ini;
 use url::Url;
 
-use crate::{api, logger::Logger};
+use crate::{
+    affinity, api,
+    api::{LichessVariant, UnknownVariant},
+    assets::{ByEngineFlavor, Cpu, EngineFlavor},
+    control::ControlCommand,
+    happy_eyeballs::IpPreference,
+    logger::Logger,
+};
 
 const DEFAULT_ENDPOINT: &str = "https://lichess.org/fishnet";
 
+pub const DEFAULT_MAX_BACKOFF: &str = "30s";
+
+const DEFAULT_ENDPOINT_NAME: &str = "default";
+
+const ENDPOINT_SECTION_PREFIX: &str = "endpoint.";
+
 /// Distributed Stockfish analysis for lichess.org.
 #[derive(Debug, Parser)]
 #[clap(setting = clap::AppSettings::DisableHelpSubcommand, version)]
@@ -25,9 +40,34 @@ pub struct Opt {
     pub verbose: Verbose,
 
     /// Automatically install available updates on startup and at random
-    /// intervals.
+    /// intervals. Optionally select the release channel (stable, beta or
+    /// off).
+    #[clap(
+        long,
+        global = true,
+        require_equals = true,
+        min_values = 0,
+        max_values = 1,
+        default_missing_value = "stable",
+        default_value = "off"
+    )]
+    pub auto_update: UpdateChannel,
+
+    /// Signed manifest of engine builds and networks that are newer than
+    /// the bundled ones, for the update-engines command.
     #[clap(long, global = true)]
-    pub auto_update: bool,
+    pub engine_manifest: Option<Url>,
+
+    /// Also check the engine manifest at this interval while running (for
+    /// example 6h), and switch to new builds between batches.
+    #[clap(long, requires = "engine-manifest", global = true)]
+    pub engine_update_interval: Option<ParsedDuration>,
+
+    /// Limit the bandwidth of downloads of engine builds and NNUE networks
+    /// to this many bytes per second (for example 2MiB or 5MB/s).
+    /// Interrupted downloads are resumed, also from mirrors.
+    #[clap(long, global = true)]
+    pub download_rate_limit: Option<ParsedRate>,
 
     /// Configuration file.
     #[clap(long, parse(from_os_str), default_value = "fishnet.ini", global = true)]
@@ -49,235 +89,2108 @@ pub struct Opt {
     #[clap(long, global = true)]
     pub endpoint: Option<Endpoint>,
 
+    /// Send all requests to the endpoint through an HTTP CONNECT or SOCKS5
+    /// proxy, like http://proxy:3128 or socks5://proxy:1080. Defaults to
+    /// the ALL_PROXY environment variable.
+    #[clap(long, global = true)]
+    pub proxy: Option<Proxy>,
+
+    /// Try IPv4 addresses of the endpoint first. IPv6 is still tried if
+    /// connecting over IPv4 is slow or fails.
+    #[clap(long, conflicts_with = "prefer-ipv6", global = true)]
+    pub prefer_ipv4: bool,
+
+    /// Try IPv6 addresses of the endpoint first. IPv4 is still tried if
+    /// connecting over IPv6 is slow or fails. Defaults to the order of the
+    /// system resolver.
+    #[clap(long, global = true)]
+    pub prefer_ipv6: bool,
+
+    /// Acquire work through a websocket, so that the server can push
+    /// batches as soon as they are available. Falls back to long polling if
+    /// the server does not support it. Not used through a proxy.
+    #[clap(long, global = true)]
+    pub websocket: bool,
+
     /// Number of logical CPU cores to use for engine processes
     /// (or auto for n - 1, or all for n).
     #[clap(long, alias = "threads", global = true)]
     pub cores: Option<Cores>,
 
+    /// Keep this many cores free for user requests (moves and analysis
+    /// requested by users), instead of using them for system analysis. At
+    /// least one core stays available for system analysis.
+    #[clap(long, global = true)]
+    pub reserve_cores: Option<usize>,
+
+    /// What to do while running on battery (ignore, pause, or one-core).
+    /// Paused or reduced workers resume when AC power returns.
+    #[clap(long, global = true)]
+    pub on_battery: Option<OnBattery>,
+
+    /// Pause while the machine is in use, judging by keyboard and mouse
+    /// input on Windows, and by CPU usage of other processes on Linux.
+    /// Workers resume after 5 minutes without activity.
+    #[clap(long, global = true)]
+    pub pause_when_active: bool,
+
+    /// Use one core less whenever the CPU is hotter than this many degrees
+    /// Celsius (for example 85), and one more again once it cooled down by
+    /// 5 degrees (Linux only).
+    #[clap(long, global = true)]
+    pub max_temperature: Option<u8>,
+
+    /// Share the cores of this host with other fishnet instances that use
+    /// the same coordination directory (for example with different keys),
+    /// instead of each using its configured number of cores. Instances get
+    /// a fair share, and more once another instance drains or stops.
+    #[clap(long, parse(from_os_str), global = true)]
+    pub core_lease_dir: Option<PathBuf>,
+
     /// Maximum backoff time. The client will use randomized expontential
     /// backoff when repeatedly receiving no job.
-    #[clap(long, default_value = "30s", global = true)]
+    #[clap(long, default_value = DEFAULT_MAX_BACKOFF, global = true)]
     pub max_backoff: ParsedDuration,
 
+    /// Maximum backoff time after failed requests (default 30s). The
+    /// client additionally honors Retry-After headers, and pauses all
+    /// requests for a while after repeated server errors.
+    #[clap(long, global = true)]
+    pub max_error_backoff: Option<ParsedDuration>,
+
     #[clap(flatten)]
     pub backlog: BacklogOpt,
 
+    #[clap(flatten)]
+    pub limits: LimitsOpt,
+
+    #[clap(flatten)]
+    pub engine: EngineOpt,
+
+    #[clap(flatten)]
+    pub format: FormatOpt,
+
+    #[clap(flatten)]
+    pub log_file: LogFileOpt,
+
+    #[clap(flatten)]
+    pub lookup: LookupOpt,
+
+    #[clap(flatten)]
+    pub verify: VerifyOpt,
+
+    /// Do not use official Stockfish. Standard chess will not be acquired.
+    #[clap(long, conflicts_with = "no-multivariant", global = true)]
+    pub no_official_stockfish: bool,
+
+    /// Do not use Fairy-Stockfish. Variants and move requests will not be
+    /// acquired. Implied for builds without the all-variants feature.
+    #[clap(long, global = true)]
+    pub no_multivariant: bool,
+
+    /// Analyse standard chess with Fairy-Stockfish instead of official
+    /// Stockfish, for platforms where the official build is not available.
+    /// Moves are exchanged in Chess960 UCI notation with both engines, so
+    /// no translation is needed.
+    #[clap(long, conflicts_with = "no-multivariant", global = true)]
+    pub force_multivariant: bool,
+
+    /// Use the engine builds for this instruction set level (sse2,
+    /// sse41-popcnt, avx2, bmi2, avx512 or vnni512), instead of the fastest
+    /// builds supported by the detected CPU features. Engines crash if the
+    /// CPU does not support the level.
+    #[clap(long, global = true)]
+    pub force_cpu_level: Option<CpuLevel>,
+
+    /// Directories with Syzygy tablebases (separated by : or on Windows by
+    /// ;), probed by official Stockfish in standard chess endgames.
+    /// Variants are analysed without tablebases.
+    #[clap(long, parse(from_os_str), global = true)]
+    pub syzygy_path: Option<PathBuf>,
+
+    /// NNUE networks for Fairy-Stockfish variants, like
+    /// atomic=<sha256>@<url>, separated by commas. Mirrors can follow the
+    /// url, like atomic=<sha256>@<url>|<mirror>. Networks are downloaded
+    /// once, checked against the SHA-256 checksum and cached. Variants
+    /// without a network use the classical evaluation.
+    #[clap(long, global = true)]
+    pub variant_nets: Option<VariantNets>,
+
+    /// Third-party UCI engines to use instead of the bundled engines for
+    /// some variants, like atomic=/usr/local/bin/atomkraft, separated by
+    /// commas. Each engine must offer the variant in its UCI_Variant option,
+    /// which is checked at startup.
+    #[clap(long, global = true)]
+    pub external_engines: Option<ExternalEngines>,
+
+    /// Accept only work for these variants, like standard,chess960,atomic,
+    /// separated by commas. Applies to all endpoints, and is sent to the
+    /// server when acquiring work, so that other variants are not offered.
+    #[clap(long, global = true)]
+    pub variants_allow: Option<VariantList>,
+
+    /// Never accept work for these variants, like horde,racingkings,
+    /// separated by commas. Applies to all endpoints, and is sent to the
+    /// server when acquiring work.
+    #[clap(long, global = true)]
+    pub variants_deny: Option<VariantList>,
+
+    /// Never search more nodes per position than this, whatever the server
+    /// requests (after scaling with --scale-nodes).
+    #[clap(long, global = true)]
+    pub max_nodes: Option<u64>,
+
+    /// Never search deeper than this per position.
+    #[clap(long, global = true)]
+    pub max_depth: Option<u8>,
+
+    /// Stop the search of each position after this time (for example 2s),
+    /// even if the node limit is not reached.
+    #[clap(long, global = true)]
+    pub movetime: Option<ParsedDuration>,
+
+    /// Scale node targets to the measured speed of this machine, within
+    /// bounds allowed by the server. Slow machines search fewer nodes, fast
+    /// machines search more. The factor is reported with the analysis.
+    #[clap(long, global = true)]
+    pub scale_nodes: bool,
+
+    /// Include the principal variation of each analysed position in SAN,
+    /// in addition to UCI, for servers that want to show it as is.
+    #[clap(long, global = true)]
+    pub pv_san: bool,
+
+    /// Order in which positions of a game are analysed: forward from the
+    /// start (default), or backward from the final position, for servers
+    /// that show the end of the game first. Progress reports follow the
+    /// same order.
+    #[clap(long, global = true)]
+    pub analysis_order: Option<AnalysisOrder>,
+
+    /// Spread the node budget of each game by phase: more nodes for
+    /// volatile middlegame positions, fewer for forced moves, the opening
+    /// and dead drawn endings. The total for the game stays the same.
+    #[clap(long, global = true)]
+    pub budget_by_phase: bool,
+
+    /// Mark analysed positions after a blunder, where exactly one move
+    /// wins, as puzzle candidates. Needs analysis with at least two lines,
+    /// as requested by the server.
+    #[clap(long, global = true)]
+    pub puzzle_candidates: bool,
+
+    /// Target for the 99th percentile of the time from receiving a move
+    /// request to submitting the move. Warns while the target is missed,
+    /// with a breakdown of where the time was spent.
+    #[clap(long, global = true)]
+    pub move_latency_budget: Option<ParsedDuration>,
+
+    /// Use only the named endpoint, as configured in an [Endpoint.<name>]
+    /// section of the configuration file. Otherwise all configured endpoints
+    /// are used, falling back from the highest weight to the lowest, while
+    /// an endpoint keeps failing.
+    #[clap(long, global = true)]
+    pub only_endpoint: Option<String>,
+
+    /// Name of this machine, sent to the server with each request and shown
+    /// in the progress line, to tell machines apart.
+    #[clap(long, global = true)]
+    pub worker_name: Option<String>,
+
+    /// Labels of this machine, like region=eu,rack=3, separated by commas.
+    /// Sent to the server with each request.
+    #[clap(long, global = true)]
+    pub labels: Option<Labels>,
+
+    #[clap(skip)]
+    pub endpoint_confs: Vec<EndpointConf>,
+
+    /// Client certificate (PEM) for endpoints that require mutual TLS.
+    /// Reloaded together with --tls-key on SIGHUP.
+    #[clap(long, parse(from_os_str), requires = "tls-key", global = true)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) of --tls-cert.
+    #[clap(long, parse(from_os_str), requires = "tls-cert", global = true)]
+    pub tls_key: Option<PathBuf>,
+
+    /// Serve a status page and JSON status on this local address (for
+    /// example 127.0.0.1:9281).
+    #[clap(long, global = true)]
+    pub status_bind: Option<SocketAddr>,
+
+    /// Serve only Prometheus metrics on this address (for example
+    /// 127.0.0.1:9184). Unlike the status server, it accepts no control
+    /// commands.
+    #[clap(long, global = true)]
+    pub metrics_bind: Option<SocketAddr>,
+
+    /// Export batch traces to this OpenTelemetry collector, using OTLP over
+    /// HTTP (for example http://localhost:4318/v1/traces).
+    #[clap(long, global = true)]
+    pub otlp_endpoint: Option<Url>,
+
+    /// Post a JSON record to this URL when an engine behaves suspiciously,
+    /// for example when it suddenly becomes much slower.
+    #[clap(long, global = true)]
+    pub anomaly_webhook: Option<Url>,
+
+    /// Write batches that fail validation to this directory, as JSON with
+    /// the error and the full server response.
+    #[clap(long, parse(from_os_str), global = true)]
+    pub invalid_batch_dir: Option<PathBuf>,
+
+    /// Remove the oldest dumped batches to stay within this size (default
+    /// 64MiB).
+    #[clap(long, global = true)]
+    pub invalid_batch_max_size: Option<ParsedSize>,
+
+    /// Analyse the acquire responses recorded in this file (a JSON array,
+    /// or one JSON object per line) instead of requesting work from a
+    /// server, and stop once all of them are done.
+    #[clap(long, parse(from_os_str), global = true)]
+    pub replay: Option<PathBuf>,
+
+    /// Write the would-be submissions of --replay to this file, one JSON
+    /// object per line. Defaults to the replayed file with the extension
+    /// submissions.jsonl.
+    #[clap(long, parse(from_os_str), requires = "replay", global = true)]
+    pub replay_out: Option<PathBuf>,
+
+    /// Log every line exchanged with the engine processes, with a
+    /// timestamp, the process id and the batch and position.
+    #[clap(long, global = true)]
+    pub trace_engine: bool,
+
+    /// Write the lines of --trace-engine to a file per engine process in
+    /// this directory, instead of the log.
+    #[clap(long, parse(from_os_str), global = true)]
+    pub trace_engine_dir: Option<PathBuf>,
+
+    /// Remove the least recently written trace files to stay within this
+    /// size (default 256MiB).
+    #[clap(long, global = true)]
+    pub trace_engine_max_size: Option<ParsedSize>,
+
+    /// Append a JSON line with provenance information (engine hash, node
+    /// target, durations) for each submitted batch to this file.
+    #[clap(long, parse(from_os_str), global = true)]
+    pub audit_log: Option<PathBuf>,
+
+    /// Emit line-delimited JSON events (batch_acquired, position_done,
+    /// batch_submitted, engine_restarted) for orchestration tooling, to
+    /// stdout (moving logs to stderr) or to an inherited file descriptor,
+    /// like fd:3.
+    #[clap(long, global = true)]
+    pub event_stream: Option<EventTarget>,
+
+    /// Do not keep a journal of analysis in progress next to the
+    /// configuration file. Without it, completed positions of batches that
+    /// were not submitted before a crash are lost. Instances that share a
+    /// configuration directory should use separate configuration files.
+    #[clap(long, global = true)]
+    pub no_journal: bool,
+
+    /// Accept commands for the running instance on this Unix socket (see
+    /// the ctl command).
+    #[clap(long, parse(from_os_str), global = true)]
+    pub control_socket: Option<PathBuf>,
+
+    /// Write analysis results as JSON lines to S3-compatible object storage
+    /// instead of submitting them, one object per batch. Use a path-style
+    /// URL with bucket and prefix (for example
+    /// https://s3.eu-central-1.amazonaws.com/bucket/prefix/). Credentials
+    /// are read from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY.
+    #[clap(long, global = true)]
+    pub s3_sink: Option<Url>,
+
+    /// Region for signing requests to the S3 sink (default us-east-1).
+    #[clap(long, global = true)]
+    pub s3_region: Option<String>,
+
+    /// Act as coordinator of a cluster: accept follower instances on this
+    /// address (for example 0.0.0.0:9283), and distribute positions to
+    /// them. Only use on trusted networks.
+    #[clap(long, global = true)]
+    pub cluster_bind: Option<SocketAddr>,
+
+    /// Print a summary of capabilities and configuration, then exit.
+    #[clap(long, global = true)]
+    pub describe: bool,
+
+    /// Print JSON instead of text (with --describe or the stats command).
+    #[clap(long, global = true)]
+    pub json: bool,
+
     #[clap(subcommand)]
     pub command: Option<Command>,
 }
 
 impl Opt {
+    /// Logs go to stderr, to keep stdout for the output of the command or
+    /// the event stream.
+    pub fn logs_to_stderr(&self) -> bool {
+        self.command.as_ref().map_or(false, Command::logs_to_stderr)
+            || self.event_stream == Some(EventTarget::Stdout)
+    }
+
     pub fn endpoint(&self) -> Endpoint {
         self.endpoint.clone().unwrap_or_default()
     }
+
+    pub fn proxy(&self) -> Option<Proxy> {
+        self.proxy.clone().or_else(Proxy::from_env)
+    }
+
+    /// Journal of analysis in progress, next to the configuration file.
+    pub fn journal_path(&self) -> Option<PathBuf> {
+        (!self.no_journal).then(|| self.conf.with_file_name("fishnet.journal"))
+    }
+
+    /// Failed submissions waiting for a retry, next to the configuration
+    /// file.
+    pub fn outbox_dir(&self) -> PathBuf {
+        self.conf.with_file_name("fishnet-outbox")
+    }
+
+    pub fn ip_preference(&self) -> IpPreference {
+        if self.prefer_ipv4 {
+            IpPreference::Ipv4
+        } else if self.prefer_ipv6 {
+            IpPreference::Ipv6
+        } else {
+            IpPreference::System
+        }
+    }
+
+    /// CPU features used to select the engine builds.
+    pub fn cpu(&self) -> Cpu {
+        self.force_cpu_level.map_or_else(Cpu::detect, CpuLevel::cpu)
+    }
+
+    pub fn enabled_engines(&self) -> ByEngineFlavor<bool> {
+        let multi_variant = cfg!(feature = "all-variants") && !self.no_multivariant;
+        ByEngineFlavor {
+            official: !self.no_official_stockfish && !(self.force_multivariant && multi_variant),
+            multi_variant,
+        }
+    }
+
+    pub(crate) fn default_endpoint_conf(&self) -> EndpointConf {
+        EndpointConf {
+            name: DEFAULT_ENDPOINT_NAME.to_owned(),
+            endpoint: self.endpoint(),
+            key: self.key.clone(),
+            proxy: self.proxy(),
+            backlog: self.backlog.clone(),
+            variants: VariantFilter::default(),
+            weight: 1,
+        }
+    }
 }
 
+/// An endpoint with its own key, backlog strategy and variant filter.
 #[derive(Debug, Clone)]
-pub struct Endpoint {
+pub struct EndpointConf {
+    pub name: String,
+    pub endpoint: Endpoint,
+    pub key: Option<Key>,
+    pub proxy: Option<Proxy>,
+    pub backlog: BacklogOpt,
+    pub variants: VariantFilter,
+    pub weight: u32,
+}
+
+impl EndpointConf {
+    fn from_ini(ini: &Ini, section: &str, name: &str, defaults: &Opt) -> EndpointConf {
+        EndpointConf {
+            name: name.to_owned(),
+            endpoint: ini
+                .get(section, "Url")
+                .map(|e| e.parse().expect("valid endpoint url"))
+                .unwrap_or_else(|| panic!("missing Url for endpoint {:?}", name)),
+            key: ini
+                .get(section, "Key")
+                .map(|k| k.parse().expect("valid endpoint key"))
+                .or_else(|| defaults.key.clone()),
+            proxy: ini
+                .get(section, "Proxy")
+                .map(|p| p.parse().expect("valid endpoint proxy"))
+                .or_else(|| defaults.proxy()),
+            backlog: BacklogOpt {
+                user: ini
+                    .get(section, "UserBacklog")
+                    .map(|b| b.parse().expect("valid endpoint user backlog"))
+                    .or(defaults.backlog.user),
+                system: ini
+                    .get(section, "SystemBacklog")
+                    .map(|b| b.parse().expect("valid endpoint system backlog"))
+                    .or(defaults.backlog.system),
+            },
+            variants: ini
+                .get(section, "Variants")
+                .map(|v| v.parse().expect("valid endpoint variants"))
+                .unwrap_or_default(),
+            weight: ini
+                .get(section, "Weight")
+                .map_or(1, |w| w.trim().parse().expect("valid endpoint weight")),
+        }
+    }
+}
+
+/// Variants that will be accepted from an endpoint. Accepts all variants if
+/// not configured.
+#[derive(Debug, Clone, Default)]
+pub struct VariantFilter {
+    allowed: Option<Vec<LichessVariant>>,
+    denied: Vec<LichessVariant>,
+}
+
+impl VariantFilter {
+    pub fn allows(&self, variant: LichessVariant) -> bool {
+        self.allowed
+            .as_ref()
+            .map_or(true, |allowed| allowed.contains(&variant))
+            && !self.denied.contains(&variant)
+    }
+
+    /// Narrows the filter with --variants-allow and --variants-deny.
+    pub fn restrict(&mut self, allow: Option<&VariantList>, deny: Option<&VariantList>) {
+        if let Some(VariantList(allow)) = allow {
+            self.allowed = Some(match self.allowed.take() {
+                Some(allowed) => allowed.into_iter().filter(|v| allow.contains(v)).collect(),
+                None => allow.clone(),
+            });
+        }
+        if let Some(VariantList(deny)) = deny {
+            self.denied.extend(deny);
+        }
+    }
+
+    /// Accepts the variants that any of the filters accepts, for example to
+    /// route batches from several endpoints.
+    pub fn any_of<'a>(filters: impl IntoIterator<Item = &'a VariantFilter>) -> VariantFilter {
+        let filters: Vec<_> = filters.into_iter().collect();
+        let allowed: Vec<_> = LichessVariant::ALL
+            .iter()
+            .copied()
+            .filter(|&v| filters.iter().any(|filter| filter.allows(v)))
+            .collect();
+        VariantFilter {
+            allowed: (allowed.len() < LichessVariant::ALL.len()).then(|| allowed),
+            denied: Vec::new(),
+        }
+    }
+
+    /// Variants to ask the server for, or `None` if all variants are
+    /// accepted.
+    pub fn accepted(&self) -> Option<VariantList> {
+        if self.allowed.is_none() && self.denied.is_empty() {
+            None
+        } else {
+            Some(VariantList(
+                LichessVariant::ALL
+                    .iter()
+                    .copied()
+                    .filter(|&v| self.allows(v))
+                    .collect(),
+            ))
+        }
+    }
+}
+
+impl FromStr for VariantFilter {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let VariantList(allowed) = s.parse()?;
+        Ok(VariantFilter {
+            allowed: Some(allowed),
+            denied: Vec::new(),
+        })
+    }
+}
+
+impl fmt::Display for VariantFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.accepted() {
+            Some(accepted) => accepted.fmt(f),
+            None => f.write_str("all"),
+        }
+    }
+}
+
+/// Variants, separated by commas.
+#[derive(Debug, Clone, Default)]
+pub struct VariantList(pub Vec<LichessVariant>);
+
+impl FromStr for VariantList {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(VariantList(
+            s.split(',')
+                .filter(|v| !v.trim().is_empty())
+                .map(LichessVariant::from_str)
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+impl fmt::Display for VariantList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(
+            &self
+                .0
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+    }
+}
+
+/// NNUE network for a variant, to be downloaded from `url` and verified
+/// against the hex encoded SHA-256 checksum.
+#[derive(Debug, Clone)]
+pub struct VariantNet {
+    pub variant: LichessVariant,
+    pub sha256: String,
     pub url: Url,
+    /// Tried in order when downloading from `url` fails.
+    pub mirrors: Vec<Url>,
 }
 
-impl Default for Endpoint {
-    fn default() -> Endpoint {
-        DEFAULT_ENDPOINT.parse().expect("default endpoint is valid")
+#[derive(Debug)]
+pub struct VariantNetError;
+
+impl fmt::Display for VariantNetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected <variant>=<sha256>@<url>, optionally followed by |<mirror>")
     }
 }
 
-impl fmt::Display for Endpoint {
+impl Error for VariantNetError {}
+
+impl FromStr for VariantNet {
+    type Err = VariantNetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (variant, rest) = s.trim().split_once('=').ok_or(VariantNetError)?;
+        let (sha256, urls) = rest.split_once('@').ok_or(VariantNetError)?;
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(VariantNetError);
+        }
+        let mut urls = urls
+            .split('|')
+            .map(|url| url.trim().parse().map_err(|_| VariantNetError));
+        Ok(VariantNet {
+            variant: variant.parse().map_err(|_| VariantNetError)?,
+            sha256: sha256.to_ascii_lowercase(),
+            url: urls.next().ok_or(VariantNetError)??,
+            mirrors: urls.collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl fmt::Display for VariantNet {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(&self.url, f)
+        write!(f, "{}={}@{}", self.variant, self.sha256, self.url)?;
+        for mirror in &self.mirrors {
+            write!(f, "|{}", mirror)?;
+        }
+        Ok(())
     }
 }
 
-impl FromStr for Endpoint {
-    type Err = url::ParseError;
+/// Preferred NNUE networks of Fairy-Stockfish variants, separated by commas.
+#[derive(Debug, Clone, Default)]
+pub struct VariantNets(pub Vec<VariantNet>);
+
+impl FromStr for VariantNets {
+    type Err = VariantNetError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut url: Url = s.parse()?;
-        if let Some(stripped_path) = url.path().to_owned().strip_suffix('/') {
-            url.set_path(stripped_path);
+        Ok(VariantNets(
+            s.split(',')
+                .filter(|net| !net.trim().is_empty())
+                .map(VariantNet::from_str)
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+impl fmt::Display for VariantNets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(
+            &self
+                .0
+                .iter()
+                .map(|net| net.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+    }
+}
+
+/// Third-party UCI engine for a variant.
+#[derive(Debug, Clone)]
+pub struct ExternalEngine {
+    pub variant: LichessVariant,
+    pub path: PathBuf,
+}
+
+#[derive(Debug)]
+pub struct ExternalEngineError;
+
+impl fmt::Display for ExternalEngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected <variant>=<path>")
+    }
+}
+
+impl Error for ExternalEngineError {}
+
+impl FromStr for ExternalEngine {
+    type Err = ExternalEngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (variant, path) = s.trim().split_once('=').ok_or(ExternalEngineError)?;
+        if path.trim().is_empty() {
+            return Err(ExternalEngineError);
         }
-        Ok(Endpoint { url })
+        Ok(ExternalEngine {
+            variant: variant.parse().map_err(|_| ExternalEngineError)?,
+            path: PathBuf::from(path.trim()),
+        })
     }
 }
 
-impl Endpoint {
-    fn is_development(&self) -> bool {
-        self.url.host_str() != Some("lichess.org")
+impl fmt::Display for ExternalEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.variant, self.path.display())
     }
 }
 
-#[derive(Debug, Default, Copy, Clone, Parser)]
-pub struct Verbose {
-    /// Increase verbosity.
-    #[clap(long = "verbose", short = 'v', parse(from_occurrences), global = true)]
-    pub level: usize,
+/// Third-party engines by variant, separated by commas.
+#[derive(Debug, Clone, Default)]
+pub struct ExternalEngines(pub Vec<ExternalEngine>);
+
+impl FromStr for ExternalEngines {
+    type Err = ExternalEngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ExternalEngines(
+            s.split(',')
+                .filter(|engine| !engine.trim().is_empty())
+                .map(ExternalEngine::from_str)
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+impl fmt::Display for ExternalEngines {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(
+            &self
+                .0
+                .iter()
+                .map(|engine| engine.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+    }
 }
 
+/// Label of this machine, sent to the server with each request.
 #[derive(Debug, Clone)]
-pub struct Key(pub String);
+pub struct Label {
+    pub key: String,
+    pub value: String,
+}
 
 #[derive(Debug)]
-pub enum KeyError {
-    EmptyKey,
-    InvalidKey,
-    AccessDenied,
+pub struct LabelError;
+
+impl fmt::Display for LabelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected <key>=<value>")
+    }
+}
+
+impl Error for LabelError {}
+
+impl FromStr for Label {
+    type Err = LabelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s.trim().split_once('=').ok_or(LabelError)?;
+        if key.trim().is_empty() {
+            return Err(LabelError);
+        }
+        Ok(Label {
+            key: key.trim().to_owned(),
+            value: value.trim().to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+/// Labels of this machine, separated by commas.
+#[derive(Debug, Clone, Default)]
+pub struct Labels(pub Vec<Label>);
+
+impl FromStr for Labels {
+    type Err = LabelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Labels(
+            s.split(',')
+                .filter(|label| !label.trim().is_empty())
+                .map(Label::from_str)
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+impl fmt::Display for Labels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(
+            &self
+                .0
+                .iter()
+                .map(|label| label.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+    }
+}
+
+/// Proxy for requests to the endpoint.
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    pub url: Url,
+}
+
+impl Proxy {
+    fn from_env() -> Option<Proxy> {
+        ["ALL_PROXY", "all_proxy"]
+            .iter()
+            .filter_map(|name| env::var(name).ok())
+            .find(|value| !value.trim().is_empty())
+            .map(|value| value.parse().expect("valid proxy in ALL_PROXY"))
+    }
+}
+
+#[derive(Debug)]
+pub struct ProxyError;
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected proxy url with scheme http, https, socks5 or socks5h")
+    }
+}
+
+impl Error for ProxyError {}
+
+impl FromStr for Proxy {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s.trim()).map_err(|_| ProxyError)?;
+        if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+            return Err(ProxyError);
+        }
+        Ok(Proxy { url })
+    }
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.url.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub url: Url,
+}
+
+impl Default for Endpoint {
+    fn default() -> Endpoint {
+        DEFAULT_ENDPOINT.parse().expect("default endpoint is valid")
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.url, f)
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = url::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut url: Url = s.parse()?;
+        if let Some(stripped_path) = url.path().to_owned().strip_suffix('/') {
+            url.set_path(stripped_path);
+        }
+        Ok(Endpoint { url })
+    }
+}
+
+impl Endpoint {
+    pub fn is_development(&self) -> bool {
+        self.url.host_str() != Some("lichess.org")
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Parser)]
+pub struct Verbose {
+    /// Increase verbosity.
+    #[clap(long = "verbose", short = 'v', parse(from_occurrences), global = true)]
+    pub level: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Key(pub String);
+
+#[derive(Debug)]
+pub enum KeyError {
+    EmptyKey,
+    InvalidKey,
+    AccessDenied,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyError::EmptyKey => "key expected to be non-empty",
+            KeyError::InvalidKey => "key expected to be alphanumeric",
+            KeyError::AccessDenied => "access denied",
+        })
+    }
+}
+
+impl Error for KeyError {}
+
+impl FromStr for Key {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            Err(KeyError::EmptyKey)
+        } else if s.chars().all(|c| char::is_ascii_alphanumeric(&c)) {
+            Ok(Key(s.to_owned()))
+        } else {
+            Err(KeyError::InvalidKey)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpdateChannel {
+    Off,
+    Stable,
+    Beta,
+}
+
+impl UpdateChannel {
+    pub fn is_enabled(self) -> bool {
+        self != UpdateChannel::Off
+    }
+
+    pub fn accepts(self, version: &str) -> bool {
+        match self {
+            UpdateChannel::Off => false,
+            UpdateChannel::Stable => !version.contains('-'),
+            UpdateChannel::Beta => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UpdateChannelError;
+
+impl fmt::Display for UpdateChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected stable, beta or off")
+    }
+}
+
+impl Error for UpdateChannelError {}
+
+impl FromStr for UpdateChannel {
+    type Err = UpdateChannelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "off" => UpdateChannel::Off,
+            "stable" => UpdateChannel::Stable,
+            "beta" => UpdateChannel::Beta,
+            _ => return Err(UpdateChannelError),
+        })
+    }
+}
+
+impl fmt::Display for UpdateChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UpdateChannel::Off => "off",
+            UpdateChannel::Stable => "stable",
+            UpdateChannel::Beta => "beta",
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Cores {
+    Auto,
+    All,
+    Number(NonZeroUsize),
+}
+
+impl Default for Cores {
+    fn default() -> Cores {
+        Cores::Auto
+    }
+}
+
+impl FromStr for Cores {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(if s == "auto" {
+            Cores::Auto
+        } else if s == "all" || s == "max" {
+            Cores::All
+        } else {
+            Cores::Number(s.parse()?)
+        })
+    }
+}
+
+impl fmt::Display for Cores {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cores::Auto => f.write_str("auto"),
+            Cores::All => f.write_str("all"),
+            Cores::Number(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+impl From<Cores> for usize {
+    fn from(cores: Cores) -> usize {
+        match cores {
+            Cores::Number(n) => usize::from(n),
+            Cores::Auto => max(1, affinity::available() - 1),
+            Cores::All => affinity::available(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Parser)]
+pub struct BacklogOpt {
+    /// Prefer to run high-priority jobs only if older than this duration
+    /// (for example 120s).
+    #[clap(long = "user-backlog", global = true)]
+    pub user: Option<Backlog>,
+
+    /// Prefer to run low-priority jobs only if older than this duration
+    /// (for example 2h).
+    #[clap(long = "system-backlog", global = true)]
+    pub system: Option<Backlog>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Backlog {
+    Short,
+    Long,
+    Duration(Duration),
+}
+
+impl Default for Backlog {
+    fn default() -> Backlog {
+        Backlog::Duration(Duration::default())
+    }
+}
+
+impl From<Backlog> for Duration {
+    fn from(backlog: Backlog) -> Duration {
+        match backlog {
+            Backlog::Short => Duration::from_secs(30),
+            Backlog::Long => Duration::from_secs(60 * 60),
+            Backlog::Duration(d) => d,
+        }
+    }
+}
+
+impl FromStr for Backlog {
+    type Err = UnitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(if s == "short" {
+            Backlog::Short
+        } else if s == "long" {
+            Backlog::Long
+        } else {
+            Backlog::Duration(s.parse::<ParsedDuration>()?.into())
+        })
+    }
+}
+
+impl fmt::Display for Backlog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backlog::Short => f.write_str("short"),
+            Backlog::Long => f.write_str("long"),
+            Backlog::Duration(d) => ParsedDuration::from(*d).fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Parser)]
+pub struct LimitsOpt {
+    /// Limit memory of the engine processes (for example 4GiB). Uses a
+    /// child cgroup if the cgroup of fishnet is delegated to it, otherwise
+    /// limits each engine process separately.
+    #[clap(long, global = true)]
+    pub max_memory: Option<ParsedSize>,
+
+    /// Limit CPU time of the engine processes as a percentage of a single
+    /// core (for example 250%). Uses a child cgroup if possible, otherwise
+    /// reduces the number of cores.
+    #[clap(long, global = true)]
+    pub cpu_quota: Option<CpuQuota>,
+
+    /// I/O scheduling class (idle, best-effort or realtime).
+    #[clap(long, global = true)]
+    pub io_class: Option<IoClass>,
+
+    /// Niceness of fishnet and its engine processes (-20 to 19, higher is
+    /// lower priority). On Windows, positive values select the below
+    /// normal priority class.
+    #[clap(long, global = true, allow_hyphen_values = true)]
+    pub nice: Option<i32>,
+
+    /// Only use CPU time that no other process wants (SCHED_IDLE on Linux,
+    /// IDLE_PRIORITY_CLASS on Windows).
+    #[clap(long, global = true)]
+    pub idle_priority: bool,
+}
+
+#[derive(Debug, Copy, Clone, Default, Parser)]
+pub struct EngineOpt {
+    /// Hash table size of each official Stockfish process (for example
+    /// 256MiB). Defaults to the engine default of 16 MiB.
+    #[clap(long, global = true)]
+    pub hash_official: Option<ParsedSize>,
+
+    /// Hash table size of each Fairy-Stockfish process.
+    #[clap(long, global = true)]
+    pub hash_multivariant: Option<ParsedSize>,
+
+    /// Keep the hash table sizes fixed. By default, they are scaled down
+    /// to fit into memory (or the cgroup limit), and shrunk further while
+    /// memory is low.
+    #[clap(long, global = true)]
+    pub fixed_hash: bool,
+
+    /// Search threads of each engine process. The cores are divided among
+    /// fewer engine processes, so that large machines can search deeper
+    /// instead of wider.
+    #[clap(long, global = true)]
+    pub threads_per_job: Option<NonZeroUsize>,
+
+    /// Pin each engine process to its own set of logical cores (Linux
+    /// only).
+    #[clap(long, global = true)]
+    pub pin_cores: bool,
+
+    /// Do not spread engine processes across NUMA nodes. By default, on
+    /// machines with multiple nodes, each engine process runs on the cores
+    /// of one node and allocates its hash table there.
+    #[clap(long, global = true)]
+    pub no_numa: bool,
+
+    /// Restrict engine processes, which are downloaded binaries (Linux on
+    /// x86-64 and ARM64 only): basic denies network access and system
+    /// calls to debug other processes or change the system, strict also
+    /// makes the file system read-only. Default is off.
+    #[clap(long, global = true)]
+    pub sandbox: Option<Sandbox>,
+
+    /// Stop the analysis of a position as soon as a forced mate in at most
+    /// this many moves is found, instead of searching up to the node
+    /// limit.
+    #[clap(long, global = true)]
+    pub early_stop_mate: Option<u32>,
+
+    /// Stop the analysis of a position once the evaluation stayed within
+    /// this many centipawns for 4 depths in a row, from depth 16.
+    #[clap(long, global = true)]
+    pub early_stop_window: Option<u32>,
+
+    /// Analyse reproducibly: one search thread per position, fixed hash
+    /// table sizes cleared for every position, node limits without scaling
+    /// or time limits, and no engine updates while running. The engine
+    /// build is reported with the analysis.
+    #[clap(
+        long,
+        conflicts_with_all = &["threads-per-job", "scale-nodes", "movetime"],
+        global = true
+    )]
+    pub deterministic: bool,
+}
+
+impl EngineOpt {
+    /// Search threads of each engine process.
+    pub fn threads_per_job(&self) -> usize {
+        if self.deterministic {
+            1
+        } else {
+            self.threads_per_job.map_or(1, usize::from)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Parser)]
+pub struct FormatOpt {
+    /// Unit for node counts and speeds in log output (nodes, knodes or
+    /// mnodes).
+    #[clap(long, global = true)]
+    pub node_unit: Option<NodeUnit>,
+
+    /// Format for scores in log output (cp or pawns).
+    #[clap(long, global = true)]
+    pub score_format: Option<ScoreFormat>,
+
+    /// Prefix log lines with the local time (off, 12h or 24h).
+    #[clap(long, global = true)]
+    pub timestamps: Option<Timestamps>,
+
+    /// Log as plain text, or as newline-delimited JSON records with
+    /// timestamp, level, batch_id, position_id and message (text or json).
+    #[clap(long, global = true)]
+    pub log_format: Option<LogFormat>,
+
+    /// Show a full-terminal status view with the queue and each worker,
+    /// instead of the progress line. Needs a terminal and text logs.
+    #[clap(long, global = true)]
+    pub tui: bool,
+}
+
+#[derive(Debug, Clone, Default, Parser)]
+pub struct LogFileOpt {
+    /// Also append log lines to this file, always with timestamps.
+    #[clap(long, parse(from_os_str), global = true)]
+    pub log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it reaches this size (default 16MiB).
+    #[clap(long, global = true)]
+    pub log_max_size: Option<ParsedSize>,
+
+    /// Also rotate the log file after this time (for example 1d).
+    #[clap(long, global = true)]
+    pub log_max_age: Option<ParsedDuration>,
+
+    /// Number of rotated log files to keep, compressed with xz (default
+    /// 5).
+    #[clap(long, global = true)]
+    pub log_keep: Option<usize>,
+}
+
+impl LogFileOpt {
+    pub fn max_size(&self) -> u64 {
+        self.log_max_size.map_or(16 << 20, u64::from)
+    }
+
+    pub fn keep(&self) -> usize {
+        self.log_keep.unwrap_or(5)
+    }
+}
+
+#[derive(Debug, Clone, Parser)]
+pub struct LookupOpt {
+    /// Polyglot opening book, or opening book in the extended format for
+    /// variants. Moves in book positions are played without searching, and
+    /// book positions are skipped by offline analysis.
+    #[clap(long, parse(from_os_str), global = true)]
+    pub book: Option<PathBuf>,
+
+    /// Only play book moves for AI levels up to this level (1 to 8).
+    /// Defaults to all levels.
+    #[clap(long, global = true)]
+    pub book_max_level: Option<u32>,
+
+    /// Skip analysis of book positions within this many plies from the
+    /// start of the game. Skipped positions are submitted without an
+    /// evaluation, like positions the server asks to skip.
+    #[clap(long, global = true)]
+    pub book_skip_plies: Option<usize>,
+
+    /// Look up positions in the lichess cloud evaluation database, and
+    /// submit evaluations of at least this depth instead of searching.
+    #[clap(long, global = true)]
+    pub cloud_eval_depth: Option<u8>,
+
+    /// Remember the results of up to this many analysed positions, and
+    /// answer repeated positions from them instead of searching again.
+    #[clap(long, global = true)]
+    pub result_cache: Option<usize>,
+
+    /// Keep the result cache in this file between sessions.
+    #[clap(long, parse(from_os_str), requires = "result-cache", global = true)]
+    pub result_cache_file: Option<PathBuf>,
+
+    /// Types of work that may be answered from the book, result cache or
+    /// cloud evaluations (for example analysis,move). Defaults to all.
+    #[clap(long, global = true)]
+    pub lookup_work: Option<WorkFilter>,
+}
+
+#[derive(Debug, Clone, Parser)]
+pub struct VerifyOpt {
+    /// Analyse this fraction of positions (for example 0.01) a second time
+    /// with the same number of nodes on a separate engine process, and
+    /// report evaluations that differ by more than --verify-threshold.
+    #[clap(long, global = true)]
+    pub verify_fraction: Option<f64>,
+
+    /// Report verified evaluations that differ by more than this many
+    /// centipawns (default 150). Mates count as 1000 centipawns.
+    #[clap(long, global = true)]
+    pub verify_threshold: Option<u32>,
+
+    /// Verify with this engine flavor (official or multi-variant) instead
+    /// of the flavor that analysed the position.
+    #[clap(long, parse(try_from_str = parse_engine_flavor), global = true)]
+    pub verify_flavor: Option<EngineFlavor>,
+}
+
+/// Types of work, as named by [`api::Work::kind()`]. Allows all types if
+/// not configured.
+#[derive(Debug, Clone, Default)]
+pub struct WorkFilter {
+    allowed: Option<Vec<&'static str>>,
+}
+
+impl WorkFilter {
+    pub fn allows(&self, work: &api::Work) -> bool {
+        self.allows_kind(work.kind())
+    }
+
+    pub fn allows_kind(&self, kind: &str) -> bool {
+        self.allowed
+            .as_ref()
+            .map_or(true, |allowed| allowed.contains(&kind))
+    }
+}
+
+impl FromStr for WorkFilter {
+    type Err = FormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(WorkFilter {
+            allowed: Some(
+                s.split(',')
+                    .filter(|w| !w.trim().is_empty())
+                    .map(|w| match w.trim() {
+                        "analysis" => Ok("analysis"),
+                        "move" => Ok("move"),
+                        _ => Err(FormatError {
+                            expected: "analysis or move",
+                        }),
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+}
+
+impl fmt::Display for WorkFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.allowed {
+            Some(ref allowed) => f.write_str(&allowed.join(",")),
+            None => f.write_str("analysis,move"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FormatError {
+    expected: &'static str,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected {}", self.expected)
+    }
+}
+
+impl Error for FormatError {}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NodeUnit {
+    Nodes,
+    Knodes,
+    Mnodes,
+}
+
+impl FromStr for NodeUnit {
+    type Err = FormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim() {
+            "nodes" => NodeUnit::Nodes,
+            "knodes" => NodeUnit::Knodes,
+            "mnodes" => NodeUnit::Mnodes,
+            _ => {
+                return Err(FormatError {
+                    expected: "nodes, knodes or mnodes",
+                })
+            }
+        })
+    }
+}
+
+impl fmt::Display for NodeUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NodeUnit::Nodes => "nodes",
+            NodeUnit::Knodes => "knodes",
+            NodeUnit::Mnodes => "mnodes",
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScoreFormat {
+    Cp,
+    Pawns,
+}
+
+impl FromStr for ScoreFormat {
+    type Err = FormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim() {
+            "cp" => ScoreFormat::Cp,
+            "pawns" => ScoreFormat::Pawns,
+            _ => {
+                return Err(FormatError {
+                    expected: "cp or pawns",
+                })
+            }
+        })
+    }
+}
+
+impl fmt::Display for ScoreFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ScoreFormat::Cp => "cp",
+            ScoreFormat::Pawns => "pawns",
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Timestamps {
+    Off,
+    Hours12,
+    Hours24,
+}
+
+impl FromStr for Timestamps {
+    type Err = FormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim() {
+            "off" => Timestamps::Off,
+            "12h" => Timestamps::Hours12,
+            "24h" => Timestamps::Hours24,
+            _ => {
+                return Err(FormatError {
+                    expected: "off, 12h or 24h",
+                })
+            }
+        })
+    }
+}
+
+impl fmt::Display for Timestamps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Timestamps::Off => "off",
+            Timestamps::Hours12 => "12h",
+            Timestamps::Hours24 => "24h",
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = FormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim() {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            _ => {
+                return Err(FormatError {
+                    expected: "text or json",
+                })
+            }
+        })
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+/// Destination of `--event-stream`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EventTarget {
+    Stdout,
+    Fd(i32),
+}
+
+impl FromStr for EventTarget {
+    type Err = FormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s {
+            "stdout" | "-" => Ok(EventTarget::Stdout),
+            _ => s
+                .strip_prefix("fd:")
+                .and_then(|fd| fd.parse().ok())
+                .map(EventTarget::Fd)
+                .ok_or(FormatError {
+                    expected: "stdout or fd:<n>",
+                }),
+        }
+    }
+}
+
+impl fmt::Display for EventTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            EventTarget::Stdout => f.write_str("stdout"),
+            EventTarget::Fd(fd) => write!(f, "fd:{}", fd),
+        }
+    }
+}
+
+/// Time control of self-play games, like 10+0.1 for 10 seconds with an
+/// increment of 0.1 seconds per move.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimeControl {
+    pub base: Duration,
+    pub increment: Duration,
+}
+
+impl FromStr for TimeControl {
+    type Err = FormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || FormatError {
+            expected: "time control in seconds like 10+0.1",
+        };
+        let (base, increment) = s.trim().split_once('+').ok_or_else(err)?;
+        let seconds = |s: &str| {
+            s.parse::<f64>()
+                .ok()
+                .filter(|s| s.is_finite() && *s >= 0.0)
+                .map(Duration::from_secs_f64)
+        };
+        match (seconds(base), seconds(increment)) {
+            (Some(base), Some(increment)) if !base.is_zero() => Ok(TimeControl { base, increment }),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl fmt::Display for TimeControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}+{}",
+            self.base.as_secs_f64(),
+            self.increment.as_secs_f64()
+        )
+    }
+}
+
+/// Engines of self-play games, like official, or
+/// official,multi-variant to play different engines against each other.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EnginePair {
+    pub first: EngineFlavor,
+    pub second: EngineFlavor,
+}
+
+impl FromStr for EnginePair {
+    type Err = FormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.split_once(',') {
+            Some((first, second)) => EnginePair {
+                first: parse_engine_flavor(first)?,
+                second: parse_engine_flavor(second)?,
+            },
+            None => EnginePair {
+                first: parse_engine_flavor(s)?,
+                second: parse_engine_flavor(s)?,
+            },
+        })
+    }
+}
+
+fn parse_engine_flavor(s: &str) -> Result<EngineFlavor, FormatError> {
+    match s.trim() {
+        "official" => Ok(EngineFlavor::Official),
+        "multi-variant" => Ok(EngineFlavor::MultiVariant),
+        _ => Err(FormatError {
+            expected: "official or multi-variant",
+        }),
+    }
+}
+
+/// Format of results written by offline subcommands.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    Csv,
+    /// One JSON object per position, see [`crate::record::PositionRecord`].
+    JsonLines,
+}
+
+impl FromStr for OutputFormat {
+    type Err = FormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim() {
+            "csv" => OutputFormat::Csv,
+            "jsonl" => OutputFormat::JsonLines,
+            _ => {
+                return Err(FormatError {
+                    expected: "csv or jsonl",
+                })
+            }
+        })
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::JsonLines => "jsonl",
+        })
+    }
+}
+
+/// CPU time as a percentage of a single core.
+#[derive(Debug, Copy, Clone)]
+pub struct CpuQuota(pub NonZeroU32);
+
+impl FromStr for CpuQuota {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(CpuQuota(s.trim().trim_end_matches('%').parse()?))
+    }
+}
+
+impl fmt::Display for CpuQuota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}%", self.0)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoClass {
+    Idle,
+    BestEffort,
+    Realtime,
+}
+
+#[derive(Debug)]
+pub struct IoClassError;
+
+impl fmt::Display for IoClassError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected idle, best-effort or realtime")
+    }
+}
+
+impl Error for IoClassError {}
+
+impl FromStr for IoClass {
+    type Err = IoClassError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim() {
+            "idle" => IoClass::Idle,
+            "best-effort" => IoClass::BestEffort,
+            "realtime" => IoClass::Realtime,
+            _ => return Err(IoClassError),
+        })
+    }
+}
+
+impl fmt::Display for IoClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IoClass::Idle => "idle",
+            IoClass::BestEffort => "best-effort",
+            IoClass::Realtime => "realtime",
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OnBattery {
+    Ignore,
+    Pause,
+    OneCore,
+}
+
+#[derive(Debug)]
+pub struct OnBatteryError;
+
+impl fmt::Display for OnBatteryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected ignore, pause or one-core")
+    }
+}
+
+impl Error for OnBatteryError {}
+
+impl FromStr for OnBattery {
+    type Err = OnBatteryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim() {
+            "ignore" => OnBattery::Ignore,
+            "pause" => OnBattery::Pause,
+            "one-core" => OnBattery::OneCore,
+            _ => return Err(OnBatteryError),
+        })
+    }
+}
+
+impl fmt::Display for OnBattery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OnBattery::Ignore => "ignore",
+            OnBattery::Pause => "pause",
+            OnBattery::OneCore => "one-core",
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AnalysisOrder {
+    Forward,
+    Backward,
+}
+
+impl Default for AnalysisOrder {
+    fn default() -> AnalysisOrder {
+        AnalysisOrder::Forward
+    }
+}
+
+#[derive(Debug)]
+pub struct AnalysisOrderError;
+
+impl fmt::Display for AnalysisOrderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected forward or backward")
+    }
+}
+
+impl Error for AnalysisOrderError {}
+
+impl FromStr for AnalysisOrder {
+    type Err = AnalysisOrderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim() {
+            "forward" => AnalysisOrder::Forward,
+            "backward" => AnalysisOrder::Backward,
+            _ => return Err(AnalysisOrderError),
+        })
+    }
+}
+
+impl fmt::Display for AnalysisOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AnalysisOrder::Forward => "forward",
+            AnalysisOrder::Backward => "backward",
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Sandbox {
+    Off,
+    Basic,
+    Strict,
+}
+
+impl Default for Sandbox {
+    fn default() -> Sandbox {
+        Sandbox::Off
+    }
+}
+
+#[derive(Debug)]
+pub struct SandboxError;
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected strict, basic or off")
+    }
+}
+
+impl Error for SandboxError {}
+
+impl FromStr for Sandbox {
+    type Err = SandboxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim() {
+            "off" => Sandbox::Off,
+            "basic" => Sandbox::Basic,
+            "strict" => Sandbox::Strict,
+            _ => return Err(SandboxError),
+        })
+    }
 }
 
-impl fmt::Display for KeyError {
+impl fmt::Display for Sandbox {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(match self {
-            KeyError::EmptyKey => "key expected to be non-empty",
-            KeyError::InvalidKey => "key expected to be alphanumeric",
-            KeyError::AccessDenied => "access denied",
+            Sandbox::Off => "off",
+            Sandbox::Basic => "basic",
+            Sandbox::Strict => "strict",
         })
     }
 }
 
-impl Error for KeyError {}
-
-impl FromStr for Key {
-    type Err = KeyError;
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CpuLevel {
+    Sse2,
+    Sse41Popcnt,
+    Avx2,
+    Bmi2,
+    Avx512,
+    Vnni512,
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.is_empty() {
-            Err(KeyError::EmptyKey)
-        } else if s.chars().all(|c| char::is_ascii_alphanumeric(&c)) {
-            Ok(Key(s.to_owned()))
-        } else {
-            Err(KeyError::InvalidKey)
+impl CpuLevel {
+    pub fn cpu(self) -> Cpu {
+        match self {
+            CpuLevel::Sse2 => Cpu::SF_SSE2,
+            CpuLevel::Sse41Popcnt => Cpu::SF_SSE41_POPCNT,
+            CpuLevel::Avx2 => Cpu::SF_AVX2,
+            CpuLevel::Bmi2 => Cpu::SF_BMI2,
+            CpuLevel::Avx512 => Cpu::SF_AVX512,
+            CpuLevel::Vnni512 => Cpu::SF_VNNI512,
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum Cores {
-    Auto,
-    All,
-    Number(NonZeroUsize),
-}
+#[derive(Debug)]
+pub struct CpuLevelError;
 
-impl Default for Cores {
-    fn default() -> Cores {
-        Cores::Auto
+impl fmt::Display for CpuLevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected sse2, sse41-popcnt, avx2, bmi2, avx512 or vnni512")
     }
 }
 
-impl FromStr for Cores {
-    type Err = ParseIntError;
+impl Error for CpuLevelError {}
+
+impl FromStr for CpuLevel {
+    type Err = CpuLevelError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(if s == "auto" {
-            Cores::Auto
-        } else if s == "all" || s == "max" {
-            Cores::All
-        } else {
-            Cores::Number(s.parse()?)
+        Ok(match s.trim() {
+            "sse2" => CpuLevel::Sse2,
+            "sse41-popcnt" => CpuLevel::Sse41Popcnt,
+            "avx2" => CpuLevel::Avx2,
+            "bmi2" => CpuLevel::Bmi2,
+            "avx512" => CpuLevel::Avx512,
+            "vnni512" => CpuLevel::Vnni512,
+            _ => return Err(CpuLevelError),
         })
     }
 }
 
-impl fmt::Display for Cores {
+impl fmt::Display for CpuLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CpuLevel::Sse2 => "sse2",
+            CpuLevel::Sse41Popcnt => "sse41-popcnt",
+            CpuLevel::Avx2 => "avx2",
+            CpuLevel::Bmi2 => "bmi2",
+            CpuLevel::Avx512 => "avx512",
+            CpuLevel::Vnni512 => "vnni512",
+        })
+    }
+}
+
+/// Error for sizes and durations that are not of the form accepted by
+/// [`ParsedSize`] and [`ParsedDuration`].
+#[derive(Debug)]
+pub enum UnitError {
+    InvalidNumber,
+    UnknownUnit(String),
+    UnitOrder,
+    Overflow,
+}
+
+impl fmt::Display for UnitError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Cores::Auto => f.write_str("auto"),
-            Cores::All => f.write_str("all"),
-            Cores::Number(n) => write!(f, "{}", n),
+            UnitError::InvalidNumber => f.write_str(
+                "expected a non-negative number with . as decimal separator (for example 1.5GiB or 90s)",
+            ),
+            UnitError::UnknownUnit(unit) => write!(f, "unknown unit: {:?}", unit),
+            UnitError::UnitOrder => f.write_str("units must be given from largest to smallest (for example 1h30m)"),
+            UnitError::Overflow => f.write_str("number too large"),
         }
     }
 }
 
-impl From<Cores> for usize {
-    fn from(cores: Cores) -> usize {
-        match cores {
-            Cores::Number(n) => usize::from(n),
-            Cores::Auto => max(1, num_cpus::get() - 1),
-            Cores::All => num_cpus::get(),
-        }
+impl Error for UnitError {}
+
+/// Splits strings like `1h30m` into `[("1", "h"), ("30", "m")]`.
+fn quantities(s: &str) -> Vec<(&str, &str)> {
+    let mut parts = Vec::new();
+    let mut rest = s;
+    while !rest.is_empty() {
+        let (number, tail) = rest.split_at(
+            rest.find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(rest.len()),
+        );
+        let (unit, tail) = tail.split_at(
+            tail.find(|c: char| c.is_ascii_digit() || c == '.')
+                .unwrap_or(tail.len()),
+        );
+        parts.push((number, unit));
+        rest = tail;
     }
+    parts
 }
 
-#[derive(Debug, Clone, Parser)]
-pub struct BacklogOpt {
-    /// Prefer to run high-priority jobs only if older than this duration
-    /// (for example 120s).
-    #[clap(long = "user-backlog", global = true)]
-    pub user: Option<Backlog>,
+/// Multiplies a decimal number like `1.5` with an integer factor, without
+/// going through floating point and independent of the locale. Fractional
+/// results are rounded down.
+fn scale(number: &str, factor: u64) -> Result<u64, UnitError> {
+    let (int, frac) = number.split_once('.').unwrap_or((number, ""));
+    if int.is_empty()
+        || !int.bytes().all(|b| b.is_ascii_digit())
+        || !frac.bytes().all(|b| b.is_ascii_digit())
+        || number.ends_with('.')
+    {
+        return Err(UnitError::InvalidNumber);
+    }
+    let int = int
+        .parse::<u64>()
+        .map_err(|_| UnitError::Overflow)?
+        .checked_mul(factor)
+        .ok_or(UnitError::Overflow)?;
+    let (numerator, denominator) = frac.bytes().take(18).fold((0u128, 1u128), |(n, d), b| {
+        (n * 10 + u128::from(b - b'0'), d * 10)
+    });
+    int.checked_add((numerator * u128::from(factor) / denominator) as u64)
+        .ok_or(UnitError::Overflow)
+}
 
-    /// Prefer to run low-priority jobs only if older than this duration
-    /// (for example 2h).
-    #[clap(long = "system-backlog", global = true)]
-    pub system: Option<Backlog>,
+/// Parses a number followed by one of the given units, or a sequence of
+/// such quantities from the largest to the smallest unit. A bare number uses
+/// the unit with the empty name.
+fn parse_quantities(s: &str, units: &[(&str, u64)]) -> Result<u64, UnitError> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(UnitError::InvalidNumber);
+    }
+    let parts = quantities(s);
+    let mut total: u64 = 0;
+    let mut previous_factor = u64::MAX;
+    for (number, unit) in &parts {
+        let factor = units
+            .iter()
+            .find(|(name, _)| name == unit)
+            .map(|&(_, factor)| factor)
+            .ok_or_else(|| UnitError::UnknownUnit((*unit).to_owned()))?;
+        if factor >= previous_factor || (unit.is_empty() && parts.len() > 1) {
+            return Err(UnitError::UnitOrder);
+        }
+        previous_factor = factor;
+        total = total
+            .checked_add(scale(number, factor)?)
+            .ok_or(UnitError::Overflow)?;
+    }
+    Ok(total)
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum Backlog {
-    Short,
-    Long,
-    Duration(Duration),
+/// Size in bytes. Accepts IEC units (KiB, MiB, GiB, TiB), SI units (kB, MB,
+/// GB, TB) and, like systemd, single letter binary units (K, M, G, T).
+/// Displayed in the largest IEC unit that represents it exactly, so that
+/// writing it back into a configuration file does not change its value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ParsedSize(u64);
+
+impl ParsedSize {
+    const UNITS: &'static [(&'static str, u64)] = &[
+        ("", 1),
+        ("B", 1),
+        ("KiB", 1 << 10),
+        ("MiB", 1 << 20),
+        ("GiB", 1 << 30),
+        ("TiB", 1 << 40),
+        ("K", 1 << 10),
+        ("M", 1 << 20),
+        ("G", 1 << 30),
+        ("T", 1 << 40),
+        ("kB", 1_000),
+        ("KB", 1_000),
+        ("MB", 1_000_000),
+        ("GB", 1_000_000_000),
+        ("TB", 1_000_000_000_000),
+    ];
 }
 
-impl Default for Backlog {
-    fn default() -> Backlog {
-        Backlog::Duration(Duration::default())
+impl FromStr for ParsedSize {
+    type Err = UnitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Sizes are a single quantity. Parsing 1G500M would be surprising.
+        let s = s.trim();
+        if quantities(s).len() > 1 {
+            return Err(UnitError::InvalidNumber);
+        }
+        parse_quantities(s, ParsedSize::UNITS).map(ParsedSize)
     }
 }
 
-impl From<Backlog> for Duration {
-    fn from(backlog: Backlog) -> Duration {
-        match backlog {
-            Backlog::Short => Duration::from_secs(30),
-            Backlog::Long => Duration::from_secs(60 * 60),
-            Backlog::Duration(d) => d,
+impl fmt::Display for ParsedSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (suffix, factor) in [
+            ("TiB", 1 << 40),
+            ("GiB", 1 << 30),
+            ("MiB", 1 << 20),
+            ("KiB", 1 << 10),
+        ] {
+            if self.0 >= factor && self.0 % factor == 0 {
+                return write!(f, "{}{}", self.0 / factor, suffix);
+            }
         }
+        write!(f, "{}B", self.0)
     }
 }
 
-impl FromStr for Backlog {
-    type Err = ParseIntError;
+impl From<ParsedSize> for u64 {
+    fn from(ParsedSize(bytes): ParsedSize) -> u64 {
+        bytes
+    }
+}
+
+/// Bytes per second, as a [`ParsedSize`] with an optional `/s` suffix.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ParsedRate(ParsedSize);
+
+impl FromStr for ParsedRate {
+    type Err = UnitError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(if s == "short" {
-            Backlog::Short
-        } else if s == "long" {
-            Backlog::Long
-        } else {
-            Backlog::Duration(s.parse::<ParsedDuration>()?.into())
-        })
+        let s = s.trim();
+        s.strip_suffix("/s").unwrap_or(s).parse().map(ParsedRate)
     }
 }
 
-impl fmt::Display for Backlog {
+impl fmt::Display for ParsedRate {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Backlog::Short => f.write_str("short"),
-            Backlog::Long => f.write_str("long"),
-            Backlog::Duration(d) => write!(f, "{}s", d.as_secs()),
-        }
+        write!(f, "{}/s", self.0)
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+impl From<ParsedRate> for u64 {
+    fn from(ParsedRate(size): ParsedRate) -> u64 {
+        size.into()
+    }
+}
+
+/// Duration with millisecond precision, like 90s, 1.5h or 2h30m. A bare
+/// number is in seconds. Displayed in canonical form (for example 1m30s),
+/// which parses back to the same value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct ParsedDuration(Duration);
 
+impl ParsedDuration {
+    const UNITS: &'static [(&'static str, u64)] = &[
+        ("d", 1000 * 60 * 60 * 24),
+        ("h", 1000 * 60 * 60),
+        ("m", 1000 * 60),
+        ("s", 1000),
+        ("", 1000),
+        ("ms", 1),
+    ];
+}
+
 impl FromStr for ParsedDuration {
-    type Err = ParseIntError;
+    type Err = UnitError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (s, factor) = if let Some(s) = s.strip_suffix('d') {
-            (s, 1000 * 60 * 60 * 24)
-        } else if let Some(s) = s.strip_suffix('h') {
-            (s, 1000 * 60 * 60)
-        } else if let Some(s) = s.strip_suffix('m') {
-            (s, 1000 * 60)
-        } else if let Some(s) = s.strip_suffix("ms") {
-            (s, 1)
-        } else {
-            (s.strip_suffix('s').unwrap_or(s), 1000)
-        };
-        Ok(ParsedDuration(Duration::from_millis(
-            u64::from(s.trim().parse::<u32>()?) * factor,
-        )))
+        parse_quantities(s, ParsedDuration::UNITS)
+            .map(|millis| ParsedDuration(Duration::from_millis(millis)))
+    }
+}
+
+impl fmt::Display for ParsedDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut millis = self.0.as_millis();
+        if millis == 0 {
+            return f.write_str("0s");
+        }
+        for &(suffix, factor) in ParsedDuration::UNITS {
+            if !suffix.is_empty() && millis >= u128::from(factor) {
+                write!(f, "{}{}", millis / u128::from(factor), suffix)?;
+                millis %= u128::from(factor);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<Duration> for ParsedDuration {
+    fn from(duration: Duration) -> ParsedDuration {
+        ParsedDuration(duration)
     }
 }
 
@@ -287,23 +2200,227 @@ impl From<ParsedDuration> for Duration {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Parser)]
+#[derive(Debug, Clone, PartialEq, Eq, Parser)]
 pub enum Command {
     /// Donate CPU time by running analysis (default).
     Run,
     /// Run interactive configuration.
     Configure,
     /// Generate a systemd service file.
-    Systemd,
+    Systemd(SystemdOpt),
     /// Generate a systemd user service file.
     SystemdUser,
+    /// Diagnose common problems and suggest fixes.
+    Doctor,
+    /// Show lifetime statistics.
+    Stats,
+    /// Show the standing of this key among all providers, if the server
+    /// provides a leaderboard.
+    Leaderboard,
+    /// Obtain a key by authorizing this machine with your account, and
+    /// save it to the configuration file.
+    Auth(AuthOpt),
+    /// Send a command to a running instance via its control socket.
+    Ctl {
+        #[clap(subcommand)]
+        command: ControlCommand,
+    },
+    /// Bundle configuration (secrets redacted), capabilities, stats, and
+    /// recent logs and engine transcripts of a running instance into a
+    /// file for bug reports.
+    Report,
+    /// Show a combined view of multiple instances, polled from their status
+    /// servers (for example host1:9281 host2:9281).
+    Dashboard {
+        #[clap(required = true)]
+        hosts: Vec<String>,
+    },
+    /// Analyse the positions of an EPD test suite with the bundled engines,
+    /// and write evaluations, best moves and depths as CSV or JSON lines.
+    AnalyseEpd(EpdOpt),
+    /// Analyse every position of games with the bundled engines, and print
+    /// evaluations and principal variations as text, or with --json as
+    /// JSON lines.
+    Analyse(AnalyseOpt),
+    /// Scan the games of a PGN file for tactical puzzle candidates, and
+    /// write them as JSON lines for the lichess puzzle generator.
+    Puzzles(PuzzleOpt),
+    /// Play the bundled engines against each other in a variant, and write
+    /// the games with evaluations as PGN, for example to playtest variants.
+    Selfplay(SelfplayOpt),
+    /// Measure the speed of the bundled engines with all cores busy, and
+    /// save it, so that the speed is known before the first batch.
+    Benchmark(BenchmarkOpt),
+    /// Search a single node with each engine flavor from the starting
+    /// position of every supported variant, and print a pass/fail matrix as
+    /// JSON. Exits with an error if any check fails.
+    Selftest,
+    /// Check the normalized moves of games against the move generation of
+    /// Fairy-Stockfish, for example to audit castling in Chess960 and
+    /// variants. Exits with an error if they disagree.
+    VerifyNotation(VerifyNotationOpt),
+    /// Run engines for a coordinator on the local network (see
+    /// --cluster-bind), for example 192.168.1.10:9283. Needs no key.
+    Follow { coordinator: String },
+    /// Serve analysis to local services via gRPC, instead of working for
+    /// the fishnet API. Requires a build with the grpc feature.
+    Grpc {
+        /// Listen on this address.
+        #[clap(long, default_value = "127.0.0.1:9282")]
+        bind: SocketAddr,
+    },
+    /// Download engine builds and networks from --engine-manifest that are
+    /// newer than the bundled ones, to be used from the next start.
+    UpdateEngines,
     /// Show GPLv3 license.
     License,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Parser)]
+pub struct AuthOpt {
+    /// Use the OAuth access token in this file, instead of the interactive
+    /// device code flow. For unattended setups.
+    #[clap(long, parse(from_os_str))]
+    pub auth_token_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Parser)]
+pub struct EpdOpt {
+    /// EPD file with one position per line. The id and hmvc/fmvn operations
+    /// are used, others are ignored.
+    pub file: PathBuf,
+    /// Number of engine processes to run in parallel (default: --cores).
+    #[clap(long)]
+    pub concurrency: Option<NonZeroUsize>,
+    /// Write results to this file.
+    #[clap(long)]
+    pub out: PathBuf,
+    /// Format of the results (csv, or jsonl for one JSON object per line).
+    #[clap(long, default_value = "csv")]
+    pub format: OutputFormat,
+    /// Variant of all positions in the file.
+    #[clap(long, default_value = "standard")]
+    pub variant: LichessVariant,
+    /// Node limit for each position.
+    #[clap(long, default_value = "1500000")]
+    pub nodes: u64,
+    /// Optional depth limit for each position.
+    #[clap(long)]
+    pub depth: Option<u8>,
+    /// Also write each position with the principal variation and evaluation
+    /// to this PGN file, for import into chess GUIs.
+    #[clap(long)]
+    pub pgn: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Parser)]
+pub struct AnalyseOpt {
+    /// PGN file, or a file with one game per line as a FEN (or startpos),
+    /// optionally followed by moves and the moves in UCI notation. Use -
+    /// to read from stdin.
+    pub file: PathBuf,
+    /// Number of engine processes to run in parallel (default: --cores).
+    #[clap(long)]
+    pub concurrency: Option<NonZeroUsize>,
+    /// Variant of games without a Variant tag.
+    #[clap(long, default_value = "standard")]
+    pub variant: LichessVariant,
+    /// Node limit for each position.
+    #[clap(long, default_value = "1500000")]
+    pub nodes: u64,
+    /// Optional depth limit for each position.
+    #[clap(long)]
+    pub depth: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Parser)]
+pub struct VerifyNotationOpt {
+    /// PGN file, or a file with one game per line as a FEN (or startpos),
+    /// optionally followed by moves and the moves in UCI notation. Use -
+    /// to read from stdin.
+    pub file: PathBuf,
+    /// Variant of games without a Variant tag.
+    #[clap(long, default_value = "standard")]
+    pub variant: LichessVariant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Parser)]
+pub struct PuzzleOpt {
+    /// PGN file with standard chess games. Existing annotations are
+    /// ignored.
+    pub file: PathBuf,
+    /// Number of engine processes to run in parallel (default: --cores).
+    #[clap(long)]
+    pub concurrency: Option<NonZeroUsize>,
+    /// Write candidates to this file.
+    #[clap(long)]
+    pub out: PathBuf,
+    /// Node limit for scanning each position of the games.
+    #[clap(long, default_value = "500000")]
+    pub nodes: u64,
+    /// Node limit for the deeper search that confirms each move of the
+    /// solution.
+    #[clap(long, default_value = "5000000")]
+    pub confirm_nodes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Parser)]
+pub struct SelfplayOpt {
+    /// Variant of all games.
+    #[clap(long, default_value = "standard")]
+    pub variant: LichessVariant,
+    /// Number of games. Each opening is played twice, with colors swapped.
+    #[clap(long, default_value = "10")]
+    pub games: u32,
+    /// Time control in seconds, as base+increment.
+    #[clap(long, default_value = "10+0.1")]
+    pub tc: TimeControl,
+    /// Engine flavors to play, for example multi-variant, or
+    /// official,multi-variant to play them against each other (default:
+    /// the engine for the variant).
+    #[clap(long)]
+    pub engines: Option<EnginePair>,
+    /// Number of random moves at the start of each game, unless playing
+    /// from the opening book (see --book).
+    #[clap(long, default_value = "2")]
+    pub random_plies: u32,
+    /// Number of games to play in parallel (default: --cores).
+    #[clap(long)]
+    pub concurrency: Option<NonZeroUsize>,
+    /// Write games to this PGN file.
+    #[clap(long)]
+    pub out: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Parser)]
+pub struct SystemdOpt {
+    /// Run as a transient system user (DynamicUser), with state in
+    /// /var/lib/fishnet, instead of as the current user. The configuration
+    /// file must be readable by other users, for example in /etc/fishnet.
+    /// Not compatible with --auto-update.
+    #[clap(long)]
+    pub dynamic_user: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Parser)]
+pub struct BenchmarkOpt {
+    /// Node limit for each position of the suite.
+    #[clap(long, default_value = "2000000")]
+    pub nodes: u64,
+    /// Only print the results, instead of saving them to
+    /// ~/.fishnet-calibration.
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
 impl Command {
-    pub fn is_systemd(self) -> bool {
-        matches!(self, Command::Systemd | Command::SystemdUser)
+    pub fn is_systemd(&self) -> bool {
+        matches!(self, Command::Systemd(_) | Command::SystemdUser)
+    }
+
+    /// Logs go to stderr, to keep stdout for the output of the command.
+    pub fn logs_to_stderr(&self) -> bool {
+        self.is_systemd() || matches!(self, Command::Analyse(_) | Command::VerifyNotation(_))
     }
 }
 
@@ -334,6 +2451,55 @@ impl FromStr for Toggle {
     }
 }
 
+/// Settings that can be reloaded from the configuration file while
+/// running.
+#[derive(Debug)]
+pub struct Reloaded {
+    pub cores: Option<Cores>,
+    pub backlog: BacklogOpt,
+    pub verbose: Option<Verbose>,
+}
+
+/// Reads the settings for the named endpoint from the configuration file
+/// again. Unlike on startup, invalid values are reported instead of
+/// panicking.
+pub fn reload(opt: &Opt, endpoint_name: &str) -> Result<Reloaded, String> {
+    if opt.no_conf {
+        return Err("running without configuration file".to_owned());
+    }
+    let mut ini = Ini::new();
+    ini.set_default_section("Fishnet");
+    let contents = fs::read_to_string(&opt.conf)
+        .map_err(|err| format!("failed to read {:?}: {}", opt.conf, err))?;
+    ini.read(contents)
+        .map_err(|err| format!("failed to parse {:?}: {}", opt.conf, err))?;
+
+    let section = format!("{}{}", ENDPOINT_SECTION_PREFIX, endpoint_name);
+    let get = |key: &str| ini.get(&section, key).or_else(|| ini.get("Fishnet", key));
+    Ok(Reloaded {
+        cores: ini
+            .get("Fishnet", "Cores")
+            .map(|c| c.parse())
+            .transpose()
+            .map_err(|err| format!("invalid cores: {}", err))?,
+        backlog: BacklogOpt {
+            user: get("UserBacklog")
+                .map(|b| b.parse())
+                .transpose()
+                .map_err(|err| format!("invalid user backlog: {}", err))?,
+            system: get("SystemBacklog")
+                .map(|b| b.parse())
+                .transpose()
+                .map_err(|err| format!("invalid system backlog: {}", err))?,
+        },
+        verbose: ini
+            .get("Fishnet", "Verbose")
+            .map(|v| v.trim().parse().map(|level| Verbose { level }))
+            .transpose()
+            .map_err(|err| format!("invalid verbose: {}", err))?,
+    })
+}
+
 #[rustfmt::skip]
 fn intro() {
     println!(r#"#   _________         .    ."#);
@@ -352,9 +2518,10 @@ pub async fn parse_and_configure() -> Opt {
     let mut opt = Opt::parse();
 
     // Show intro and configure logger.
-    let is_systemd = opt.command.map_or(false, Command::is_systemd);
-    let logger = Logger::new(opt.verbose, is_systemd);
-    if !is_systemd {
+    let is_systemd = opt.command.as_ref().map_or(false, Command::is_systemd);
+    let logs_to_stderr = opt.logs_to_stderr();
+    let logger = Logger::new(opt.verbose, opt.format, logs_to_stderr);
+    if !logs_to_stderr && !opt.json && !matches!(opt.command, Some(Command::Ctl { .. })) {
         intro();
     }
 
@@ -373,7 +2540,10 @@ pub async fn parse_and_configure() -> Opt {
 
     // Handle config file.
     if opt.command == Some(Command::Configure)
-        || (opt.command != Some(Command::License) && !opt.no_conf)
+        || (!matches!(
+            opt.command,
+            Some(Command::License | Command::Stats | Command::Dashboard { .. })
+        ) && !opt.no_conf)
     {
         let mut ini = Ini::new();
         ini.set_default_section("Fishnet");
@@ -389,7 +2559,29 @@ pub async fn parse_and_configure() -> Opt {
         };
 
         // Configuration dialog.
-        if (!file_found && opt.command != Some(Command::Run))
+        if (!file_found
+            && !opt.describe
+            && !matches!(
+                opt.command,
+                Some(
+                    Command::Run
+                        | Command::Doctor
+                        | Command::Leaderboard
+                        | Command::Auth(_)
+                        | Command::Report
+                        | Command::Ctl { .. }
+                        | Command::AnalyseEpd(_)
+                        | Command::Analyse(_)
+                        | Command::Puzzles(_)
+                        | Command::Selfplay(_)
+                        | Command::Benchmark(_)
+                        | Command::Selftest
+                        | Command::VerifyNotation(_)
+                        | Command::UpdateEngines
+                        | Command::Grpc { .. }
+                        | Command::Follow { .. }
+                )
+            ))
             || opt.command == Some(Command::Configure)
         {
             logger.headline("Configuration");
@@ -401,6 +2593,14 @@ pub async fn parse_and_configure() -> Opt {
                     .parse()
                     .expect("valid endpoint from fishnet.ini")
             });
+            let proxy = opt
+                .proxy
+                .clone()
+                .or_else(|| {
+                    ini.get("Fishnet", "Proxy")
+                        .map(|p| p.parse().expect("valid proxy from fishnet.ini"))
+                })
+                .or_else(Proxy::from_env);
 
             // Step 2: Key.
             loop {
@@ -440,8 +2640,12 @@ pub async fn parse_and_configure() -> Opt {
                 let key = match Key::from_str(key) {
                     Ok(key) if !network => Ok(key),
                     Ok(key) => {
-                        let mut api =
-                            api::spawn(endpoint.clone(), Some(key.clone()), logger.clone());
+                        let mut api = api::spawn(
+                            endpoint.clone(),
+                            Some(key.clone()),
+                            proxy.clone(),
+                            logger.clone(),
+                        );
                         match api.check_key().await {
                             Some(Ok(())) => Ok(key),
                             Some(Err(err)) => Err(err),
@@ -464,7 +2668,7 @@ pub async fn parse_and_configure() -> Opt {
             eprintln!();
             loop {
                 let mut cores = String::new();
-                let all = num_cpus::get();
+                let all = affinity::available();
                 let auto = max(all - 1, 1);
                 eprint!(
                     "Number of logical cores to use for engine threads (default {}, max {}): ",
@@ -548,6 +2752,30 @@ pub async fn parse_and_configure() -> Opt {
                     .map(|e| e.parse().expect("valid endpoint"))
             });
 
+            opt.reserve_cores = opt.reserve_cores.or_else(|| {
+                ini.get("Fishnet", "ReserveCores")
+                    .map(|n| n.trim().parse().expect("valid reserve cores"))
+            });
+            opt.proxy = opt.proxy.or_else(|| {
+                ini.get("Fishnet", "Proxy")
+                    .map(|p| p.parse().expect("valid proxy"))
+            });
+            opt.websocket |= ini
+                .getbool("Fishnet", "Websocket")
+                .expect("valid websocket")
+                .unwrap_or(false);
+            opt.prefer_ipv4 |= ini
+                .getbool("Fishnet", "PreferIpv4")
+                .expect("valid prefer ipv4")
+                .unwrap_or(false);
+            opt.prefer_ipv6 |= ini
+                .getbool("Fishnet", "PreferIpv6")
+                .expect("valid prefer ipv6")
+                .unwrap_or(false);
+            if opt.prefer_ipv4 && opt.prefer_ipv6 {
+                panic!("cannot prefer both ipv4 and ipv6");
+            }
+
             opt.key = opt.key.or_else(|| {
                 ini.get("Fishnet", "Key")
                     .map(|k| k.parse().expect("valid key"))
@@ -557,6 +2785,26 @@ pub async fn parse_and_configure() -> Opt {
                 ini.get("Fishnet", "Cores")
                     .map(|c| c.parse().expect("valid cores"))
             });
+            if opt.verbose.level == 0 {
+                opt.verbose.level = ini
+                    .get("Fishnet", "Verbose")
+                    .map_or(0, |v| v.trim().parse().expect("valid verbose"));
+            }
+            opt.on_battery = opt.on_battery.or_else(|| {
+                ini.get("Fishnet", "OnBattery")
+                    .map(|b| b.parse().expect("valid on battery"))
+            });
+            opt.pause_when_active |= ini
+                .getbool("Fishnet", "PauseWhenActive")
+                .expect("valid pause when active")
+                .unwrap_or(false);
+            opt.max_temperature = opt.max_temperature.or_else(|| {
+                ini.get("Fishnet", "MaxTemperature")
+                    .map(|t| t.trim().parse().expect("valid max temperature"))
+            });
+            opt.core_lease_dir = opt
+                .core_lease_dir
+                .or_else(|| ini.get("Fishnet", "CoreLeaseDir").map(PathBuf::from));
 
             opt.backlog.user = opt.backlog.user.or_else(|| {
                 ini.get("Fishnet", "UserBacklog")
@@ -566,11 +2814,346 @@ pub async fn parse_and_configure() -> Opt {
                 ini.get("Fishnet", "SystemBacklog")
                     .map(|b| b.parse().expect("valid system backlog"))
             });
+
+            opt.limits.max_memory = opt.limits.max_memory.or_else(|| {
+                ini.get("Fishnet", "MaxMemory")
+                    .map(|m| m.parse().expect("valid max memory"))
+            });
+            opt.limits.cpu_quota = opt.limits.cpu_quota.or_else(|| {
+                ini.get("Fishnet", "CpuQuota")
+                    .map(|q| q.parse().expect("valid cpu quota"))
+            });
+            opt.limits.io_class = opt.limits.io_class.or_else(|| {
+                ini.get("Fishnet", "IoClass")
+                    .map(|c| c.parse().expect("valid io class"))
+            });
+            opt.limits.nice = opt.limits.nice.or_else(|| {
+                ini.get("Fishnet", "Nice")
+                    .map(|n| n.trim().parse().expect("valid nice"))
+            });
+            opt.limits.idle_priority |= ini
+                .getbool("Fishnet", "IdlePriority")
+                .expect("valid idle priority")
+                .unwrap_or(false);
+
+            opt.engine.hash_official = opt.engine.hash_official.or_else(|| {
+                ini.get("Fishnet", "HashOfficial")
+                    .map(|h| h.parse().expect("valid official hash size"))
+            });
+            opt.engine.hash_multivariant = opt.engine.hash_multivariant.or_else(|| {
+                ini.get("Fishnet", "HashMultivariant")
+                    .map(|h| h.parse().expect("valid multivariant hash size"))
+            });
+            opt.engine.fixed_hash |= ini
+                .getbool("Fishnet", "FixedHash")
+                .expect("valid fixed hash")
+                .unwrap_or(false);
+            opt.engine.threads_per_job = opt.engine.threads_per_job.or_else(|| {
+                ini.get("Fishnet", "ThreadsPerJob")
+                    .map(|t| t.trim().parse().expect("valid threads per job"))
+            });
+            opt.engine.pin_cores |= ini
+                .getbool("Fishnet", "PinCores")
+                .expect("valid pin cores")
+                .unwrap_or(false);
+            opt.engine.no_numa |= ini
+                .getbool("Fishnet", "NoNuma")
+                .expect("valid no numa")
+                .unwrap_or(false);
+            opt.engine.sandbox = opt.engine.sandbox.or_else(|| {
+                ini.get("Fishnet", "Sandbox")
+                    .map(|s| s.parse().expect("valid sandbox"))
+            });
+            opt.engine.early_stop_mate = opt.engine.early_stop_mate.or_else(|| {
+                ini.get("Fishnet", "EarlyStopMate")
+                    .map(|m| m.trim().parse().expect("valid early stop mate"))
+            });
+            opt.engine.early_stop_window = opt.engine.early_stop_window.or_else(|| {
+                ini.get("Fishnet", "EarlyStopWindow")
+                    .map(|w| w.trim().parse().expect("valid early stop window"))
+            });
+            opt.engine.deterministic |= ini
+                .getbool("Fishnet", "Deterministic")
+                .expect("valid deterministic")
+                .unwrap_or(false);
+
+            opt.format.node_unit = opt.format.node_unit.or_else(|| {
+                ini.get("Fishnet", "NodeUnit")
+                    .map(|u| u.parse().expect("valid node unit"))
+            });
+            opt.format.score_format = opt.format.score_format.or_else(|| {
+                ini.get("Fishnet", "ScoreFormat")
+                    .map(|f| f.parse().expect("valid score format"))
+            });
+            opt.format.timestamps = opt.format.timestamps.or_else(|| {
+                ini.get("Fishnet", "Timestamps")
+                    .map(|t| t.parse().expect("valid timestamps"))
+            });
+            opt.format.log_format = opt.format.log_format.or_else(|| {
+                ini.get("Fishnet", "LogFormat")
+                    .map(|f| f.parse().expect("valid log format"))
+            });
+            opt.format.tui |= ini
+                .getbool("Fishnet", "Tui")
+                .expect("valid tui")
+                .unwrap_or(false);
+            opt.log_file.log_file = opt
+                .log_file
+                .log_file
+                .or_else(|| ini.get("Fishnet", "LogFile").map(PathBuf::from));
+            opt.log_file.log_max_size = opt.log_file.log_max_size.or_else(|| {
+                ini.get("Fishnet", "LogMaxSize")
+                    .map(|s| s.parse().expect("valid log max size"))
+            });
+            opt.log_file.log_max_age = opt.log_file.log_max_age.or_else(|| {
+                ini.get("Fishnet", "LogMaxAge")
+                    .map(|d| d.parse().expect("valid log max age"))
+            });
+            opt.log_file.log_keep = opt.log_file.log_keep.or_else(|| {
+                ini.get("Fishnet", "LogKeep")
+                    .map(|n| n.trim().parse().expect("valid log keep"))
+            });
+
+            opt.status_bind = opt.status_bind.or_else(|| {
+                ini.get("Fishnet", "StatusBind")
+                    .map(|a| a.parse().expect("valid status bind address"))
+            });
+            opt.metrics_bind = opt.metrics_bind.or_else(|| {
+                ini.get("Fishnet", "MetricsBind")
+                    .map(|a| a.parse().expect("valid metrics bind address"))
+            });
+            opt.otlp_endpoint = opt.otlp_endpoint.or_else(|| {
+                ini.get("Fishnet", "OtlpEndpoint")
+                    .map(|u| u.parse().expect("valid otlp endpoint"))
+            });
+            opt.anomaly_webhook = opt.anomaly_webhook.or_else(|| {
+                ini.get("Fishnet", "AnomalyWebhook")
+                    .map(|u| u.parse().expect("valid anomaly webhook"))
+            });
+            opt.engine_manifest = opt.engine_manifest.or_else(|| {
+                ini.get("Fishnet", "EngineManifest")
+                    .map(|u| u.parse().expect("valid engine manifest"))
+            });
+            opt.engine_update_interval = opt.engine_update_interval.or_else(|| {
+                ini.get("Fishnet", "EngineUpdateInterval")
+                    .map(|d| d.parse().expect("valid engine update interval"))
+            });
+            opt.download_rate_limit = opt.download_rate_limit.or_else(|| {
+                ini.get("Fishnet", "DownloadRateLimit")
+                    .map(|r| r.parse().expect("valid download rate limit"))
+            });
+            opt.trace_engine |= ini
+                .getbool("Fishnet", "TraceEngine")
+                .expect("valid trace engine")
+                .unwrap_or(false);
+            opt.trace_engine_dir = opt
+                .trace_engine_dir
+                .or_else(|| ini.get("Fishnet", "TraceEngineDir").map(PathBuf::from));
+            opt.trace_engine_max_size = opt.trace_engine_max_size.or_else(|| {
+                ini.get("Fishnet", "TraceEngineMaxSize")
+                    .map(|s| s.parse().expect("valid trace engine max size"))
+            });
+            opt.audit_log = opt
+                .audit_log
+                .or_else(|| ini.get("Fishnet", "AuditLog").map(PathBuf::from));
+            opt.no_journal |= ini
+                .getbool("Fishnet", "NoJournal")
+                .expect("valid no journal")
+                .unwrap_or(false);
+            opt.control_socket = opt
+                .control_socket
+                .or_else(|| ini.get("Fishnet", "ControlSocket").map(PathBuf::from));
+            opt.invalid_batch_dir = opt
+                .invalid_batch_dir
+                .or_else(|| ini.get("Fishnet", "InvalidBatchDir").map(PathBuf::from));
+            opt.invalid_batch_max_size = opt.invalid_batch_max_size.or_else(|| {
+                ini.get("Fishnet", "InvalidBatchMaxSize")
+                    .map(|s| s.parse().expect("valid invalid batch max size"))
+            });
+            opt.s3_sink = opt.s3_sink.or_else(|| {
+                ini.get("Fishnet", "S3Sink")
+                    .map(|u| u.parse().expect("valid s3 sink"))
+            });
+            opt.s3_region = opt.s3_region.or_else(|| ini.get("Fishnet", "S3Region"));
+            opt.cluster_bind = opt.cluster_bind.or_else(|| {
+                ini.get("Fishnet", "ClusterBind")
+                    .map(|a| a.parse().expect("valid cluster bind address"))
+            });
+
+            opt.lookup.book = opt
+                .lookup
+                .book
+                .or_else(|| ini.get("Fishnet", "Book").map(PathBuf::from));
+            opt.lookup.book_max_level = opt.lookup.book_max_level.or_else(|| {
+                ini.get("Fishnet", "BookMaxLevel")
+                    .map(|l| l.parse().expect("valid book max level"))
+            });
+            opt.lookup.book_skip_plies = opt.lookup.book_skip_plies.or_else(|| {
+                ini.get("Fishnet", "BookSkipPlies")
+                    .map(|n| n.trim().parse().expect("valid book skip plies"))
+            });
+            opt.lookup.cloud_eval_depth = opt.lookup.cloud_eval_depth.or_else(|| {
+                ini.get("Fishnet", "CloudEvalDepth")
+                    .map(|d| d.parse().expect("valid cloud eval depth"))
+            });
+            opt.lookup.result_cache = opt.lookup.result_cache.or_else(|| {
+                ini.get("Fishnet", "ResultCache")
+                    .map(|n| n.trim().parse().expect("valid result cache"))
+            });
+            opt.lookup.result_cache_file = opt
+                .lookup
+                .result_cache_file
+                .or_else(|| ini.get("Fishnet", "ResultCacheFile").map(PathBuf::from));
+            opt.lookup.lookup_work = opt.lookup.lookup_work.or_else(|| {
+                ini.get("Fishnet", "LookupWork")
+                    .map(|w| w.parse().expect("valid lookup work"))
+            });
+
+            opt.verify.verify_fraction = opt.verify.verify_fraction.or_else(|| {
+                ini.get("Fishnet", "VerifyFraction")
+                    .map(|f| f.parse().expect("valid verify fraction"))
+            });
+            opt.verify.verify_threshold = opt.verify.verify_threshold.or_else(|| {
+                ini.get("Fishnet", "VerifyThreshold")
+                    .map(|t| t.parse().expect("valid verify threshold"))
+            });
+            opt.verify.verify_flavor = opt.verify.verify_flavor.or_else(|| {
+                ini.get("Fishnet", "VerifyFlavor")
+                    .map(|f| parse_engine_flavor(&f).expect("valid verify flavor"))
+            });
+
+            opt.no_official_stockfish |= ini
+                .getbool("Fishnet", "NoOfficialStockfish")
+                .expect("valid no official stockfish")
+                .unwrap_or(false);
+            opt.no_multivariant |= ini
+                .getbool("Fishnet", "NoMultivariant")
+                .expect("valid no multivariant")
+                .unwrap_or(false);
+            opt.force_multivariant |= ini
+                .getbool("Fishnet", "ForceMultivariant")
+                .expect("valid force multivariant")
+                .unwrap_or(false);
+            opt.force_cpu_level = opt.force_cpu_level.or_else(|| {
+                ini.get("Fishnet", "ForceCpuLevel")
+                    .map(|l| l.parse().expect("valid cpu level"))
+            });
+            opt.syzygy_path = opt
+                .syzygy_path
+                .or_else(|| ini.get("Fishnet", "SyzygyPath").map(PathBuf::from));
+            opt.variant_nets = opt.variant_nets.or_else(|| {
+                ini.get("Fishnet", "VariantNets")
+                    .map(|nets| nets.parse().expect("valid variant nets"))
+            });
+            opt.external_engines = opt.external_engines.or_else(|| {
+                ini.get("Fishnet", "ExternalEngines")
+                    .map(|engines| engines.parse().expect("valid external engines"))
+            });
+            opt.worker_name = opt.worker_name.or_else(|| ini.get("Fishnet", "WorkerName"));
+            opt.tls_cert = opt
+                .tls_cert
+                .or_else(|| ini.get("Fishnet", "TlsCert").map(PathBuf::from));
+            opt.tls_key = opt
+                .tls_key
+                .or_else(|| ini.get("Fishnet", "TlsKey").map(PathBuf::from));
+            assert_eq!(
+                opt.tls_cert.is_some(),
+                opt.tls_key.is_some(),
+                "TlsCert and TlsKey must be configured together"
+            );
+            opt.labels = opt.labels.or_else(|| {
+                ini.get("Fishnet", "Labels")
+                    .map(|labels| labels.parse().expect("valid labels"))
+            });
+            opt.variants_allow = opt.variants_allow.or_else(|| {
+                ini.get("Fishnet", "VariantsAllow")
+                    .map(|v| v.parse().expect("valid variants allow"))
+            });
+            opt.variants_deny = opt.variants_deny.or_else(|| {
+                ini.get("Fishnet", "VariantsDeny")
+                    .map(|v| v.parse().expect("valid variants deny"))
+            });
+            opt.max_nodes = opt.max_nodes.or_else(|| {
+                ini.get("Fishnet", "MaxNodes")
+                    .map(|n| n.trim().parse().expect("valid max nodes"))
+            });
+            opt.max_depth = opt.max_depth.or_else(|| {
+                ini.get("Fishnet", "MaxDepth")
+                    .map(|d| d.trim().parse().expect("valid max depth"))
+            });
+            opt.movetime = opt.movetime.or_else(|| {
+                ini.get("Fishnet", "Movetime")
+                    .map(|t| t.parse().expect("valid movetime"))
+            });
+            opt.scale_nodes |= ini
+                .getbool("Fishnet", "ScaleNodes")
+                .expect("valid scale nodes")
+                .unwrap_or(false);
+            opt.pv_san |= ini
+                .getbool("Fishnet", "PvSan")
+                .expect("valid pv san")
+                .unwrap_or(false);
+            opt.analysis_order = opt.analysis_order.or_else(|| {
+                ini.get("Fishnet", "AnalysisOrder")
+                    .map(|o| o.parse().expect("valid analysis order"))
+            });
+            opt.budget_by_phase |= ini
+                .getbool("Fishnet", "BudgetByPhase")
+                .expect("valid budget by phase")
+                .unwrap_or(false);
+            opt.puzzle_candidates |= ini
+                .getbool("Fishnet", "PuzzleCandidates")
+                .expect("valid puzzle candidates")
+                .unwrap_or(false);
+            opt.move_latency_budget = opt.move_latency_budget.or_else(|| {
+                ini.get("Fishnet", "MoveLatencyBudget")
+                    .map(|d| d.parse().expect("valid move latency budget"))
+            });
+            opt.max_error_backoff = opt.max_error_backoff.or_else(|| {
+                ini.get("Fishnet", "MaxErrorBackoff")
+                    .map(|d| d.parse().expect("valid max error backoff"))
+            });
+            if opt.no_multivariant && (opt.no_official_stockfish || opt.force_multivariant) {
+                panic!("at least one engine flavor must be enabled");
+            }
+
+            // Named endpoint blocks. An endpoint given on the command line
+            // takes precedence.
+            if opt.endpoint.is_none() || opt.only_endpoint.is_some() {
+                let mut sections = ini.sections();
+                sections.sort();
+                for section in sections {
+                    if let Some(name) = section.strip_prefix(ENDPOINT_SECTION_PREFIX) {
+                        let conf = EndpointConf::from_ini(&ini, &section, name, &opt);
+                        opt.endpoint_confs.push(conf);
+                    }
+                }
+            }
+        }
+    }
+
+    // Fall back to the single endpoint configured in the main section or on
+    // the command line.
+    if opt.endpoint_confs.is_empty() {
+        opt.endpoint_confs.push(opt.default_endpoint_conf());
+    }
+    opt.endpoint_confs.sort_by_key(|c| Reverse(c.weight));
+    for conf in &mut opt.endpoint_confs {
+        conf.variants
+            .restrict(opt.variants_allow.as_ref(), opt.variants_deny.as_ref());
+    }
+
+    // Select endpoint.
+    if let Some(ref only_endpoint) = opt.only_endpoint {
+        if let Err(err) = select_endpoint(&mut opt.endpoint_confs, only_endpoint) {
+            Opt::into_app()
+                .error(clap::ErrorKind::InvalidValue, err)
+                .exit();
         }
     }
 
     // Validate number of cores.
-    let all = num_cpus::get();
+    let all = affinity::available();
     match opt.cores {
         Some(Cores::Number(n)) if usize::from(n) > all => {
             logger.warn(&format!(
@@ -584,3 +3167,186 @@ pub async fn parse_and_configure() -> Opt {
 
     opt
 }
+
+/// Keeps only the named endpoint. Section names of the configuration file
+/// are not case-sensitive, so neither is the name.
+fn select_endpoint(confs: &mut Vec<EndpointConf>, name: &str) -> Result<(), String> {
+    let wanted = name.to_lowercase();
+    if !confs.iter().any(|c| c.name == wanted) {
+        return Err(format!(
+            "no endpoint named {:?} configured (available: {})",
+            name,
+            confs
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    confs.retain(|c| c.name == wanted);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variant_filter_any_of() {
+        let chess: VariantFilter = "standard,chess960".parse().unwrap();
+        let atomic: VariantFilter = "atomic".parse().unwrap();
+        let any = VariantFilter::any_of([&chess, &atomic]);
+        assert!(any.allows(LichessVariant::Standard));
+        assert!(any.allows(LichessVariant::Atomic));
+        assert!(!any.allows(LichessVariant::Horde));
+        assert_eq!(any.to_string(), "standard,chess960,atomic");
+
+        // No need to ask for variants if any endpoint accepts all.
+        let all = VariantFilter::any_of([&chess, &VariantFilter::default()]);
+        assert!(all.accepted().is_none());
+    }
+
+    #[test]
+    fn test_parsed_size() {
+        assert_eq!(u64::from("1024".parse::<ParsedSize>().unwrap()), 1024);
+        assert_eq!(u64::from("1.5GiB".parse::<ParsedSize>().unwrap()), 3 << 29);
+        assert_eq!(u64::from("2M".parse::<ParsedSize>().unwrap()), 2 << 20);
+        assert_eq!(u64::from("5MB".parse::<ParsedSize>().unwrap()), 5_000_000);
+        assert!("1G500M".parse::<ParsedSize>().is_err());
+        assert!("1,5GiB".parse::<ParsedSize>().is_err());
+        assert!("5MB/s".parse::<ParsedSize>().is_err());
+        assert!("".parse::<ParsedSize>().is_err());
+
+        for size in ["256MiB", "1GiB", "1000B"] {
+            assert_eq!(size.parse::<ParsedSize>().unwrap().to_string(), size);
+        }
+        assert_eq!("5MB".parse::<ParsedSize>().unwrap().to_string(), "5000000B");
+    }
+
+    #[test]
+    fn test_parsed_rate() {
+        let rate: ParsedRate = "5MB/s".parse().unwrap();
+        assert_eq!(u64::from(rate), 5_000_000);
+        assert_eq!("2MiB".parse::<ParsedRate>().unwrap().to_string(), "2MiB/s");
+        assert_eq!(
+            "2MiB/s".parse::<ParsedRate>().unwrap().to_string(),
+            "2MiB/s"
+        );
+        assert!("2MiB/h".parse::<ParsedRate>().is_err());
+    }
+
+    #[test]
+    fn test_parsed_duration() {
+        let millis = |s: &str| Duration::from(s.parse::<ParsedDuration>().unwrap()).as_millis();
+        assert_eq!(millis("90"), 90_000);
+        assert_eq!(millis("1.5h"), 90 * 60 * 1000);
+        assert_eq!(millis("2h30m"), 150 * 60 * 1000);
+        assert_eq!(millis("250ms"), 250);
+        assert!("30m2h".parse::<ParsedDuration>().is_err());
+        assert!("1h30".parse::<ParsedDuration>().is_err());
+        assert!("1w".parse::<ParsedDuration>().is_err());
+
+        for duration in ["0s", "1m30s", "1d6h", "1s500ms"] {
+            assert_eq!(
+                duration.parse::<ParsedDuration>().unwrap().to_string(),
+                duration
+            );
+        }
+    }
+
+    #[test]
+    fn test_endpoint_and_key() {
+        let endpoint: Endpoint = "https://lichess.org/fishnet/".parse().unwrap();
+        assert_eq!(endpoint.to_string(), "https://lichess.org/fishnet");
+        assert!(!endpoint.is_development());
+        assert!("http://localhost:9663/fishnet"
+            .parse::<Endpoint>()
+            .unwrap()
+            .is_development());
+
+        assert!("abc123".parse::<Key>().is_ok());
+        assert!(matches!("".parse::<Key>(), Err(KeyError::EmptyKey)));
+        assert!(matches!(
+            "abc 123".parse::<Key>(),
+            Err(KeyError::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn test_endpoint_conf_from_ini() {
+        let mut ini = Ini::new();
+        ini.set_default_section("Fishnet");
+        ini.read(
+            [
+                "[Fishnet]",
+                "Key = defaultkey",
+                "",
+                "[endpoint.variants]",
+                "Url = http://localhost:9663/fishnet/",
+                "Variants = atomic,horde",
+                "Weight = 3",
+                "",
+                "[endpoint.other]",
+                "Url = https://lichess.org/fishnet",
+                "Key = otherkey",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        let mut defaults = Opt::try_parse_from(["fishnet"]).unwrap();
+        defaults.key = ini.get("Fishnet", "Key").map(|k| k.parse().unwrap());
+
+        let conf = EndpointConf::from_ini(&ini, "endpoint.variants", "variants", &defaults);
+        assert_eq!(conf.name, "variants");
+        assert_eq!(conf.endpoint.to_string(), "http://localhost:9663/fishnet");
+        assert_eq!(conf.key.map(|Key(k)| k).as_deref(), Some("defaultkey"));
+        assert!(conf.variants.allows(LichessVariant::Atomic));
+        assert!(!conf.variants.allows(LichessVariant::Standard));
+        assert_eq!(conf.weight, 3);
+
+        let conf = EndpointConf::from_ini(&ini, "endpoint.other", "other", &defaults);
+        assert_eq!(conf.key.map(|Key(k)| k).as_deref(), Some("otherkey"));
+        assert!(conf.variants.allows(LichessVariant::Standard));
+        assert_eq!(conf.weight, 1);
+    }
+
+    #[test]
+    fn test_select_endpoint() {
+        let mut ini = Ini::new();
+        ini.read(
+            [
+                "[Endpoint.Local]",
+                "Url = http://localhost:9663/fishnet/",
+                "",
+                "[Endpoint.Lichess]",
+                "Url = https://lichess.org/fishnet",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        let defaults = Opt::try_parse_from(["fishnet"]).unwrap();
+        let confs: Vec<EndpointConf> = ini
+            .sections()
+            .iter()
+            .filter_map(|section| {
+                let name = section.strip_prefix(ENDPOINT_SECTION_PREFIX)?;
+                Some(EndpointConf::from_ini(&ini, section, name, &defaults))
+            })
+            .collect();
+        assert_eq!(confs.len(), 2);
+
+        let mut selected = confs.clone();
+        select_endpoint(&mut selected, "Local").unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(
+            selected[0].endpoint.to_string(),
+            "http://localhost:9663/fishnet"
+        );
+
+        let mut selected = confs.clone();
+        let err = select_endpoint(&mut selected, "Staging").unwrap_err();
+        assert!(err.contains("Staging"), "{}", err);
+        assert!(err.contains("lichess"), "{}", err);
+        assert_eq!(selected.len(), 2);
+    }
+}
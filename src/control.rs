@@ -0,0 +1,327 @@
+use std::{error::Error, fmt, io, io::BufRead as _, num::NonZeroUsize, str::FromStr, thread};
+
+use clap::Parser;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::logger::Logger;
+
+/// Commands for a running instance. Sent as a single line to the control
+/// socket, or as the body of POST /control to the status server (with
+/// `Content-Type: application/x-fishnet-control`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Parser)]
+pub enum ControlCommand {
+    /// Print the status as JSON.
+    Status,
+    /// Print the status, recent log lines and engine transcripts as JSON.
+    Report,
+    /// Print cumulative statistics of this session and all time as JSON.
+    Stats,
+    /// Finish pending batches, then stop.
+    Drain,
+    /// Reload cores, backlog and verbosity from the configuration file.
+    Reload,
+    /// Change a setting at runtime (for example cores=2 or verbose=1).
+    Set { setting: Setting },
+    /// Use one more core, up to the number of cores at startup.
+    Up,
+    /// Use one fewer core. Workers finish their current batch before they
+    /// are parked.
+    Down,
+    /// Park all workers after their current batch, until resumed.
+    Pause,
+    /// Use the cores from before pausing again.
+    Resume,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Setting {
+    Cores(NonZeroUsize),
+    Verbose(usize),
+}
+
+#[derive(Debug)]
+pub struct ControlError(String);
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for ControlError {}
+
+impl FromStr for Setting {
+    type Err = ControlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once('=') {
+            Some(("cores", n)) => n
+                .trim()
+                .parse()
+                .map(Setting::Cores)
+                .map_err(|err| ControlError(format!("invalid cores: {}", err))),
+            Some(("verbose", level)) => level
+                .trim()
+                .parse()
+                .map(Setting::Verbose)
+                .map_err(|err| ControlError(format!("invalid verbose: {}", err))),
+            _ => Err(ControlError(format!(
+                "unknown setting: {:?} (expected cores=N or verbose=N)",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Setting::Cores(n) => write!(f, "cores={}", n),
+            Setting::Verbose(level) => write!(f, "verbose={}", level),
+        }
+    }
+}
+
+impl FromStr for ControlCommand {
+    type Err = ControlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let command = match (parts.next(), parts.next()) {
+            (Some("status"), None) => ControlCommand::Status,
+            (Some("report"), None) => ControlCommand::Report,
+            (Some("stats"), None) => ControlCommand::Stats,
+            (Some("drain"), None) => ControlCommand::Drain,
+            (Some("reload"), None) => ControlCommand::Reload,
+            (Some("up"), None) => ControlCommand::Up,
+            (Some("down"), None) => ControlCommand::Down,
+            (Some("pause"), None) => ControlCommand::Pause,
+            (Some("resume"), None) => ControlCommand::Resume,
+            (Some("set"), Some(setting)) => ControlCommand::Set {
+                setting: setting.parse()?,
+            },
+            _ => {
+                return Err(ControlError(format!(
+                    "unknown command: {:?} (expected status, report, stats, drain, reload, up, down, pause, resume, set cores=N or set verbose=N)",
+                    s.trim()
+                )))
+            }
+        };
+        match parts.next() {
+            Some(extra) => Err(ControlError(format!("unexpected argument: {:?}", extra))),
+            None => Ok(command),
+        }
+    }
+}
+
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlCommand::Status => f.write_str("status"),
+            ControlCommand::Report => f.write_str("report"),
+            ControlCommand::Stats => f.write_str("stats"),
+            ControlCommand::Drain => f.write_str("drain"),
+            ControlCommand::Reload => f.write_str("reload"),
+            ControlCommand::Set { setting } => write!(f, "set {}", setting),
+            ControlCommand::Up => f.write_str("up"),
+            ControlCommand::Down => f.write_str("down"),
+            ControlCommand::Pause => f.write_str("pause"),
+            ControlCommand::Resume => f.write_str("resume"),
+        }
+    }
+}
+
+/// A command that changes the running instance, to be handled by the main
+/// loop.
+#[derive(Debug)]
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    pub callback: oneshot::Sender<Result<String, String>>,
+}
+
+pub fn channel() -> (ControlStub, mpsc::UnboundedReceiver<ControlRequest>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (ControlStub { tx }, rx)
+}
+
+#[derive(Debug, Clone)]
+pub struct ControlStub {
+    tx: mpsc::UnboundedSender<ControlRequest>,
+}
+
+impl ControlStub {
+    pub async fn send(&self, command: ControlCommand) -> Result<String, String> {
+        let (callback, response) = oneshot::channel();
+        self.tx
+            .send(ControlRequest { command, callback })
+            .map_err(|_| "shutting down".to_owned())?;
+        response.await.map_err(|_| "shutting down".to_owned())?
+    }
+}
+
+/// Scales the number of active cores with keys typed into the terminal:
+/// `+` for one more, `-` for one fewer, each line confirmed with enter.
+pub fn spawn_keys(control: ControlStub, logger: Logger) {
+    // Reading stdin blocks. A detached thread does not hold up the runtime
+    // on shutdown.
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        while let Some(line) = rx.recv().await {
+            for key in line.chars().filter(|c| !c.is_whitespace()) {
+                let command = match key {
+                    '+' => ControlCommand::Up,
+                    '-' => ControlCommand::Down,
+                    _ => {
+                        logger.warn(&format!(
+                            "Unknown key {:?}. Press + or - and enter to scale cores.",
+                            key
+                        ));
+                        break;
+                    }
+                };
+                if let Err(err) = control.send(command).await {
+                    logger.warn(&format!("Cannot scale cores: {}", err));
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(unix)]
+pub mod socket {
+    use std::{fs, io, os::unix::fs::FileTypeExt as _, path::Path};
+
+    use tokio::{
+        io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader},
+        net::{UnixListener, UnixStream},
+    };
+
+    use super::*;
+    use crate::status::StatusServer;
+
+    /// Serves the control socket until the process exits. Each connection
+    /// sends a single command line, and receives the response.
+    pub fn spawn(path: &Path, status: StatusServer, logger: Logger) {
+        if let Err(err) = remove_stale(path) {
+            logger.error(&format!(
+                "Failed to bind control socket {:?}: {}",
+                path, err
+            ));
+            return;
+        }
+        let listener = match UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(err) => {
+                logger.error(&format!(
+                    "Failed to bind control socket {:?}: {}",
+                    path, err
+                ));
+                return;
+            }
+        };
+        logger.info(&format!("Control socket: {:?}", path));
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let status = status.clone();
+                        let logger = logger.clone();
+                        tokio::spawn(async move {
+                            if let Err(err) = serve(stream, status).await {
+                                logger.debug(&format!("Control connection failed: {}", err));
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        logger.error(&format!("Control socket failed: {}", err));
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Removes the socket of a previous run that was not shut down cleanly,
+    /// but neither other files nor the socket of a running instance.
+    fn remove_stale(path: &Path) -> io::Result<()> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "file exists and is not a socket",
+            ));
+        }
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another instance is listening",
+            )),
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn serve(stream: UnixStream, status: StatusServer) -> io::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut line = String::new();
+        BufReader::new(read).read_line(&mut line).await?;
+        let response = match line.parse::<ControlCommand>() {
+            Ok(command) => status.control(command).await,
+            Err(err) => Err(err.to_string()),
+        };
+        match response {
+            Ok(text) => write.write_all(text.as_bytes()).await?,
+            Err(err) => write.write_all(format!("E: {}", err).as_bytes()).await?,
+        }
+        write.write_all(b"\n").await?;
+        write.shutdown().await
+    }
+
+    /// Sends a command to a running instance and returns its response.
+    pub async fn request(path: &Path, command: ControlCommand) -> io::Result<String> {
+        let mut stream = UnixStream::connect(path).await?;
+        stream
+            .write_all(format!("{}\n", command).as_bytes())
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+}
+
+#[cfg(not(unix))]
+pub mod socket {
+    use std::{io, path::Path};
+
+    use super::*;
+    use crate::status::StatusServer;
+
+    pub fn spawn(_path: &Path, _status: StatusServer, logger: Logger) {
+        logger.warn("Control sockets are not supported on this platform. Use POST /control on the status server instead.");
+    }
+
+    pub async fn request(_path: &Path, _command: ControlCommand) -> io::Result<String> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "control sockets not supported on this platform",
+        ))
+    }
+}
@@ -0,0 +1,472 @@
+use std::cmp::{max, min};
+
+use tokio::sync::{mpsc, watch};
+
+use crate::{
+    activity::{self, UserActivity},
+    configure::{OnBattery, Opt},
+    lease::CoreLease,
+    logger::Logger,
+    power::{self, PowerChange, PowerSource},
+    queue::QueueStub,
+    thermal,
+    util::NevermindExt as _,
+};
+
+/// Degrees Celsius that the CPU must cool down below --max-temperature,
+/// before throttled cores are used again.
+const THERMAL_HYSTERESIS: f64 = 5.0;
+
+/// Limits on the number of cores in use. Each source sets and clears only
+/// its own cap, and the workers use the configured number of cores, capped
+/// by all active limits. So when events overlap, like pausing while on
+/// battery, each of them ending restores exactly what the others still
+/// allow.
+#[derive(Debug, Clone)]
+pub struct CoreCaps {
+    /// Cores the workers were started with.
+    max: usize,
+    /// Cores requested on startup, at runtime or by reloading the
+    /// configuration file.
+    configured: usize,
+    /// Paused with the control socket.
+    paused: bool,
+    /// Paused while the machine is in use.
+    in_use: bool,
+    /// Running on battery.
+    battery: Option<usize>,
+    /// Throttled while the CPU is too hot.
+    thermal: Option<usize>,
+    /// Granted from the budget shared with other instances.
+    lease: Option<usize>,
+}
+
+impl CoreCaps {
+    pub fn new(max: usize) -> CoreCaps {
+        CoreCaps {
+            max,
+            configured: max,
+            paused: false,
+            in_use: false,
+            battery: None,
+            thermal: None,
+            lease: None,
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn configured(&self) -> usize {
+        self.configured
+    }
+
+    /// Cores to use.
+    pub fn effective(&self) -> usize {
+        if self.paused || self.in_use {
+            return 0;
+        }
+        [self.battery, self.thermal, self.lease]
+            .into_iter()
+            .flatten()
+            .fold(self.configured, min)
+    }
+
+    /// Active limits, for messages.
+    pub fn limited_by(&self) -> Vec<&'static str> {
+        let mut limits = Vec::new();
+        if self.paused {
+            limits.push("paused");
+        }
+        if self.in_use {
+            limits.push("machine in use");
+        }
+        if self.battery.is_some() {
+            limits.push("on battery");
+        }
+        if self.thermal.is_some() {
+            limits.push("temperature");
+        }
+        if self.lease.map_or(false, |lease| lease < self.configured) {
+            limits.push("core lease");
+        }
+        limits
+    }
+
+    pub fn configure(&mut self, n: usize) -> Result<(), String> {
+        if n > self.max {
+            return Err(format!(
+                "started with {} cores, restart to use more",
+                self.max
+            ));
+        }
+        self.configured = n;
+        Ok(())
+    }
+
+    pub fn pause(&mut self) -> Result<(), String> {
+        if self.paused {
+            return Err("already paused".to_owned());
+        }
+        self.paused = true;
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> Result<(), String> {
+        if !self.paused {
+            return Err("not paused".to_owned());
+        }
+        self.paused = false;
+        Ok(())
+    }
+
+    pub fn power(&mut self, source: PowerSource, on_battery: Option<OnBattery>) {
+        self.battery = match (source, on_battery) {
+            (PowerSource::Battery, Some(OnBattery::Pause)) => Some(0),
+            (PowerSource::Battery, Some(OnBattery::OneCore)) => Some(1),
+            (PowerSource::Battery, Some(OnBattery::Ignore) | None) | (PowerSource::Ac, _) => None,
+        };
+    }
+
+    /// Pauses while the machine is in use. Returns whether the activity
+    /// changed.
+    pub fn activity(&mut self, activity: UserActivity) -> bool {
+        let in_use = activity == UserActivity::Active;
+        let changed = in_use != self.in_use;
+        self.in_use = in_use;
+        changed
+    }
+
+    /// Uses one core less while the CPU is above the maximum temperature,
+    /// and one core more each time it is reported well below. Returns
+    /// whether a core was given up.
+    pub fn temperature(&mut self, celsius: f64, max_temperature: f64) -> bool {
+        let current = self.effective();
+        if celsius > max_temperature && current > 1 {
+            self.thermal = Some(current - 1);
+            true
+        } else {
+            if celsius < max_temperature - THERMAL_HYSTERESIS {
+                self.thermal = self
+                    .thermal
+                    .map(|cap| cap + 1)
+                    .filter(|&cap| cap < self.configured);
+            }
+            false
+        }
+    }
+
+    pub fn lease(&mut self, granted: usize) {
+        self.lease = Some(min(granted, self.max));
+    }
+
+    /// Cores in use and why, for replies on the control socket.
+    pub fn summary(&self) -> String {
+        let n = self.effective();
+        let limited_by = self.limited_by();
+        if limited_by.is_empty() {
+            format!("Using {} of {} cores.", n, self.max)
+        } else {
+            format!(
+                "Using {} of {} cores ({} configured, limited: {}).",
+                n,
+                self.max,
+                self.configured,
+                limited_by.join(", ")
+            )
+        }
+    }
+}
+
+/// Event that may change the cores allowed.
+#[derive(Debug, Copy, Clone)]
+pub enum CoreEvent {
+    Power(PowerSource),
+    Activity(UserActivity),
+    Temperature(f64),
+    Lease(usize),
+}
+
+/// Arbitrates the cores in use between the control socket and the
+/// watchers for the power source, user activity, CPU temperature and the
+/// core lease. Workers with an index of at least the number of active
+/// cores are parked.
+pub struct CoreArbiter {
+    caps: CoreCaps,
+    active_cores: watch::Sender<usize>,
+    on_battery: Option<OnBattery>,
+    max_temperature: Option<u8>,
+    power: mpsc::UnboundedReceiver<PowerSource>,
+    user_activity: mpsc::UnboundedReceiver<UserActivity>,
+    temperature: mpsc::UnboundedReceiver<f64>,
+    leased_cores: mpsc::UnboundedReceiver<usize>,
+    core_lease: Option<CoreLease>,
+    queue: QueueStub,
+    logger: Logger,
+}
+
+impl CoreArbiter {
+    /// Starts the watchers enabled by the options. The receiver gets the
+    /// number of active cores.
+    pub fn spawn(
+        opt: &Opt,
+        cores: usize,
+        queue: QueueStub,
+        logger: Logger,
+    ) -> (CoreArbiter, watch::Receiver<usize>) {
+        let (active_cores, active_cores_rx) = watch::channel(cores);
+
+        // React to running on battery, by pausing or reducing to one core
+        // until AC power returns.
+        let power = match opt.on_battery {
+            Some(OnBattery::Pause | OnBattery::OneCore) => power::spawn_watcher(logger.clone()),
+            Some(OnBattery::Ignore) | None => mpsc::unbounded_channel().1,
+        };
+
+        // Pause while the machine is in use, until it was idle for a while.
+        let user_activity = if opt.pause_when_active {
+            activity::spawn_watcher(logger.clone())
+        } else {
+            mpsc::unbounded_channel().1
+        };
+
+        // Use fewer cores while the CPU is too hot, and restore them one by
+        // one once it cooled down.
+        let temperature = match opt.max_temperature {
+            Some(_) => thermal::spawn_watcher(logger.clone()),
+            None => mpsc::unbounded_channel().1,
+        };
+
+        // Use at most the cores leased from the budget shared with other
+        // instances on this host.
+        let (core_lease, leased_cores) = match opt.core_lease_dir {
+            Some(ref dir) => match CoreLease::spawn(dir.clone(), cores, logger.clone()) {
+                Ok((core_lease, leased_cores)) => {
+                    logger.info(&format!("Sharing cores with instances using {:?}", dir));
+                    (Some(core_lease), leased_cores)
+                }
+                Err(err) => {
+                    logger.error(&format!(
+                        "Failed to use core lease directory {:?}: {}",
+                        dir, err
+                    ));
+                    (None, mpsc::unbounded_channel().1)
+                }
+            },
+            None => (None, mpsc::unbounded_channel().1),
+        };
+
+        (
+            CoreArbiter {
+                caps: CoreCaps::new(cores),
+                active_cores,
+                on_battery: opt.on_battery,
+                max_temperature: opt.max_temperature,
+                power,
+                user_activity,
+                temperature,
+                leased_cores,
+                core_lease,
+                queue,
+                logger,
+            },
+            active_cores_rx,
+        )
+    }
+
+    pub fn max(&self) -> usize {
+        self.caps.max()
+    }
+
+    /// Waits for the next event of an enabled watcher, or returns `None`
+    /// once there are none left.
+    pub async fn next_event(&mut self) -> Option<CoreEvent> {
+        tokio::select! {
+            Some(source) = self.power.recv() => Some(CoreEvent::Power(source)),
+            Some(activity) = self.user_activity.recv() => Some(CoreEvent::Activity(activity)),
+            Some(celsius) = self.temperature.recv() => Some(CoreEvent::Temperature(celsius)),
+            Some(granted) = self.leased_cores.recv() => Some(CoreEvent::Lease(granted)),
+            else => None,
+        }
+    }
+
+    pub async fn handle(&mut self, event: CoreEvent) {
+        match event {
+            CoreEvent::Power(source) => {
+                self.logger
+                    .fishnet_info(&format!("Power source: {}", source));
+                self.caps.power(source, self.on_battery);
+                self.apply().await;
+                let change = PowerChange::new(source, *self.active_cores.borrow());
+                self.queue.record_power_change(change).await;
+            }
+            CoreEvent::Activity(activity) => {
+                if self.caps.activity(activity) {
+                    self.logger.fishnet_info(match activity {
+                        UserActivity::Active => "Machine in use. Pausing.",
+                        UserActivity::Idle => "Machine idle. Resuming.",
+                    });
+                    self.apply().await;
+                }
+            }
+            CoreEvent::Temperature(celsius) => {
+                let max_temperature = f64::from(self.max_temperature.unwrap_or(u8::MAX));
+                if self.caps.temperature(celsius, max_temperature) {
+                    self.logger.fishnet_info(&format!(
+                        "CPU at {:.0}°C, above {}°C. Using one core less.",
+                        celsius, max_temperature
+                    ));
+                }
+                self.apply().await;
+            }
+            CoreEvent::Lease(granted) => {
+                self.caps.lease(granted);
+                self.apply().await;
+            }
+        }
+    }
+
+    /// Uses the given number of cores, as far as the limits allow.
+    pub async fn configure(&mut self, n: usize) -> Result<String, String> {
+        self.caps.configure(n)?;
+        Ok(self.apply().await)
+    }
+
+    pub async fn up(&mut self) -> Result<String, String> {
+        self.configure(self.caps.configured() + 1).await
+    }
+
+    pub async fn down(&mut self) -> Result<String, String> {
+        if self.caps.configured() > 1 {
+            self.configure(self.caps.configured() - 1).await
+        } else {
+            Err("already at one core, drain to stop".to_owned())
+        }
+    }
+
+    pub async fn pause(&mut self) -> Result<String, String> {
+        self.caps.pause()?;
+        self.apply().await;
+        Ok("Paused. Workers finish their current batch.".to_owned())
+    }
+
+    pub async fn resume(&mut self) -> Result<String, String> {
+        self.caps.resume()?;
+        Ok(self.apply().await)
+    }
+
+    /// Gives up leased cores, so that other instances can have them, for
+    /// example while draining.
+    pub fn release(&self) {
+        if let Some(ref core_lease) = self.core_lease {
+            core_lease.release();
+        }
+    }
+
+    /// Applies the cores allowed by the current limits, if they changed.
+    async fn apply(&self) -> String {
+        let n = self.caps.effective();
+        if n != *self.active_cores.borrow() {
+            // Keep the queue estimates meaningful, even while all workers
+            // are paused.
+            self.queue.set_cores(max(1, n)).await;
+            self.active_cores.send(n).nevermind("workers stopped");
+            self.logger
+                .fishnet_info(&format!("Cores: {} (changed at runtime)", n));
+        }
+        self.caps.summary()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlapping_caps() {
+        let mut caps = CoreCaps::new(8);
+        assert_eq!(caps.effective(), 8);
+
+        // Pausing on battery, then resuming, keeps the battery limit.
+        caps.power(PowerSource::Battery, Some(OnBattery::OneCore));
+        caps.pause().unwrap();
+        assert_eq!(caps.effective(), 0);
+        caps.resume().unwrap();
+        assert_eq!(caps.effective(), 1);
+        caps.power(PowerSource::Ac, Some(OnBattery::OneCore));
+        assert_eq!(caps.effective(), 8);
+
+        // Battery while paused, then AC, stays paused.
+        caps.pause().unwrap();
+        caps.power(PowerSource::Battery, Some(OnBattery::Pause));
+        caps.power(PowerSource::Ac, Some(OnBattery::Pause));
+        assert_eq!(caps.effective(), 0);
+        caps.resume().unwrap();
+        assert_eq!(caps.effective(), 8);
+
+        // AC does not lift a thermal throttle or lease.
+        caps.lease(6);
+        assert!(caps.temperature(90.0, 80.0));
+        assert_eq!(caps.effective(), 5);
+        caps.power(PowerSource::Battery, Some(OnBattery::Pause));
+        caps.power(PowerSource::Ac, Some(OnBattery::Pause));
+        assert_eq!(caps.effective(), 5);
+        assert_eq!(caps.limited_by(), ["temperature", "core lease"]);
+
+        // Cooling down restores one core at a time, up to the lease.
+        assert!(!caps.temperature(70.0, 80.0));
+        assert_eq!(caps.effective(), 6);
+        assert!(!caps.temperature(70.0, 80.0));
+        assert!(!caps.temperature(70.0, 80.0));
+        assert!(!caps.temperature(70.0, 80.0));
+        assert_eq!(caps.effective(), 6);
+        caps.lease(8);
+        assert_eq!(caps.effective(), 8);
+        assert!(caps.limited_by().is_empty());
+    }
+
+    #[test]
+    fn test_activity() {
+        let mut caps = CoreCaps::new(8);
+
+        // Idle does not lift the battery limit that started while in use.
+        assert!(caps.activity(UserActivity::Active));
+        assert!(!caps.activity(UserActivity::Active));
+        caps.power(PowerSource::Battery, Some(OnBattery::OneCore));
+        assert_eq!(caps.effective(), 0);
+        assert!(caps.activity(UserActivity::Idle));
+        assert_eq!(caps.effective(), 1);
+
+        // Nor a lease that shrank in the meantime.
+        caps.power(PowerSource::Ac, Some(OnBattery::OneCore));
+        caps.activity(UserActivity::Active);
+        caps.lease(3);
+        caps.activity(UserActivity::Idle);
+        assert_eq!(caps.effective(), 3);
+    }
+
+    #[test]
+    fn test_configure() {
+        let mut caps = CoreCaps::new(4);
+        caps.lease(2);
+        caps.configure(3).unwrap();
+        assert_eq!(caps.effective(), 2);
+        caps.configure(1).unwrap();
+        assert_eq!(caps.effective(), 1);
+        assert!(caps.configure(5).is_err());
+        assert!(caps.resume().is_err());
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut caps = CoreCaps::new(4);
+        assert_eq!(caps.summary(), "Using 4 of 4 cores.");
+        caps.configure(3).unwrap();
+        caps.power(PowerSource::Battery, Some(OnBattery::OneCore));
+        assert_eq!(
+            caps.summary(),
+            "Using 1 of 4 cores (3 configured, limited: on battery)."
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use tokio::process::Child;
+
+/// Reads the CPU time consumed by a running engine process, to account for
+/// the actual cost of analysis, rather than estimating it from nodes.
+#[derive(Debug, Copy, Clone)]
+pub struct CpuClock {
+    inner: imp::Clock,
+}
+
+impl CpuClock {
+    /// `None` if the CPU time of the process can not be measured on this
+    /// platform, or if it already exited.
+    pub fn new(child: &Child) -> Option<CpuClock> {
+        imp::Clock::new(child).map(|inner| CpuClock { inner })
+    }
+
+    /// User and system time of all threads of the process so far. Only
+    /// valid while the child is not reaped.
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.inner.elapsed()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{fs, time::Duration};
+
+    use tokio::process::Child;
+
+    #[derive(Debug, Copy, Clone)]
+    pub struct Clock {
+        pid: u32,
+        ticks_per_second: u64,
+    }
+
+    impl Clock {
+        pub fn new(child: &Child) -> Option<Clock> {
+            let ticks_per_second = unsafe {
+                // Safety: Has no memory safety implications.
+                libc::sysconf(libc::_SC_CLK_TCK)
+            };
+            Some(Clock {
+                pid: child.id()?,
+                ticks_per_second: u64::try_from(ticks_per_second).ok().filter(|&t| t > 0)?,
+            })
+        }
+
+        pub fn elapsed(&self) -> Option<Duration> {
+            // pid (comm) state ppid ... utime stime, where utime is the 14th
+            // field.
+            let stat = fs::read_to_string(format!("/proc/{}/stat", self.pid)).ok()?;
+            let (_, rest) = stat.rsplit_once(')')?;
+            let mut fields = rest.split_whitespace().skip(11);
+            let utime: u64 = fields.next()?.parse().ok()?;
+            let stime: u64 = fields.next()?.parse().ok()?;
+            Some(Duration::from_nanos(
+                (utime + stime).saturating_mul(1_000_000_000) / self.ticks_per_second,
+            ))
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::{mem, time::Duration};
+
+    use tokio::process::Child;
+    use windows_sys::Win32::{Foundation::FILETIME, System::Threading::GetProcessTimes};
+
+    #[derive(Debug, Copy, Clone)]
+    pub struct Clock {
+        handle: isize,
+    }
+
+    impl Clock {
+        pub fn new(child: &Child) -> Option<Clock> {
+            Some(Clock {
+                handle: child.raw_handle()? as isize,
+            })
+        }
+
+        pub fn elapsed(&self) -> Option<Duration> {
+            fn hundred_nanos(time: FILETIME) -> u64 {
+                (u64::from(time.dwHighDateTime) << 32) | u64::from(time.dwLowDateTime)
+            }
+
+            unsafe {
+                // Safety: The handle is valid while the child is not reaped,
+                // and the times are plain data that is valid when zeroed.
+                let mut creation: FILETIME = mem::zeroed();
+                let mut exit: FILETIME = mem::zeroed();
+                let mut kernel: FILETIME = mem::zeroed();
+                let mut user: FILETIME = mem::zeroed();
+                if GetProcessTimes(
+                    self.handle,
+                    &mut creation,
+                    &mut exit,
+                    &mut kernel,
+                    &mut user,
+                ) == 0
+                {
+                    return None;
+                }
+                Some(Duration::from_nanos(
+                    (hundred_nanos(kernel) + hundred_nanos(user)) * 100,
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod imp {
+    use std::time::Duration;
+
+    use tokio::process::Child;
+
+    #[derive(Debug, Copy, Clone)]
+    pub enum Clock {}
+
+    impl Clock {
+        pub fn new(_child: &Child) -> Option<Clock> {
+            None
+        }
+
+        pub fn elapsed(&self) -> Option<Duration> {
+            match *self {}
+        }
+    }
+}
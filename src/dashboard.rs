@@ -0,0 +1,213 @@
+use std::{io, io::Write as _, time::Duration};
+
+use atty::Stream;
+use serde::Deserialize;
+use serde_json::Value;
+use thousands::Separable as _;
+use tokio::time;
+use url::Url;
+
+use crate::logger::Logger;
+
+/// Poll the status servers this often.
+const REFRESH: Duration = Duration::from_secs(5);
+
+/// The part of /status.json that is aggregated. Fields are looked up
+/// leniently, so that instances running other versions can be shown.
+#[derive(Debug, Default)]
+struct InstanceStatus {
+    version: String,
+    cores: u64,
+    working: u64,
+    pending_batches: u64,
+    draining: bool,
+    anomalies: u64,
+    degradations: u64,
+    clock_skew: i64,
+}
+
+impl InstanceStatus {
+    fn from_json(status: &Value) -> InstanceStatus {
+        let queue = &status["queue"];
+        InstanceStatus {
+            version: status["description"]["version"]
+                .as_str()
+                .unwrap_or("?")
+                .to_owned(),
+            cores: queue["cores"].as_u64().unwrap_or(0),
+            working: status["workers"].as_array().map_or(0, |workers| {
+                workers
+                    .iter()
+                    .filter(|worker| worker["state"] == "working")
+                    .count() as u64
+            }),
+            pending_batches: queue["pending"].as_array().map_or(0, |p| p.len() as u64),
+            draining: queue["shutdown_soon"].as_bool().unwrap_or(false),
+            anomalies: queue["anomalies"].as_array().map_or(0, |a| a.len() as u64),
+            degradations: queue["degradations"]
+                .as_array()
+                .map_or(0, |d| d.len() as u64),
+            clock_skew: status["clock_skew"].as_i64().unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ThroughputSample {
+    nodes: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Readiness {
+    ready: bool,
+}
+
+#[derive(Debug)]
+struct Instance {
+    status: InstanceStatus,
+    /// Nodes per second during the last full minute.
+    nps: u64,
+    ready: bool,
+}
+
+impl Instance {
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.ready {
+            problems.push("not ready".to_owned());
+        }
+        if self.status.draining {
+            problems.push("draining".to_owned());
+        }
+        if self.status.anomalies > 0 {
+            problems.push(format!("{} engine anomalies", self.status.anomalies));
+        }
+        if self.status.degradations > 0 {
+            problems.push(format!("{} degradations", self.status.degradations));
+        }
+        if self.status.clock_skew.abs() > 60 {
+            problems.push(format!("clock skew {}s", self.status.clock_skew));
+        }
+        problems
+    }
+}
+
+fn parse_host(host: &str) -> Result<Url, url::ParseError> {
+    if host.contains("://") {
+        host.parse()
+    } else {
+        format!("http://{}/", host).parse()
+    }
+}
+
+async fn poll(client: &reqwest::Client, base: &Url) -> reqwest::Result<Instance> {
+    let status: Value = client
+        .get(base.join("status.json").expect("status url"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let throughput: Vec<ThroughputSample> = client
+        .get(base.join("throughput.json").expect("throughput url"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    // Not ready is signalled by 503, with the same body.
+    let readiness: Readiness = client
+        .get(base.join("readyz").expect("readiness url"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    Ok(Instance {
+        status: InstanceStatus::from_json(&status),
+        nps: throughput.last().map_or(0, |sample| sample.nodes / 60),
+        ready: readiness.ready,
+    })
+}
+
+/// Polls the status servers of multiple instances, and renders a combined
+/// view until interrupted.
+pub async fn dashboard(hosts: Vec<String>, logger: &Logger) {
+    let hosts: Vec<(String, Url)> = hosts
+        .into_iter()
+        .map(|host| {
+            let url = parse_host(&host).unwrap_or_else(|err| {
+                logger.error(&format!("Invalid status server {:?}: {}", host, err));
+                std::process::exit(1);
+            });
+            (host, url)
+        })
+        .collect();
+
+    let client = reqwest::Client::builder()
+        .timeout(REFRESH / 2)
+        .build()
+        .expect("client");
+
+    let clear = atty::is(Stream::Stdout);
+    let mut interval = time::interval(REFRESH);
+    loop {
+        interval.tick().await;
+
+        let handles: Vec<_> = hosts
+            .iter()
+            .map(|(_, url)| {
+                let client = client.clone();
+                let url = url.clone();
+                tokio::spawn(async move { poll(&client, &url).await })
+            })
+            .collect();
+        let mut results = Vec::with_capacity(handles.len());
+        for handle in handles {
+            results.push(handle.await.expect("join poll"));
+        }
+
+        if clear {
+            print!("\x1b[2J\x1b[H");
+            io::stdout().flush().expect("flush stdout");
+        }
+        logger.headline(&format!("Dashboard ({} instances)", hosts.len()));
+
+        let (mut cores, mut working, mut nps, mut reachable) = (0, 0, 0, 0);
+        for ((host, _), res) in hosts.iter().zip(results) {
+            match res {
+                Ok(instance) => {
+                    reachable += 1;
+                    cores += instance.status.cores;
+                    working += instance.status.working;
+                    nps += instance.nps;
+                    let line = format!(
+                        "{}: fishnet {}, {}/{} cores working, {} nps, {} pending batches",
+                        host,
+                        instance.status.version,
+                        instance.status.working,
+                        instance.status.cores,
+                        instance.nps.separate_with_dots(),
+                        instance.status.pending_batches
+                    );
+                    let problems = instance.problems();
+                    if problems.is_empty() {
+                        logger.info(&line);
+                    } else {
+                        logger.warn(&format!("{} ({})", line, problems.join(", ")));
+                    }
+                }
+                Err(err) => logger.error(&format!("{}: unreachable ({})", host, err)),
+            }
+        }
+
+        logger.headline("Total");
+        logger.info(&format!(
+            "{}/{} instances reachable, {}/{} cores working, {} nps",
+            reachable,
+            hosts.len(),
+            working,
+            cores,
+            nps.separate_with_dots()
+        ));
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use ring::digest;
+
+use crate::{
+    api::{AcquireResponseBody, AnalysisPart},
+    assets::EvalFlavor,
+};
+
+/// Completed batches to remember.
+const CAPACITY: usize = 64;
+
+/// Results older than this are not resubmitted. The server would have
+/// recorded or given up on them by now.
+const MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Identifies the content of an analysis batch, independent of its id, so
+/// that a batch issued again by the server is recognized. The positions to
+/// skip are part of the content, independent of their order: The stored
+/// analysis has no results for them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BatchKey([u8; 32]);
+
+impl BatchKey {
+    pub fn new(body: &AcquireResponseBody) -> Option<BatchKey> {
+        if !body.work.is_analysis() {
+            return None;
+        }
+        let mut value = serde_json::to_value(body).ok()?;
+        value.get_mut("work")?.as_object_mut()?.remove("id");
+        let mut skip_positions = body.skip_positions.clone();
+        skip_positions.sort_unstable();
+        skip_positions.dedup();
+        value
+            .as_object_mut()?
+            .insert("skipPositions".to_owned(), skip_positions.into());
+        let digest = digest::digest(&digest::SHA256, value.to_string().as_bytes());
+        Some(BatchKey(digest.as_ref().try_into().ok()?))
+    }
+}
+
+/// Analysis of a completed batch, as submitted.
+#[derive(Debug, Clone)]
+pub struct RecentBatch {
+    key: BatchKey,
+    pub flavor: EvalFlavor,
+    pub node_scale: Option<f64>,
+    pub analysis: Vec<Option<AnalysisPart>>,
+    completed_at: Instant,
+}
+
+/// Results of recently completed analysis batches. If a submission got
+/// lost, for example in a timeout that the server did not record, the
+/// server issues the same batch again, and the results are resubmitted
+/// instead of analysing the whole game again.
+#[derive(Debug, Default)]
+pub struct RecentBatches {
+    batches: VecDeque<RecentBatch>,
+}
+
+impl RecentBatches {
+    pub fn insert(
+        &mut self,
+        key: BatchKey,
+        flavor: EvalFlavor,
+        node_scale: Option<f64>,
+        analysis: Vec<Option<AnalysisPart>>,
+    ) {
+        self.batches.retain(|batch| batch.key != key);
+        if self.batches.len() >= CAPACITY {
+            self.batches.pop_front();
+        }
+        self.batches.push_back(RecentBatch {
+            key,
+            flavor,
+            node_scale,
+            analysis,
+            completed_at: Instant::now(),
+        });
+    }
+
+    pub fn get(&self, key: BatchKey) -> Option<&RecentBatch> {
+        self.batches
+            .iter()
+            .find(|batch| batch.key == key && batch.completed_at.elapsed() < MAX_AGE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(id: &str, skip_positions: &str) -> AcquireResponseBody {
+        serde_json::from_str(&format!(
+            r#"{{
+                "work": {{ "type": "analysis", "id": "{}", "nodes": {{ "sf15": 1500000, "classical": 4050000 }}, "timeout": 7000 }},
+                "position": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "moves": "e2e4 c7c5 g1f3",
+                "skipPositions": {}
+            }}"#,
+            id, skip_positions
+        ))
+        .expect("valid body")
+    }
+
+    #[test]
+    fn test_batch_key() {
+        let key = BatchKey::new(&body("a", "[1, 2]")).unwrap();
+        assert_eq!(BatchKey::new(&body("b", "[1, 2]")), Some(key));
+        assert_eq!(BatchKey::new(&body("b", "[2, 1, 2]")), Some(key));
+        assert_ne!(BatchKey::new(&body("a", "[1]")), Some(key));
+        assert_ne!(BatchKey::new(&body("a", "[]")), Some(key));
+    }
+}
@@ -0,0 +1,220 @@
+use std::{ffi::OsString, path::Path, time::Duration};
+
+use serde::Serialize;
+use tokio::time;
+
+use crate::{
+    api::LichessVariant,
+    assets::{Assets, ByEngineFlavor, Cpu, EngineFlavor},
+    configure::{Cores, EndpointConf, Opt},
+    disk,
+    logger::Logger,
+    stats, stockfish,
+};
+
+async fn identify(assets: &Assets, flavor: EngineFlavor) -> Option<String> {
+    let exe = assets.stockfish.get(flavor).as_ref()?;
+    time::timeout(
+        Duration::from_secs(10),
+        stockfish::identify(exe, &assets.nnue),
+    )
+    .await
+    .ok()
+    .and_then(Result::ok)
+}
+
+/// Capabilities and configuration of this client, for fleet audits.
+#[derive(Debug, Serialize)]
+pub struct Description {
+    version: &'static str,
+    os: &'static str,
+    arch: &'static str,
+    cpu: String,
+    cores: usize,
+    max_memory: Option<String>,
+    cpu_quota: Option<String>,
+    endpoints: Vec<EndpointDescription>,
+    engines: Vec<EngineDescription>,
+    subsystems: Subsystems,
+    disk: DiskUsage,
+}
+
+/// Bytes on disk, if known. Assets and stats are bounded. The others are
+/// only present if configured, and kept within their configured sizes:
+/// logs by rotation, the outbox and the result cache by number of entries,
+/// and traces and invalid batches by removing the oldest files.
+#[derive(Debug, Serialize)]
+struct DiskUsage {
+    assets: Option<u64>,
+    stats: Option<u64>,
+    outbox: Option<u64>,
+    result_cache: Option<u64>,
+    logs: Option<u64>,
+    traces: Option<u64>,
+    invalid_batches: Option<u64>,
+}
+
+impl DiskUsage {
+    fn gather(opt: &Opt, assets: &Assets) -> DiskUsage {
+        let usage = |path: Option<&Path>| path.and_then(|path| disk::usage(path).ok());
+        DiskUsage {
+            assets: assets.disk_usage().ok(),
+            stats: stats::disk_usage().ok(),
+            outbox: usage(Some(&opt.outbox_dir())),
+            result_cache: usage(opt.lookup.result_cache_file.as_deref()),
+            logs: opt.log_file.log_file.as_ref().and_then(|path| {
+                // Rotated files are fishnet.log.1.xz to fishnet.log.<keep>.xz.
+                (1..=opt.log_file.keep()).try_fold(disk::usage(path).ok()?, |total, n| {
+                    let mut rotated = OsString::from(path.as_os_str());
+                    rotated.push(format!(".{}.xz", n));
+                    Some(total + disk::usage(Path::new(&rotated)).ok()?)
+                })
+            }),
+            traces: usage(opt.trace_engine_dir.as_deref()),
+            invalid_batches: usage(opt.invalid_batch_dir.as_deref()),
+        }
+    }
+
+    fn summary(&self) -> String {
+        [
+            ("engines", self.assets),
+            ("stats", self.stats),
+            ("outbox", self.outbox),
+            ("result cache", self.result_cache),
+            ("logs", self.logs),
+            ("traces", self.traces),
+            ("invalid batches", self.invalid_batches),
+        ]
+        .iter()
+        .filter_map(|(what, bytes)| bytes.map(|bytes| format!("{} KiB for {}", bytes >> 10, what)))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct EndpointDescription {
+    name: String,
+    url: String,
+    weight: u32,
+    key: bool,
+    variants: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct EngineDescription {
+    flavor: &'static str,
+    enabled: bool,
+    id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Subsystems {
+    auto_update: String,
+    force_multivariant: bool,
+    user_backlog: Option<String>,
+    system_backlog: Option<String>,
+    io_class: Option<String>,
+}
+
+impl Description {
+    /// Collects the description. Starts each enabled engine once to query
+    /// its name, all at the same time.
+    pub async fn gather(opt: &Opt, cpu: Cpu, assets: &Assets) -> Description {
+        let engines = opt.enabled_engines();
+        let (official, multi_variant) = tokio::join!(
+            identify(assets, EngineFlavor::Official),
+            identify(assets, EngineFlavor::MultiVariant)
+        );
+        let engine_descriptions = [
+            (EngineFlavor::Official, official),
+            (EngineFlavor::MultiVariant, multi_variant),
+        ]
+        .into_iter()
+        .map(|(flavor, id)| EngineDescription {
+            flavor: flavor.name(),
+            enabled: *engines.get(flavor),
+            id,
+        })
+        .collect();
+
+        Description {
+            version: env!("CARGO_PKG_VERSION"),
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            cpu: format!("{:?}", cpu),
+            cores: usize::from(opt.cores.unwrap_or(Cores::Auto)),
+            max_memory: opt.limits.max_memory.map(|m| m.to_string()),
+            cpu_quota: opt.limits.cpu_quota.map(|q| q.to_string()),
+            endpoints: opt
+                .endpoint_confs
+                .iter()
+                .map(|conf| EndpointDescription {
+                    name: conf.name.clone(),
+                    url: conf.endpoint.to_string(),
+                    weight: conf.weight,
+                    key: conf.key.is_some(),
+                    variants: supported_variants(conf, engines)
+                        .into_iter()
+                        .map(|v| v.to_string())
+                        .collect(),
+                })
+                .collect(),
+            engines: engine_descriptions,
+            subsystems: Subsystems {
+                auto_update: opt.auto_update.to_string(),
+                force_multivariant: opt.force_multivariant,
+                user_backlog: opt.backlog.user.map(|b| b.to_string()),
+                system_backlog: opt.backlog.system.map(|b| b.to_string()),
+                io_class: opt.limits.io_class.map(|c| c.to_string()),
+            },
+            disk: DiskUsage::gather(opt, assets),
+        }
+    }
+
+    /// Logs the description as a human readable banner.
+    pub fn log(&self, logger: &Logger) {
+        logger.info(&format!(
+            "fishnet/{} on {}-{}, {} cores",
+            self.version, self.os, self.arch, self.cores
+        ));
+        for engine in &self.engines {
+            logger.info(&format!(
+                "Engine ({}): {}",
+                engine.flavor,
+                match (engine.enabled, &engine.id) {
+                    (false, _) => "disabled",
+                    (true, Some(id)) => id,
+                    (true, None) => "not responding",
+                }
+            ));
+        }
+        logger.info(&format!("Disk usage: {}", self.disk.summary()));
+        for endpoint in &self.endpoints {
+            logger.info(&format!(
+                "Endpoint {} ({}, weight {}): {}",
+                endpoint.name,
+                endpoint.url,
+                endpoint.weight,
+                endpoint.variants.join(", ")
+            ));
+        }
+    }
+}
+
+/// Variants that the endpoint allows and that one of the enabled engines
+/// can handle.
+fn supported_variants(conf: &EndpointConf, engines: ByEngineFlavor<bool>) -> Vec<LichessVariant> {
+    LichessVariant::ALL
+        .iter()
+        .copied()
+        .filter(|&v| conf.variants.allows(v))
+        .filter(|&v| match v {
+            // Analysis with either engine, moves with Fairy-Stockfish.
+            LichessVariant::Standard | LichessVariant::Chess960 | LichessVariant::FromPosition => {
+                engines.official || engines.multi_variant
+            }
+            _ => engines.multi_variant,
+        })
+        .collect()
+}
@@ -0,0 +1,92 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Bytes used by a file, or by all files in a directory and its
+/// subdirectories. Zero if it does not exist.
+pub fn usage(path: &Path) -> io::Result<u64> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        total += usage(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+/// Removes the least recently modified files with the given extension from
+/// the directory, until `size` more bytes fit within `max_size`. Returns
+/// the number of removed files.
+pub fn make_room(dir: &Path, extension: &str, max_size: u64, size: u64) -> io::Result<usize> {
+    if size > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "file larger than the size limit",
+        ));
+    }
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry
+            .path()
+            .extension()
+            .map_or(false, |ext| ext == extension)
+        {
+            let metadata = entry.metadata()?;
+            files.push((
+                metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                metadata.len(),
+                entry.path(),
+            ));
+        }
+    }
+    files.sort_unstable();
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    let mut removed = 0;
+    for (_, len, path) in files {
+        if total + size <= max_size {
+            break;
+        }
+        fs::remove_file(path)?;
+        total -= len;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn test_make_room() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(dir.path().join(format!("{}.log", name)), [0; 100]).unwrap();
+            // Distinct modification times.
+            thread::sleep(Duration::from_millis(20));
+        }
+        fs::write(dir.path().join("other.json"), [0; 100]).unwrap();
+        assert_eq!(usage(dir.path()).unwrap(), 400);
+
+        assert_eq!(make_room(dir.path(), "log", 300, 0).unwrap(), 0);
+        assert_eq!(make_room(dir.path(), "log", 300, 50).unwrap(), 1);
+        assert!(!dir.path().join("a.log").exists());
+        assert!(dir.path().join("b.log").exists());
+        assert_eq!(make_room(dir.path(), "log", 150, 0).unwrap(), 1);
+        assert!(!dir.path().join("b.log").exists());
+        assert!(dir.path().join("c.log").exists());
+        assert!(dir.path().join("other.json").exists());
+        assert!(make_room(dir.path(), "log", 150, 200).is_err());
+    }
+}
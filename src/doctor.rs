@@ -0,0 +1,324 @@
+use std::{
+    cmp::Reverse,
+    env, fmt,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use reqwest::StatusCode;
+use tokio::time;
+
+use crate::{
+    affinity,
+    api::{self, MAX_CLOCK_SKEW},
+    assets::{Assets, ByEngineFlavor, Cpu, EngineFlavor},
+    configure::{KeyError, Opt},
+    logger::Logger,
+    stockfish,
+};
+
+/// Roughly what is needed to unpack the engines and the NNUE file.
+const MIN_FREE_DISK: u64 = 256 * 1024 * 1024;
+
+const SLOW_LATENCY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Severity {
+    Hint,
+    Warning,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Hint => "hint",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        })
+    }
+}
+
+struct Problem {
+    severity: Severity,
+    summary: String,
+    fix: String,
+}
+
+#[derive(Default)]
+struct Diagnosis {
+    problems: Vec<Problem>,
+}
+
+impl Diagnosis {
+    fn report(&mut self, severity: Severity, summary: String, fix: &str) {
+        self.problems.push(Problem {
+            severity,
+            summary,
+            fix: fix.to_owned(),
+        });
+    }
+}
+
+/// Runs all diagnostics and prints the problems found, most severe first.
+/// Exits with status 1 if there is a problem that prevents fishnet from
+/// working at all.
+pub async fn doctor(opt: Opt, logger: &Logger) {
+    let mut diagnosis = Diagnosis::default();
+
+    logger.headline("Checking CPU ...");
+    let cpu = check_cpu(&opt, &mut diagnosis, logger);
+
+    logger.headline("Checking disk space ...");
+    check_disk_space(&mut diagnosis, logger);
+
+    logger.headline("Checking engines ...");
+    check_engines(cpu, opt.enabled_engines(), &mut diagnosis, logger).await;
+
+    logger.headline("Checking network and key ...");
+    check_endpoint(&opt, &mut diagnosis, logger).await;
+
+    diagnosis.problems.sort_by_key(|p| Reverse(p.severity));
+
+    logger.headline("Diagnosis");
+    if diagnosis.problems.is_empty() {
+        logger.fishnet_info("No problems found.");
+        return;
+    }
+    for (i, problem) in diagnosis.problems.iter().enumerate() {
+        println!("{}. [{}] {}", i + 1, problem.severity, problem.summary);
+        println!("   Fix: {}", problem.fix);
+    }
+    if diagnosis
+        .problems
+        .iter()
+        .any(|p| p.severity == Severity::Critical)
+    {
+        std::process::exit(1);
+    }
+}
+
+fn check_cpu(opt: &Opt, diagnosis: &mut Diagnosis, logger: &Logger) -> Cpu {
+    let cpu = Cpu::detect();
+    logger.info(&format!("CPU features: {:?}", cpu));
+    if let Some(level) = opt.force_cpu_level {
+        if !cpu.contains(level.cpu()) {
+            diagnosis.report(
+                Severity::Critical,
+                format!("CPU does not support the forced level {}", level),
+                "Remove --force-cpu-level (ForceCpuLevel), or choose a lower level.",
+            );
+        }
+    }
+    if cfg!(target_arch = "x86_64") && !cpu.contains(Cpu::SF_AVX2) {
+        diagnosis.report(
+            Severity::Hint,
+            format!("CPU lacks AVX2 ({:?}), so analysis will be slow", cpu),
+            "Contribute from a more modern machine, if you have one. Slow clients still help with the system queue.",
+        );
+    }
+    let cores = affinity::available();
+    logger.info(&format!("Logical cores: {}", cores));
+    if cores < 2 {
+        diagnosis.report(
+            Severity::Hint,
+            "Only a single logical core available".to_owned(),
+            "Run with --cores all, or contribute from a machine with more cores.",
+        );
+    }
+    opt.cpu()
+}
+
+fn check_disk_space(diagnosis: &mut Diagnosis, logger: &Logger) {
+    let dir = env::temp_dir();
+    match free_disk_space(&dir) {
+        Some(free) => {
+            logger.info(&format!(
+                "Free space in {:?}: {} MiB",
+                dir,
+                free / 1024 / 1024
+            ));
+            if free < MIN_FREE_DISK {
+                diagnosis.report(
+                    Severity::Critical,
+                    format!(
+                        "Only {} MiB free in {:?}, but engines need about {} MiB",
+                        free / 1024 / 1024,
+                        dir,
+                        MIN_FREE_DISK / 1024 / 1024
+                    ),
+                    "Free up disk space, or point TMPDIR to a directory with more space.",
+                );
+            }
+        }
+        None => logger.info(&format!("Free space in {:?}: unknown", dir)),
+    }
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // Field types differ between platforms
+fn free_disk_space(dir: &Path) -> Option<u64> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt as _};
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // Safety: Valid C string and pointer to uninitialized struct, which is
+    // initialized if the call succeeds.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } == 0 {
+        let stat = unsafe { stat.assume_init() };
+        Some((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn free_disk_space(_dir: &Path) -> Option<u64> {
+    None
+}
+
+async fn check_engines(
+    cpu: Cpu,
+    enabled: ByEngineFlavor<bool>,
+    diagnosis: &mut Diagnosis,
+    logger: &Logger,
+) {
+    let assets = match Assets::prepare(cpu, enabled) {
+        Ok(assets) => assets,
+        Err(err) => {
+            diagnosis.report(
+                Severity::Critical,
+                format!("Failed to unpack engines: {}", err),
+                "Make sure the temporary directory is writable and not mounted noexec, or point TMPDIR elsewhere.",
+            );
+            return;
+        }
+    };
+    logger.info(&format!("Engine: {}", assets.sf_name));
+    for flavor in [EngineFlavor::Official, EngineFlavor::MultiVariant] {
+        let name = match flavor {
+            EngineFlavor::Official => "Stockfish",
+            EngineFlavor::MultiVariant => "Fairy-Stockfish",
+        };
+        let exe = match assets.stockfish.get(flavor) {
+            Some(exe) => exe,
+            None => {
+                logger.info(&format!("{}: disabled", name));
+                continue;
+            }
+        };
+        match time::timeout(Duration::from_secs(10), stockfish::identify(exe, &assets.nnue))
+        .await
+        {
+            Ok(Ok(id)) => logger.info(&format!("{}: {}", name, id)),
+            Ok(Err(err)) => diagnosis.report(
+                Severity::Critical,
+                format!("{} failed to start: {}", name, err),
+                "Make sure the temporary directory is not mounted noexec, and that no antivirus software quarantines the engine.",
+            ),
+            Err(_) => diagnosis.report(
+                Severity::Critical,
+                format!("{} did not respond within 10s", name),
+                "Check if the machine is overloaded or if the engine is blocked by security software.",
+            ),
+        }
+    }
+}
+
+async fn check_endpoint(opt: &Opt, diagnosis: &mut Diagnosis, logger: &Logger) {
+    for conf in &opt.endpoint_confs {
+        logger.info(&format!("Endpoint: {} ({})", conf.endpoint, conf.name));
+        let mut api = api::spawn(
+            conf.endpoint.clone(),
+            conf.key.clone(),
+            conf.proxy.clone(),
+            logger.clone(),
+        );
+
+        match time::timeout(Duration::from_secs(30), api.probe()).await {
+            Ok(Some(probe)) => {
+                logger.info(&format!(
+                    "Response: {} in {:?}",
+                    probe.status, probe.latency
+                ));
+                if probe.status.is_server_error() || probe.status == StatusCode::TOO_MANY_REQUESTS {
+                    diagnosis.report(
+                        Severity::Warning,
+                        format!("{} responded with {}", conf.endpoint, probe.status),
+                        "The server may be under maintenance. Try again later.",
+                    );
+                }
+                if probe.latency > SLOW_LATENCY {
+                    diagnosis.report(
+                        Severity::Hint,
+                        format!(
+                            "Slow connection to {} ({:?})",
+                            conf.endpoint, probe.latency
+                        ),
+                        "Results arrive late on slow connections. Prefer a wired connection, or use --user-backlog to focus on the system queue.",
+                    );
+                }
+                if let Some(server_time) = probe.server_time {
+                    check_clock(server_time, diagnosis, logger);
+                }
+            }
+            Ok(None) | Err(_) => {
+                diagnosis.report(
+                    Severity::Critical,
+                    format!("Could not connect to {}", conf.endpoint),
+                    "Check your internet connection, DNS and proxy settings, and that outgoing HTTPS is not blocked by a firewall.",
+                );
+                continue;
+            }
+        }
+
+        if conf.key.is_none() {
+            if !conf.endpoint.is_development() {
+                diagnosis.report(
+                    Severity::Critical,
+                    format!("No key configured for {}", conf.endpoint),
+                    "Get a personal key from https://lichess.org/get-fishnet and run fishnet configure.",
+                );
+            }
+            continue;
+        }
+        match time::timeout(Duration::from_secs(30), api.check_key()).await {
+            Ok(Some(Ok(()))) => logger.info("Key: ok"),
+            Ok(Some(Err(KeyError::AccessDenied))) => diagnosis.report(
+                Severity::Critical,
+                format!("Key rejected by {}", conf.endpoint),
+                "Check for typos, or get a new key from https://lichess.org/get-fishnet.",
+            ),
+            Ok(Some(Err(err))) => diagnosis.report(
+                Severity::Critical,
+                format!("Invalid key: {}", err),
+                "Run fishnet configure to enter the key again.",
+            ),
+            Ok(None) | Err(_) => diagnosis.report(
+                Severity::Warning,
+                "Could not check key".to_owned(),
+                "Try again later.",
+            ),
+        }
+    }
+}
+
+fn check_clock(server_time: SystemTime, diagnosis: &mut Diagnosis, logger: &Logger) {
+    let now = SystemTime::now();
+    let (skew, direction) = match now.duration_since(server_time) {
+        Ok(ahead) => (ahead, "ahead of"),
+        Err(behind) => (behind.duration(), "behind"),
+    };
+    // The Date header has a resolution of one second.
+    logger.info(&format!("Clock: {}s {} server", skew.as_secs(), direction));
+    if skew > MAX_CLOCK_SKEW {
+        diagnosis.report(
+            Severity::Warning,
+            format!(
+                "System clock is {}s {} the server",
+                skew.as_secs(),
+                direction
+            ),
+            "Enable time synchronization (for example NTP). A wrong clock can break TLS certificate validation.",
+        );
+    }
+}
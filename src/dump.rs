@@ -0,0 +1,48 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::{api::AcquireResponseBody, disk, validate::ValidationError};
+
+/// Batches that failed validation, as written to the dump directory.
+#[derive(Debug, Serialize)]
+struct InvalidBatch<'a> {
+    error: String,
+    acquired: &'a AcquireResponseBody,
+}
+
+/// Keeps batches that failed validation as JSON files, to debug legality
+/// mismatches between the server and the engines. The oldest files are
+/// removed to stay within `max_size`.
+#[derive(Debug)]
+pub struct InvalidBatchDump {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl InvalidBatchDump {
+    pub fn new(dir: PathBuf, max_size: u64) -> io::Result<InvalidBatchDump> {
+        fs::create_dir_all(&dir)?;
+        Ok(InvalidBatchDump { dir, max_size })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes the batch, and returns the path of the new file.
+    pub fn write(&self, body: &AcquireResponseBody, err: &ValidationError) -> io::Result<PathBuf> {
+        let buf = serde_json::to_vec_pretty(&InvalidBatch {
+            error: err.to_string(),
+            acquired: body,
+        })
+        .expect("serialize invalid batch");
+        disk::make_room(&self.dir, "json", self.max_size, buf.len() as u64)?;
+        let path = self.dir.join(format!("{}.json", body.work.id()));
+        fs::write(&path, buf)?;
+        Ok(path)
+    }
+}
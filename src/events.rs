@@ -0,0 +1,148 @@
+use std::{
+    io,
+    io::Write as _,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{api::Score, configure::EventTarget, ipc::PositionResponse, util::NevermindExt as _};
+
+/// Something that happened, as emitted on the event stream.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    BatchAcquired {
+        batch: String,
+        kind: &'static str,
+        variant: String,
+        positions: usize,
+        /// Positions already completed, for batches recovered from the
+        /// journal.
+        completed: usize,
+    },
+    PositionDone {
+        batch: String,
+        position: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        score: Option<Score>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        best_move: Option<String>,
+        depth: u8,
+        nodes: u64,
+        time_ms: u64,
+        cached: bool,
+    },
+    BatchSubmitted {
+        batch: String,
+        kind: &'static str,
+        positions: u64,
+        nodes: u64,
+        wall_time_ms: u64,
+    },
+    EngineRestarted {
+        worker: usize,
+        engine: &'static str,
+        reason: &'static str,
+    },
+}
+
+impl Event {
+    pub fn position_done(res: &PositionResponse) -> Event {
+        Event::PositionDone {
+            batch: res.work.id().to_string(),
+            position: res.position_id.0,
+            score: res.scores.best().copied(),
+            best_move: res.best_move.as_ref().map(|uci| uci.to_string()),
+            depth: res.depth,
+            nodes: res.nodes,
+            time_ms: res.time.as_millis() as u64,
+            cached: res.cached,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    /// Unix timestamp in milliseconds.
+    at: u64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Line-delimited JSON events for `--event-stream`, for tools that wrap
+/// fishnet. Unlike the progress line, the format of events is stable: new
+/// fields and events may be added, but existing ones are kept.
+#[derive(Clone)]
+pub struct EventStream {
+    out: Arc<Mutex<Box<dyn io::Write + Send>>>,
+}
+
+impl EventStream {
+    pub fn open(target: EventTarget) -> io::Result<EventStream> {
+        let out: Box<dyn io::Write + Send> = match target {
+            EventTarget::Stdout => Box::new(io::stdout()),
+            EventTarget::Fd(fd) => Box::new(imp::open_fd(fd)?),
+        };
+        Ok(EventStream {
+            out: Arc::new(Mutex::new(out)),
+        })
+    }
+
+    /// Writes the event as a single line. Events are best effort: a reader
+    /// that went away does not stop fishnet.
+    pub fn emit(&self, event: &Event) {
+        let mut line = serde_json::to_vec(&Line {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            event,
+        })
+        .expect("serialize event");
+        line.push(b'\n');
+        let mut out = self.out.lock().expect("event stream");
+        out.write_all(&line)
+            .and_then(|_| out.flush())
+            .nevermind("event stream closed");
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::{fs::File, io, os::unix::io::FromRawFd as _};
+
+    pub fn open_fd(fd: i32) -> io::Result<File> {
+        if fd <= 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "use stdout instead of standard file descriptors",
+            ));
+        }
+        if unsafe {
+            // Safety: Only checks if the descriptor is open.
+            libc::fcntl(fd, libc::F_GETFD)
+        } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe {
+            // Safety: The descriptor is open, and was handed to fishnet for
+            // exclusive use as the event stream.
+            File::from_raw_fd(fd)
+        })
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::{fs::File, io};
+
+    pub fn open_fd(_fd: i32) -> io::Result<File> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "file descriptors are only supported on unix",
+        ))
+    }
+}
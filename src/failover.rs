@@ -0,0 +1,299 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc, oneshot},
+    time,
+    time::MissedTickBehavior,
+};
+
+use crate::{
+    api::{self, Acquired, ApiMessage, ApiStub, BatchId, Cancellations},
+    logger::Logger,
+    util::NevermindExt as _,
+};
+
+/// Consecutive failed requests after which the next endpoint is used.
+const FAILOVER_THRESHOLD: u32 = 3;
+
+/// Interval for checking if a preferred endpoint is healthy again, while
+/// falling back to another one.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An endpoint, with separate actors for move submissions and all other
+/// requests.
+pub struct Upstream {
+    pub name: String,
+    pub api: ApiStub,
+    pub submit_api: ApiStub,
+}
+
+/// Serves requests from the first healthy endpoint, in order of preference.
+/// Sticks to a fallback until the health check of a preferred endpoint
+/// succeeds. Results are submitted to the endpoint that the batch was
+/// acquired from.
+pub fn channel(upstreams: Vec<Upstream>, logger: Logger) -> (ApiStub, FailoverActor) {
+    assert!(!upstreams.is_empty(), "at least one upstream");
+    let (stub, rx) = api::detached_channel(upstreams[0].api.endpoint().clone());
+    let (outcome_tx, outcome_rx) = mpsc::unbounded_channel();
+    let cancellations = stub.cancellations();
+    (
+        stub,
+        FailoverActor {
+            rx,
+            cancellations,
+            upstreams,
+            active: 0,
+            failures: 0,
+            batches: HashMap::new(),
+            outcome_tx,
+            outcome_rx,
+            logger,
+        },
+    )
+}
+
+/// Result of a request that was forwarded to an endpoint.
+enum Outcome {
+    Acquired {
+        upstream: usize,
+        acquired: Option<Acquired>,
+        callback: oneshot::Sender<Acquired>,
+    },
+    Healthy {
+        upstream: usize,
+        healthy: bool,
+    },
+}
+
+pub struct FailoverActor {
+    rx: mpsc::UnboundedReceiver<ApiMessage>,
+    /// Cancellations from all endpoints, for subscribers of the failover
+    /// stub.
+    cancellations: Cancellations,
+    upstreams: Vec<Upstream>,
+    active: usize,
+    /// Consecutive failed requests to the active endpoint.
+    failures: u32,
+    /// Endpoint that each batch in progress was acquired from.
+    batches: HashMap<BatchId, usize>,
+    outcome_tx: mpsc::UnboundedSender<Outcome>,
+    outcome_rx: mpsc::UnboundedReceiver<Outcome>,
+    logger: Logger,
+}
+
+impl FailoverActor {
+    pub async fn run(mut self) {
+        self.logger.debug("Failover actor started");
+        for upstream in &self.upstreams {
+            let mut upstream_cancellations = upstream.api.cancellations().subscribe();
+            let cancellations = self.cancellations.clone();
+            tokio::spawn(async move {
+                loop {
+                    match upstream_cancellations.recv().await {
+                        Ok(batch_id) => cancellations.cancel(batch_id),
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            });
+        }
+        let mut health_check = time::interval(HEALTH_CHECK_INTERVAL);
+        health_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                msg = self.rx.recv() => match msg {
+                    Some(msg) => self.handle_message(msg),
+                    None => break,
+                },
+                Some(outcome) = self.outcome_rx.recv() => self.handle_outcome(outcome),
+                _ = health_check.tick() => self.check_health(),
+            }
+        }
+        self.logger.debug("Failover actor exited");
+    }
+
+    /// Endpoint for requests about the given batch.
+    fn upstream_of(&mut self, batch_id: BatchId) -> usize {
+        self.batches.remove(&batch_id).unwrap_or(self.active)
+    }
+
+    fn handle_message(&mut self, msg: ApiMessage) {
+        match msg {
+            ApiMessage::CheckKey { callback } => {
+                let mut api = self.upstreams[self.active].api.clone();
+                tokio::spawn(async move {
+                    if let Some(res) = api.check_key().await {
+                        callback.send(res).nevermind("callback dropped");
+                    }
+                });
+            }
+            ApiMessage::Status { callback } => {
+                let mut api = self.upstreams[self.active].api.clone();
+                tokio::spawn(async move {
+                    if let Some(status) = api.status().await {
+                        callback.send(status).nevermind("callback dropped");
+                    }
+                });
+            }
+            ApiMessage::Probe { callback } => {
+                let mut api = self.upstreams[self.active].api.clone();
+                tokio::spawn(async move {
+                    if let Some(probe) = api.probe().await {
+                        callback.send(probe).nevermind("callback dropped");
+                    }
+                });
+            }
+            ApiMessage::Standing { callback } => {
+                let mut api = self.upstreams[self.active].api.clone();
+                tokio::spawn(async move {
+                    if let Some(standing) = api.standing().await {
+                        callback.send(standing).nevermind("callback dropped");
+                    }
+                });
+            }
+            ApiMessage::Abort { batch_id } => {
+                let upstream = self.upstream_of(batch_id);
+                self.upstreams[upstream].api.abort(batch_id);
+            }
+            ApiMessage::Acquire { query, callback } => {
+                let upstream = self.active;
+                let mut api = self.upstreams[upstream].api.clone();
+                let outcome_tx = self.outcome_tx.clone();
+                tokio::spawn(async move {
+                    let acquired = api.acquire(query).await;
+                    outcome_tx
+                        .send(Outcome::Acquired {
+                            upstream,
+                            acquired,
+                            callback,
+                        })
+                        .nevermind("failover actor exited");
+                });
+            }
+            ApiMessage::SubmitAnalysis {
+                batch_id,
+                flavor,
+                node_scale,
+                cpu_time,
+                analysis,
+            } => {
+                let upstream = self.upstream_of(batch_id);
+                self.upstreams[upstream]
+                    .submit_api
+                    .submit_analysis(batch_id, flavor, node_scale, cpu_time, analysis);
+            }
+            ApiMessage::SubmitMove {
+                batch_id,
+                best_move,
+                callback,
+            } => {
+                let upstream = self.upstream_of(batch_id);
+                let mut submit_api = self.upstreams[upstream].submit_api.clone();
+                let outcome_tx = self.outcome_tx.clone();
+                tokio::spawn(async move {
+                    let acquired = submit_api
+                        .submit_move_and_acquire(batch_id, best_move)
+                        .await;
+                    outcome_tx
+                        .send(Outcome::Acquired {
+                            upstream,
+                            acquired,
+                            callback,
+                        })
+                        .nevermind("failover actor exited");
+                });
+            }
+            ApiMessage::SubmitPlayout {
+                batch_id,
+                flavor,
+                playout,
+            } => {
+                let upstream = self.upstream_of(batch_id);
+                self.upstreams[upstream]
+                    .submit_api
+                    .submit_playout(batch_id, flavor, playout);
+            }
+            // Only sent by an API actor to itself.
+            ApiMessage::RetryAnalysis { .. } => (),
+        }
+    }
+
+    fn handle_outcome(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Acquired {
+                upstream,
+                acquired,
+                callback,
+            } => {
+                if upstream == self.active {
+                    self.record(acquired.is_some());
+                }
+                match acquired {
+                    Some(Acquired::Accepted(body)) => {
+                        let batch_id = body.work.id();
+                        self.batches.insert(batch_id, upstream);
+                        if callback.send(Acquired::Accepted(body)).is_err() {
+                            self.logger.error(&format!(
+                                "Acquired batch {} from {}, but callback dropped. Aborting.",
+                                batch_id, self.upstreams[upstream].name
+                            ));
+                            self.upstreams[upstream].api.abort(batch_id);
+                            self.batches.remove(&batch_id);
+                        }
+                    }
+                    Some(acquired) => callback.send(acquired).nevermind("callback dropped"),
+                    // The endpoint actor failed the request, and already
+                    // logged why. Dropping the callback reports the error.
+                    None => (),
+                }
+            }
+            Outcome::Healthy { upstream, healthy } => {
+                if healthy && upstream < self.active {
+                    self.logger.info(&format!(
+                        "Endpoint {} is healthy again. Switching back from {}.",
+                        self.upstreams[upstream].name, self.upstreams[self.active].name
+                    ));
+                    self.active = upstream;
+                    self.failures = 0;
+                }
+            }
+        }
+    }
+
+    /// Counts failed requests to the active endpoint, and falls back to the
+    /// next one after too many in a row.
+    fn record(&mut self, success: bool) {
+        if success {
+            self.failures = 0;
+            return;
+        }
+        self.failures += 1;
+        if self.failures >= FAILOVER_THRESHOLD && self.active + 1 < self.upstreams.len() {
+            self.logger.warn(&format!(
+                "Endpoint {} failed {} times in a row. Falling back to {}.",
+                self.upstreams[self.active].name,
+                self.failures,
+                self.upstreams[self.active + 1].name
+            ));
+            self.active += 1;
+            self.failures = 0;
+        }
+    }
+
+    /// Probes the endpoints that are preferred over the active one.
+    fn check_health(&mut self) {
+        for upstream in 0..self.active {
+            let mut api = self.upstreams[upstream].api.clone();
+            let outcome_tx = self.outcome_tx.clone();
+            tokio::spawn(async move {
+                let healthy = api
+                    .probe()
+                    .await
+                    .map_or(false, |probe| probe.status.is_success());
+                outcome_tx
+                    .send(Outcome::Healthy { upstream, healthy })
+                    .nevermind("failover actor exited");
+            });
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    collections::BTreeMap, net::SocketAddr, num::NonZeroU8, pin::Pin, sync::Arc, time::Duration,
+};
+
+use shakmaty::{
+    fen::Fen,
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    CastlingMode, Position as _,
+};
+use tokio::{
+    signal,
+    sync::{mpsc, oneshot},
+};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+
+use crate::{
+    api::{LichessVariant, NodeLimit, Score, SkillLevel, Work},
+    assets::{Assets, EngineFlavor},
+    configure::{Cores, Opt},
+    ipc::{Position, PositionId, PositionResponse},
+    logger::Logger,
+    pool::EnginePool,
+    util::NevermindExt as _,
+};
+
+mod proto {
+    tonic::include_proto!("fishnet");
+}
+
+/// Node limit for analysis, unless requested otherwise.
+const DEFAULT_NODES: u64 = 1_500_000;
+
+/// A requested position, validated and ready for the engines.
+struct Prepared {
+    pos: VariantPosition,
+    castling_mode: CastlingMode,
+    position: Position,
+}
+
+#[derive(Clone)]
+struct AnalysisService {
+    assets: Arc<Assets>,
+    pool: EnginePool,
+    concurrency: usize,
+}
+
+impl AnalysisService {
+    fn prepare(&self, request: &proto::Position, work: Work) -> Result<Prepared, String> {
+        let variant: LichessVariant = if request.variant.is_empty() {
+            LichessVariant::default()
+        } else {
+            request.variant.parse().map_err(|err| format!("{}", err))?
+        };
+
+        let root_pos = if request.fen.is_empty() {
+            VariantPosition::new(Variant::from(variant))
+        } else {
+            let fen: Fen = request
+                .fen
+                .parse()
+                .map_err(|err| format!("invalid fen: {}", err))?;
+            VariantPosition::from_setup(Variant::from(variant), &fen, CastlingMode::Chess960)
+                .or_else(|err| err.ignore_impossible_material())
+                .map_err(|err| format!("illegal position: {}", err))?
+        };
+
+        let mut pos = root_pos.clone();
+        let mut moves = Vec::with_capacity(request.moves.len());
+        for uci in &request.moves {
+            let uci: Uci = uci
+                .parse()
+                .map_err(|err| format!("invalid move {}: {}", uci, err))?;
+            let m = uci
+                .to_move(&pos)
+                .map_err(|err| format!("illegal move {}: {}", uci, err))?;
+            moves.push(m.to_uci(CastlingMode::Chess960));
+            pos.play_unchecked(&m);
+        }
+        if pos.legal_moves().is_empty() {
+            return Err("no legal moves".to_owned());
+        }
+
+        // Select engine like the queue does for work from the fishnet API.
+        let flavor = match root_pos {
+            VariantPosition::Chess(_)
+                if work.is_analysis() && self.assets.stockfish.official.is_some() =>
+            {
+                EngineFlavor::Official
+            }
+            _ if self.assets.stockfish.multi_variant.is_some() => EngineFlavor::MultiVariant,
+            _ => return Err("no engine enabled for this variant".to_owned()),
+        };
+
+        Ok(Prepared {
+            pos,
+            castling_mode: if variant == LichessVariant::Chess960 {
+                CastlingMode::Chess960
+            } else {
+                CastlingMode::Standard
+            },
+            position: Position {
+                work,
+                position_id: PositionId(0),
+                flavor,
+                url: None,
+                variant,
+                root_fen: Fen::from_setup(&root_pos),
+                moves,
+            },
+        })
+    }
+}
+
+fn analysis_work(request: &proto::Position) -> Work {
+    Work::Analysis {
+        id: "grpc".parse().expect("batch id"),
+        nodes: NodeLimit::fixed(if request.nodes == 0 {
+            DEFAULT_NODES
+        } else {
+            request.nodes
+        }),
+        depth: u8::try_from(request.depth).ok().filter(|&depth| depth > 0),
+        multipv: u8::try_from(request.multipv)
+            .ok()
+            .and_then(NonZeroU8::new)
+            .filter(|multipv| multipv.get() > 1),
+        timeout: Duration::default(),
+        movetime: None,
+        wdl: false,
+        annotations: false,
+        tier: None,
+        options: BTreeMap::new(),
+    }
+}
+
+/// Converts moves from the engine to the castling notation of the variant.
+fn uci_line(mut pos: VariantPosition, castling_mode: CastlingMode, line: &[Uci]) -> Vec<String> {
+    let mut converted = Vec::with_capacity(line.len());
+    for uci in line {
+        let m = match uci.to_move(&pos) {
+            Ok(m) => m,
+            Err(_) => break,
+        };
+        converted.push(m.to_uci(castling_mode).to_string());
+        pos.play_unchecked(&m);
+    }
+    converted
+}
+
+fn evaluation(id: String, prepared: Prepared, res: PositionResponse) -> proto::Evaluation {
+    let lines = if res.work.matrix_wanted() {
+        (1..=res.scores.lines())
+            .filter_map(|i| NonZeroU8::new(u8::try_from(i).ok()?))
+            .map(|multipv| proto::Line {
+                score: res.scores.line(multipv).map(|score| match *score {
+                    Score::Cp(cp) => proto::line::Score::Cp(cp),
+                    Score::Mate(mate) => proto::line::Score::Mate(mate),
+                }),
+                pv: uci_line(
+                    prepared.pos.clone(),
+                    prepared.castling_mode,
+                    res.pvs.line(multipv).map_or(&[], Vec::as_slice),
+                ),
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    proto::Evaluation {
+        id,
+        score: res.scores.best().map(|score| match *score {
+            Score::Cp(cp) => proto::evaluation::Score::Cp(cp),
+            Score::Mate(mate) => proto::evaluation::Score::Mate(mate),
+        }),
+        pv: uci_line(
+            prepared.pos,
+            prepared.castling_mode,
+            res.pvs.best().map_or(&[], Vec::as_slice),
+        ),
+        depth: u32::from(res.depth),
+        nodes: res.nodes,
+        error: String::new(),
+        lines,
+    }
+}
+
+fn failed(id: String, error: String) -> proto::Evaluation {
+    proto::Evaluation {
+        id,
+        score: None,
+        pv: Vec::new(),
+        depth: 0,
+        nodes: 0,
+        error,
+        lines: Vec::new(),
+    }
+}
+
+type Pending = Result<(Prepared, oneshot::Receiver<PositionResponse>), String>;
+
+#[tonic::async_trait]
+impl proto::analysis_server::Analysis for AnalysisService {
+    type AnalyseStream =
+        Pin<Box<dyn Stream<Item = Result<proto::Evaluation, Status>> + Send + 'static>>;
+
+    async fn analyse(
+        &self,
+        request: Request<Streaming<proto::Position>>,
+    ) -> Result<Response<Self::AnalyseStream>, Status> {
+        let mut requests = request.into_inner();
+        let (pending_tx, mut pending_rx) = mpsc::channel::<(String, Pending)>(self.concurrency);
+        let (tx, rx) = mpsc::channel(self.concurrency);
+
+        // Submit positions to the pool as they arrive ...
+        let service = self.clone();
+        tokio::spawn(async move {
+            while let Ok(Some(request)) = requests.message().await {
+                let pending = match service.prepare(&request, analysis_work(&request)) {
+                    Ok(prepared) => {
+                        let position = prepared.position.clone();
+                        Ok((prepared, service.pool.submit(position).await))
+                    }
+                    Err(err) => Err(err),
+                };
+                if pending_tx.send((request.id, pending)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // ... and return the results in the same order.
+        tokio::spawn(async move {
+            while let Some((id, pending)) = pending_rx.recv().await {
+                let evaluation = match pending {
+                    Ok((prepared, response)) => match response.await {
+                        Ok(res) => evaluation(id, prepared, res),
+                        Err(_) => failed(id, "engine error".to_owned()),
+                    },
+                    Err(err) => failed(id, err),
+                };
+                if tx.send(Ok(evaluation)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn best_move(
+        &self,
+        request: Request<proto::Position>,
+    ) -> Result<Response<proto::Move>, Status> {
+        let request = request.into_inner();
+        let level = if request.level == 0 {
+            SkillLevel::Eight
+        } else {
+            SkillLevel::from_level(request.level)
+                .ok_or_else(|| Status::invalid_argument("level must be between 1 and 8"))?
+        };
+        let prepared = self
+            .prepare(
+                &request,
+                Work::Move {
+                    id: "grpc".parse().expect("batch id"),
+                    level,
+                    clock: None,
+                    options: BTreeMap::new(),
+                },
+            )
+            .map_err(Status::invalid_argument)?;
+        let res = self
+            .pool
+            .go(prepared.position.clone())
+            .await
+            .map_err(|_| Status::internal("engine error"))?;
+        let best_move = res
+            .best_move
+            .ok_or_else(|| Status::internal("engine did not play a move"))?;
+        Ok(Response::new(proto::Move {
+            id: request.id,
+            uci: uci_line(prepared.pos, prepared.castling_mode, &[best_move])
+                .pop()
+                .ok_or_else(|| Status::internal("engine played an illegal move"))?,
+        }))
+    }
+}
+
+/// Serves analysis to other local services, using the same engines and
+/// settings as `run`, until interrupted.
+pub async fn serve(opt: &Opt, bind: SocketAddr, logger: &Logger) {
+    let concurrency = usize::from(opt.cores.unwrap_or(Cores::Auto));
+    let cpu = opt.cpu();
+    let assets =
+        Arc::new(Assets::prepare(cpu, opt.enabled_engines()).expect("prepared bundled stockfish"));
+    let (pool, pool_join_handle) = EnginePool::spawn(assets.clone(), concurrency, logger.clone());
+
+    logger.headline(&format!(
+        "Serving analysis via gRPC on {} with {} engine processes",
+        bind, concurrency
+    ));
+    let res = Server::builder()
+        .add_service(proto::analysis_server::AnalysisServer::new(
+            AnalysisService {
+                assets,
+                pool,
+                concurrency,
+            },
+        ))
+        .serve_with_shutdown(bind, async {
+            signal::ctrl_c().await.nevermind("ctrl+c handler");
+        })
+        .await;
+    if let Err(err) = res {
+        logger.error(&format!("gRPC server failed: {}", err));
+        std::process::exit(1);
+    }
+
+    logger.headline("Stopping engines ...");
+    pool_join_handle.await.expect("join");
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use crate::{
+    api::{ApiStub, Work},
+    logger::Logger,
+    metrics::METRICS,
+    queue::{CompletedBatch, Skip},
+};
+
+/// Handles one type of work, as named by [`Work::kind()`]. The queue
+/// validates acquired batches, distributes their positions to the engines,
+/// and collects the results. Handlers decide which positions are analysed,
+/// and what happens with the results.
+pub trait WorkHandler: Send + Sync {
+    /// Selects the positions of a game with `moves` moves that are sent to
+    /// the engines, each as the number of moves played from the root
+    /// position. Results are collected in the same order.
+    fn plies(&self, work: &Work, moves: usize, skip_positions: &[usize]) -> Vec<Skip<usize>>;
+
+    /// Delivers a batch once all its positions are complete. `summary`
+    /// describes the batch for the log.
+    fn submit(&self, batch: CompletedBatch, summary: &str, submission: &mut Submission<'_>);
+}
+
+/// Where handlers deliver completed batches.
+pub struct Submission<'a> {
+    pub api: &'a mut ApiStub,
+    pub logger: &'a Logger,
+    deferred: &'a mut VecDeque<CompletedBatch>,
+}
+
+impl Submission<'_> {
+    pub(crate) fn new<'a>(
+        api: &'a mut ApiStub,
+        logger: &'a Logger,
+        deferred: &'a mut VecDeque<CompletedBatch>,
+    ) -> Submission<'a> {
+        Submission {
+            api,
+            logger,
+            deferred,
+        }
+    }
+
+    /// Submits the batch from the queue actor, together with the request
+    /// for the next batch.
+    pub fn defer(&mut self, batch: CompletedBatch) {
+        self.deferred.push_back(batch);
+    }
+}
+
+/// Analyses every position of the game, except those the server wants to
+/// skip.
+pub struct AnalysisHandler;
+
+impl WorkHandler for AnalysisHandler {
+    fn plies(&self, _work: &Work, moves: usize, skip_positions: &[usize]) -> Vec<Skip<usize>> {
+        let mut plies: Vec<_> = (0..=moves).map(Skip::Present).collect();
+        for &skip in skip_positions {
+            if let Some(ply) = plies.get_mut(skip) {
+                *ply = Skip::Skip;
+            }
+        }
+        plies
+    }
+
+    fn submit(&self, batch: CompletedBatch, summary: &str, submission: &mut Submission<'_>) {
+        submission.logger.info(summary);
+        if let Some(metrics) = batch.metrics() {
+            submission.logger.debug(&format!(
+                "Batch {}: white {}; black {}",
+                batch.work().id(),
+                metrics.white,
+                metrics.black
+            ));
+        }
+        METRICS.batches_submitted.inc();
+        submission.api.submit_analysis(
+            batch.work().id(),
+            batch.flavor().eval_flavor(),
+            batch.node_scale(),
+            batch.total_cpu_time(),
+            batch.into_analysis(),
+        );
+    }
+}
+
+/// Plays a move in the final position of the game.
+pub struct MoveHandler;
+
+impl WorkHandler for MoveHandler {
+    fn plies(&self, _work: &Work, moves: usize, _skip_positions: &[usize]) -> Vec<Skip<usize>> {
+        vec![Skip::Present(moves)]
+    }
+
+    fn submit(&self, batch: CompletedBatch, summary: &str, submission: &mut Submission<'_>) {
+        submission.logger.debug(summary);
+        submission.defer(batch);
+    }
+}
+
+/// Plays out the final position of the game.
+pub struct PlayoutHandler;
+
+impl WorkHandler for PlayoutHandler {
+    fn plies(&self, _work: &Work, moves: usize, _skip_positions: &[usize]) -> Vec<Skip<usize>> {
+        vec![Skip::Present(moves)]
+    }
+
+    fn submit(&self, batch: CompletedBatch, summary: &str, submission: &mut Submission<'_>) {
+        submission.logger.info(summary);
+        METRICS.batches_submitted.inc();
+        submission.api.submit_playout(
+            batch.work().id(),
+            batch.flavor().eval_flavor(),
+            batch.into_playout(),
+        );
+    }
+}
+
+/// Handlers by type of work.
+pub struct WorkHandlers {
+    handlers: HashMap<&'static str, Box<dyn WorkHandler>>,
+}
+
+impl Default for WorkHandlers {
+    fn default() -> WorkHandlers {
+        let mut handlers = WorkHandlers {
+            handlers: HashMap::new(),
+        };
+        handlers.register("analysis", Box::new(AnalysisHandler));
+        handlers.register("move", Box::new(MoveHandler));
+        handlers.register("playout", Box::new(PlayoutHandler));
+        handlers
+    }
+}
+
+impl fmt::Debug for WorkHandlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.handlers.keys()).finish()
+    }
+}
+
+impl WorkHandlers {
+    /// Adds or replaces the handler for a type of work.
+    pub fn register(&mut self, kind: &'static str, handler: Box<dyn WorkHandler>) {
+        self.handlers.insert(kind, handler);
+    }
+
+    pub fn get(&self, work: &Work) -> &dyn WorkHandler {
+        self.handlers
+            .get(work.kind())
+            .map(AsRef::as_ref)
+            .unwrap_or_else(|| panic!("no handler for {} work", work.kind()))
+    }
+}
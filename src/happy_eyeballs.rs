@@ -0,0 +1,138 @@
+use std::{
+    error::Error,
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use tokio::{net::TcpStream, sync::mpsc, task::JoinHandle, time};
+
+/// Delay before starting the next connection attempt while the previous
+/// ones are still pending, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Address family to try first when a host has both IPv4 and IPv6
+/// addresses. The other family is still tried if connecting is slow or
+/// fails.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IpPreference {
+    /// Order of the system resolver, usually IPv6 first if available.
+    System,
+    Ipv4,
+    Ipv6,
+}
+
+impl Default for IpPreference {
+    fn default() -> IpPreference {
+        IpPreference::System
+    }
+}
+
+impl IpPreference {
+    fn prefers_ipv6(self, first: &SocketAddr) -> bool {
+        match self {
+            IpPreference::System => first.is_ipv6(),
+            IpPreference::Ipv4 => false,
+            IpPreference::Ipv6 => true,
+        }
+    }
+}
+
+/// Interleaves the address families, starting with the preferred one, so
+/// that a broken family delays connecting only briefly (RFC 8305, section
+/// 4).
+pub fn sort_addrs(addrs: Vec<SocketAddr>, preference: IpPreference) -> Vec<SocketAddr> {
+    let ipv6_first = match addrs.first() {
+        Some(first) => preference.prefers_ipv6(first),
+        None => return addrs,
+    };
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == ipv6_first);
+    let mut sorted = Vec::with_capacity(preferred.len() + other.len());
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+    sorted
+}
+
+/// Connects to the first address of the host that answers. Attempts are
+/// started one after another, but without waiting more than
+/// [`CONNECTION_ATTEMPT_DELAY`] for the previous ones.
+pub async fn connect(host: &str, port: u16, preference: IpPreference) -> io::Result<TcpStream> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = sort_addrs(
+        time::timeout(
+            Duration::from_secs(10),
+            tokio::net::lookup_host((host, port)),
+        )
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??
+        .collect(),
+        preference,
+    );
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut attempts: Vec<JoinHandle<()>> = Vec::with_capacity(addrs.len());
+    let mut pending = 0;
+    let mut next_attempt_at = Instant::now();
+    let mut last_err = None;
+    let stream = loop {
+        if attempts.len() < addrs.len() && Instant::now() >= next_attempt_at {
+            let addr = addrs[attempts.len()];
+            let tx = tx.clone();
+            attempts.push(tokio::spawn(async move {
+                let _ = tx.send(TcpStream::connect(addr).await);
+            }));
+            pending += 1;
+            next_attempt_at = Instant::now() + CONNECTION_ATTEMPT_DELAY;
+        }
+        if pending == 0 {
+            break Err(
+                last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses"))
+            );
+        }
+        tokio::select! {
+            Some(res) = rx.recv() => {
+                pending -= 1;
+                match res {
+                    Ok(stream) => break Ok(stream),
+                    Err(err) => {
+                        // Start the next attempt right away.
+                        last_err = Some(err);
+                        next_attempt_at = Instant::now();
+                    }
+                }
+            }
+            _ = time::sleep_until(next_attempt_at.into()), if attempts.len() < addrs.len() => (),
+        }
+    };
+    for attempt in attempts {
+        attempt.abort();
+    }
+    stream
+}
+
+/// Resolver for the HTTP client, which races the address families itself,
+/// starting with the family of the first address.
+pub struct Resolver {
+    pub preference: IpPreference,
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let preference = self.preference;
+        let name = name.as_str().to_owned();
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            let addrs: Addrs = Box::new(sort_addrs(addrs, preference).into_iter());
+            Ok::<_, Box<dyn Error + Send + Sync>>(addrs)
+        })
+    }
+}
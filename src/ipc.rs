@@ -1,14 +1,141 @@
-use std::{num::NonZeroU8, time::Duration};
+use std::{
+    cmp::{max, min},
+    fmt,
+    num::NonZeroU8,
+    time::Duration,
+};
 
-use shakmaty::{fen::Fen, uci::Uci};
+use serde::{Deserialize, Serialize};
+use shakmaty::{fen::Fen, uci::Uci, Color, Setup as _};
 use tokio::sync::oneshot;
 use url::Url;
 
 use crate::{
-    api::{AnalysisPart, BatchId, LichessVariant, Score, Work},
+    api::{AnalysisPart, BatchId, LichessVariant, PlayoutPly, Score, Tier, Wdl, Work},
     assets::EngineFlavor,
 };
 
+/// Local bounds for the cost of each position, whatever the server
+/// requests. Move requests are already cheap, so only analysis is bounded.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct WorkLimits {
+    pub max_nodes: Option<u64>,
+    pub max_depth: Option<u8>,
+    pub movetime: Option<Duration>,
+    /// Ignore time limits, so that results only depend on the position and
+    /// node limit.
+    pub nodes_only: bool,
+}
+
+impl WorkLimits {
+    pub fn apply(&self, work: &mut Work) {
+        if let Work::Analysis {
+            nodes,
+            depth,
+            movetime,
+            ..
+        } = work
+        {
+            if let Some(max_nodes) = self.max_nodes {
+                *nodes = nodes.clamped(max_nodes);
+            }
+            if let Some(max_depth) = self.max_depth {
+                *depth = Some(depth.map_or(max_depth, |d| min(d, max_depth)));
+            }
+            if let Some(max_movetime) = self.movetime {
+                *movetime = Some(movetime.map_or(max_movetime, |t| min(t, max_movetime)));
+            }
+            if self.nodes_only {
+                *movetime = None;
+            }
+        }
+    }
+}
+
+/// Lines searched in the deep tier, at least.
+const DEEP_MULTIPV: u8 = 3;
+
+/// Maps the tier selected by the server to a node budget and number of
+/// lines: a quarter of the requested nodes and only the best line for fast
+/// evaluations, four times the nodes and a few lines for deep analysis.
+/// Work without a tier is left as requested.
+pub fn apply_tier(work: &mut Work) {
+    if let Work::Analysis {
+        nodes,
+        multipv,
+        tier: Some(tier),
+        ..
+    } = work
+    {
+        match *tier {
+            Tier::Fast => {
+                *nodes = nodes.scaled(0.25);
+                *multipv = None;
+            }
+            Tier::Standard => (),
+            Tier::Deep => {
+                *nodes = nodes.scaled(4.0);
+                *multipv = Some(max(
+                    multipv.unwrap_or_else(|| NonZeroU8::new(1).unwrap()),
+                    NonZeroU8::new(DEEP_MULTIPV).unwrap(),
+                ));
+            }
+        }
+    }
+}
+
+/// Search depths with a best line score to compare for a stable
+/// evaluation.
+const STABLE_DEPTHS: usize = 4;
+
+/// Depth before which the evaluation is not considered stable, however
+/// little it changes.
+const STABLE_MIN_DEPTH: u8 = 16;
+
+/// Conditions to stop the search of a position before its node limit,
+/// once further search is unlikely to change the result. Only for
+/// analysis. The reported nodes are those actually searched.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct EarlyStop {
+    /// Stop once a forced mate in at most this many moves is found.
+    pub mate: Option<u32>,
+    /// Stop once the evaluation of the last few depths stayed within this
+    /// many centipawns.
+    pub cp_window: Option<u32>,
+}
+
+impl EarlyStop {
+    pub fn is_enabled(&self) -> bool {
+        self.mate.is_some() || self.cp_window.is_some()
+    }
+
+    /// Decides if the search can stop, given the exact score of the best
+    /// line at each completed depth so far.
+    pub fn reached(&self, scores: &[(u8, Score)]) -> bool {
+        let mate = match (self.mate, scores.last()) {
+            (Some(max), Some(&(_, Score::Mate(n)))) => n.unsigned_abs() <= u64::from(max),
+            _ => false,
+        };
+        let stable = self.cp_window.map_or(false, |window| {
+            let recent = &scores[scores.len().saturating_sub(STABLE_DEPTHS)..];
+            if recent.len() < STABLE_DEPTHS || recent[0].0 < STABLE_MIN_DEPTH {
+                return false;
+            }
+            let mut cps = Vec::with_capacity(STABLE_DEPTHS);
+            for &(_, score) in recent {
+                match score {
+                    Score::Cp(cp) => cps.push(cp),
+                    Score::Mate(_) => return false,
+                }
+            }
+            let (min, max) = (cps.iter().min(), cps.iter().max());
+            min.zip(max)
+                .map_or(false, |(min, max)| max.abs_diff(*min) <= u64::from(window))
+        });
+        mate || stable
+    }
+}
+
 /// Uniquely identifies a position within a batch.
 #[derive(Debug, Copy, Clone)]
 pub struct PositionId(pub usize);
@@ -25,6 +152,33 @@ pub struct Position {
     pub moves: Vec<Uci>,
 }
 
+impl Position {
+    /// Plies played since the start of the game.
+    pub fn ply(&self) -> u32 {
+        (self.root_fen.fullmoves().get() - 1) * 2
+            + u32::from(self.root_fen.turn() == Color::Black)
+            + self.moves.len() as u32
+    }
+
+    /// One ply before or after the other position of the same game, in the
+    /// same batch. Batch ids are only unique per endpoint, so the game is
+    /// compared as well, to never carry the hash table of one game over to
+    /// another.
+    pub fn is_adjacent_ply(&self, other: &Position) -> bool {
+        let (shorter, longer) = if self.moves.len() < other.moves.len() {
+            (&self.moves, &other.moves)
+        } else {
+            (&other.moves, &self.moves)
+        };
+        self.work.id() == other.work.id()
+            && self.url == other.url
+            && self.variant == other.variant
+            && self.root_fen == other.root_fen
+            && shorter.len() + 1 == longer.len()
+            && longer.starts_with(shorter)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PositionResponse {
     pub work: Work,
@@ -38,17 +192,35 @@ pub struct PositionResponse {
     pub nodes: u64,
     pub time: Duration,
     pub nps: Option<u32>,
+    /// Win/draw/loss statistics of the best line, if requested.
+    pub wdl: Option<Wdl>,
+    /// Permille of the hash table in use.
+    pub hashfull: Option<u16>,
+    /// Wall-clock time from setting up the position to the best move.
+    pub latency: Duration,
+    /// Answered from the opening book or cloud evaluations, without
+    /// searching.
+    pub cached: bool,
+    /// Moves and evaluations, if the position was played out.
+    pub playout: Vec<PlayoutPly>,
+    /// CPU time of the engine process while searching, if it can be
+    /// measured on this platform.
+    pub cpu_time: Option<Duration>,
 }
 
 impl PositionResponse {
     pub fn to_best(&self) -> AnalysisPart {
         AnalysisPart::Best {
             pv: self.pvs.best().cloned().unwrap_or_default(),
+            san: None,
             score: self.scores.best().copied().expect("got score"),
             depth: self.depth,
             nodes: self.nodes,
             time: self.time.as_millis() as u64,
             nps: self.nps,
+            wdl: self.wdl,
+            judgement: None,
+            cached: self.cached,
         }
     }
 
@@ -60,11 +232,15 @@ impl PositionResponse {
             nodes: self.nodes,
             time: self.time.as_millis() as u64,
             nps: self.nps,
+            wdl: self.wdl,
+            judgement: None,
+            puzzle_candidate: false,
+            cached: self.cached,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Matrix<T> {
     matrix: Vec<Vec<Option<T>>>,
 }
@@ -90,17 +266,107 @@ impl<T> Matrix<T> {
             .get(0)
             .and_then(|row| row.last().and_then(|v| v.as_ref()))
     }
+
+    /// Removes the deepest entry of the given line, for example to reuse its
+    /// allocation. Deeper entries replace it as the best.
+    pub fn take_line(&mut self, multipv: NonZeroU8) -> Option<T> {
+        self.matrix
+            .get_mut(usize::from(multipv.get() - 1))
+            .and_then(|row| row.iter_mut().rev().find_map(Option::take))
+    }
+
+    /// Deepest entry of the given line, like [`Matrix::best()`] for the
+    /// first line.
+    pub fn line(&self, multipv: NonZeroU8) -> Option<&T> {
+        self.matrix
+            .get(usize::from(multipv.get() - 1))
+            .and_then(|row| row.last().and_then(|v| v.as_ref()))
+    }
+
+    /// Number of lines, as requested with multipv.
+    pub fn lines(&self) -> usize {
+        self.matrix.len()
+    }
+
+    pub fn map<U, F: FnMut(T) -> U>(self, mut f: F) -> Matrix<U> {
+        Matrix {
+            matrix: self
+                .matrix
+                .into_iter()
+                .map(|row| row.into_iter().map(|v| v.map(&mut f)).collect())
+                .collect(),
+        }
+    }
 }
 
-#[derive(Debug)]
+/// Engine processes that exit while analysing a position are restarted,
+/// and the position is replayed, up to this many attempts in total.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+/// Why a position could not be analysed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FailureReason {
+    /// The engine process exited or stopped accepting commands, for example
+    /// when it ran out of memory or hit an assertion.
+    EngineExited,
+    /// The engine did not finish within the time budget.
+    Timeout,
+    /// The position was handed to a cluster follower that disconnected.
+    Disconnected,
+}
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            FailureReason::EngineExited => "engine exited",
+            FailureReason::Timeout => "engine timed out",
+            FailureReason::Disconnected => "follower disconnected",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct PositionFailed {
     pub batch_id: BatchId,
+    pub position_id: PositionId,
+    pub reason: FailureReason,
+    /// Including the first attempt.
+    pub attempts: u32,
+}
+
+impl PositionFailed {
+    pub fn new(position: &Position, reason: FailureReason) -> PositionFailed {
+        PositionFailed {
+            batch_id: position.work.id(),
+            position_id: position.position_id,
+            reason,
+            attempts: 1,
+        }
+    }
+
+    /// The position can be replayed on a restarted engine process.
+    pub fn can_retry(&self) -> bool {
+        self.reason == FailureReason::EngineExited && self.attempts < MAX_ATTEMPTS
+    }
+}
+
+impl fmt::Display for PositionFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "position {} of batch {}: {} (attempts: {})",
+            self.position_id.0, self.batch_id, self.reason, self.attempts
+        )
+    }
 }
 
 #[derive(Debug)]
 pub struct Pull {
     pub response: Option<Result<PositionResponse, PositionFailed>>,
-    pub callback: oneshot::Sender<Position>,
+    /// Absent if the worker does not want more work for now.
+    pub callback: Option<oneshot::Sender<Position>>,
+    /// The worker is reserved for user requests.
+    pub reserved: bool,
 }
 
 impl Pull {
@@ -108,7 +374,7 @@ impl Pull {
         self,
     ) -> (
         Option<Result<PositionResponse, PositionFailed>>,
-        oneshot::Sender<Position>,
+        Option<oneshot::Sender<Position>>,
     ) {
         (self.response, self.callback)
     }
@@ -0,0 +1,329 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io,
+    io::Write as _,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::{
+    api::{AcquireResponseBody, BatchId},
+    checkpoint::BatchCheckpoint,
+    cluster::WireResponse,
+    configure::Endpoint,
+};
+
+/// Rewrite the journal with only the open batches once it grows beyond
+/// this size.
+const COMPACT_SIZE: u64 = 4 * 1024 * 1024;
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+enum Entry {
+    Acquired {
+        endpoint: String,
+        /// Batch as acquired, before scaling node limits.
+        body: AcquireResponseBody,
+        node_scale: Option<f64>,
+        /// Seconds since the Unix epoch.
+        at: u64,
+    },
+    Completed {
+        #[serde_as(as = "DisplayFromStr")]
+        batch: BatchId,
+        position: usize,
+        response: WireResponse,
+    },
+    Closed {
+        #[serde_as(as = "DisplayFromStr")]
+        batch: BatchId,
+    },
+}
+
+/// Append-only record of analysis in progress: acquired batches, each
+/// completed position, and when the batch was submitted or given up. After
+/// a crash, batches that were never closed are recovered from it.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    endpoint: String,
+    /// Lines of the batches that are still open, to compact the journal.
+    open: HashMap<BatchId, Vec<u8>>,
+}
+
+impl Journal {
+    pub fn open(path: &Path, endpoint: &Endpoint) -> io::Result<Journal> {
+        Ok(Journal {
+            path: path.to_owned(),
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            endpoint: endpoint.url.to_string(),
+            open: HashMap::new(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the batches that were left open by a previous run, and
+    /// starts over with an empty journal. Recovered batches are journaled
+    /// again when they are resumed.
+    pub fn recover(&mut self) -> io::Result<Vec<BatchCheckpoint>> {
+        let data = fs::read(&self.path)?;
+        let mut batches: Vec<BatchCheckpoint> = Vec::new();
+        // A crash while writing may have left a partial last line.
+        for entry in data
+            .split(|&b| b == b'\n')
+            .filter_map(|line| serde_json::from_slice(line).ok())
+        {
+            match entry {
+                Entry::Acquired {
+                    endpoint,
+                    body,
+                    node_scale,
+                    at,
+                } => {
+                    let id = body.work.id();
+                    batches.retain(|batch| batch.body.work.id() != id);
+                    batches.push(BatchCheckpoint {
+                        endpoint,
+                        completed: Vec::new(),
+                        body,
+                        node_scale,
+                        saved_at: at,
+                    });
+                }
+                Entry::Completed {
+                    batch,
+                    position,
+                    response,
+                } => {
+                    if let Some(open) = batches.iter_mut().find(|b| b.body.work.id() == batch) {
+                        if open.completed.len() <= position {
+                            open.completed.resize_with(position + 1, || None);
+                        }
+                        open.completed[position] = Some(response);
+                    }
+                }
+                Entry::Closed { batch } => batches.retain(|b| b.body.work.id() != batch),
+            }
+        }
+        self.open.clear();
+        self.file.set_len(0)?;
+        Ok(batches)
+    }
+
+    pub fn acquired(
+        &mut self,
+        body: &AcquireResponseBody,
+        node_scale: Option<f64>,
+    ) -> io::Result<()> {
+        let entry = Entry::Acquired {
+            endpoint: self.endpoint.clone(),
+            body: body.clone(),
+            node_scale,
+            at: unix_now(),
+        };
+        self.append(body.work.id(), &entry)
+    }
+
+    pub fn completed(
+        &mut self,
+        batch: BatchId,
+        position: usize,
+        response: WireResponse,
+    ) -> io::Result<()> {
+        if !self.open.contains_key(&batch) {
+            return Ok(());
+        }
+        let entry = Entry::Completed {
+            batch,
+            position,
+            response,
+        };
+        self.append(batch, &entry)
+    }
+
+    /// The batch was submitted or aborted, or will be resumed from a
+    /// checkpoint.
+    pub fn closed(&mut self, batch: BatchId) -> io::Result<()> {
+        if self.open.remove(&batch).is_none() {
+            return Ok(());
+        }
+        if self.open.is_empty() {
+            return self.file.set_len(0);
+        }
+        self.write(&line(&Entry::Closed { batch }))?;
+        if self.file.metadata()?.len() > COMPACT_SIZE {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, batch: BatchId, entry: &Entry) -> io::Result<()> {
+        let line = line(entry);
+        self.open.entry(batch).or_default().extend_from_slice(&line);
+        self.write(&line)
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        // Single write, so that a crash leaves at most one partial line.
+        self.file.write_all(line)?;
+        self.file.flush()
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        // Replace the file at once, so that a crash while writing does not
+        // lose the open batches.
+        let tmp = self.path.with_extension("tmp");
+        fs::write(
+            &tmp,
+            self.open.values().flatten().copied().collect::<Vec<u8>>(),
+        )?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+fn line(entry: &Entry) -> Vec<u8> {
+    let mut line = serde_json::to_vec(entry).expect("serialize journal entry");
+    line.push(b'\n');
+    line
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(id: &str) -> AcquireResponseBody {
+        serde_json::from_value(serde_json::json!({
+            "work": {
+                "type": "analysis",
+                "id": id,
+                "nodes": { "classical": 1000, "sf15": 1000 },
+                "timeout": 7000,
+            },
+            "position": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "moves": "e2e4 e7e5",
+        }))
+        .unwrap()
+    }
+
+    fn response() -> WireResponse {
+        serde_json::from_value(serde_json::json!({
+            "scores": { "matrix": [[{ "cp": 20 }]] },
+            "pvs": { "matrix": [[["g1f3"]]] },
+            "best_move": "g1f3",
+            "depth": 20,
+            "nodes": 1000,
+            "time": 100,
+            "nps": null,
+            "hashfull": null,
+            "latency": 5,
+        }))
+        .unwrap()
+    }
+
+    fn open(path: &Path) -> Journal {
+        Journal::open(path, &Endpoint::default()).unwrap()
+    }
+
+    #[test]
+    fn test_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let (a, b, c) = (body("a"), body("b"), body("c"));
+
+        let mut journal = open(&path);
+        journal.acquired(&a, None).unwrap();
+        journal.acquired(&b, Some(0.5)).unwrap();
+        journal.acquired(&c, None).unwrap();
+        journal.completed(a.work.id(), 2, response()).unwrap();
+        journal.closed(b.work.id()).unwrap();
+        // Not journaled for batches that are not open.
+        journal.completed(b.work.id(), 0, response()).unwrap();
+        drop(journal);
+
+        let mut journal = open(&path);
+        let batches = journal.recover().unwrap();
+        assert_eq!(
+            batches
+                .iter()
+                .map(|batch| batch.body.work.id())
+                .collect::<Vec<_>>(),
+            [a.work.id(), c.work.id()]
+        );
+        assert_eq!(batches[0].endpoint, Endpoint::default().url.to_string());
+        assert_eq!(batches[0].completed.len(), 3);
+        assert!(batches[0].completed[0].is_none());
+        assert!(batches[0].completed[2].is_some());
+        assert!(batches[1].completed.is_empty());
+
+        // Starts over after recovery.
+        assert!(journal.recover().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_partial_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let a = body("a");
+
+        let mut journal = open(&path);
+        journal.acquired(&a, None).unwrap();
+        drop(journal);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"event\":\"closed\",\"bat").unwrap();
+
+        let batches = open(&path).recover().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].body.work.id(), a.work.id());
+    }
+
+    #[test]
+    fn test_truncate_when_all_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let a = body("a");
+
+        let mut journal = open(&path);
+        journal.acquired(&a, None).unwrap();
+        journal.completed(a.work.id(), 0, response()).unwrap();
+        journal.closed(a.work.id()).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let (a, b) = (body("a"), body("b"));
+
+        let mut journal = open(&path);
+        journal.acquired(&a, None).unwrap();
+        journal.acquired(&b, None).unwrap();
+        journal.closed(b.work.id()).unwrap();
+        journal.compact().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), journal.open[&a.work.id()]);
+
+        // Appends after compacting.
+        journal.completed(a.work.id(), 0, response()).unwrap();
+        drop(journal);
+        let batches = open(&path).recover().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].completed[0].is_some());
+    }
+}
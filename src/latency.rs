@@ -0,0 +1,341 @@
+use std::{collections::BTreeMap, fmt::Write as _, time::Duration};
+
+use serde::Serialize;
+
+use crate::{
+    api::{LichessVariant, Work},
+    assets::EngineFlavor,
+};
+
+/// Values below this are recorded exactly. Larger values are recorded with
+/// this many buckets per power of two, for a relative error of at most
+/// 1/16.
+const SUB_BUCKETS: u64 = 16;
+
+/// Log-linear histogram of milliseconds, in the style of HdrHistogram.
+/// Buckets are allocated as needed, so that memory is bounded by the
+/// largest recorded value.
+#[derive(Debug, Default, Clone)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    total: u64,
+    sum_millis: u64,
+}
+
+impl LatencyHistogram {
+    fn index(millis: u64) -> usize {
+        if millis < SUB_BUCKETS {
+            millis as usize
+        } else {
+            let shift = 63 - millis.leading_zeros() - SUB_BUCKETS.trailing_zeros();
+            let top = millis >> shift;
+            (SUB_BUCKETS * u64::from(shift + 1) + top - SUB_BUCKETS) as usize
+        }
+    }
+
+    /// Largest value that is recorded in the bucket.
+    fn highest_equivalent(index: usize) -> u64 {
+        let index = index as u64;
+        if index < SUB_BUCKETS {
+            index
+        } else {
+            let shift = index / SUB_BUCKETS - 1;
+            let top = index % SUB_BUCKETS + SUB_BUCKETS;
+            ((top + 1) << shift) - 1
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let millis = u64::try_from(latency.as_millis()).unwrap_or(u64::MAX);
+        let index = LatencyHistogram::index(millis);
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.total += 1;
+        self.sum_millis = self.sum_millis.saturating_add(millis);
+    }
+
+    /// Upper bound of the given quantile in milliseconds, or 0 if nothing
+    /// was recorded.
+    pub fn quantile(&self, q: f64) -> u64 {
+        let rank = ((q * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return LatencyHistogram::highest_equivalent(index);
+            }
+        }
+        0
+    }
+}
+
+/// Coarse classes of node targets, so that latencies of comparable work are
+/// grouped together.
+fn node_class(work: &Work, flavor: EngineFlavor) -> &'static str {
+    match work {
+        Work::Move { .. } => "move",
+        Work::Playout { .. } => "playout",
+        Work::Analysis { nodes, .. } => match nodes.get(flavor.eval_flavor()) {
+            n if n < 1_000_000 => "<1M",
+            n if n < 2_000_000 => "1M-2M",
+            n if n < 5_000_000 => "2M-5M",
+            _ => ">=5M",
+        },
+    }
+}
+
+/// Stages of the pipeline, from acquiring a batch to submitting the
+/// result. Stages of batches are recorded once per batch, stages of
+/// positions once per position.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    /// Request to acquire the batch.
+    Acquire,
+    /// Parsing and replaying the game.
+    Validate,
+    /// Position waiting in the queue for an engine worker.
+    QueueWait,
+    /// Search time, as reported by the engine.
+    Search,
+    /// Everything else between handing the position to the engine and
+    /// receiving the parsed result: setting up the position, reading and
+    /// parsing the output.
+    Parse,
+    /// Request to submit the result. Only recorded for moves, since
+    /// analysis is submitted in the background.
+    Submit,
+    /// From receiving the batch to completing the submission. Only
+    /// recorded for moves.
+    Total,
+}
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Acquire => "acquire",
+            Stage::Validate => "validate",
+            Stage::QueueWait => "queue-wait",
+            Stage::Search => "search",
+            Stage::Parse => "parse",
+            Stage::Submit => "submit",
+            Stage::Total => "total",
+        }
+    }
+}
+
+/// Wall-clock latency of positions in this session, broken down by node
+/// target class and variant, and by pipeline stage and kind of work.
+#[derive(Debug, Default, Clone)]
+pub struct Latencies {
+    histograms: BTreeMap<(&'static str, String), LatencyHistogram>,
+    stages: BTreeMap<(&'static str, Stage), LatencyHistogram>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencySummary {
+    pub nodes: &'static str,
+    pub variant: String,
+    pub positions: u64,
+    /// Milliseconds.
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+    sum_millis: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StageSummary {
+    pub work: &'static str,
+    pub stage: Stage,
+    pub count: u64,
+    /// Milliseconds.
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+    sum_millis: u64,
+}
+
+impl Latencies {
+    pub fn record(
+        &mut self,
+        work: &Work,
+        flavor: EngineFlavor,
+        variant: LichessVariant,
+        latency: Duration,
+    ) {
+        self.histograms
+            .entry((node_class(work, flavor), variant.to_string()))
+            .or_default()
+            .record(latency);
+    }
+
+    pub fn record_stage(&mut self, work: &Work, stage: Stage, latency: Duration) {
+        self.stages
+            .entry((work.kind(), stage))
+            .or_default()
+            .record(latency);
+    }
+
+    /// Upper bound of the 99th percentile of the stage in milliseconds, if
+    /// anything was recorded.
+    pub fn stage_p99(&self, work: &Work, stage: Stage) -> Option<u64> {
+        self.stages
+            .get(&(work.kind(), stage))
+            .map(|histogram| histogram.quantile(0.99))
+    }
+
+    pub fn summaries(&self) -> Vec<LatencySummary> {
+        self.histograms
+            .iter()
+            .map(|((nodes, variant), histogram)| LatencySummary {
+                nodes,
+                variant: variant.clone(),
+                positions: histogram.total,
+                p50: histogram.quantile(0.5),
+                p95: histogram.quantile(0.95),
+                p99: histogram.quantile(0.99),
+                sum_millis: histogram.sum_millis,
+            })
+            .collect()
+    }
+
+    pub fn stage_summaries(&self) -> Vec<StageSummary> {
+        self.stages
+            .iter()
+            .map(|(&(work, stage), histogram)| StageSummary {
+                work,
+                stage,
+                count: histogram.total,
+                p50: histogram.quantile(0.5),
+                p95: histogram.quantile(0.95),
+                p99: histogram.quantile(0.99),
+                sum_millis: histogram.sum_millis,
+            })
+            .collect()
+    }
+}
+
+/// Renders the summaries in the Prometheus text format.
+pub fn render(summaries: &[LatencySummary]) -> String {
+    let name = "fishnet_position_latency_seconds";
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# HELP {} Wall-clock time to analyse a position, by node target and variant.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} summary", name);
+    for summary in summaries {
+        let labels = format!(
+            "nodes=\"{}\",variant=\"{}\"",
+            summary.nodes, summary.variant
+        );
+        for (quantile, millis) in [
+            ("0.5", summary.p50),
+            ("0.95", summary.p95),
+            ("0.99", summary.p99),
+        ] {
+            let _ = writeln!(
+                out,
+                "{}{{{},quantile=\"{}\"}} {}",
+                name,
+                labels,
+                quantile,
+                millis as f64 / 1000.0
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_sum{{{}}} {}",
+            name,
+            labels,
+            summary.sum_millis as f64 / 1000.0
+        );
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, summary.positions);
+    }
+    out
+}
+
+/// Renders the stage summaries in the Prometheus text format.
+pub fn render_stages(summaries: &[StageSummary]) -> String {
+    let name = "fishnet_stage_latency_seconds";
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# HELP {} Wall-clock time spent in each stage of the pipeline, by kind of work.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} summary", name);
+    for summary in summaries {
+        let labels = format!(
+            "work=\"{}\",stage=\"{}\"",
+            summary.work,
+            summary.stage.name()
+        );
+        for (quantile, millis) in [
+            ("0.5", summary.p50),
+            ("0.95", summary.p95),
+            ("0.99", summary.p99),
+        ] {
+            let _ = writeln!(
+                out,
+                "{}{{{},quantile=\"{}\"}} {}",
+                name,
+                labels,
+                quantile,
+                millis as f64 / 1000.0
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_sum{{{}}} {}",
+            name,
+            labels,
+            summary.sum_millis as f64 / 1000.0
+        );
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, summary.count);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        for millis in (0..100_000).chain([1 << 40, (1 << 40) + 12345]) {
+            let index = LatencyHistogram::index(millis);
+            let highest = LatencyHistogram::highest_equivalent(index);
+            assert!(highest >= millis, "{} in bucket up to {}", millis, highest);
+            assert!(
+                highest - millis <= millis / SUB_BUCKETS,
+                "{} in bucket up to {}",
+                millis,
+                highest
+            );
+            if millis > 0 && millis < 100_000 {
+                assert!(LatencyHistogram::index(millis - 1) <= index);
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantile() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.quantile(0.5), 0);
+        for millis in 1..=100 {
+            histogram.record(Duration::from_millis(millis));
+        }
+        assert_eq!(histogram.quantile(0.0), 1);
+        assert_eq!(histogram.quantile(0.1), 10);
+        let p50 = histogram.quantile(0.5);
+        assert!((50..=53).contains(&p50), "p50 {}", p50);
+        let p99 = histogram.quantile(0.99);
+        assert!((99..=103).contains(&p99), "p99 {}", p99);
+        assert_eq!(histogram.sum_millis, 5050);
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    cmp::{max, min},
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, watch},
+    time,
+};
+
+use crate::{affinity, logger::Logger, util::NevermindExt as _};
+
+/// Renew the lease and reconsider the share this often.
+const RENEW_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Leases of instances that crashed or were killed are ignored after this
+/// long.
+const STALE_AFTER: Duration = Duration::from_secs(45);
+
+/// What an instance writes to its file in the coordination directory.
+#[derive(Debug, Serialize, Deserialize)]
+struct LeaseFile {
+    /// Cores the instance would use on its own. Zero while draining.
+    wanted: usize,
+    /// Unix timestamp in seconds.
+    renewed_at: u64,
+}
+
+/// Shares the cores of the host between fishnet instances that use the same
+/// coordination directory, for example with different API keys. Each
+/// instance owns a file in the directory, and independently computes the
+/// same fair share from all files, so no lock is needed.
+pub struct CoreLease {
+    path: PathBuf,
+    wanted: watch::Sender<usize>,
+}
+
+impl CoreLease {
+    /// Starts renewing the lease. The receiver gets the number of cores
+    /// granted to this instance after each renewal.
+    pub fn spawn(
+        dir: PathBuf,
+        wanted: usize,
+        logger: Logger,
+    ) -> io::Result<(CoreLease, mpsc::UnboundedReceiver<usize>)> {
+        fs::create_dir_all(&dir)?;
+        let id = format!("{}-{:08x}", process::id(), rand::random::<u32>());
+        let path = dir.join(format!("{}.json", id));
+        let (wanted_tx, mut wanted_rx) = watch::channel(wanted);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let lease_path = path.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(RENEW_INTERVAL);
+            loop {
+                tokio::select! {
+                    res = wanted_rx.changed() => if res.is_err() {
+                        break;
+                    },
+                    _ = interval.tick() => (),
+                }
+                let wanted = *wanted_rx.borrow();
+                match renew(&dir, &id, &lease_path, wanted) {
+                    Ok(granted) => {
+                        if tx.send(granted).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => logger.warn(&format!("Failed to renew core lease: {}", err)),
+                }
+            }
+        });
+        Ok((
+            CoreLease {
+                path,
+                wanted: wanted_tx,
+            },
+            rx,
+        ))
+    }
+
+    /// Gives up all but one core, so that other instances can use them,
+    /// for example while draining.
+    pub fn release(&self) {
+        if *self.wanted.borrow() != 0 {
+            self.wanted.send(0).nevermind("lease task stopped");
+        }
+    }
+}
+
+impl Drop for CoreLease {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).nevermind("remove core lease");
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Writes the lease of this instance, and returns its share of the cores.
+fn renew(dir: &Path, id: &str, path: &Path, wanted: usize) -> io::Result<usize> {
+    let now = unix_now();
+
+    // Write atomically, so that other instances never read a partial file.
+    let tmp = path.with_extension("tmp");
+    fs::write(
+        &tmp,
+        serde_json::to_vec(&LeaseFile {
+            wanted,
+            renewed_at: now,
+        })
+        .expect("serialize lease"),
+    )?;
+    fs::rename(&tmp, path)?;
+
+    let mut leases = vec![(wanted, id.to_owned())];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+        if entry_path == path || entry_path.extension().map_or(true, |ext| ext != "json") {
+            continue;
+        }
+        let lease: LeaseFile = match fs::read(&entry_path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        {
+            Some(lease) => lease,
+            None => continue, // Removed in the meantime, or foreign file
+        };
+        if now.saturating_sub(lease.renewed_at) > STALE_AFTER.as_secs() {
+            fs::remove_file(&entry_path).nevermind("remove stale core lease");
+            continue;
+        }
+        let entry_id = entry_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        leases.push((lease.wanted, entry_id));
+    }
+
+    Ok(fair_share(affinity::available(), leases, id))
+}
+
+/// Max-min fair share: Instances that want less than an equal share get
+/// what they want, and the rest is split equally between the others. Every
+/// instance keeps at least one core, even if that oversubscribes the host.
+fn fair_share(budget: usize, mut leases: Vec<(usize, String)>, id: &str) -> usize {
+    leases.sort();
+    let mut remaining = budget;
+    let mut left = leases.len();
+    for (wanted, entry_id) in &leases {
+        // Round up. Rounding for the remaining instances keeps the total
+        // within the budget.
+        let share = min(*wanted, (remaining + left - 1) / left);
+        if entry_id == id {
+            return max(1, share);
+        }
+        remaining -= share;
+        left -= 1;
+    }
+    unreachable!("own lease included")
+}
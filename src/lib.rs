@@ -0,0 +1,217 @@
+//! Building blocks of the fishnet client, for tools that want to talk to
+//! the fishnet API or drive the bundled engines without shelling out to the
+//! `fishnet` binary.
+//!
+//! To run a complete client in process, like the `fishnet` binary, build a
+//! [`Config`] and call [`run()`]. Stop it with the [`ShutdownToken`].
+//!
+//! The main entry points for more control are:
+//!
+//! * [`api`]: Types of the HTTP protocol, and an actor that performs
+//!   requests (see [`api::channel`]).
+//! * [`assets`]: Selects and unpacks the bundled Stockfish builds for the
+//!   current CPU (see [`assets::Assets::prepare`]).
+//! * [`stockfish`]: Drives a single engine process. Send it positions
+//!   through the [`stockfish::StockfishStub`], and run the
+//!   [`stockfish::StockfishActor`] on a task.
+//! * [`ipc`]: Positions and results exchanged between the queue and
+//!   engine workers. Moves use UCI notation (Chess960 castling for both
+//!   engines), positions use FEN, as provided by `shakmaty`.
+//! * [`queue`]: Splits acquired batches into positions, collects results,
+//!   and submits completed batches.
+//! * [`handler`]: Decides how each type of work is split and submitted.
+//!   Implement [`handler::WorkHandler`] to support new types of work.
+//!
+//! The remaining modules support these, and are public so that embedders
+//! can configure them the same way the binary does.
+//!
+//! All of the above need the default `engine` feature. Without it, only
+//! [`protocol`], [`validate`], [`pgn`], [`san`] and [`accuracy`] are
+//! available, and
+//! the crate compiles to `wasm32`, so that web frontends can validate
+//! positions and moves exactly like the client.
+
+#![forbid(unsafe_op_in_unsafe_fn)]
+
+/// Centipawn loss, accuracy and judgements of analysed moves.
+pub mod accuracy;
+/// Detection of the machine being in use by someone else than fishnet.
+#[cfg(feature = "engine")]
+pub mod activity;
+/// Available logical cores, and pinning of engine processes to them.
+#[cfg(feature = "engine")]
+pub mod affinity;
+/// Detection of suspicious engine behavior.
+#[cfg(feature = "engine")]
+pub mod anomaly;
+/// Types and client of the fishnet HTTP protocol.
+#[cfg(feature = "engine")]
+pub mod api;
+/// Bundled engines and evaluation files.
+#[cfg(feature = "engine")]
+pub mod assets;
+/// Optional provenance log of submitted batches.
+#[cfg(feature = "engine")]
+pub mod audit;
+/// Polyglot and variant opening books.
+#[cfg(feature = "engine")]
+pub mod book;
+/// Error budgets, and degradation when they are exhausted.
+#[cfg(feature = "engine")]
+pub mod budget;
+/// Results of repeated positions, to answer them without searching.
+#[cfg(feature = "engine")]
+pub mod cache;
+/// Engine speed measured by `fishnet benchmark`.
+#[cfg(feature = "engine")]
+pub mod calibration;
+/// Pending analysis saved on shutdown, to resume after a restart.
+#[cfg(feature = "engine")]
+pub mod checkpoint;
+/// Coordinator and followers of a cluster on the local network.
+#[cfg(feature = "engine")]
+pub mod cluster;
+/// Command line options and the configuration file.
+#[cfg(feature = "engine")]
+pub mod configure;
+/// Commands for a running instance.
+#[cfg(feature = "engine")]
+pub mod control;
+/// Arbitration between the limits on the cores in use.
+#[cfg(feature = "engine")]
+pub mod cores;
+/// CPU time of engine processes.
+#[cfg(feature = "engine")]
+pub mod cputime;
+/// Results of recently completed batches, to resubmit them if the server
+/// issues the same batch again.
+#[cfg(feature = "engine")]
+pub mod dedup;
+/// Summary of the machine, engines and configuration.
+#[cfg(feature = "engine")]
+pub mod describe;
+/// Disk usage of files written by fishnet, and eviction to stay within
+/// configured sizes.
+#[cfg(feature = "engine")]
+pub mod disk;
+/// Dumps of batches that failed validation, for debugging.
+#[cfg(feature = "engine")]
+pub mod dump;
+/// Machine-readable event stream for orchestration tooling.
+#[cfg(feature = "engine")]
+pub mod events;
+/// Failover between multiple endpoints.
+#[cfg(feature = "engine")]
+pub mod failover;
+/// Handlers for each type of work.
+#[cfg(feature = "engine")]
+pub mod handler;
+/// Dual-stack connections that race IPv6 and IPv4 addresses (RFC 8305).
+#[cfg(feature = "engine")]
+pub mod happy_eyeballs;
+/// Messages between the queue and engine workers.
+#[cfg(feature = "engine")]
+pub mod ipc;
+/// Journal of analysis in progress, to recover completed work after a
+/// crash.
+#[cfg(feature = "engine")]
+pub mod journal;
+/// Latency histograms of analysed positions.
+#[cfg(feature = "engine")]
+pub mod latency;
+/// Cores shared with other instances on the same host.
+#[cfg(feature = "engine")]
+pub mod lease;
+/// Resource limits of fishnet and its engine processes.
+#[cfg(feature = "engine")]
+pub mod limits;
+/// Log file with rotation and compression of old files.
+#[cfg(feature = "engine")]
+pub mod logfile;
+/// Console output.
+#[cfg(feature = "engine")]
+pub mod logger;
+/// Opening book and cloud evaluation lookups before searching.
+#[cfg(feature = "engine")]
+pub mod lookup;
+/// Hash table sizes that adapt to available memory.
+#[cfg(feature = "engine")]
+pub mod memory;
+/// Process wide metrics in the Prometheus format.
+#[cfg(feature = "engine")]
+pub mod metrics;
+/// Readiness and watchdog notifications for systemd.
+#[cfg(feature = "engine")]
+pub mod notify;
+/// Detection of NUMA nodes, and placement of engine processes on them.
+#[cfg(feature = "engine")]
+pub mod numa;
+/// Keeps engine processes from outliving fishnet, and cleans up those left
+/// behind after a crash.
+#[cfg(feature = "engine")]
+pub mod orphans;
+/// Retries of failed analysis submissions.
+#[cfg(feature = "engine")]
+pub mod outbox;
+/// PGN output with engine annotations.
+pub mod pgn;
+/// Engine workers for positions from other sources than the fishnet API.
+#[cfg(feature = "engine")]
+pub mod pool;
+/// Detection of running on battery.
+#[cfg(feature = "engine")]
+pub mod power;
+/// Types of the fishnet HTTP protocol, without the client.
+pub mod protocol;
+/// Work queue, shared by all engine workers.
+#[cfg(feature = "engine")]
+pub mod queue;
+/// JSON lines records of analysed positions.
+#[cfg(feature = "engine")]
+pub mod record;
+/// Analysis of recorded batches, without a server.
+#[cfg(feature = "engine")]
+pub mod replay;
+/// Conversion of engine lines to SAN, and position keys.
+pub mod san;
+/// Seccomp filters for engine processes.
+#[cfg(feature = "engine")]
+pub mod sandbox;
+/// Checks that the engines answer a search in every variant.
+#[cfg(feature = "engine")]
+pub mod selftest;
+/// Embeds a fishnet client in other programs.
+#[cfg(feature = "engine")]
+pub mod session;
+/// Optional upload of analysis results to object storage.
+#[cfg(feature = "engine")]
+pub mod sink;
+/// Lifetime and session statistics.
+#[cfg(feature = "engine")]
+pub mod stats;
+/// Status server.
+#[cfg(feature = "engine")]
+pub mod status;
+/// Engine process driver.
+#[cfg(feature = "engine")]
+pub mod stockfish;
+/// CPU temperature, for throttling when hot.
+#[cfg(feature = "engine")]
+pub mod thermal;
+/// Optional export of batch traces to OpenTelemetry.
+#[cfg(feature = "engine")]
+pub mod trace;
+/// Full-terminal status view, instead of the progress line.
+#[cfg(feature = "engine")]
+pub mod tui;
+/// Small helpers.
+#[cfg(feature = "engine")]
+pub mod util;
+/// Validation of positions and moves, as done before analysis.
+pub mod validate;
+/// Cross-checks of analysis results on a second engine.
+#[cfg(feature = "engine")]
+pub mod verify;
+
+#[cfg(feature = "engine")]
+pub use session::{run, Config, SessionSummary, ShutdownToken};
@@ -0,0 +1,394 @@
+use std::{
+    cmp::max,
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::process::Command;
+
+use crate::{
+    configure::{CpuQuota, IoClass, LimitsOpt},
+    logger::Logger,
+};
+
+/// Limits that only apply to the engine processes, so that fishnet itself
+/// keeps working (and reporting) when the engines hit them.
+#[derive(Debug, Clone, Default)]
+pub struct EngineLimits {
+    /// Cgroup that engine processes join, with the memory and CPU limits.
+    cgroup: Option<PathBuf>,
+    /// Address space of each engine process in bytes, if memory can not be
+    /// limited with a cgroup.
+    address_space: Option<u64>,
+}
+
+impl EngineLimits {
+    /// Cgroup of the engine processes, if limited with a cgroup.
+    pub fn cgroup(&self) -> Option<&Path> {
+        self.cgroup.as_deref()
+    }
+
+    /// Applies the limits to the process started by the command, before
+    /// exec.
+    pub fn apply<'a>(&self, command: &'a mut Command) -> &'a mut Command {
+        if let Some(ref dir) = self.cgroup {
+            cgroup::join(command, dir);
+        }
+        if let Some(bytes) = self.address_space {
+            rlimit::limit_address_space(command, bytes);
+        }
+        command
+    }
+}
+
+/// Applies the configured resource limits. Priorities apply to the current
+/// process, so that they are inherited by all engine processes. Memory and
+/// CPU limits are returned, to be applied to the engine processes only.
+/// Also returns the number of cores that can be used, if the CPU quota can
+/// only be approximated by running fewer workers.
+pub fn apply(opt: &LimitsOpt, cores: usize, logger: &Logger) -> (usize, EngineLimits) {
+    let mut engine_limits = EngineLimits::default();
+    let mut controllers = Vec::new();
+    if opt.max_memory.is_some() {
+        controllers.push("memory");
+    }
+    if opt.cpu_quota.is_some() {
+        controllers.push("cpu");
+    }
+    let group = if controllers.is_empty() {
+        None
+    } else {
+        Some(cgroup::engine_group(&controllers))
+    };
+    let write = |file: &str, value: &str| match group {
+        Some(Ok(ref dir)) => cgroup::write(dir, file, value),
+        Some(Err(ref err)) => Err(io::Error::new(err.kind(), err.to_string())),
+        None => unreachable!("cgroup requested"),
+    };
+
+    if let Some(max_memory) = opt.max_memory {
+        let bytes = u64::from(max_memory);
+        match write("memory.max", &bytes.to_string()) {
+            Ok(path) => {
+                logger.info(&format!(
+                    "Memory: Limited engine processes to {} via {:?}",
+                    max_memory, path
+                ));
+                engine_limits.cgroup = path.parent().map(Path::to_owned);
+            }
+            Err(err) => {
+                logger.debug(&format!("Could not limit memory via cgroup: {}", err));
+                if rlimit::SUPPORTED {
+                    logger.info(&format!(
+                        "Memory: Limited to {} per engine process via rlimit",
+                        max_memory
+                    ));
+                    engine_limits.address_space = Some(bytes);
+                } else {
+                    logger.warn("Failed to limit memory: rlimits not supported on this platform");
+                }
+            }
+        }
+    }
+
+    let cores = if let Some(CpuQuota(percent)) = opt.cpu_quota {
+        let percent = percent.get();
+        match write("cpu.max", &format!("{} 100000", u64::from(percent) * 1000)) {
+            Ok(path) => {
+                logger.info(&format!(
+                    "CPU quota: {}% for engine processes via {:?}",
+                    percent, path
+                ));
+                engine_limits.cgroup = path.parent().map(Path::to_owned);
+                cores
+            }
+            Err(err) => {
+                let capped = max(1, (percent as usize + 99) / 100).min(cores);
+                logger.warn(&format!(
+                    "Could not set CPU quota via cgroup ({}). Using at most {} cores instead.",
+                    err, capped
+                ));
+                capped
+            }
+        }
+    } else {
+        cores
+    };
+
+    if let Some(io_class) = opt.io_class {
+        match ioprio::set(io_class) {
+            Ok(()) => logger.info(&format!("I/O scheduling class: {}", io_class)),
+            Err(err) => logger.warn(&format!("Failed to set I/O scheduling class: {}", err)),
+        }
+    }
+
+    if let Some(nice) = opt.nice {
+        match priority::set_nice(nice) {
+            Ok(()) => logger.info(&format!("Priority: nice {}", nice)),
+            Err(err) => logger.warn(&format!("Failed to set nice {}: {}", nice, err)),
+        }
+    }
+
+    if opt.idle_priority {
+        match priority::set_idle() {
+            Ok(()) => logger.info("Priority: idle (only using otherwise idle CPU time)"),
+            Err(err) => logger.warn(&format!("Failed to set idle priority: {}", err)),
+        }
+    }
+
+    (cores, engine_limits)
+}
+
+mod cgroup {
+    use std::fs;
+
+    use super::*;
+
+    /// Leaf cgroup for fishnet itself.
+    #[cfg(target_os = "linux")]
+    const OWN_GROUP: &str = "fishnet";
+
+    /// Cgroup for the engine processes, next to the one of fishnet.
+    #[cfg(target_os = "linux")]
+    const ENGINE_GROUP: &str = "engines";
+
+    #[cfg(target_os = "linux")]
+    fn dir() -> io::Result<PathBuf> {
+        let cgroup = fs::read_to_string("/proc/self/cgroup")?;
+        let path = cgroup
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "cgroup v2 not available"))?;
+        Ok(PathBuf::from("/sys/fs/cgroup").join(path.trim_start_matches('/')))
+    }
+
+    /// Creates a cgroup for the engine processes, so that they can be
+    /// limited without affecting fishnet or other processes. Controllers
+    /// can only be enabled for cgroups without processes of their own, so
+    /// fishnet first moves into a leaf of its cgroup. Requires that the
+    /// cgroup is delegated to fishnet (for example with Delegate=yes in a
+    /// systemd service), and not shared with other processes.
+    #[cfg(target_os = "linux")]
+    pub fn engine_group(controllers: &[&str]) -> io::Result<PathBuf> {
+        let mut dir = dir()?;
+        if dir.ends_with(OWN_GROUP) && dir.with_file_name(ENGINE_GROUP).is_dir() {
+            // Moved before restarting after an update.
+            dir.pop();
+        } else {
+            let pid = std::process::id().to_string();
+            let procs = fs::read_to_string(dir.join("cgroup.procs"))?;
+            if procs.split_whitespace().any(|other| other != pid) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "cgroup shared with other processes",
+                ));
+            }
+            let own = dir.join(OWN_GROUP);
+            fs::create_dir_all(&own)?;
+            fs::write(own.join("cgroup.procs"), pid)?;
+        }
+        fs::write(
+            dir.join("cgroup.subtree_control"),
+            controllers
+                .iter()
+                .map(|controller| format!("+{}", controller))
+                .collect::<Vec<_>>()
+                .join(" "),
+        )?;
+        let engines = dir.join(ENGINE_GROUP);
+        fs::create_dir_all(&engines)?;
+        Ok(engines)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn engine_group(_controllers: &[&str]) -> io::Result<PathBuf> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "cgroups not supported on this platform",
+        ))
+    }
+
+    pub fn write(dir: &Path, file: &str, value: &str) -> io::Result<PathBuf> {
+        let path = dir.join(file);
+        fs::write(&path, value)?;
+        Ok(path)
+    }
+
+    /// Moves the process started by the command into the cgroup before
+    /// exec, so that it is limited from the start.
+    #[cfg(target_os = "linux")]
+    pub fn join(command: &mut Command, dir: &Path) {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt as _};
+
+        let procs = match CString::new(dir.join("cgroup.procs").as_os_str().as_bytes()) {
+            Ok(procs) => procs,
+            Err(_) => return,
+        };
+        unsafe {
+            // Safety: The closure is run in a fork, and only makes system
+            // calls with a path that was prepared before. Writing 0 moves
+            // the writing process.
+            command.pre_exec(move || {
+                let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                if fd == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+                let err = io::Error::last_os_error();
+                libc::close(fd);
+                if written == -1 {
+                    Err(err)
+                } else {
+                    Ok(())
+                }
+            });
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn join(_command: &mut Command, _dir: &Path) {}
+}
+
+mod rlimit {
+    use super::*;
+
+    pub const SUPPORTED: bool = cfg!(unix);
+
+    /// Limits the address space of the process started by the command,
+    /// before exec. Unlike a cgroup, this limits each process separately.
+    #[cfg(unix)]
+    pub fn limit_address_space(command: &mut Command, bytes: u64) {
+        let limit = libc::rlimit {
+            rlim_cur: bytes as libc::rlim_t,
+            rlim_max: bytes as libc::rlim_t,
+        };
+        unsafe {
+            // Safety: The closure is run in a fork, and only passes a
+            // pointer to a valid struct.
+            command.pre_exec(move || {
+                if libc::setrlimit(libc::RLIMIT_AS, &limit) == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::last_os_error())
+                }
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn limit_address_space(_command: &mut Command, _bytes: u64) {}
+}
+
+mod ioprio {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    pub fn set(io_class: IoClass) -> io::Result<()> {
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        let (class, level) = match io_class {
+            IoClass::Realtime => (1, 4),
+            IoClass::BestEffort => (2, 4),
+            IoClass::Idle => (3, 0),
+        };
+        // Safety: ioprio_set does not access memory. Who 0 is the current
+        // process, and its children will inherit the priority.
+        if unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                class << IOPRIO_CLASS_SHIFT | level,
+            )
+        } == 0
+        {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn set(_io_class: IoClass) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "I/O scheduling classes not supported on this platform",
+        ))
+    }
+}
+
+mod priority {
+    use super::*;
+
+    // On Linux, priorities are per thread. They are set on the thread that
+    // spawns the engine processes, so that they inherit them.
+
+    #[cfg(unix)]
+    pub fn set_nice(nice: i32) -> io::Result<()> {
+        // Safety: setpriority does not access memory. Who 0 is the current
+        // process (or thread on Linux).
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn set_idle() -> io::Result<()> {
+        let param = libc::sched_param { sched_priority: 0 };
+        // Safety: Passing a pointer to a valid struct.
+        if unsafe { libc::sched_setscheduler(0, libc::SCHED_IDLE, &param) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    pub fn set_idle() -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "idle scheduling not supported on this platform (use --nice 19)",
+        ))
+    }
+
+    #[cfg(windows)]
+    mod win {
+        pub const IDLE_PRIORITY_CLASS: u32 = 0x40;
+        pub const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x4000;
+        pub const NORMAL_PRIORITY_CLASS: u32 = 0x20;
+        pub const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x8000;
+
+        #[link(name = "kernel32")]
+        extern "system" {
+            pub fn GetCurrentProcess() -> isize;
+            pub fn SetPriorityClass(process: isize, class: u32) -> i32;
+        }
+    }
+
+    /// Child processes inherit the idle and below normal priority classes.
+    #[cfg(windows)]
+    fn set_priority_class(class: u32) -> io::Result<()> {
+        // Safety: The pseudo handle of the current process is always valid.
+        if unsafe { win::SetPriorityClass(win::GetCurrentProcess(), class) } != 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(windows)]
+    pub fn set_nice(nice: i32) -> io::Result<()> {
+        set_priority_class(match nice {
+            n if n > 0 => win::BELOW_NORMAL_PRIORITY_CLASS,
+            0 => win::NORMAL_PRIORITY_CLASS,
+            _ => win::ABOVE_NORMAL_PRIORITY_CLASS,
+        })
+    }
+
+    #[cfg(windows)]
+    pub fn set_idle() -> io::Result<()> {
+        set_priority_class(win::IDLE_PRIORITY_CLASS)
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    fs,
+    fs::{File, OpenOptions},
+    io,
+    io::Write as _,
+    path::{Path, PathBuf},
+    thread,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use xz2::write::XzEncoder;
+
+/// Log file that is rotated once it gets too large or too old. Rotated
+/// files are compressed in the background, as `fishnet.log.1.xz` (newest)
+/// to `fishnet.log.<keep>.xz` (oldest).
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+    max_size: u64,
+    max_age: Option<Duration>,
+    keep: usize,
+    compressing: Option<JoinHandle<()>>,
+}
+
+impl LogFile {
+    pub fn open(
+        path: &Path,
+        max_size: u64,
+        max_age: Option<Duration>,
+        keep: usize,
+    ) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(LogFile {
+            path: path.to_owned(),
+            size: file.metadata()?.len(),
+            file,
+            opened_at: Instant::now(),
+            max_size,
+            max_age,
+            keep,
+            compressing: None,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size >= self.max_size
+            || self
+                .max_age
+                .map_or(false, |max_age| self.opened_at.elapsed() >= max_age)
+        {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: usize, ext: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}{}", n, ext));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // Finish compressing the previous file before renaming it.
+        if let Some(compressing) = self.compressing.take() {
+            compressing.join().expect("join log compression");
+        }
+
+        for n in (1..=self.keep).rev() {
+            let from = self.rotated_path(n, ".xz");
+            if n == self.keep {
+                remove_if_exists(&from)?;
+            } else if from.exists() {
+                fs::rename(&from, self.rotated_path(n + 1, ".xz"))?;
+            }
+        }
+
+        if self.keep > 0 {
+            let uncompressed = self.rotated_path(1, "");
+            let compressed = self.rotated_path(1, ".xz");
+            fs::rename(&self.path, &uncompressed)?;
+            self.compressing = Some(thread::spawn(move || {
+                if let Err(err) = compress(&uncompressed, &compressed) {
+                    eprintln!("E: Failed to compress {:?}: {}", uncompressed, err);
+                }
+            }));
+        } else {
+            remove_if_exists(&self.path)?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+}
+
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let mut encoder = XzEncoder::new(File::create(to)?, 6);
+    io::copy(&mut File::open(from)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(from)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+
+    use xz2::read::XzDecoder;
+
+    use super::*;
+
+    fn decompress(path: &Path) -> String {
+        let mut text = String::new();
+        XzDecoder::new(File::open(path).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    }
+
+    fn finish_compressing(log: &mut LogFile) {
+        if let Some(compressing) = log.compressing.take() {
+            compressing.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fishnet.log");
+        let mut log = LogFile::open(&path, 10, None, 2).unwrap();
+        for line in ["first line", "second line", "third line", "fourth line"] {
+            log.write_line(line).unwrap();
+        }
+        finish_compressing(&mut log);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth line\n");
+        assert_eq!(decompress(&log.rotated_path(1, ".xz")), "third line\n");
+        assert_eq!(decompress(&log.rotated_path(2, ".xz")), "second line\n");
+        // Only the configured number of rotated files is kept.
+        assert!(!log.rotated_path(3, ".xz").exists());
+        assert!(!log.rotated_path(1, "").exists());
+    }
+
+    #[test]
+    fn test_rotate_by_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fishnet.log");
+        let mut log = LogFile::open(&path, u64::MAX, Some(Duration::ZERO), 1).unwrap();
+        log.write_line("old").unwrap();
+        log.write_line("new").unwrap();
+        finish_compressing(&mut log);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert_eq!(decompress(&log.rotated_path(1, ".xz")), "old\n");
+    }
+
+    #[test]
+    fn test_keep_none() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fishnet.log");
+        let mut log = LogFile::open(&path, 1, None, 0).unwrap();
+        log.write_line("old").unwrap();
+        log.write_line("new").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert!(!log.rotated_path(1, ".xz").exists());
+        assert!(!log.rotated_path(1, "").exists());
+    }
+
+    #[test]
+    fn test_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fishnet.log");
+        fs::write(&path, "before restart\n").unwrap();
+        let mut log = LogFile::open(&path, 10, None, 1).unwrap();
+        // Size of the existing file counts towards the limit.
+        log.write_line("after restart").unwrap();
+        finish_compressing(&mut log);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "after restart\n");
+        assert_eq!(decompress(&log.rotated_path(1, ".xz")), "before restart\n");
+    }
+}
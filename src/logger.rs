@@ -1,42 +1,167 @@
 use std::{
     cmp::{max, min},
+    collections::VecDeque,
     fmt, io,
     io::Write as _,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use atty::Stream;
+use chrono::{Local, SecondsFormat, Utc};
+use serde::Serialize;
+use thousands::Separable as _;
 use url::Url;
 
 use crate::{
-    api::BatchId,
-    configure::Verbose,
+    api::{BatchId, Quota, Score},
+    configure::{FormatOpt, LogFormat, NodeUnit, ScoreFormat, Timestamps, Verbose},
     ipc::{Position, PositionId, PositionResponse},
+    logfile::LogFile,
     util::NevermindExt as _,
 };
 
+/// Keep this many recent lines, including debug output, for bug reports.
+const MAX_RECENT_LINES: usize = 500;
+
 #[derive(Clone)]
 pub struct Logger {
-    verbose: Verbose,
+    /// Verbosity level, shared by all clones so that it can be changed at
+    /// runtime.
+    verbose: Arc<AtomicUsize>,
+    format: FormatOpt,
     stderr: bool,
     atty: bool,
+    worker_name: Option<Arc<str>>,
+    /// The terminal is taken over by the status view. Lines are only kept
+    /// in the recent lines and the log file.
+    tui: Arc<AtomicBool>,
     state: Arc<Mutex<LoggerState>>,
 }
 
 impl Logger {
-    pub fn new(verbose: Verbose, stderr: bool) -> Logger {
+    pub fn new(verbose: Verbose, format: FormatOpt, stderr: bool) -> Logger {
         Logger {
-            verbose,
+            verbose: Arc::new(AtomicUsize::new(verbose.level)),
+            format,
             stderr,
             atty: atty::is(Stream::Stdout),
-            state: Arc::new(Mutex::new(LoggerState { progress_line: 0 })),
+            worker_name: None,
+            tui: Arc::new(AtomicBool::new(false)),
+            state: Arc::new(Mutex::new(LoggerState {
+                progress_line: 0,
+                recent: VecDeque::new(),
+                file: None,
+            })),
+        }
+    }
+
+    /// Also appends log lines to the given file.
+    pub fn log_file(self, file: LogFile) -> Logger {
+        self.state.lock().expect("logger state").file = Some(file);
+        self
+    }
+
+    /// Changes the verbosity of this logger and all its clones.
+    pub fn set_verbose(&self, verbose: Verbose) {
+        self.verbose.store(verbose.level, Ordering::Relaxed);
+    }
+
+    /// Switches between normal and verbose logging, for example on
+    /// SIGUSR2. Returns the new verbosity.
+    pub fn cycle_verbose(&self) -> Verbose {
+        let level = if self.verbose_level() == 0 { 1 } else { 0 };
+        self.verbose.store(level, Ordering::Relaxed);
+        Verbose { level }
+    }
+
+    fn verbose_level(&self) -> usize {
+        self.verbose.load(Ordering::Relaxed)
+    }
+
+    /// Shows the name of this machine in the progress line.
+    pub fn worker_name(mut self, name: Option<String>) -> Logger {
+        self.worker_name = name.map(Arc::from);
+        self
+    }
+
+    /// Hands the terminal to the status view of `--tui`, or back. Returns
+    /// `false` if stdout is not a terminal or logs are not plain text.
+    pub fn set_tui(&self, tui: bool) -> bool {
+        if tui && (!self.atty || self.stderr || self.log_format() != LogFormat::Text) {
+            return false;
+        }
+        self.clear_echo();
+        self.tui.store(tui, Ordering::Relaxed);
+        true
+    }
+
+    fn is_tui(&self) -> bool {
+        self.tui.load(Ordering::Relaxed)
+    }
+
+    fn log_format(&self) -> LogFormat {
+        self.format.log_format.unwrap_or(LogFormat::Text)
+    }
+
+    fn log(&self, level: Level, line: &str, at: Option<&ProgressAt>) {
+        let text = format!("{}{}", level.prefix(), line);
+        if level == Level::Debug && self.verbose_level() == 0 {
+            self.state.lock().expect("logger state").remember(&text);
+            return;
+        }
+        match self.log_format() {
+            LogFormat::Text => self.println(&text),
+            LogFormat::Json => {
+                let mut state = self.state.lock().expect("logger state");
+                state.remember(&text);
+                let record = Record {
+                    timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                    level: level.name(),
+                    batch_id: at.map(|at| at.batch_id.to_string()),
+                    position_id: at.and_then(|at| at.position_id).map(|PositionId(id)| id),
+                    message: line,
+                };
+                let record = serde_json::to_string(&record).expect("serialize log record");
+                state.write_file(&record);
+                self.write_line(&record);
+            }
         }
     }
 
     fn println(&self, line: &str) {
         let mut state = self.state.lock().expect("logger state");
         state.line_feed();
+        state.remember(line);
+        if state.file.is_some() {
+            state.write_file(&format!(
+                "[{}] {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                line
+            ));
+        }
 
+        let timestamped;
+        let line = match self.format.timestamps.unwrap_or(Timestamps::Off) {
+            Timestamps::Off => line,
+            Timestamps::Hours12 => {
+                timestamped = format!("[{}] {}", Local::now().format("%I:%M:%S %p"), line);
+                &timestamped
+            }
+            Timestamps::Hours24 => {
+                timestamped = format!("[{}] {}", Local::now().format("%H:%M:%S"), line);
+                &timestamped
+            }
+        };
+
+        if !self.is_tui() {
+            self.write_line(line);
+        }
+    }
+
+    fn write_line(&self, line: &str) {
         if self.stderr {
             writeln!(io::stderr(), "{}", line).nevermind("log to stderr");
         } else if let Err(e) = writeln!(io::stdout(), "{}", line) {
@@ -53,43 +178,84 @@ impl Logger {
     }
 
     pub fn headline(&self, title: &str) {
-        self.println(&format!("\n### {}\n", title));
+        match self.log_format() {
+            LogFormat::Text => self.println(&format!("\n### {}\n", title)),
+            LogFormat::Json => self.log(Level::Info, title, None),
+        }
     }
 
     pub fn debug(&self, line: &str) {
-        if self.verbose.level > 0 {
-            self.println(&format!("D: {}", line));
-        }
+        self.log(Level::Debug, line, None);
+    }
+
+    /// Recent lines, regardless of the verbosity, oldest first.
+    pub fn recent(&self) -> Vec<String> {
+        let state = self.state.lock().expect("logger state");
+        state.recent.iter().cloned().collect()
     }
 
     pub fn info(&self, line: &str) {
-        self.println(line);
+        self.log(Level::Info, line, None);
     }
 
     pub fn fishnet_info(&self, line: &str) {
-        self.println(&format!("><> {}", line));
+        self.log(Level::Fishnet, line, None);
     }
 
     pub fn warn(&self, line: &str) {
-        self.println(&format!("W: {}", line));
+        self.log(Level::Warn, line, None);
     }
 
     pub fn error(&self, line: &str) {
-        self.println(&format!("E: {}", line));
+        self.log(Level::Error, line, None);
+    }
+
+    /// Formats a node count in the configured unit.
+    pub fn nodes(&self, nodes: u64) -> String {
+        match self.format.node_unit {
+            None | Some(NodeUnit::Nodes) => format!("{} nodes", nodes.separate_with_dots()),
+            Some(NodeUnit::Knodes) => format!("{} knodes", (nodes / 1000).separate_with_dots()),
+            Some(NodeUnit::Mnodes) => format!("{:.1} Mnodes", nodes as f64 / 1e6),
+        }
+    }
+
+    /// Formats a speed in the configured unit. Defaults to knps.
+    pub fn nps(&self, nps: u32) -> String {
+        match self.format.node_unit {
+            Some(NodeUnit::Nodes) => format!("{} nps", nps.separate_with_dots()),
+            None | Some(NodeUnit::Knodes) => format!("{} knps", nps / 1000),
+            Some(NodeUnit::Mnodes) => format!("{:.1} Mnps", f64::from(nps) / 1e6),
+        }
+    }
+
+    /// Formats a score in the configured format.
+    pub fn score(&self, score: &Score) -> String {
+        match (score, self.format.score_format.unwrap_or(ScoreFormat::Cp)) {
+            (Score::Cp(cp), ScoreFormat::Cp) => format!("cp {}", cp),
+            (Score::Cp(cp), ScoreFormat::Pawns) => format!("{:+.2}", *cp as f64 / 100.0),
+            (Score::Mate(mate), ScoreFormat::Cp) => format!("mate {}", mate),
+            (Score::Mate(mate), ScoreFormat::Pawns) => format!("#{}", mate),
+        }
     }
 
     pub fn progress<P>(&self, queue: QueueStatusBar, progress: P)
     where
         P: Into<ProgressAt>,
     {
-        let line = format!(
-            "{} {} cores, {} queued, latest: {}",
-            queue,
-            queue.cores,
-            queue.pending,
-            progress.into()
+        let at = progress.into();
+        let mut line = format!(
+            "{} {} cores, {} queued, lookahead {}, latest: {}",
+            queue, queue.cores, queue.pending, queue.lookahead, at
         );
-        if self.atty {
+        if let Some(quota) = queue.quota {
+            line = format!("{}, quota: {}", line, quota);
+        }
+        if let Some(ref name) = self.worker_name {
+            line = format!("{}: {}", name, line);
+        }
+        if self.is_tui() {
+            // Shown in the status view instead.
+        } else if self.atty && self.log_format() == LogFormat::Text {
             let mut state = self.state.lock().expect("logger state");
             print!(
                 "\r{}{}",
@@ -98,12 +264,53 @@ impl Logger {
             );
             io::stdout().flush().expect("flush stdout");
             state.progress_line = line.len();
-        } else if self.verbose.level > 0 {
-            self.println(&line);
+        } else if self.verbose_level() > 0 {
+            self.log(Level::Info, &line, Some(&at));
         }
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Level {
+    Debug,
+    Info,
+    /// Info about fishnet itself, rather than the work.
+    Fishnet,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info | Level::Fishnet => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            Level::Debug => "D: ",
+            Level::Info => "",
+            Level::Fishnet => "><> ",
+            Level::Warn => "W: ",
+            Level::Error => "E: ",
+        }
+    }
+}
+
+/// Log line in the JSON format.
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    level: &'static str,
+    batch_id: Option<String>,
+    position_id: Option<usize>,
+    message: &'a str,
+}
+
 pub struct ProgressAt {
     pub batch_id: BatchId,
     pub batch_url: Option<Url>,
@@ -150,9 +357,34 @@ impl From<&PositionResponse> for ProgressAt {
 
 struct LoggerState {
     pub progress_line: usize,
+    recent: VecDeque<String>,
+    file: Option<LogFile>,
 }
 
 impl LoggerState {
+    fn remember(&mut self, line: &str) {
+        if self.recent.len() >= MAX_RECENT_LINES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(format!(
+            "[{}] {}",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            line
+        ));
+    }
+
+    fn write_file(&mut self, line: &str) {
+        if let Some(ref mut file) = self.file {
+            if let Err(err) = file.write_line(line) {
+                // Stop logging to the file, rather than reporting every
+                // line.
+                writeln!(io::stderr(), "E: {} while logging to file. Giving up.", err)
+                    .nevermind("log to stderr");
+                self.file = None;
+            }
+        }
+    }
+
     fn line_feed(&mut self) {
         if self.progress_line > 0 {
             self.progress_line = 0;
@@ -164,6 +396,10 @@ impl LoggerState {
 pub struct QueueStatusBar {
     pub pending: usize,
     pub cores: usize,
+    /// Positions kept queued ahead of the workers.
+    pub lookahead: usize,
+    /// Request quota announced by the server, if any.
+    pub quota: Option<Quota>,
 }
 
 impl fmt::Display for QueueStatusBar {
@@ -0,0 +1,361 @@
+use std::{
+    cmp::min,
+    fmt, io,
+    num::NonZeroU8,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use shakmaty::{
+    fen::Fen, uci::Uci, variant::VariantPosition, CastlingMode, Chess, Color, Position as _,
+    Setup as _,
+};
+
+use crate::{
+    api::{Score, SkillLevel, Wdl, Work},
+    book::Book,
+    cache::ResultCache,
+    configure::{LookupOpt, WorkFilter},
+    ipc::{Matrix, Position, PositionResponse},
+    logger::Logger,
+};
+
+const CLOUD_EVAL_URL: &str = "https://lichess.org/api/cloud-eval";
+
+/// Pause cloud evaluation lookups for this long when rate limited.
+const RATE_LIMIT_PAUSE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+struct CloudEvalResponse {
+    depth: u8,
+    knodes: u64,
+    pvs: Vec<CloudPv>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CloudPv {
+    moves: String,
+    cp: Option<i64>,
+    mate: Option<i64>,
+}
+
+struct CloudEval {
+    client: reqwest::Client,
+    min_depth: u8,
+    paused_until: Mutex<Option<Instant>>,
+}
+
+impl CloudEval {
+    async fn get(
+        &self,
+        pos: &Chess,
+        multipv: NonZeroU8,
+    ) -> Result<Option<CloudEvalResponse>, String> {
+        if let Some(until) = *self.paused_until.lock().expect("paused until") {
+            if Instant::now() < until {
+                return Ok(None);
+            }
+        }
+        let res = self
+            .client
+            .get(CLOUD_EVAL_URL)
+            .query(&[
+                ("fen", Fen::from_setup(pos).to_string()),
+                ("multiPv", multipv.to_string()),
+            ])
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        match res.status() {
+            reqwest::StatusCode::NOT_FOUND => Ok(None),
+            reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                *self.paused_until.lock().expect("paused until") =
+                    Some(Instant::now() + RATE_LIMIT_PAUSE);
+                Err("rate limited, pausing cloud evaluation lookups".to_owned())
+            }
+            status if status.is_success() => {
+                res.json().await.map(Some).map_err(|err| err.to_string())
+            }
+            status => Err(format!("cloud evaluation responded with {}", status)),
+        }
+    }
+}
+
+/// Answers positions from a local opening book, previous results or cloud
+/// evaluations, so that they do not need to be searched.
+pub struct Lookup {
+    book: Option<Arc<Book>>,
+    cache: Option<ResultCache>,
+    book_max_level: Option<u32>,
+    book_skip_plies: Option<usize>,
+    cloud_eval: Option<CloudEval>,
+    work: WorkFilter,
+    logger: Logger,
+}
+
+impl Lookup {
+    pub fn new(opt: &LookupOpt, logger: Logger) -> io::Result<Lookup> {
+        let cache = opt.result_cache.map(|capacity| {
+            let cache = ResultCache::new(capacity, opt.result_cache_file.clone());
+            if let Err(err) = cache.load() {
+                logger.warn(&format!(
+                    "Failed to load result cache {:?}: {}",
+                    opt.result_cache_file, err
+                ));
+            }
+            cache
+        });
+        Ok(Lookup {
+            cache,
+            book: opt
+                .book
+                .as_deref()
+                .map(Book::open)
+                .transpose()?
+                .map(Arc::new),
+            book_max_level: opt.book_max_level,
+            book_skip_plies: opt.book_skip_plies,
+            cloud_eval: opt.cloud_eval_depth.map(|min_depth| CloudEval {
+                client: reqwest::Client::builder()
+                    .timeout(Duration::from_secs(5))
+                    .build()
+                    .expect("client"),
+                min_depth,
+                paused_until: Mutex::new(None),
+            }),
+            work: opt.lookup_work.clone().unwrap_or_default(),
+            logger,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.book.is_some() || self.cache.is_some() || self.cloud_eval.is_some()
+    }
+
+    pub fn cache(&self) -> Option<&ResultCache> {
+        self.cache.as_ref()
+    }
+
+    /// Remembers the result of a search, if caching is enabled.
+    pub fn record(&self, position: &Position, res: &PositionResponse) {
+        if let Some(ref cache) = self.cache {
+            if self.work.allows(&position.work) {
+                cache.insert(position, res);
+            }
+        }
+    }
+
+    /// Skipping of book positions in analysis, if configured.
+    pub fn book_skip(&self) -> Option<BookSkip> {
+        Some(BookSkip {
+            book: self.book.clone()?,
+            max_plies: self
+                .book_skip_plies
+                .filter(|_| self.work.allows_kind("analysis"))?,
+        })
+    }
+
+    /// Looks up the position. Cloud evaluations are only available for
+    /// standard chess.
+    pub async fn probe(&self, position: &Position) -> Option<PositionResponse> {
+        // Play-outs need the engine for every ply.
+        if !self.is_enabled()
+            || !self.work.allows(&position.work)
+            || matches!(position.work, Work::Playout { .. })
+        {
+            return None;
+        }
+        if let Some(res) = self.cache.as_ref().and_then(|cache| cache.get(position)) {
+            return Some(res);
+        }
+        let pos = current_pos(position)?;
+        let started_at = Instant::now();
+
+        // Book moves have no evaluation, so they can only be played.
+        if let (Work::Move { level, .. }, Some(book)) = (&position.work, &self.book) {
+            let in_book = self
+                .book_max_level
+                .map_or(true, |max_level| *level as u32 <= max_level)
+                .then(|| book.pick(&pos))
+                .flatten();
+            if let Some(best_move) = in_book {
+                self.logger.debug(&format!(
+                    "Playing book move {} for {}",
+                    best_move,
+                    position.work.id()
+                ));
+                return Some(cached_response(
+                    position,
+                    Matrix::new(),
+                    Matrix::new(),
+                    Some(best_move),
+                    0,
+                    0,
+                    started_at,
+                ));
+            }
+        }
+
+        let pos = match pos {
+            VariantPosition::Chess(pos) => pos,
+            _ => return None,
+        };
+
+        // Cloud evaluations are deep, so only use them for full strength
+        // moves.
+        if let Work::Move { level, .. } = position.work {
+            if !matches!(level, SkillLevel::Eight) {
+                return None;
+            }
+        }
+        let cloud_eval = self.cloud_eval.as_ref()?;
+        let multipv = position.work.multipv();
+        let eval = match cloud_eval.get(&pos, multipv).await {
+            Ok(Some(eval)) => eval,
+            Ok(None) => return None,
+            Err(err) => {
+                self.logger
+                    .warn(&format!("Cloud evaluation lookup failed: {}", err));
+                return None;
+            }
+        };
+        if eval.depth < cloud_eval.min_depth || eval.pvs.len() < usize::from(multipv.get()) {
+            return None;
+        }
+
+        let mut scores = Matrix::new();
+        let mut pvs = Matrix::new();
+        for (i, pv) in eval.pvs.iter().take(usize::from(multipv.get())).enumerate() {
+            // Cloud evaluations are from the point of view of white, engine
+            // scores from the point of view of the side to move.
+            let sign = match pos.turn() {
+                Color::White => 1,
+                Color::Black => -1,
+            };
+            let score = match (pv.cp, pv.mate) {
+                (_, Some(mate)) => Score::Mate(sign * mate),
+                (Some(cp), None) => Score::Cp(sign * cp),
+                (None, None) => return None,
+            };
+            let line = uci_line(&pos, &pv.moves);
+            let multipv = NonZeroU8::new(i as u8 + 1).expect("multipv");
+            scores.set(multipv, eval.depth, score);
+            pvs.set(multipv, eval.depth, line);
+        }
+        let best_move = pvs.best().and_then(|pv| pv.first().cloned());
+        Some(cached_response(
+            position,
+            scores,
+            pvs,
+            best_move,
+            eval.depth,
+            eval.knodes * 1000,
+            started_at,
+        ))
+    }
+}
+
+/// Plays the moves of the position.
+/// Skips analysis of opening book positions near the start of games,
+/// before they reach the engines.
+pub struct BookSkip {
+    book: Arc<Book>,
+    max_plies: usize,
+}
+
+impl fmt::Debug for BookSkip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BookSkip")
+            .field("max_plies", &self.max_plies)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BookSkip {
+    /// Plies of the game, up to the limit, with positions in the book.
+    pub fn plies(&self, root: &VariantPosition, moves: &[Uci]) -> Vec<usize> {
+        let mut plies = Vec::new();
+        let mut pos = root.clone();
+        for ply in 0..=min(self.max_plies, moves.len()) {
+            if self.book.contains(&pos) {
+                plies.push(ply);
+            }
+            match moves.get(ply).map(|uci| uci.to_move(&pos)) {
+                Some(Ok(m)) => pos.play_unchecked(&m),
+                _ => break,
+            }
+        }
+        plies
+    }
+}
+
+fn current_pos(position: &Position) -> Option<VariantPosition> {
+    let mut pos = VariantPosition::from_setup(
+        position.variant.into(),
+        &position.root_fen,
+        CastlingMode::Chess960,
+    )
+    .ok()?;
+    for uci in &position.moves {
+        let m = uci.to_move(&pos).ok()?;
+        pos.play_unchecked(&m);
+    }
+    Some(pos)
+}
+
+/// Converts a line in standard notation to the notation used between the
+/// queue and the engines.
+fn uci_line(pos: &Chess, moves: &str) -> Vec<Uci> {
+    let mut pos = pos.clone();
+    let mut line = Vec::new();
+    for uci in moves.split(' ') {
+        let m = match uci
+            .parse::<Uci>()
+            .ok()
+            .and_then(|uci| uci.to_move(&pos).ok())
+        {
+            Some(m) => m,
+            None => break,
+        };
+        line.push(m.to_uci(CastlingMode::Chess960));
+        pos.play_unchecked(&m);
+    }
+    line
+}
+
+fn cached_response(
+    position: &Position,
+    scores: Matrix<Score>,
+    pvs: Matrix<Vec<Uci>>,
+    best_move: Option<Uci>,
+    depth: u8,
+    nodes: u64,
+    started_at: Instant,
+) -> PositionResponse {
+    let wdl = if position.work.wdl_wanted() {
+        scores
+            .best()
+            .map(|&score| Wdl::from_score(score, position.ply()))
+    } else {
+        None
+    };
+    PositionResponse {
+        work: position.work.clone(),
+        position_id: position.position_id,
+        url: position.url.clone(),
+        scores,
+        pvs,
+        best_move,
+        depth,
+        nodes,
+        time: Duration::default(),
+        nps: None,
+        wdl,
+        hashfull: None,
+        latency: started_at.elapsed(),
+        cached: true,
+        playout: Vec::new(),
+        cpu_time: None,
+    }
+}
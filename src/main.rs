@@ -1,41 +1,46 @@
 #![forbid(unsafe_op_in_unsafe_fn)]
 
-mod api;
-mod assets;
-mod configure;
-mod ipc;
-mod logger;
-mod queue;
-mod stats;
-mod stockfish;
+mod analyse;
+mod analyse_epd;
+mod auth;
+mod benchmark;
+mod dashboard;
+mod doctor;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod puzzles;
+mod report;
+mod selfplay;
 mod systemd;
-mod util;
-
-use std::{
-    cmp::min,
-    env,
-    path::PathBuf,
-    ptr,
-    sync::Arc,
-    thread,
-    time::{Duration, Instant},
-};
+mod update;
+mod verify_notation;
+
+use std::{env, net::SocketAddr, path::PathBuf, process, ptr, sync::Arc, thread, time::Duration};
 
 use atty::Stream;
+// Also makes the library modules available to the binary modules as
+// crate::<module>.
+use fishnet_core::{
+    accuracy, affinity, api, assets, book, calibration, cluster, configure, control, describe, ipc,
+    limits, logfile, logger, pgn, pool, record, selftest, session, stats, stockfish, util,
+    validate,
+};
 use thousands::Separable as _;
 use tokio::{
     signal,
-    sync::{mpsc, oneshot},
+    sync::{oneshot, watch, Notify},
     time,
 };
 
 use crate::{
-    assets::{Assets, ByEngineFlavor, Cpu, EngineFlavor},
-    configure::{Command, Cores, Opt},
-    ipc::{Position, PositionFailed, Pull},
-    logger::{Logger, ProgressAt},
-    stockfish::StockfishInit,
-    util::RandomizedBackoff,
+    assets::Assets,
+    configure::{Command, Cores, Opt, ParsedDuration, UpdateChannel},
+    control::ControlCommand,
+    describe::Description,
+    logfile::LogFile,
+    logger::Logger,
+    session::{Config, Shutdown, ShutdownToken},
+    util::NevermindExt as _,
 };
 
 static COMPRESSED_DEPENDENCY_LIST: &[u8] = auditable::inject_dependency_list!();
@@ -48,13 +53,36 @@ async fn main() {
         ptr::read_volatile(&COMPRESSED_DEPENDENCY_LIST[0]);
     }
 
-    let opt = configure::parse_and_configure().await;
-    let logger = Logger::new(opt.verbose, opt.command.map_or(false, Command::is_systemd));
+    let mut opt = configure::parse_and_configure().await;
+    let mut logger = Logger::new(opt.verbose, opt.format, opt.logs_to_stderr())
+        .worker_name(opt.worker_name.clone());
+    if let Some(ref path) = opt.log_file.log_file {
+        match LogFile::open(
+            path,
+            opt.log_file.max_size(),
+            opt.log_file.log_max_age.map(Duration::from),
+            opt.log_file.keep(),
+        ) {
+            Ok(log_file) => logger = logger.log_file(log_file),
+            Err(err) => {
+                logger.error(&format!("Failed to open log file {:?}: {}", path, err));
+                process::exit(1);
+            }
+        }
+    }
+
+    if opt.auto_update.is_enabled() && !update::can_verify_updates() {
+        logger.warn(
+            "This build of fishnet cannot verify signed releases. Ignoring --auto-update, please update manually.",
+        );
+        opt.auto_update = UpdateChannel::Off;
+    }
 
-    if opt.auto_update {
+    if opt.auto_update.is_enabled() {
         let current_exe = env::current_exe().expect("current exe");
-        match auto_update(
-            !opt.command.map_or(false, Command::is_systemd),
+        match update::auto_update(
+            opt.auto_update,
+            !opt.command.as_ref().map_or(false, Command::is_systemd),
             logger.clone(),
         )
         .await
@@ -70,39 +98,55 @@ async fn main() {
         }
     }
 
+    if opt.describe {
+        describe(opt, &logger).await;
+        return;
+    }
+
     match opt.command {
         Some(Command::Run) | None => run(opt, &logger).await,
-        Some(Command::Systemd) => systemd::systemd_system(opt),
+        Some(Command::Systemd(ref systemd_opt)) => systemd::systemd_system(&opt, systemd_opt),
         Some(Command::SystemdUser) => systemd::systemd_user(opt),
         Some(Command::Configure) => (),
+        Some(Command::Doctor) => doctor::doctor(opt, &logger).await,
+        Some(Command::Stats) => lifetime_stats(opt.json, &logger),
+        Some(Command::Leaderboard) => leaderboard(opt, &logger).await,
+        Some(Command::Auth(ref auth_opt)) => auth::auth(&opt, auth_opt, &logger).await,
+        Some(Command::Report) => report::report(opt, &logger).await,
+        Some(Command::Ctl { command }) => ctl(&opt, command).await,
+        Some(Command::Dashboard { hosts }) => dashboard::dashboard(hosts, &logger).await,
+        Some(Command::AnalyseEpd(ref epd_opt)) => {
+            analyse_epd::analyse_epd(&opt, epd_opt, &logger).await
+        }
+        Some(Command::Analyse(ref analyse_opt)) => {
+            analyse::analyse(&opt, analyse_opt, &logger).await
+        }
+        Some(Command::Puzzles(ref puzzle_opt)) => puzzles::puzzles(&opt, puzzle_opt, &logger).await,
+        Some(Command::Selfplay(ref selfplay_opt)) => {
+            selfplay::selfplay(&opt, selfplay_opt, &logger).await
+        }
+        Some(Command::Benchmark(ref benchmark_opt)) => {
+            benchmark::benchmark(&opt, benchmark_opt, &logger).await
+        }
+        Some(Command::Selftest) => self_test(&opt, &logger).await,
+        Some(Command::Follow { ref coordinator }) => {
+            follow(&opt, coordinator.clone(), &logger).await
+        }
+        Some(Command::VerifyNotation(ref verify_opt)) => {
+            verify_notation::verify_notation(&opt, verify_opt, &logger).await
+        }
+        Some(Command::Grpc { bind }) => serve_grpc(&opt, bind, &logger).await,
+        Some(Command::UpdateEngines) => update::update_engines(&opt, &logger).await,
         Some(Command::License) => license(&logger),
     }
 }
 
 async fn run(opt: Opt, logger: &Logger) {
-    logger.headline("Checking configuration ...");
-
-    let endpoint = opt.endpoint();
-    logger.info(&format!("Endpoint: {}", endpoint));
-
-    logger.info(&format!(
-        "Backlog: Join queue if user backlog >= {:?} or system backlog >= {:?}",
-        Duration::from(opt.backlog.user.unwrap_or_default()),
-        Duration::from(opt.backlog.system.unwrap_or_default())
-    ));
-
-    let cpu = Cpu::detect();
-    logger.info(&format!("CPU features: {:?}", cpu));
-
-    let assets = Assets::prepare(cpu).expect("prepared bundled stockfish");
-    logger.info(&format!(
-        "Engine: {} (for GPLv3, run: {} license)",
-        assets.sf_name,
-        env::args().next().unwrap_or_else(|| "./fishnet".to_owned())
-    ));
-
-    let cores = usize::from(opt.cores.unwrap_or(Cores::Auto));
-    logger.info(&format!("Cores: {}", cores));
+    let (cores, engine_limits) = limits::apply(
+        &opt.limits,
+        usize::from(opt.cores.unwrap_or(Cores::Auto)),
+        logger,
+    );
 
     // Install handler for SIGTERM.
     #[cfg(unix)]
@@ -118,294 +162,288 @@ async fn run(opt: Opt, logger: &Logger) {
     #[cfg(windows)]
     let mut sig_int = signal::windows::ctrl_c().expect("install handler for ctrl+c");
 
-    // To wait for workers and API actor before shutdown.
-    let mut join_handles = Vec::new();
+    // Install handler for SIGUSR1, to log a summary.
+    #[cfg(unix)]
+    let mut sig_usr1 = signal::unix::signal(signal::unix::SignalKind::user_defined1())
+        .expect("install handler for sigusr1");
+    let summary_trigger = Arc::new(Notify::new());
+    #[cfg(unix)]
+    {
+        let summary_trigger = summary_trigger.clone();
+        tokio::spawn(async move {
+            while sig_usr1.recv().await.is_some() {
+                summary_trigger.notify_one();
+            }
+        });
+    }
 
-    // Spawn API actor.
-    let api = {
-        let (api, api_actor) = api::channel(endpoint.clone(), opt.key, logger.clone());
-        join_handles.push(tokio::spawn(async move {
-            api_actor.run().await;
-        }));
-        api
-    };
+    // Install handler for SIGUSR2, to toggle verbose logging without a
+    // restart.
+    #[cfg(unix)]
+    {
+        let mut sig_usr2 = signal::unix::signal(signal::unix::SignalKind::user_defined2())
+            .expect("install handler for sigusr2");
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            while sig_usr2.recv().await.is_some() {
+                let verbose = logger.cycle_verbose();
+                logger.fishnet_info(&format!("Verbose level {} (SIGUSR2)", verbose.level));
+            }
+        });
+    }
+
+    // Install handler for SIGHUP, to reload the configuration file and the
+    // TLS client certificate.
+    let (reload_tx, reload_trigger) = watch::channel(());
+    #[cfg(unix)]
+    {
+        let mut sig_hup = signal::unix::signal(signal::unix::SignalKind::hangup())
+            .expect("install handler for sighup");
+        tokio::spawn(async move {
+            while sig_hup.recv().await.is_some() {
+                if reload_tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    #[cfg(windows)]
+    let _reload_tx = reload_tx;
 
     let to_stop = if atty::is(Stream::Stdout) {
         "CTRL-C"
     } else {
         "SIGINT"
     };
-    logger.headline(&format!("Running ({} to stop) ...", to_stop));
-
-    // Spawn queue actor.
-    let mut queue = {
-        let (queue, queue_actor) = queue::channel(
-            opt.backlog,
-            cores,
-            api,
-            opt.max_backoff.into(),
-            logger.clone(),
-        );
-        join_handles.push(tokio::spawn(async move {
-            queue_actor.run().await;
-        }));
-        queue
-    };
-
-    // Spawn workers. Workers handle engine processes and send their results
-    // to tx, thereby requesting more work.
-    let mut rx = {
-        let assets = Arc::new(assets);
-        let (tx, rx) = mpsc::channel::<Pull>(cores);
-        for i in 0..cores {
-            let assets = assets.clone();
-            let tx = tx.clone();
-            let logger = logger.clone();
-            join_handles.push(tokio::spawn(async move {
-                worker(i, assets, tx, logger).await;
-            }));
-        }
-        rx
-    };
 
-    let mut restart = None;
-    let mut up_to_date = Instant::now();
-    let mut summarized = Instant::now();
-    let mut shutdown_soon = false;
-
-    loop {
-        // Check for updates from time to time.
-        let now = Instant::now();
-        if opt.auto_update
-            && !shutdown_soon
-            && now.duration_since(up_to_date) >= Duration::from_secs(60 * 60 * 5)
-        {
-            up_to_date = now;
-            let current_exe = env::current_exe().expect("current exe");
-            match auto_update(false, logger.clone()).await {
-                Err(err) => logger.error(&format!("Failed to update in the background: {}", err)),
-                Ok(self_update::Status::UpToDate(version)) => {
-                    logger.fishnet_info(&format!("Fishnet {} is up to date", version));
-                }
-                Ok(self_update::Status::Updated(version)) => {
-                    logger.fishnet_info(&format!(
-                        "Fishnet updated to {}. Will restart soon",
-                        version
-                    ));
-                    restart = Some(current_exe);
-                    shutdown_soon = true;
-                    queue.shutdown_soon().await;
+    let shutdown = ShutdownToken::new();
+
+    // Handle signals.
+    {
+        let shutdown = shutdown.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    res = sig_int.recv() => {
+                        res.expect("sigint handler installed");
+                        logger.clear_echo();
+                        match shutdown.state() {
+                            Shutdown::Running => {
+                                logger.headline(&format!("Stopping soon. {} again to save pending batches for later ...", to_stop));
+                                shutdown.drain();
+                            }
+                            Shutdown::Soon => {
+                                logger.headline(&format!("Stopping after positions in progress. {} again to abort pending batches ...", to_stop));
+                                shutdown.checkpoint();
+                            }
+                            _ => {
+                                logger.fishnet_info("Stopping now.");
+                                shutdown.abort();
+                            }
+                        }
+                    }
+                    res = sig_term.recv() => {
+                        res.expect("sigterm handler installed");
+                        if shutdown.state() < Shutdown::Checkpoint {
+                            logger.fishnet_info("Stopping after positions in progress.");
+                            shutdown.checkpoint();
+                        } else {
+                            logger.fishnet_info("Stopping now.");
+                            shutdown.abort();
+                        }
+                    }
                 }
             }
-        }
-
-        // Print summary from time to time.
-        if now.duration_since(summarized) >= Duration::from_secs(120) {
-            summarized = now;
-            let (stats, nnue_nps) = queue.stats().await;
-            logger.fishnet_info(&format!(
-                "fishnet/{}: {} (nnue), {} batches, {} positions, {} total nodes",
-                env!("CARGO_PKG_VERSION"),
-                nnue_nps,
-                stats.total_batches.separate_with_dots(),
-                stats.total_positions.separate_with_dots(),
-                stats.total_nodes.separate_with_dots()
-            ));
-        }
+        });
+    }
 
-        // Main loop. Handles signals, forwards worker results from rx to the
-        // queue and responds with more work.
-        tokio::select! {
-            res = sig_int.recv() => {
-                res.expect("sigint handler installed");
-                logger.clear_echo();
-                if shutdown_soon {
-                    logger.fishnet_info("Stopping now.");
-                    rx.close();
-                } else {
-                    logger.headline(&format!("Stopping soon. {} again to abort pending batches ...", to_stop));
-                    queue.shutdown_soon().await;
-                    shutdown_soon = true;
-                }
-            }
-            res = sig_term.recv() => {
-                res.expect("sigterm handler installed");
-                logger.fishnet_info("Stopping now.");
-                shutdown_soon = true;
-                rx.close();
-            }
-            res = rx.recv() => {
-                if let Some(res) = res {
-                    queue.pull(res).await;
-                } else {
-                    logger.debug("About to exit.");
+    // Check for updates from time to time, and restart after draining.
+    let (restart_tx, mut restart_rx) = oneshot::channel();
+    let updater = (opt.auto_update.is_enabled() && opt.replay.is_none()).then(|| {
+        let auto_update = opt.auto_update;
+        let shutdown = shutdown.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            loop {
+                time::sleep(Duration::from_secs(60 * 60 * 5)).await;
+                if shutdown.state() != Shutdown::Running {
                     break;
                 }
+                let current_exe = env::current_exe().expect("current exe");
+                match update::auto_update(auto_update, false, logger.clone()).await {
+                    Err(err) => {
+                        logger.error(&format!("Failed to update in the background: {}", err))
+                    }
+                    Ok(self_update::Status::UpToDate(version)) => {
+                        logger.fishnet_info(&format!("Fishnet {} is up to date", version));
+                    }
+                    Ok(self_update::Status::Updated(version)) => {
+                        logger.fishnet_info(&format!(
+                            "Fishnet updated to {}. Will restart soon",
+                            version
+                        ));
+                        restart_tx.send(current_exe).nevermind("session ended");
+                        shutdown.drain_for_restart();
+                        break;
+                    }
+                }
             }
-            _ = time::sleep(Duration::from_secs(120)) => (),
-        }
+        })
+    });
+
+    let config = Config::from_opt(opt)
+        .cores(cores)
+        .engine_limits(engine_limits)
+        .logger(logger.clone())
+        .stop_hint(to_stop)
+        .summary_trigger(summary_trigger)
+        .reload_trigger(reload_trigger)
+        .keys(atty::is(Stream::Stdin) && atty::is(Stream::Stdout));
+    if let Err(err) = session::run(config, shutdown).await {
+        logger.error(&format!("Failed to run: {}", err));
+        process::exit(1);
     }
-
-    // Shutdown queue to abort remaining jobs.
-    queue.shutdown().await;
-
-    // Wait for all workers.
-    for join_handle in join_handles.into_iter() {
-        join_handle.await.expect("join");
+    if let Some(updater) = updater {
+        updater.abort();
     }
 
     // Restart.
-    if let Some(restart) = restart.take() {
+    if let Ok(restart) = restart_rx.try_recv() {
         restart_process(restart, logger);
     }
 }
 
-async fn worker(i: usize, assets: Arc<Assets>, tx: mpsc::Sender<Pull>, logger: Logger) {
-    logger.debug(&format!("Started worker {}.", i));
-
-    let mut job: Option<Position> = None;
-    let mut engine = ByEngineFlavor {
-        official: None,
-        multi_variant: None,
-    };
-    let mut engine_backoff = RandomizedBackoff::default();
-
-    let default_budget = Duration::from_secs(60);
-    let mut budget = default_budget;
-
-    loop {
-        let response = if let Some(job) = job.take() {
-            // Ensure engine process is ready.
-            let flavor = job.flavor;
-            let context = ProgressAt::from(&job);
-            let (mut sf, join_handle) =
-                if let Some((sf, join_handle)) = engine.get_mut(flavor).take() {
-                    (sf, join_handle)
-                } else {
-                    // Backoff before starting engine.
-                    let backoff = engine_backoff.next();
-                    if backoff >= Duration::from_secs(5) {
-                        logger.info(&format!(
-                            "Waiting {:?} before attempting to start engine",
-                            backoff
-                        ));
-                    } else {
-                        logger.debug(&format!(
-                            "Waiting {:?} before attempting to start engine",
-                            backoff
-                        ));
-                    }
-                    tokio::select! {
-                        _ = tx.closed() => break,
-                        _ = time::sleep(engine_backoff.next()) => (),
-                    }
-
-                    // Reset budget, start engine and spawn actor.
-                    budget = default_budget;
-                    let (sf, sf_actor) = stockfish::channel(
-                        assets.stockfish.get(flavor).clone(),
-                        StockfishInit {
-                            nnue: assets.nnue.clone(),
-                        },
-                        logger.clone(),
-                    );
-                    let join_handle = tokio::spawn(async move {
-                        sf_actor.run().await;
-                    });
-                    (sf, join_handle)
-                };
-
-            // Provide time budget.
-            budget = min(default_budget, budget) + job.work.timeout();
-
-            // Analyse or play.
-            let timer = Instant::now();
-            let batch_id = job.work.id();
-            let res = tokio::select! {
-                _ = tx.closed() => {
-                    logger.debug(&format!("Worker {} shutting down engine early", i));
-                    drop(sf);
-                    join_handle.await.expect("join");
-                    break;
-                }
-                res = sf.go(job) => {
-                    match res {
-                        Ok(res) => {
-                            *engine.get_mut(flavor) = Some((sf, join_handle));
-                            engine_backoff.reset();
-                            Ok(res)
-                        }
-                        Err(failed) => {
-                            drop(sf);
-                            logger.warn(&format!("Worker {} waiting for engine to shut down after error. Context: {}", i, context));
-                            join_handle.await.expect("join");
-                            Err(failed)
-                        },
-                    }
-                }
-                _ = time::sleep(budget) => {
-                    logger.warn(&match flavor {
-                        EngineFlavor::Official => format!("Official Stockfish timed out in worker {}. If this happens frequently it is better to stop and defer to clients with better hardware. Context: {}", i, context),
-                        EngineFlavor::MultiVariant => format!("Fairy-Stockfish timed out in worker {}. Context: {}", i, context),
-                    });
-                    drop(sf);
-                    join_handle.await.expect("join");
-                    Err(PositionFailed { batch_id })
-                }
-            };
-
-            // Update time budget.
-            budget = budget.checked_sub(timer.elapsed()).unwrap_or_default();
-            if budget < default_budget {
-                logger.debug(&format!("Low engine timeout budget: {:?}", budget));
-            }
+async fn describe(opt: Opt, logger: &Logger) {
+    let cpu = opt.cpu();
+    let assets = Assets::prepare(cpu, opt.enabled_engines()).expect("prepared bundled stockfish");
+    let description = Description::gather(&opt, cpu, &assets).await;
+    if opt.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&description).expect("serialize description")
+        );
+    } else {
+        description.log(logger);
+    }
+}
 
-            Some(res)
-        } else {
-            None
-        };
+async fn self_test(opt: &Opt, logger: &Logger) {
+    let mut assets =
+        Assets::prepare(opt.cpu(), opt.enabled_engines()).expect("prepared bundled stockfish");
+    assets.set_engine_options(&opt.engine);
+    let report = selftest::selftest(Arc::new(assets), true, logger).await;
+    for result in &report.results {
+        match result.error {
+            None => logger.info(&format!("{} {}: ok", result.engine_name(), result.variant)),
+            Some(ref err) => logger.error(&format!(
+                "{} {}: {}",
+                result.engine_name(),
+                result.variant,
+                err
+            )),
+        }
+    }
+    println!(
+        "{}",
+        serde_json::to_string(&report).expect("serialize self-test report")
+    );
+    if !report.pass {
+        process::exit(1);
+    }
+}
 
-        let (callback, waiter) = oneshot::channel();
+fn lifetime_stats(json: bool, logger: &Logger) {
+    let stats = stats::load().expect("read stats");
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&stats).expect("serialize stats")
+        );
+        return;
+    }
+    logger.headline("Lifetime statistics");
+    logger.info(&format!(
+        "{} batches, {} positions, {}, {} uptime, {} cpu",
+        stats.total_batches.separate_with_dots(),
+        stats.total_positions.separate_with_dots(),
+        logger.nodes(stats.total_nodes),
+        ParsedDuration::from(stats.total_uptime),
+        ParsedDuration::from(Duration::from_secs(stats.total_cpu_time.as_secs()))
+    ));
+    for (variant, v) in &stats.variants {
+        logger.info(&format!(
+            "{}: {} batches, {} positions, {}",
+            variant,
+            v.batches.separate_with_dots(),
+            v.positions.separate_with_dots(),
+            logger.nodes(v.nodes)
+        ));
+    }
+}
 
-        if tx.send(Pull { response, callback }).await.is_err() {
-            logger.debug(&format!(
-                "Worker {} was about to send result, but shutting down",
-                i
-            ));
-            break;
-        }
+async fn leaderboard(opt: Opt, logger: &Logger) {
+    let conf = opt
+        .endpoint_confs
+        .first()
+        .cloned()
+        .expect("endpoint configured");
+    let mut api = api::spawn(conf.endpoint.clone(), conf.key, conf.proxy, logger.clone());
+    logger.headline("Leaderboard");
+    match api.standing().await {
+        Some(standing) => logger.info(&format!(
+            "Rank {} of {} providers, {} positions",
+            standing.rank.separate_with_dots(),
+            standing.providers.separate_with_dots(),
+            standing.positions.separate_with_dots()
+        )),
+        None => logger.warn(&format!("No leaderboard available from {}", conf.endpoint)),
+    }
+    if let Some(rank_change) = stats::load().ok().and_then(|s| s.rank_change()) {
+        logger.info(&format!("Recent rank change: {}", rank_change));
+    }
+}
 
-        tokio::select! {
-            _ = tx.closed() => break,
-            res = waiter => {
-                match res {
-                    Ok(next_job) => job = Some(next_job),
-                    Err(_) => break,
-                }
+async fn ctl(opt: &Opt, command: ControlCommand) {
+    let path = opt
+        .control_socket
+        .as_ref()
+        .expect("--control-socket or ControlSocket in fishnet.ini required");
+    match control::socket::request(path, command).await {
+        Ok(response) => {
+            print!("{}", response);
+            if response.starts_with("E: ") {
+                process::exit(1);
             }
         }
+        Err(err) => {
+            eprintln!("E: Failed to send command to {:?}: {}", path, err);
+            process::exit(1);
+        }
     }
+}
 
-    if let Some((sf, join_handle)) = engine.get_mut(EngineFlavor::Official).take() {
-        logger.debug(&format!(
-            "Worker {} waiting for standard engine to shut down",
-            i
-        ));
-        drop(sf);
-        join_handle.await.expect("join");
-    }
+async fn follow(opt: &Opt, coordinator: String, logger: &Logger) {
+    let workers = usize::from(opt.cores.unwrap_or(Cores::Auto));
+    let cpu = opt.cpu();
+    let assets = Assets::prepare(cpu, opt.enabled_engines()).expect("prepared bundled stockfish");
+    logger.info(&format!(
+        "Engine: {} (for GPLv3, run: {} license)",
+        assets.sf_name,
+        env::args().next().unwrap_or_else(|| "./fishnet".to_owned())
+    ));
+    cluster::follow(coordinator, workers, Arc::new(assets), logger.clone()).await;
+}
 
-    if let Some((sf, join_handle)) = engine.get_mut(EngineFlavor::MultiVariant).take() {
-        logger.debug(&format!(
-            "Worker {} waiting for multi-variant engine to shut down",
-            i
-        ));
-        drop(sf);
-        join_handle.await.expect("join");
-    }
+#[cfg(feature = "grpc")]
+async fn serve_grpc(opt: &Opt, bind: SocketAddr, logger: &Logger) {
+    grpc::serve(opt, bind, logger).await;
+}
 
-    logger.debug(&format!("Stopped worker {}", i));
-    drop(tx);
+#[cfg(not(feature = "grpc"))]
+async fn serve_grpc(_opt: &Opt, _bind: SocketAddr, logger: &Logger) {
+    logger.error("This build does not include gRPC support. Rebuild with --features grpc.");
+    process::exit(1);
 }
 
 fn license(logger: &Logger) {
@@ -441,28 +479,3 @@ fn restart_process(current_exe: PathBuf, logger: &Logger) {
         .spawn()
         .expect("restarted");
 }
-
-async fn auto_update(
-    verbose: bool,
-    logger: Logger,
-) -> Result<self_update::Status, self_update::errors::Error> {
-    tokio::task::spawn_blocking(move || {
-        if verbose {
-            logger.headline("Updating ...");
-        }
-        logger.fishnet_info("Checking for updates (--auto-update) ...");
-        self_update::backends::github::Update::configure()
-            .repo_owner("lichess-org")
-            .repo_name("fishnet")
-            .bin_name("fishnet")
-            .show_output(verbose)
-            .show_download_progress(atty::is(Stream::Stdout) && verbose)
-            .current_version(env!("CARGO_PKG_VERSION"))
-            .no_confirm(true)
-            .build()
-            .expect("self_update config")
-            .update()
-    })
-    .await
-    .expect("spawn blocking update")
-}
@@ -0,0 +1,243 @@
+use std::{
+    cmp::{max, min},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+
+use tokio::time;
+
+use crate::logger::Logger;
+
+/// Check available memory this often.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Hash table size of Stockfish and Fairy-Stockfish, if not configured.
+pub const DEFAULT_HASH_MIB: u64 = 16;
+
+/// Hash tables shrink at most to 1/2^MAX_SHIFT of the configured size.
+const MAX_SHIFT: u32 = 10;
+
+/// Memory of the host or cgroup, whichever is more restrictive.
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryInfo {
+    /// Bytes.
+    pub total: u64,
+    /// Bytes that can be allocated without swapping or hitting the cgroup
+    /// limit.
+    pub available: u64,
+}
+
+impl MemoryInfo {
+    /// Hash tables may take up to half of the memory. The rest is left for
+    /// NNUE networks, fishnet and other processes.
+    fn hash_budget(&self) -> u64 {
+        self.total / 2
+    }
+
+    fn is_low(&self) -> bool {
+        self.available < self.total / 10
+    }
+
+    fn is_plenty(&self) -> bool {
+        self.available > self.total / 4
+    }
+}
+
+/// Memory that can be used, or `None` if it can not be determined.
+pub fn memory_info() -> Option<MemoryInfo> {
+    imp::memory_info(None)
+}
+
+/// Scales the configured hash table sizes of all engine processes, so that
+/// they fit into memory, and shrinks them further while the host is under
+/// memory pressure. Engine processes pick up the size before each search.
+#[derive(Debug, Default)]
+pub struct HashSizer {
+    /// Hash tables are 1/2^shift of the configured size.
+    shift: AtomicU32,
+}
+
+impl HashSizer {
+    /// Starts watching memory. `total_hash_mib` is the configured hash
+    /// table size of all engine processes together, and `cgroup` their
+    /// cgroup, if it is not the one of this process. Stops once the sizer
+    /// is no longer used.
+    pub fn spawn(total_hash_mib: u64, cgroup: Option<PathBuf>, logger: Logger) -> Arc<HashSizer> {
+        let sizer = Arc::new(HashSizer::default());
+        let info = match imp::memory_info(cgroup.as_deref()) {
+            Some(info) => info,
+            None => {
+                logger.debug("Could not read available memory. Using fixed hash table sizes.");
+                return sizer;
+            }
+        };
+        let fit = fitting_shift(total_hash_mib, &info);
+        if fit > 0 {
+            logger.warn(&format!(
+                "Hash tables of {} MiB in total do not fit into {} MiB of memory. Using 1/{} of the configured sizes.",
+                total_hash_mib,
+                info.total >> 20,
+                1u64 << fit
+            ));
+        }
+        sizer.shift.store(fit, Ordering::Relaxed);
+        tokio::spawn(watch(
+            Arc::downgrade(&sizer),
+            total_hash_mib,
+            cgroup,
+            logger,
+        ));
+        sizer
+    }
+
+    /// Hash table size in MiB for an engine process, given the configured
+    /// size. `None` if the engine default can be used.
+    pub fn hash_mib(&self, configured: Option<u64>) -> Option<u64> {
+        match self.shift.load(Ordering::Relaxed) {
+            0 => configured,
+            shift => Some(max(1, configured.unwrap_or(DEFAULT_HASH_MIB) >> shift)),
+        }
+    }
+}
+
+/// Smallest shift, so that the hash tables fit into the budget.
+fn fitting_shift(total_hash_mib: u64, info: &MemoryInfo) -> u32 {
+    let mut shift = 0;
+    while shift < MAX_SHIFT && (total_hash_mib << 20) >> shift > info.hash_budget() {
+        shift += 1;
+    }
+    shift
+}
+
+async fn watch(
+    sizer: Weak<HashSizer>,
+    total_hash_mib: u64,
+    cgroup: Option<PathBuf>,
+    logger: Logger,
+) {
+    let mut interval = time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let sizer = match sizer.upgrade() {
+            Some(sizer) => sizer,
+            None => break,
+        };
+        let info = match imp::memory_info(cgroup.as_deref()) {
+            Some(info) => info,
+            None => continue,
+        };
+        let fit = fitting_shift(total_hash_mib, &info);
+        let shift = sizer.shift.load(Ordering::Relaxed);
+        // Shrink quickly, but grow back only one step at a time.
+        let new_shift = if info.is_low() {
+            min(shift + 1, MAX_SHIFT)
+        } else if info.is_plenty() && shift > fit {
+            shift - 1
+        } else {
+            shift
+        };
+        let new_shift = max(new_shift, fit);
+        if new_shift > shift {
+            logger.warn(&format!(
+                "Memory is low ({} MiB available). Shrinking hash tables to 1/{} of the configured sizes.",
+                info.available >> 20,
+                1u64 << new_shift
+            ));
+        } else if new_shift < shift {
+            logger.info(&format!(
+                "Memory is available again ({} MiB). Growing hash tables to 1/{} of the configured sizes.",
+                info.available >> 20,
+                1u64 << new_shift
+            ));
+        }
+        sizer.shift.store(new_shift, Ordering::Relaxed);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{
+        cmp::min,
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use super::MemoryInfo;
+
+    /// Reads a field of /proc/meminfo, in bytes.
+    fn meminfo(meminfo: &str, field: &str) -> Option<u64> {
+        let kib: u64 = meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))?
+            .trim()
+            .strip_suffix("kB")?
+            .trim()
+            .parse()
+            .ok()?;
+        Some(kib << 10)
+    }
+
+    fn read_u64(path: &Path) -> Option<u64> {
+        fs::read_to_string(path).ok()?.trim().parse().ok()
+    }
+
+    fn inactive_file(dir: &Path) -> Option<u64> {
+        fs::read_to_string(dir.join("memory.stat"))
+            .ok()?
+            .lines()
+            .find_map(|line| line.strip_prefix("inactive_file "))?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    fn cgroup_dir() -> Option<PathBuf> {
+        let cgroup = fs::read_to_string("/proc/self/cgroup").ok()?;
+        let path = cgroup.lines().find_map(|line| line.strip_prefix("0::"))?;
+        Some(Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/')))
+    }
+
+    /// Also considers the memory limit of the cgroup (of this process, if
+    /// not given) and its ancestors (cgroup v2), for example in containers.
+    pub fn memory_info(cgroup: Option<&Path>) -> Option<MemoryInfo> {
+        let meminfo_contents = fs::read_to_string("/proc/meminfo").ok()?;
+        let mut info = MemoryInfo {
+            total: meminfo(&meminfo_contents, "MemTotal")?,
+            available: meminfo(&meminfo_contents, "MemAvailable")?,
+        };
+        if let Some(mut dir) = cgroup.map(Path::to_owned).or_else(cgroup_dir) {
+            let root = Path::new("/sys/fs/cgroup");
+            while dir.starts_with(root) {
+                // memory.max is "max" without a limit.
+                if let Some(limit) = read_u64(&dir.join("memory.max")) {
+                    // Inactive page cache is reclaimed before hitting the
+                    // limit.
+                    let current = read_u64(&dir.join("memory.current"))
+                        .unwrap_or(0)
+                        .saturating_sub(inactive_file(&dir).unwrap_or(0));
+                    info.total = min(info.total, limit);
+                    info.available = min(info.available, limit.saturating_sub(current));
+                }
+                if !dir.pop() {
+                    break;
+                }
+            }
+        }
+        Some(info)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::path::Path;
+
+    use super::MemoryInfo;
+
+    pub fn memory_info(_cgroup: Option<&Path>) -> Option<MemoryInfo> {
+        None
+    }
+}
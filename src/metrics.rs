@@ -0,0 +1,307 @@
+use std::{
+    fmt::Write as _,
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Process wide metrics, rendered in the Prometheus text format by the
+/// status server.
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    pub batches_acquired: Counter,
+    pub batches_submitted: Counter,
+    pub batches_failed: Counter,
+    pub batches_cancelled: Counter,
+    pub positions_analyzed: Counter,
+    pub nodes_searched: Counter,
+    pub positions_looked_up: Counter,
+    pub engine_spawns: Counter,
+    pub engine_restarts: Counter,
+    pub positions_retried: Counter,
+    pub engine_anomalies: Counter,
+    pub api_errors: Counter,
+    pub positions_verified: Counter,
+    pub verify_mismatches: Counter,
+    pub move_budget_exceeded: Counter,
+    pub positions_warm_hash: Counter,
+    pub positions_cold_hash: Counter,
+    pub depth_warm_hash: Counter,
+    pub depth_cold_hash: Counter,
+    pub clock_skew: Gauge,
+    pub queue_pending: Gauge,
+    pub queue_cores: Gauge,
+    pub api_latency: Histogram,
+    pub queue_wait: Histogram,
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            batches_acquired: Counter::new(),
+            batches_submitted: Counter::new(),
+            batches_failed: Counter::new(),
+            batches_cancelled: Counter::new(),
+            positions_analyzed: Counter::new(),
+            nodes_searched: Counter::new(),
+            positions_looked_up: Counter::new(),
+            engine_spawns: Counter::new(),
+            engine_restarts: Counter::new(),
+            positions_retried: Counter::new(),
+            engine_anomalies: Counter::new(),
+            api_errors: Counter::new(),
+            positions_verified: Counter::new(),
+            verify_mismatches: Counter::new(),
+            move_budget_exceeded: Counter::new(),
+            positions_warm_hash: Counter::new(),
+            positions_cold_hash: Counter::new(),
+            depth_warm_hash: Counter::new(),
+            depth_cold_hash: Counter::new(),
+            clock_skew: Gauge::new(),
+            queue_pending: Gauge::new(),
+            queue_cores: Gauge::new(),
+            api_latency: Histogram::new(),
+            queue_wait: Histogram::new(),
+        }
+    }
+
+    /// Counts an analysed position, to compare the depth reached with and
+    /// without the hash table of an adjacent ply.
+    pub fn record_hash(&self, warm: bool, depth: u8) {
+        if warm {
+            self.positions_warm_hash.inc();
+            self.depth_warm_hash.add(u64::from(depth));
+        } else {
+            self.positions_cold_hash.inc();
+            self.depth_cold_hash.add(u64::from(depth));
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.batches_acquired.render(
+            &mut out,
+            "fishnet_batches_acquired_total",
+            "Batches acquired from the server.",
+        );
+        self.batches_submitted.render(
+            &mut out,
+            "fishnet_batches_submitted_total",
+            "Completed batches submitted to the server.",
+        );
+        self.batches_failed.render(
+            &mut out,
+            "fishnet_batches_failed_total",
+            "Batches aborted or dropped due to errors.",
+        );
+        self.batches_cancelled.render(
+            &mut out,
+            "fishnet_batches_cancelled_total",
+            "Batches in progress that the server cancelled.",
+        );
+        self.positions_analyzed.render(
+            &mut out,
+            "fishnet_positions_analyzed_total",
+            "Positions analysed by the engines.",
+        );
+        self.nodes_searched.render(
+            &mut out,
+            "fishnet_nodes_searched_total",
+            "Nodes searched by the engines.",
+        );
+        self.positions_looked_up.render(
+            &mut out,
+            "fishnet_positions_looked_up_total",
+            "Positions answered from the opening book or cloud evaluations.",
+        );
+        self.engine_spawns.render(
+            &mut out,
+            "fishnet_engine_spawns_total",
+            "Engine processes started, including restarts after errors.",
+        );
+        self.engine_restarts.render(
+            &mut out,
+            "fishnet_engine_restarts_total",
+            "Engine processes replaced after errors, timeouts or many batches.",
+        );
+        self.positions_retried.render(
+            &mut out,
+            "fishnet_positions_retried_total",
+            "Positions replayed on a new engine process after the engine exited.",
+        );
+        self.engine_anomalies.render(
+            &mut out,
+            "fishnet_engine_anomalies_total",
+            "Suspicious engine behavior, like sudden slowdowns.",
+        );
+        self.positions_verified.render(
+            &mut out,
+            "fishnet_positions_verified_total",
+            "Positions analysed a second time to verify the evaluation.",
+        );
+        self.verify_mismatches.render(
+            &mut out,
+            "fishnet_verify_mismatches_total",
+            "Verified positions with evaluations beyond the threshold.",
+        );
+        self.move_budget_exceeded.render(
+            &mut out,
+            "fishnet_move_budget_exceeded_total",
+            "Moves submitted later than the configured latency budget.",
+        );
+        self.positions_warm_hash.render(
+            &mut out,
+            "fishnet_positions_warm_hash_total",
+            "Positions analysed with the hash table of an adjacent ply.",
+        );
+        self.positions_cold_hash.render(
+            &mut out,
+            "fishnet_positions_cold_hash_total",
+            "Positions analysed with a cleared hash table.",
+        );
+        self.depth_warm_hash.render(
+            &mut out,
+            "fishnet_depth_warm_hash_total",
+            "Sum of the depths reached with the hash table of an adjacent ply.",
+        );
+        self.depth_cold_hash.render(
+            &mut out,
+            "fishnet_depth_cold_hash_total",
+            "Sum of the depths reached with a cleared hash table.",
+        );
+        self.api_errors.render(
+            &mut out,
+            "fishnet_api_errors_total",
+            "Failed requests to the server.",
+        );
+        self.clock_skew.render(
+            &mut out,
+            "fishnet_clock_skew_seconds",
+            "Local clock minus server clock, according to the last Date header.",
+        );
+        self.queue_pending.render(
+            &mut out,
+            "fishnet_queue_pending_positions",
+            "Positions of acquired batches that are not yet analysed.",
+        );
+        self.queue_cores.render(
+            &mut out,
+            "fishnet_queue_cores",
+            "Cores currently used to analyse positions.",
+        );
+        self.api_latency.render(
+            &mut out,
+            "fishnet_api_request_duration_seconds",
+            "Duration of requests to the server.",
+        );
+        self.queue_wait.render(
+            &mut out,
+            "fishnet_queue_wait_seconds",
+            "Time workers spent waiting for the next position.",
+        );
+        out
+    }
+}
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, self.0.load(Ordering::Relaxed));
+    }
+}
+
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Gauge {
+        Gauge(AtomicI64::new(0))
+    }
+
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{} {}", name, self.get());
+    }
+}
+
+/// Upper bounds of the histogram buckets, in milliseconds.
+const BUCKETS: [u64; 10] = [10, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 60000];
+
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_millis: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        #[allow(clippy::declare_interior_mutable_const)] // Only used for initialization
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Histogram {
+            buckets: [ZERO; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_millis: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let millis = duration.as_millis() as u64;
+        for (bucket, &bound) in self.buckets.iter().zip(BUCKETS.iter()) {
+            if millis <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_millis.fetch_add(millis, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bucket, &bound) in self.buckets.iter().zip(BUCKETS.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound as f64 / 1000.0,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            self.sum_millis.load(Ordering::Relaxed) as f64 / 1000.0
+        );
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
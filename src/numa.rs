@@ -0,0 +1,128 @@
+#[cfg(target_os = "linux")]
+use std::fs;
+
+use tokio::process::Command;
+
+#[cfg(target_os = "linux")]
+use crate::affinity;
+
+/// A NUMA node, with the logical cores of it that this process may use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub id: usize,
+    pub cores: Vec<usize>,
+}
+
+/// Highest supported node id, plus one.
+#[cfg(target_os = "linux")]
+const MAX_NODES: usize = 1024;
+
+/// Detects NUMA nodes from `/sys/devices/system/node`. Returns nothing
+/// unless there are at least two nodes with usable cores, so that single
+/// socket machines are not affected at all.
+#[cfg(target_os = "linux")]
+pub fn detect() -> Vec<Node> {
+    let allowed = affinity::allowed();
+    let mut nodes = Vec::new();
+    let entries = match fs::read_dir("/sys/devices/system/node") {
+        Ok(entries) => entries,
+        Err(_) => return nodes,
+    };
+    for entry in entries.flatten() {
+        let id = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("node"))
+            .and_then(|id| id.parse::<usize>().ok())
+        {
+            Some(id) if id < MAX_NODES => id,
+            _ => continue,
+        };
+        let cores: Vec<usize> = match fs::read_to_string(entry.path().join("cpulist")) {
+            Ok(cpulist) => parse_cpulist(&cpulist)
+                .into_iter()
+                .filter(|core| {
+                    allowed
+                        .as_ref()
+                        .map_or(true, |allowed| allowed.contains(core))
+                })
+                .collect(),
+            Err(_) => continue,
+        };
+        if !cores.is_empty() {
+            nodes.push(Node { id, cores });
+        }
+    }
+    nodes.sort_by_key(|node| node.id);
+    if nodes.len() < 2 {
+        nodes.clear();
+    }
+    nodes
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn detect() -> Vec<Node> {
+    Vec::new()
+}
+
+/// Parses a kernel cpulist, for example `0-15,32-47`.
+#[cfg(target_os = "linux")]
+fn parse_cpulist(cpulist: &str) -> Vec<usize> {
+    let mut cores = Vec::new();
+    for range in cpulist.trim().split(',').filter(|range| !range.is_empty()) {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.parse::<usize>(), end.parse::<usize>()),
+            None => (range.parse::<usize>(), range.parse::<usize>()),
+        };
+        if let (Ok(start), Ok(end)) = (start, end) {
+            cores.extend(start..=end);
+        }
+    }
+    cores
+}
+
+/// Makes the process started by the command allocate its memory, in
+/// particular the hash table, on the given node. The node is preferred
+/// rather than strictly bound, so that an exhausted node falls back to
+/// remote memory instead of getting the engine killed.
+#[cfg(target_os = "linux")]
+pub fn bind<'a>(command: &'a mut Command, node: &Node) -> &'a mut Command {
+    const MPOL_PREFERRED: libc::c_int = 1;
+    const BITS: usize = libc::c_ulong::BITS as usize;
+    let mut mask: [libc::c_ulong; MAX_NODES / BITS] = [0; MAX_NODES / BITS];
+    mask[node.id / BITS] |= 1 << (node.id % BITS);
+    unsafe {
+        // Safety: The closure is run in a fork, and only makes a system call
+        // with a mask that was prepared before.
+        command.pre_exec(move || {
+            if libc::syscall(
+                libc::SYS_set_mempolicy,
+                MPOL_PREFERRED,
+                mask.as_ptr(),
+                MAX_NODES as libc::c_ulong,
+            ) == -1
+            {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind<'a>(command: &'a mut Command, _node: &Node) -> &'a mut Command {
+    command
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpulist() {
+        assert_eq!(parse_cpulist("0-3,8,10-11\n"), [0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpulist(""), Vec::<usize>::new());
+        assert_eq!(parse_cpulist("x,2"), [2]);
+    }
+}
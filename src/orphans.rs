@@ -0,0 +1,145 @@
+#[cfg(target_os = "linux")]
+use std::{fs, path::Path};
+#[cfg(windows)]
+use std::{
+    io, mem,
+    sync::atomic::{AtomicIsize, Ordering},
+};
+
+use tokio::process::Child;
+
+#[cfg(target_os = "linux")]
+use crate::assets;
+
+/// Job object of this process, once created.
+#[cfg(windows)]
+static JOB: AtomicIsize = AtomicIsize::new(0);
+
+#[cfg(windows)]
+fn job() -> io::Result<isize> {
+    use windows_sys::Win32::System::JobObjects::{
+        CreateJobObjectW, JobObjectExtendedLimitInformation, SetInformationJobObject,
+        JOBOBJECT_EXTENDED_LIMIT_INFORMATION, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+    };
+
+    let job = JOB.load(Ordering::Acquire);
+    if job != 0 {
+        return Ok(job);
+    }
+    unsafe {
+        // Safety: The job handle is checked, and the limit information is
+        // plain data that is valid when zeroed.
+        let job = CreateJobObjectW(std::ptr::null(), std::ptr::null());
+        if job == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+        info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        if SetInformationJobObject(
+            job,
+            JobObjectExtendedLimitInformation,
+            &info as *const _ as *const _,
+            mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+        ) == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        // The handle is intentionally never closed. The system closes it
+        // when this process exits, for whatever reason, and then kills all
+        // processes in the job.
+        match JOB.compare_exchange(0, job, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => Ok(job),
+            Err(existing) => Ok(existing),
+        }
+    }
+}
+
+/// Assigns the engine process to a job object that kills it when this
+/// process exits, even if it crashes.
+#[cfg(windows)]
+pub fn contain(child: &Child) -> io::Result<()> {
+    use windows_sys::Win32::System::JobObjects::AssignProcessToJobObject;
+
+    let handle = match child.raw_handle() {
+        Some(handle) => handle as isize,
+        None => return Ok(()), // Already exited
+    };
+    let job = job()?;
+    unsafe {
+        // Safety: Both handles are valid while the child is not reaped.
+        if AssignProcessToJobObject(job, handle) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// On Unix, engine processes are tied to this process when they are
+/// spawned, where supported (see `stockfish::new_process_group()`).
+#[cfg(not(windows))]
+pub fn contain(_child: &Child) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Kills engine processes that were left behind by a previous fishnet
+/// process, for example after it was killed with SIGKILL, so that they do
+/// not keep cores busy. These run an executable from the asset directories,
+/// and were adopted by init or a subreaper like the systemd user manager.
+/// Engines of other running fishnet processes are not affected. Returns the
+/// number of killed processes.
+#[cfg(target_os = "linux")]
+pub fn reap() -> usize {
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    let mut killed = 0;
+    for entry in entries.flatten() {
+        let pid: libc::pid_t = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // Fails for processes of other users.
+        let exe = match fs::read_link(entry.path().join("exe")) {
+            Ok(exe) => exe,
+            Err(_) => continue,
+        };
+        // Executables of old versions may have been removed already.
+        let exe = match exe.to_str() {
+            Some(exe) => Path::new(exe.trim_end_matches(" (deleted)")).to_owned(),
+            None => continue,
+        };
+        if !assets::is_engine_path(&exe) {
+            continue;
+        }
+        if parent(pid).map_or(false, is_adopting) {
+            unsafe {
+                // Safety: Sending a signal has no memory safety implications.
+                if libc::kill(pid, libc::SIGKILL) == 0 {
+                    killed += 1;
+                }
+            }
+        }
+    }
+    killed
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reap() -> usize {
+    0
+}
+
+#[cfg(target_os = "linux")]
+fn parent(pid: libc::pid_t) -> Option<libc::pid_t> {
+    // pid (comm) state ppid ...
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[cfg(target_os = "linux")]
+fn is_adopting(pid: libc::pid_t) -> bool {
+    pid == 1
+        || fs::read_to_string(format!("/proc/{}/comm", pid))
+            .map_or(false, |comm| comm.trim_end() == "systemd")
+}
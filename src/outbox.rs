@@ -0,0 +1,323 @@
+use std::{
+    collections::VecDeque,
+    fs, future, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_with::{serde_as, DisplayFromStr};
+use tokio::time;
+
+use crate::{api::BatchId, configure::Endpoint, logger::Logger, util::RandomizedBackoff};
+
+/// Failed submissions kept in memory. Further ones are spilled to disk, if
+/// a directory is configured.
+const MEMORY_CAPACITY: usize = 16;
+
+/// Failed submissions kept on disk. The oldest are given up beyond that.
+const DISK_CAPACITY: usize = 256;
+
+/// Give up on a submission after this long. The server will have given the
+/// batch to someone else by then.
+const MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Maximum delay between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Analysis that could not be submitted, for example because of a network
+/// error or a 502 from the server.
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Submission {
+    endpoint: String,
+    #[serde_as(as = "DisplayFromStr")]
+    pub batch_id: BatchId,
+    /// Request body, without the fishnet section with the key.
+    pub body: Value,
+    /// Seconds since the Unix epoch.
+    first_failed_at: u64,
+    pub attempts: u32,
+}
+
+impl Submission {
+    pub fn new(endpoint: &Endpoint, batch_id: BatchId, body: Value) -> Submission {
+        Submission {
+            endpoint: endpoint.url.to_string(),
+            batch_id,
+            body,
+            first_failed_at: unix_now(),
+            attempts: 0,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        unix_now().saturating_sub(self.first_failed_at) > MAX_AGE.as_secs()
+    }
+}
+
+/// Bounded buffer of failed analysis submissions, which are retried with
+/// exponential backoff, instead of losing the analysis. Submissions beyond
+/// what is kept in memory are spilled to disk, and survive a restart.
+pub struct Outbox {
+    endpoint: String,
+    memory: VecDeque<Submission>,
+    dir: Option<PathBuf>,
+    /// Batches spilled to `dir`, oldest first.
+    spilled: VecDeque<BatchId>,
+    backoff: RandomizedBackoff,
+    retry_at: Instant,
+}
+
+impl Outbox {
+    pub fn new(endpoint: &Endpoint) -> Outbox {
+        Outbox {
+            endpoint: endpoint.url.to_string(),
+            memory: VecDeque::new(),
+            dir: None,
+            spilled: VecDeque::new(),
+            backoff: RandomizedBackoff::new(MAX_BACKOFF),
+            retry_at: Instant::now(),
+        }
+    }
+
+    /// Spills to this directory, and picks up submissions spilled for the
+    /// same endpoint by a previous run.
+    pub fn spill_dir(mut self, dir: PathBuf, logger: &Logger) -> Outbox {
+        if let Err(err) = fs::create_dir_all(&dir) {
+            logger.error(&format!(
+                "Failed to create directory {:?} for failed submissions: {}. Keeping them in memory only.",
+                dir, err
+            ));
+            return self;
+        }
+        match self.recover(&dir) {
+            Ok(recovered) if !recovered.is_empty() => {
+                logger.fishnet_info(&format!(
+                    "Retrying {} submissions that failed in a previous run",
+                    recovered.len()
+                ));
+                self.spilled = recovered.into();
+            }
+            Ok(_) => (),
+            Err(err) => logger.error(&format!(
+                "Failed to read failed submissions from {:?}: {}",
+                dir, err
+            )),
+        }
+        self.dir = Some(dir);
+        self
+    }
+
+    fn recover(&self, dir: &Path) -> io::Result<Vec<BatchId>> {
+        let mut recovered = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            // Submissions for other endpoints are left for their instances.
+            if let Some(submission) = read(&path).filter(|s| s.endpoint == self.endpoint) {
+                recovered.push((submission.first_failed_at, submission.batch_id));
+            }
+        }
+        recovered.sort_by_key(|&(first_failed_at, _)| first_failed_at);
+        Ok(recovered
+            .into_iter()
+            .map(|(_, batch_id)| batch_id)
+            .collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.memory.len() + self.spilled.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Schedules a retry of a failed submission.
+    pub fn push(&mut self, mut submission: Submission, logger: &Logger) {
+        submission.attempts += 1;
+        self.retry_at = Instant::now() + self.backoff.next();
+        // Progress reports are superseded by later submissions of the same
+        // batch.
+        self.remove(submission.batch_id);
+        if self.memory.len() < MEMORY_CAPACITY {
+            self.memory.push_back(submission);
+            return;
+        }
+        if let Some(ref dir) = self.dir {
+            if self.spilled.len() >= DISK_CAPACITY {
+                if let Some(oldest) = self.spilled.pop_front() {
+                    logger.error(&format!(
+                        "Too many failed submissions. Giving up on batch {}.",
+                        oldest
+                    ));
+                    let _ = fs::remove_file(spill_path(dir, oldest));
+                }
+            }
+            match write(dir, &submission) {
+                Ok(()) => {
+                    self.spilled.push_back(submission.batch_id);
+                    return;
+                }
+                Err(err) => logger.error(&format!(
+                    "Failed to spill submission of batch {} to {:?}: {}",
+                    submission.batch_id, dir, err
+                )),
+            }
+        }
+        if let Some(oldest) = self.memory.pop_front() {
+            logger.error(&format!(
+                "Too many failed submissions. Giving up on batch {}.",
+                oldest.batch_id
+            ));
+        }
+        self.memory.push_back(submission);
+    }
+
+    /// Waits until the next submission is due for a retry. Pending forever
+    /// if there is none.
+    pub async fn next(&mut self, logger: &Logger) -> Submission {
+        if self.is_empty() {
+            future::pending::<()>().await;
+        }
+        time::sleep_until(self.retry_at.into()).await;
+        while let Some(submission) = self.pop(logger) {
+            if submission.is_expired() {
+                logger.error(&format!(
+                    "Giving up on submitting batch {} after {} attempts.",
+                    submission.batch_id, submission.attempts
+                ));
+                continue;
+            }
+            return submission;
+        }
+        future::pending().await
+    }
+
+    /// A submission of the batch went through. Pending ones, like progress
+    /// reports that failed before, must not be retried after it.
+    pub fn remove(&mut self, batch_id: BatchId) {
+        self.memory.retain(|pending| pending.batch_id != batch_id);
+        if let Some(ref dir) = self.dir {
+            if self.spilled.contains(&batch_id) {
+                self.spilled.retain(|&spilled| spilled != batch_id);
+                let _ = fs::remove_file(spill_path(dir, batch_id));
+            }
+        }
+    }
+
+    /// A retry went through. Retries the remaining submissions right away.
+    pub fn succeeded(&mut self) {
+        self.backoff.reset();
+        self.retry_at = Instant::now();
+    }
+
+    fn pop(&mut self, logger: &Logger) -> Option<Submission> {
+        if let Some(submission) = self.memory.pop_front() {
+            return Some(submission);
+        }
+        let dir = self.dir.as_ref()?;
+        while let Some(batch_id) = self.spilled.pop_front() {
+            let path = spill_path(dir, batch_id);
+            let submission = read(&path);
+            if let Err(err) = fs::remove_file(&path) {
+                logger.warn(&format!("Failed to remove {:?}: {}", path, err));
+            }
+            if submission.is_some() {
+                return submission;
+            }
+        }
+        None
+    }
+
+    /// Spills all submissions in memory, so that they are retried by the
+    /// next run.
+    pub fn persist(&mut self, logger: &Logger) {
+        let dir = match self.dir {
+            Some(ref dir) => dir,
+            None => {
+                if !self.memory.is_empty() {
+                    logger.error(&format!(
+                        "Giving up on {} failed submissions.",
+                        self.memory.len()
+                    ));
+                }
+                return;
+            }
+        };
+        for submission in self.memory.drain(..) {
+            if let Err(err) = write(dir, &submission) {
+                logger.error(&format!(
+                    "Failed to spill submission of batch {} to {:?}: {}",
+                    submission.batch_id, dir, err
+                ));
+            }
+        }
+    }
+}
+
+fn spill_path(dir: &Path, batch_id: BatchId) -> PathBuf {
+    dir.join(format!("{}.json", batch_id))
+}
+
+fn write(dir: &Path, submission: &Submission) -> io::Result<()> {
+    // Replace the file at once, so that a crash does not leave a partial
+    // submission.
+    let path = spill_path(dir, submission.batch_id);
+    let tmp = path.with_extension("tmp");
+    fs::write(
+        &tmp,
+        serde_json::to_vec(submission).expect("serialize submission"),
+    )?;
+    fs::rename(&tmp, &path)
+}
+
+fn read(path: &Path) -> Option<Submission> {
+    serde_json::from_slice(&fs::read(path).ok()?).ok()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::configure::{FormatOpt, Verbose};
+
+    #[test]
+    fn test_remove_after_success() {
+        let logger = Logger::new(Verbose::default(), FormatOpt::default(), false);
+        let endpoint = Endpoint::default();
+        let dir = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::new(&endpoint).spill_dir(dir.path().to_owned(), &logger);
+
+        // Failed progress reports, enough to spill the last one to disk.
+        let batch_ids: Vec<BatchId> = (0..=MEMORY_CAPACITY)
+            .map(|i| format!("batch{}", i).parse().unwrap())
+            .collect();
+        for &batch_id in &batch_ids {
+            let report = Submission::new(&endpoint, batch_id, json!({ "partial": true }));
+            outbox.push(report, &logger);
+        }
+        assert_eq!(outbox.len(), MEMORY_CAPACITY + 1);
+        let spilled = spill_path(dir.path(), batch_ids[MEMORY_CAPACITY]);
+        assert!(spilled.exists());
+
+        // The complete analysis of these batches went through directly.
+        outbox.remove(batch_ids[0]);
+        outbox.remove(batch_ids[MEMORY_CAPACITY]);
+        assert_eq!(outbox.len(), MEMORY_CAPACITY - 1);
+        assert!(!spilled.exists());
+        assert!(outbox.memory.iter().all(|s| s.batch_id != batch_ids[0]));
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt;
+
+use shakmaty::{
+    fen::fen, san::SanPlus, uci::Uci, variant::VariantPosition, Chess, Color, Outcome, Setup as _,
+};
+
+use crate::protocol::{LichessVariant, Score};
+
+/// Recommended maximum line length of the PGN export format.
+const MAX_LINE_LENGTH: usize = 80;
+
+/// A move of the main line, with optional engine annotations.
+#[derive(Debug, Clone)]
+pub struct AnnotatedMove {
+    pub uci: Uci,
+    /// Evaluation of the position after the move, as reported by the engine
+    /// (from the point of view of the side to move).
+    pub eval: Option<Score>,
+    /// Alternative line, starting in the position before the move. Written
+    /// as a variation if not empty.
+    pub variation: Vec<Uci>,
+}
+
+/// A game with engine annotations, written as PGN with `[%eval]` comments,
+/// as understood by lichess and most chess GUIs.
+#[derive(Debug, Clone)]
+pub struct AnnotatedGame {
+    /// Tag pairs, in order. `Variant`, `SetUp`, `FEN` and `Result` are added
+    /// automatically.
+    pub headers: Vec<(String, String)>,
+    pub variant: LichessVariant,
+    pub root: VariantPosition,
+    pub moves: Vec<AnnotatedMove>,
+    /// Result of a finished game, or `None` for `*`.
+    pub outcome: Option<Outcome>,
+}
+
+/// Evaluation after playing the best move, from the point of view of the
+/// opponent.
+pub fn after_best_move(score: Score) -> Score {
+    match score {
+        Score::Cp(cp) => Score::Cp(-cp),
+        Score::Mate(mate) if mate > 0 => Score::Mate(-(mate - 1)),
+        Score::Mate(mate) => Score::Mate(-mate),
+    }
+}
+
+fn variant_name(variant: LichessVariant) -> Option<&'static str> {
+    Some(match variant {
+        LichessVariant::Standard | LichessVariant::FromPosition => return None,
+        LichessVariant::Antichess => "Antichess",
+        LichessVariant::Atomic => "Atomic",
+        LichessVariant::Chess960 => "Chess960",
+        LichessVariant::Crazyhouse => "Crazyhouse",
+        LichessVariant::Horde => "Horde",
+        LichessVariant::KingOfTheHill => "King of the Hill",
+        LichessVariant::RacingKings => "Racing Kings",
+        LichessVariant::ThreeCheck => "Three-check",
+    })
+}
+
+/// Parses the value of a Variant tag, as written by lichess, ignoring case.
+pub fn variant_from_name(name: &str) -> Option<LichessVariant> {
+    Some(match name.to_ascii_lowercase().as_str() {
+        "standard" => LichessVariant::Standard,
+        "from position" => LichessVariant::FromPosition,
+        "antichess" => LichessVariant::Antichess,
+        "atomic" => LichessVariant::Atomic,
+        "chess960" => LichessVariant::Chess960,
+        "crazyhouse" => LichessVariant::Crazyhouse,
+        "horde" => LichessVariant::Horde,
+        "king of the hill" => LichessVariant::KingOfTheHill,
+        "racing kings" => LichessVariant::RacingKings,
+        "three-check" => LichessVariant::ThreeCheck,
+        _ => return None,
+    })
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Formats a score from the point of view of `turn` as seen from white.
+/// Checkmated positions have no evaluation.
+fn eval(score: Score, turn: Color) -> Option<String> {
+    let sign = if turn == Color::White { 1 } else { -1 };
+    match score {
+        Score::Cp(cp) => Some(format!("{:.2}", (sign * cp) as f64 / 100.0)),
+        Score::Mate(0) => None,
+        Score::Mate(mate) => Some(format!("#{}", sign * mate)),
+    }
+}
+
+fn move_number(pos: &VariantPosition) -> String {
+    if pos.turn() == Color::White {
+        format!("{}.", pos.fullmoves())
+    } else {
+        format!("{}...", pos.fullmoves())
+    }
+}
+
+/// Moves of a variation, each as a token. Stops at the first illegal move.
+fn variation(mut pos: VariantPosition, line: &[Uci]) -> Vec<String> {
+    let mut tokens = Vec::new();
+    for (i, uci) in line.iter().enumerate() {
+        let m = match uci.to_move(&pos) {
+            Ok(m) => m,
+            Err(_) => break,
+        };
+        if i == 0 || pos.turn() == Color::White {
+            tokens.push(move_number(&pos));
+        }
+        tokens.push(SanPlus::from_move_and_play_unchecked(&mut pos, &m).to_string());
+    }
+    if let Some(first) = tokens.first_mut() {
+        first.insert(0, '(');
+    }
+    if let Some(last) = tokens.last_mut() {
+        last.push(')');
+    }
+    tokens
+}
+
+/// Writes tokens, wrapping lines before they get too long.
+struct Movetext<'a, 'b> {
+    f: &'a mut fmt::Formatter<'b>,
+    line_length: usize,
+}
+
+impl Movetext<'_, '_> {
+    fn push(&mut self, token: &str) -> fmt::Result {
+        if self.line_length > 0 && self.line_length + 1 + token.len() > MAX_LINE_LENGTH {
+            self.f.write_str("\n")?;
+            self.line_length = 0;
+        }
+        if self.line_length > 0 {
+            self.f.write_str(" ")?;
+            self.line_length += 1;
+        }
+        self.line_length += token.len();
+        self.f.write_str(token)
+    }
+}
+
+impl fmt::Display for AnnotatedGame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in &self.headers {
+            writeln!(f, "[{} \"{}\"]", name, escape(value))?;
+        }
+        if let Some(name) = variant_name(self.variant) {
+            writeln!(f, "[Variant \"{}\"]", name)?;
+        }
+        let root_fen = fen(&self.root);
+        if root_fen != fen(&Chess::default()) {
+            writeln!(f, "[SetUp \"1\"]")?;
+            writeln!(f, "[FEN \"{}\"]", root_fen)?;
+        }
+        let result = self
+            .outcome
+            .map_or_else(|| "*".to_owned(), |outcome| outcome.to_string());
+        writeln!(f, "[Result \"{}\"]", result)?;
+        writeln!(f)?;
+
+        let mut movetext = Movetext { f, line_length: 0 };
+        let mut pos = self.root.clone();
+        let mut needs_number = true;
+        for annotated in &self.moves {
+            let before = pos.clone();
+            let m = match annotated.uci.to_move(&pos) {
+                Ok(m) => m,
+                Err(_) => break,
+            };
+            if needs_number || pos.turn() == Color::White {
+                movetext.push(&move_number(&pos))?;
+            }
+            let san = SanPlus::from_move_and_play_unchecked(&mut pos, &m);
+            movetext.push(&san.to_string())?;
+            needs_number = false;
+
+            if let Some(eval) = annotated.eval.and_then(|score| eval(score, pos.turn())) {
+                movetext.push(&format!("{{ [%eval {}] }}", eval))?;
+                needs_number = true;
+            }
+            for token in variation(before, &annotated.variation) {
+                movetext.push(&token)?;
+                needs_number = true;
+            }
+        }
+        movetext.push(&result)?;
+        writeln!(movetext.f)
+    }
+}
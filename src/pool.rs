@@ -0,0 +1,175 @@
+use std::sync::Arc;
+
+use shakmaty::fen::fen;
+use tokio::{
+    sync::{mpsc, oneshot, Mutex},
+    task::JoinHandle,
+};
+
+use crate::{
+    assets::{Assets, ByEngineFlavor, EngineFlavor},
+    ipc::{EarlyStop, FailureReason, Position, PositionFailed, PositionResponse, MAX_ATTEMPTS},
+    logger::Logger,
+    metrics::METRICS,
+    stockfish::{self, StockfishInit, StockfishStub},
+    util::NevermindExt as _,
+};
+
+#[derive(Debug)]
+struct PoolJob {
+    position: Position,
+    callback: oneshot::Sender<PositionResponse>,
+}
+
+/// Engine workers for positions that do not come from the fishnet API, for
+/// example from local files or other services. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct EnginePool {
+    tx: mpsc::Sender<PoolJob>,
+}
+
+impl EnginePool {
+    /// Starts `workers` workers, each with its own engine processes, started
+    /// on demand. The returned handle completes when all clones of the pool
+    /// are dropped and the engines have shut down.
+    pub fn spawn(
+        assets: Arc<Assets>,
+        workers: usize,
+        logger: Logger,
+    ) -> (EnginePool, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(workers);
+        let rx = Arc::new(Mutex::new(rx));
+        let handles: Vec<_> = (0..workers)
+            .map(|i| tokio::spawn(worker(i, assets.clone(), rx.clone(), logger.clone())))
+            .collect();
+        let join_handle = tokio::spawn(async move {
+            for handle in handles {
+                handle.await.expect("join");
+            }
+        });
+        (EnginePool { tx }, join_handle)
+    }
+
+    /// Waits until the position is queued, and returns the pending result.
+    /// Positions are started in the order they are submitted. Positions are
+    /// replayed on a new engine process if the engine exits, and the sender
+    /// is dropped if all attempts fail.
+    pub async fn submit(&self, position: Position) -> oneshot::Receiver<PositionResponse> {
+        let (callback, response) = oneshot::channel();
+        self.tx
+            .send(PoolJob { position, callback })
+            .await
+            .nevermind("pool gone, callback dropped with job");
+        response
+    }
+
+    pub async fn go(&self, position: Position) -> Result<PositionResponse, PositionFailed> {
+        let failed = PositionFailed {
+            attempts: MAX_ATTEMPTS,
+            ..PositionFailed::new(&position, FailureReason::EngineExited)
+        };
+        self.submit(position).await.await.map_err(|_| failed)
+    }
+}
+
+async fn worker(
+    i: usize,
+    assets: Arc<Assets>,
+    rx: Arc<Mutex<mpsc::Receiver<PoolJob>>>,
+    logger: Logger,
+) {
+    logger.debug(&format!("Started pool worker {}.", i));
+
+    let mut engine: ByEngineFlavor<Option<(StockfishStub, JoinHandle<()>)>> = ByEngineFlavor {
+        official: None,
+        multi_variant: None,
+    };
+
+    loop {
+        let job = match rx.lock().await.recv().await {
+            Some(job) => job,
+            None => break,
+        };
+
+        let flavor = job.position.flavor;
+        let context = format!("{} {:?}", fen(&job.position.root_fen), job.position.moves);
+        let mut attempts = 1;
+        loop {
+            let (mut sf, join_handle) = engine.get_mut(flavor).take().unwrap_or_else(|| {
+                let (sf, sf_actor) = stockfish::channel(
+                    assets
+                        .stockfish
+                        .get(flavor)
+                        .clone()
+                        .expect("engine flavor enabled"),
+                    StockfishInit {
+                        nnue: assets.nnue.clone(),
+                        syzygy_path: assets
+                            .syzygy_path
+                            .clone()
+                            .filter(|_| flavor == EngineFlavor::Official),
+                        variant_nets: if flavor == EngineFlavor::MultiVariant {
+                            assets.variant_nets.clone()
+                        } else {
+                            Vec::new()
+                        },
+                        external: false,
+                        early_stop: EarlyStop::default(),
+                        warm_hash: false,
+                        hash_mib: *assets.hash_mib.get(flavor),
+                        hash_sizer: assets.hash_sizer.clone(),
+                        threads: assets.threads,
+                        cores: assets.engine_cores(i),
+                        numa_node: assets.numa_node(i).cloned(),
+                        sandbox: assets.sandbox,
+                        limits: assets.engine_limits.clone(),
+                        transcript: Default::default(),
+                        trace: None,
+                    },
+                    logger.clone(),
+                );
+                (sf, tokio::spawn(sf_actor.run()))
+            });
+
+            match sf.go(job.position.clone()).await {
+                Ok(res) => {
+                    *engine.get_mut(flavor) = Some((sf, join_handle));
+                    if job.callback.send(res).is_err() {
+                        logger.debug(&format!("Pool worker {} result no longer needed", i));
+                    }
+                    break;
+                }
+                Err(failed) => {
+                    drop(sf);
+                    logger.warn(&format!(
+                        "Pool worker {} waiting for engine to shut down after error. Context: {}",
+                        i, context
+                    ));
+                    join_handle.await.expect("join");
+                    METRICS.engine_restarts.inc();
+
+                    let failed = PositionFailed { attempts, ..failed };
+                    if !failed.can_retry() {
+                        logger.error(&format!("Pool worker {} giving up on {}", i, failed));
+                        break;
+                    }
+                    logger.warn(&format!(
+                        "Pool worker {} replaying {} on a new engine",
+                        i, failed
+                    ));
+                    METRICS.positions_retried.inc();
+                    attempts += 1;
+                }
+            }
+        }
+    }
+
+    for (sf, join_handle) in [engine.official, engine.multi_variant]
+        .into_iter()
+        .flatten()
+    {
+        drop(sf);
+        join_handle.await.expect("join");
+    }
+    logger.debug(&format!("Stopped pool worker {}.", i));
+}
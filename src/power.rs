@@ -0,0 +1,174 @@
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::{sync::mpsc, time};
+
+use crate::logger::Logger;
+
+/// Check the power source this often.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PowerSource {
+    Ac,
+    Battery,
+}
+
+impl fmt::Display for PowerSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PowerSource::Ac => "AC",
+            PowerSource::Battery => "battery",
+        })
+    }
+}
+
+/// A change of the power source, recorded in the session stats.
+#[derive(Debug, Clone, Serialize)]
+pub struct PowerChange {
+    pub source: PowerSource,
+    /// Number of cores in use after the change.
+    pub cores: usize,
+    /// Unix timestamp.
+    pub at: u64,
+}
+
+impl PowerChange {
+    pub fn new(source: PowerSource, cores: usize) -> PowerChange {
+        PowerChange {
+            source,
+            cores,
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+}
+
+/// The current power source, or `None` if it can not be determined, for
+/// example on machines without a battery.
+pub fn power_source() -> Option<PowerSource> {
+    imp::power_source()
+}
+
+/// Reports changes of the power source, starting with the current one.
+/// Nothing is reported if the power source can not be determined.
+pub fn spawn_watcher(logger: Logger) -> mpsc::UnboundedReceiver<PowerSource> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut last = match power_source() {
+        Some(source) => source,
+        None => {
+            logger.warn("Could not determine power source. Will not react to running on battery.");
+            return rx;
+        }
+    };
+    tokio::spawn(async move {
+        if tx.send(last).is_err() {
+            return;
+        }
+        let mut interval = time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = tx.closed() => break,
+                _ = interval.tick() => (),
+            }
+            match power_source() {
+                Some(source) if source != last => {
+                    last = source;
+                    if tx.send(source).is_err() {
+                        break;
+                    }
+                }
+                Some(_) => (),
+                None => logger.debug("Power source temporarily unknown"),
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::fs;
+
+    use super::PowerSource;
+
+    fn read(path: &std::path::Path) -> String {
+        fs::read_to_string(path)
+            .map(|s| s.trim().to_owned())
+            .unwrap_or_default()
+    }
+
+    /// Reads the same information that upower uses.
+    pub fn power_source() -> Option<PowerSource> {
+        let mut discharging = false;
+        for entry in fs::read_dir("/sys/class/power_supply").ok()?.flatten() {
+            let path = entry.path();
+            match read(&path.join("type")).as_str() {
+                "Mains" | "USB" if read(&path.join("online")) == "1" => {
+                    return Some(PowerSource::Ac)
+                }
+                "Battery" if read(&path.join("status")) == "Discharging" => discharging = true,
+                _ => (),
+            }
+        }
+        if discharging {
+            Some(PowerSource::Battery)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::mem::MaybeUninit;
+
+    use super::PowerSource;
+
+    #[repr(C)]
+    #[allow(dead_code)] // Filled in by Windows
+    struct SystemPowerStatus {
+        ac_line_status: u8,
+        battery_flag: u8,
+        battery_life_percent: u8,
+        system_status_flag: u8,
+        battery_life_time: u32,
+        battery_full_life_time: u32,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetSystemPowerStatus(status: *mut SystemPowerStatus) -> i32;
+    }
+
+    pub fn power_source() -> Option<PowerSource> {
+        let mut status = MaybeUninit::<SystemPowerStatus>::uninit();
+        // Safety: The structure is fully initialized on success.
+        let status = unsafe {
+            if GetSystemPowerStatus(status.as_mut_ptr()) == 0 {
+                return None;
+            }
+            status.assume_init()
+        };
+        match status.ac_line_status {
+            0 => Some(PowerSource::Battery),
+            1 => Some(PowerSource::Ac),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod imp {
+    use super::PowerSource;
+
+    pub fn power_source() -> Option<PowerSource> {
+        None
+    }
+}
@@ -0,0 +1,625 @@
+use std::{collections::BTreeMap, error::Error, fmt, num::NonZeroU8, str::FromStr, time::Duration};
+
+use arrayvec::ArrayString;
+use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr as DeserializeRepr, Serialize_repr as SerializeRepr};
+use serde_with::{
+    serde_as, DisplayFromStr, DurationMilliSeconds, DurationSeconds, SpaceSeparator,
+    StringWithSeparator,
+};
+use shakmaty::{san::SanPlus, uci::Uci, variant::Variant};
+
+use crate::accuracy::Judgement;
+#[cfg(feature = "engine")]
+use crate::assets::EvalFlavor;
+
+/// Play-outs are cut off after this many plies, whatever the server
+/// requests.
+pub const MAX_PLAYOUT_PLIES: u16 = 100;
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum Work {
+    #[serde(rename = "analysis")]
+    Analysis {
+        #[serde_as(as = "DisplayFromStr")]
+        id: BatchId,
+        nodes: NodeLimit,
+        #[serde(default)]
+        depth: Option<u8>,
+        #[serde(default)]
+        multipv: Option<NonZeroU8>,
+        #[serde_as(as = "DurationMilliSeconds<u64>")]
+        timeout: Duration,
+        /// Time limit for each position.
+        #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        movetime: Option<Duration>,
+        /// Include win/draw/loss statistics with each position.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        wdl: bool,
+        /// Include the judgement of the move leading to each position.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        annotations: bool,
+        /// Tier selected by the server, out of those advertised when
+        /// acquiring work.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tier: Option<Tier>,
+        /// Engine options to set for this batch, out of
+        /// [`ENGINE_OPTIONS`](crate::validate::ENGINE_OPTIONS).
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        options: BTreeMap<String, String>,
+    },
+    #[serde(rename = "move")]
+    Move {
+        #[serde_as(as = "DisplayFromStr")]
+        id: BatchId,
+        level: SkillLevel,
+        #[serde(default)]
+        clock: Option<Clock>,
+        /// Engine options to set for this batch, out of
+        /// [`ENGINE_OPTIONS`](crate::validate::ENGINE_OPTIONS).
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        options: BTreeMap<String, String>,
+    },
+    /// Continuation of the final position, played by the engine against
+    /// itself, for example to explore what happens if a move is played.
+    #[serde(rename = "playout")]
+    Playout {
+        #[serde_as(as = "DisplayFromStr")]
+        id: BatchId,
+        /// Node limit for each ply.
+        nodes: NodeLimit,
+        /// Plies to play, unless the game ends before. At most
+        /// [`MAX_PLAYOUT_PLIES`].
+        plies: u16,
+        /// Time limit for each ply.
+        #[serde_as(as = "DurationMilliSeconds<u64>")]
+        timeout: Duration,
+        /// Engine options to set for this batch, out of
+        /// [`ENGINE_OPTIONS`](crate::validate::ENGINE_OPTIONS).
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        options: BTreeMap<String, String>,
+    },
+}
+
+impl Work {
+    pub fn id(&self) -> BatchId {
+        match *self {
+            Work::Analysis { id, .. } | Work::Move { id, .. } | Work::Playout { id, .. } => id,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        match *self {
+            Work::Analysis { timeout, .. } => timeout,
+            Work::Move { .. } => Duration::from_secs(2),
+            Work::Playout { timeout, .. } => timeout * u32::from(self.playout_plies()),
+        }
+    }
+
+    /// Plies to play out, within bounds. 0 for other types of work.
+    pub fn playout_plies(&self) -> u16 {
+        match *self {
+            Work::Playout { plies, .. } => plies.clamp(1, MAX_PLAYOUT_PLIES),
+            Work::Analysis { .. } | Work::Move { .. } => 0,
+        }
+    }
+
+    pub fn is_analysis(&self) -> bool {
+        matches!(self, Work::Analysis { .. })
+    }
+
+    /// Engine option overrides of the server for this batch.
+    pub fn engine_options(&self) -> &BTreeMap<String, String> {
+        match self {
+            Work::Analysis { options, .. }
+            | Work::Move { options, .. }
+            | Work::Playout { options, .. } => options,
+        }
+    }
+
+    /// The type of work, as named in the protocol.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Work::Analysis { .. } => "analysis",
+            Work::Move { .. } => "move",
+            Work::Playout { .. } => "playout",
+        }
+    }
+
+    pub fn multipv(&self) -> NonZeroU8 {
+        match *self {
+            Work::Analysis { multipv, .. } => multipv,
+            Work::Move { .. } | Work::Playout { .. } => None,
+        }
+        .unwrap_or_else(|| NonZeroU8::new(1).unwrap())
+    }
+
+    pub fn wdl_wanted(&self) -> bool {
+        matches!(*self, Work::Analysis { wdl: true, .. })
+    }
+
+    pub fn annotations_wanted(&self) -> bool {
+        matches!(
+            *self,
+            Work::Analysis {
+                annotations: true,
+                ..
+            }
+        )
+    }
+
+    pub fn matrix_wanted(&self) -> bool {
+        matches!(
+            *self,
+            Work::Analysis {
+                multipv: Some(_),
+                ..
+            }
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct BatchId(ArrayString<24>);
+
+impl FromStr for BatchId {
+    type Err = arrayvec::CapacityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(BatchId(s.parse()?))
+    }
+}
+
+impl fmt::Display for BatchId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NodeLimit {
+    classical: u64,
+    sf15: u64,
+}
+
+impl NodeLimit {
+    /// The same limit for both evaluation flavors.
+    pub fn fixed(nodes: u64) -> NodeLimit {
+        NodeLimit {
+            classical: nodes,
+            sf15: nodes,
+        }
+    }
+
+    /// Caps both limits.
+    pub fn clamped(self, max: u64) -> NodeLimit {
+        NodeLimit {
+            classical: self.classical.min(max),
+            sf15: self.sf15.min(max),
+        }
+    }
+
+    /// Scales both limits, for example to the speed of the machine.
+    pub fn scaled(self, factor: f64) -> NodeLimit {
+        NodeLimit {
+            classical: (self.classical as f64 * factor) as u64,
+            sf15: (self.sf15 as f64 * factor) as u64,
+        }
+    }
+
+    #[cfg(feature = "engine")]
+    pub fn get(&self, flavor: EvalFlavor) -> u64 {
+        match flavor {
+            EvalFlavor::Hce => self.classical,
+            EvalFlavor::Nnue => self.sf15,
+        }
+    }
+}
+
+/// Server policy for scaling node targets to the speed of the client. Factors
+/// below 1 let slow machines finish in reasonable time, factors above 1 let
+/// fast machines search deeper.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct NodeScaleBounds {
+    pub min: f64,
+    pub max: f64,
+}
+
+#[derive(SerializeRepr, DeserializeRepr, Debug, Copy, Clone)]
+#[repr(u32)]
+pub enum SkillLevel {
+    One = 1,
+    Two = 2,
+    Three = 3,
+    Four = 4,
+    Five = 5,
+    Six = 6,
+    Seven = 7,
+    Eight = 8,
+}
+
+impl SkillLevel {
+    pub fn from_level(level: u32) -> Option<SkillLevel> {
+        use SkillLevel::*;
+        Some(match level {
+            1 => One,
+            2 => Two,
+            3 => Three,
+            4 => Four,
+            5 => Five,
+            6 => Six,
+            7 => Seven,
+            8 => Eight,
+            _ => return None,
+        })
+    }
+
+    pub fn time(self) -> Duration {
+        use SkillLevel::*;
+        Duration::from_millis(match self {
+            One => 50,
+            Two => 100,
+            Three => 150,
+            Four => 200,
+            Five => 300,
+            Six => 400,
+            Seven => 500,
+            Eight => 1000,
+        })
+    }
+
+    pub fn skill_level(self) -> i32 {
+        use SkillLevel::*;
+        match self {
+            One => -9,
+            Two => -5,
+            Three => -1,
+            Four => 3,
+            Five => 7,
+            Six => 11,
+            Seven => 16,
+            Eight => 20,
+        }
+    }
+
+    pub fn depth(self) -> u8 {
+        use SkillLevel::*;
+        match self {
+            One | Two | Three | Four | Five => 5,
+            Six => 8,
+            Seven => 13,
+            Eight => 22,
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Clock {
+    pub wtime: Centis,
+    pub btime: Centis,
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub inc: Duration,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Centis(u32);
+
+impl From<Centis> for Duration {
+    fn from(Centis(centis): Centis) -> Duration {
+        Duration::from_millis(u64::from(centis) * 10)
+    }
+}
+
+impl From<Duration> for Centis {
+    fn from(duration: Duration) -> Centis {
+        Centis(u32::try_from(duration.as_millis() / 10).unwrap_or(u32::MAX))
+    }
+}
+
+/// Depth of analysis, from quick evaluations for server-side eval bars to
+/// deep analysis for studies. Each tier maps to a node budget and number of
+/// lines relative to what the server requests.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Tier {
+    Fast,
+    Standard,
+    Deep,
+}
+
+impl Tier {
+    pub const ALL: [Tier; 3] = [Tier::Fast, Tier::Standard, Tier::Deep];
+}
+
+impl fmt::Display for Tier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            Tier::Fast => "fast",
+            Tier::Standard => "standard",
+            Tier::Deep => "deep",
+        })
+    }
+}
+
+/// Lane of acquired work. Requests of users waiting for the result are
+/// analysed before broad system analysis.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "camelCase")]
+pub enum Priority {
+    User,
+    System,
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::System
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum LichessVariant {
+    Antichess,
+    Atomic,
+    Chess960,
+    Crazyhouse,
+    FromPosition,
+    Horde,
+    KingOfTheHill,
+    RacingKings,
+    Standard,
+    ThreeCheck,
+}
+
+#[derive(Debug)]
+pub struct UnknownVariant;
+
+impl fmt::Display for UnknownVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("unknown variant")
+    }
+}
+
+impl Error for UnknownVariant {}
+
+impl FromStr for LichessVariant {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "antichess" => LichessVariant::Antichess,
+            "atomic" => LichessVariant::Atomic,
+            "chess960" => LichessVariant::Chess960,
+            "crazyhouse" => LichessVariant::Crazyhouse,
+            "fromposition" => LichessVariant::FromPosition,
+            "horde" => LichessVariant::Horde,
+            "kingofthehill" => LichessVariant::KingOfTheHill,
+            "racingkings" => LichessVariant::RacingKings,
+            "standard" => LichessVariant::Standard,
+            "threecheck" => LichessVariant::ThreeCheck,
+            _ => return Err(UnknownVariant),
+        })
+    }
+}
+
+impl fmt::Display for LichessVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LichessVariant::Antichess => "antichess",
+            LichessVariant::Atomic => "atomic",
+            LichessVariant::Chess960 => "chess960",
+            LichessVariant::Crazyhouse => "crazyhouse",
+            LichessVariant::FromPosition => "fromPosition",
+            LichessVariant::Horde => "horde",
+            LichessVariant::KingOfTheHill => "kingOfTheHill",
+            LichessVariant::RacingKings => "racingKings",
+            LichessVariant::Standard => "standard",
+            LichessVariant::ThreeCheck => "threeCheck",
+        })
+    }
+}
+
+impl LichessVariant {
+    pub const ALL: [LichessVariant; 10] = [
+        LichessVariant::Standard,
+        LichessVariant::Chess960,
+        LichessVariant::FromPosition,
+        LichessVariant::Antichess,
+        LichessVariant::Atomic,
+        LichessVariant::Crazyhouse,
+        LichessVariant::Horde,
+        LichessVariant::KingOfTheHill,
+        LichessVariant::RacingKings,
+        LichessVariant::ThreeCheck,
+    ];
+
+    pub fn short_name(self) -> Option<&'static str> {
+        Some(match self {
+            LichessVariant::Antichess => "anti",
+            LichessVariant::Atomic => "atomic",
+            LichessVariant::Chess960 => "chess960",
+            LichessVariant::Crazyhouse => "zh",
+            LichessVariant::FromPosition => "setup",
+            LichessVariant::Horde => "horde",
+            LichessVariant::KingOfTheHill => "koth",
+            LichessVariant::RacingKings => "race",
+            LichessVariant::ThreeCheck => "3check",
+            LichessVariant::Standard => return None,
+        })
+    }
+}
+
+/// What this client can analyse in a variant. Sent with acquire requests,
+/// so that the server does not assign work that would be rejected.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VariantCapability {
+    pub variant: LichessVariant,
+    /// Largest board, in files and ranks.
+    pub files: u8,
+    pub ranks: u8,
+    /// Pieces in hand can be dropped.
+    pub drops: bool,
+    /// Evaluated with an NNUE network, rather than classical evaluation.
+    pub nnue: bool,
+}
+
+impl VariantCapability {
+    pub fn new(variant: LichessVariant, nnue: bool) -> VariantCapability {
+        // All variants of shakmaty are played on the regular board.
+        VariantCapability {
+            variant,
+            files: 8,
+            ranks: 8,
+            drops: Variant::from(variant) == Variant::Crazyhouse,
+            nnue,
+        }
+    }
+}
+
+impl From<LichessVariant> for Variant {
+    fn from(lichess: LichessVariant) -> Variant {
+        match lichess {
+            LichessVariant::Antichess => Variant::Antichess,
+            LichessVariant::Atomic => Variant::Atomic,
+            LichessVariant::Chess960 | LichessVariant::Standard | LichessVariant::FromPosition => {
+                Variant::Chess
+            }
+            LichessVariant::Crazyhouse => Variant::Crazyhouse,
+            LichessVariant::Horde => Variant::Horde,
+            LichessVariant::KingOfTheHill => Variant::KingOfTheHill,
+            LichessVariant::RacingKings => Variant::RacingKings,
+            LichessVariant::ThreeCheck => Variant::ThreeCheck,
+        }
+    }
+}
+
+impl Default for LichessVariant {
+    fn default() -> LichessVariant {
+        LichessVariant::Standard
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum AnalysisPart {
+    Skipped {
+        skipped: bool,
+    },
+    Best {
+        #[serde_as(as = "StringWithSeparator::<SpaceSeparator, Uci>")]
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        pv: Vec<Uci>,
+        /// The principal variation in SAN, if requested with --pv-san.
+        #[serde_as(as = "Option<StringWithSeparator::<SpaceSeparator, SanPlus>>")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        san: Option<Vec<SanPlus>>,
+        score: Score,
+        depth: u8,
+        nodes: u64,
+        time: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nps: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        wdl: Option<Wdl>,
+        /// Judgement of the move leading to the position, if requested.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        judgement: Option<Judgement>,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        cached: bool,
+    },
+    Matrix {
+        #[serde_as(as = "Vec<Vec<Option<Vec<DisplayFromStr>>>>")]
+        pv: Vec<Vec<Option<Vec<Uci>>>>,
+        score: Vec<Vec<Option<Score>>>,
+        depth: u8,
+        nodes: u64,
+        time: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nps: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        wdl: Option<Wdl>,
+        /// Judgement of the move leading to the position, if requested.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        judgement: Option<Judgement>,
+        /// Exactly one move wins after a blunder, if requested with
+        /// --puzzle-candidates.
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        puzzle_candidate: bool,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        cached: bool,
+    },
+}
+
+/// A move of a play-out, with the evaluation of the position before it, from
+/// the point of view of the side to move.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayoutPly {
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(rename = "move")]
+    pub uci: Uci,
+    pub score: Score,
+    pub depth: u8,
+    pub nodes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
+pub enum Score {
+    #[serde(rename = "cp")]
+    Cp(i64),
+    #[serde(rename = "mate")]
+    Mate(i64),
+}
+
+/// Win, draw and loss probabilities in permille, from the point of view of
+/// the side to move.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+pub struct Wdl {
+    pub win: u16,
+    pub draw: u16,
+    pub loss: u16,
+}
+
+impl Wdl {
+    /// Estimates the probabilities from a score, for engines and cached
+    /// evaluations without their own statistics. Uses the win rate model of
+    /// Stockfish 15.1, where the same score is more decisive later in the
+    /// game.
+    pub fn from_score(score: Score, ply: u32) -> Wdl {
+        fn win_rate(cp: i64, ply: u32) -> u16 {
+            let m = f64::from(ply.min(240)) / 64.0;
+            let a = ((-3.683_893_04 * m + 30.070_659_21) * m - 60.528_787_23) * m + 149.533_785_57;
+            let b = ((-2.018_185_7 * m + 15.856_850_38) * m - 29.834_520_23) * m + 47.590_788_27;
+            let x = (cp as f64).clamp(-2000.0, 2000.0);
+            (0.5 + 1000.0 / (1.0 + ((a - x) / b).exp())) as u16
+        }
+
+        match score {
+            Score::Mate(mate) if mate > 0 => Wdl {
+                win: 1000,
+                draw: 0,
+                loss: 0,
+            },
+            Score::Mate(_) => Wdl {
+                win: 0,
+                draw: 0,
+                loss: 1000,
+            },
+            Score::Cp(cp) => {
+                let win = win_rate(cp, ply);
+                let loss = win_rate(-cp, ply).min(1000 - win);
+                Wdl {
+                    win,
+                    draw: 1000 - win - loss,
+                    loss,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,496 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufWriter, Write as _},
+    num::NonZeroU8,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use shakmaty::{
+    fen::{fen, Fen},
+    san::San,
+    uci::Uci,
+    variant::VariantPosition,
+    CastlingMode, Chess, FromSetup as _, Move, Position as _,
+};
+
+use crate::{
+    accuracy::{is_puzzle_swing, is_unique_win},
+    api::{LichessVariant, NodeLimit, Score, Work},
+    assets::{Assets, EngineFlavor},
+    book::Book,
+    configure::{Cores, Opt, PuzzleOpt},
+    ipc::{Position, PositionId},
+    logger::Logger,
+    pool::EnginePool,
+};
+
+const MAX_SOLUTION_MOVES: usize = 4;
+
+/// Stands in for the evaluation of mate puzzles.
+const MATE_CP: i64 = 999_999_999;
+
+const GENERATOR_VERSION: &str = concat!("fishnet-", env!("CARGO_PKG_VERSION"));
+
+struct Game {
+    id: String,
+    root: Chess,
+    moves: Vec<Move>,
+}
+
+impl Game {
+    /// Moves up to the given ply, as sent to the engines.
+    fn uci_moves(&self, ply: usize) -> Vec<Uci> {
+        self.moves[..ply]
+            .iter()
+            .map(|m| m.to_uci(CastlingMode::Chess960))
+            .collect()
+    }
+
+    fn pos_at(&self, ply: usize) -> Chess {
+        let mut pos = self.root.clone();
+        for m in &self.moves[..ply] {
+            pos.play_unchecked(m);
+        }
+        pos
+    }
+}
+
+/// A puzzle candidate, as accepted by the lichess puzzle generator. The
+/// first move leads from `fen` to the puzzle, the remaining moves are the
+/// solution.
+#[derive(Serialize)]
+struct Candidate<'a> {
+    game_id: &'a str,
+    fen: String,
+    ply: usize,
+    moves: Vec<String>,
+    cp: i64,
+    generator_version: &'static str,
+}
+
+pub fn split_games(text: &str) -> Vec<(Vec<(String, String)>, String)> {
+    let mut games = Vec::new();
+    let mut tags = Vec::new();
+    let mut movetext = String::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(tag) = line.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            if !movetext.trim().is_empty() {
+                games.push((std::mem::take(&mut tags), std::mem::take(&mut movetext)));
+            }
+            if let Some((name, value)) = tag.split_once(char::is_whitespace) {
+                tags.push((name.to_owned(), value.trim().trim_matches('"').to_owned()));
+            }
+        } else if !line.starts_with('%') {
+            movetext.push_str(line);
+            movetext.push('\n');
+        }
+    }
+    if !movetext.trim().is_empty() {
+        games.push((tags, movetext));
+    }
+    games
+}
+
+/// Tokens of the main line, without comments, variations, move numbers and
+/// annotations.
+fn mainline_tokens(movetext: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut variation_depth = 0usize;
+    let mut chars = movetext.chars();
+    while let Some(ch) = chars.next() {
+        if matches!(ch, '{' | ';' | '(' | ')') || ch.is_whitespace() {
+            if !token.is_empty() {
+                tokens.push(std::mem::take(&mut token));
+            }
+        }
+        match ch {
+            '{' => chars.by_ref().take_while(|&c| c != '}').for_each(drop),
+            ';' => chars.by_ref().take_while(|&c| c != '\n').for_each(drop),
+            '(' => variation_depth += 1,
+            ')' => variation_depth = variation_depth.saturating_sub(1),
+            ch if ch.is_whitespace() => (),
+            ch if variation_depth == 0 => token.push(ch),
+            _ => (),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// Moves of the main line in SAN, up to the result.
+pub fn mainline_sans(movetext: &str) -> Vec<String> {
+    let mut sans = Vec::new();
+    for token in mainline_tokens(movetext) {
+        let token = match token.rfind('.') {
+            Some(i) if token.starts_with(|c: char| c.is_ascii_digit()) => &token[i + 1..],
+            _ => token.as_str(),
+        };
+        if token.is_empty() || token.starts_with('$') {
+            continue;
+        }
+        if matches!(token, "1-0" | "0-1" | "1/2-1/2" | "*") {
+            break;
+        }
+        sans.push(
+            token
+                .trim_end_matches(|c| matches!(c, '!' | '?'))
+                .replace("0-0", "O-O"),
+        );
+    }
+    sans
+}
+
+fn parse_game(index: usize, tags: &[(String, String)], movetext: &str) -> Result<Game, String> {
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let id = tag("Site")
+        .and_then(|site| site.strip_prefix("https://lichess.org/"))
+        .or_else(|| tag("GameId"))
+        .map_or_else(|| format!("game{}", index + 1), str::to_owned);
+
+    if tag("Variant").map_or(false, |v| !v.eq_ignore_ascii_case("standard")) {
+        return Err(format!("{}: only standard chess is supported", id));
+    }
+
+    let root = match tag("FEN") {
+        Some(fen) => {
+            let fen: Fen = fen
+                .parse()
+                .map_err(|err| format!("{}: invalid fen: {}", id, err))?;
+            Chess::from_setup(&fen, CastlingMode::Standard)
+                .map_err(|err| format!("{}: illegal position: {}", id, err))?
+        }
+        None => Chess::default(),
+    };
+
+    let mut pos = root.clone();
+    let mut moves = Vec::new();
+    for token in mainline_sans(movetext) {
+        let m = token
+            .parse::<San>()
+            .ok()
+            .and_then(|san| san.to_move(&pos).ok())
+            .ok_or_else(|| format!("{}: illegal move {} after {} plies", id, token, moves.len()))?;
+        pos.play_unchecked(&m);
+        moves.push(m);
+    }
+
+    Ok(Game { id, root, moves })
+}
+
+fn position(root: &Chess, moves: Vec<Uci>, nodes: u64, multipv: Option<NonZeroU8>) -> Position {
+    Position {
+        work: Work::Analysis {
+            id: "puzzles".parse().expect("batch id"),
+            nodes: NodeLimit::fixed(nodes),
+            depth: None,
+            multipv,
+            timeout: Duration::default(),
+            movetime: None,
+            wdl: false,
+            annotations: false,
+            tier: None,
+            options: BTreeMap::new(),
+        },
+        position_id: PositionId(moves.len()),
+        flavor: EngineFlavor::Official,
+        url: None,
+        variant: LichessVariant::Standard,
+        root_fen: Fen::from_setup(root),
+        moves,
+    }
+}
+
+/// Evaluates all positions of the game in parallel, except book positions.
+async fn scan(
+    pool: &EnginePool,
+    book: Option<&Book>,
+    game: &Game,
+    nodes: u64,
+) -> Vec<Option<Score>> {
+    let mut pending = Vec::with_capacity(game.moves.len() + 1);
+    for ply in 0..=game.moves.len() {
+        let in_book = book.map_or(false, |book| {
+            book.contains(&VariantPosition::Chess(game.pos_at(ply)))
+        });
+        pending.push(if in_book {
+            None
+        } else {
+            Some(
+                pool.submit(position(&game.root, game.uci_moves(ply), nodes, None))
+                    .await,
+            )
+        });
+    }
+    let mut scores = Vec::with_capacity(pending.len());
+    for res in pending {
+        scores.push(match res {
+            Some(res) => res.await.ok().and_then(|res| res.scores.best().copied()),
+            None => None,
+        });
+    }
+    scores
+}
+
+/// Searches for a unique winning move, and returns it with its evaluation
+/// and the expected reply.
+async fn unique_move(
+    pool: &EnginePool,
+    root: &Chess,
+    moves: &[Uci],
+    nodes: u64,
+) -> Option<(Uci, Score, Option<Uci>)> {
+    let res = pool
+        .go(position(root, moves.to_vec(), nodes, NonZeroU8::new(2)))
+        .await
+        .ok()?;
+    let best = *res.scores.best()?;
+    let second = res
+        .scores
+        .line(NonZeroU8::new(2).expect("second line"))
+        .copied();
+    if !is_unique_win(best, second) {
+        return None;
+    }
+    let pv = res.pvs.best()?;
+    Some((pv.first()?.clone(), best, pv.get(1).cloned()))
+}
+
+/// Confirms the puzzle starting at the given ply with deeper searches, and
+/// returns the solution with the evaluation of the first move.
+async fn solve(
+    pool: &EnginePool,
+    game: &Game,
+    ply: usize,
+    nodes: u64,
+) -> Option<(Vec<Uci>, Score)> {
+    let mut moves = game.uci_moves(ply);
+    let mut solution = Vec::new();
+    let mut first_score = None;
+    while solution.len() < 2 * MAX_SOLUTION_MOVES {
+        let (m, score, reply) = match unique_move(pool, &game.root, &moves, nodes).await {
+            Some(found) => found,
+            None => break,
+        };
+        first_score.get_or_insert(score);
+        moves.push(m.clone());
+        solution.push(m);
+        match (score, reply) {
+            (Score::Mate(1), _) | (_, None) => break,
+            (_, Some(reply)) => {
+                moves.push(reply.clone());
+                solution.push(reply);
+            }
+        }
+    }
+    // The solution ends with a move of the solver.
+    if solution.len() % 2 == 0 {
+        solution.pop();
+    }
+    Some((solution, first_score?))
+}
+
+/// Converts moves to standard castling notation.
+fn standard_uci(mut pos: Chess, moves: &[Uci]) -> Vec<String> {
+    let mut converted = Vec::with_capacity(moves.len());
+    for uci in moves {
+        let m = match uci.to_move(&pos) {
+            Ok(m) => m,
+            Err(_) => break,
+        };
+        converted.push(m.to_uci(CastlingMode::Standard).to_string());
+        pos.play_unchecked(&m);
+    }
+    converted
+}
+
+/// Scans games for positions after a blunder, where the opponent has a
+/// unique winning move, and confirms each move of the solution with a deeper
+/// MultiPV search.
+pub async fn puzzles(opt: &Opt, puzzle_opt: &PuzzleOpt, logger: &Logger) {
+    let concurrency = puzzle_opt.concurrency.map_or_else(
+        || usize::from(opt.cores.unwrap_or(Cores::Auto)),
+        usize::from,
+    );
+
+    let text = match fs::read_to_string(&puzzle_opt.file) {
+        Ok(text) => text,
+        Err(err) => {
+            logger.error(&format!("Failed to read {:?}: {}", puzzle_opt.file, err));
+            std::process::exit(1);
+        }
+    };
+    let mut out = match File::create(&puzzle_opt.out) {
+        Ok(file) => BufWriter::new(file),
+        Err(err) => {
+            logger.error(&format!("Failed to create {:?}: {}", puzzle_opt.out, err));
+            std::process::exit(1);
+        }
+    };
+
+    let book = match opt.lookup.book.as_deref().map(Book::open).transpose() {
+        Ok(book) => book,
+        Err(err) => {
+            logger.error(&format!(
+                "Failed to open book {:?}: {}",
+                opt.lookup.book, err
+            ));
+            std::process::exit(1);
+        }
+    };
+
+    let cpu = opt.cpu();
+    let assets =
+        Arc::new(Assets::prepare(cpu, opt.enabled_engines()).expect("prepared bundled stockfish"));
+    if assets.stockfish.official.is_none() {
+        logger.error("Puzzle extraction requires official Stockfish.");
+        std::process::exit(1);
+    }
+
+    logger.headline(&format!(
+        "Scanning {:?} for puzzles with {} engine processes",
+        puzzle_opt.file, concurrency
+    ));
+    let started_at = Instant::now();
+    let (pool, pool_join_handle) = EnginePool::spawn(assets, concurrency, logger.clone());
+
+    let mut scanned = 0;
+    let mut found = 0;
+    for (index, (tags, movetext)) in split_games(&text).into_iter().enumerate() {
+        let game = match parse_game(index, &tags, &movetext) {
+            Ok(game) => game,
+            Err(err) => {
+                logger.warn(&format!("Skipping game: {}", err));
+                continue;
+            }
+        };
+        scanned += 1;
+
+        let scores = scan(&pool, book.as_ref(), &game, puzzle_opt.nodes).await;
+        let mut candidates = 0;
+        for ply in 1..scores.len() {
+            match (scores[ply - 1], scores[ply]) {
+                (Some(before), Some(after)) if is_puzzle_swing(before, after) => (),
+                _ => continue,
+            }
+
+            let (solution, score) = match solve(&pool, &game, ply, puzzle_opt.confirm_nodes).await {
+                Some(solved) => solved,
+                None => continue,
+            };
+            let before_blunder = game.pos_at(ply - 1);
+            let mut moves = game.uci_moves(ply).split_off(ply - 1);
+            moves.extend(solution);
+            let candidate = Candidate {
+                game_id: &game.id,
+                fen: fen(&before_blunder),
+                ply: ply - 1,
+                moves: standard_uci(before_blunder, &moves),
+                cp: match score {
+                    Score::Cp(cp) => cp,
+                    Score::Mate(_) => MATE_CP,
+                },
+                generator_version: GENERATOR_VERSION,
+            };
+            serde_json::to_writer(&mut out, &candidate).expect("write candidate");
+            writeln!(out).expect("write candidate");
+            out.flush().expect("flush candidates");
+            candidates += 1;
+        }
+
+        logger.info(&format!(
+            "Game {}: {} plies, {} candidates",
+            game.id,
+            game.moves.len(),
+            candidates
+        ));
+        found += candidates;
+    }
+
+    drop(pool);
+    pool_join_handle.await.expect("join");
+
+    logger.fishnet_info(&format!(
+        "Found {} puzzle candidates in {} games in {:.1?}. Written to {:?}.",
+        found,
+        scanned,
+        started_at.elapsed(),
+        puzzle_opt.out
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PGN: &str = r#"[Event "Rated Blitz game"]
+[Site "https://lichess.org/abcdefgh"]
+
+1. e4 { [%clk 0:03:00] } e5 (1... c5 2. Nf3) 2. Qh5?! Nc6 3. Bc4 Nf6?? 4. Qxf7# 1-0
+
+[Event "Casual game"]
+[FEN "4k3/8/8/8/8/8/4P3/4K3 w - - 0 1"]
+
+1. e4 Kd7 *
+"#;
+
+    #[test]
+    fn test_split_games() {
+        let games = split_games(PGN);
+        assert_eq!(games.len(), 2);
+        assert_eq!(
+            games[0].0[1],
+            ("Site".to_owned(), "https://lichess.org/abcdefgh".to_owned())
+        );
+        assert_eq!(games[1].0[1].0, "FEN");
+    }
+
+    #[test]
+    fn test_mainline_tokens() {
+        assert_eq!(
+            mainline_tokens("1. e4 { [%clk 0:03:00] } e5 (1... c5 2. Nf3) 2. Nf3 ; comment\nNc6"),
+            ["1.", "e4", "e5", "2.", "Nf3", "Nc6"]
+        );
+    }
+
+    #[test]
+    fn test_parse_game() {
+        let games = split_games(PGN);
+        let game = parse_game(0, &games[0].0, &games[0].1).unwrap();
+        assert_eq!(game.id, "abcdefgh");
+        assert_eq!(game.moves.len(), 7);
+        assert_eq!(
+            game.uci_moves(3),
+            ["e2e4", "e7e5", "d1h5"].map(|m| m.parse::<Uci>().unwrap())
+        );
+
+        let game = parse_game(1, &games[1].0, &games[1].1).unwrap();
+        assert_eq!(game.id, "game2");
+        assert_eq!(game.moves.len(), 2);
+
+        let variant = "[Variant \"Atomic\"]\n\n1. e4 *";
+        let games = split_games(variant);
+        assert!(parse_game(0, &games[0].0, &games[0].1).is_err());
+        let games = split_games("1. e4 e4 *");
+        assert!(parse_game(0, &games[0].0, &games[0].1).is_err());
+    }
+
+    #[test]
+    fn test_standard_uci() {
+        let fen: Fen = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1".parse().unwrap();
+        let pos = Chess::from_setup(&fen, CastlingMode::Standard).unwrap();
+        let moves = ["e1h1", "e8a8"].map(|m| m.parse::<Uci>().unwrap());
+        assert_eq!(standard_uci(pos, &moves), ["e1g1", "e8c8"]);
+    }
+}
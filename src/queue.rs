@@ -2,59 +2,173 @@ use std::{
     cmp::{max, min},
     collections::{hash_map::Entry, HashMap, VecDeque},
     convert::TryInto,
+    io, iter,
+    num::NonZeroU8,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use shakmaty::{
-    fen::Fen,
-    uci::{IllegalUciError, Uci},
-    variant::VariantPosition,
-    CastlingMode, Position as _, PositionError,
-};
+use serde::Serialize;
+use serde_json::json;
+use shakmaty::{fen::Fen, uci::Uci, variant::VariantPosition, Color, Position as _, Setup as _};
 use tokio::{
-    sync::{mpsc, oneshot, Mutex, Notify},
-    time,
+    sync::{broadcast::error::RecvError, mpsc, oneshot, Mutex, Notify},
+    task, time,
+    time::MissedTickBehavior,
 };
 use url::Url;
 
 use crate::{
+    accuracy::{self, is_puzzle_swing, is_unique_win, GameMetrics},
+    anomaly::{Anomaly, AnomalyDetector, Webhook},
     api::{
-        AcquireQuery, AcquireResponseBody, Acquired, AnalysisPart, ApiStub, BatchId,
-        LichessVariant, Work,
+        AcquireQuery, AcquireResponseBody, Acquired, AnalysisPart, ApiStub, BatchId, Cancellations,
+        LichessVariant, PlayoutPly, Priority, Quota, Standing, Tier, Work,
     },
-    assets::{EngineFlavor, EvalFlavor},
-    configure::{BacklogOpt, Endpoint},
-    ipc::{Position, PositionFailed, PositionId, PositionResponse, Pull},
+    assets::{ByEngineFlavor, EngineFlavor, EvalFlavor},
+    audit::{AuditLog, Provenance},
+    budget::{Degradation, ErrorBudget, ErrorCategory},
+    calibration::Calibration,
+    checkpoint::{self, BatchCheckpoint},
+    cluster::WireResponse,
+    configure::{AnalysisOrder, BacklogOpt, Endpoint, VariantFilter},
+    dedup::{BatchKey, RecentBatches},
+    dump::InvalidBatchDump,
+    events::{Event, EventStream},
+    handler::{Submission, WorkHandler, WorkHandlers},
+    ipc::{apply_tier, Position, PositionFailed, PositionId, PositionResponse, Pull, WorkLimits},
+    journal::Journal,
+    latency::{LatencySummary, Stage, StageSummary},
     logger::{Logger, ProgressAt, QueueStatusBar},
-    stats::{NpsRecorder, Stats, StatsRecorder},
+    lookup::BookSkip,
+    metrics::METRICS,
+    power::PowerChange,
+    san,
+    stats::{NpsRecorder, PerformanceSummary, Stats, StatsRecorder, Throughput},
+    trace::{Span, Tracer},
     util::{NevermindExt as _, RandomizedBackoff},
+    validate::{validate_engine_options, validate_game_fen, ValidationError},
 };
 
+/// Analysis batches with up to this many positions that keep failing are
+/// submitted with those positions skipped.
+const MAX_SKIPPED_FAILURES: usize = 3;
+
+/// Bounds of the work kept queued ahead of the workers, in terms of the
+/// measured throughput.
+const MIN_LOOKAHEAD: Duration = Duration::from_secs(5);
+const MAX_LOOKAHEAD: Duration = Duration::from_secs(60);
+
+/// Ask the server about cancelled batches this often, while a batch has been
+/// in progress for at least as long. Servers can also push cancellations
+/// through the websocket.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Consider the queue stalled if no position was completed for this long,
+/// while there is work.
+const STALE_PROGRESS: Duration = Duration::from_secs(10 * 60);
+
+/// Decides which batches are accepted, which engine analyses them, and
+/// how they are handled.
+#[derive(Debug, Clone)]
+pub struct Routing {
+    pub variants: VariantFilter,
+    pub engines: ByEngineFlavor<bool>,
+    pub force_multi_variant: bool,
+    /// Scale node targets to the measured speed, where the server allows.
+    pub scale_nodes: bool,
+    /// Target for the p99 latency of move requests.
+    pub move_latency_budget: Option<Duration>,
+    /// Include principal variations in SAN with analysis.
+    pub pv_san: bool,
+    /// Order in which positions of analysis batches are handed to workers.
+    pub analysis_order: AnalysisOrder,
+    /// Spread the node budget of each game by phase.
+    pub budget_by_phase: bool,
+    /// Mark positions that may make good puzzles in analysis.
+    pub puzzle_candidates: bool,
+    pub limits: WorkLimits,
+    /// Speed measured by `fishnet benchmark`, as the initial estimate.
+    pub calibration: Option<Calibration>,
+    /// Skip analysis of book positions near the start of games.
+    pub book_skip: Option<Arc<BookSkip>>,
+    /// Keep batches that fail validation for debugging.
+    pub invalid_batch_dump: Option<Arc<InvalidBatchDump>>,
+    pub handlers: Arc<WorkHandlers>,
+}
+
+/// Connections to the server.
+pub struct Upstream {
+    /// Acquires work and submits analysis.
+    pub api: ApiStub,
+    /// Submits moves, so that they do not wait behind other requests.
+    pub submit_api: ApiStub,
+    pub max_backoff: Duration,
+}
+
+/// Where the queue reports what happens to batches, if enabled.
+pub struct Sinks {
+    pub tracer: Option<Tracer>,
+    pub webhook: Option<Webhook>,
+    pub audit_log: Option<AuditLog>,
+    pub events: Option<EventStream>,
+    pub journal: Option<Journal>,
+}
+
 pub fn channel(
     opt: BacklogOpt,
+    routing: Routing,
     cores: usize,
-    api: ApiStub,
-    max_backoff: Duration,
+    upstream: Upstream,
+    sinks: Sinks,
     logger: Logger,
 ) -> (QueueStub, QueueActor) {
+    let Upstream {
+        api,
+        submit_api,
+        max_backoff,
+    } = upstream;
     let (tx, rx) = mpsc::unbounded_channel();
     let interrupt = Arc::new(Notify::new());
-    let state = Arc::new(Mutex::new(QueueState::new(cores, logger.clone())));
+    let submit_ready = Arc::new(Notify::new());
+    let state = Arc::new(Mutex::new(QueueState::new(
+        opt,
+        cores,
+        routing
+            .calibration
+            .and_then(|calibration| calibration.official),
+        routing.handlers.clone(),
+        routing.analysis_order,
+        sinks,
+        logger.clone(),
+    )));
+    let submitter = MoveSubmitter {
+        tx: tx.clone(),
+        ready: submit_ready.clone(),
+        interrupt: interrupt.clone(),
+        state: state.clone(),
+        api: submit_api,
+        budget: routing.move_latency_budget,
+        logger: logger.clone(),
+    };
     let stub = QueueStub {
         tx: Some(tx),
         interrupt: interrupt.clone(),
+        submit_ready: submit_ready.clone(),
         state: state.clone(),
         api: api.clone(),
     };
     let actor = QueueActor {
         rx,
         interrupt,
+        submit_ready,
+        submitter: Some(submitter),
         state,
         api,
-        opt,
+        routing,
         logger,
         backoff: RandomizedBackoff::new(max_backoff),
+        prefetch_after: Instant::now(),
     };
     (stub, actor)
 }
@@ -63,50 +177,201 @@ pub fn channel(
 pub struct QueueStub {
     tx: Option<mpsc::UnboundedSender<QueueMessage>>,
     interrupt: Arc<Notify>,
+    submit_ready: Arc<Notify>,
     state: Arc<Mutex<QueueState>>,
     api: ApiStub,
 }
 
 impl QueueStub {
+    /// Batches that the server cancelled, so that workers can stop searching
+    /// their positions.
+    pub fn cancellations(&self) -> Cancellations {
+        self.api.cancellations()
+    }
+
     pub async fn pull(&mut self, pull: Pull) {
         let mut state = self.state.lock().await;
+        let reserved = pull.reserved;
         let (response, callback) = pull.split();
         if let Some(response) = response {
             state.handle_position_response(self.clone(), response);
         }
-        if let Err(callback) = state.try_pull(callback) {
-            if let Some(ref mut tx) = self.tx {
-                tx.send(QueueMessage::Pull { callback })
-                    .nevermind("queue dropped");
+        if let Some(callback) = callback {
+            if let Err(callback) = state.try_pull(callback, reserved) {
+                if let Some(ref mut tx) = self.tx {
+                    tx.send(QueueMessage::Pull { callback, reserved })
+                        .nevermind("queue dropped");
+                }
             }
         }
     }
 
-    fn move_submitted(&mut self) {
-        if let Some(ref tx) = self.tx {
-            tx.send(QueueMessage::MoveSubmitted).nevermind("too late");
+    /// Changes the number of workers that are acquiring work.
+    pub async fn set_cores(&self, cores: usize) {
+        let mut state = self.state.lock().await;
+        state.cores = cores;
+    }
 
-            // Skip the queue backoff.
-            self.interrupt.notify_one();
-        }
+    pub async fn record_power_change(&self, change: PowerChange) {
+        let mut state = self.state.lock().await;
+        state.stats_recorder.power_changes.push(change);
+    }
+
+    pub async fn set_backlog(&self, backlog: BacklogOpt) {
+        let mut state = self.state.lock().await;
+        state.backlog = backlog;
+    }
+
+    fn move_submitted(&self) {
+        self.submit_ready.notify_one();
     }
 
     pub async fn shutdown_soon(&mut self) {
         let mut state = self.state.lock().await;
         state.shutdown_soon = true;
+        // Release workers that are waiting for user requests.
+        state.parked.clear();
         self.tx.take();
         self.interrupt.notify_one();
+        self.submit_ready.notify_one();
+    }
+
+    /// Like `shutdown_soon`, but also drops analysis that was not started
+    /// yet. Positions in flight are still completed, so that they can be
+    /// saved with `checkpoint`.
+    pub async fn checkpoint_soon(&mut self) {
+        self.shutdown_soon().await;
+        let mut state = self.state.lock().await;
+        state.incoming.retain(|pos| !pos.work.is_analysis());
+    }
+
+    /// Saves pending analysis to resume after a restart, instead of
+    /// aborting it on shutdown.
+    pub async fn checkpoint(&mut self) {
+        let mut state = self.state.lock().await;
+        let state = &mut *state;
+        let ids: Vec<BatchId> = state
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.is_worth_resuming())
+            .map(|(id, _)| *id)
+            .collect();
+        if ids.is_empty() {
+            return;
+        }
+        let batches = ids
+            .iter()
+            .filter_map(|id| state.pending.remove(id))
+            .map(|pending| pending.into_checkpoint(self.api.endpoint()))
+            .collect();
+        for id in &ids {
+            write_journal(&mut state.journal, &state.logger, |journal| {
+                journal.closed(*id)
+            });
+        }
+        match checkpoint::save(batches) {
+            Ok(path) => state.logger.fishnet_info(&format!(
+                "Saved {} unfinished batches to {:?}. Analysis will resume on the next start.",
+                ids.len(),
+                path
+            )),
+            Err(err) => {
+                state
+                    .logger
+                    .error(&format!("Failed to save unfinished batches: {}", err));
+                for id in ids {
+                    METRICS.batches_failed.inc();
+                    self.api.abort(id);
+                }
+            }
+        }
     }
 
     pub async fn shutdown(mut self) {
         self.shutdown_soon().await;
 
         let mut state = self.state.lock().await;
+        state.stats_recorder.save();
+        let state = &mut *state;
         for (k, _) in state.pending.drain() {
+            METRICS.batches_failed.inc();
             self.api.abort(k);
+            write_journal(&mut state.journal, &state.logger, |journal| {
+                journal.closed(k)
+            });
+        }
+    }
+
+    pub async fn snapshot(&self) -> QueueSnapshot {
+        let state = self.state.lock().await;
+        QueueSnapshot {
+            shutdown_soon: state.shutdown_soon,
+            cores: state.cores,
+            incoming: state.incoming.len(),
+            move_submissions: state.move_submissions.len(),
+            pending: state
+                .pending
+                .values()
+                .map(|pending| PendingSnapshot {
+                    batch: pending.work.id().to_string(),
+                    url: pending.url.as_ref().map(|url| url.to_string()),
+                    variant: pending.variant.to_string(),
+                    positions: pending.positions.len(),
+                    pending: pending.pending(),
+                    age: pending.started_at.elapsed().as_secs(),
+                })
+                .collect(),
+            stats: state.stats_recorder.stats.clone(),
+            nnue_nps: state.stats_recorder.nnue_nps.nps,
+            performance: state.stats_recorder.performance.summaries(),
+            latencies: state.stats_recorder.latencies.summaries(),
+            stages: state.stats_recorder.latencies.stage_summaries(),
+            degradations: state.stats_recorder.degradations.clone(),
+            anomalies: state.stats_recorder.anomalies.clone(),
+            power_changes: state.stats_recorder.power_changes.clone(),
+        }
+    }
+
+    pub async fn performance(&self) -> Vec<PerformanceSummary> {
+        let state = self.state.lock().await;
+        state.stats_recorder.performance.summaries()
+    }
+
+    pub async fn latencies(&self) -> Vec<LatencySummary> {
+        let state = self.state.lock().await;
+        state.stats_recorder.latencies.summaries()
+    }
+
+    pub async fn status_bar(&self) -> QueueStatusBar {
+        let state = self.state.lock().await;
+        state.status_bar()
+    }
+
+    pub async fn stage_latencies(&self) -> Vec<StageSummary> {
+        let state = self.state.lock().await;
+        state.stats_recorder.latencies.stage_summaries()
+    }
+
+    pub async fn health(&self) -> QueueHealth {
+        let state = self.state.lock().await;
+        QueueHealth {
+            connected: state.connected,
+            draining: state.shutdown_soon,
+            stalled_for: Some(state.progress_at.elapsed())
+                .filter(|&elapsed| !state.pending.is_empty() && elapsed >= STALE_PROGRESS),
         }
     }
 
+    pub async fn record_standing(&self, standing: &Standing) {
+        let mut state = self.state.lock().await;
+        state.stats_recorder.record_standing(standing);
+    }
+
+    pub async fn throughput(&self) -> Throughput {
+        let state = self.state.lock().await;
+        state.stats_recorder.throughput.clone()
+    }
+
     pub async fn stats(&self) -> (Stats, NpsRecorder) {
         let state = self.state.lock().await;
         (
@@ -116,25 +381,119 @@ impl QueueStub {
     }
 }
 
+#[derive(Debug, Serialize)]
+pub struct QueueHealth {
+    /// The last request to acquire work reached the server.
+    pub connected: bool,
+    /// Not acquiring new batches, because fishnet is about to stop.
+    pub draining: bool,
+    /// Time since the last position was completed, if there is work and it
+    /// has been too long.
+    pub stalled_for: Option<Duration>,
+}
+
+/// Point in time view of the queue, for the status server.
+#[derive(Debug, Serialize)]
+pub struct QueueSnapshot {
+    shutdown_soon: bool,
+    cores: usize,
+    incoming: usize,
+    move_submissions: usize,
+    pending: Vec<PendingSnapshot>,
+    stats: Stats,
+    nnue_nps: u32,
+    performance: Vec<PerformanceSummary>,
+    latencies: Vec<LatencySummary>,
+    stages: Vec<StageSummary>,
+    degradations: Vec<Degradation>,
+    anomalies: Vec<Anomaly>,
+    power_changes: Vec<PowerChange>,
+}
+
+#[derive(Debug, Serialize)]
+struct PendingSnapshot {
+    batch: String,
+    url: Option<String>,
+    variant: String,
+    positions: usize,
+    pending: usize,
+    age: u64,
+}
+
 struct QueueState {
     shutdown_soon: bool,
+    connected: bool,
+    /// Request quota announced by the server with the last acquire
+    /// response.
+    quota: Option<Quota>,
+    backlog: BacklogOpt,
     cores: usize,
+    /// Positions by lane: moves, then user analysis, then system analysis.
     incoming: VecDeque<Position>,
+    /// Reserved workers waiting for user requests.
+    parked: Vec<oneshot::Sender<Position>>,
     pending: HashMap<BatchId, PendingBatch>,
+    /// Last time a position was completed or failed, or work arrived while
+    /// there was none.
+    progress_at: Instant,
     move_submissions: VecDeque<CompletedBatch>,
+    follow_ups: VecDeque<AcquireResponseBody>,
+    recent_batches: RecentBatches,
+    over_budget: bool,
+    handlers: Arc<WorkHandlers>,
+    analysis_order: AnalysisOrder,
     stats_recorder: StatsRecorder,
+    error_budget: ErrorBudget,
+    anomaly_detector: AnomalyDetector,
+    tracer: Option<Tracer>,
+    webhook: Option<Webhook>,
+    audit_log: Option<AuditLog>,
+    events: Option<EventStream>,
+    journal: Option<Journal>,
     logger: Logger,
 }
 
 impl QueueState {
-    fn new(cores: usize, logger: Logger) -> QueueState {
+    fn new(
+        backlog: BacklogOpt,
+        cores: usize,
+        calibrated_nps: Option<u32>,
+        handlers: Arc<WorkHandlers>,
+        analysis_order: AnalysisOrder,
+        sinks: Sinks,
+        logger: Logger,
+    ) -> QueueState {
+        let Sinks {
+            tracer,
+            webhook,
+            audit_log,
+            events,
+            journal,
+        } = sinks;
         QueueState {
             shutdown_soon: false,
+            connected: true,
+            quota: None,
+            backlog,
             cores,
             incoming: VecDeque::new(),
+            parked: Vec::new(),
             pending: HashMap::new(),
+            progress_at: Instant::now(),
             move_submissions: VecDeque::new(),
-            stats_recorder: StatsRecorder::open(cores),
+            follow_ups: VecDeque::new(),
+            recent_batches: RecentBatches::default(),
+            over_budget: false,
+            handlers,
+            analysis_order,
+            stats_recorder: StatsRecorder::open(cores, calibrated_nps),
+            error_budget: ErrorBudget::default(),
+            anomaly_detector: AnomalyDetector::default(),
+            tracer,
+            webhook,
+            audit_log,
+            events,
+            journal,
             logger,
         }
     }
@@ -143,10 +502,47 @@ impl QueueState {
         QueueStatusBar {
             pending: self.pending.values().map(|p| p.pending()).sum(),
             cores: self.cores,
+            lookahead: self.lookahead(),
+            quota: self.quota.filter(Quota::is_current),
         }
     }
 
+    /// Positions to keep queued, so that workers do not wait for the next
+    /// batch to be acquired: the configured backlog (within bounds) worth
+    /// of the measured throughput. None until something was measured, so
+    /// that slow machines do not hoard work.
+    fn lookahead(&self) -> usize {
+        let backlog = max(
+            self.backlog.user.map(Duration::from).unwrap_or_default(),
+            self.backlog.system.map(Duration::from).unwrap_or_default(),
+        );
+        let horizon = backlog.clamp(MIN_LOOKAHEAD, MAX_LOOKAHEAD);
+        (self.stats_recorder.throughput.positions_per_second() * horizon.as_secs_f64()) as usize
+    }
+
+    /// Order of lanes. Move requests are for games in progress, so they
+    /// skip ahead of user analysis, which skips ahead of system analysis.
+    fn lane(&self, work: &Work) -> (Priority, bool) {
+        let priority = self
+            .pending
+            .get(&work.id())
+            .map_or(Priority::System, |batch| batch.priority);
+        (priority, work.is_analysis())
+    }
+
     fn add_incoming_batch(&mut self, batch: IncomingBatch) {
+        // Batches are queued behind their own lane, but not ahead of each
+        // other.
+        let lane = (batch.priority, batch.work.is_analysis());
+        if self.pending.is_empty() {
+            self.progress_at = Instant::now();
+        }
+        let at = self
+            .incoming
+            .iter()
+            .take_while(|pos| self.lane(&pos.work) <= lane)
+            .count();
+
         match self.pending.entry(batch.work.id()) {
             Entry::Occupied(entry) => self.logger.error(&format!(
                 "Dropping duplicate incoming batch {}",
@@ -155,31 +551,119 @@ impl QueueState {
             Entry::Vacant(entry) => {
                 let progress_at = ProgressAt::from(&batch);
 
-                // Reversal only for cosmetics when displaying progress.
+                let mut completed = batch.completed;
                 let mut positions = Vec::with_capacity(batch.positions.len());
-                for pos in batch.positions.into_iter().rev() {
-                    positions.insert(
-                        0,
-                        match pos {
-                            Skip::Present(pos) => {
-                                self.incoming.push_back(pos);
-                                None
+                let mut queued = Vec::new();
+                for (i, pos) in batch.positions.into_iter().enumerate() {
+                    positions.push(match (pos, completed.get_mut(i).and_then(Option::take)) {
+                        (Skip::Present(_), Some(res)) => Some(Skip::Present(res)),
+                        (Skip::Present(pos), None) => {
+                            queued.push(pos);
+                            None
+                        }
+                        (Skip::Skip, _) => Some(Skip::Skip),
+                    });
+                }
+                if self.analysis_order == AnalysisOrder::Backward {
+                    queued.reverse();
+                }
+                let behind = self.incoming.split_off(at);
+                self.incoming.extend(queued);
+                self.incoming.extend(behind);
+
+                if let Some(ref acquired) = batch.acquired {
+                    write_journal(&mut self.journal, &self.logger, |journal| {
+                        journal.acquired(acquired, batch.node_scale)?;
+                        for (i, pos) in positions.iter().enumerate() {
+                            if let Some(Skip::Present(res)) = pos {
+                                journal.completed(
+                                    acquired.work.id(),
+                                    i,
+                                    WireResponse::from(res.clone()),
+                                )?;
                             }
-                            Skip::Skip => Some(Skip::Skip),
-                        },
-                    );
+                        }
+                        Ok(())
+                    });
+                }
+
+                if let Some(ref events) = self.events {
+                    events.emit(&Event::BatchAcquired {
+                        batch: batch.work.id().to_string(),
+                        kind: batch.work.kind(),
+                        variant: batch.variant.to_string(),
+                        positions: positions.len(),
+                        completed: positions
+                            .iter()
+                            .filter(|pos| matches!(pos, Some(Skip::Present(_))))
+                            .count(),
+                    });
                 }
 
                 entry.insert(PendingBatch {
                     work: batch.work,
                     flavor: batch.flavor,
                     variant: batch.variant,
+                    root_turn: batch.root_turn,
                     url: batch.url,
                     positions,
+                    node_scale: batch.node_scale,
+                    san_roots: batch.san_roots,
+                    puzzle_candidates: batch.puzzle_candidates,
+                    acquired_at: batch.acquired_at,
                     started_at: Instant::now(),
+                    started_wall: SystemTime::now(),
+                    acquired: batch.acquired,
+                    priority: batch.priority,
+                    skipped_failures: 0,
                 });
 
                 self.logger.progress(self.status_bar(), progress_at);
+                self.serve_parked();
+            }
+        }
+    }
+
+    fn serve_parked(&mut self) {
+        while let Some(callback) = self.parked.pop() {
+            if let Err(callback) = self.try_pull(callback, true) {
+                self.parked.push(callback);
+                break;
+            }
+        }
+    }
+
+    fn record_outcome(&mut self, category: ErrorCategory, ok: bool) {
+        if let Some(degradation) = self.error_budget.record(category, ok) {
+            self.logger.warn(&format!(
+                "{:.0}% of recent {} outcomes failed. Degrading for {}s: {}.",
+                degradation.error_rate * 100.0,
+                degradation.category,
+                degradation.cooldown,
+                degradation.action
+            ));
+            self.stats_recorder.degradations.push(degradation);
+        }
+    }
+
+    fn record_anomaly(&mut self, anomaly: Anomaly) {
+        METRICS.engine_anomalies.inc();
+        self.logger.warn(&format!(
+            "Engine anomaly: {}",
+            serde_json::to_string(&anomaly).expect("serialize anomaly")
+        ));
+        if let Some(ref webhook) = self.webhook {
+            webhook.notify(anomaly.clone());
+        }
+        self.stats_recorder.anomalies.push(anomaly);
+    }
+
+    fn record_provenance(&mut self, completed: &CompletedBatch) {
+        if let Some(ref mut audit_log) = self.audit_log {
+            let record = completed.provenance(audit_log.engine_hash(completed.flavor));
+            if let Err(err) = audit_log.append(&record) {
+                self.logger
+                    .error(&format!("Failed to write provenance record: {}", err));
             }
         }
     }
@@ -189,32 +673,222 @@ impl QueueState {
         queue: QueueStub,
         res: Result<PositionResponse, PositionFailed>,
     ) {
+        self.progress_at = Instant::now();
         match res {
             Ok(res) => {
+                if res.cached {
+                    METRICS.positions_looked_up.inc();
+                } else {
+                    METRICS.positions_analyzed.inc();
+                    METRICS.nodes_searched.add(res.nodes);
+                }
                 let progress_at = ProgressAt::from(&res);
+                self.logger.debug(&format!(
+                    "{} done: depth {}, {}, {}",
+                    progress_at,
+                    res.depth,
+                    res.scores
+                        .best()
+                        .map_or_else(|| "no score".to_owned(), |s| self.logger.score(s)),
+                    self.logger.nodes(res.nodes)
+                ));
                 let batch_id = res.work.id();
                 if let Some(pending) = self.pending.get_mut(&batch_id) {
+                    let flavor = pending.flavor;
+                    // Lookups say nothing about the performance of the
+                    // engines.
+                    if !res.cached {
+                        self.stats_recorder.performance.record_position(
+                            pending.variant,
+                            pending.flavor,
+                            res.depth,
+                            res.nodes,
+                            res.time,
+                        );
+                        let latencies = &mut self.stats_recorder.latencies;
+                        latencies.record(&res.work, pending.flavor, pending.variant, res.latency);
+                        latencies.record_stage(
+                            &res.work,
+                            Stage::QueueWait,
+                            pending.started_at.elapsed().saturating_sub(res.latency),
+                        );
+                        latencies.record_stage(&res.work, Stage::Search, res.time);
+                        latencies.record_stage(
+                            &res.work,
+                            Stage::Parse,
+                            res.latency.saturating_sub(res.time),
+                        );
+                    }
+                    if let Some(ref tracer) = self.tracer {
+                        tracer.record(
+                            Span::position(batch_id, res.time)
+                                .attribute("fishnet.position", json!(res.position_id.0))
+                                .attribute("fishnet.engine", json!(pending.flavor.name()))
+                                .attribute("fishnet.depth", json!(res.depth))
+                                .attribute("fishnet.nodes", json!(res.nodes)),
+                        );
+                    }
+                    if let Some(ref events) = self.events {
+                        events.emit(&Event::position_done(&res));
+                    }
+                    let anomalies = if res.cached {
+                        Vec::new()
+                    } else {
+                        self.anomaly_detector.record(flavor, &res)
+                    };
                     if let Some(pos) = pending.positions.get_mut(res.position_id.0) {
+                        write_journal(&mut self.journal, &self.logger, |journal| {
+                            journal.completed(
+                                batch_id,
+                                res.position_id.0,
+                                WireResponse::from(res.clone()),
+                            )
+                        });
                         *pos = Some(Skip::Present(res));
                     }
+                    self.record_outcome(ErrorCategory::Engine(flavor), true);
+                    for anomaly in anomalies {
+                        self.record_anomaly(anomaly);
+                    }
                 }
                 self.logger.progress(self.status_bar(), progress_at);
                 self.maybe_finished(queue, batch_id);
             }
             Err(failed) => {
-                // Just forget about batches with failed positions,
+                // Skip a few positions that keep failing, for example on an
+                // engine assertion in an exotic position, rather than losing
+                // the analysis of the whole game.
+                let skipped = match self.pending.get_mut(&failed.batch_id) {
+                    Some(pending)
+                        if pending.work.is_analysis()
+                            && pending.skipped_failures < MAX_SKIPPED_FAILURES =>
+                    {
+                        match pending.positions.get_mut(failed.position_id.0) {
+                            Some(pos) => {
+                                *pos = Some(Skip::Skip);
+                                pending.skipped_failures += 1;
+                                Some(pending.flavor)
+                            }
+                            None => None,
+                        }
+                    }
+                    _ => None,
+                };
+                if let Some(flavor) = skipped {
+                    self.logger.warn(&format!(
+                        "Skipping {}. The rest of the batch will be submitted.",
+                        failed
+                    ));
+                    self.record_outcome(ErrorCategory::Engine(flavor), false);
+                    self.maybe_finished(queue, failed.batch_id);
+                    return;
+                }
+
+                // Otherwise just forget about batches with failed positions,
                 // intentionally letting them time out, instead of handing
                 // them to the next client.
-                self.pending.remove(&failed.batch_id);
+                if let Some(pending) = self.pending.remove(&failed.batch_id) {
+                    self.logger
+                        .warn(&format!("Dropping batch after failure of {}", failed));
+                    write_journal(&mut self.journal, &self.logger, |journal| {
+                        journal.closed(failed.batch_id)
+                    });
+                    METRICS.batches_failed.inc();
+                    self.stats_recorder.throughput.record_failed_batch();
+                    self.record_outcome(ErrorCategory::Engine(pending.flavor), false);
+                    if let Some(ref tracer) = self.tracer {
+                        tracer.record(
+                            Span::batch(failed.batch_id, pending.started_wall, SystemTime::now())
+                                .attribute("fishnet.variant", json!(pending.variant.to_string()))
+                                .attribute("fishnet.engine", json!(pending.flavor.name()))
+                                .error(),
+                        );
+                    }
+                }
                 self.incoming.retain(|p| p.work.id() != failed.batch_id);
             }
         }
     }
 
+    /// Drops a batch that the server cancelled, with the positions that were
+    /// not yet handed to workers. Workers stop searching positions of the
+    /// batch on their own.
+    fn cancel(&mut self, batch_id: BatchId) {
+        if self.pending.remove(&batch_id).is_some() {
+            self.logger.info(&format!(
+                "Dropping batch {} cancelled by the server",
+                batch_id
+            ));
+            write_journal(&mut self.journal, &self.logger, |journal| {
+                journal.closed(batch_id)
+            });
+            METRICS.batches_cancelled.inc();
+        }
+        self.incoming.retain(|p| p.work.id() != batch_id);
+    }
+
+    fn record_move_submitted(
+        &mut self,
+        work: &Work,
+        submit: Duration,
+        total: Duration,
+        budget: Option<Duration>,
+    ) {
+        let latencies = &mut self.stats_recorder.latencies;
+        latencies.record_stage(work, Stage::Submit, submit);
+        latencies.record_stage(work, Stage::Total, total);
+
+        let budget = match budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        if total > budget {
+            METRICS.move_budget_exceeded.inc();
+        }
+        let budget_millis = u64::try_from(budget.as_millis()).unwrap_or(u64::MAX);
+        let p99 = latencies.stage_p99(work, Stage::Total).unwrap_or_default();
+        let over_budget = p99 > budget_millis;
+        if over_budget && !self.over_budget {
+            let breakdown: Vec<_> = [
+                Stage::Validate,
+                Stage::QueueWait,
+                Stage::Search,
+                Stage::Parse,
+                Stage::Submit,
+            ]
+            .into_iter()
+            .filter_map(|stage| {
+                latencies
+                    .stage_p99(work, stage)
+                    .map(|millis| format!("{} {} ms", stage.name(), millis))
+            })
+            .collect();
+            self.logger.warn(&format!(
+                "Move latency p99 {} ms exceeds budget of {} ms (p99 by stage: {}).",
+                p99,
+                budget_millis,
+                breakdown.join(", ")
+            ));
+        } else if !over_budget && self.over_budget {
+            self.logger.info("Move latency is within budget again.");
+        }
+        self.over_budget = over_budget;
+    }
+
+    /// Reserved workers only take user requests.
     fn try_pull(
         &mut self,
         callback: oneshot::Sender<Position>,
+        reserved: bool,
     ) -> Result<(), oneshot::Sender<Position>> {
+        if reserved
+            && self
+                .incoming
+                .front()
+                .map_or(false, |pos| self.lane(&pos.work).0 != Priority::User)
+        {
+            return Err(callback);
+        }
         if let Some(position) = self.incoming.pop_front() {
             if let Err(err) = callback.send(position) {
                 self.incoming.push_front(err);
@@ -225,15 +899,69 @@ impl QueueState {
         }
     }
 
+    /// Removes the batch if all its positions are already analysed, for
+    /// example when it was resumed after the last position completed.
+    fn take_completed(&mut self, batch: BatchId) -> Option<CompletedBatch> {
+        match self.pending.remove(&batch)?.try_into_completed() {
+            Ok(completed) => {
+                write_journal(&mut self.journal, &self.logger, |journal| {
+                    journal.closed(batch)
+                });
+                Some(completed)
+            }
+            Err(pending) => {
+                self.pending.insert(batch, pending);
+                None
+            }
+        }
+    }
+
     fn maybe_finished(&mut self, mut queue: QueueStub, batch: BatchId) {
         if let Some(pending) = self.pending.remove(&batch) {
+            let started_wall = pending.started_wall;
+            let key = pending.acquired.as_ref().and_then(BatchKey::new);
             match pending.try_into_completed() {
                 Ok(completed) => {
+                    write_journal(&mut self.journal, &self.logger, |journal| {
+                        journal.closed(batch)
+                    });
+                    if let Some(key) = key {
+                        self.recent_batches.insert(
+                            key,
+                            completed.flavor.eval_flavor(),
+                            completed.node_scale,
+                            completed.clone().into_analysis(),
+                        );
+                    }
+                    if let Some(ref tracer) = self.tracer {
+                        tracer.record(
+                            Span::batch(batch, started_wall, SystemTime::now())
+                                .attribute("fishnet.variant", json!(completed.variant.to_string()))
+                                .attribute("fishnet.engine", json!(completed.flavor.name()))
+                                .attribute("fishnet.positions", json!(completed.total_positions()))
+                                .attribute("fishnet.nodes", json!(completed.total_nodes())),
+                        );
+                    }
+                    self.stats_recorder.throughput.record_batch(
+                        completed.total_positions(),
+                        completed.total_nodes(),
+                        completed.total_engine_time(),
+                        completed.total_cpu_time(),
+                    );
                     let mut extra = Vec::new();
                     extra.extend(completed.variant.short_name().map(|n| n.to_owned()));
                     if completed.flavor.eval_flavor().is_hce() {
                         extra.push("hce".to_owned());
                     }
+                    let wall_time = completed.wall_time();
+                    extra.push(format!("{:.1}s", wall_time.as_secs_f64()));
+                    if let Some(cpu_time) = completed.total_cpu_time() {
+                        extra.push(format!("{:.1}s cpu", cpu_time.as_secs_f64()));
+                    }
+                    extra.push(format!(
+                        "{:.1} positions/s",
+                        completed.total_positions() as f64 / wall_time.as_secs_f64().max(0.001)
+                    ));
                     extra.push(match completed.nps() {
                         Some(nps) => {
                             let nnue_nps = if completed.flavor.eval_flavor() == EvalFlavor::Nnue {
@@ -242,14 +970,26 @@ impl QueueState {
                                 None
                             };
                             self.stats_recorder.record_batch(
+                                completed.variant,
                                 completed.total_positions(),
                                 completed.total_nodes(),
+                                completed.total_cpu_time(),
                                 nnue_nps,
                             );
-                            format!("{} knps", nps / 1000)
+                            self.logger.nps(nps)
                         }
                         None => "? nps".to_owned(),
                     });
+                    self.record_provenance(&completed);
+                    if let Some(ref events) = self.events {
+                        events.emit(&Event::BatchSubmitted {
+                            batch: batch.to_string(),
+                            kind: completed.work.kind(),
+                            positions: completed.total_positions(),
+                            nodes: completed.total_nodes(),
+                            wall_time_ms: wall_time.as_millis() as u64,
+                        });
+                    }
                     let log = match completed.url {
                         Some(ref url) => format!(
                             "{} {} finished ({})",
@@ -264,20 +1004,19 @@ impl QueueState {
                             extra.join(", ")
                         ),
                     };
-                    match completed.work {
-                        Work::Analysis { id, .. } => {
-                            self.logger.info(&log);
-                            queue.api.submit_analysis(
-                                id,
-                                completed.flavor.eval_flavor(),
-                                completed.into_analysis(),
-                            );
-                        }
-                        Work::Move { .. } => {
-                            self.logger.debug(&log);
-                            self.move_submissions.push_back(completed);
-                            queue.move_submitted();
-                        }
+                    let handlers = self.handlers.clone();
+                    let deferred = self.move_submissions.len();
+                    handlers.get(&completed.work).submit(
+                        completed,
+                        &log,
+                        &mut Submission::new(
+                            &mut queue.api,
+                            &self.logger,
+                            &mut self.move_submissions,
+                        ),
+                    );
+                    if self.move_submissions.len() > deferred {
+                        queue.move_submitted();
                     }
                 }
                 Err(pending) => {
@@ -290,6 +1029,8 @@ impl QueueState {
                             queue.api.submit_analysis(
                                 pending.work.id(),
                                 pending.flavor.eval_flavor(),
+                                pending.node_scale,
+                                None,
                                 progress_report,
                             );
                         }
@@ -302,41 +1043,85 @@ impl QueueState {
     }
 }
 
+/// Journal failures are logged, but do not stop analysis.
+fn write_journal(
+    journal: &mut Option<Journal>,
+    logger: &Logger,
+    f: impl FnOnce(&mut Journal) -> io::Result<()>,
+) {
+    if let Some(journal) = journal {
+        if let Err(err) = f(journal) {
+            logger.error(&format!(
+                "Failed to write journal {:?}: {}",
+                journal.path(),
+                err
+            ));
+        }
+    }
+}
+
 #[derive(Debug)]
 enum QueueMessage {
-    Pull { callback: oneshot::Sender<Position> },
-    MoveSubmitted,
+    Pull {
+        callback: oneshot::Sender<Position>,
+        reserved: bool,
+    },
+    FollowUp,
 }
 
 pub struct QueueActor {
     rx: mpsc::UnboundedReceiver<QueueMessage>,
     interrupt: Arc<Notify>,
+    submit_ready: Arc<Notify>,
+    submitter: Option<MoveSubmitter>,
     state: Arc<Mutex<QueueState>>,
     api: ApiStub,
-    opt: BacklogOpt,
+    routing: Routing,
     backoff: RandomizedBackoff,
+    /// Do not acquire ahead of the workers before this, for example after
+    /// the server had no work.
+    prefetch_after: Instant,
     logger: Logger,
 }
 
 impl QueueActor {
-    pub async fn run(self) {
+    pub async fn run(mut self) {
         self.logger.debug("Queue actor started");
+        let submitter = self
+            .submitter
+            .take()
+            .map(|submitter| tokio::spawn(submitter.run()));
+        let cancel_watcher = tokio::spawn(
+            CancelWatcher {
+                state: self.state.clone(),
+                api: self.api.clone(),
+            }
+            .run(),
+        );
+        self.resume().await;
         self.run_inner().await;
+        cancel_watcher.abort();
+        if let Some(submitter) = submitter {
+            submitter.await.expect("join");
+        }
     }
 
     pub async fn backlog_wait_time(&mut self) -> (Duration, AcquireQuery) {
         let sec = Duration::from_secs(1);
-        let min_user_backlog = {
+        let (min_user_backlog, opt) = {
             let state = self.state.lock().await;
-            state.stats_recorder.min_user_backlog()
+            (
+                state.stats_recorder.min_user_backlog(),
+                state.backlog.clone(),
+            )
         };
         let user_backlog = max(
             min_user_backlog,
-            self.opt.user.map(Duration::from).unwrap_or_default(),
+            opt.user.map(Duration::from).unwrap_or_default(),
         );
-        let system_backlog = self.opt.system.map(Duration::from).unwrap_or_default();
+        let system_backlog = opt.system.map(Duration::from).unwrap_or_default();
 
-        if user_backlog >= sec || system_backlog >= sec {
+        let (wait, slow) = if user_backlog >= sec || system_backlog >= sec {
             if let Some(status) = self.api.status().await {
                 let user_wait = user_backlog
                     .checked_sub(status.user.oldest)
@@ -348,70 +1133,338 @@ impl QueueActor {
                        user_wait, user_backlog, status.user.oldest,
                        system_wait, system_backlog, status.system.oldest));
                 let slow = user_wait >= system_wait + sec;
-                (min(user_wait, system_wait), AcquireQuery { slow })
+                (min(user_wait, system_wait), slow)
             } else {
                 self.logger
                     .debug("Queue status not available. Will not delay acquire.");
                 let slow = user_backlog >= system_backlog + sec;
-                (Duration::default(), AcquireQuery { slow })
+                (Duration::default(), slow)
             }
         } else {
-            (Duration::default(), AcquireQuery { slow: false })
+            (Duration::default(), false)
+        };
+
+        // Let the server skip variants that would be rejected anyway.
+        let variants = self
+            .routing
+            .variants
+            .accepted()
+            .map(|variants| variants.to_string());
+        // Moves are only played with Fairy-Stockfish.
+        let work = (!self.routing.engines.multi_variant).then(|| "analysis,playout".to_owned());
+        (
+            wait,
+            AcquireQuery {
+                slow,
+                tiers: Tier::ALL.to_vec(),
+                variants,
+                work,
+            },
+        )
+    }
+
+    /// Takes over analysis that was saved when a previous session stopped,
+    /// or recovered from the journal after a crash.
+    async fn resume(&mut self) {
+        match checkpoint::take(self.api.endpoint().url.as_str()) {
+            Ok(batches) => {
+                for batch in batches {
+                    self.resume_batch(batch, "Resuming").await;
+                }
+            }
+            Err(err) => self
+                .logger
+                .warn(&format!("Failed to load unfinished batches: {}", err)),
+        }
+
+        let recovered = {
+            let mut state = self.state.lock().await;
+            match state.journal {
+                Some(ref mut journal) => journal.recover(),
+                None => Ok(Vec::new()),
+            }
+        };
+        match recovered {
+            Ok(batches) => {
+                for batch in batches {
+                    let batch_id = batch.body.work.id();
+                    let context = ProgressAt {
+                        batch_id,
+                        batch_url: batch.body.batch_url(self.api.endpoint()),
+                        position_id: None,
+                    };
+                    if batch.endpoint != self.api.endpoint().url.as_str() {
+                        self.logger.warn(&format!(
+                            "Dropping batch {} from the journal, because it was acquired from {}",
+                            context, batch.endpoint
+                        ));
+                    } else if batch.is_expired() {
+                        self.logger.warn(&format!(
+                            "Aborting batch {} from the journal, because it is too old",
+                            context
+                        ));
+                        METRICS.batches_failed.inc();
+                        self.api.abort(batch_id);
+                    } else if !self.resume_batch(batch, "Recovering").await {
+                        // Hand it to the next client right away.
+                        METRICS.batches_failed.inc();
+                        self.api.abort(batch_id);
+                    }
+                }
+            }
+            Err(err) => self
+                .logger
+                .warn(&format!("Failed to recover batches from journal: {}", err)),
+        }
+    }
+
+    async fn resume_batch(&mut self, batch: BatchCheckpoint, verb: &str) -> bool {
+        let context = ProgressAt {
+            batch_id: batch.body.work.id(),
+            batch_url: batch.body.batch_url(self.api.endpoint()),
+            position_id: None,
+        };
+        let handler = self.routing.handlers.get(&batch.body.work);
+        match IncomingBatch::from_acquired(
+            self.api.endpoint(),
+            batch.body,
+            &self.routing,
+            batch.node_scale,
+            Instant::now(),
+            handler,
+        ) {
+            Ok(mut incoming) if *self.routing.engines.get(incoming.flavor) => {
+                incoming.resume(batch.completed);
+                let resumed = incoming.completed.iter().flatten().count();
+                self.logger.fishnet_info(&format!(
+                    "{} batch {} with {} of {} positions already analysed",
+                    verb,
+                    context,
+                    resumed,
+                    incoming.positions.len()
+                ));
+                let id = incoming.work.id();
+                let mut state = self.state.lock().await;
+                state.add_incoming_batch(incoming);
+                // Crashed after the last position, but before submitting.
+                if let Some(completed) = state.take_completed(id) {
+                    state.record_provenance(&completed);
+                    let deferred = state.move_submissions.len();
+                    handler.submit(
+                        completed,
+                        &format!("Submitting completed batch {}.", context),
+                        &mut Submission::new(
+                            &mut self.api,
+                            &self.logger,
+                            &mut state.move_submissions,
+                        ),
+                    );
+                    if state.move_submissions.len() > deferred {
+                        self.submit_ready.notify_one();
+                    }
+                }
+                true
+            }
+            Ok(_) | Err(_) => {
+                self.logger
+                    .warn(&format!("Dropping unfinished batch {}", context));
+                false
+            }
         }
     }
 
     async fn handle_acquired_response_body(&mut self, body: AcquireResponseBody) {
+        METRICS.batches_acquired.inc();
+        let acquired_at = Instant::now();
+
         let context = ProgressAt {
             batch_id: body.work.id(),
             batch_url: body.batch_url(self.api.endpoint()),
             position_id: None,
         };
 
-        match IncomingBatch::from_acquired(self.api.endpoint(), body) {
+        let variant = body.variant;
+        let degraded = {
+            let state = self.state.lock().await;
+            state
+                .error_budget
+                .degraded(ErrorCategory::Validation(variant))
+        };
+        if let Some(left) = degraded {
+            self.logger.warn(&format!(
+                "Aborting {} batch {}, because too many recent batches of this variant were invalid. Retrying in {:?}.",
+                variant, context, left
+            ));
+            METRICS.batches_failed.inc();
+            self.api.abort(body.work.id());
+            return;
+        }
+
+        // Only if the server ignores the variants of the acquire request.
+        if !self.routing.variants.allows(body.variant) {
+            self.logger.warn(&format!(
+                "Aborting {} batch {}, because the variant is not enabled for this endpoint.",
+                body.variant, context
+            ));
+            METRICS.batches_failed.inc();
+            self.api.abort(body.work.id());
+            return;
+        }
+
+        // The same batch again, for example because the server did not
+        // record a submission that timed out. Resubmit instead of analysing
+        // the whole game again.
+        if let Some(key) = BatchKey::new(&body) {
+            let recent = self.state.lock().await.recent_batches.get(key).cloned();
+            if let Some(recent) = recent {
+                self.logger.info(&format!(
+                    "Resubmitting analysis of identical batch {}",
+                    context
+                ));
+                METRICS.batches_submitted.inc();
+                self.api.submit_analysis(
+                    body.work.id(),
+                    recent.flavor,
+                    recent.node_scale,
+                    None,
+                    recent.analysis,
+                );
+                return;
+            }
+        }
+
+        let handlers = self.routing.handlers.clone();
+        let handler = handlers.get(&body.work);
+
+        let node_scale = match body.node_scale {
+            Some(bounds) if self.routing.scale_nodes => {
+                let state = self.state.lock().await;
+                state
+                    .stats_recorder
+                    .nnue_nps
+                    .node_scale(state.cores, bounds)
+            }
+            _ => None,
+        };
+
+        // Validate on the blocking thread pool. Long games of some variants
+        // take a while to replay, and should not hold up the engine workers
+        // on the runtime in the meantime.
+        let work = body.work.clone();
+        let (incoming, dumped) = {
+            let endpoint = self.api.endpoint().clone();
+            let routing = self.routing.clone();
+            let invalid_batch_dump = self.routing.invalid_batch_dump.clone();
+            let handlers = handlers.clone();
+            task::spawn_blocking(move || {
+                let handler = handlers.get(&body.work);
+                let dump_body = invalid_batch_dump.as_ref().map(|_| body.clone());
+                let incoming = IncomingBatch::from_acquired(
+                    &endpoint,
+                    body,
+                    &routing,
+                    node_scale,
+                    acquired_at,
+                    handler,
+                );
+                let dumped = match (&incoming, invalid_batch_dump, dump_body) {
+                    (Err(IncomingError::Invalid(err)), Some(dump), Some(body)) => {
+                        Some(dump.write(&body, err))
+                    }
+                    _ => None,
+                };
+                (incoming, dumped)
+            })
+            .await
+            .expect("join")
+        };
+        self.state
+            .lock()
+            .await
+            .stats_recorder
+            .latencies
+            .record_stage(&work, Stage::Validate, acquired_at.elapsed());
+
+        match incoming {
+            Ok(incoming)
+                if self
+                    .state
+                    .lock()
+                    .await
+                    .error_budget
+                    .degraded(ErrorCategory::Engine(incoming.flavor))
+                    .is_some() =>
+            {
+                self.logger.warn(&format!(
+                    "Aborting batch {}, because too many recent positions failed with the {} engine.",
+                    context,
+                    incoming.flavor.name()
+                ));
+                METRICS.batches_failed.inc();
+                self.api.abort(incoming.work.id());
+            }
+            // Only if the server ignores the variants and work types of the
+            // acquire request.
+            Ok(incoming) if !self.routing.engines.get(incoming.flavor) => {
+                self.logger.warn(&format!(
+                    "Aborting batch {}, because {} is disabled.",
+                    context,
+                    match incoming.flavor {
+                        EngineFlavor::Official => "official Stockfish",
+                        EngineFlavor::MultiVariant => "Fairy-Stockfish",
+                    }
+                ));
+                METRICS.batches_failed.inc();
+                self.api.abort(incoming.work.id());
+            }
             Ok(incoming) => {
                 let mut state = self.state.lock().await;
+                state.record_outcome(ErrorCategory::Validation(variant), true);
                 state.add_incoming_batch(incoming);
             }
             Err(IncomingError::AllSkipped(completed)) => {
-                self.logger
-                    .warn(&format!("Completed empty batch {}.", context));
-                self.api.submit_analysis(
-                    completed.work.id(),
-                    completed.flavor.eval_flavor(),
-                    completed.into_analysis(),
+                let mut state = self.state.lock().await;
+                state.record_provenance(&completed);
+                let deferred = state.move_submissions.len();
+                handler.submit(
+                    completed,
+                    &format!("Completed empty batch {}.", context),
+                    &mut Submission::new(&mut self.api, &self.logger, &mut state.move_submissions),
                 );
+                if state.move_submissions.len() > deferred {
+                    self.submit_ready.notify_one();
+                }
             }
             Err(err) => {
+                METRICS.batches_failed.inc();
+                self.state
+                    .lock()
+                    .await
+                    .record_outcome(ErrorCategory::Validation(variant), false);
                 self.logger
                     .warn(&format!("Ignoring invalid batch {}: {:?}", context, err));
+                match dumped {
+                    Some(Ok(path)) => self
+                        .logger
+                        .warn(&format!("Invalid batch {} dumped to {:?}", context, path)),
+                    Some(Err(err)) => self.logger.error(&format!(
+                        "Failed to dump invalid batch {}: {}",
+                        context, err
+                    )),
+                    None => (),
+                }
             }
         }
     }
 
-    async fn handle_move_submissions(&mut self) {
+    /// Takes over batches that were acquired together with move
+    /// submissions.
+    async fn handle_follow_ups(&mut self) {
         loop {
-            let next = {
-                let mut state = self.state.lock().await;
-                if state.shutdown_soon {
-                    // Each move submision can come with a follow-up task,
-                    // so we might never finish if we keep submitting.
-                    // Just drop some. They are short-lived anyway.
-                    break;
-                }
-
-                state.move_submissions.pop_front()
-            };
-
-            if let Some(completed) = next {
-                if let Some(Acquired::Accepted(body)) = self
-                    .api
-                    .submit_move_and_acquire(completed.work.id(), completed.into_best_move())
-                    .await
-                {
-                    self.handle_acquired_response_body(body).await;
-                }
-            } else {
-                break;
+            let next = self.state.lock().await.follow_ups.pop_front();
+            match next {
+                Some(body) => self.handle_acquired_response_body(body).await,
+                None => break,
             }
         }
     }
@@ -419,64 +1472,276 @@ impl QueueActor {
     async fn run_inner(mut self) {
         while let Some(msg) = self.rx.recv().await {
             match msg {
-                QueueMessage::Pull { mut callback } => loop {
-                    self.handle_move_submissions().await;
-
-                    {
-                        let mut state = self.state.lock().await;
-                        callback = match state.try_pull(callback) {
-                            Ok(()) => break,
-                            Err(not_done) => not_done,
-                        };
+                QueueMessage::Pull {
+                    mut callback,
+                    reserved,
+                } => {
+                    let mut served = false;
+                    loop {
+                        self.handle_follow_ups().await;
 
-                        if state.shutdown_soon {
-                            break;
-                        }
-                    }
+                        {
+                            let mut state = self.state.lock().await;
+                            callback = match state.try_pull(callback, reserved) {
+                                Ok(()) => {
+                                    served = true;
+                                    break;
+                                }
+                                Err(not_done) => not_done,
+                            };
 
-                    let (wait, query) = tokio::select! {
-                        _ = callback.closed() => break,
-                        res = self.backlog_wait_time() => res,
-                    };
+                            if state.shutdown_soon {
+                                break;
+                            }
 
-                    if wait >= Duration::from_secs(1) {
-                        if wait >= Duration::from_secs(40) {
-                            self.logger.info(&format!("Going idle for {:?}.", wait));
-                        } else {
-                            self.logger.debug(&format!("Going idle for {:?}.", wait));
+                            // Other workers take care of system analysis. Wait
+                            // for user requests without acquiring more.
+                            if reserved && !state.incoming.is_empty() {
+                                state.parked.push(callback);
+                                break;
+                            }
                         }
 
-                        tokio::select! {
+                        let (wait, query) = tokio::select! {
                             _ = callback.closed() => break,
-                            _ = self.interrupt.notified() => continue,
-                            _ = time::sleep(wait) => continue,
+                            res = self.backlog_wait_time() => res,
+                        };
+
+                        if wait >= Duration::from_secs(1) {
+                            if wait >= Duration::from_secs(40) {
+                                self.logger.info(&format!("Going idle for {:?}.", wait));
+                            } else {
+                                self.logger.debug(&format!("Going idle for {:?}.", wait));
+                            }
+
+                            tokio::select! {
+                                _ = callback.closed() => break,
+                                _ = self.interrupt.notified() => continue,
+                                _ = time::sleep(wait) => continue,
+                            }
                         }
-                    }
 
-                    match self.api.acquire(query).await {
-                        Some(Acquired::Accepted(body)) => {
-                            self.backoff.reset();
-                            self.handle_acquired_response_body(body).await;
+                        let degraded = {
+                            let state = self.state.lock().await;
+                            state.error_budget.degraded(ErrorCategory::Api)
+                        };
+                        if let Some(left) = degraded {
+                            self.logger.debug(&format!(
+                                "Not acquiring for {:?} after repeated API errors.",
+                                left
+                            ));
+                            tokio::select! {
+                                _ = callback.closed() => break,
+                                _ = self.interrupt.notified() => continue,
+                                _ = time::sleep(left) => continue,
+                            }
                         }
-                        Some(Acquired::NoContent) => {
-                            let backoff = self.backoff.next();
-                            self.logger
-                                .debug(&format!("No job received. Backing off {:?}.", backoff));
+
+                        // Stay within the quota announced by the server,
+                        // rather than running into 429s.
+                        if let Some(pace) = self.api.quota().and_then(|quota| quota.pace()) {
+                            self.logger.debug(&format!(
+                                "Pacing acquire requests to the server quota. Waiting {:?}.",
+                                pace
+                            ));
                             tokio::select! {
                                 _ = callback.closed() => break,
                                 _ = self.interrupt.notified() => (),
-                                _ = time::sleep(backoff) => (),
+                                _ = time::sleep(pace) => (),
                             }
                         }
-                        Some(Acquired::Rejected) => {
-                            self.logger.error("Client update or reconfiguration might be required. Stopping queue.");
-                            let mut state = self.state.lock().await;
-                            state.shutdown_soon = true;
+
+                        match self.acquire(query).await {
+                            Some(Acquired::Accepted(body)) => {
+                                self.backoff.reset();
+                                self.handle_acquired_response_body(body).await;
+                            }
+                            Some(Acquired::NoContent) => {
+                                let backoff = self.backoff.next();
+                                self.prefetch_after = Instant::now() + backoff;
+                                self.logger
+                                    .debug(&format!("No job received. Backing off {:?}.", backoff));
+                                tokio::select! {
+                                    _ = callback.closed() => break,
+                                    _ = self.interrupt.notified() => (),
+                                    _ = time::sleep(backoff) => (),
+                                }
+                            }
+                            Some(Acquired::Rejected) => {
+                                self.logger.error("Client update or reconfiguration might be required. Stopping queue.");
+                                let mut state = self.state.lock().await;
+                                state.shutdown_soon = true;
+                                self.submit_ready.notify_one();
+                            }
+                            None => (),
                         }
-                        None => (),
                     }
+                    if served {
+                        self.prefetch().await;
+                    }
+                }
+                QueueMessage::FollowUp => self.handle_follow_ups().await,
+            }
+        }
+    }
+
+    async fn acquire(&mut self, query: AcquireQuery) -> Option<Acquired> {
+        let acquire_started = Instant::now();
+        let acquired = self.api.acquire(query).await;
+        let mut state = self.state.lock().await;
+        state.connected = acquired.is_some();
+        state.quota = self.api.quota();
+        state.record_outcome(ErrorCategory::Api, acquired.is_some());
+        if let Some(Acquired::Accepted(ref body)) = acquired {
+            state.stats_recorder.latencies.record_stage(
+                &body.work,
+                Stage::Acquire,
+                acquire_started.elapsed(),
+            );
+        }
+        acquired
+    }
+
+    /// Acquires another batch while the workers are still busy, if fewer
+    /// positions than the lookahead are queued. Never waits: Backlog,
+    /// backoff and quota are left to the next worker that runs out of work.
+    async fn prefetch(&mut self) {
+        if Instant::now() < self.prefetch_after {
+            return;
+        }
+        {
+            let state = self.state.lock().await;
+            if state.shutdown_soon
+                || state.incoming.len() >= state.lookahead()
+                || state.error_budget.degraded(ErrorCategory::Api).is_some()
+            {
+                return;
+            }
+        }
+        if self.api.quota().and_then(|quota| quota.pace()).is_some() {
+            return;
+        }
+        let (wait, query) = self.backlog_wait_time().await;
+        if wait >= Duration::from_secs(1) {
+            self.prefetch_after = Instant::now() + wait;
+            return;
+        }
+        match self.acquire(query).await {
+            Some(Acquired::Accepted(body)) => {
+                self.logger.debug(&format!(
+                    "Acquired batch {} ahead of the workers.",
+                    body.work.id()
+                ));
+                self.backoff.reset();
+                self.handle_acquired_response_body(body).await;
+            }
+            Some(Acquired::NoContent) => {
+                self.prefetch_after = Instant::now() + self.backoff.next();
+            }
+            Some(Acquired::Rejected) | None => (),
+        }
+    }
+}
+
+/// Submits completed move requests, and hands the batches acquired with
+/// them to the queue actor. Runs as a separate task with its own API actor,
+/// so that moves do not wait for the queue actor or for other requests to
+/// the server.
+/// Drops batches that the server cancelled.
+struct CancelWatcher {
+    state: Arc<Mutex<QueueState>>,
+    api: ApiStub,
+}
+
+impl CancelWatcher {
+    async fn run(mut self) {
+        let mut cancellations = self.api.cancellations().subscribe();
+        let mut poll = time::interval(CANCEL_POLL_INTERVAL);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                res = cancellations.recv() => match res {
+                    Ok(batch_id) => self.state.lock().await.cancel(batch_id),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
                 },
-                QueueMessage::MoveSubmitted => self.handle_move_submissions().await,
+                _ = poll.tick() => {
+                    let long_running = self
+                        .state
+                        .lock()
+                        .await
+                        .pending
+                        .values()
+                        .any(|pending| pending.started_at.elapsed() >= CANCEL_POLL_INTERVAL);
+                    if long_running {
+                        // Cancellations in the response are delivered
+                        // through the subscription.
+                        self.api.status().await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct MoveSubmitter {
+    tx: mpsc::UnboundedSender<QueueMessage>,
+    ready: Arc<Notify>,
+    interrupt: Arc<Notify>,
+    state: Arc<Mutex<QueueState>>,
+    api: ApiStub,
+    budget: Option<Duration>,
+    logger: Logger,
+}
+
+impl MoveSubmitter {
+    async fn run(mut self) {
+        self.logger.debug("Move submitter started");
+        loop {
+            self.ready.notified().await;
+            loop {
+                let next = {
+                    let mut state = self.state.lock().await;
+                    if state.shutdown_soon {
+                        // Each move submision can come with a follow-up
+                        // task, so we might never finish if we keep
+                        // submitting. Just drop some. They are short-lived
+                        // anyway.
+                        self.logger.debug("Move submitter exited");
+                        return;
+                    }
+                    state.move_submissions.pop_front()
+                };
+
+                let completed = match next {
+                    Some(completed) => completed,
+                    None => break,
+                };
+                METRICS.batches_submitted.inc();
+                let work = completed.work.clone();
+                let acquired_at = completed.acquired_at;
+                let started = Instant::now();
+                let acquired = self
+                    .api
+                    .submit_move_and_acquire(work.id(), completed.into_best_move())
+                    .await;
+
+                let mut state = self.state.lock().await;
+                state.record_move_submitted(
+                    &work,
+                    started.elapsed(),
+                    acquired_at.elapsed(),
+                    self.budget,
+                );
+                if let Some(Acquired::Accepted(body)) = acquired {
+                    state.follow_ups.push_back(body);
+                    self.tx
+                        .send(QueueMessage::FollowUp)
+                        .nevermind("queue dropped");
+
+                    // Skip the queue backoff.
+                    self.interrupt.notify_one();
+                }
             }
         }
     }
@@ -489,13 +1754,13 @@ impl Drop for QueueActor {
 }
 
 #[derive(Debug, Clone)]
-enum Skip<T> {
+pub enum Skip<T> {
     Present(T),
     Skip,
 }
 
 impl<T> Skip<T> {
-    fn is_skipped(&self) -> bool {
+    pub fn is_skipped(&self) -> bool {
         matches!(self, Skip::Skip)
     }
 }
@@ -505,121 +1770,241 @@ pub struct IncomingBatch {
     work: Work,
     flavor: EngineFlavor,
     variant: LichessVariant,
+    root_turn: Color,
     positions: Vec<Skip<Position>>,
     url: Option<Url>,
+    node_scale: Option<f64>,
+    san_roots: Vec<Option<VariantPosition>>,
+    /// Whether to mark puzzle candidates in the analysis.
+    puzzle_candidates: bool,
+    acquired_at: Instant,
+    /// Batch as acquired, to save analysis on shutdown.
+    acquired: Option<AcquireResponseBody>,
+    priority: Priority,
+    /// Results restored from a checkpoint, by position id.
+    completed: Vec<Option<PositionResponse>>,
 }
 
 impl IncomingBatch {
     fn from_acquired(
         endpoint: &Endpoint,
-        body: AcquireResponseBody,
+        mut body: AcquireResponseBody,
+        routing: &Routing,
+        node_scale: Option<f64>,
+        acquired_at: Instant,
+        handler: &dyn WorkHandler,
     ) -> Result<IncomingBatch, IncomingError> {
+        let Routing {
+            force_multi_variant,
+            pv_san,
+            limits,
+            budget_by_phase,
+            puzzle_candidates,
+            ..
+        } = *routing;
+        let book_skip = routing.book_skip.as_deref();
         let url = body.batch_url(endpoint);
+        let priority = body.priority();
+        let acquired = body.work.is_analysis().then(|| body.clone());
+
+        // The tier is relative to the request of the server, and scaled like
+        // it.
+        apply_tier(&mut body.work);
+        let node_scale = match (node_scale, &mut body.work) {
+            (Some(scale), Work::Analysis { nodes, .. }) => {
+                *nodes = nodes.scaled(scale);
+                Some(scale)
+            }
+            _ => None,
+        };
+        // Local limits apply after scaling, so that they are never exceeded.
+        limits.apply(&mut body.work);
 
-        let maybe_root_pos = VariantPosition::from_setup(
-            body.variant.into(),
-            &body.position,
-            CastlingMode::Chess960,
-        );
+        validate_engine_options(body.work.engine_options())?;
+        let game = validate_game_fen(body.variant, &body.position, body.moves)?;
 
-        let (flavor, root_pos) = match maybe_root_pos {
-            Ok(pos @ VariantPosition::Chess(_)) if body.work.is_analysis() => {
-                (EngineFlavor::Official, pos)
+        // Opening book positions are known, and not worth the engine time.
+        if let Some(book_skip) = book_skip.filter(|_| body.work.is_analysis()) {
+            body.skip_positions
+                .extend(book_skip.plies(&game.root, &game.moves));
+        }
+
+        // Moves are played with Fairy-Stockfish, for its skill levels.
+        let flavor = match game.root {
+            VariantPosition::Chess(_)
+                if !matches!(body.work, Work::Move { .. })
+                    && !force_multi_variant
+                    && !game.impossible_material =>
+            {
+                EngineFlavor::Official
             }
-            Ok(pos) => (EngineFlavor::MultiVariant, pos),
-            Err(pos) => (
-                EngineFlavor::MultiVariant,
-                pos.ignore_impossible_material()?,
-            ),
+            _ => EngineFlavor::MultiVariant,
         };
 
-        let root_fen = Fen::from_setup(&root_pos);
+        let root_fen = Fen::from_setup(&game.root);
+        let root_turn = game.root.turn();
+        let body_moves = game.moves;
 
-        let body_moves = {
-            let mut moves = Vec::with_capacity(body.moves.len());
-            let mut pos = root_pos;
-            for uci in body.moves {
-                let m = uci.to_move(&pos)?;
-                moves.push(m.to_uci(CastlingMode::Chess960));
-                pos.play_unchecked(&m);
+        let mut positions: Vec<_> = handler
+            .plies(&body.work, body_moves.len(), &body.skip_positions)
+            .into_iter()
+            .enumerate()
+            .map(|(i, ply)| match ply {
+                Skip::Present(ply) => Skip::Present(Position {
+                    work: body.work.clone(),
+                    url: url.clone().map(|mut url| {
+                        url.set_fragment(Some(&ply.to_string()));
+                        url
+                    }),
+                    flavor,
+                    position_id: PositionId(i),
+                    variant: body.variant,
+                    root_fen: root_fen.clone(),
+                    moves: body_moves[..min(ply, body_moves.len())].to_vec(),
+                }),
+                Skip::Skip => Skip::Skip,
+            })
+            .collect();
+
+        if budget_by_phase && body.work.is_analysis() {
+            let weights = phase_weights(&game.root, &body_moves);
+            let present: Vec<f64> = positions
+                .iter()
+                .filter_map(|pos| match pos {
+                    Skip::Present(pos) => weights.get(pos.moves.len()).copied(),
+                    Skip::Skip => None,
+                })
+                .collect();
+            let mean = present.iter().sum::<f64>() / present.len().max(1) as f64;
+            for pos in positions.iter_mut() {
+                if let Skip::Present(pos) = pos {
+                    if let (Some(weight), Work::Analysis { nodes, .. }) =
+                        (weights.get(pos.moves.len()), &mut pos.work)
+                    {
+                        *nodes = nodes.scaled(weight / mean);
+                    }
+                    limits.apply(&mut pos.work);
+                }
             }
-            moves
+        }
+
+        let san_roots = if pv_san && body.work.is_analysis() {
+            positions
+                .iter()
+                .map(|pos| match pos {
+                    Skip::Present(pos) => san::position_after(&game.root, &pos.moves),
+                    Skip::Skip => None,
+                })
+                .collect()
+        } else {
+            Vec::new()
         };
 
+        // Edge case: Batch is immediately completed, because all positions
+        // are skipped.
+        if positions.iter().all(Skip::is_skipped) {
+            let now = Instant::now();
+            return Err(IncomingError::AllSkipped(CompletedBatch {
+                work: body.work,
+                url,
+                flavor,
+                variant: body.variant,
+                root_turn,
+                positions: positions.into_iter().map(|_| Skip::Skip).collect(),
+                node_scale,
+                san_roots,
+                puzzle_candidates,
+                acquired_at,
+                started_at: now,
+                completed_at: now,
+            }));
+        }
+
         Ok(IncomingBatch {
-            work: body.work.clone(),
-            url: url.clone(),
+            work: body.work,
+            url,
             flavor,
             variant: body.variant,
-            positions: match body.work {
-                Work::Move { .. } => {
-                    vec![Skip::Present(Position {
-                        work: body.work,
-                        url,
-                        flavor,
-                        position_id: PositionId(0),
-                        variant: body.variant,
-                        root_fen,
-                        moves: body_moves,
-                    })]
-                }
-                Work::Analysis { .. } => {
-                    let mut moves = Vec::new();
-                    let mut positions = vec![Skip::Present(Position {
-                        work: body.work.clone(),
-                        url: url.clone().map(|mut url| {
-                            url.set_fragment(Some("0"));
-                            url
-                        }),
-                        flavor,
-                        position_id: PositionId(0),
-                        variant: body.variant,
-                        root_fen: root_fen.clone(),
-                        moves: moves.clone(),
-                    })];
-
-                    for (i, m) in body_moves.into_iter().enumerate() {
-                        moves.push(m);
-                        positions.push(Skip::Present(Position {
-                            work: body.work.clone(),
-                            url: url.clone().map(|mut url| {
-                                url.set_fragment(Some(&(1 + i).to_string()));
-                                url
-                            }),
-                            flavor,
-                            position_id: PositionId(1 + i),
-                            variant: body.variant,
-                            root_fen: root_fen.clone(),
-                            moves: moves.clone(),
-                        }));
-                    }
+            root_turn,
+            positions,
+            node_scale,
+            san_roots,
+            puzzle_candidates,
+            acquired_at,
+            acquired,
+            priority,
+            completed: Vec::new(),
+        })
+    }
 
-                    for skip in body.skip_positions.into_iter() {
-                        if let Some(pos) = positions.get_mut(skip) {
-                            *pos = Skip::Skip;
-                        }
-                    }
+    /// Takes over results from a checkpoint, so that only the remaining
+    /// positions are analysed.
+    fn resume(&mut self, completed: Vec<Option<WireResponse>>) {
+        self.completed = self
+            .positions
+            .iter()
+            .zip(completed)
+            .map(|(pos, res)| match (pos, res) {
+                (Skip::Present(pos), Some(res)) => Some(res.into_response(pos.clone())),
+                _ => None,
+            })
+            .collect();
+    }
+}
 
-                    // Edge case: Batch is immediately completed, because all
-                    // positions are skipped.
-                    if positions.iter().all(Skip::is_skipped) {
-                        let now = Instant::now();
-                        return Err(IncomingError::AllSkipped(CompletedBatch {
-                            work: body.work,
-                            url,
-                            flavor,
-                            variant: body.variant,
-                            positions: positions.into_iter().map(|_| Skip::Skip).collect(),
-                            started_at: now,
-                            completed_at: now,
-                        }));
-                    }
+/// Relative node budget of positions without much to decide: forced
+/// moves, and endings without mating material.
+const FORCED_WEIGHT: f64 = 0.25;
+/// Relative node budget of positions with most pieces still on the board,
+/// which are usually well known.
+const OPENING_WEIGHT: f64 = 0.75;
+/// Relative node budget of middlegame positions.
+const MIDDLEGAME_WEIGHT: f64 = 1.25;
+/// Factor for positions right before or after a capture or check.
+const VOLATILE_FACTOR: f64 = 1.5;
+
+fn phase_weight(pos: &VariantPosition) -> f64 {
+    if pos.legal_moves().len() <= 1 || pos.is_insufficient_material() {
+        return FORCED_WEIGHT;
+    }
+    let board = pos.board();
+    // 24 with all pieces on the board, 0 in pawn endings.
+    let phase = (board.knights() | board.bishops()).count()
+        + 2 * board.rooks().count()
+        + 4 * board.queens().count();
+    if phase >= 22 {
+        OPENING_WEIGHT
+    } else if phase >= 10 {
+        MIDDLEGAME_WEIGHT
+    } else {
+        1.0
+    }
+}
 
-                    positions
-                }
-            },
-        })
+/// Relative node budget of each ply of the game, by phase. Evaluations are
+/// not known before the analysis, so volatility is judged by captures and
+/// checks around each position.
+fn phase_weights(root: &VariantPosition, moves: &[Uci]) -> Vec<f64> {
+    let mut weights = Vec::with_capacity(moves.len() + 1);
+    let mut volatile = Vec::with_capacity(moves.len());
+    let mut pos = root.clone();
+    loop {
+        weights.push(phase_weight(&pos));
+        let m = match moves.get(volatile.len()).map(|uci| uci.to_move(&pos)) {
+            Some(Ok(m)) => m,
+            _ => break,
+        };
+        pos.play_unchecked(&m);
+        volatile.push(m.is_capture() || pos.is_check());
+    }
+    for (ply, weight) in weights.iter_mut().enumerate() {
+        let before = ply.checked_sub(1).map_or(false, |prev| volatile[prev]);
+        let after = volatile.get(ply).copied().unwrap_or(false);
+        if *weight > FORCED_WEIGHT && (before || after) {
+            *weight *= VOLATILE_FACTOR;
+        }
     }
+    weights
 }
 
 impl From<&IncomingBatch> for ProgressAt {
@@ -634,20 +2019,13 @@ impl From<&IncomingBatch> for ProgressAt {
 
 #[derive(Debug)]
 enum IncomingError {
-    Position(PositionError<VariantPosition>),
-    IllegalUci(IllegalUciError),
+    Invalid(ValidationError),
     AllSkipped(CompletedBatch),
 }
 
-impl From<PositionError<VariantPosition>> for IncomingError {
-    fn from(err: PositionError<VariantPosition>) -> IncomingError {
-        IncomingError::Position(err)
-    }
-}
-
-impl From<IllegalUciError> for IncomingError {
-    fn from(err: IllegalUciError) -> IncomingError {
-        IncomingError::IllegalUci(err)
+impl From<ValidationError> for IncomingError {
+    fn from(err: ValidationError) -> IncomingError {
+        IncomingError::Invalid(err)
     }
 }
 
@@ -657,8 +2035,21 @@ struct PendingBatch {
     url: Option<Url>,
     flavor: EngineFlavor,
     variant: LichessVariant,
+    root_turn: Color,
     positions: Vec<Option<Skip<PositionResponse>>>,
+    node_scale: Option<f64>,
+    /// Analysed positions by position id, if principal variations should
+    /// be converted to SAN.
+    san_roots: Vec<Option<VariantPosition>>,
+    /// Whether to mark puzzle candidates in the analysis.
+    puzzle_candidates: bool,
+    acquired_at: Instant,
     started_at: Instant,
+    started_wall: SystemTime,
+    acquired: Option<AcquireResponseBody>,
+    priority: Priority,
+    /// Positions skipped because they kept failing.
+    skipped_failures: usize,
 }
 
 impl PendingBatch {
@@ -669,7 +2060,12 @@ impl PendingBatch {
                 url: self.url,
                 flavor: self.flavor,
                 variant: self.variant,
+                root_turn: self.root_turn,
                 positions,
+                node_scale: self.node_scale,
+                san_roots: self.san_roots,
+                puzzle_candidates: self.puzzle_candidates,
+                acquired_at: self.acquired_at,
                 started_at: self.started_at,
                 completed_at: Instant::now(),
             }),
@@ -684,7 +2080,13 @@ impl PendingBatch {
             .map(|(i, p)| match p {
                 // Quirk: Lila distinguishes progress reports from complete
                 // analysis by looking at the first part.
-                Some(Skip::Present(pos)) if i > 0 => Some(pos.to_best()),
+                Some(Skip::Present(pos)) if i > 0 => {
+                    let mut part = pos.to_best();
+                    if let Some(Some(root)) = self.san_roots.get(i) {
+                        san::annotate(&mut part, root);
+                    }
+                    Some(part)
+                }
                 _ => None,
             })
             .collect()
@@ -693,34 +2095,165 @@ impl PendingBatch {
     fn pending(&self) -> usize {
         self.positions.iter().filter(|p| p.is_none()).count()
     }
+
+    /// Analysis with some results. Move requests and batches without any
+    /// results are left to be aborted.
+    fn is_worth_resuming(&self) -> bool {
+        self.acquired.is_some()
+            && self
+                .positions
+                .iter()
+                .any(|p| matches!(p, Some(Skip::Present(_))))
+    }
+
+    fn into_checkpoint(self, endpoint: &Endpoint) -> BatchCheckpoint {
+        BatchCheckpoint::new(
+            endpoint.url.to_string(),
+            self.acquired.expect("acquired analysis"),
+            self.node_scale,
+            self.positions
+                .into_iter()
+                .map(|p| match p {
+                    Some(Skip::Present(res)) => Some(WireResponse::from(res)),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CompletedBatch {
     work: Work,
     url: Option<Url>,
     flavor: EngineFlavor,
     variant: LichessVariant,
+    root_turn: Color,
     positions: Vec<Skip<PositionResponse>>,
+    node_scale: Option<f64>,
+    san_roots: Vec<Option<VariantPosition>>,
+    /// Whether to mark puzzle candidates in the analysis.
+    puzzle_candidates: bool,
+    acquired_at: Instant,
     started_at: Instant,
     completed_at: Instant,
 }
 
 impl CompletedBatch {
-    fn into_analysis(self) -> Vec<Option<AnalysisPart>> {
-        self.positions
+    pub fn work(&self) -> &Work {
+        &self.work
+    }
+
+    /// Factor applied to the node targets of the server, if any.
+    pub fn node_scale(&self) -> Option<f64> {
+        self.node_scale
+    }
+
+    pub fn flavor(&self) -> EngineFlavor {
+        self.flavor
+    }
+
+    pub fn url(&self) -> Option<&Url> {
+        self.url.as_ref()
+    }
+
+    pub fn variant(&self) -> LichessVariant {
+        self.variant
+    }
+
+    /// Centipawn loss, accuracy and judgements of each move, if the batch
+    /// is the analysis of a game.
+    pub fn metrics(&self) -> Option<GameMetrics> {
+        self.work.is_analysis().then(|| {
+            let evals: Vec<_> = self
+                .positions
+                .iter()
+                .map(|pos| match pos {
+                    Skip::Present(res) => res.scores.best().copied(),
+                    Skip::Skip => None,
+                })
+                .collect();
+            GameMetrics::compute(self.root_turn, &evals)
+        })
+    }
+
+    /// Positions after a blunder, where exactly one move wins, judged by
+    /// the best two lines of each position. Positions analysed with a
+    /// single line are never candidates.
+    fn puzzle_candidates(&self) -> Vec<bool> {
+        let second = NonZeroU8::new(2).expect("second line");
+        iter::once(false)
+            .chain(self.positions.windows(2).map(|pair| match pair {
+                [Skip::Present(before), Skip::Present(res)] => {
+                    match (
+                        before.scores.best(),
+                        res.scores.best(),
+                        res.scores.line(second),
+                    ) {
+                        (Some(&before), Some(&best), Some(&second)) => {
+                            is_puzzle_swing(before, best) && is_unique_win(best, Some(second))
+                        }
+                        _ => false,
+                    }
+                }
+                _ => false,
+            }))
+            .collect()
+    }
+
+    pub fn into_analysis(self) -> Vec<Option<AnalysisPart>> {
+        let metrics = if self.work.annotations_wanted() {
+            self.metrics()
+        } else {
+            None
+        };
+        let puzzle_candidates = if self.puzzle_candidates && self.work.matrix_wanted() {
+            self.puzzle_candidates()
+        } else {
+            Vec::new()
+        };
+        let san_roots = self.san_roots;
+        let mut analysis: Vec<_> = self
+            .positions
             .into_iter()
-            .map(|p| {
+            .enumerate()
+            .map(|(i, p)| {
                 Some(match p {
                     Skip::Skip => AnalysisPart::Skipped { skipped: true },
                     Skip::Present(pos) if pos.work.matrix_wanted() => pos.into_matrix(),
-                    Skip::Present(pos) => pos.to_best(),
+                    Skip::Present(pos) => {
+                        let mut part = pos.to_best();
+                        if let Some(Some(root)) = san_roots.get(i) {
+                            san::annotate(&mut part, root);
+                        }
+                        part
+                    }
                 })
             })
-            .collect()
+            .collect();
+        if let Some(ref metrics) = metrics {
+            accuracy::annotate(&mut analysis, metrics);
+        }
+        for (part, candidate) in analysis.iter_mut().zip(puzzle_candidates) {
+            if let Some(AnalysisPart::Matrix {
+                puzzle_candidate, ..
+            }) = part
+            {
+                *puzzle_candidate = candidate;
+            }
+        }
+        analysis
     }
 
-    fn into_best_move(self) -> Option<Uci> {
+    /// Moves and evaluations of a play-out.
+    pub fn into_playout(self) -> Vec<PlayoutPly> {
+        match self.positions.into_iter().next() {
+            Some(Skip::Present(pos)) => pos.playout,
+            Some(Skip::Skip) | None => Vec::new(),
+        }
+    }
+
+    pub fn into_best_move(self) -> Option<Uci> {
         self.positions.into_iter().next().and_then(|p| match p {
             Skip::Skip => None,
             Skip::Present(pos) => pos.best_move,
@@ -747,6 +2280,67 @@ impl CompletedBatch {
             .sum()
     }
 
+    /// Search time of the engines, summed over all positions.
+    fn total_engine_time(&self) -> Duration {
+        self.positions
+            .iter()
+            .map(|p| match p {
+                Skip::Skip => Duration::default(),
+                Skip::Present(pos) => pos.time,
+            })
+            .sum()
+    }
+
+    /// CPU time of the engine processes, summed over all positions, or
+    /// `None` if it could not be measured for any of them.
+    pub fn total_cpu_time(&self) -> Option<Duration> {
+        self.positions
+            .iter()
+            .filter_map(|p| match p {
+                Skip::Skip => None,
+                Skip::Present(pos) => pos.cpu_time,
+            })
+            .reduce(|a, b| a + b)
+    }
+
+    fn wall_time(&self) -> Duration {
+        self.completed_at.saturating_duration_since(self.started_at)
+    }
+
+    fn provenance(&self, engine_sha256: Option<String>) -> Provenance {
+        let node_target = match self.work {
+            Work::Analysis { nodes, .. } | Work::Playout { nodes, .. } => {
+                Some(nodes.get(self.flavor.eval_flavor()))
+            }
+            Work::Move { .. } => None,
+        };
+        Provenance {
+            batch: self.work.id().to_string(),
+            kind: self.work.kind(),
+            variant: self.variant.to_string(),
+            engine: self.flavor.name(),
+            engine_sha256,
+            node_target,
+            submitted_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            duration_ms: self.wall_time().as_millis() as u64,
+            engine_time_ms: self.total_engine_time().as_millis() as u64,
+            positions: self.total_positions(),
+            skipped: self.positions.iter().filter(|p| p.is_skipped()).count() as u64,
+            truncated: self
+                .positions
+                .iter()
+                .enumerate()
+                .filter_map(|(i, p)| match (p, node_target) {
+                    (Skip::Present(pos), Some(target)) if pos.nodes < target => Some(i),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
     fn nps(&self) -> Option<u32> {
         self.completed_at
             .checked_duration_since(self.started_at)
@@ -754,3 +2348,55 @@ impl CompletedBatch {
             .and_then(|nps| nps.try_into().ok())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{variant::Variant, CastlingMode};
+
+    use super::*;
+
+    fn position(fen: &str) -> VariantPosition {
+        let fen: Fen = fen.parse().expect("valid fen");
+        VariantPosition::from_setup(Variant::Chess, &fen, CastlingMode::Standard)
+            .expect("legal position")
+    }
+
+    #[test]
+    fn test_phase_weight() {
+        let start = VariantPosition::new(Variant::Chess);
+        assert_eq!(phase_weight(&start), OPENING_WEIGHT);
+        // Queens and a pair of rooks traded.
+        assert_eq!(
+            phase_weight(&position(
+                "r1b1k1n1/pppp1ppp/2n5/2b1p3/2B1P3/2N2N2/PPPP1PPP/R1B1K3 w Qq - 0 1"
+            )),
+            MIDDLEGAME_WEIGHT
+        );
+        assert_eq!(
+            phase_weight(&position("4k3/8/8/8/8/8/8/R3K3 w Q - 0 1")),
+            1.0
+        );
+        assert_eq!(
+            phase_weight(&position("4k3/8/8/8/8/8/8/4K3 w - - 0 1")),
+            FORCED_WEIGHT
+        );
+    }
+
+    #[test]
+    fn test_phase_weights_around_captures() {
+        let start = VariantPosition::new(Variant::Chess);
+        let moves: Vec<Uci> = ["e2e4", "d7d5", "e4d5"]
+            .iter()
+            .map(|uci| uci.parse().expect("valid uci"))
+            .collect();
+        assert_eq!(
+            phase_weights(&start, &moves),
+            [
+                OPENING_WEIGHT,
+                OPENING_WEIGHT,
+                OPENING_WEIGHT * VOLATILE_FACTOR,
+                OPENING_WEIGHT * VOLATILE_FACTOR
+            ]
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::Score;
+
+/// Conditions worth knowing about a position record.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Flag {
+    /// The position could not be parsed or is illegal.
+    Invalid,
+    /// The engine failed while analysing the position.
+    EngineError,
+    /// Answered from the opening book or cloud evaluations, without
+    /// searching.
+    Cached,
+    /// In the opening book, so skipped without searching.
+    Book,
+}
+
+/// One line of JSON lines output, shared by all offline subcommands, so
+/// that downstream tools need only one parser.
+///
+/// Moves use UCI notation with the castling notation of the variant.
+/// Evaluations are from the point of view of the side to move, like
+/// `{"cp": 25}` or `{"mate": -3}`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PositionRecord {
+    pub id: String,
+    pub fen: String,
+    #[serde(rename = "move")]
+    pub best_move: Option<String>,
+    pub eval: Option<Score>,
+    pub depth: Option<u8>,
+    pub nodes: Option<u64>,
+    #[serde(default)]
+    pub pv: Vec<String>,
+    #[serde(default)]
+    pub flags: Vec<Flag>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl PositionRecord {
+    /// Writes the record as a single line.
+    pub fn write_line(&self, out: &mut impl io::Write) -> io::Result<()> {
+        serde_json::to_writer(&mut *out, self)?;
+        out.write_all(b"\n")
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    fs::File,
+    io,
+    io::{BufWriter, Write as _},
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use shakmaty::uci::Uci;
+use tokio::sync::mpsc;
+
+use crate::{
+    api::{self, AcquireResponseBody, Acquired, AnalysisPart, ApiMessage, ApiStub, PlayoutPly},
+    assets::EvalFlavor,
+    configure::Endpoint,
+    logger::Logger,
+    session::ShutdownToken,
+    util::NevermindExt as _,
+};
+
+/// Reads recorded acquire responses, either as a JSON array or as one JSON
+/// object per line.
+pub fn load(path: &Path) -> io::Result<VecDeque<AcquireResponseBody>> {
+    let buf = fs::read_to_string(path)?;
+    let invalid = |err: serde_json::Error| io::Error::new(io::ErrorKind::InvalidData, err);
+    if buf.trim_start().starts_with('[') {
+        serde_json::from_str(&buf).map_err(invalid)
+    } else {
+        buf.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(invalid))
+            .collect()
+    }
+}
+
+/// Default output for the replay of `path`, next to it.
+pub fn default_out(path: &Path) -> PathBuf {
+    path.with_extension("submissions.jsonl")
+}
+
+/// Serves the recorded batches instead of a fishnet server, and writes
+/// everything that would have been submitted to `out`. Drains the session
+/// once all recorded batches are acquired.
+pub fn channel(
+    endpoint: Endpoint,
+    recorded: VecDeque<AcquireResponseBody>,
+    out: &Path,
+    shutdown: ShutdownToken,
+    logger: Logger,
+) -> io::Result<(ApiStub, ReplayActor)> {
+    let (stub, rx) = api::detached_channel(endpoint);
+    Ok((
+        stub,
+        ReplayActor {
+            rx,
+            recorded,
+            out: BufWriter::new(File::create(out)?),
+            shutdown,
+            logger,
+        },
+    ))
+}
+
+#[serde_as]
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Submission {
+    #[serde(rename_all = "camelCase")]
+    Analysis {
+        batch_id: String,
+        flavor: EvalFlavor,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        node_scale: Option<f64>,
+        analysis: Vec<Option<AnalysisPart>>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Move {
+        batch_id: String,
+        #[serde_as(as = "Option<DisplayFromStr>")]
+        best_move: Option<Uci>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Playout {
+        batch_id: String,
+        flavor: EvalFlavor,
+        playout: Vec<PlayoutPly>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Abort { batch_id: String },
+}
+
+pub struct ReplayActor {
+    rx: mpsc::UnboundedReceiver<ApiMessage>,
+    recorded: VecDeque<AcquireResponseBody>,
+    out: BufWriter<File>,
+    shutdown: ShutdownToken,
+    logger: Logger,
+}
+
+impl ReplayActor {
+    pub async fn run(mut self) {
+        self.logger.debug(&format!(
+            "Replay actor started ({} batches)",
+            self.recorded.len()
+        ));
+        while let Some(msg) = self.rx.recv().await {
+            self.handle_message(msg);
+        }
+        self.logger.debug("Replay actor exited");
+    }
+
+    fn handle_message(&mut self, msg: ApiMessage) {
+        match msg {
+            ApiMessage::CheckKey { callback } => {
+                callback.send(Ok(())).nevermind("callback dropped");
+            }
+            // Not available without a server. Dropping the callback answers
+            // with None.
+            ApiMessage::Status { .. } | ApiMessage::Probe { .. } | ApiMessage::Standing { .. } => {}
+            ApiMessage::Abort { batch_id } => {
+                self.logger
+                    .warn(&format!("Replayed batch {} aborted", batch_id));
+                self.write(&Submission::Abort {
+                    batch_id: batch_id.to_string(),
+                });
+            }
+            ApiMessage::Acquire { callback, .. } => {
+                callback.send(self.acquire()).nevermind("callback dropped");
+            }
+            ApiMessage::SubmitAnalysis {
+                batch_id,
+                flavor,
+                node_scale,
+                analysis,
+                ..
+            } => {
+                self.write(&Submission::Analysis {
+                    batch_id: batch_id.to_string(),
+                    flavor,
+                    node_scale,
+                    analysis,
+                });
+            }
+            ApiMessage::SubmitMove {
+                batch_id,
+                best_move,
+                callback,
+            } => {
+                self.write(&Submission::Move {
+                    batch_id: batch_id.to_string(),
+                    best_move,
+                });
+                callback.send(self.acquire()).nevermind("callback dropped");
+            }
+            ApiMessage::SubmitPlayout {
+                batch_id,
+                flavor,
+                playout,
+            } => {
+                self.write(&Submission::Playout {
+                    batch_id: batch_id.to_string(),
+                    flavor,
+                    playout,
+                });
+            }
+            // Only sent by an API actor to itself.
+            ApiMessage::RetryAnalysis { .. } => (),
+        }
+    }
+
+    fn acquire(&mut self) -> Acquired {
+        match self.recorded.pop_front() {
+            Some(body) => {
+                self.logger.debug(&format!(
+                    "Replaying batch {} ({} remaining)",
+                    body.work.id(),
+                    self.recorded.len()
+                ));
+                Acquired::Accepted(body)
+            }
+            None => {
+                self.shutdown.drain();
+                Acquired::NoContent
+            }
+        }
+    }
+
+    /// Writes and flushes each submission, so that the output is complete
+    /// up to the last batch even if the replay is aborted.
+    fn write(&mut self, submission: &Submission) {
+        let res = serde_json::to_writer(&mut self.out, submission)
+            .map_err(io::Error::from)
+            .and_then(|()| self.out.write_all(b"\n"))
+            .and_then(|()| self.out.flush());
+        if let Err(err) = res {
+            self.logger
+                .error(&format!("Failed to write replayed submission: {}", err));
+        }
+    }
+}
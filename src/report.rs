@@ -0,0 +1,131 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{
+    assets::Assets,
+    configure::Opt,
+    control::{self, ControlCommand},
+    describe::Description,
+    logger::Logger,
+    stats::{self, Stats},
+};
+
+/// Configuration keys with any of these words in their name are redacted.
+const SECRET_WORDS: [&str; 5] = ["key", "token", "secret", "password", "webhook"];
+
+#[derive(Serialize)]
+struct Report {
+    /// Unix timestamp.
+    created_at: u64,
+    /// Contents of the configuration file, with secrets redacted.
+    config: Option<String>,
+    description: Description,
+    stats: Option<Stats>,
+    /// Status, recent log lines and engine transcripts of the running
+    /// instance, if reachable via the control socket.
+    instance: Option<serde_json::Value>,
+}
+
+fn redact(config: &str) -> String {
+    config
+        .lines()
+        .map(
+            |line| match line.split_once('=').or_else(|| line.split_once(':')) {
+                Some((name, _))
+                    if SECRET_WORDS
+                        .iter()
+                        .any(|word| name.to_ascii_lowercase().contains(word)) =>
+                {
+                    format!("{}= <redacted>", name)
+                }
+                _ => line.to_owned(),
+            },
+        )
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+async fn instance(opt: &Opt, logger: &Logger) -> Option<serde_json::Value> {
+    let path = match opt.control_socket {
+        Some(ref path) => path,
+        None => {
+            logger.warn("No control socket configured. Report will not include recent logs and engine transcripts of a running instance.");
+            return None;
+        }
+    };
+    match control::socket::request(path, ControlCommand::Report).await {
+        Ok(response) => match serde_json::from_str(&response) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                logger.warn(&format!("Unexpected report from running instance: {}", err));
+                None
+            }
+        },
+        Err(err) => {
+            logger.warn(&format!(
+                "Could not reach running instance via {:?}: {}",
+                path, err
+            ));
+            None
+        }
+    }
+}
+
+/// Bundles the information needed to triage a problem into a single file,
+/// that can be attached to an issue.
+pub async fn report(opt: Opt, logger: &Logger) {
+    logger.headline("Creating report");
+
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let config = if opt.no_conf {
+        None
+    } else {
+        fs::read_to_string(&opt.conf)
+            .ok()
+            .map(|config| redact(&config))
+    };
+
+    let cpu = opt.cpu();
+    let assets = Assets::prepare(cpu, opt.enabled_engines()).expect("prepared bundled stockfish");
+    let description = Description::gather(&opt, cpu, &assets).await;
+
+    let stats = match stats::load() {
+        Ok(stats) => Some(stats),
+        Err(err) => {
+            logger.warn(&format!("Could not load stats: {}", err));
+            None
+        }
+    };
+
+    let report = Report {
+        created_at,
+        config,
+        description,
+        stats,
+        instance: instance(&opt, logger).await,
+    };
+
+    let path = PathBuf::from(format!("fishnet-report-{}.json", created_at));
+    match fs::write(
+        &path,
+        serde_json::to_string_pretty(&report).expect("serialize report"),
+    ) {
+        Ok(()) => logger.info(&format!(
+            "Wrote {:?}. Please review it, and attach it to your issue.",
+            path
+        )),
+        Err(err) => {
+            logger.error(&format!("Failed to write {:?}: {}", path, err));
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use shakmaty::{
+    fen::Fen, san::SanPlus, uci::Uci, variant::VariantPosition, zobrist::Zobrist, CastlingMode,
+    Position as _,
+};
+
+use crate::protocol::{AnalysisPart, LichessVariant};
+
+/// Plays the moves from the root position. The moves are expected to be
+/// validated already, but `None` is returned if any of them is illegal.
+pub fn position_after(root: &VariantPosition, moves: &[Uci]) -> Option<VariantPosition> {
+    let mut pos = root.clone();
+    for uci in moves {
+        let m = uci.to_move(&pos).ok()?;
+        pos.play_unchecked(&m);
+    }
+    Some(pos)
+}
+
+/// Stable 64-bit key of the position after the moves, for caching and
+/// deduplication. Unlike FEN strings, keys do not depend on move counters
+/// or on en passant squares without a legal capture. Positions of
+/// different variants may have the same key, so compare keys together with
+/// the variant.
+pub fn position_key(variant: LichessVariant, fen: &Fen, moves: &[Uci]) -> Option<u64> {
+    let root = VariantPosition::from_setup(variant.into(), fen, CastlingMode::Chess960).ok()?;
+    let pos = position_after(&root, moves)?;
+    Some(Zobrist::<VariantPosition, u64>::new(pos).zobrist_hash())
+}
+
+/// Converts a line in UCI notation to SAN. Stops at the first illegal move,
+/// so the result is a prefix of the line.
+pub fn line(pos: &VariantPosition, line: &[Uci]) -> Vec<SanPlus> {
+    let mut pos = pos.clone();
+    let mut sans = Vec::with_capacity(line.len());
+    for uci in line {
+        match uci.to_move(&pos) {
+            Ok(m) => sans.push(SanPlus::from_move_and_play_unchecked(&mut pos, &m)),
+            Err(_) => break,
+        }
+    }
+    sans
+}
+
+/// Adds the principal variation in SAN to an analysis part, given the
+/// analysed position. Multi-PV matrices are left as they are.
+pub fn annotate(part: &mut AnalysisPart, pos: &VariantPosition) {
+    if let AnalysisPart::Best { pv, san, .. } = part {
+        if !pv.is_empty() {
+            *san = Some(line(pos, pv));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{fen::Fen, variant::Variant, CastlingMode, Chess};
+
+    use super::*;
+    use crate::protocol::Score;
+
+    fn ucis(moves: &str) -> Vec<Uci> {
+        moves.split(' ').map(|uci| uci.parse().unwrap()).collect()
+    }
+
+    fn sans(sans: &[SanPlus]) -> String {
+        sans.iter()
+            .map(|san| san.to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn test_line() {
+        let pos = VariantPosition::Chess(Chess::default());
+        assert_eq!(
+            sans(&line(&pos, &ucis("f2f3 e7e5 g2g4 d8h4"))),
+            "f3 e5 g4 Qh4#"
+        );
+        // Stops at the first illegal move.
+        assert_eq!(sans(&line(&pos, &ucis("e2e4 e2e4 e7e5"))), "e4");
+    }
+
+    #[test]
+    fn test_line_castling() {
+        let fen: Fen = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1".parse().unwrap();
+        let pos =
+            VariantPosition::from_setup(Variant::Chess, &fen, CastlingMode::Chess960).unwrap();
+        assert_eq!(sans(&line(&pos, &ucis("e1h1 e8a8"))), "O-O O-O-O");
+    }
+
+    #[test]
+    fn test_position_after() {
+        let root = VariantPosition::Chess(Chess::default());
+        let pos = position_after(&root, &ucis("e2e4 e7e5")).unwrap();
+        assert_eq!(sans(&line(&pos, &ucis("g1f3"))), "Nf3");
+        assert!(position_after(&root, &ucis("e2e4 e4e5")).is_none());
+    }
+
+    #[test]
+    fn test_annotate() {
+        let pos = VariantPosition::Chess(Chess::default());
+        let mut part = AnalysisPart::Best {
+            pv: ucis("e2e4 e7e5"),
+            san: None,
+            score: Score::Cp(30),
+            depth: 20,
+            nodes: 1000,
+            time: 10,
+            nps: None,
+            wdl: None,
+            judgement: None,
+            cached: false,
+        };
+        annotate(&mut part, &pos);
+        match part {
+            AnalysisPart::Best { san: Some(san), .. } => assert_eq!(sans(&san), "e4 e5"),
+            _ => panic!("expected san"),
+        }
+
+        // Nothing to annotate without a principal variation.
+        let mut part = AnalysisPart::Best {
+            pv: Vec::new(),
+            san: None,
+            score: Score::Cp(30),
+            depth: 20,
+            nodes: 1000,
+            time: 10,
+            nps: None,
+            wdl: None,
+            judgement: None,
+            cached: false,
+        };
+        annotate(&mut part, &pos);
+        assert!(matches!(part, AnalysisPart::Best { san: None, .. }));
+    }
+}
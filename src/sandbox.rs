@@ -0,0 +1,267 @@
+use tokio::process::Command;
+
+use crate::configure::Sandbox;
+
+/// Whether engine processes can be sandboxed at the given level on this
+/// platform.
+pub fn supported(level: Sandbox) -> bool {
+    level == Sandbox::Off || imp::SUPPORTED
+}
+
+/// Restricts the process started by the command before it runs the engine.
+/// Engines are downloaded artifacts, and only need to read their networks
+/// and talk UCI over the pipes set up by fishnet.
+///
+/// - `basic`: No new privileges, no network sockets, and none of the system
+///   calls to debug other processes, mount file systems or load kernel
+///   modules.
+/// - `strict`: Additionally, the file system is read-only for the engine.
+pub fn apply(command: &mut Command, level: Sandbox) -> &mut Command {
+    if level == Sandbox::Off {
+        command
+    } else {
+        imp::apply(command, level)
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod imp {
+    use std::io;
+
+    use tokio::process::Command;
+
+    use crate::configure::Sandbox;
+
+    pub const SUPPORTED: bool = true;
+
+    // Instructions of classic BPF, as used by seccomp filters.
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JMP_JEQ_K: u16 = 0x15;
+    const BPF_JMP_JGE_K: u16 = 0x35;
+    const BPF_JMP_JSET_K: u16 = 0x45;
+    const BPF_RET_K: u16 = 0x06;
+
+    const SECCOMP_MODE_FILTER: libc::c_ulong = 2;
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// Not in all versions of libc. The same on all architectures.
+    const SYS_OPENAT2: libc::c_long = 437;
+
+    /// Offsets into struct seccomp_data.
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    fn arg(i: u32) -> u32 {
+        // Lower half of the argument, on little endian architectures.
+        16 + 8 * i
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    struct SockFilter {
+        code: u16,
+        jt: u8,
+        jf: u8,
+        k: u32,
+    }
+
+    #[repr(C)]
+    struct SockFprog {
+        len: libc::c_ushort,
+        filter: *const SockFilter,
+    }
+
+    /// Seccomp filter, built before forking, so that the child only has to
+    /// install it.
+    struct Filter {
+        code: Vec<SockFilter>,
+    }
+
+    impl Filter {
+        fn new() -> Filter {
+            let mut filter = Filter { code: Vec::new() };
+            filter.stmt(BPF_LD_W_ABS, ARCH);
+            filter.jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0);
+            filter.stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS);
+            filter.stmt(BPF_LD_W_ABS, NR);
+            // System calls of the x32 ABI share the architecture.
+            #[cfg(target_arch = "x86_64")]
+            {
+                filter.jump(BPF_JMP_JGE_K, 0x4000_0000, 0, 1);
+                filter.stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS);
+            }
+            filter
+        }
+
+        fn stmt(&mut self, code: u16, k: u32) {
+            self.code.push(SockFilter {
+                code,
+                jt: 0,
+                jf: 0,
+                k,
+            });
+        }
+
+        fn jump(&mut self, code: u16, k: u32, jt: u8, jf: u8) {
+            self.code.push(SockFilter { code, jt, jf, k });
+        }
+
+        fn deny(&mut self, nr: libc::c_long, errno: libc::c_int) {
+            self.jump(BPF_JMP_JEQ_K, nr as u32, 0, 1);
+            self.stmt(BPF_RET_K, SECCOMP_RET_ERRNO | errno as u32);
+        }
+
+        /// Denies the system call, unless the argument has the given value.
+        fn deny_unless_arg(&mut self, nr: libc::c_long, i: u32, value: u32, errno: libc::c_int) {
+            self.jump(BPF_JMP_JEQ_K, nr as u32, 0, 4);
+            self.stmt(BPF_LD_W_ABS, arg(i));
+            self.jump(BPF_JMP_JEQ_K, value, 1, 0);
+            self.stmt(BPF_RET_K, SECCOMP_RET_ERRNO | errno as u32);
+            self.stmt(BPF_LD_W_ABS, NR);
+        }
+
+        /// Denies the system call, if the argument has any of the given
+        /// bits set.
+        fn deny_if_arg_bits(&mut self, nr: libc::c_long, i: u32, bits: u32, errno: libc::c_int) {
+            self.jump(BPF_JMP_JEQ_K, nr as u32, 0, 4);
+            self.stmt(BPF_LD_W_ABS, arg(i));
+            self.jump(BPF_JMP_JSET_K, bits, 0, 1);
+            self.stmt(BPF_RET_K, SECCOMP_RET_ERRNO | errno as u32);
+            self.stmt(BPF_LD_W_ABS, NR);
+        }
+
+        fn allow_rest(mut self) -> Vec<SockFilter> {
+            self.stmt(BPF_RET_K, SECCOMP_RET_ALLOW);
+            self.code
+        }
+    }
+
+    fn filter(level: Sandbox) -> Vec<SockFilter> {
+        let mut filter = Filter::new();
+
+        // No network, but local pipes and socket pairs.
+        filter.deny_unless_arg(libc::SYS_socket, 0, libc::AF_UNIX as u32, libc::EACCES);
+
+        for nr in [
+            libc::SYS_ptrace,
+            libc::SYS_process_vm_readv,
+            libc::SYS_process_vm_writev,
+            libc::SYS_mount,
+            libc::SYS_umount2,
+            libc::SYS_pivot_root,
+            libc::SYS_chroot,
+            libc::SYS_unshare,
+            libc::SYS_setns,
+            libc::SYS_init_module,
+            libc::SYS_finit_module,
+            libc::SYS_delete_module,
+            libc::SYS_kexec_load,
+            libc::SYS_bpf,
+            libc::SYS_keyctl,
+            libc::SYS_add_key,
+            libc::SYS_request_key,
+            libc::SYS_swapon,
+            libc::SYS_swapoff,
+            libc::SYS_reboot,
+        ] {
+            filter.deny(nr, libc::EPERM);
+        }
+
+        if level == Sandbox::Strict {
+            // Read-only file system. Files can still be opened for reading,
+            // and the pipes to fishnet are already open.
+            let write = (libc::O_WRONLY | libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC) as u32;
+            filter.deny_if_arg_bits(libc::SYS_openat, 2, write, libc::EROFS);
+            #[cfg(target_arch = "x86_64")]
+            {
+                filter.deny_if_arg_bits(libc::SYS_open, 1, write, libc::EROFS);
+                for nr in [
+                    libc::SYS_creat,
+                    libc::SYS_unlink,
+                    libc::SYS_rename,
+                    libc::SYS_mkdir,
+                    libc::SYS_rmdir,
+                    libc::SYS_link,
+                    libc::SYS_symlink,
+                    libc::SYS_chmod,
+                    libc::SYS_chown,
+                    libc::SYS_lchown,
+                    libc::SYS_mknod,
+                ] {
+                    filter.deny(nr, libc::EROFS);
+                }
+            }
+            // The flags of openat2 are behind a pointer. C libraries fall
+            // back to openat.
+            filter.deny(SYS_OPENAT2, libc::ENOSYS);
+            for nr in [
+                libc::SYS_truncate,
+                libc::SYS_unlinkat,
+                libc::SYS_renameat,
+                libc::SYS_renameat2,
+                libc::SYS_mkdirat,
+                libc::SYS_linkat,
+                libc::SYS_symlinkat,
+                libc::SYS_fchmodat,
+                libc::SYS_fchownat,
+                libc::SYS_mknodat,
+                libc::SYS_utimensat,
+            ] {
+                filter.deny(nr, libc::EROFS);
+            }
+        }
+
+        filter.allow_rest()
+    }
+
+    pub fn apply(command: &mut Command, level: Sandbox) -> &mut Command {
+        let filter = filter(level);
+        unsafe {
+            // Safety: The closure is run in a fork, and only makes system
+            // calls with a filter that was prepared before.
+            command.pre_exec(move || {
+                let prog = SockFprog {
+                    len: filter.len() as libc::c_ushort,
+                    filter: filter.as_ptr(),
+                };
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::prctl(
+                    libc::PR_SET_SECCOMP,
+                    SECCOMP_MODE_FILTER,
+                    &prog as *const SockFprog,
+                ) == -1
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            })
+        }
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod imp {
+    use tokio::process::Command;
+
+    use crate::configure::Sandbox;
+
+    pub const SUPPORTED: bool = false;
+
+    pub fn apply(command: &mut Command, _level: Sandbox) -> &mut Command {
+        command
+    }
+}
@@ -0,0 +1,451 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufWriter, Write as _},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use rand::{seq::SliceRandom as _, Rng as _};
+use shakmaty::{
+    fen::Fen,
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    zobrist::Zobrist,
+    CastlingMode, Color, Outcome, Position as _, Setup as _,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    api::{Clock, LichessVariant, SkillLevel, Work},
+    assets::{Assets, EngineFlavor},
+    book::Book,
+    configure::{Cores, EnginePair, Opt, SelfplayOpt},
+    ipc::{Position, PositionId},
+    logger::Logger,
+    pgn::{after_best_move, AnnotatedGame, AnnotatedMove},
+    pool::EnginePool,
+};
+
+/// Games that last longer are stopped without result.
+const MAX_PLIES: usize = 600;
+
+/// Book moves are played for at most this many plies.
+const MAX_BOOK_PLIES: usize = 16;
+
+struct Opening {
+    root: VariantPosition,
+    moves: Vec<Uci>,
+}
+
+/// A finished game, numbered from 1.
+struct Played {
+    round: u32,
+    game: AnnotatedGame,
+}
+
+fn engine_name(assets: &Assets, flavor: EngineFlavor) -> String {
+    match flavor {
+        EngineFlavor::Official => assets.sf_name.to_owned(),
+        EngineFlavor::MultiVariant => "Fairy-Stockfish".to_owned(),
+    }
+}
+
+/// Starting position number `n` (from 0 to 959) of Chess960, as numbered
+/// by Scharnagl.
+fn chess960_root(mut n: usize) -> VariantPosition {
+    let mut rank = [None; 8];
+    rank[(n % 4) * 2 + 1] = Some('b');
+    n /= 4;
+    rank[(n % 4) * 2] = Some('b');
+    n /= 4;
+    let mut place = |piece: char, nth: usize| {
+        let file = (0..8)
+            .filter(|&file| rank[file].is_none())
+            .nth(nth)
+            .expect("free square");
+        rank[file] = Some(piece);
+    };
+    place('q', n % 6);
+    n /= 6;
+    let (first, second) = [
+        (0, 0),
+        (0, 1),
+        (0, 2),
+        (0, 3),
+        (1, 1),
+        (1, 2),
+        (1, 3),
+        (2, 2),
+        (2, 3),
+        (3, 3),
+    ][n];
+    // The second knight is placed after the first one took a square.
+    place('n', first);
+    place('n', second);
+    place('r', 0);
+    place('k', 0);
+    place('r', 0);
+    let black: String = rank.iter().map(|piece| piece.expect("full rank")).collect();
+    let fen: Fen = format!(
+        "{}/pppppppp/8/8/8/8/PPPPPPPP/{} w KQkq - 0 1",
+        black,
+        black.to_ascii_uppercase()
+    )
+    .parse()
+    .expect("valid chess960 fen");
+    VariantPosition::from_setup(Variant::Chess, &fen, CastlingMode::Chess960)
+        .expect("legal chess960 position")
+}
+
+/// Picks the starting position and the first moves, shared by both games
+/// of a pair.
+fn pick_opening(variant: LichessVariant, book: Option<&Book>, random_plies: u32) -> Opening {
+    let root = match variant {
+        LichessVariant::Chess960 => chess960_root(rand::thread_rng().gen_range(0..960)),
+        variant => VariantPosition::new(variant.into()),
+    };
+
+    let mut pos = root.clone();
+    let mut moves = Vec::new();
+    if let Some(book) = book {
+        while moves.len() < MAX_BOOK_PLIES {
+            let uci = match book.pick(&pos) {
+                Some(uci) => uci,
+                None => break,
+            };
+            let m = uci.to_move(&pos).expect("legal book move");
+            pos.play_unchecked(&m);
+            moves.push(uci);
+        }
+    }
+    if moves.is_empty() {
+        for _ in 0..random_plies {
+            let legals = pos.legal_moves();
+            let m = match legals.choose(&mut rand::thread_rng()) {
+                Some(m) if pos.outcome().is_none() => m.clone(),
+                _ => break,
+            };
+            moves.push(m.to_uci(CastlingMode::Chess960));
+            pos.play_unchecked(&m);
+        }
+    }
+
+    Opening { root, moves }
+}
+
+/// Plays a game, with the clock of each side running while its engine is
+/// searching.
+async fn play(
+    pool: &EnginePool,
+    selfplay_opt: &SelfplayOpt,
+    opening: &Opening,
+    engines: [EngineFlavor; 2],
+    logger: &Logger,
+) -> (Vec<AnnotatedMove>, Option<Outcome>, &'static str) {
+    let variant = selfplay_opt.variant;
+    let root_fen = Fen::from_setup(&opening.root);
+    let mut pos = opening.root.clone();
+    let mut line = Vec::new();
+    let mut moves = Vec::new();
+    let mut repetitions = HashMap::new();
+    let mut clock = [selfplay_opt.tc.base; 2];
+
+    for uci in &opening.moves {
+        let m = uci.to_move(&pos).expect("legal opening move");
+        pos.play_unchecked(&m);
+        line.push(uci.clone());
+        moves.push(AnnotatedMove {
+            uci: uci.clone(),
+            eval: None,
+            variation: Vec::new(),
+        });
+    }
+
+    loop {
+        if let Some(outcome) = pos.outcome() {
+            return (moves, Some(outcome), "normal");
+        }
+        let key: u64 = Zobrist::<VariantPosition, u64>::new(pos.clone()).zobrist_hash();
+        let seen = repetitions.entry(key).or_insert(0);
+        *seen += 1;
+        if *seen >= 3 || pos.halfmoves() >= 100 {
+            return (moves, Some(Outcome::Draw), "normal");
+        }
+        if line.len() >= MAX_PLIES {
+            return (moves, None, "unterminated");
+        }
+
+        let side = match pos.turn() {
+            Color::White => 0,
+            Color::Black => 1,
+        };
+        let position = Position {
+            work: Work::Move {
+                id: "selfplay".parse().expect("batch id"),
+                level: SkillLevel::Eight,
+                clock: Some(Clock {
+                    wtime: clock[0].into(),
+                    btime: clock[1].into(),
+                    inc: selfplay_opt.tc.increment,
+                }),
+                options: BTreeMap::new(),
+            },
+            position_id: PositionId(line.len()),
+            flavor: engines[side],
+            url: None,
+            variant,
+            root_fen: root_fen.clone(),
+            moves: line.clone(),
+        };
+
+        let started_at = Instant::now();
+        let res = pool.go(position).await;
+        clock[side] = match clock[side].checked_sub(started_at.elapsed()) {
+            Some(remaining) => remaining + selfplay_opt.tc.increment,
+            None => {
+                return (
+                    moves,
+                    Some(Outcome::Decisive {
+                        winner: !pos.turn(),
+                    }),
+                    "time forfeit",
+                )
+            }
+        };
+
+        let (uci, score) = match res {
+            Ok(res) => match res.best_move {
+                Some(uci) => (uci, res.scores.best().copied()),
+                None => return (moves, None, "abandoned"),
+            },
+            Err(_) => {
+                logger.warn(&format!(
+                    "Engine failed after {} plies, abandoning game",
+                    line.len()
+                ));
+                return (moves, None, "abandoned");
+            }
+        };
+        let m = match uci.to_move(&pos) {
+            Ok(m) => m,
+            Err(_) => {
+                logger.warn(&format!("Engine played illegal move {}", uci));
+                return (moves, None, "abandoned");
+            }
+        };
+        pos.play_unchecked(&m);
+        line.push(uci.clone());
+        moves.push(AnnotatedMove {
+            uci,
+            eval: score.map(after_best_move),
+            variation: Vec::new(),
+        });
+    }
+}
+
+/// Plays the engines against each other, and writes the games as PGN as
+/// soon as they are finished.
+pub async fn selfplay(opt: &Opt, selfplay_opt: &SelfplayOpt, logger: &Logger) {
+    let concurrency = selfplay_opt.concurrency.map_or_else(
+        || usize::from(opt.cores.unwrap_or(Cores::Auto)),
+        usize::from,
+    );
+
+    if selfplay_opt.variant == LichessVariant::FromPosition {
+        logger.error("Self-play needs a variant with a starting position.");
+        std::process::exit(1);
+    }
+
+    let book = match opt.lookup.book.as_deref().map(Book::open).transpose() {
+        Ok(book) => book.map(Arc::new),
+        Err(err) => {
+            logger.error(&format!(
+                "Failed to open book {:?}: {}",
+                opt.lookup.book, err
+            ));
+            std::process::exit(1);
+        }
+    };
+
+    let mut out = match File::create(&selfplay_opt.out) {
+        Ok(file) => BufWriter::new(file),
+        Err(err) => {
+            logger.error(&format!("Failed to create {:?}: {}", selfplay_opt.out, err));
+            std::process::exit(1);
+        }
+    };
+
+    let cpu = opt.cpu();
+    let assets =
+        Arc::new(Assets::prepare(cpu, opt.enabled_engines()).expect("prepared bundled stockfish"));
+
+    let standard_like = matches!(
+        selfplay_opt.variant,
+        LichessVariant::Standard | LichessVariant::Chess960
+    );
+    let engines = selfplay_opt.engines.unwrap_or_else(|| {
+        let flavor = if standard_like && assets.stockfish.official.is_some() {
+            EngineFlavor::Official
+        } else {
+            EngineFlavor::MultiVariant
+        };
+        EnginePair {
+            first: flavor,
+            second: flavor,
+        }
+    });
+    for flavor in [engines.first, engines.second] {
+        if assets.stockfish.get(flavor).is_none() {
+            logger.error(&format!("Engine {} is not enabled.", flavor.name()));
+            std::process::exit(1);
+        }
+        if flavor == EngineFlavor::Official && !standard_like {
+            logger.error(&format!(
+                "Official Stockfish cannot play {:?}.",
+                selfplay_opt.variant
+            ));
+            std::process::exit(1);
+        }
+    }
+
+    logger.headline(&format!(
+        "Playing {} games of {:?} at {} with {} engine processes",
+        selfplay_opt.games, selfplay_opt.variant, selfplay_opt.tc, concurrency
+    ));
+    let started_at = Instant::now();
+    let (pool, pool_join_handle) = EnginePool::spawn(assets.clone(), concurrency, logger.clone());
+
+    // Each task plays a pair of games with the same opening and swapped
+    // colors.
+    let pairs = (selfplay_opt.games + 1) / 2;
+    let (played_tx, mut played_rx) = mpsc::channel(concurrency);
+    let next_pair = Arc::new(AtomicU32::new(0));
+    for _ in 0..concurrency {
+        let assets = assets.clone();
+        let pool = pool.clone();
+        let book = book.clone();
+        let next_pair = next_pair.clone();
+        let played_tx = played_tx.clone();
+        let selfplay_opt = selfplay_opt.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            loop {
+                let pair = next_pair.fetch_add(1, Ordering::Relaxed);
+                if pair >= pairs {
+                    break;
+                }
+                let opening = pick_opening(
+                    selfplay_opt.variant,
+                    book.as_deref(),
+                    selfplay_opt.random_plies,
+                );
+                for round in [pair * 2, pair * 2 + 1] {
+                    if round >= selfplay_opt.games {
+                        break;
+                    }
+                    let white = if round % 2 == 0 {
+                        engines.first
+                    } else {
+                        engines.second
+                    };
+                    let black = if round % 2 == 0 {
+                        engines.second
+                    } else {
+                        engines.first
+                    };
+                    let (moves, outcome, termination) =
+                        play(&pool, &selfplay_opt, &opening, [white, black], &logger).await;
+                    let game = AnnotatedGame {
+                        headers: vec![
+                            ("Event".to_owned(), "fishnet selfplay".to_owned()),
+                            ("Round".to_owned(), (round + 1).to_string()),
+                            ("White".to_owned(), engine_name(&assets, white)),
+                            ("Black".to_owned(), engine_name(&assets, black)),
+                            ("TimeControl".to_owned(), selfplay_opt.tc.to_string()),
+                            ("Termination".to_owned(), termination.to_owned()),
+                        ],
+                        variant: selfplay_opt.variant,
+                        root: opening.root.clone(),
+                        moves,
+                        outcome,
+                    };
+                    let played = Played {
+                        round: round + 1,
+                        game,
+                    };
+                    if played_tx.send(played).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+    drop(played_tx);
+    drop(pool);
+
+    // Scores of the first engine, and of white, in half points.
+    let mut first_points = 0;
+    let mut white_points = 0;
+    let mut decided = 0;
+    let mut finished = 0;
+    while let Some(played) = played_rx.recv().await {
+        writeln!(out, "{}", played.game).expect("write pgn game");
+        out.flush().expect("flush pgn");
+
+        let white_half_points = match played.game.outcome {
+            Some(Outcome::Decisive {
+                winner: Color::White,
+            }) => 2,
+            Some(Outcome::Decisive {
+                winner: Color::Black,
+            }) => 0,
+            Some(Outcome::Draw) => 1,
+            None => {
+                logger.info(&format!("Game {}: no result", played.round));
+                continue;
+            }
+        };
+        finished += 1;
+        if white_half_points != 1 {
+            decided += 1;
+        }
+        white_points += white_half_points;
+        // The first engine plays white in odd rounds.
+        first_points += if played.round % 2 == 1 {
+            white_half_points
+        } else {
+            2 - white_half_points
+        };
+        logger.info(&format!(
+            "Game {}: {} ({} plies)",
+            played.round,
+            played.game.outcome.expect("finished"),
+            played.game.moves.len()
+        ));
+    }
+
+    pool_join_handle.await.expect("join");
+
+    if finished > 0 {
+        logger.fishnet_info(&format!(
+            "Finished {} games ({} decisive) in {:.1?}. White scored {:.1}%.",
+            finished,
+            decided,
+            started_at.elapsed(),
+            f64::from(white_points) * 50.0 / f64::from(finished)
+        ));
+        if engines.first != engines.second {
+            logger.fishnet_info(&format!(
+                "{} scored {:.1}% against {}.",
+                engines.first.name(),
+                f64::from(first_points) * 50.0 / f64::from(finished),
+                engines.second.name()
+            ));
+        }
+    }
+    logger.fishnet_info(&format!("Games written to {:?}.", selfplay_opt.out));
+}
@@ -0,0 +1,152 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use serde::Serialize;
+use shakmaty::{
+    fen::Fen,
+    variant::{Variant, VariantPosition},
+};
+use tokio::time;
+
+use crate::{
+    api::{LichessVariant, NodeLimit, Work},
+    assets::{Assets, EngineFlavor},
+    ipc::{Position, PositionId},
+    logger::Logger,
+    pool::EnginePool,
+};
+
+/// Give up on an engine that does not answer a one-node search in this time,
+/// including starting the process.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Variants to check for an engine flavor. Games from a position are
+/// searched like standard chess.
+fn variants(flavor: EngineFlavor, full: bool) -> Vec<LichessVariant> {
+    let variants = LichessVariant::ALL
+        .iter()
+        .copied()
+        .filter(|&variant| variant != LichessVariant::FromPosition)
+        .filter(|&variant| match flavor {
+            EngineFlavor::Official => Variant::from(variant) == Variant::Chess,
+            EngineFlavor::MultiVariant => true,
+        });
+    if full {
+        variants.collect()
+    } else {
+        // Abbreviated: Only the first, which is enough to catch engines that
+        // do not start at all.
+        variants.take(1).collect()
+    }
+}
+
+/// Outcome of a one-node search from the starting position of a variant.
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestResult {
+    pub flavor: EngineFlavor,
+    pub variant: LichessVariant,
+    pub pass: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_move: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SelfTestResult {
+    pub fn engine_name(&self) -> &'static str {
+        match self.flavor {
+            EngineFlavor::Official => "Official Stockfish",
+            EngineFlavor::MultiVariant => "Fairy-Stockfish",
+        }
+    }
+}
+
+/// Pass/fail matrix of engine flavors and variants.
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestReport {
+    pub pass: bool,
+    pub results: Vec<SelfTestResult>,
+}
+
+impl SelfTestReport {
+    pub fn failures(&self) -> impl Iterator<Item = &SelfTestResult> {
+        self.results.iter().filter(|result| !result.pass)
+    }
+}
+
+/// Boots each available engine flavor, and checks that it answers a
+/// one-node search from the starting position of every variant it supports
+/// with a legal best move. With `full` unset, only the first variant of each
+/// flavor is checked, as done on startup.
+pub async fn selftest(assets: Arc<Assets>, full: bool, logger: &Logger) -> SelfTestReport {
+    let (pool, pool_join_handle) = EnginePool::spawn(assets.clone(), 1, logger.clone());
+    let mut results = Vec::new();
+    for flavor in [EngineFlavor::Official, EngineFlavor::MultiVariant] {
+        if assets.stockfish.get(flavor).is_none() {
+            continue;
+        }
+        for (i, variant) in variants(flavor, full).into_iter().enumerate() {
+            results.push(check(&pool, flavor, variant, PositionId(i)).await);
+        }
+    }
+    drop(pool);
+    pool_join_handle.await.expect("join");
+    SelfTestReport {
+        pass: results.iter().all(|result| result.pass),
+        results,
+    }
+}
+
+async fn check(
+    pool: &EnginePool,
+    flavor: EngineFlavor,
+    variant: LichessVariant,
+    position_id: PositionId,
+) -> SelfTestResult {
+    let pos = VariantPosition::new(Variant::from(variant));
+    let mut result = SelfTestResult {
+        flavor,
+        variant,
+        pass: false,
+        best_move: None,
+        error: None,
+    };
+    let response = time::timeout(
+        TIMEOUT,
+        pool.go(Position {
+            work: Work::Analysis {
+                id: "selftest".parse().expect("batch id"),
+                nodes: NodeLimit::fixed(1),
+                depth: None,
+                multipv: None,
+                timeout: TIMEOUT,
+                movetime: None,
+                wdl: false,
+                annotations: false,
+                tier: None,
+                options: BTreeMap::new(),
+            },
+            position_id,
+            flavor,
+            url: None,
+            variant,
+            root_fen: Fen::from_setup(&pos),
+            moves: Vec::new(),
+        }),
+    )
+    .await;
+    match response {
+        Err(_) => result.error = Some(format!("no answer within {:?}", TIMEOUT)),
+        Ok(Err(failed)) => result.error = Some(failed.reason.to_string()),
+        Ok(Ok(res)) => match res.best_move {
+            None => result.error = Some("no best move".to_owned()),
+            Some(uci) => {
+                match uci.to_move(&pos) {
+                    Ok(_) => result.pass = true,
+                    Err(_) => result.error = Some(format!("illegal best move {}", uci)),
+                }
+                result.best_move = Some(uci.to_string());
+            }
+        },
+    }
+    result
+}
@@ -0,0 +1,1901 @@
+use std::{
+    cmp::{max, min},
+    collections::HashMap,
+    env,
+    error::Error,
+    fmt, fs, future, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::Parser as _;
+use shakmaty::variant::Variant;
+use thousands::Separable as _;
+use tokio::{
+    sync::{broadcast, broadcast::error::RecvError, mpsc, oneshot, watch, Notify},
+    task::JoinHandle,
+    time,
+};
+
+use crate::{
+    anomaly::{self, AnomalyDetector},
+    api::{self, BatchId, LichessVariant, VariantCapability},
+    assets::{Assets, ByEngineFlavor, Cpu, EngineFlavor, EngineUpdate},
+    audit, calibration, cluster,
+    configure::{
+        self, BacklogOpt, Cores, Endpoint, Key, Opt, ParsedDuration, Sandbox, VariantFilter,
+        VariantList, Verbose,
+    },
+    control::{self, ControlCommand, Setting},
+    cores::CoreArbiter,
+    describe::Description,
+    disk,
+    dump::InvalidBatchDump,
+    events::{Event, EventStream},
+    failover,
+    handler::{WorkHandler, WorkHandlers},
+    ipc::{FailureReason, Position, PositionFailed, PositionResponse, Pull, WorkLimits},
+    journal,
+    latency::{LatencySummary, StageSummary},
+    limits::EngineLimits,
+    logger::{Logger, ProgressAt},
+    lookup::Lookup,
+    memory,
+    metrics::METRICS,
+    notify, orphans,
+    queue::{self, QueueStub},
+    replay, sandbox, selftest, sink,
+    stats::{PerformanceSummary, Throughput},
+    status::{Activity, StatusServer, WorkerBoard},
+    stockfish::{self, EngineTrace, StockfishInit, StockfishStub},
+    trace,
+    tui::Tui,
+    util::{NevermindExt as _, RandomizedBackoff},
+    verify::Verifier,
+};
+
+/// Configuration of a session, like the command line options and
+/// configuration file of the binary.
+pub struct Config {
+    opt: Opt,
+    cores: usize,
+    handlers: WorkHandlers,
+    logger: Logger,
+    stop_hint: Option<String>,
+    summary_trigger: Option<Arc<Notify>>,
+    reload_trigger: Option<watch::Receiver<()>>,
+    keys: bool,
+    engine_limits: EngineLimits,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+impl Config {
+    /// Defaults as if running `fishnet --no-conf` without further options.
+    pub fn new() -> Config {
+        Config::from_opt(Opt::parse_from(["fishnet", "--no-conf"]))
+    }
+
+    /// Uses options as parsed and configured by
+    /// [`configure::parse_and_configure()`].
+    pub fn from_opt(opt: Opt) -> Config {
+        // Engine processes with several threads each share the cores.
+        let threads = opt.engine.threads_per_job();
+        Config {
+            cores: max(1, usize::from(opt.cores.unwrap_or(Cores::Auto)) / threads),
+            logger: Logger::new(opt.verbose, opt.format, false),
+            handlers: WorkHandlers::default(),
+            stop_hint: None,
+            summary_trigger: None,
+            reload_trigger: None,
+            keys: false,
+            engine_limits: EngineLimits::default(),
+            opt,
+        }
+    }
+
+    /// Sets the fishnet key. Replaces endpoints from the configuration file.
+    pub fn key(mut self, key: Key) -> Config {
+        self.opt.key = Some(key);
+        self.opt.endpoint_confs.clear();
+        self
+    }
+
+    /// Sets the lichess HTTP endpoint. Replaces endpoints from the
+    /// configuration file.
+    pub fn endpoint(mut self, endpoint: Endpoint) -> Config {
+        self.opt.endpoint = Some(endpoint);
+        self.opt.endpoint_confs.clear();
+        self
+    }
+
+    /// Sets the backlog strategy. Replaces endpoints from the configuration
+    /// file.
+    pub fn backlog(mut self, backlog: BacklogOpt) -> Config {
+        self.opt.backlog = backlog;
+        self.opt.endpoint_confs.clear();
+        self
+    }
+
+    /// Number of engine processes to run in parallel.
+    pub fn cores(mut self, cores: usize) -> Config {
+        self.cores = max(1, cores);
+        self
+    }
+
+    /// Memory and CPU limits for the engine processes, as returned by
+    /// [`crate::limits::apply()`].
+    pub fn engine_limits(mut self, engine_limits: EngineLimits) -> Config {
+        self.engine_limits = engine_limits;
+        self
+    }
+
+    /// Selects the engine flavors. At least one must be enabled.
+    pub fn engines(mut self, engines: ByEngineFlavor<bool>) -> Config {
+        self.opt.no_official_stockfish = !engines.official;
+        self.opt.no_multivariant = !engines.multi_variant;
+        self.opt.force_multivariant = false;
+        self
+    }
+
+    /// Maximum backoff time when repeatedly receiving no job.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Config {
+        self.opt.max_backoff = max_backoff.into();
+        self
+    }
+
+    /// Serves a status page and JSON status on this address.
+    pub fn status_bind(mut self, status_bind: SocketAddr) -> Config {
+        self.opt.status_bind = Some(status_bind);
+        self
+    }
+
+    /// Adds or replaces the handler for a type of work.
+    pub fn handler(mut self, kind: &'static str, handler: Box<dyn WorkHandler>) -> Config {
+        self.handlers.register(kind, handler);
+        self
+    }
+
+    pub fn logger(mut self, logger: Logger) -> Config {
+        self.logger = logger;
+        self
+    }
+
+    /// Tells the user how to stop the session (for example CTRL-C).
+    pub fn stop_hint(mut self, stop_hint: impl Into<String>) -> Config {
+        self.stop_hint = Some(stop_hint.into());
+        self
+    }
+
+    /// Logs a cumulative summary of the session whenever notified, for
+    /// example on SIGUSR1.
+    pub fn summary_trigger(mut self, summary_trigger: Arc<Notify>) -> Config {
+        self.summary_trigger = Some(summary_trigger);
+        self
+    }
+
+    /// Reloads the configuration file and the TLS client certificate
+    /// whenever notified, for example on SIGHUP.
+    pub fn reload_trigger(mut self, reload_trigger: watch::Receiver<()>) -> Config {
+        self.reload_trigger = Some(reload_trigger);
+        self
+    }
+
+    /// Scales the number of active cores with `+` and `-` typed into the
+    /// terminal.
+    pub fn keys(mut self, keys: bool) -> Config {
+        self.keys = keys;
+        self
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Options include the key.
+        f.debug_struct("Config")
+            .field("cores", &self.cores)
+            .field("handlers", &self.handlers)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Requested state of a session.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Shutdown {
+    Running,
+    /// Finish pending batches, but do not acquire new ones.
+    Soon,
+    /// Finish positions in flight, and save unfinished analysis to resume
+    /// on the next start.
+    Checkpoint,
+    /// Abort pending batches.
+    Now,
+}
+
+/// Stops a session started with [`run()`]. All clones refer to the same
+/// session.
+#[derive(Debug, Clone)]
+pub struct ShutdownToken {
+    tx: Arc<watch::Sender<Shutdown>>,
+    rx: watch::Receiver<Shutdown>,
+    restart: Arc<AtomicBool>,
+}
+
+impl Default for ShutdownToken {
+    fn default() -> ShutdownToken {
+        ShutdownToken::new()
+    }
+}
+
+impl ShutdownToken {
+    pub fn new() -> ShutdownToken {
+        let (tx, rx) = watch::channel(Shutdown::Running);
+        ShutdownToken {
+            tx: Arc::new(tx),
+            rx,
+            restart: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn state(&self) -> Shutdown {
+        *self.rx.borrow()
+    }
+
+    /// Stops once pending batches are complete.
+    pub fn drain(&self) {
+        self.request(Shutdown::Soon);
+    }
+
+    /// Like [`ShutdownToken::drain()`], but the process is going to restart
+    /// itself afterwards, for example after an update. Systemd is told that
+    /// the service is reloading rather than stopping.
+    pub fn drain_for_restart(&self) {
+        self.restart.store(true, Ordering::Relaxed);
+        self.drain();
+    }
+
+    /// Stops once positions in flight are complete, saving unfinished
+    /// analysis for the next session.
+    pub fn checkpoint(&self) {
+        self.request(Shutdown::Checkpoint);
+    }
+
+    /// Stops as soon as possible, abandoning pending batches.
+    pub fn abort(&self) {
+        self.request(Shutdown::Now);
+    }
+
+    fn request(&self, shutdown: Shutdown) {
+        if self.state() < shutdown {
+            // Cannot fail while this token holds a receiver.
+            self.tx
+                .send(shutdown)
+                .nevermind("shutdown receiver dropped");
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Book(PathBuf, io::Error),
+    Syzygy(PathBuf, io::Error),
+    ObjectStore(String),
+    Cluster(SocketAddr, io::Error),
+    Replay(PathBuf, io::Error),
+    Sandbox(Sandbox),
+    SelfTest(usize),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Book(path, err) => write!(f, "failed to open book {:?}: {}", path, err),
+            SessionError::Syzygy(path, err) => {
+                write!(f, "invalid syzygy path {:?}: {}", path, err)
+            }
+            SessionError::ObjectStore(err) => write!(f, "invalid S3 sink: {}", err),
+            SessionError::Cluster(bind, err) => {
+                write!(f, "failed to accept followers on {}: {}", bind, err)
+            }
+            SessionError::Replay(path, err) => write!(f, "failed to replay {:?}: {}", path, err),
+            SessionError::Sandbox(sandbox) => {
+                write!(f, "--sandbox {} is not supported on this platform", sandbox)
+            }
+            SessionError::SelfTest(failures) => write!(
+                f,
+                "engine self-test failed for {} variants (run: fishnet selftest)",
+                failures
+            ),
+        }
+    }
+}
+
+impl Error for SessionError {}
+
+/// Contribution and performance of a session.
+#[derive(Debug)]
+pub struct SessionSummary {
+    pub batches: u64,
+    pub positions: u64,
+    pub nodes: u64,
+    pub uptime: Duration,
+    pub performance: Vec<PerformanceSummary>,
+    pub latencies: Vec<LatencySummary>,
+    pub stages: Vec<StageSummary>,
+    pub throughput: Throughput,
+}
+
+/// Runs a fishnet client in this process, until stopped with the shutdown
+/// token or the control socket. Signals are left to the caller.
+pub async fn run(config: Config, shutdown: ShutdownToken) -> Result<SessionSummary, SessionError> {
+    let Config {
+        mut opt,
+        cores,
+        mut handlers,
+        logger,
+        stop_hint,
+        summary_trigger,
+        mut reload_trigger,
+        keys,
+        engine_limits,
+    } = config;
+    let logger = &logger;
+    let started_at = Instant::now();
+
+    logger.headline("Checking configuration ...");
+
+    if opt.endpoint_confs.is_empty() {
+        let conf = opt.default_endpoint_conf();
+        opt.endpoint_confs.push(conf);
+    }
+    let conf = opt
+        .endpoint_confs
+        .first()
+        .cloned()
+        .expect("endpoint configured");
+    if opt.endpoint_confs.len() > 1 {
+        logger.info(&format!(
+            "Endpoints: {} (falling back in this order, select one with --only-endpoint)",
+            opt.endpoint_confs
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    let endpoint = conf.endpoint.clone();
+    if let Some(ref worker_name) = opt.worker_name {
+        logger.info(&format!(
+            "Worker name: {}{}",
+            worker_name,
+            opt.labels
+                .as_ref()
+                .map_or(String::new(), |labels| format!(" ({})", labels))
+        ));
+    }
+    let client_cert = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => {
+            logger.info(&format!(
+                "TLS client certificate: {:?} (reload with SIGHUP)",
+                cert
+            ));
+            Some(api::ClientCert {
+                cert: cert.clone(),
+                key: key.clone(),
+            })
+        }
+        _ => None,
+    };
+
+    logger.info(&format!(
+        "Backlog: Join queue if user backlog >= {:?} or system backlog >= {:?}",
+        Duration::from(conf.backlog.user.unwrap_or_default()),
+        Duration::from(conf.backlog.system.unwrap_or_default())
+    ));
+
+    let cpu = opt.cpu();
+    match opt.force_cpu_level {
+        Some(level) => {
+            logger.info(&format!(
+                "CPU features: {:?} (forced with --force-cpu-level {})",
+                cpu, level
+            ));
+            let detected = Cpu::detect();
+            if !detected.contains(cpu) {
+                logger.warn(&format!(
+                    "Detected CPU features {:?} do not include the forced level. Engines may crash.",
+                    detected
+                ));
+            }
+        }
+        None => logger.info(&format!("CPU features: {:?}", cpu)),
+    }
+
+    let reaped = orphans::reap();
+    if reaped > 0 {
+        logger.warn(&format!(
+            "Killed {} engine processes left behind by a previous run",
+            reaped
+        ));
+    }
+
+    let engines = opt.enabled_engines();
+    let mut assets = Assets::prepare(cpu, engines).expect("prepared bundled stockfish");
+    assets.engine_limits = engine_limits;
+    logger.info(&format!(
+        "Engine: {} (for GPLv3, run: {} license)",
+        assets.sf_name,
+        env::args().next().unwrap_or_else(|| "./fishnet".to_owned())
+    ));
+    if let Some(name) = assets.multi_variant_name() {
+        logger.info(&format!("Multi-variant engine: {}", name));
+    }
+    if let Some(ref update) = assets.engine_update {
+        logger.info(&format!("Engine update: {}", update.version));
+    }
+    let description = Description::gather(&opt, cpu, &assets).await;
+    description.log(logger);
+    if opt.force_multivariant {
+        logger.info("Official Stockfish: disabled (analysing standard chess with Fairy-Stockfish)");
+    } else if !engines.official {
+        logger.info("Official Stockfish: disabled (rejecting standard chess analysis)");
+    }
+    if !engines.multi_variant {
+        logger.info("Fairy-Stockfish: disabled (rejecting variants and move requests)");
+    }
+    if let Some(ref external_engines) = opt.external_engines {
+        for external in &external_engines.0 {
+            match stockfish::handshake(&external.path, external.variant).await {
+                Ok(name) => {
+                    logger.info(&format!(
+                        "Engine for {}: {} ({:?})",
+                        external.variant, name, external.path
+                    ));
+                    assets
+                        .external_engines
+                        .push((external.variant, external.path.clone()));
+                }
+                Err(err) => logger.error(&format!(
+                    "External engine {:?} failed the handshake for {}: {}. Using the bundled engine.",
+                    external.path, external.variant, err
+                )),
+            }
+        }
+    }
+    if let Some(ref path) = opt.syzygy_path {
+        let files = assets
+            .set_syzygy_path(path)
+            .map_err(|err| SessionError::Syzygy(path.clone(), err))?;
+        logger.info(&format!(
+            "Syzygy tablebases: {} files in {:?} (official Stockfish only)",
+            files, path
+        ));
+    }
+    assets.download_rate_limit = opt.download_rate_limit.map(u64::from);
+    if let Some(ref variant_nets) = opt.variant_nets {
+        for net in variant_nets.0.iter().filter(|_| engines.multi_variant) {
+            match assets.fetch_variant_net(net).await {
+                Ok(path) => logger.info(&format!("NNUE for {}: {:?}", net.variant, path)),
+                Err(err) => logger.error(&format!(
+                    "Failed to fetch NNUE for {} from {}: {}. Using classical evaluation.",
+                    net.variant, net.url, err
+                )),
+            }
+        }
+    }
+    assets.set_engine_options(&opt.engine);
+    if assets.threads > 1 {
+        logger.info(&format!(
+            "Cores: {} engine processes with {} threads each",
+            cores, assets.threads
+        ));
+    } else {
+        logger.info(&format!("Cores: {}", cores));
+    }
+    if !assets.numa_nodes.is_empty() {
+        logger.info(&format!(
+            "NUMA: spreading engine processes across {} nodes",
+            assets.numa_nodes.len()
+        ));
+    }
+    if assets.pin_cores && !cfg!(target_os = "linux") {
+        logger.warn("Pinning engine processes to cores is only supported on Linux");
+    }
+    match assets.sandbox {
+        Sandbox::Off => (),
+        sandbox if !sandbox::supported(sandbox) => return Err(SessionError::Sandbox(sandbox)),
+        sandbox => logger.info(&format!("Sandbox: {} for engine processes", sandbox)),
+    }
+    if let Some(mate) = assets.early_stop.mate {
+        logger.info(&format!(
+            "Early stop: forced mates in at most {} moves",
+            mate
+        ));
+    }
+    if let Some(cp_window) = assets.early_stop.cp_window {
+        logger.info(&format!(
+            "Early stop: evaluations stable within {} cp",
+            cp_window
+        ));
+    }
+    let reserved_cores = opt.reserve_cores.map_or(0, |n| min(n, cores - 1));
+    if reserved_cores > 0 {
+        logger.info(&format!(
+            "Reserving {} of {} cores for user requests",
+            reserved_cores, cores
+        ));
+    }
+    if let Some(hash_mib) = assets.hash_mib.official {
+        logger.info(&format!(
+            "Official Stockfish: {} MiB hash per process",
+            hash_mib
+        ));
+    }
+    if let Some(hash_mib) = assets.hash_mib.multi_variant {
+        logger.info(&format!(
+            "Fairy-Stockfish: {} MiB hash per process",
+            hash_mib
+        ));
+    }
+    if opt.engine.deterministic {
+        logger.info("Deterministic: one thread per position, fixed hash, no time limits");
+    }
+    if !opt.engine.fixed_hash && !opt.engine.deterministic {
+        // Every worker may run an engine process of each flavor.
+        let per_worker: u64 = [
+            engines.official.then(|| assets.hash_mib.official),
+            engines.multi_variant.then(|| assets.hash_mib.multi_variant),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|hash_mib| hash_mib.unwrap_or(memory::DEFAULT_HASH_MIB))
+        .sum();
+        assets.hash_sizer = memory::HashSizer::spawn(
+            cores as u64 * per_worker,
+            assets.engine_limits.cgroup().map(Path::to_owned),
+            logger.clone(),
+        );
+    }
+    let assets = Arc::new(assets);
+
+    // Refuse to acquire work with engines that can not even search a single
+    // node, instead of failing every batch.
+    let selftest = selftest::selftest(assets.clone(), false, logger).await;
+    for failure in selftest.failures() {
+        logger.error(&format!(
+            "Self-test of {} for {} failed: {}",
+            failure.engine_name(),
+            failure.variant,
+            failure.error.as_deref().unwrap_or("unknown error")
+        ));
+    }
+    if !selftest.pass {
+        return Err(SessionError::SelfTest(selftest.failures().count()));
+    }
+    let capabilities = variant_capabilities(&assets, engines, opt.force_multivariant);
+    let unsupported: Vec<LichessVariant> = LichessVariant::ALL
+        .iter()
+        .copied()
+        .filter(|&variant| !capabilities.iter().any(|c| c.variant == variant))
+        .collect();
+    if !unsupported.is_empty() {
+        logger.info(&format!(
+            "Not acquiring {} (no engine enabled)",
+            VariantList(unsupported.clone())
+        ));
+    }
+
+    let calibration = match calibration::load() {
+        Ok(Some(calibration)) if calibration.threads != assets.threads => {
+            logger.warn(&format!(
+                "Ignoring calibration with {} threads per engine process. Run fishnet benchmark again.",
+                calibration.threads
+            ));
+            None
+        }
+        Ok(Some(calibration)) => {
+            for flavor in [EngineFlavor::Official, EngineFlavor::MultiVariant] {
+                if let Some(nps) = calibration.nps(flavor) {
+                    logger.info(&format!(
+                        "Calibrated {}: {} per process",
+                        match flavor {
+                            EngineFlavor::Official => "Official Stockfish",
+                            EngineFlavor::MultiVariant => "Fairy-Stockfish",
+                        },
+                        logger.nps(nps)
+                    ));
+                }
+            }
+            Some(calibration)
+        }
+        Ok(None) => None,
+        Err(err) => {
+            logger.warn(&format!("Failed to read calibration: {}", err));
+            None
+        }
+    };
+
+    let lookup = Lookup::new(&opt.lookup, logger.clone())
+        .map_err(|err| SessionError::Book(opt.lookup.book.clone().unwrap_or_default(), err))?;
+    let book_skip = lookup.book_skip().map(Arc::new);
+    if let (Some(cache), Some(capacity)) = (lookup.cache(), opt.lookup.result_cache) {
+        logger.info(&format!(
+            "Result cache: Up to {} positions ({} loaded)",
+            capacity.separate_with_dots(),
+            cache.len().separate_with_dots()
+        ));
+    }
+    let lookup = Arc::new(lookup);
+    if let Some(book_skip_plies) = opt.lookup.book_skip_plies.filter(|_| book_skip.is_some()) {
+        logger.info(&format!(
+            "Book: Skipping analysis of book positions in the first {} plies",
+            book_skip_plies
+        ));
+    }
+
+    let invalid_batch_dump = match opt.invalid_batch_dir {
+        Some(ref dir) => {
+            let max_size = opt.invalid_batch_max_size.map_or(64 << 20, u64::from);
+            match InvalidBatchDump::new(dir.clone(), max_size) {
+                Ok(dump) => {
+                    logger.info(&format!("Invalid batches: dumped to {:?}", dump.dir()));
+                    Some(Arc::new(dump))
+                }
+                Err(err) => {
+                    logger.error(&format!(
+                        "Failed to create directory for invalid batches {:?}: {}",
+                        dir, err
+                    ));
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    // Write analysis results to object storage, instead of submitting them.
+    let store = match opt.s3_sink {
+        Some(ref url) => {
+            let region = opt
+                .s3_region
+                .clone()
+                .unwrap_or_else(|| "us-east-1".to_owned());
+            Some((
+                url,
+                sink::ObjectStore::from_env(url, region).map_err(SessionError::ObjectStore)?,
+            ))
+        }
+        None => None,
+    };
+
+    // To wait for workers and API actor before shutdown.
+    let mut join_handles = Vec::new();
+
+    // Replay recorded batches instead of talking to the server, if
+    // requested. The same actor takes move submissions.
+    let replay = match opt.replay {
+        Some(ref path) => {
+            let recorded =
+                replay::load(path).map_err(|err| SessionError::Replay(path.clone(), err))?;
+            let out = opt
+                .replay_out
+                .clone()
+                .unwrap_or_else(|| replay::default_out(path));
+            let (api, replay_actor) = replay::channel(
+                endpoint.clone(),
+                recorded,
+                &out,
+                shutdown.clone(),
+                logger.clone(),
+            )
+            .map_err(|err| SessionError::Replay(out.clone(), err))?;
+            logger.info(&format!("Replay: {:?}, submissions to {:?}", path, out));
+            join_handles.push(tokio::spawn(async move {
+                replay_actor.run().await;
+            }));
+            Some(api)
+        }
+        None => None,
+    };
+
+    // Engine builds to report with deterministic analysis.
+    let engine_builds = if opt.engine.deterministic {
+        match assets.engine_hashes() {
+            Ok(hashes) => Some(hashes),
+            Err(err) => {
+                logger.error(&format!("Failed to hash engine executables: {}", err));
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Spawn API actors: one for move submissions, so that they are not
+    // held up by other requests, and one for everything else. With multiple
+    // endpoints, each has its own pair, behind a failover actor.
+    let (api, submit_api) = if let Some(api) = replay {
+        (api.clone(), api)
+    } else {
+        let mut upstreams = Vec::with_capacity(opt.endpoint_confs.len());
+        for conf in &opt.endpoint_confs {
+            let (api, api_actor) = api::channel(
+                conf.endpoint.clone(),
+                conf.key.clone(),
+                conf.proxy.clone(),
+                logger.clone(),
+            );
+            let api_actor = api_actor
+                .websocket(opt.websocket)
+                .calibration(calibration)
+                .worker(opt.worker_name.clone(), opt.labels.clone())
+                .client_cert(client_cert.clone(), reload_trigger.clone())
+                .ip_preference(opt.ip_preference())
+                .max_error_backoff(opt.max_error_backoff.map(Duration::from))
+                .variants(
+                    capabilities
+                        .iter()
+                        .filter(|c| conf.variants.allows(c.variant))
+                        .cloned()
+                        .collect(),
+                )
+                .variant_filter(conf.variants.clone());
+            join_handles.push(tokio::spawn(async move {
+                api_actor.run().await;
+            }));
+
+            let (submit_api, submit_api_actor) = api::channel(
+                conf.endpoint.clone(),
+                conf.key.clone(),
+                conf.proxy.clone(),
+                logger.clone(),
+            );
+            let submit_api_actor = submit_api_actor
+                .worker(opt.worker_name.clone(), opt.labels.clone())
+                .client_cert(client_cert.clone(), reload_trigger.clone())
+                .ip_preference(opt.ip_preference())
+                .max_error_backoff(opt.max_error_backoff.map(Duration::from))
+                .outbox_dir(opt.outbox_dir())
+                .engine_builds(engine_builds.clone());
+            join_handles.push(tokio::spawn(async move {
+                submit_api_actor.run().await;
+            }));
+
+            upstreams.push(failover::Upstream {
+                name: conf.name.clone(),
+                api,
+                submit_api,
+            });
+        }
+        if upstreams.len() > 1 {
+            let (api, failover_actor) = failover::channel(upstreams, logger.clone());
+            join_handles.push(tokio::spawn(async move {
+                failover_actor.run().await;
+            }));
+            (api.clone(), api)
+        } else {
+            let upstream = upstreams.pop().expect("endpoint configured");
+            (upstream.api, upstream.submit_api)
+        }
+    };
+
+    logger.headline(&match stop_hint {
+        Some(stop_hint) => format!("Running ({} to stop) ...", stop_hint),
+        None => "Running ...".to_owned(),
+    });
+
+    // Spawn trace exporter.
+    let tracer = opt
+        .otlp_endpoint
+        .clone()
+        .map(|endpoint| trace::spawn(endpoint, logger.clone()));
+
+    // Spawn anomaly notifier.
+    let webhook = opt
+        .anomaly_webhook
+        .clone()
+        .map(|url| anomaly::spawn_webhook(url, logger.clone()));
+
+    // Open event stream.
+    let events = opt
+        .event_stream
+        .and_then(|target| match EventStream::open(target) {
+            Ok(events) => {
+                logger.info(&format!("Event stream: {}", target));
+                Some(events)
+            }
+            Err(err) => {
+                logger.error(&format!("Failed to open event stream {}: {}", target, err));
+                None
+            }
+        });
+
+    // Open provenance log.
+    let audit_log = opt.audit_log.as_ref().and_then(|path| {
+        match assets
+            .engine_hashes()
+            .and_then(|hashes| audit::AuditLog::open(path, hashes))
+        {
+            Ok(audit_log) => {
+                logger.info(&format!("Audit log: {:?}", path));
+                Some(audit_log)
+            }
+            Err(err) => {
+                logger.error(&format!("Failed to open audit log {:?}: {}", path, err));
+                None
+            }
+        }
+    });
+
+    // Open journal of analysis in progress.
+    let journal =
+        opt.journal_path()
+            .and_then(|path| match journal::Journal::open(&path, api.endpoint()) {
+                Ok(journal) => Some(journal),
+                Err(err) => {
+                    logger.error(&format!("Failed to open journal {:?}: {}", path, err));
+                    None
+                }
+            });
+
+    if let Some((url, store)) = store {
+        let (sink, join_handle) = sink::spawn(store, logger.clone());
+        join_handles.push(join_handle);
+        handlers.register("analysis", Box::new(sink::ObjectStoreHandler { sink }));
+        logger.info(&format!("Analysis results: {}", url));
+    }
+
+    // Spawn queue actor.
+    let mut queue = {
+        let (queue, queue_actor) = queue::channel(
+            conf.backlog.clone(),
+            queue::Routing {
+                // Each endpoint narrows the variants of its acquire requests.
+                variants: {
+                    let mut variants =
+                        VariantFilter::any_of(opt.endpoint_confs.iter().map(|conf| &conf.variants));
+                    if !unsupported.is_empty() {
+                        variants.restrict(None, Some(&VariantList(unsupported)));
+                    }
+                    variants
+                },
+                engines,
+                force_multi_variant: opt.force_multivariant,
+                scale_nodes: opt.scale_nodes && !opt.engine.deterministic,
+                move_latency_budget: opt.move_latency_budget.map(Duration::from),
+                pv_san: opt.pv_san,
+                analysis_order: opt.analysis_order.unwrap_or_default(),
+                budget_by_phase: opt.budget_by_phase,
+                puzzle_candidates: opt.puzzle_candidates,
+                limits: WorkLimits {
+                    max_nodes: opt.max_nodes,
+                    max_depth: opt.max_depth,
+                    movetime: opt.movetime.map(Duration::from),
+                    nodes_only: opt.engine.deterministic,
+                },
+                calibration,
+                book_skip,
+                invalid_batch_dump,
+                handlers: Arc::new(handlers),
+            },
+            cores,
+            queue::Upstream {
+                api,
+                submit_api,
+                max_backoff: opt.max_backoff.into(),
+            },
+            queue::Sinks {
+                tracer,
+                webhook: webhook.clone(),
+                audit_log,
+                events: events.clone(),
+                journal,
+            },
+            logger.clone(),
+        );
+        join_handles.push(tokio::spawn(async move {
+            queue_actor.run().await;
+        }));
+        queue
+    };
+    let (lifetime_at_start, _) = queue.stats().await;
+    logger.fishnet_info(&format!(
+        "Lifetime contribution: {} batches, {} positions, {}, {} uptime",
+        lifetime_at_start.total_batches.separate_with_dots(),
+        lifetime_at_start.total_positions.separate_with_dots(),
+        logger.nodes(lifetime_at_start.total_nodes),
+        ParsedDuration::from(lifetime_at_start.total_uptime)
+    ));
+
+    // Check leaderboard standing from time to time. Uses a separate API
+    // actor, which does not need to be shut down.
+    let standing_checker = opt.replay.is_none().then(|| {
+        let mut api = api::spawn(
+            endpoint.clone(),
+            conf.key.clone(),
+            conf.proxy.clone(),
+            logger.clone(),
+        );
+        let queue = queue.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Some(standing) = api.standing().await {
+                    queue.record_standing(&standing).await;
+                }
+                time::sleep(Duration::from_secs(6 * 60 * 60)).await;
+            }
+        })
+    });
+
+    // Serve status and accept control commands.
+    let board = WorkerBoard::new(cores);
+    let (control, mut control_rx) = control::channel();
+    {
+        let status = StatusServer::new(
+            description,
+            queue.clone(),
+            board.clone(),
+            control.clone(),
+            logger.clone(),
+        );
+        if let Some(ref control_socket) = opt.control_socket {
+            control::socket::spawn(control_socket, status.clone(), logger.clone());
+        }
+        if keys {
+            logger.info("Keys: + and - (then enter) to use more or fewer cores");
+            control::spawn_keys(control.clone(), logger.clone());
+        }
+        if let Some(metrics_bind) = opt.metrics_bind {
+            status.clone().spawn_metrics(metrics_bind);
+        }
+        if let Some(status_bind) = opt.status_bind {
+            status.spawn(status_bind);
+        }
+    }
+    let tui = if opt.format.tui {
+        Tui::spawn(queue.clone(), board.clone(), logger.clone())
+    } else {
+        None
+    };
+
+    // Workers with an index of at least the number of active cores are
+    // parked. The number is arbitrated between the limits of the control
+    // socket and the watchers.
+    let (mut arbiter, active_cores) =
+        CoreArbiter::spawn(&opt, cores, queue.clone(), logger.clone());
+
+    // Spawn workers. Workers handle engine processes and send their results
+    // to tx, thereby requesting more work.
+    let mut coordinator = None;
+    let mut rx = {
+        let verifier = Verifier::spawn(&opt.verify, assets.clone(), webhook, logger.clone()).map(
+            |(verifier, join_handle)| {
+                logger.info(&format!(
+                    "Verifying {}% of positions on a separate engine",
+                    opt.verify.verify_fraction.unwrap_or_default() * 100.0
+                ));
+                join_handles.push(join_handle);
+                Arc::new(verifier)
+            },
+        );
+        let trace = match opt.trace_engine_dir {
+            Some(ref dir) => match fs::create_dir_all(dir) {
+                Ok(()) => {
+                    let max_size = opt.trace_engine_max_size.map_or(256 << 20, u64::from);
+                    logger.info(&format!(
+                        "Tracing engine communication to {:?} (up to {} MiB)",
+                        dir,
+                        max_size >> 20
+                    ));
+                    spawn_trace_eviction(dir.clone(), max_size, logger.clone());
+                    Some(EngineTrace::Dir(dir.clone()))
+                }
+                Err(err) => {
+                    logger.error(&format!(
+                        "Failed to create engine trace directory {:?}: {}",
+                        dir, err
+                    ));
+                    None
+                }
+            },
+            None if opt.trace_engine => {
+                logger.info("Tracing engine communication");
+                Some(EngineTrace::Log)
+            }
+            None => None,
+        };
+
+        // Check for engine updates from time to time. Workers switch to new
+        // builds between batches.
+        let (engine_update_tx, engine_update_rx) = watch::channel(None);
+        let engine_update_interval = match opt.engine_update_interval {
+            Some(_) if opt.engine.deterministic => {
+                logger.warn("Not checking for engine updates with --deterministic");
+                None
+            }
+            interval => interval,
+        };
+        if let (Some(url), Some(interval)) = (opt.engine_manifest.clone(), engine_update_interval) {
+            logger.info(&format!(
+                "Engine updates: Checking {} every {}",
+                url, interval
+            ));
+            let assets = assets.clone();
+            let logger = logger.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = engine_update_tx.closed() => break,
+                        _ = time::sleep(Duration::from(interval)) => (),
+                    }
+                    match assets.fetch_engine_update(&url).await {
+                        Ok(Some(update)) => {
+                            logger.info(&format!(
+                                "Engines updated to {}. Switching between batches.",
+                                update.version
+                            ));
+                            engine_update_tx
+                                .send(Some(Arc::new(update)))
+                                .nevermind("workers exited");
+                        }
+                        Ok(None) => logger.debug("Engines are up to date"),
+                        Err(err) => {
+                            logger.warn(&format!("Failed to check for engine updates: {}", err))
+                        }
+                    }
+                }
+            });
+        }
+
+        let (tx, rx) = mpsc::channel::<Pull>(cores);
+        let cancellations = queue.cancellations();
+        let worker_context = WorkerContext {
+            reserved_cores,
+            assets: assets.clone(),
+            lookup: lookup.clone(),
+            verifier,
+            tx: tx.clone(),
+            events: events.clone(),
+            board: board.clone(),
+            active_cores,
+            engine_update: engine_update_rx,
+            trace,
+            logger: logger.clone(),
+        };
+        for i in 0..cores {
+            let cancelled = cancellations.subscribe();
+            join_handles.push(tokio::spawn(worker(i, cancelled, worker_context.clone())));
+        }
+        // Only workers and followers hold senders, so that rx ends once
+        // they are done.
+        drop(worker_context);
+        if let Some(cluster_bind) = opt.cluster_bind {
+            match cluster::spawn_coordinator(cluster_bind, tx, logger.clone()).await {
+                Ok(c) => {
+                    logger.info(&format!("Accepting followers on {}", cluster_bind));
+                    coordinator = Some(c);
+                }
+                Err(err) => {
+                    // Workers stop once the receiver is dropped.
+                    queue.shutdown().await;
+                    if let Some(ref standing_checker) = standing_checker {
+                        standing_checker.abort();
+                    }
+                    return Err(SessionError::Cluster(cluster_bind, err));
+                }
+            }
+        }
+        rx
+    };
+
+    // Engines are started on demand from the prepared binaries, so the
+    // service is up once the workers are running.
+    notify_service_manager("READY=1", logger);
+    let mut watchdog = notify::watchdog_interval().map(|period| {
+        logger.debug(&format!("Sending watchdog heartbeats every {:?}", period));
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        interval
+    });
+
+    let mut shutdown_rx = shutdown.rx.clone();
+    let mut summarized = Instant::now();
+    let mut shutdown_soon = false;
+    let mut checkpointing = false;
+    let mut notified_stopping = false;
+
+    loop {
+        // Apply shutdown requests.
+        let requested = *shutdown_rx.borrow();
+        match requested {
+            Shutdown::Running => (),
+            Shutdown::Soon => {
+                if !shutdown_soon {
+                    queue.shutdown_soon().await;
+                    shutdown_soon = true;
+                }
+            }
+            Shutdown::Checkpoint => {
+                if !checkpointing {
+                    queue.checkpoint_soon().await;
+                    shutdown_soon = true;
+                    checkpointing = true;
+                }
+            }
+            Shutdown::Now => {
+                shutdown_soon = true;
+                rx.close();
+            }
+        }
+
+        // Stop accepting followers, so that the loop ends once all workers
+        // are done. Other instances can have the leased cores.
+        if shutdown_soon {
+            drop(coordinator.take());
+            arbiter.release();
+        }
+
+        // Tell systemd, so that it does not consider the drain a hang.
+        if shutdown_soon && !notified_stopping {
+            notified_stopping = true;
+            notify_service_manager(
+                if shutdown.restart.load(Ordering::Relaxed) {
+                    "RELOADING=1"
+                } else {
+                    "STOPPING=1"
+                },
+                logger,
+            );
+        }
+
+        // Print summary from time to time.
+        let now = Instant::now();
+        if now.duration_since(summarized) >= Duration::from_secs(120) {
+            summarized = now;
+            let (stats, nnue_nps) = queue.stats().await;
+            logger.fishnet_info(&format!(
+                "fishnet/{}: {}{} (nnue), {} batches, {} positions, {} total",
+                env!("CARGO_PKG_VERSION"),
+                logger.nps(nnue_nps.nps),
+                nnue_nps.uncertainty_marks(),
+                stats.total_batches.separate_with_dots(),
+                stats.total_positions.separate_with_dots(),
+                logger.nodes(stats.total_nodes)
+            ));
+        }
+
+        // Main loop. Handles shutdown requests, forwards worker results from
+        // rx to the queue and responds with more work.
+        tokio::select! {
+            Ok(()) = shutdown_rx.changed() => (),
+            res = rx.recv() => {
+                if let Some(res) = res {
+                    queue.pull(res).await;
+                } else {
+                    logger.debug("About to exit.");
+                    break;
+                }
+            }
+            Some(req) = control_rx.recv() => {
+                let res = match req.command {
+                    ControlCommand::Status | ControlCommand::Report | ControlCommand::Stats => {
+                        Err("handled by the status server".to_owned())
+                    }
+                    ControlCommand::Drain => {
+                        if !shutdown_soon {
+                            logger.headline("Stopping soon (drain requested) ...");
+                            shutdown.drain();
+                        }
+                        Ok("Draining. Will stop after pending batches.".to_owned())
+                    }
+                    ControlCommand::Reload => {
+                        reload(&opt, &conf.name, &mut arbiter, &queue, logger).await
+                    }
+                    ControlCommand::Set { setting: Setting::Cores(n) } => {
+                        arbiter.configure(n.get()).await
+                    }
+                    ControlCommand::Set { setting: Setting::Verbose(level) } => {
+                        logger.set_verbose(Verbose { level });
+                        Ok(format!("Verbose level {}.", level))
+                    }
+                    ControlCommand::Up => arbiter.up().await,
+                    ControlCommand::Down => arbiter.down().await,
+                    ControlCommand::Pause => arbiter.pause().await,
+                    ControlCommand::Resume => arbiter.resume().await,
+                };
+                req.callback.send(res).nevermind("control callback dropped");
+            }
+            Some(()) = summary_requested(&summary_trigger) => {
+                log_throughput(&queue.throughput().await, logger);
+            }
+            Some(()) = reload_requested(&mut reload_trigger) => {
+                // In-flight batches are kept. Only future acquires and
+                // workers see the new settings.
+                if let Err(err) = reload(&opt, &conf.name, &mut arbiter, &queue, logger).await {
+                    logger.error(&format!("Failed to reload {:?}: {}", opt.conf, err));
+                }
+            }
+            Some(period) = watchdog_tick(&mut watchdog) => {
+                // Heartbeats stop if this loop or the queue is wedged, so
+                // that systemd restarts the service.
+                match time::timeout(period, queue.health()).await {
+                    Ok(health) => match health.stalled_for {
+                        None => notify_service_manager("WATCHDOG=1", logger),
+                        Some(stalled_for) => logger.error(&format!(
+                            "No position completed for {}s. Skipping watchdog heartbeat.",
+                            stalled_for.as_secs()
+                        )),
+                    },
+                    Err(_) => logger.error("Queue not responding. Skipping watchdog heartbeat."),
+                }
+            }
+            Some(event) = arbiter.next_event() => arbiter.handle(event).await,
+            _ = time::sleep(Duration::from_secs(120)) => (),
+        }
+    }
+
+    if let Some(tui) = tui {
+        tui.stop();
+    }
+
+    // Performance breakdown of this session.
+    let performance = queue.performance().await;
+    for perf in &performance {
+        logger.info(&format!(
+            "{} ({}): {} positions, {}, avg depth {:.1}, avg {} ms per position",
+            perf.variant,
+            perf.flavor,
+            perf.positions.separate_with_dots(),
+            logger.nps(u32::try_from(perf.nps).unwrap_or(u32::MAX)),
+            perf.avg_depth,
+            perf.avg_time
+        ));
+    }
+    let latencies = queue.latencies().await;
+    for latency in &latencies {
+        logger.info(&format!(
+            "{} ({} nodes): {} positions, latency p50 {} ms, p95 {} ms, p99 {} ms",
+            latency.variant,
+            latency.nodes,
+            latency.positions.separate_with_dots(),
+            latency.p50,
+            latency.p95,
+            latency.p99
+        ));
+    }
+    let stages = queue.stage_latencies().await;
+    for stage in &stages {
+        logger.debug(&format!(
+            "{} {}: {} samples, latency p50 {} ms, p95 {} ms, p99 {} ms",
+            stage.work,
+            stage.stage.name(),
+            stage.count.separate_with_dots(),
+            stage.p50,
+            stage.p95,
+            stage.p99
+        ));
+    }
+    let throughput = queue.throughput().await;
+    log_throughput(&throughput, logger);
+    let (lifetime, _) = queue.stats().await;
+    if let Some(standing_checker) = standing_checker {
+        standing_checker.abort();
+    }
+
+    // Save unfinished analysis, unless asked to abort after all. Then
+    // shutdown queue to abort remaining jobs.
+    if *shutdown_rx.borrow() == Shutdown::Checkpoint {
+        queue.checkpoint().await;
+    }
+    queue.shutdown().await;
+
+    // Wait for all workers.
+    for join_handle in join_handles.into_iter() {
+        join_handle.await.expect("join");
+    }
+
+    let (warm, cold) = (
+        METRICS.positions_warm_hash.get(),
+        METRICS.positions_cold_hash.get(),
+    );
+    if warm > 0 {
+        logger.fishnet_info(&format!(
+            "Warm hash: {} of {} positions continued from an adjacent ply, avg depth {:.1} ({:.1} cold)",
+            warm.separate_with_dots(),
+            (warm + cold).separate_with_dots(),
+            METRICS.depth_warm_hash.get() as f64 / warm as f64,
+            METRICS.depth_cold_hash.get() as f64 / cold.max(1) as f64
+        ));
+    }
+
+    if let Some(cache) = lookup.cache() {
+        let (hits, misses) = cache.hits_and_misses();
+        logger.fishnet_info(&format!(
+            "Result cache: {} hits, {} misses ({:.1}% hit rate)",
+            hits.separate_with_dots(),
+            misses.separate_with_dots(),
+            100.0 * hits as f64 / (hits + misses).max(1) as f64
+        ));
+        if let Err(err) = cache.save() {
+            logger.error(&format!("Failed to save result cache: {}", err));
+        }
+    }
+
+    Ok(SessionSummary {
+        batches: lifetime.total_batches - lifetime_at_start.total_batches,
+        positions: lifetime.total_positions - lifetime_at_start.total_positions,
+        nodes: lifetime.total_nodes - lifetime_at_start.total_nodes,
+        uptime: started_at.elapsed(),
+        performance,
+        latencies,
+        stages,
+        throughput,
+    })
+}
+
+/// Variants that the enabled engines can analyse, and how.
+fn variant_capabilities(
+    assets: &Assets,
+    engines: ByEngineFlavor<bool>,
+    force_multi_variant: bool,
+) -> Vec<VariantCapability> {
+    LichessVariant::ALL
+        .iter()
+        .copied()
+        .filter_map(|variant| {
+            // Chess is analysed with official Stockfish, unless forced
+            // otherwise, and everything else with Fairy-Stockfish.
+            let flavor = if Variant::from(variant) == Variant::Chess && !force_multi_variant {
+                EngineFlavor::Official
+            } else {
+                EngineFlavor::MultiVariant
+            };
+            let nnue = flavor.eval_flavor().is_nnue()
+                || assets.variant_nets.iter().any(|(v, _)| *v == variant)
+                || assets.external_engines.iter().any(|(v, _)| *v == variant);
+            engines
+                .get(flavor)
+                .then(|| VariantCapability::new(variant, nnue))
+        })
+        .collect()
+}
+
+/// Removes the least recently written engine traces from time to time, so
+/// that they do not fill the disk.
+fn spawn_trace_eviction(dir: PathBuf, max_size: u64, logger: Logger) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            match disk::make_room(&dir, "log", max_size, 0) {
+                Ok(0) => (),
+                Ok(removed) => logger.debug(&format!(
+                    "Removed {} engine traces to stay within {} MiB",
+                    removed,
+                    max_size >> 20
+                )),
+                Err(err) => logger.warn(&format!("Failed to evict engine traces: {}", err)),
+            }
+        }
+    });
+}
+
+fn log_throughput(throughput: &Throughput, logger: &Logger) {
+    logger.fishnet_info(&format!(
+        "Session: {} batches ({} failed), {} positions in {}, {:.1} positions/s, {} per engine process, {} cpu",
+        throughput.batches.separate_with_dots(),
+        throughput.failed_batches.separate_with_dots(),
+        throughput.positions.separate_with_dots(),
+        ParsedDuration::from(Duration::from_secs(throughput.uptime().as_secs())),
+        throughput.positions_per_second(),
+        throughput
+            .engine_nps()
+            .map_or_else(|| "? nps".to_owned(), |nps| logger.nps(nps)),
+        ParsedDuration::from(Duration::from_secs(throughput.cpu_time.as_secs()))
+    ));
+}
+
+fn engine_restarted(
+    events: &Option<EventStream>,
+    worker: usize,
+    flavor: EngineFlavor,
+    reason: &'static str,
+) {
+    METRICS.engine_restarts.inc();
+    if let Some(events) = events {
+        events.emit(&Event::EngineRestarted {
+            worker,
+            engine: flavor.name(),
+            reason,
+        });
+    }
+}
+
+/// Waits until the server cancels the given batch.
+async fn batch_cancelled(cancelled: &mut broadcast::Receiver<BatchId>, batch_id: BatchId) {
+    loop {
+        match cancelled.recv().await {
+            Ok(cancelled) if cancelled == batch_id => return,
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => future::pending().await,
+        }
+    }
+}
+
+/// Waits for a request to log a summary, if enabled.
+async fn summary_requested(summary_trigger: &Option<Arc<Notify>>) -> Option<()> {
+    summary_trigger.as_ref()?.notified().await;
+    Some(())
+}
+
+/// Waits for a request to reload the configuration file, if enabled.
+async fn reload_requested(reload_trigger: &mut Option<watch::Receiver<()>>) -> Option<()> {
+    reload_trigger.as_mut()?.changed().await.ok()
+}
+
+fn notify_service_manager(state: &str, logger: &Logger) {
+    if let Err(err) = notify::notify(state) {
+        logger.warn(&format!("Failed to notify systemd ({}): {}", state, err));
+    }
+}
+
+/// Waits for the next watchdog heartbeat, if enabled.
+async fn watchdog_tick(watchdog: &mut Option<time::Interval>) -> Option<Duration> {
+    let interval = watchdog.as_mut()?;
+    interval.tick().await;
+    Some(interval.period())
+}
+
+/// Applies the settings that can change at runtime from the configuration
+/// file.
+async fn reload(
+    opt: &Opt,
+    endpoint_name: &str,
+    arbiter: &mut CoreArbiter,
+    queue: &QueueStub,
+    logger: &Logger,
+) -> Result<String, String> {
+    let reloaded = configure::reload(opt, endpoint_name)?;
+    logger.fishnet_info(&format!("Reloaded backlog from {:?}", opt.conf));
+    queue.set_backlog(reloaded.backlog).await;
+    if let Some(verbose) = reloaded.verbose {
+        logger.set_verbose(verbose);
+    }
+    match reloaded.cores {
+        Some(reloaded_cores) => {
+            let n = min(arbiter.max(), usize::from(reloaded_cores));
+            arbiter.configure(n).await
+        }
+        None => Ok("Reloaded backlog.".to_owned()),
+    }
+}
+
+/// Replace engine processes after serving this many batches, so that state
+/// can not leak from batch to batch indefinitely.
+const ENGINE_MAX_BATCHES: u32 = 100;
+
+/// Engine process of a worker. Third-party engines get their own process
+/// for each variant.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum EngineKey {
+    Bundled(EngineFlavor),
+    External(LichessVariant),
+}
+
+impl EngineKey {
+    fn for_position(assets: &Assets, position: &Position) -> EngineKey {
+        if assets.external_engine(position.variant).is_some() {
+            EngineKey::External(position.variant)
+        } else {
+            EngineKey::Bundled(position.flavor)
+        }
+    }
+}
+
+/// Batches served by an engine process.
+#[derive(Default)]
+struct EngineUsage {
+    batches: u32,
+    last_batch: Option<BatchId>,
+    /// Watches only this engine process, so that an anomaly on one worker
+    /// does not retire the engines of all others.
+    anomaly_detector: AnomalyDetector,
+    anomalies: usize,
+    /// Engine update that the process was started from, if it was picked
+    /// up while running.
+    update: Option<Arc<EngineUpdate>>,
+}
+
+impl EngineUsage {
+    fn started(update: Option<Arc<EngineUpdate>>) -> EngineUsage {
+        EngineUsage {
+            update,
+            ..EngineUsage::default()
+        }
+    }
+
+    fn record(&mut self, batch_id: BatchId, flavor: EngineFlavor, res: &PositionResponse) {
+        if self.last_batch != Some(batch_id) {
+            self.last_batch = Some(batch_id);
+            self.batches += 1;
+        }
+        self.anomalies += self.anomaly_detector.record(flavor, res).len();
+    }
+
+    fn retire_reason(
+        &self,
+        batch_id: BatchId,
+        update: Option<&Arc<EngineUpdate>>,
+    ) -> Option<&'static str> {
+        if self.batches >= ENGINE_MAX_BATCHES {
+            Some("after serving many batches")
+        } else if self.anomalies > 0 {
+            Some("after an engine anomaly")
+        } else if self.last_batch != Some(batch_id)
+            && update.map(|u| &u.version) != self.update.as_ref().map(|u| &u.version)
+        {
+            // Only between batches, so that all positions of a batch are
+            // analysed by the same engine.
+            Some("to switch to the engine update")
+        } else {
+            None
+        }
+    }
+}
+
+/// Inputs shared by all workers.
+#[derive(Clone)]
+struct WorkerContext {
+    /// Workers with a lower index leave room for system analysis.
+    reserved_cores: usize,
+    assets: Arc<Assets>,
+    lookup: Arc<Lookup>,
+    verifier: Option<Arc<Verifier>>,
+    /// Results and requests for more work.
+    tx: mpsc::Sender<Pull>,
+    events: Option<EventStream>,
+    board: WorkerBoard,
+    /// Workers with an index of at least this number are parked.
+    active_cores: watch::Receiver<usize>,
+    engine_update: watch::Receiver<Option<Arc<EngineUpdate>>>,
+    trace: Option<EngineTrace>,
+    logger: Logger,
+}
+
+async fn worker(
+    i: usize,
+    mut cancelled: broadcast::Receiver<BatchId>,
+    worker_context: WorkerContext,
+) {
+    let WorkerContext {
+        reserved_cores,
+        assets,
+        lookup,
+        verifier,
+        tx,
+        events,
+        board,
+        mut active_cores,
+        engine_update,
+        trace,
+        logger,
+    } = worker_context;
+
+    logger.debug(&format!("Started worker {}.", i));
+
+    let mut job: Option<Position> = None;
+    let mut attempts = 1;
+    let mut engine: HashMap<EngineKey, (StockfishStub, JoinHandle<()>)> = HashMap::new();
+    let mut usage: HashMap<EngineKey, EngineUsage> = HashMap::new();
+    let mut engine_backoff = RandomizedBackoff::default();
+
+    let default_budget = Duration::from_secs(60);
+    let mut budget = default_budget;
+
+    'work: loop {
+        let mut replay = None;
+
+        // Skip the search if the position can be looked up.
+        let cached = match job {
+            Some(ref job) => lookup.probe(job).await,
+            None => None,
+        };
+
+        let response = if let Some(res) = cached {
+            job = None;
+            Some(Ok(res))
+        } else if let Some(job) = job.take() {
+            // Engine processes are reused between batches, with ucinewgame
+            // before each position, unless it continues the previous ply of
+            // the same batch and game (not with --deterministic). Replace
+            // them from time to time.
+            let flavor = job.flavor;
+            let key = EngineKey::for_position(&assets, &job);
+            let update = match key {
+                EngineKey::Bundled(_) => engine_update.borrow().clone(),
+                EngineKey::External(_) => None,
+            };
+            if let Some(reason) = usage
+                .get(&key)
+                .and_then(|u| u.retire_reason(job.work.id(), update.as_ref()))
+            {
+                if let Some((sf, join_handle)) = engine.remove(&key) {
+                    logger.debug(&format!(
+                        "Worker {} restarting {} engine {}",
+                        i,
+                        flavor.name(),
+                        reason
+                    ));
+                    drop(sf);
+                    join_handle.await.expect("join");
+                    engine_restarted(&events, i, flavor, "retired");
+                }
+            }
+
+            // Ensure engine process is ready.
+            let context = ProgressAt::from(&job);
+            let (mut sf, join_handle) = if let Some((sf, join_handle)) = engine.remove(&key) {
+                (sf, join_handle)
+            } else {
+                // Backoff before starting engine.
+                board.set(i, Activity::starting_engine(flavor));
+                let backoff = engine_backoff.next();
+                if backoff >= Duration::from_secs(5) {
+                    logger.info(&format!(
+                        "Waiting {:?} before attempting to start engine",
+                        backoff
+                    ));
+                } else {
+                    logger.debug(&format!(
+                        "Waiting {:?} before attempting to start engine",
+                        backoff
+                    ));
+                }
+                tokio::select! {
+                    _ = tx.closed() => break,
+                    _ = time::sleep(engine_backoff.next()) => (),
+                }
+
+                // Reset budget, start engine and spawn actor.
+                budget = default_budget;
+                METRICS.engine_spawns.inc();
+                usage.insert(key, EngineUsage::started(update.clone()));
+                let external = assets.external_engine(job.variant);
+                let (sf, sf_actor) = stockfish::channel(
+                    external
+                        .or_else(|| update.as_ref().and_then(|u| u.engine(flavor)))
+                        .cloned()
+                        .unwrap_or_else(|| {
+                            assets
+                                .stockfish
+                                .get(flavor)
+                                .clone()
+                                .expect("engine flavor enabled")
+                        }),
+                    StockfishInit {
+                        nnue: update
+                            .as_ref()
+                            .and_then(|u| u.nnue())
+                            .unwrap_or_else(|| assets.nnue.clone()),
+                        syzygy_path: assets
+                            .syzygy_path
+                            .clone()
+                            .filter(|_| flavor == EngineFlavor::Official && external.is_none()),
+                        variant_nets: if flavor == EngineFlavor::MultiVariant && external.is_none()
+                        {
+                            assets.variant_nets.clone()
+                        } else {
+                            Vec::new()
+                        },
+                        external: external.is_some(),
+                        early_stop: assets.early_stop,
+                        warm_hash: assets.warm_hash,
+                        hash_mib: *assets.hash_mib.get(flavor),
+                        hash_sizer: assets.hash_sizer.clone(),
+                        threads: assets.threads,
+                        cores: assets.engine_cores(i),
+                        numa_node: assets.numa_node(i).cloned(),
+                        sandbox: assets.sandbox,
+                        limits: assets.engine_limits.clone(),
+                        transcript: board.transcript(i),
+                        trace: trace.clone(),
+                    },
+                    logger.clone(),
+                );
+                let join_handle = tokio::spawn(async move {
+                    sf_actor.run().await;
+                });
+                (sf, join_handle)
+            };
+
+            // Provide time budget.
+            board.working(i, &job);
+            budget = min(default_budget, budget) + job.work.timeout();
+
+            // Analyse or play.
+            let timer = Instant::now();
+            let batch_id = job.work.id();
+            let sample = verifier.as_ref().and_then(|v| v.sample(&job));
+            let retry = job.clone();
+            let res = tokio::select! {
+                _ = tx.closed() => {
+                    logger.debug(&format!("Worker {} shutting down engine early", i));
+                    drop(sf);
+                    join_handle.await.expect("join");
+                    break;
+                }
+                _ = batch_cancelled(&mut cancelled, batch_id) => {
+                    logger.debug(&format!("Worker {} stopping engine, because the batch was cancelled. Context: {}", i, context));
+                    drop(sf);
+                    join_handle.await.expect("join");
+                    engine_restarted(&events, i, flavor, "cancelled");
+                    board.set(i, Activity::Idle);
+                    continue 'work;
+                }
+                res = sf.go(job) => {
+                    match res {
+                        Ok(res) => {
+                            lookup.record(&retry, &res);
+                            engine.insert(key, (sf, join_handle));
+                            usage.entry(key).or_default().record(batch_id, flavor, &res);
+                            engine_backoff.reset();
+                            board.set_engine_ok(i, true);
+                            board.searched(i, &res);
+                            if let (Some(verifier), Some(sample)) = (&verifier, sample) {
+                                verifier.check(sample, &res);
+                            }
+                            Ok(res)
+                        }
+                        Err(failed) => {
+                            drop(sf);
+                            logger.warn(&format!("Worker {} waiting for engine to shut down after error. Context: {}", i, context));
+                            join_handle.await.expect("join");
+                            board.set_engine_ok(i, false);
+                            engine_restarted(&events, i, flavor, "error");
+                            Err(PositionFailed { attempts, ..failed })
+                        },
+                    }
+                }
+                _ = time::sleep(budget) => {
+                    logger.warn(&match flavor {
+                        EngineFlavor::Official => format!("Official Stockfish timed out in worker {}. If this happens frequently it is better to stop and defer to clients with better hardware. Context: {}", i, context),
+                        EngineFlavor::MultiVariant => format!("Fairy-Stockfish timed out in worker {}. Context: {}", i, context),
+                    });
+                    drop(sf);
+                    join_handle.await.expect("join");
+                    board.set_engine_ok(i, false);
+                    engine_restarted(&events, i, flavor, "timeout");
+                    Err(PositionFailed { attempts, ..PositionFailed::new(&retry, FailureReason::Timeout) })
+                }
+            };
+
+            // Update time budget.
+            budget = budget.checked_sub(timer.elapsed()).unwrap_or_default();
+            if budget < default_budget {
+                logger.debug(&format!("Low engine timeout budget: {:?}", budget));
+            }
+
+            // Replay the position on a new engine process, if the engine
+            // exited. Only report failure once all attempts are used up.
+            match res {
+                Err(ref failed) if failed.can_retry() => {
+                    logger.warn(&format!(
+                        "Worker {} replaying {} on a new engine. Context: {}",
+                        i, failed, context
+                    ));
+                    replay = Some(retry);
+                    None
+                }
+                Err(ref failed) => {
+                    logger.error(&format!(
+                        "Worker {} giving up on {}. Context: {}",
+                        i, failed, context
+                    ));
+                    Some(res)
+                }
+                Ok(_) => Some(res),
+            }
+        } else {
+            None
+        };
+
+        if let Some(retry) = replay {
+            METRICS.positions_retried.inc();
+            attempts += 1;
+            job = Some(retry);
+            continue;
+        }
+
+        board.set(i, Activity::Idle);
+
+        // Hand in the result without asking for more work, while this
+        // worker is not needed.
+        if i >= *active_cores.borrow() {
+            if response.is_some()
+                && tx
+                    .send(Pull {
+                        response,
+                        callback: None,
+                        reserved: false,
+                    })
+                    .await
+                    .is_err()
+            {
+                break;
+            }
+            logger.debug(&format!("Worker {} parked", i));
+            board.set(i, Activity::Parked);
+            while i >= *active_cores.borrow() {
+                tokio::select! {
+                    _ = tx.closed() => break 'work,
+                    res = active_cores.changed() => if res.is_err() {
+                        break 'work;
+                    }
+                }
+            }
+            logger.debug(&format!("Worker {} unparked", i));
+            board.set(i, Activity::Idle);
+            continue;
+        }
+
+        let waiting_since = Instant::now();
+        let (callback, waiter) = oneshot::channel();
+
+        if tx
+            .send(Pull {
+                response,
+                callback: Some(callback),
+                // Leave at least one active worker for system analysis.
+                reserved: i < reserved_cores && reserved_cores < *active_cores.borrow(),
+            })
+            .await
+            .is_err()
+        {
+            logger.debug(&format!(
+                "Worker {} was about to send result, but shutting down",
+                i
+            ));
+            break;
+        }
+
+        tokio::select! {
+            _ = tx.closed() => break,
+            res = waiter => {
+                match res {
+                    Ok(next_job) => {
+                        METRICS.queue_wait.observe(waiting_since.elapsed());
+                        job = Some(next_job);
+                        attempts = 1;
+                    }
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+
+    for (key, (sf, join_handle)) in engine.drain() {
+        logger.debug(&format!(
+            "Worker {} waiting for {} engine to shut down",
+            i,
+            match key {
+                EngineKey::Bundled(EngineFlavor::Official) => "standard".to_owned(),
+                EngineKey::Bundled(EngineFlavor::MultiVariant) => "multi-variant".to_owned(),
+                EngineKey::External(variant) => format!("external {}", variant),
+            }
+        ));
+        drop(sf);
+        join_handle.await.expect("join");
+    }
+
+    logger.debug(&format!("Stopped worker {}", i));
+    drop(tx);
+}
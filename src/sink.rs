@@ -0,0 +1,235 @@
+use std::{env, time::Duration};
+
+use ring::{digest, hmac};
+use serde::Serialize;
+use tokio::{sync::mpsc, task::JoinHandle, time};
+use url::Url;
+
+use crate::{
+    accuracy::MoveMetrics,
+    api::{AnalysisPart, Work},
+    handler::{AnalysisHandler, Submission, WorkHandler},
+    logger::Logger,
+    metrics::METRICS,
+    queue::{CompletedBatch, Skip},
+    util::RandomizedBackoff,
+};
+
+/// Give up on uploading an object after this many attempts.
+const MAX_ATTEMPTS: usize = 5;
+
+/// Location and credentials of a bucket in S3-compatible object storage.
+#[derive(Debug, Clone)]
+pub struct ObjectStore {
+    endpoint: Url,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+impl ObjectStore {
+    /// Parses a path-style URL like `https://s3.example.com/bucket/prefix/`.
+    /// Credentials are read from `AWS_ACCESS_KEY_ID` and
+    /// `AWS_SECRET_ACCESS_KEY`.
+    pub fn from_env(url: &Url, region: String) -> Result<ObjectStore, String> {
+        let path = url.path().trim_start_matches('/');
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(format!("expected bucket in path of {}", url));
+        }
+        let mut endpoint = url.clone();
+        endpoint.set_path("");
+        endpoint.set_query(None);
+        Ok(ObjectStore {
+            endpoint,
+            bucket: bucket.to_owned(),
+            prefix: prefix.to_owned(),
+            region,
+            access_key_id: env::var("AWS_ACCESS_KEY_ID")
+                .map_err(|_| "AWS_ACCESS_KEY_ID not set".to_owned())?,
+            secret_access_key: env::var("AWS_SECRET_ACCESS_KEY")
+                .map_err(|_| "AWS_SECRET_ACCESS_KEY not set".to_owned())?,
+        })
+    }
+
+    fn object_url(&self, key: &str) -> Url {
+        let mut url = self.endpoint.clone();
+        url.set_path(&format!("{}/{}{}", self.bucket, self.prefix, key));
+        url
+    }
+
+    /// Uploads an object, signed with AWS Signature Version 4.
+    async fn put(&self, client: &reqwest::Client, key: &str, body: Vec<u8>) -> Result<(), String> {
+        let url = self.object_url(key);
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => return Err(format!("expected host in {}", url)),
+        };
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex(digest::digest(&digest::SHA256, &body).as_ref());
+
+        let canonical_request = format!(
+            "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            url.path(),
+            host,
+            payload_hash,
+            amz_date,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+        );
+        let signing_key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+            hmac_sha256(
+                format!("AWS4{}", self.secret_access_key).as_bytes(),
+                date.as_bytes(),
+            ),
+            |key, part| hmac_sha256(&key, part.as_bytes()),
+        );
+        let signature = hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+        let res = client
+            .put(url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header(
+                "Authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+                    self.access_key_id, scope, signature
+                ),
+            )
+            .header("Content-Type", "application/x-ndjson")
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("storage responded with {}", res.status()))
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data)
+        .as_ref()
+        .to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// One line of an uploaded object.
+#[derive(Serialize)]
+struct ResultLine<'a> {
+    batch: String,
+    url: Option<&'a str>,
+    variant: String,
+    engine: &'static str,
+    ply: usize,
+    #[serde(flatten)]
+    analysis: AnalysisPart,
+    /// Metrics of the move leading to this position.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics: Option<MoveMetrics>,
+}
+
+/// Uploads completed batches to object storage, one at a time, in order.
+#[derive(Clone)]
+pub struct ResultSink {
+    tx: mpsc::UnboundedSender<(String, Vec<u8>)>,
+}
+
+impl ResultSink {
+    fn put(&self, batch: CompletedBatch) {
+        let batch_id = batch.work().id().to_string();
+        let url = batch.url().map(|url| url.to_string());
+        let variant = batch.variant().to_string();
+        let engine = batch.flavor().name();
+        let mut metrics = batch
+            .metrics()
+            .map_or_else(Vec::new, |metrics| metrics.moves);
+        let mut body = Vec::new();
+        for (ply, analysis) in batch.into_analysis().into_iter().enumerate() {
+            let line = ResultLine {
+                batch: batch_id.clone(),
+                url: url.as_deref(),
+                variant: variant.clone(),
+                engine,
+                ply,
+                analysis: analysis.expect("analysis part"),
+                metrics: metrics
+                    .iter()
+                    .position(|m| m.ply == ply)
+                    .map(|i| metrics.swap_remove(i)),
+            };
+            serde_json::to_writer(&mut body, &line).expect("serialize result line");
+            body.push(b'\n');
+        }
+        // Uploads during shutdown may be lost.
+        let _ = self.tx.send((format!("{}.jsonl", batch_id), body));
+    }
+}
+
+/// Spawns the uploader. The returned handle completes once all sinks are
+/// dropped and pending uploads are done.
+pub fn spawn(store: ObjectStore, logger: Logger) -> (ResultSink, JoinHandle<()>) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<(String, Vec<u8>)>();
+    let join_handle = tokio::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .expect("client");
+        let mut backoff = RandomizedBackoff::default();
+        while let Some((key, body)) = rx.recv().await {
+            for attempt in 1..=MAX_ATTEMPTS {
+                match store.put(&client, &key, body.clone()).await {
+                    Ok(()) => {
+                        logger.debug(&format!("Uploaded {}", key));
+                        backoff.reset();
+                        break;
+                    }
+                    Err(err) if attempt < MAX_ATTEMPTS => {
+                        logger.warn(&format!(
+                            "Failed to upload {} (attempt {}/{}): {}",
+                            key, attempt, MAX_ATTEMPTS, err
+                        ));
+                        time::sleep(backoff.next()).await;
+                    }
+                    Err(err) => logger.error(&format!("Giving up on uploading {}: {}", key, err)),
+                }
+            }
+        }
+    });
+    (ResultSink { tx }, join_handle)
+}
+
+/// Analyses like [`AnalysisHandler`], but writes the results to object
+/// storage instead of submitting them to the endpoint.
+pub struct ObjectStoreHandler {
+    pub sink: ResultSink,
+}
+
+impl WorkHandler for ObjectStoreHandler {
+    fn plies(&self, work: &Work, moves: usize, skip_positions: &[usize]) -> Vec<Skip<usize>> {
+        AnalysisHandler.plies(work, moves, skip_positions)
+    }
+
+    fn submit(&self, batch: CompletedBatch, summary: &str, submission: &mut Submission<'_>) {
+        submission.logger.info(summary);
+        METRICS.batches_submitted.inc();
+        self.sink.put(batch);
+    }
+}
@@ -1,17 +1,32 @@
 use std::{
     cmp::{max, min},
-    fmt,
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
     fs::{File, OpenOptions},
     io,
     io::{Read as _, Seek as _, SeekFrom, Write as _},
     path::PathBuf,
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationSeconds};
+
+use crate::{
+    anomaly::Anomaly,
+    api::{LichessVariant, NodeScaleBounds, Standing},
+    assets::EngineFlavor,
+    budget::Degradation,
+    latency::Latencies,
+    power::PowerChange,
+};
 
 const STATS_FILENAME: &str = ".fishnet-stats";
 
+/// Keep this many leaderboard standings, to show rank changes.
+const MAX_STANDINGS: usize = 30;
+
 fn stats_path() -> io::Result<PathBuf> {
     home::home_dir()
         .map(|dir| dir.join(STATS_FILENAME))
@@ -26,17 +41,95 @@ fn stats_path() -> io::Result<PathBuf> {
 pub struct StatsRecorder {
     pub stats: Stats,
     pub nnue_nps: NpsRecorder,
+    pub performance: Performance,
+    pub latencies: Latencies,
+    pub degradations: Vec<Degradation>,
+    pub anomalies: Vec<Anomaly>,
+    pub power_changes: Vec<PowerChange>,
+    pub throughput: Throughput,
     stats_file: Option<File>,
+    uptime_since: Instant,
 }
 
-#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde_as]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Stats {
     pub total_batches: u64,
     pub total_positions: u64,
     pub total_nodes: u64,
+    #[serde(default)]
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub total_uptime: Duration,
+    /// CPU time of the engine processes, where it can be measured.
+    #[serde(default)]
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub total_cpu_time: Duration,
+    #[serde(default)]
+    pub variants: BTreeMap<String, VariantStats>,
+    #[serde(default)]
+    pub standings: Vec<RankSample>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct VariantStats {
+    pub batches: u64,
+    pub positions: u64,
+    pub nodes: u64,
+}
+
+/// Leaderboard rank at a point in time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RankSample {
+    /// Unix timestamp.
+    pub at: u64,
+    pub rank: u32,
+    pub providers: u32,
+}
+
+/// Bytes used by the stats file.
+pub fn disk_usage() -> io::Result<u64> {
+    match fs::metadata(stats_path()?) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err),
+    }
+}
+
+/// Reads the lifetime statistics without recording.
+pub fn load() -> io::Result<Stats> {
+    match fs::read(stats_path()?) {
+        Ok(buf) if buf.is_empty() => Ok(Stats::default()),
+        Ok(buf) => serde_json::from_slice(&buf)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Stats::default()),
+        Err(err) => Err(err),
+    }
 }
 
 impl Stats {
+    /// Describes the rank change since the oldest remembered standing, like
+    /// "up 3 places in 5 days".
+    pub fn rank_change(&self) -> Option<String> {
+        let (oldest, latest) = match (self.standings.first(), self.standings.last()) {
+            (Some(oldest), Some(latest)) if self.standings.len() > 1 => (oldest, latest),
+            _ => return None,
+        };
+        let days = latest.at.saturating_sub(oldest.at) / (60 * 60 * 24);
+        let places = i64::from(oldest.rank) - i64::from(latest.rank);
+        Some(format!(
+            "{} in {} day{}",
+            match places {
+                0 => "unchanged".to_owned(),
+                1 => "up 1 place".to_owned(),
+                -1 => "down 1 place".to_owned(),
+                p if p > 0 => format!("up {} places", p),
+                p => format!("down {} places", -p),
+            },
+            days,
+            if days == 1 { "" } else { "s" }
+        ))
+    }
+
     fn load_from(file: &mut File) -> io::Result<Option<Stats>> {
         file.seek(SeekFrom::Start(0))?;
         let mut buf = Vec::new();
@@ -64,7 +157,7 @@ impl Stats {
 }
 
 impl StatsRecorder {
-    pub fn open(cores: usize) -> StatsRecorder {
+    pub fn open(cores: usize, calibrated_nps: Option<u32>) -> StatsRecorder {
         let (stats, stats_file) = match stats_path().and_then(|path| {
             OpenOptions::new()
                 .read(true)
@@ -101,19 +194,63 @@ impl StatsRecorder {
         StatsRecorder {
             stats,
             stats_file,
-            nnue_nps: NpsRecorder::new(cores),
+            nnue_nps: NpsRecorder::new(cores, calibrated_nps),
+            performance: Performance::default(),
+            latencies: Latencies::default(),
+            degradations: Vec::new(),
+            anomalies: Vec::new(),
+            power_changes: Vec::new(),
+            throughput: Throughput::new(),
+            uptime_since: Instant::now(),
         }
     }
 
-    pub fn record_batch(&mut self, positions: u64, nodes: u64, nnue_nps: Option<u32>) {
+    pub fn record_batch(
+        &mut self,
+        variant: LichessVariant,
+        positions: u64,
+        nodes: u64,
+        cpu_time: Option<Duration>,
+        nnue_nps: Option<u32>,
+    ) {
         self.stats.total_batches += 1;
         self.stats.total_positions += positions;
         self.stats.total_nodes += nodes;
+        self.stats.total_cpu_time += cpu_time.unwrap_or_default();
+
+        let variant = self.stats.variants.entry(variant.to_string()).or_default();
+        variant.batches += 1;
+        variant.positions += positions;
+        variant.nodes += nodes;
 
         if let Some(nnue_nps) = nnue_nps {
             self.nnue_nps.record(nnue_nps);
         }
 
+        self.save();
+    }
+
+    pub fn record_standing(&mut self, standing: &Standing) {
+        if self.stats.standings.len() >= MAX_STANDINGS {
+            self.stats.standings.remove(0);
+        }
+        self.stats.standings.push(RankSample {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            rank: standing.rank,
+            providers: standing.providers,
+        });
+        self.save();
+    }
+
+    /// Accounts uptime and writes the stats file.
+    pub fn save(&mut self) {
+        let now = Instant::now();
+        self.stats.total_uptime += now.duration_since(self.uptime_since);
+        self.uptime_since = now;
+
         if let Some(ref mut stats_file) = self.stats_file {
             if let Err(err) = self.stats.save_to(stats_file) {
                 eprintln!("E: Failed to write stats to ~/{}: {}", STATS_FILENAME, err);
@@ -137,6 +274,153 @@ impl StatsRecorder {
     }
 }
 
+/// Performance of this session, broken down by variant and engine flavor.
+#[derive(Debug, Default, Clone)]
+pub struct Performance {
+    entries: BTreeMap<(String, &'static str), PerformanceEntry>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct PerformanceEntry {
+    positions: u64,
+    nodes: u64,
+    depth: u64,
+    time: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PerformanceSummary {
+    pub variant: String,
+    pub flavor: &'static str,
+    pub positions: u64,
+    pub nps: u64,
+    pub avg_depth: f64,
+    /// Average time per position in milliseconds.
+    pub avg_time: u64,
+}
+
+impl Performance {
+    pub fn record_position(
+        &mut self,
+        variant: LichessVariant,
+        flavor: EngineFlavor,
+        depth: u8,
+        nodes: u64,
+        time: Duration,
+    ) {
+        let entry = self
+            .entries
+            .entry((variant.to_string(), flavor.name()))
+            .or_default();
+        entry.positions += 1;
+        entry.nodes += nodes;
+        entry.depth += u64::from(depth);
+        entry.time += time;
+    }
+
+    pub fn summaries(&self) -> Vec<PerformanceSummary> {
+        self.entries
+            .iter()
+            .map(|((variant, flavor), entry)| PerformanceSummary {
+                variant: variant.clone(),
+                flavor,
+                positions: entry.positions,
+                nps: (u128::from(entry.nodes) * 1000 / max(1, entry.time.as_millis())) as u64,
+                avg_depth: entry.depth as f64 / max(1, entry.positions) as f64,
+                avg_time: (entry.time.as_millis() / u128::from(max(1, entry.positions))) as u64,
+            })
+            .collect()
+    }
+}
+
+/// Batches of this session, for a cumulative summary while running and on
+/// shutdown.
+#[derive(Debug, Clone)]
+pub struct Throughput {
+    pub batches: u64,
+    pub failed_batches: u64,
+    pub positions: u64,
+    pub nodes: u64,
+    /// Search time of the engines, summed over all positions.
+    pub engine_time: Duration,
+    /// CPU time of the engine processes, where it can be measured.
+    pub cpu_time: Duration,
+    since: Instant,
+}
+
+impl Throughput {
+    fn new() -> Throughput {
+        Throughput {
+            batches: 0,
+            failed_batches: 0,
+            positions: 0,
+            nodes: 0,
+            engine_time: Duration::default(),
+            cpu_time: Duration::default(),
+            since: Instant::now(),
+        }
+    }
+
+    pub fn record_batch(
+        &mut self,
+        positions: u64,
+        nodes: u64,
+        engine_time: Duration,
+        cpu_time: Option<Duration>,
+    ) {
+        self.batches += 1;
+        self.positions += positions;
+        self.nodes += nodes;
+        self.engine_time += engine_time;
+        self.cpu_time += cpu_time.unwrap_or_default();
+    }
+
+    pub fn record_failed_batch(&mut self) {
+        self.failed_batches += 1;
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.since.elapsed()
+    }
+
+    /// Positions per second of uptime, including time spent waiting for
+    /// work.
+    pub fn positions_per_second(&self) -> f64 {
+        self.positions as f64 / self.uptime().as_secs_f64().max(1.0)
+    }
+
+    /// Nodes per second of each engine process while searching.
+    pub fn engine_nps(&self) -> Option<u32> {
+        (u128::from(self.nodes) * 1000)
+            .checked_div(self.engine_time.as_millis())
+            .and_then(|nps| nps.try_into().ok())
+    }
+}
+
+/// Renders the speed of each engine flavor and variant in the Prometheus
+/// text format.
+pub fn render(summaries: &[PerformanceSummary]) -> String {
+    let name = "fishnet_engine_nps";
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# HELP {} Average nodes per second in this session, by engine and variant.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for summary in summaries {
+        let _ = writeln!(
+            out,
+            "{}{{engine=\"{}\",variant=\"{}\"}} {}",
+            name, summary.flavor, summary.variant, summary.nps
+        );
+    }
+    out
+}
+
+/// Speed of a typical desktop core with NNUE, for scaling node targets.
+const REFERENCE_NPS_PER_CORE: u32 = 1_000_000;
+
 #[derive(Clone)]
 pub struct NpsRecorder {
     pub nps: u32,
@@ -144,10 +428,18 @@ pub struct NpsRecorder {
 }
 
 impl NpsRecorder {
-    fn new(cores: usize) -> NpsRecorder {
-        NpsRecorder {
-            nps: 400_000 * cores as u32, // start with a low estimate
-            uncertainty: 1.0,
+    fn new(cores: usize, calibrated_nps: Option<u32>) -> NpsRecorder {
+        match calibrated_nps {
+            // Measured with all cores busy, so almost as good as real
+            // batches.
+            Some(nps) => NpsRecorder {
+                nps: nps.saturating_mul(cores as u32),
+                uncertainty: 0.3,
+            },
+            None => NpsRecorder {
+                nps: 400_000 * cores as u32, // start with a low estimate
+                uncertainty: 1.0,
+            },
         }
     }
 
@@ -158,18 +450,27 @@ impl NpsRecorder {
     }
 }
 
-impl fmt::Display for NpsRecorder {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} knps", self.nps / 1000)?;
-        if self.uncertainty > 0.7 {
-            write!(f, "?")?;
-        }
-        if self.uncertainty > 0.4 {
-            write!(f, "?")?;
+impl NpsRecorder {
+    /// Factor for the node targets of the server, so that positions take
+    /// about as long as on a machine with the reference speed per core.
+    /// Not available until the speed is known well enough.
+    pub fn node_scale(&self, cores: usize, bounds: NodeScaleBounds) -> Option<f64> {
+        if self.uncertainty > 0.4 || !(0.0 < bounds.min && bounds.min <= bounds.max) {
+            return None;
         }
-        if self.uncertainty > 0.1 {
-            write!(f, "?")?;
+        let nps_per_core = f64::from(self.nps) / max(cores, 1) as f64;
+        Some((nps_per_core / f64::from(REFERENCE_NPS_PER_CORE)).clamp(bounds.min, bounds.max))
+    }
+
+    pub fn uncertainty_marks(&self) -> &'static str {
+        if self.uncertainty > 0.7 {
+            "???"
+        } else if self.uncertainty > 0.4 {
+            "??"
+        } else if self.uncertainty > 0.1 {
+            "?"
+        } else {
+            ""
         }
-        Ok(())
     }
 }
@@ -0,0 +1,812 @@
+use std::{
+    cmp::max,
+    collections::VecDeque,
+    convert::Infallible,
+    fmt::Write as _,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{
+    header::{CONTENT_TYPE, HOST, ORIGIN, REFRESH},
+    http::uri::Authority,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Serialize;
+use tokio::time;
+
+use crate::{
+    api::Work,
+    assets::EngineFlavor,
+    control::{ControlCommand, ControlStub},
+    describe::Description,
+    ipc::{Position, PositionResponse},
+    latency,
+    logger::Logger,
+    metrics::METRICS,
+    queue::{QueueSnapshot, QueueStub},
+    stats,
+    stockfish::Transcript,
+};
+
+/// What each worker is currently doing. Workers report changes, the status
+/// server reads it.
+#[derive(Clone)]
+pub struct WorkerBoard {
+    workers: Arc<Mutex<Vec<WorkerEntry>>>,
+}
+
+#[derive(Clone)]
+struct WorkerEntry {
+    activity: Activity,
+    since: Instant,
+    /// The last engine run completed without error or timeout.
+    engine_ok: bool,
+    transcript: Transcript,
+    /// Node limit of the current position, if known.
+    target_nodes: Option<u64>,
+    /// Search of the last completed position.
+    last_search: Option<SearchStats>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct SearchStats {
+    depth: u8,
+    nps: u64,
+}
+
+/// What a worker is doing, for the status view of `--tui`.
+#[derive(Debug, Clone)]
+pub struct WorkerPane {
+    pub activity: Activity,
+    pub since: Duration,
+    /// Depth reached in the last completed position.
+    pub depth: Option<u8>,
+    pub nps: Option<u64>,
+    /// Estimated time until the current position is complete, from the
+    /// node limit and the last speed.
+    pub eta: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum Activity {
+    Idle,
+    StartingEngine {
+        flavor: &'static str,
+    },
+    Working {
+        batch: String,
+        position: usize,
+        flavor: &'static str,
+    },
+    /// Not acquiring work, because the number of cores was reduced at
+    /// runtime.
+    Parked,
+}
+
+impl Activity {
+    pub fn starting_engine(flavor: EngineFlavor) -> Activity {
+        Activity::StartingEngine {
+            flavor: flavor.name(),
+        }
+    }
+
+    pub fn working(position: &Position) -> Activity {
+        Activity::Working {
+            batch: position.work.id().to_string(),
+            position: position.position_id.0,
+            flavor: position.flavor.name(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct WorkerSnapshot {
+    #[serde(flatten)]
+    activity: Activity,
+    /// Seconds since the last change.
+    since: u64,
+}
+
+impl WorkerBoard {
+    pub fn new(workers: usize) -> WorkerBoard {
+        WorkerBoard {
+            workers: Arc::new(Mutex::new(
+                (0..workers)
+                    .map(|_| WorkerEntry {
+                        activity: Activity::Idle,
+                        since: Instant::now(),
+                        engine_ok: true,
+                        transcript: Transcript::default(),
+                        target_nodes: None,
+                        last_search: None,
+                    })
+                    .collect(),
+            )),
+        }
+    }
+
+    pub fn set(&self, worker: usize, activity: Activity) {
+        let mut workers = self.workers.lock().expect("worker board");
+        if let Some(entry) = workers.get_mut(worker) {
+            entry.activity = activity;
+            entry.since = Instant::now();
+        }
+    }
+
+    /// Starts working on a position.
+    pub fn working(&self, worker: usize, position: &Position) {
+        let target_nodes = match position.work {
+            Work::Analysis { nodes, .. } => Some(nodes.get(position.flavor.eval_flavor())),
+            Work::Move { .. } | Work::Playout { .. } => None,
+        };
+        self.set(worker, Activity::working(position));
+        let mut workers = self.workers.lock().expect("worker board");
+        if let Some(entry) = workers.get_mut(worker) {
+            entry.target_nodes = target_nodes;
+        }
+    }
+
+    /// Records the search of a completed position.
+    pub fn searched(&self, worker: usize, res: &PositionResponse) {
+        let mut workers = self.workers.lock().expect("worker board");
+        if let Some(entry) = workers.get_mut(worker) {
+            let millis = res.time.as_millis() as u64;
+            entry.last_search = Some(SearchStats {
+                depth: res.depth,
+                nps: res
+                    .nps
+                    .map(u64::from)
+                    .unwrap_or_else(|| res.nodes * 1000 / max(millis, 1)),
+            });
+        }
+    }
+
+    pub fn set_engine_ok(&self, worker: usize, engine_ok: bool) {
+        let mut workers = self.workers.lock().expect("worker board");
+        if let Some(entry) = workers.get_mut(worker) {
+            entry.engine_ok = engine_ok;
+        }
+    }
+
+    /// Shared with the engine processes of the worker.
+    pub fn transcript(&self, worker: usize) -> Transcript {
+        let workers = self.workers.lock().expect("worker board");
+        workers[worker].transcript.clone()
+    }
+
+    fn transcripts(&self) -> Vec<Vec<String>> {
+        let workers = self.workers.lock().expect("worker board");
+        workers
+            .iter()
+            .map(|entry| entry.transcript.lines())
+            .collect()
+    }
+
+    /// At least one worker has an engine that is not failing.
+    fn engines_ok(&self) -> bool {
+        let workers = self.workers.lock().expect("worker board");
+        workers.iter().any(|entry| entry.engine_ok)
+    }
+
+    pub fn panes(&self) -> Vec<WorkerPane> {
+        let workers = self.workers.lock().expect("worker board");
+        workers
+            .iter()
+            .map(|entry| {
+                let since = entry.since.elapsed();
+                let nps = entry
+                    .last_search
+                    .map(|search| search.nps)
+                    .filter(|&nps| nps > 0);
+                WorkerPane {
+                    activity: entry.activity.clone(),
+                    since,
+                    depth: entry.last_search.map(|search| search.depth),
+                    nps,
+                    eta: match (&entry.activity, entry.target_nodes, nps) {
+                        (Activity::Working { .. }, Some(target), Some(nps)) => {
+                            Some(Duration::from_millis(target * 1000 / nps).saturating_sub(since))
+                        }
+                        _ => None,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    fn snapshot(&self) -> Vec<WorkerSnapshot> {
+        let workers = self.workers.lock().expect("worker board");
+        workers
+            .iter()
+            .map(|entry| WorkerSnapshot {
+                activity: entry.activity.clone(),
+                since: entry.since.elapsed().as_secs(),
+            })
+            .collect()
+    }
+}
+
+/// A queue that does not answer a health check in this time is wedged.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Required content type of POST /control. Unlike text/plain, browsers do
+/// not send it cross-origin without a preflight request, which the status
+/// server never allows.
+const CONTROL_CONTENT_TYPE: &str = "application/x-fishnet-control";
+
+/// Keep a day of per-minute throughput samples.
+const MAX_SAMPLES: usize = 24 * 60;
+
+#[derive(Debug, Copy, Clone, Serialize)]
+struct ThroughputSample {
+    /// Unix timestamp at the end of the minute.
+    at: u64,
+    positions: u64,
+    nodes: u64,
+}
+
+#[derive(Clone, Default)]
+struct Throughput {
+    samples: Arc<Mutex<VecDeque<ThroughputSample>>>,
+}
+
+impl Throughput {
+    /// Samples the metrics once per minute, until the process exits.
+    fn spawn_sampler(&self) {
+        let samples = self.samples.clone();
+        tokio::spawn(async move {
+            let mut positions = METRICS.positions_analyzed.get();
+            let mut nodes = METRICS.nodes_searched.get();
+            let mut interval = time::interval(Duration::from_secs(60));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let (new_positions, new_nodes) = (
+                    METRICS.positions_analyzed.get(),
+                    METRICS.nodes_searched.get(),
+                );
+                let mut samples = samples.lock().expect("throughput samples");
+                if samples.len() >= MAX_SAMPLES {
+                    samples.pop_front();
+                }
+                samples.push_back(ThroughputSample {
+                    at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    positions: new_positions - positions,
+                    nodes: new_nodes - nodes,
+                });
+                positions = new_positions;
+                nodes = new_nodes;
+            }
+        });
+    }
+
+    fn snapshot(&self) -> Vec<ThroughputSample> {
+        let samples = self.samples.lock().expect("throughput samples");
+        samples.iter().copied().collect()
+    }
+
+    /// Renders nodes per minute as an inline SVG polyline.
+    fn sparkline(&self) -> String {
+        let samples = self.snapshot();
+        let (width, height) = (MAX_SAMPLES / 4, 40);
+        let mut svg = format!(
+            "<svg width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\" style=\"background:#eee\">",
+            width, height, width, height
+        );
+        let max = samples.iter().map(|s| s.nodes).max().unwrap_or(0);
+        if max > 0 {
+            let mut points = String::new();
+            let offset = MAX_SAMPLES - samples.len();
+            for (i, sample) in samples.iter().enumerate() {
+                let _ = write!(
+                    points,
+                    "{:.1},{:.1} ",
+                    (offset + i) as f64 / 4.0,
+                    height as f64 - sample.nodes as f64 / max as f64 * (height - 2) as f64 - 1.0
+                );
+            }
+            let _ = write!(
+                svg,
+                "<polyline fill=\"none\" stroke=\"#333\" points=\"{}\"/>",
+                points.trim_end()
+            );
+        }
+        svg.push_str("</svg>");
+        svg
+    }
+}
+
+#[derive(Clone)]
+pub struct StatusServer {
+    started_at: Instant,
+    description: Arc<Description>,
+    queue: QueueStub,
+    workers: WorkerBoard,
+    throughput: Throughput,
+    control: ControlStub,
+    logger: Logger,
+}
+
+impl StatusServer {
+    pub fn new(
+        description: Description,
+        queue: QueueStub,
+        workers: WorkerBoard,
+        control: ControlStub,
+        logger: Logger,
+    ) -> StatusServer {
+        StatusServer {
+            started_at: Instant::now(),
+            description: Arc::new(description),
+            queue,
+            workers,
+            throughput: Throughput::default(),
+            control,
+            logger,
+        }
+    }
+
+    /// Handles a command from the control socket or POST /control.
+    pub async fn control(&self, command: ControlCommand) -> Result<String, String> {
+        match command {
+            ControlCommand::Status => Ok(self.json().await),
+            ControlCommand::Report => Ok(self.report().await),
+            ControlCommand::Stats => Ok(self.stats().await),
+            command => self.control.send(command).await,
+        }
+    }
+
+    async fn handle_control(&self, req: Request<Body>) -> Response<Body> {
+        if let Err((status, err)) = check_control_request(&req) {
+            return Response::builder()
+                .status(status)
+                .header(CONTENT_TYPE, "text/plain")
+                .body(Body::from(format!("{}\n", err)))
+                .expect("control response");
+        }
+        let body = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(body) => body,
+            Err(err) => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from(err.to_string()))
+                    .expect("control response")
+            }
+        };
+        let res = match String::from_utf8_lossy(&body).parse::<ControlCommand>() {
+            Ok(command) => self.control(command).await,
+            Err(err) => Err(err.to_string()),
+        };
+        match res {
+            Ok(text) => Response::builder()
+                .header(CONTENT_TYPE, "text/plain")
+                .body(Body::from(text + "\n"))
+                .expect("control response"),
+            Err(err) => Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header(CONTENT_TYPE, "text/plain")
+                .body(Body::from(err + "\n"))
+                .expect("control response"),
+        }
+    }
+
+    /// Serves the status until the process exits.
+    pub fn spawn(self, addr: SocketAddr) {
+        self.throughput.spawn_sampler();
+        self.serve(addr, false);
+    }
+
+    /// Serves only the metrics until the process exits.
+    pub fn spawn_metrics(self, addr: SocketAddr) {
+        self.serve(addr, true);
+    }
+
+    fn serve(self, addr: SocketAddr, metrics_only: bool) {
+        let logger = self.logger.clone();
+        let what = if metrics_only { "metrics" } else { "status" };
+        let server = match Server::try_bind(&addr) {
+            Ok(server) => server,
+            Err(err) => {
+                logger.error(&format!(
+                    "Failed to bind {} server to {}: {}",
+                    what, addr, err
+                ));
+                return;
+            }
+        };
+        if metrics_only {
+            logger.info(&format!("Metrics: http://{}/metrics", addr));
+        } else {
+            logger.info(&format!("Status: http://{}/", addr));
+        }
+        let make_service = make_service_fn(move |_conn| {
+            let status = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let status = status.clone();
+                    async move {
+                        Ok::<_, Infallible>(if metrics_only {
+                            status.handle_metrics(req).await
+                        } else {
+                            status.handle(req).await
+                        })
+                    }
+                }))
+            }
+        });
+        tokio::spawn(async move {
+            if let Err(err) = server.serve(make_service).await {
+                logger.error(&format!("Failed to serve {}: {}", what, err));
+            }
+        });
+    }
+
+    async fn handle_metrics(&self, req: Request<Body>) -> Response<Body> {
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/metrics") => self.metrics().await,
+            (&Method::GET, "/healthz") => self.healthz().await,
+            (&Method::GET, "/readyz") => self.readyz().await,
+            _ => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("not found"))
+                .expect("metrics response"),
+        }
+    }
+
+    async fn metrics(&self) -> Response<Body> {
+        let status_bar = self.queue.status_bar().await;
+        METRICS.queue_pending.set(status_bar.pending as i64);
+        METRICS.queue_cores.set(status_bar.cores as i64);
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(
+                METRICS.render()
+                    + &stats::render(&self.queue.performance().await)
+                    + &latency::render(&self.queue.latencies().await)
+                    + &latency::render_stages(&self.queue.stage_latencies().await),
+            ))
+            .expect("metrics response")
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if req.method() == Method::POST && req.uri().path() == "/control" {
+            return self.handle_control(req).await;
+        }
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/status.json") => Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(self.json().await))
+                .expect("status response"),
+            (&Method::GET, "/healthz") => self.healthz().await,
+            (&Method::GET, "/readyz") => self.readyz().await,
+            (&Method::GET, "/throughput.json") => Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_string(&self.throughput.snapshot())
+                        .expect("serialize throughput"),
+                ))
+                .expect("throughput response"),
+            (&Method::GET, "/metrics") => self.metrics().await,
+            (&Method::GET, "/") => Response::builder()
+                .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                .header(REFRESH, "5")
+                .body(Body::from(self.html().await))
+                .expect("status response"),
+            _ => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("not found"))
+                .expect("status response"),
+        }
+    }
+
+    /// Alive: the queue responds, and positions are being completed while
+    /// there is work. Otherwise fishnet is wedged, and should be restarted.
+    async fn healthz(&self) -> Response<Body> {
+        #[derive(Serialize)]
+        struct Liveness {
+            alive: bool,
+            responding: bool,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            stalled_secs: Option<u64>,
+        }
+
+        let health = time::timeout(HEALTH_TIMEOUT, self.queue.health())
+            .await
+            .ok();
+        let stalled_for = health.as_ref().and_then(|health| health.stalled_for);
+        let alive = health.is_some() && stalled_for.is_none();
+        Response::builder()
+            .status(if alive {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            })
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_string(&Liveness {
+                    alive,
+                    responding: health.is_some(),
+                    stalled_secs: stalled_for.map(|stalled_for| stalled_for.as_secs()),
+                })
+                .expect("serialize liveness"),
+            ))
+            .expect("liveness response")
+    }
+
+    /// Ready to accept work: the server is reachable, engines are working,
+    /// the queue is making progress and fishnet is not about to stop.
+    async fn readyz(&self) -> Response<Body> {
+        #[derive(Serialize)]
+        struct Readiness {
+            ready: bool,
+            connected: bool,
+            engines: bool,
+            draining: bool,
+            stalled: bool,
+        }
+
+        let health = self.queue.health().await;
+        let engines = self.workers.engines_ok();
+        let stalled = health.stalled_for.is_some();
+        let ready = health.connected && engines && !health.draining && !stalled;
+        Response::builder()
+            .status(if ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            })
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_string(&Readiness {
+                    ready,
+                    connected: health.connected,
+                    engines,
+                    draining: health.draining,
+                    stalled,
+                })
+                .expect("serialize readiness"),
+            ))
+            .expect("readiness response")
+    }
+
+    async fn json(&self) -> String {
+        #[derive(Serialize)]
+        struct Status<'a> {
+            uptime: u64,
+            /// Local clock minus server clock in seconds.
+            clock_skew: i64,
+            description: &'a Description,
+            workers: Vec<WorkerSnapshot>,
+            queue: QueueSnapshot,
+        }
+
+        serde_json::to_string_pretty(&Status {
+            uptime: self.started_at.elapsed().as_secs(),
+            clock_skew: METRICS.clock_skew.get(),
+            description: &self.description,
+            workers: self.workers.snapshot(),
+            queue: self.queue.snapshot().await,
+        })
+        .expect("serialize status")
+    }
+
+    /// Status, recent log lines and engine transcripts, for bug reports.
+    async fn report(&self) -> String {
+        #[derive(Serialize)]
+        struct Report {
+            status: serde_json::Value,
+            log: Vec<String>,
+            transcripts: Vec<Vec<String>>,
+        }
+
+        serde_json::to_string(&Report {
+            status: serde_json::from_str(&self.json().await).expect("status json"),
+            log: self.logger.recent(),
+            transcripts: self.workers.transcripts(),
+        })
+        .expect("serialize report")
+    }
+
+    /// Cumulative statistics of this session and all time.
+    async fn stats(&self) -> String {
+        #[derive(Serialize)]
+        struct Session {
+            uptime: u64,
+            batches: u64,
+            failed_batches: u64,
+            positions: u64,
+            nodes: u64,
+            positions_per_second: f64,
+            engine_nps: Option<u32>,
+        }
+
+        #[derive(Serialize)]
+        struct Stats {
+            session: Session,
+            total: stats::Stats,
+            nnue_nps: u32,
+        }
+
+        let throughput = self.queue.throughput().await;
+        let (total, nnue_nps) = self.queue.stats().await;
+        serde_json::to_string_pretty(&Stats {
+            session: Session {
+                uptime: throughput.uptime().as_secs(),
+                batches: throughput.batches,
+                failed_batches: throughput.failed_batches,
+                positions: throughput.positions,
+                nodes: throughput.nodes,
+                positions_per_second: throughput.positions_per_second(),
+                engine_nps: throughput.engine_nps(),
+            },
+            total,
+            nnue_nps: nnue_nps.nps,
+        })
+        .expect("serialize stats")
+    }
+
+    async fn leaderboard(&self) -> String {
+        let (stats, _) = self.queue.stats().await;
+        match stats.standings.last() {
+            Some(latest) => match stats.rank_change() {
+                Some(rank_change) => format!(
+                    "rank {} of {} ({})",
+                    latest.rank, latest.providers, rank_change
+                ),
+                None => format!("rank {} of {}", latest.rank, latest.providers),
+            },
+            None => "not available".to_owned(),
+        }
+    }
+
+    async fn html(&self) -> String {
+        format!(
+            concat!(
+                "<!DOCTYPE html>\n",
+                "<html><head><meta charset=\"utf-8\"><title>fishnet</title></head>\n",
+                "<body><h1>fishnet/{}</h1>\n",
+                "<p>Up for {}s. Raw data: <a href=\"/status.json\">status.json</a>, <a href=\"/throughput.json\">throughput.json</a>, <a href=\"/metrics\">metrics</a></p>\n",
+                "<p>Nodes per minute, last 24 hours:<br>{}</p>\n",
+                "<p>Leaderboard: {}</p>\n",
+                "<pre>{}</pre>\n",
+                "</body></html>\n"
+            ),
+            env!("CARGO_PKG_VERSION"),
+            self.started_at.elapsed().as_secs(),
+            self.throughput.sparkline(),
+            escape_html(&self.leaderboard().await),
+            escape_html(&self.json().await)
+        )
+    }
+}
+
+/// Only accepts commands from non-browser clients like curl: Requests from
+/// web pages carry an Origin header, and pages that rebind their domain to
+/// the status server do not use an IP address or localhost as host.
+fn check_control_request(req: &Request<Body>) -> Result<(), (StatusCode, &'static str)> {
+    if req.headers().contains_key(ORIGIN) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "cross-origin control requests are not allowed",
+        ));
+    }
+    let local_host = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+        .map_or(false, |authority| {
+            let host = authority.host();
+            host == "localhost"
+                || host
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse::<IpAddr>()
+                    .is_ok()
+        });
+    if !local_host {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "control requests must address the status server by ip or localhost",
+        ));
+    }
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .map(str::trim);
+    if content_type != Some(CONTROL_CONTENT_TYPE) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "expected content-type: application/x-fishnet-control",
+        ));
+    }
+    Ok(())
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control_request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = Request::post("/control");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(Body::from("drain")).expect("request")
+    }
+
+    #[test]
+    fn test_check_control_request() {
+        let ok = [
+            ("host", "127.0.0.1:9281"),
+            ("content-type", CONTROL_CONTENT_TYPE),
+        ];
+        assert!(check_control_request(&control_request(&ok)).is_ok());
+        assert!(check_control_request(&control_request(&[
+            ("host", "[::1]:9281"),
+            ("content-type", CONTROL_CONTENT_TYPE),
+        ]))
+        .is_ok());
+        assert!(check_control_request(&control_request(&[
+            ("host", "localhost:9281"),
+            (
+                "content-type",
+                "application/x-fishnet-control; charset=utf-8"
+            ),
+        ]))
+        .is_ok());
+
+        // Simple requests from a web page.
+        assert_eq!(
+            check_control_request(&control_request(&[
+                ("host", "127.0.0.1:9281"),
+                ("content-type", "text/plain"),
+            ]))
+            .unwrap_err()
+            .0,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            check_control_request(&control_request(&[
+                ("host", "127.0.0.1:9281"),
+                ("content-type", CONTROL_CONTENT_TYPE),
+                ("origin", "https://example.com"),
+            ]))
+            .unwrap_err()
+            .0,
+            StatusCode::FORBIDDEN
+        );
+
+        // DNS rebinding.
+        assert_eq!(
+            check_control_request(&control_request(&[
+                ("host", "example.com:9281"),
+                ("content-type", CONTROL_CONTENT_TYPE),
+            ]))
+            .unwrap_err()
+            .0,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            check_control_request(&control_request(&[("content-type", CONTROL_CONTENT_TYPE)]))
+                .unwrap_err()
+                .0,
+            StatusCode::FORBIDDEN
+        );
+    }
+}
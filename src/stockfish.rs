@@ -1,18 +1,40 @@
-use std::{io, num::NonZeroU8, path::PathBuf, process::Stdio, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs::{File, OpenOptions},
+    io,
+    io::{LineWriter, Write as _},
+    num::NonZeroU8,
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::{self, FromStr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use shakmaty::{fen::fen, variant::Variant};
+use chrono::Local;
+use shakmaty::{fen::fen, uci::Uci, variant::Variant};
 use tokio::{
-    io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader, BufWriter, Lines},
+    io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader, BufWriter},
     process::{ChildStdin, ChildStdout, Command},
     sync::{mpsc, oneshot},
+    time,
 };
 
 use crate::{
-    api::{Score, Work},
-    assets::EngineFlavor,
-    ipc::{Matrix, Position, PositionFailed, PositionResponse},
-    logger::Logger,
+    affinity,
+    api::{LichessVariant, PlayoutPly, Score, Wdl, Work},
+    assets::{EngineFlavor, EvalFlavor},
+    configure::Sandbox,
+    cputime::CpuClock,
+    ipc::{EarlyStop, FailureReason, Matrix, Position, PositionFailed, PositionResponse},
+    limits::EngineLimits,
+    logger::{Logger, ProgressAt},
+    memory::{HashSizer, DEFAULT_HASH_MIB},
+    metrics::METRICS,
+    numa, orphans, sandbox,
     util::NevermindExt as _,
+    validate::ENGINE_OPTIONS,
 };
 
 pub fn channel(
@@ -26,25 +48,349 @@ pub fn channel(
         StockfishActor {
             rx,
             exe,
+            transcript: init.transcript.clone(),
+            variant_nets: init.variant_nets.clone(),
+            external: init.external,
+            early_stop: init.early_stop,
+            warm_hash: init.warm_hash,
+            previous: None,
+            hash_mib: init.hash_mib,
+            hash_sizer: init.hash_sizer.clone(),
+            trace: init.trace.clone(),
+            tracer: None,
             init: Some(init),
+            options: HashMap::new(),
+            cpu_clock: None,
             logger,
         },
     )
 }
 
+/// Keep this many lines of engine communication, for bug reports.
+const MAX_TRANSCRIPT_LINES: usize = 200;
+
+/// The most recent lines exchanged with an engine process.
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    tracer: Option<Arc<Mutex<Tracer>>>,
+}
+
+impl Transcript {
+    /// The same transcript, additionally tracing every line.
+    fn traced(&self, tracer: Arc<Mutex<Tracer>>) -> Transcript {
+        Transcript {
+            lines: Arc::clone(&self.lines),
+            tracer: Some(tracer),
+        }
+    }
+
+    fn push(&self, prefix: &str, line: &str) {
+        if let Some(ref tracer) = self.tracer {
+            tracer.lock().expect("tracer").trace(prefix, line);
+        }
+        let mut lines = self.lines.lock().expect("transcript");
+        // Reuse the allocation of the oldest line, once full.
+        let mut entry = if lines.len() >= MAX_TRANSCRIPT_LINES {
+            lines.pop_front().unwrap_or_default()
+        } else {
+            String::new()
+        };
+        entry.clear();
+        entry.push_str(prefix);
+        entry.push(' ');
+        entry.push_str(line);
+        lines.push_back(entry);
+    }
+
+    /// Lines sent to the engine start with >, lines received with <, and
+    /// lines of the error output (only captured while tracing) with !.
+    pub fn lines(&self) -> Vec<String> {
+        let lines = self.lines.lock().expect("transcript");
+        lines.iter().cloned().collect()
+    }
+}
+
+/// Where to trace every line exchanged with engine processes.
+#[derive(Debug, Clone)]
+pub enum EngineTrace {
+    /// Log the lines.
+    Log,
+    /// Write the lines of each engine process to its own file in this
+    /// directory.
+    Dir(PathBuf),
+}
+
+enum TraceSink {
+    Log(Logger),
+    File(LineWriter<File>),
+}
+
+/// Timestamps the lines of one engine process, with the position it is
+/// working on.
+struct Tracer {
+    pid: u32,
+    context: Option<String>,
+    sink: TraceSink,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("pid", &self.pid)
+            .field("context", &self.context)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tracer {
+    fn open(trace: &EngineTrace, exe: &Path, pid: u32, logger: &Logger) -> io::Result<Tracer> {
+        Ok(Tracer {
+            pid,
+            context: None,
+            sink: match trace {
+                EngineTrace::Log => TraceSink::Log(logger.clone()),
+                EngineTrace::Dir(dir) => {
+                    let name = exe
+                        .file_stem()
+                        .map_or("engine".into(), |stem| stem.to_string_lossy());
+                    let path = dir.join(format!("{}-{}.log", name, pid));
+                    TraceSink::File(LineWriter::new(
+                        OpenOptions::new().create(true).append(true).open(path)?,
+                    ))
+                }
+            },
+        })
+    }
+
+    fn trace(&mut self, prefix: &str, line: &str) {
+        let traced = format!(
+            "{} {} [{}] {} {}",
+            Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            self.pid,
+            self.context.as_deref().unwrap_or("-"),
+            prefix,
+            line
+        );
+        match self.sink {
+            TraceSink::Log(ref logger) => logger.info(&traced),
+            TraceSink::File(ref mut file) => {
+                // Tracing is best effort. Failures would show up in every
+                // line, so they are ignored.
+                let _ = writeln!(file, "{}", traced);
+            }
+        }
+    }
+}
+
+/// Starts the engine, waits for it to complete the UCI handshake, and
+/// returns its name.
+pub async fn identify(exe: &Path, nnue: &str) -> io::Result<String> {
+    let mut child = Command::new(exe)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdin closed"))?;
+    let mut stdout = Stdout::new(
+        child
+            .stdout
+            .take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdout closed"))?,
+        Transcript::default(),
+    );
+    stdin
+        .write_all(format!("uci\nsetoption name EvalFile value {}\nisready\n", nnue).as_bytes())
+        .await?;
+    stdin.flush().await?;
+    let mut name = None;
+    loop {
+        let line = stdout.read_line().await?;
+        if let Some(id) = line.strip_prefix(b"id name ") {
+            name = Some(String::from_utf8_lossy(id).trim().to_owned());
+        } else if trim_end(line) == b"readyok" {
+            break;
+        }
+    }
+    stdin.write_all(b"quit\n").await?;
+    stdin.flush().await?;
+    child.wait().await?;
+    Ok(name.unwrap_or_else(|| "unknown engine".to_owned()))
+}
+
+/// Starts a third-party engine and checks that it offers the variant in its
+/// UCI_Variant option. Returns the name of the engine.
+pub async fn handshake(exe: &Path, variant: LichessVariant) -> io::Result<String> {
+    let mut child = Command::new(exe)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdin closed"))?;
+    let mut stdout = Stdout::new(
+        child
+            .stdout
+            .take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdout closed"))?,
+        Transcript::default(),
+    );
+    stdin.write_all(b"uci\n").await?;
+    stdin.flush().await?;
+
+    let uci_variant = Variant::from(variant).uci();
+    let mut name = None;
+    let mut supported = false;
+    let handshake = async {
+        loop {
+            let line = stdout.read_line().await?;
+            if let Some(id) = line.strip_prefix(b"id name ") {
+                name = Some(String::from_utf8_lossy(id).trim().to_owned());
+            } else if line.starts_with(b"option name UCI_Variant ") {
+                // option name UCI_Variant type combo default chess var chess var atomic ...
+                let mut tokens = Tokens::new(line);
+                while let Some(token) = tokens.next() {
+                    if token == b"var" && tokens.next() == Some(uci_variant.as_bytes()) {
+                        supported = true;
+                    }
+                }
+            } else if trim_end(line) == b"uciok" {
+                return Ok::<_, io::Error>(());
+            }
+        }
+    };
+    time::timeout(Duration::from_secs(10), handshake)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no uciok within 10s"))??;
+
+    stdin.write_all(b"quit\n").await?;
+    stdin.flush().await?;
+    child.wait().await?;
+
+    if !supported {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("UCI_Variant does not offer {}", uci_variant),
+        ));
+    }
+    Ok(name.unwrap_or_else(|| "unknown engine".to_owned()))
+}
+
+/// Asks an engine for the legal moves of positions with `go perft 1`,
+/// outside of the actor, to cross-check move generation.
+pub struct PerftSession {
+    child: tokio::process::Child,
+    stdin: ChildStdin,
+    stdout: Stdout,
+}
+
+impl PerftSession {
+    pub async fn start(exe: &Path) -> io::Result<PerftSession> {
+        let mut child = Command::new(exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdin closed"))?;
+        let stdout = Stdout::new(
+            child
+                .stdout
+                .take()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdout closed"))?,
+            Transcript::default(),
+        );
+        let mut session = PerftSession {
+            child,
+            stdin,
+            stdout,
+        };
+        session
+            .send("uci\nsetoption name UCI_Chess960 value true\nisready\n")
+            .await?;
+        loop {
+            if trim_end(session.stdout.read_line().await?) == b"readyok" {
+                break;
+            }
+        }
+        Ok(session)
+    }
+
+    async fn send(&mut self, commands: &str) -> io::Result<()> {
+        self.stdin.write_all(commands.as_bytes()).await?;
+        self.stdin.flush().await
+    }
+
+    /// Legal moves after playing `moves` from `fen`, in UCI notation with
+    /// Chess960 castling, as reported by the engine.
+    pub async fn legal_moves(
+        &mut self,
+        variant: LichessVariant,
+        fen: &str,
+        moves: &[Uci],
+    ) -> io::Result<Vec<String>> {
+        let mut commands = format!(
+            "setoption name UCI_Variant value {}\nposition fen {}",
+            Variant::from(variant).uci(),
+            fen
+        );
+        if !moves.is_empty() {
+            commands.push_str(" moves");
+            for m in moves {
+                commands.push(' ');
+                commands.push_str(&m.to_string());
+            }
+        }
+        commands.push_str("\ngo perft 1\n");
+        self.send(&commands).await?;
+
+        // a2a3: 1 for each move, followed by Nodes searched: 20.
+        let mut legal = Vec::new();
+        loop {
+            let line = self.stdout.read_line().await?;
+            if line.starts_with(b"Nodes searched") {
+                return Ok(legal);
+            }
+            let line = String::from_utf8_lossy(trim_end(line));
+            if let Some((m, count)) = line.split_once(": ") {
+                if count.trim().parse::<u64>().is_ok() {
+                    legal.push(m.to_owned());
+                }
+            }
+        }
+    }
+
+    pub async fn quit(mut self) -> io::Result<()> {
+        self.send("quit\n").await?;
+        self.child.wait().await?;
+        Ok(())
+    }
+}
+
 pub struct StockfishStub {
     tx: mpsc::Sender<StockfishMessage>,
 }
 
 impl StockfishStub {
+    /// Fails if the engine process exits before responding.
     pub async fn go(&mut self, position: Position) -> Result<PositionResponse, PositionFailed> {
         let (callback, response) = oneshot::channel();
-        let batch_id = position.work.id();
+        let failed = PositionFailed::new(&position, FailureReason::EngineExited);
         self.tx
             .send(StockfishMessage::Go { position, callback })
             .await
-            .map_err(|_| PositionFailed { batch_id })?;
-        response.await.map_err(|_| PositionFailed { batch_id })
+            .map_err(|_| failed.clone())?;
+        response.await.map_err(|_| failed)
     }
 }
 
@@ -52,6 +398,20 @@ pub struct StockfishActor {
     rx: mpsc::Receiver<StockfishMessage>,
     exe: PathBuf,
     init: Option<StockfishInit>,
+    transcript: Transcript,
+    variant_nets: Vec<(LichessVariant, String)>,
+    external: bool,
+    early_stop: EarlyStop,
+    warm_hash: bool,
+    /// Last position searched, whose tree is still in the hash table.
+    previous: Option<Position>,
+    hash_mib: Option<u64>,
+    hash_sizer: Arc<HashSizer>,
+    trace: Option<EngineTrace>,
+    tracer: Option<Arc<Mutex<Tracer>>>,
+    /// Option values last sent to the engine process.
+    options: HashMap<&'static str, String>,
+    cpu_clock: Option<CpuClock>,
     logger: Logger,
 }
 
@@ -66,25 +426,146 @@ enum StockfishMessage {
 #[derive(Debug)]
 pub struct StockfishInit {
     pub nnue: String,
+    /// Only for official Stockfish. Fairy-Stockfish would also probe the
+    /// tables in variants.
+    pub syzygy_path: Option<String>,
+    /// Only for Fairy-Stockfish. NNUE networks by variant.
+    pub variant_nets: Vec<(LichessVariant, String)>,
+    /// Third-party engine, configured with standard UCI options only.
+    pub external: bool,
+    /// Conditions to stop analysis before the node limit.
+    pub early_stop: EarlyStop,
+    /// Keep the hash table when analysing an adjacent ply of the same
+    /// game, instead of clearing it for every position.
+    pub warm_hash: bool,
+    /// Hash table size in MiB, if not the engine default.
+    pub hash_mib: Option<u64>,
+    /// Shrinks the hash table when memory is low.
+    pub hash_sizer: Arc<HashSizer>,
+    pub threads: usize,
+    /// Logical cores to pin the engine process to, if any.
+    pub cores: Option<Vec<usize>>,
+    /// NUMA node to allocate the memory of the engine process on, if any.
+    pub numa_node: Option<numa::Node>,
+    /// Restrictions for the engine process.
+    pub sandbox: Sandbox,
+    /// Memory and CPU limits for the engine process.
+    pub limits: EngineLimits,
+    pub transcript: Transcript,
+    /// Trace every line exchanged with the engine process, if requested.
+    pub trace: Option<EngineTrace>,
 }
 
+/// Engines can print many info lines between two reads. A larger buffer
+/// than the default takes them with fewer read syscalls.
+const STDOUT_BUFFER_SIZE: usize = 64 * 1024;
+
 struct Stdout {
-    inner: Lines<BufReader<ChildStdout>>,
+    inner: BufReader<ChildStdout>,
+    buf: Vec<u8>,
+    /// The line in the buffer was returned, so that the buffer can be
+    /// cleared for the next one. Otherwise it holds the start of a line
+    /// from a read that was cancelled.
+    consumed: bool,
+    transcript: Transcript,
 }
 
 impl Stdout {
-    fn new(inner: ChildStdout) -> Stdout {
+    fn new(inner: ChildStdout, transcript: Transcript) -> Stdout {
         Stdout {
-            inner: BufReader::new(inner).lines(),
+            inner: BufReader::with_capacity(STDOUT_BUFFER_SIZE, inner),
+            buf: Vec::new(),
+            consumed: true,
+            transcript,
         }
     }
 
-    async fn read_line(&mut self) -> io::Result<String> {
-        if let Some(line) = self.inner.next_line().await? {
-            Ok(line)
-        } else {
-            Err(io::ErrorKind::UnexpectedEof.into())
+    /// Reads the next line, without the line terminator. The buffer is
+    /// reused for the following line, so that engines printing millions of
+    /// info lines do not cause an allocation for each of them.
+    ///
+    /// Cancel safe: a partially read line is completed by the next call.
+    async fn read_line(&mut self) -> io::Result<&[u8]> {
+        if self.consumed {
+            self.buf.clear();
+            self.consumed = false;
+        }
+        if self.inner.read_until(b'\n', &mut self.buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
         }
+        self.consumed = true;
+        let mut line = &self.buf[..];
+        line = line.strip_suffix(b"\n").unwrap_or(line);
+        line = line.strip_suffix(b"\r").unwrap_or(line);
+        self.transcript.push("<", &String::from_utf8_lossy(line));
+        Ok(line)
+    }
+}
+
+/// Splits engine output at ASCII whitespace, borrowing from the line.
+struct Tokens<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Tokens<'a> {
+    fn new(line: &'a [u8]) -> Tokens<'a> {
+        Tokens { rest: line }
+    }
+
+    fn parse<T: FromStr>(&mut self) -> Option<T> {
+        str::from_utf8(self.next()?).ok()?.parse().ok()
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let start = self.rest.iter().position(|b| !b.is_ascii_whitespace())?;
+        let rest = &self.rest[start..];
+        let end = rest
+            .iter()
+            .position(u8::is_ascii_whitespace)
+            .unwrap_or(rest.len());
+        self.rest = &rest[end..];
+        Some(&rest[..end])
+    }
+}
+
+fn trim_end(line: &[u8]) -> &[u8] {
+    let end = line
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |i| i + 1);
+    &line[..end]
+}
+
+struct Stdin {
+    inner: BufWriter<ChildStdin>,
+    transcript: Transcript,
+    partial: String,
+}
+
+impl Stdin {
+    fn new(inner: ChildStdin, transcript: Transcript) -> Stdin {
+        Stdin {
+            inner: BufWriter::new(inner),
+            transcript,
+            partial: String::new(),
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.partial.push_str(&String::from_utf8_lossy(buf));
+        while let Some(end) = self.partial.find('\n') {
+            self.transcript.push(">", &self.partial[..end]);
+            self.partial.drain(..=end);
+        }
+        self.inner.write_all(buf).await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().await
     }
 }
 
@@ -102,16 +583,33 @@ impl From<io::Error> for EngineError {
 
 #[cfg(unix)]
 fn new_process_group(command: &mut Command) -> &mut Command {
-    // Stop SIGINT from propagating to child process.
+    #[cfg(target_os = "linux")]
+    let parent = unsafe {
+        // Safety: No preconditions.
+        libc::getpid()
+    };
     unsafe {
         // Safety: The closure is run in a fork, and is not allowed to break
         // invariants by using raw handles.
-        command.pre_exec(|| {
+        command.pre_exec(move || {
+            // Stop SIGINT from propagating to child process.
             if libc::setpgid(0, 0) == -1 {
-                Err(io::Error::last_os_error())
-            } else {
-                Ok(())
+                return Err(io::Error::last_os_error());
+            }
+            // Kill the engine when fishnet dies, even if it had no chance
+            // to clean up. Sent when the spawning thread exits, which is
+            // the main thread with the current thread runtime. Check that
+            // fishnet did not die before the signal was requested.
+            #[cfg(target_os = "linux")]
+            {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::getppid() != parent {
+                    return Err(io::Error::from_raw_os_error(libc::ESRCH));
+                }
             }
+            Ok(())
         })
     }
 }
@@ -133,26 +631,66 @@ impl StockfishActor {
     }
 
     async fn run_inner(mut self) -> Result<(), EngineError> {
-        let mut child = new_process_group(
-            Command::new(&self.exe)
-                .stdout(Stdio::piped())
-                .stdin(Stdio::piped())
-                .kill_on_drop(true),
-        )
-        .spawn()?;
+        let mut command = Command::new(&self.exe);
+        command
+            .stdout(Stdio::piped())
+            .stdin(Stdio::piped())
+            .kill_on_drop(true);
+        if self.trace.is_some() {
+            command.stderr(Stdio::piped());
+        }
+        if let Some(ref init) = self.init {
+            if let Some(ref cores) = init.cores {
+                affinity::pin(&mut command, cores);
+            }
+            if let Some(ref node) = init.numa_node {
+                numa::bind(&mut command, node);
+            }
+            sandbox::apply(&mut command, init.sandbox);
+            init.limits.apply(&mut command);
+        }
+        let mut child = new_process_group(&mut command).spawn()?;
+        if let Err(err) = orphans::contain(&child) {
+            self.logger
+                .warn(&format!("Failed to tie engine process to fishnet: {}", err));
+        }
 
         let pid = child.id().expect("pid");
+        self.cpu_clock = CpuClock::new(&child);
+        if let Some(ref trace) = self.trace {
+            match Tracer::open(trace, &self.exe, pid, &self.logger) {
+                Ok(tracer) => {
+                    let tracer = Arc::new(Mutex::new(tracer));
+                    self.transcript = self.transcript.traced(Arc::clone(&tracer));
+                    self.tracer = Some(tracer);
+                }
+                Err(err) => self
+                    .logger
+                    .error(&format!("Failed to trace engine process {}: {}", pid, err)),
+            }
+        }
+        if let Some(stderr) = child.stderr.take() {
+            let transcript = self.transcript.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    transcript.push("!", &line);
+                }
+            });
+        }
         let mut stdout = Stdout::new(
             child
                 .stdout
                 .take()
                 .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdout closed"))?,
+            self.transcript.clone(),
         );
-        let mut stdin = BufWriter::new(
+        let mut stdin = Stdin::new(
             child
                 .stdin
                 .take()
                 .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdin closed"))?,
+            self.transcript.clone(),
         );
 
         loop {
@@ -184,7 +722,7 @@ impl StockfishActor {
     async fn handle_message(
         &mut self,
         stdout: &mut Stdout,
-        stdin: &mut BufWriter<ChildStdin>,
+        stdin: &mut Stdin,
         msg: StockfishMessage,
     ) -> Result<(), EngineError> {
         match msg {
@@ -192,42 +730,66 @@ impl StockfishActor {
                 mut callback,
                 position,
             } => {
-                tokio::select! {
+                self.trace_context(Some(ProgressAt::from(&position).to_string()));
+                let res = tokio::select! {
                     _ = callback.closed() => Err(EngineError::Shutdown),
-                    res = self.go(stdout, stdin, position) => {
+                    res = self.search(stdout, stdin, position) => {
                         callback.send(res?).nevermind("go receiver dropped");
                         Ok(())
                     }
-                }
+                };
+                self.trace_context(None);
+                res
             }
         }
     }
 
-    async fn init(
-        &mut self,
-        stdout: &mut Stdout,
-        stdin: &mut BufWriter<ChildStdin>,
-    ) -> io::Result<()> {
+    fn trace_context(&self, context: Option<String>) {
+        if let Some(ref tracer) = self.tracer {
+            tracer.lock().expect("tracer").context = context;
+        }
+    }
+
+    async fn init(&mut self, stdout: &mut Stdout, stdin: &mut Stdin) -> io::Result<()> {
         if let Some(init) = self.init.take() {
-            stdin
-                .write_all(format!("setoption name EvalFile value {}\n", init.nnue).as_bytes())
-                .await?;
+            if !init.external {
+                stdin
+                    .write_all(format!("setoption name EvalFile value {}\n", init.nnue).as_bytes())
+                    .await?;
+            }
             stdin
                 .write_all(b"setoption name UCI_Chess960 value true\n")
                 .await?;
+            if init.threads > 1 {
+                stdin
+                    .write_all(
+                        format!("setoption name Threads value {}\n", init.threads).as_bytes(),
+                    )
+                    .await?;
+            }
+            if let Some(syzygy_path) = init.syzygy_path {
+                stdin
+                    .write_all(
+                        format!("setoption name SyzygyPath value {}\n", syzygy_path).as_bytes(),
+                    )
+                    .await?;
+            }
             stdin.write_all(b"isready\n").await?;
             stdin.flush().await?;
 
             loop {
                 let line = stdout.read_line().await?;
-                if line.trim_end() == "readyok" {
+                if trim_end(line) == b"readyok" {
                     self.logger.debug("Engine is ready");
                     break;
-                } else if !line.starts_with("Stockfish ") && !line.starts_with("Fairy-Stockfish ") {
+                } else if !init.external
+                    && !line.starts_with(b"Stockfish ")
+                    && !line.starts_with(b"Fairy-Stockfish ")
+                {
                     // ignore preamble
                     self.logger.warn(&format!(
                         "Unexpected engine initialization output: {}",
-                        line.trim_end()
+                        String::from_utf8_lossy(trim_end(line))
                     ));
                 }
             }
@@ -235,41 +797,161 @@ impl StockfishActor {
         Ok(())
     }
 
+    /// Sends the option, unless the engine process already has the value.
+    /// Options survive ucinewgame, so they only need to be sent when they
+    /// change.
+    async fn set_option(
+        &mut self,
+        stdin: &mut Stdin,
+        name: &'static str,
+        value: String,
+    ) -> io::Result<()> {
+        if self.options.get(name) == Some(&value) {
+            return Ok(());
+        }
+        stdin
+            .write_all(format!("setoption name {} value {}\n", name, value).as_bytes())
+            .await?;
+        self.options.insert(name, value);
+        Ok(())
+    }
+
+    /// Plays out the position, if requested, or searches it once.
+    async fn search(
+        &mut self,
+        stdout: &mut Stdout,
+        stdin: &mut Stdin,
+        position: Position,
+    ) -> io::Result<PositionResponse> {
+        let cpu_before = self.cpu_clock.and_then(|clock| clock.elapsed());
+        let mut res = match position.work {
+            Work::Playout { .. } => self.playout(stdout, stdin, position).await?,
+            Work::Analysis { .. } | Work::Move { .. } => self.go(stdout, stdin, position).await?,
+        };
+        let cpu_after = self.cpu_clock.and_then(|clock| clock.elapsed());
+        res.cpu_time = cpu_before
+            .zip(cpu_after)
+            .map(|(before, after)| after.saturating_sub(before));
+        Ok(res)
+    }
+
+    /// Plays the best move of each search, until the requested number of
+    /// plies or the end of the game. Responds like the search of the
+    /// position itself, with the line played out.
+    async fn playout(
+        &mut self,
+        stdout: &mut Stdout,
+        stdin: &mut Stdin,
+        position: Position,
+    ) -> io::Result<PositionResponse> {
+        let started_at = Instant::now();
+        let plies = usize::from(position.work.playout_plies());
+        let mut current = position.clone();
+        let mut first: Option<PositionResponse> = None;
+        let mut playout = Vec::with_capacity(plies);
+        let mut nodes = 0;
+        let mut time = Duration::default();
+        while playout.len() < plies {
+            let res = self.go(stdout, stdin, current.clone()).await?;
+            nodes += res.nodes;
+            time += res.time;
+            // No best move at the end of the game.
+            let ply = res
+                .best_move
+                .clone()
+                .zip(res.scores.best().copied())
+                .map(|(uci, score)| PlayoutPly {
+                    uci,
+                    score,
+                    depth: res.depth,
+                    nodes: res.nodes,
+                });
+            first.get_or_insert(res);
+            match ply {
+                Some(ply) => {
+                    current.moves.push(ply.uci.clone());
+                    playout.push(ply);
+                }
+                None => break,
+            }
+        }
+        let mut res = first.expect("searched at least one ply");
+        res.nodes = nodes;
+        res.time = time;
+        res.nps = (u128::from(nodes) * 1000)
+            .checked_div(time.as_millis())
+            .and_then(|nps| nps.try_into().ok());
+        res.latency = started_at.elapsed();
+        res.playout = playout;
+        Ok(res)
+    }
+
     async fn go(
         &mut self,
         stdout: &mut Stdout,
-        stdin: &mut BufWriter<ChildStdin>,
+        stdin: &mut Stdin,
         position: Position,
     ) -> io::Result<PositionResponse> {
         // Set global options (once).
         self.init(stdout, stdin).await?;
+        let started_at = Instant::now();
 
-        // Clear hash.
-        stdin.write_all(b"ucinewgame\n").await?;
+        // Resize the hash table, before it is cleared anyway.
+        let hash_before = self.options.get("Hash").cloned();
+        match self.hash_sizer.hash_mib(self.hash_mib) {
+            Some(hash_mib) => self.set_option(stdin, "Hash", hash_mib.to_string()).await?,
+            None if self.options.contains_key("Hash") => {
+                self.set_option(stdin, "Hash", DEFAULT_HASH_MIB.to_string())
+                    .await?
+            }
+            None => (),
+        }
 
-        // Set basic options.
-        stdin
-            .write_all(
-                format!(
-                    "setoption name Use NNUE value {}\n",
-                    position.flavor.eval_flavor().is_nnue()
-                )
-                .as_bytes(),
-            )
-            .await?;
+        // Clear hash, unless continuing the analysis of the previous ply.
+        // Most of the tree is the same, so the search gets deeper with the
+        // same number of nodes. Resizing clears the hash table anyway.
+        let warm = self.warm_hash
+            && matches!(position.work, Work::Analysis { .. })
+            && self.options.get("Hash") == hash_before.as_ref()
+            && self
+                .previous
+                .as_ref()
+                .map_or(false, |previous| previous.is_adjacent_ply(&position));
+        if !warm {
+            stdin.write_all(b"ucinewgame\n").await?;
+        }
+        self.previous = Some(position.clone());
+
+        // Set basic options. Variants with their own network are evaluated
+        // like standard chess, and so are third-party engines.
+        let variant_net = self
+            .variant_nets
+            .iter()
+            .find(|(variant, _)| *variant == position.variant)
+            .map(|(_, net)| net.clone());
+        let eval_flavor = if variant_net.is_some() || self.external {
+            EvalFlavor::Nnue
+        } else {
+            position.flavor.eval_flavor()
+        };
+        if !self.external {
+            self.set_option(stdin, "Use NNUE", eval_flavor.is_nnue().to_string())
+                .await?;
+        }
         let variant = Variant::from(position.variant);
-        if position.flavor == EngineFlavor::MultiVariant {
-            stdin
-                .write_all(
-                    format!("setoption name UCI_Variant value {}\n", variant.uci()).as_bytes(),
-                )
+        if position.flavor == EngineFlavor::MultiVariant || self.external {
+            self.set_option(stdin, "UCI_Variant", variant.uci().to_owned())
                 .await?;
+            if let Some(net) = variant_net {
+                self.set_option(stdin, "EvalFile", net).await?;
+            }
         }
-        stdin
-            .write_all(
-                format!("setoption name MultiPV value {}\n", position.work.multipv()).as_bytes(),
-            )
+        self.set_option(stdin, "MultiPV", position.work.multipv().to_string())
             .await?;
+        let wdl_wanted = position.work.wdl_wanted();
+        self.set_option(stdin, "UCI_ShowWDL", wdl_wanted.to_string())
+            .await?;
+        let ply = position.ply();
 
         // Setup position.
         let moves = position
@@ -287,14 +969,9 @@ impl StockfishActor {
         // Go.
         let go = match &position.work {
             Work::Move { level, clock, .. } => {
-                stdin
-                    .write_all(b"setoption name UCI_AnalyseMode value false\n")
+                self.set_option(stdin, "UCI_AnalyseMode", "false".to_owned())
                     .await?;
-                stdin
-                    .write_all(
-                        format!("setoption name Skill Level value {}\n", level.skill_level())
-                            .as_bytes(),
-                    )
+                self.set_option(stdin, "Skill Level", level.skill_level().to_string())
                     .await?;
 
                 let mut go = vec![
@@ -320,27 +997,67 @@ impl StockfishActor {
 
                 go
             }
-            Work::Analysis { nodes, depth, .. } => {
-                stdin
-                    .write_all(b"setoption name UCI_AnalyseMode value true\n")
+            Work::Playout { nodes, .. } => {
+                self.set_option(stdin, "UCI_AnalyseMode", "true".to_owned())
                     .await?;
-                stdin
-                    .write_all(b"setoption name Skill Level value 20\n")
+                self.set_option(stdin, "Skill Level", "20".to_owned())
+                    .await?;
+
+                vec![
+                    "go".to_owned(),
+                    "nodes".to_owned(),
+                    nodes.get(eval_flavor).to_string(),
+                ]
+            }
+            Work::Analysis {
+                nodes,
+                depth,
+                movetime,
+                ..
+            } => {
+                self.set_option(stdin, "UCI_AnalyseMode", "true".to_owned())
+                    .await?;
+                self.set_option(stdin, "Skill Level", "20".to_owned())
                     .await?;
 
                 let mut go = vec![
                     "go".to_owned(),
                     "nodes".to_owned(),
-                    nodes.get(position.flavor.eval_flavor()).to_string(),
+                    nodes.get(eval_flavor).to_string(),
                 ];
 
                 if let Some(depth) = depth {
                     go.extend_from_slice(&["depth".to_owned(), depth.to_string()]);
                 }
 
+                if let Some(movetime) = movetime {
+                    go.extend_from_slice(&[
+                        "movetime".to_owned(),
+                        movetime.as_millis().to_string(),
+                    ]);
+                }
+
                 go
             }
         };
+
+        // Overrides of the server for this batch, validated when it was
+        // acquired. Options overridden for an earlier batch are restored.
+        for option in ENGINE_OPTIONS {
+            match position.work.engine_options().get(option.name) {
+                Some(value) => self.set_option(stdin, option.name, value.clone()).await?,
+                None => {
+                    if let Some(default) = option
+                        .default
+                        .filter(|_| self.options.contains_key(option.name))
+                    {
+                        self.set_option(stdin, option.name, default.to_owned())
+                            .await?;
+                    }
+                }
+            }
+        }
+
         stdin.write_all(go.join(" ").as_bytes()).await?;
         stdin.write_all(b"\n").await?;
         stdin.flush().await?;
@@ -348,99 +1065,163 @@ impl StockfishActor {
         // Process response.
         let mut scores = Matrix::new();
         let mut pvs = Matrix::new();
+        let matrix_wanted = position.work.matrix_wanted();
         let mut depth = 0;
         let mut multipv = NonZeroU8::new(1).unwrap();
         let mut time = Duration::default();
         let mut nodes = 0;
         let mut nps = None;
+        let mut hashfull = None;
+        let mut wdl = None;
+
+        // Exact scores of the best line by depth, and if stop was already
+        // sent, for early stopping.
+        let early_stop = Some(self.early_stop)
+            .filter(|early_stop| early_stop.is_enabled() && position.work.is_analysis());
+        let mut best_scores = Vec::new();
+        let mut stopping = false;
+
+        // Stop analysis that is still searching when the position times out,
+        // and submit what was found so far. If the engine does not respond
+        // to stop either, the worker kills it once its own budget is used
+        // up.
+        let deadline = position
+            .work
+            .is_analysis()
+            .then(|| started_at + position.work.timeout());
 
         loop {
-            let line = stdout.read_line().await?;
-            let mut parts = line.split(' ');
-            match parts.next() {
-                Some("bestmove") => {
+            let line = match deadline.filter(|_| !stopping) {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    match time::timeout(remaining, stdout.read_line()).await {
+                        Ok(line) => line?,
+                        Err(_) => {
+                            self.logger.warn(&format!(
+                                "Engine still searching {} after {:?}. Stopping with partial results.",
+                                ProgressAt::from(&position),
+                                position.work.timeout()
+                            ));
+                            stdin.write_all(b"stop\n").await?;
+                            stdin.flush().await?;
+                            stopping = true;
+                            continue;
+                        }
+                    }
+                }
+                None => stdout.read_line().await?,
+            };
+            let mut tokens = Tokens::new(line);
+            match tokens.next() {
+                Some(b"bestmove") => {
                     if scores.best().is_none() {
                         return Err(io::Error::new(io::ErrorKind::InvalidData, "missing score"));
                     }
 
+                    // Not all engines and evaluations report statistics.
+                    let wdl = if wdl_wanted {
+                        wdl.or_else(|| scores.best().map(|&score| Wdl::from_score(score, ply)))
+                    } else {
+                        None
+                    };
+
+                    if let Work::Analysis { .. } = position.work {
+                        METRICS.record_hash(warm, depth);
+                    }
+
                     return Ok(PositionResponse {
                         work: position.work,
                         position_id: position.position_id,
                         url: position.url,
-                        best_move: parts.next().and_then(|m| m.parse().ok()),
+                        best_move: tokens.next().and_then(|m| Uci::from_ascii(m).ok()),
                         scores,
                         depth,
                         pvs,
                         time,
                         nodes,
                         nps,
+                        wdl,
+                        hashfull,
+                        latency: started_at.elapsed(),
+                        cached: false,
+                        playout: Vec::new(),
+                        cpu_time: None,
                     });
                 }
-                Some("info") => {
-                    while let Some(part) = parts.next() {
-                        match part {
-                            "multipv" => {
-                                multipv =
-                                    parts.next().and_then(|t| t.parse().ok()).ok_or_else(|| {
-                                        io::Error::new(
-                                            io::ErrorKind::InvalidData,
-                                            "expected multipv",
-                                        )
-                                    })?;
+                Some(b"info") => {
+                    let mut line_score = None;
+                    let mut bound = false;
+                    while let Some(token) = tokens.next() {
+                        match token {
+                            b"multipv" => {
+                                multipv = tokens.parse().ok_or_else(|| {
+                                    io::Error::new(io::ErrorKind::InvalidData, "expected multipv")
+                                })?;
                             }
-                            "depth" => {
-                                depth =
-                                    parts.next().and_then(|t| t.parse().ok()).ok_or_else(|| {
-                                        io::Error::new(io::ErrorKind::InvalidData, "expected depth")
-                                    })?;
+                            b"depth" => {
+                                depth = tokens.parse().ok_or_else(|| {
+                                    io::Error::new(io::ErrorKind::InvalidData, "expected depth")
+                                })?;
                             }
-                            "nodes" => {
-                                nodes =
-                                    parts.next().and_then(|t| t.parse().ok()).ok_or_else(|| {
-                                        io::Error::new(io::ErrorKind::InvalidData, "expected nodes")
-                                    })?;
+                            b"nodes" => {
+                                nodes = tokens.parse().ok_or_else(|| {
+                                    io::Error::new(io::ErrorKind::InvalidData, "expected nodes")
+                                })?;
                             }
-                            "time" => {
-                                time = parts
-                                    .next()
-                                    .and_then(|t| t.parse().ok())
-                                    .map(Duration::from_millis)
-                                    .ok_or_else(|| {
+                            b"time" => {
+                                time =
+                                    tokens.parse().map(Duration::from_millis).ok_or_else(|| {
                                         io::Error::new(io::ErrorKind::InvalidData, "expected time")
                                     })?;
                             }
-                            "nps" => {
-                                nps = parts.next().and_then(|n| n.parse().ok());
+                            b"nps" => {
+                                nps = tokens.parse();
                             }
-                            "score" => {
-                                scores.set(
-                                    multipv,
-                                    depth,
-                                    match parts.next() {
-                                        Some("cp") => parts
-                                            .next()
-                                            .and_then(|cp| cp.parse().ok())
-                                            .map(Score::Cp),
-                                        Some("mate") => parts
-                                            .next()
-                                            .and_then(|mate| mate.parse().ok())
-                                            .map(Score::Mate),
-                                        _ => {
-                                            return Err(io::Error::new(
-                                                io::ErrorKind::InvalidData,
-                                                "expected cp or mate",
-                                            ))
-                                        }
+                            b"hashfull" => {
+                                hashfull = tokens.parse();
+                            }
+                            b"wdl" => {
+                                if let (Some(win), Some(draw), Some(loss)) =
+                                    (tokens.parse(), tokens.parse(), tokens.parse())
+                                {
+                                    if multipv.get() == 1 {
+                                        wdl = Some(Wdl { win, draw, loss });
                                     }
-                                    .ok_or_else(|| {
-                                        io::Error::new(io::ErrorKind::InvalidData, "expected score")
-                                    })?,
-                                );
+                                }
                             }
-                            "pv" => {
-                                let mut pv = Vec::new();
-                                for part in &mut parts {
-                                    pv.push(part.parse().map_err(|_| {
+                            b"lowerbound" | b"upperbound" => {
+                                bound = true;
+                            }
+                            b"score" => {
+                                let score = match tokens.next() {
+                                    Some(b"cp") => tokens.parse().map(Score::Cp),
+                                    Some(b"mate") => tokens.parse().map(Score::Mate),
+                                    _ => {
+                                        return Err(io::Error::new(
+                                            io::ErrorKind::InvalidData,
+                                            "expected cp or mate",
+                                        ))
+                                    }
+                                }
+                                .ok_or_else(|| {
+                                    io::Error::new(io::ErrorKind::InvalidData, "expected score")
+                                })?;
+                                scores.set(multipv, depth, score);
+                                line_score = Some(score);
+                            }
+                            b"pv" => {
+                                // Unless the full matrix is wanted, only the
+                                // deepest line is submitted. Then reuse the
+                                // buffer of the previous depth, instead of
+                                // allocating one for every info line.
+                                let mut pv = if matrix_wanted {
+                                    Vec::new()
+                                } else {
+                                    pvs.take_line(multipv).unwrap_or_default()
+                                };
+                                pv.clear();
+                                for token in &mut tokens {
+                                    pv.push(Uci::from_ascii(token).map_err(|_| {
                                         io::Error::new(io::ErrorKind::InvalidData, "invalid pv")
                                     })?);
                                 }
@@ -449,10 +1230,25 @@ impl StockfishActor {
                             _ => (),
                         }
                     }
+
+                    if let (Some(early_stop), Some(score)) = (early_stop, line_score) {
+                        if multipv.get() == 1 && !bound && !stopping {
+                            match best_scores.last_mut() {
+                                Some((d, s)) if *d == depth => *s = score,
+                                _ => best_scores.push((depth, score)),
+                            }
+                            if early_stop.reached(&best_scores) {
+                                stdin.write_all(b"stop\n").await?;
+                                stdin.flush().await?;
+                                stopping = true;
+                            }
+                        }
+                    }
                 }
-                _ => self
-                    .logger
-                    .warn(&format!("Unexpected engine output: {}", line)),
+                _ => self.logger.warn(&format!(
+                    "Unexpected engine output: {}",
+                    String::from_utf8_lossy(line)
+                )),
             }
         }
     }
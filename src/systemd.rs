@@ -1,36 +1,65 @@
-use std::{env, fs};
+use std::{cmp::max, env, fs};
 
 use atty::Stream;
 use shell_escape::escape;
 
-use crate::configure::{Key, Opt};
+use crate::configure::{
+    Cores, ExternalEngine, ExternalEngines, Key, Opt, SystemdOpt, DEFAULT_MAX_BACKOFF,
+};
 
-pub fn systemd_system(opt: Opt) {
-    let exe = exec_start(&opt);
+/// State directory of units with a dynamic user.
+const STATE_DIRECTORY: &str = "fishnet";
+
+pub fn systemd_system(opt: &Opt, systemd_opt: &SystemdOpt) {
+    let exe = exec_start(opt, "run");
     println!("[Unit]");
     println!("Description=Fishnet client");
     println!("After=network-online.target");
     println!("Wants=network-online.target");
+    if !opt.no_conf {
+        println!("ConditionPathExists={}", conf_path(opt));
+    }
     println!();
     println!("[Service]");
+    println!("Type=notify");
     println!("ExecStart={}", exe);
+    exec_reload(opt);
     println!("KillMode=mixed");
     println!("WorkingDirectory=/tmp");
-    println!(
-        "User={}",
-        env::var("USER").unwrap_or_else(|_| "XXX".to_owned())
-    );
+    if systemd_opt.dynamic_user {
+        println!("DynamicUser=true");
+        println!("StateDirectory={}", STATE_DIRECTORY);
+        // Lifetime stats and downloaded networks are kept in the home
+        // directory.
+        println!("Environment=HOME=/var/lib/{}", STATE_DIRECTORY);
+    } else {
+        println!(
+            "User={}",
+            env::var("USER").unwrap_or_else(|_| "XXX".to_owned())
+        );
+    }
     println!("Nice=5");
+    resource_limits(opt);
     println!("CapabilityBoundingSet=");
     println!("PrivateTmp=true");
     println!("PrivateDevices=true");
     println!("DevicePolicy=closed");
-    if opt.auto_update && exe.starts_with("/usr/") {
+    if systemd_opt.dynamic_user {
+        println!("ProtectSystem=strict");
+        println!("ProtectHome=read-only");
+        println!("ProtectKernelTunables=true");
+        println!("ProtectKernelModules=true");
+        println!("ProtectControlGroups=true");
+        println!("RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6");
+    } else if opt.auto_update.is_enabled() && exe.starts_with("/usr/") {
         println!("ProtectSystem=false");
     } else {
         println!("ProtectSystem=full");
     }
     println!("NoNewPrivileges=true");
+    println!("TimeoutStartSec=5min");
+    println!("TimeoutStopSec=5min");
+    println!("WatchdogSec=5min");
     println!("Restart=on-failure");
     println!();
     println!("[Install]");
@@ -47,29 +76,45 @@ pub fn systemd_system(opt: Opt) {
         eprintln!("# systemctl enable fishnet.service");
         eprintln!("# systemctl start fishnet.service");
         eprintln!("# Live view of log: journalctl --unit fishnet --follow");
+        usage_hints(opt, &command);
         eprintln!("# Need a user unit? {} systemd-user", command);
     }
+    if systemd_opt.dynamic_user && opt.auto_update.is_enabled() {
+        eprintln!("# WARNING: Auto update can not replace the executable with a dynamic user.");
+    }
+    if systemd_opt.dynamic_user && !opt.no_conf && conf_path(opt).starts_with("/home/") {
+        eprintln!("# WARNING: The dynamic user may not be able to read the configuration in /home. Consider moving it to /etc/fishnet.");
+    }
 }
 
 pub fn systemd_user(opt: Opt) {
-    let exe = exec_start(&opt);
+    let exe = exec_start(&opt, "run");
     println!("[Unit]");
     println!("Description=Fishnet client");
     println!("After=network-online.target");
     println!("Wants=network-online.target");
+    if !opt.no_conf {
+        println!("ConditionPathExists={}", conf_path(&opt));
+    }
     println!();
     println!("[Service]");
+    println!("Type=notify");
     println!("ExecStart={}", exe);
+    exec_reload(&opt);
     println!("KillMode=mixed");
     println!("WorkingDirectory=/tmp");
     println!("Nice=5");
+    resource_limits(&opt);
     println!("PrivateTmp=true");
     println!("DevicePolicy=closed");
-    if opt.auto_update && exe.starts_with("/usr/") {
+    if opt.auto_update.is_enabled() && exe.starts_with("/usr/") {
         println!("ProtectSystem=false");
     } else {
         println!("ProtectSystem=full");
     }
+    println!("TimeoutStartSec=5min");
+    println!("TimeoutStopSec=5min");
+    println!("WatchdogSec=5min");
     println!("Restart=on-failure");
     println!();
     println!("[Install]");
@@ -86,10 +131,100 @@ pub fn systemd_user(opt: Opt) {
         eprintln!("# systemctl enable --user fishnet.service");
         eprintln!("# systemctl start --user fishnet.service");
         eprintln!("# Live view of log: journalctl --user --user-unit fishnet --follow");
+        usage_hints(&opt, &command);
+    }
+}
+
+/// Drain and reload go through the control socket, if configured.
+/// Stopping the unit sends SIGTERM, which finishes positions in progress
+/// and saves unfinished analysis for the next start.
+fn exec_reload(opt: &Opt) {
+    if opt.control_socket.is_some() {
+        println!("ExecReload={}", exec_start(opt, "ctl reload"));
+    }
+}
+
+fn usage_hints(opt: &Opt, command: &str) {
+    if opt.control_socket.is_some() {
+        eprintln!("# Reload cores and backlog: systemctl reload fishnet.service");
+        eprintln!(
+            "# Finish pending batches, then stop: {} --control-socket {} ctl drain",
+            command,
+            control_socket_path(opt)
+        );
+    }
+}
+
+fn conf_path(opt: &Opt) -> String {
+    fs::canonicalize(&opt.conf)
+        .expect("canonicalize config path")
+        .to_str()
+        .expect("printable config path")
+        .to_owned()
+}
+
+fn control_socket_path(opt: &Opt) -> String {
+    env::current_dir()
+        .expect("current dir")
+        .join(opt.control_socket.as_ref().expect("control socket"))
+        .to_str()
+        .expect("printable control socket path")
+        .to_owned()
+}
+
+/// Memory of each engine process besides the hash table, mostly for the
+/// NNUE network.
+const ENGINE_OVERHEAD: u64 = 256 << 20;
+
+/// Memory of fishnet itself.
+const CLIENT_OVERHEAD: u64 = 256 << 20;
+
+/// Generous estimate of the memory needed with the configured hash table
+/// sizes, if any. Every worker may run an engine process of each flavor.
+fn estimate_memory(opt: &Opt) -> Option<u64> {
+    if opt.engine.hash_official.is_none() && opt.engine.hash_multivariant.is_none() {
+        return None;
+    }
+    let engines = opt.enabled_engines();
+    let threads = opt.engine.threads_per_job();
+    let processes = max(1, usize::from(opt.cores.unwrap_or(Cores::Auto)) / threads) as u64;
+    let default_hash = 16 << 20;
+    let mut per_worker = 0;
+    if engines.official {
+        per_worker += opt.engine.hash_official.map_or(default_hash, u64::from) + ENGINE_OVERHEAD;
+    }
+    if engines.multi_variant {
+        per_worker +=
+            opt.engine.hash_multivariant.map_or(default_hash, u64::from) + ENGINE_OVERHEAD;
+    }
+    Some(processes * per_worker + CLIENT_OVERHEAD)
+}
+
+fn resource_limits(opt: &Opt) {
+    // Let systemd enforce limits, instead of passing them on.
+    if let Some(max_memory) = opt
+        .limits
+        .max_memory
+        .map(u64::from)
+        .or_else(|| estimate_memory(opt))
+    {
+        println!("MemoryMax={}", max_memory);
+    }
+    if let Some(cpu_quota) = opt.limits.cpu_quota {
+        println!("CPUQuota={}", cpu_quota);
+    }
+    if let Some(io_class) = opt.limits.io_class {
+        println!("IOSchedulingClass={}", io_class);
+    }
+    if let Some(nice) = opt.limits.nice {
+        println!("Nice={}", nice);
+    }
+    if opt.limits.idle_priority {
+        println!("CPUSchedulingPolicy=idle");
     }
 }
 
-fn exec_start(opt: &Opt) -> String {
+fn exec_start(opt: &Opt, command: &str) -> String {
     let exe = env::current_exe()
         .expect("current exe")
         .to_str()
@@ -100,20 +235,15 @@ fn exec_start(opt: &Opt) -> String {
     if opt.verbose.level > 0 {
         builder.push(format!("-{}", "v".repeat(opt.verbose.level)));
     }
-    if opt.auto_update {
-        builder.push("--auto-update".to_owned());
+    if opt.auto_update.is_enabled() {
+        builder.push(format!("--auto-update={}", opt.auto_update));
     }
 
     if opt.no_conf {
         builder.push("--no-conf".to_owned());
     } else {
         builder.push("--conf".to_owned());
-        let canonical = fs::canonicalize(&opt.conf)
-            .expect("canonicalize config path")
-            .to_str()
-            .expect("printable config path")
-            .to_owned();
-        builder.push(escape(canonical.into()).into_owned());
+        builder.push(escape(conf_path(opt).into()).into_owned());
     }
 
     if let Some(ref key_file) = opt.key_file {
@@ -133,19 +263,346 @@ fn exec_start(opt: &Opt) -> String {
         builder.push("--endpoint".to_owned());
         builder.push(escape(endpoint.to_string().into()).into_owned());
     }
+    if let Some(ref proxy) = opt.proxy {
+        builder.push("--proxy".to_owned());
+        builder.push(escape(proxy.to_string().into()).into_owned());
+    }
+    if opt.websocket {
+        builder.push("--websocket".to_owned());
+    }
+    if opt.prefer_ipv4 {
+        builder.push("--prefer-ipv4".to_owned());
+    }
+    if opt.prefer_ipv6 {
+        builder.push("--prefer-ipv6".to_owned());
+    }
+    if let Some(ref only_endpoint) = opt.only_endpoint {
+        builder.push("--only-endpoint".to_owned());
+        builder.push(escape(only_endpoint.into()).into_owned());
+    }
     if let Some(ref cores) = opt.cores {
         builder.push("--cores".to_owned());
         builder.push(escape(cores.to_string().into()).into_owned());
     }
+    if let Some(reserve_cores) = opt.reserve_cores {
+        builder.push(format!("--reserve-cores {}", reserve_cores));
+    }
+    if let Some(on_battery) = opt.on_battery {
+        builder.push(format!("--on-battery {}", on_battery));
+    }
+    if opt.pause_when_active {
+        builder.push("--pause-when-active".to_owned());
+    }
+    if let Some(max_temperature) = opt.max_temperature {
+        builder.push(format!("--max-temperature {}", max_temperature));
+    }
+    if let Some(ref core_lease_dir) = opt.core_lease_dir {
+        builder.push("--core-lease-dir".to_owned());
+        builder.push(escape(core_lease_dir.to_string_lossy()).into_owned());
+    }
+    if let Some(status_bind) = opt.status_bind {
+        builder.push(format!("--status-bind {}", status_bind));
+    }
+    if let Some(metrics_bind) = opt.metrics_bind {
+        builder.push(format!("--metrics-bind {}", metrics_bind));
+    }
+    if let Some(ref otlp_endpoint) = opt.otlp_endpoint {
+        builder.push("--otlp-endpoint".to_owned());
+        builder.push(escape(otlp_endpoint.as_str().into()).into_owned());
+    }
+    if let Some(ref engine_manifest) = opt.engine_manifest {
+        builder.push("--engine-manifest".to_owned());
+        builder.push(escape(engine_manifest.as_str().into()).into_owned());
+    }
+    if let Some(ref engine_update_interval) = opt.engine_update_interval {
+        builder.push("--engine-update-interval".to_owned());
+        builder.push(escape(engine_update_interval.to_string().into()).into_owned());
+    }
+    if let Some(download_rate_limit) = opt.download_rate_limit {
+        builder.push(format!("--download-rate-limit {}", download_rate_limit));
+    }
+    if let Some(ref anomaly_webhook) = opt.anomaly_webhook {
+        builder.push("--anomaly-webhook".to_owned());
+        builder.push(escape(anomaly_webhook.as_str().into()).into_owned());
+    }
+    if opt.trace_engine {
+        builder.push("--trace-engine".to_owned());
+    }
+    if let Some(ref trace_engine_dir) = opt.trace_engine_dir {
+        builder.push("--trace-engine-dir".to_owned());
+        let absolute = env::current_dir()
+            .expect("current dir")
+            .join(trace_engine_dir)
+            .to_str()
+            .expect("printable trace engine dir")
+            .to_owned();
+        builder.push(escape(absolute.into()).into_owned());
+    }
+    if let Some(ref trace_engine_max_size) = opt.trace_engine_max_size {
+        builder.push("--trace-engine-max-size".to_owned());
+        builder.push(escape(trace_engine_max_size.to_string().into()).into_owned());
+    }
+    if let Some(ref audit_log) = opt.audit_log {
+        builder.push("--audit-log".to_owned());
+        let absolute = env::current_dir()
+            .expect("current dir")
+            .join(audit_log)
+            .to_str()
+            .expect("printable audit log path")
+            .to_owned();
+        builder.push(escape(absolute.into()).into_owned());
+    }
+    if opt.no_journal {
+        builder.push("--no-journal".to_owned());
+    }
+    if let Some(ref invalid_batch_dir) = opt.invalid_batch_dir {
+        builder.push("--invalid-batch-dir".to_owned());
+        let absolute = env::current_dir()
+            .expect("current dir")
+            .join(invalid_batch_dir)
+            .to_str()
+            .expect("printable invalid batch dir")
+            .to_owned();
+        builder.push(escape(absolute.into()).into_owned());
+    }
+    if let Some(ref invalid_batch_max_size) = opt.invalid_batch_max_size {
+        builder.push("--invalid-batch-max-size".to_owned());
+        builder.push(escape(invalid_batch_max_size.to_string().into()).into_owned());
+    }
+    if opt.control_socket.is_some() {
+        builder.push("--control-socket".to_owned());
+        builder.push(escape(control_socket_path(opt).into()).into_owned());
+    }
+    if let Some(cluster_bind) = opt.cluster_bind {
+        builder.push(format!("--cluster-bind {}", cluster_bind));
+    }
+    if let Some(ref s3_sink) = opt.s3_sink {
+        builder.push("--s3-sink".to_owned());
+        builder.push(escape(s3_sink.as_str().into()).into_owned());
+    }
+    if let Some(ref s3_region) = opt.s3_region {
+        builder.push("--s3-region".to_owned());
+        builder.push(escape(s3_region.into()).into_owned());
+    }
+    if let Some(ref book) = opt.lookup.book {
+        builder.push("--book".to_owned());
+        let absolute = env::current_dir()
+            .expect("current dir")
+            .join(book)
+            .to_str()
+            .expect("printable book path")
+            .to_owned();
+        builder.push(escape(absolute.into()).into_owned());
+    }
+    if let Some(book_max_level) = opt.lookup.book_max_level {
+        builder.push(format!("--book-max-level {}", book_max_level));
+    }
+    if let Some(book_skip_plies) = opt.lookup.book_skip_plies {
+        builder.push(format!("--book-skip-plies {}", book_skip_plies));
+    }
+    if let Some(cloud_eval_depth) = opt.lookup.cloud_eval_depth {
+        builder.push(format!("--cloud-eval-depth {}", cloud_eval_depth));
+    }
+    if let Some(result_cache) = opt.lookup.result_cache {
+        builder.push(format!("--result-cache {}", result_cache));
+    }
+    if let Some(ref result_cache_file) = opt.lookup.result_cache_file {
+        builder.push("--result-cache-file".to_owned());
+        let absolute = env::current_dir()
+            .expect("current dir")
+            .join(result_cache_file)
+            .to_str()
+            .expect("printable result cache path")
+            .to_owned();
+        builder.push(escape(absolute.into()).into_owned());
+    }
+    if let Some(ref lookup_work) = opt.lookup.lookup_work {
+        builder.push(format!("--lookup-work {}", lookup_work));
+    }
+    if let Some(verify_fraction) = opt.verify.verify_fraction {
+        builder.push(format!("--verify-fraction {}", verify_fraction));
+    }
+    if let Some(verify_threshold) = opt.verify.verify_threshold {
+        builder.push(format!("--verify-threshold {}", verify_threshold));
+    }
+    if let Some(verify_flavor) = opt.verify.verify_flavor {
+        builder.push(format!("--verify-flavor {}", verify_flavor.name()));
+    }
+    if let Some(hash_official) = opt.engine.hash_official {
+        builder.push(format!("--hash-official {}", hash_official));
+    }
+    if let Some(hash_multivariant) = opt.engine.hash_multivariant {
+        builder.push(format!("--hash-multivariant {}", hash_multivariant));
+    }
+    if opt.engine.fixed_hash {
+        builder.push("--fixed-hash".to_owned());
+    }
+    if let Some(threads_per_job) = opt.engine.threads_per_job {
+        builder.push(format!("--threads-per-job {}", threads_per_job));
+    }
+    if opt.engine.pin_cores {
+        builder.push("--pin-cores".to_owned());
+    }
+    if opt.engine.no_numa {
+        builder.push("--no-numa".to_owned());
+    }
+    if let Some(sandbox) = opt.engine.sandbox {
+        builder.push(format!("--sandbox {}", sandbox));
+    }
+    if let Some(early_stop_mate) = opt.engine.early_stop_mate {
+        builder.push(format!("--early-stop-mate {}", early_stop_mate));
+    }
+    if let Some(early_stop_window) = opt.engine.early_stop_window {
+        builder.push(format!("--early-stop-window {}", early_stop_window));
+    }
+    if opt.engine.deterministic {
+        builder.push("--deterministic".to_owned());
+    }
+    if let Some(node_unit) = opt.format.node_unit {
+        builder.push(format!("--node-unit {}", node_unit));
+    }
+    if let Some(score_format) = opt.format.score_format {
+        builder.push(format!("--score-format {}", score_format));
+    }
+    if let Some(timestamps) = opt.format.timestamps {
+        builder.push(format!("--timestamps {}", timestamps));
+    }
+    if let Some(log_format) = opt.format.log_format {
+        builder.push(format!("--log-format {}", log_format));
+    }
+    if let Some(ref log_file) = opt.log_file.log_file {
+        builder.push("--log-file".to_owned());
+        let absolute = env::current_dir()
+            .expect("current dir")
+            .join(log_file)
+            .to_str()
+            .expect("printable log file path")
+            .to_owned();
+        builder.push(escape(absolute.into()).into_owned());
+    }
+    if let Some(ref log_max_size) = opt.log_file.log_max_size {
+        builder.push("--log-max-size".to_owned());
+        builder.push(escape(log_max_size.to_string().into()).into_owned());
+    }
+    if let Some(ref log_max_age) = opt.log_file.log_max_age {
+        builder.push("--log-max-age".to_owned());
+        builder.push(escape(log_max_age.to_string().into()).into_owned());
+    }
+    if let Some(log_keep) = opt.log_file.log_keep {
+        builder.push(format!("--log-keep {}", log_keep));
+    }
+    if opt.no_official_stockfish {
+        builder.push("--no-official-stockfish".to_owned());
+    }
+    if opt.no_multivariant {
+        builder.push("--no-multivariant".to_owned());
+    }
+    if opt.force_multivariant {
+        builder.push("--force-multivariant".to_owned());
+    }
+    if let Some(force_cpu_level) = opt.force_cpu_level {
+        builder.push(format!("--force-cpu-level {}", force_cpu_level));
+    }
+    if let Some(ref syzygy_path) = opt.syzygy_path {
+        builder.push("--syzygy-path".to_owned());
+        let absolute = env::join_paths(
+            env::split_paths(syzygy_path)
+                .map(|dir| env::current_dir().expect("current dir").join(dir)),
+        )
+        .expect("joinable syzygy path")
+        .into_string()
+        .expect("printable syzygy path");
+        builder.push(escape(absolute.into()).into_owned());
+    }
+    if let (Some(ref tls_cert), Some(ref tls_key)) = (&opt.tls_cert, &opt.tls_key) {
+        for (flag, path) in [("--tls-cert", tls_cert), ("--tls-key", tls_key)] {
+            builder.push(flag.to_owned());
+            let absolute = env::current_dir()
+                .expect("current dir")
+                .join(path)
+                .to_str()
+                .expect("printable tls path")
+                .to_owned();
+            builder.push(escape(absolute.into()).into_owned());
+        }
+    }
+    if let Some(ref worker_name) = opt.worker_name {
+        builder.push("--worker-name".to_owned());
+        builder.push(escape(worker_name.as_str().into()).into_owned());
+    }
+    if let Some(ref labels) = opt.labels {
+        builder.push("--labels".to_owned());
+        builder.push(escape(labels.to_string().into()).into_owned());
+    }
+    if let Some(ref variant_nets) = opt.variant_nets {
+        builder.push("--variant-nets".to_owned());
+        builder.push(escape(variant_nets.to_string().into()).into_owned());
+    }
+    if let Some(ref external_engines) = opt.external_engines {
+        let absolute = ExternalEngines(
+            external_engines
+                .0
+                .iter()
+                .map(|engine| ExternalEngine {
+                    variant: engine.variant,
+                    path: env::current_dir().expect("current dir").join(&engine.path),
+                })
+                .collect(),
+        );
+        builder.push("--external-engines".to_owned());
+        builder.push(escape(absolute.to_string().into()).into_owned());
+    }
+    if let Some(ref variants_allow) = opt.variants_allow {
+        builder.push(format!("--variants-allow {}", variants_allow));
+    }
+    if let Some(ref variants_deny) = opt.variants_deny {
+        builder.push(format!("--variants-deny {}", variants_deny));
+    }
+    if let Some(max_nodes) = opt.max_nodes {
+        builder.push(format!("--max-nodes {}", max_nodes));
+    }
+    if let Some(max_depth) = opt.max_depth {
+        builder.push(format!("--max-depth {}", max_depth));
+    }
+    if let Some(movetime) = opt.movetime {
+        builder.push(format!("--movetime {}", movetime));
+    }
+    if opt.scale_nodes {
+        builder.push("--scale-nodes".to_owned());
+    }
+    if opt.pv_san {
+        builder.push("--pv-san".to_owned());
+    }
+    if let Some(analysis_order) = opt.analysis_order {
+        builder.push(format!("--analysis-order {}", analysis_order));
+    }
+    if opt.budget_by_phase {
+        builder.push("--budget-by-phase".to_owned());
+    }
+    if opt.puzzle_candidates {
+        builder.push("--puzzle-candidates".to_owned());
+    }
+    if let Some(ref move_latency_budget) = opt.move_latency_budget {
+        builder.push("--move-latency-budget".to_owned());
+        builder.push(escape(move_latency_budget.to_string().into()).into_owned());
+    }
+    if opt.max_backoff.to_string() != DEFAULT_MAX_BACKOFF {
+        builder.push("--max-backoff".to_owned());
+        builder.push(escape(opt.max_backoff.to_string().into()).into_owned());
+    }
+    if let Some(ref max_error_backoff) = opt.max_error_backoff {
+        builder.push("--max-error-backoff".to_owned());
+        builder.push(escape(max_error_backoff.to_string().into()).into_owned());
+    }
     if let Some(ref user_backlog) = opt.backlog.user {
         builder.push("--user-backlog".to_owned());
         builder.push(escape(user_backlog.to_string().into()).into_owned());
     }
     if let Some(ref system_backlog) = opt.backlog.system {
-        builder.push("--system_backlog".to_owned());
+        builder.push("--system-backlog".to_owned());
         builder.push(escape(system_backlog.to_string().into()).into_owned());
     }
 
-    builder.push("run".to_owned());
+    builder.push(command.to_owned());
     builder.join(" ")
 }
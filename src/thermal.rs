@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use tokio::{sync::mpsc, time};
+
+use crate::logger::Logger;
+
+/// Read the CPU temperature this often.
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Highest CPU temperature in degrees Celsius, or `None` if it can not be
+/// determined, for example in virtual machines.
+pub fn cpu_temperature() -> Option<f64> {
+    imp::cpu_temperature()
+}
+
+/// Reports the CPU temperature periodically. Nothing is reported if the
+/// temperature can not be determined.
+pub fn spawn_watcher(logger: Logger) -> mpsc::UnboundedReceiver<f64> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    if cpu_temperature().is_none() {
+        logger.warn("Could not read CPU temperature. Will not throttle when hot.");
+        return rx;
+    }
+    tokio::spawn(async move {
+        let mut interval = time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = tx.closed() => break,
+                _ = interval.tick() => (),
+            }
+            match cpu_temperature() {
+                Some(celsius) => {
+                    if tx.send(celsius).is_err() {
+                        break;
+                    }
+                }
+                None => logger.debug("CPU temperature temporarily unknown"),
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{fs, path::Path};
+
+    /// hwmon drivers of CPU package or core sensors.
+    const CPU_HWMON: &[&str] = &["coretemp", "k10temp", "zenpower", "cpu_thermal"];
+
+    fn read(path: &Path) -> Option<String> {
+        fs::read_to_string(path).ok().map(|s| s.trim().to_owned())
+    }
+
+    fn millidegrees(path: &Path) -> Option<f64> {
+        read(path)?.parse::<i64>().ok().map(|m| m as f64 / 1000.0)
+    }
+
+    fn max(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Prefers hwmon sensors, and falls back to thermal zones, which are
+    /// the only source on many ARM boards.
+    pub fn cpu_temperature() -> Option<f64> {
+        let mut hottest = None;
+        for hwmon in fs::read_dir("/sys/class/hwmon")
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            let path = hwmon.path();
+            if !read(&path.join("name")).map_or(false, |name| CPU_HWMON.contains(&name.as_str())) {
+                continue;
+            }
+            for entry in fs::read_dir(&path).into_iter().flatten().flatten() {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if name.starts_with("temp") && name.ends_with("_input") {
+                    hottest = max(hottest, millidegrees(&entry.path()));
+                }
+            }
+        }
+        if hottest.is_some() {
+            return hottest;
+        }
+        for zone in fs::read_dir("/sys/class/thermal")
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            let path = zone.path();
+            if read(&path.join("type")).map_or(false, |t| {
+                t == "x86_pkg_temp" || t.contains("cpu") || t.contains("soc")
+            }) {
+                hottest = max(hottest, millidegrees(&path.join("temp")));
+            }
+        }
+        hottest
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    pub fn cpu_temperature() -> Option<f64> {
+        None
+    }
+}
@@ -0,0 +1,208 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ring::digest;
+use serde_json::{json, Value};
+use tokio::{
+    sync::mpsc,
+    time::{self, Instant},
+};
+use url::Url;
+
+use crate::{api::BatchId, logger::Logger};
+
+/// Export spans at least this often.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Export early when this many spans are buffered.
+const MAX_BUFFERED: usize = 512;
+
+/// Records batch lifecycles as traces and exports them to an OpenTelemetry
+/// collector, using OTLP over HTTP with JSON encoding. Each batch is a trace
+/// with a root span, and each analysed position is a child span.
+#[derive(Clone)]
+pub struct Tracer {
+    tx: mpsc::UnboundedSender<Span>,
+}
+
+pub struct Span {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: bool,
+}
+
+impl Span {
+    /// Root span covering the entire lifecycle of a batch.
+    pub fn batch(batch: BatchId, start: SystemTime, end: SystemTime) -> Span {
+        let (trace_id, root_span_id) = batch_ids(batch);
+        Span {
+            trace_id,
+            span_id: root_span_id,
+            parent_span_id: None,
+            name: "batch",
+            start,
+            end,
+            attributes: vec![("fishnet.batch", json!(batch.to_string()))],
+            error: false,
+        }
+    }
+
+    /// Child span for a single position, ending now.
+    pub fn position(batch: BatchId, time: Duration) -> Span {
+        let (trace_id, root_span_id) = batch_ids(batch);
+        let end = SystemTime::now();
+        Span {
+            trace_id,
+            span_id: rand::random(),
+            parent_span_id: Some(root_span_id),
+            name: "position",
+            start: end.checked_sub(time).unwrap_or(end),
+            end,
+            attributes: Vec::new(),
+            error: false,
+        }
+    }
+
+    pub fn attribute(mut self, key: &'static str, value: Value) -> Span {
+        self.attributes.push((key, value));
+        self
+    }
+
+    pub fn error(mut self) -> Span {
+        self.error = true;
+        self
+    }
+
+    fn to_json(&self) -> Value {
+        let mut span = json!({
+            "traceId": hex(&self.trace_id),
+            "spanId": hex(&self.span_id),
+            "name": self.name,
+            "kind": 1, // SPAN_KIND_INTERNAL
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.end),
+            "attributes": self.attributes.iter().map(|(key, value)| json!({
+                "key": key,
+                "value": any_value(value),
+            })).collect::<Vec<_>>(),
+            "status": {
+                "code": if self.error { 2 } else { 1 }, // ERROR or OK
+            },
+        });
+        if let Some(ref parent_span_id) = self.parent_span_id {
+            span["parentSpanId"] = json!(hex(parent_span_id));
+        }
+        span
+    }
+}
+
+impl Tracer {
+    pub fn record(&self, span: Span) {
+        // Spans recorded during shutdown may be lost.
+        let _ = self.tx.send(span);
+    }
+}
+
+/// Spawns the exporter. Buffered spans are flushed periodically.
+pub fn spawn(endpoint: Url, logger: Logger) -> Tracer {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("client");
+        let mut buffer: Vec<Span> = Vec::new();
+        let mut deadline = Instant::now() + FLUSH_INTERVAL;
+        loop {
+            let closed = tokio::select! {
+                span = rx.recv() => match span {
+                    Some(span) => {
+                        buffer.push(span);
+                        false
+                    }
+                    None => true,
+                },
+                _ = time::sleep_until(deadline) => false,
+            };
+            if closed || buffer.len() >= MAX_BUFFERED || Instant::now() >= deadline {
+                if !buffer.is_empty() {
+                    export(&client, &endpoint, &buffer, &logger).await;
+                    buffer.clear();
+                }
+                deadline = Instant::now() + FLUSH_INTERVAL;
+            }
+            if closed {
+                break;
+            }
+        }
+    });
+    Tracer { tx }
+}
+
+async fn export(client: &reqwest::Client, endpoint: &Url, spans: &[Span], logger: &Logger) {
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": "fishnet" } },
+                    { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "fishnet" },
+                "spans": spans.iter().map(Span::to_json).collect::<Vec<_>>(),
+            }],
+        }],
+    });
+    match client.post(endpoint.clone()).json(&body).send().await {
+        Ok(res) if res.status().is_success() => {
+            logger.debug(&format!("Exported {} spans", spans.len()));
+        }
+        Ok(res) => logger.warn(&format!(
+            "Trace collector at {} responded with {}. Dropped {} spans.",
+            endpoint,
+            res.status(),
+            spans.len()
+        )),
+        Err(err) => logger.warn(&format!("Failed to export {} spans: {}", spans.len(), err)),
+    }
+}
+
+/// Derives the trace id and root span id from the batch id, so that all
+/// spans of a batch end up in the same trace without keeping extra state.
+fn batch_ids(batch: BatchId) -> ([u8; 16], [u8; 8]) {
+    let hash = digest::digest(&digest::SHA256, batch.to_string().as_bytes());
+    let hash = hash.as_ref();
+    let mut trace_id = [0; 16];
+    trace_id.copy_from_slice(&hash[..16]);
+    let mut span_id = [0; 8];
+    span_id.copy_from_slice(&hash[16..24]);
+    (trace_id, span_id)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    // Encoded as string, because 64 bit integers do not fit into JSON
+    // numbers.
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn any_value(value: &Value) -> Value {
+    match value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_f64() => json!({ "doubleValue": n }),
+        Value::Number(n) => json!({ "intValue": n.to_string() }),
+        Value::String(s) => json!({ "stringValue": s }),
+        other => json!({ "stringValue": other.to_string() }),
+    }
+}
@@ -0,0 +1,120 @@
+use std::{fmt::Write as _, io, io::Write as _, time::Duration};
+
+use tokio::{task::JoinHandle, time};
+
+use crate::{
+    logger::Logger,
+    queue::QueueStub,
+    status::{Activity, WorkerBoard, WorkerPane},
+    util::NevermindExt as _,
+};
+
+/// Redraw this often.
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// Recent log lines shown below the workers.
+const LOG_LINES: usize = 12;
+
+/// Full-terminal status view of `--tui`, with the queue and a line for each
+/// worker. Log lines are shown below it, instead of being printed.
+pub struct Tui {
+    logger: Logger,
+    task: JoinHandle<()>,
+}
+
+impl Tui {
+    /// Takes over the terminal, or returns `None` (keeping the progress
+    /// line) if stdout is not a terminal or logs are not plain text.
+    pub fn spawn(queue: QueueStub, board: WorkerBoard, logger: Logger) -> Option<Tui> {
+        if !logger.set_tui(true) {
+            logger.warn("--tui needs a terminal and text logs. Showing the progress line instead.");
+            return None;
+        }
+        let task = tokio::spawn({
+            let logger = logger.clone();
+            async move {
+                let mut interval = time::interval(REFRESH);
+                loop {
+                    interval.tick().await;
+                    let screen = render(&queue, &board, &logger).await;
+                    print!("\x1b[2J\x1b[H{}", screen);
+                    io::stdout().flush().nevermind("flush stdout");
+                }
+            }
+        });
+        Some(Tui { logger, task })
+    }
+
+    /// Gives the terminal back to the logger.
+    pub fn stop(self) {
+        self.task.abort();
+        print!("\x1b[2J\x1b[H");
+        io::stdout().flush().nevermind("flush stdout");
+        self.logger.set_tui(false);
+    }
+}
+
+async fn render(queue: &QueueStub, board: &WorkerBoard, logger: &Logger) -> String {
+    let mut screen = String::new();
+    let bar = queue.status_bar().await;
+    let _ = write!(
+        screen,
+        "### fishnet {}\n\n{} {} cores, {} queued, lookahead {}",
+        env!("CARGO_PKG_VERSION"),
+        bar,
+        bar.cores,
+        bar.pending,
+        bar.lookahead
+    );
+    if let Some(quota) = bar.quota {
+        let _ = write!(screen, ", quota: {}", quota);
+    }
+    screen.push_str("\n\n");
+    for (i, pane) in board.panes().into_iter().enumerate() {
+        let _ = writeln!(screen, "{:>3} {}", i, pane_line(&pane, logger));
+    }
+    let recent: Vec<String> = logger
+        .recent()
+        .into_iter()
+        .filter(|line| !is_debug(line))
+        .collect();
+    screen.push('\n');
+    for line in &recent[recent.len().saturating_sub(LOG_LINES)..] {
+        let _ = writeln!(screen, "{}", line);
+    }
+    screen
+}
+
+fn pane_line(pane: &WorkerPane, logger: &Logger) -> String {
+    let mut line = match pane.activity {
+        Activity::Idle => "idle".to_owned(),
+        Activity::Parked => "parked".to_owned(),
+        Activity::StartingEngine { flavor } => format!("starting {}", flavor),
+        Activity::Working {
+            ref batch,
+            position,
+            flavor,
+        } => format!("{}#{} ({})", batch, position, flavor),
+    };
+    let _ = write!(line, ", {}s", pane.since.as_secs());
+    if let Some(depth) = pane.depth {
+        let _ = write!(line, ", depth {}", depth);
+    }
+    if let Some(nps) = pane.nps {
+        let _ = write!(
+            line,
+            ", {}",
+            logger.nps(u32::try_from(nps).unwrap_or(u32::MAX))
+        );
+    }
+    if let Some(eta) = pane.eta {
+        let _ = write!(line, ", eta {}s", eta.as_secs());
+    }
+    line
+}
+
+/// Recent lines are prefixed with a timestamp, and debug lines with "D: ".
+fn is_debug(line: &str) -> bool {
+    line.split_once("] ")
+        .map_or(false, |(_, message)| message.starts_with("D: "))
+}
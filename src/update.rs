@@ -0,0 +1,172 @@
+use std::{env, fs, io, path::Path, process};
+
+use atty::Stream;
+use reqwest::header::{HeaderValue, ACCEPT};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use self_update::{
+    backends::github, errors::Error, update::Release, version::bump_is_greater, Download, Move,
+    Status,
+};
+
+use crate::{
+    assets::Assets,
+    configure::{Opt, UpdateChannel},
+    logger::Logger,
+    util::decode_hex,
+};
+
+/// Hex encoded Ed25519 key, used to verify the detached signatures
+/// (`<asset>.sig`) that are published along with each release binary.
+const UPDATE_PUBLIC_KEY: Option<&str> = option_env!("FISHNET_UPDATE_PUBLIC_KEY");
+
+/// Builds without the public key (for example from source) cannot verify
+/// release binaries, so they never install them. Official releases refuse
+/// to build without the key.
+pub fn can_verify_updates() -> bool {
+    UPDATE_PUBLIC_KEY.and_then(decode_hex).is_some()
+}
+
+pub async fn auto_update(
+    channel: UpdateChannel,
+    verbose: bool,
+    logger: Logger,
+) -> Result<Status, Error> {
+    tokio::task::spawn_blocking(move || {
+        if verbose {
+            logger.headline("Updating ...");
+        }
+        logger.fishnet_info(&format!(
+            "Checking for updates (--auto-update={}) ...",
+            channel
+        ));
+        update(channel, verbose, &logger)
+    })
+    .await
+    .expect("spawn blocking update")
+}
+
+fn update(channel: UpdateChannel, verbose: bool, logger: &Logger) -> Result<Status, Error> {
+    let current_version = env!("CARGO_PKG_VERSION");
+    let target = self_update::get_target();
+
+    // The list of releases serves as the manifest. Newest releases come
+    // first.
+    let release = match github::ReleaseList::configure()
+        .repo_owner("lichess-org")
+        .repo_name("fishnet")
+        .with_target(target)
+        .build()?
+        .fetch()?
+        .into_iter()
+        .find(|r| channel.accepts(&r.version))
+    {
+        Some(release) if bump_is_greater(current_version, &release.version)? => release,
+        _ => return Ok(Status::UpToDate(current_version.to_owned())),
+    };
+
+    logger.fishnet_info(&format!(
+        "Found fishnet {} on {} channel",
+        release.version, channel
+    ));
+
+    let tmp_dir = tempfile::Builder::new()
+        .prefix("fishnet-update-")
+        .tempdir_in(
+            env::current_exe()?
+                .parent()
+                .unwrap_or_else(|| Path::new(".")),
+        )?;
+    let new_exe = tmp_dir.path().join("fishnet");
+    let binary = download_verified(&release, target, verbose)?;
+    fs::write(&new_exe, binary)?;
+    set_executable(&new_exe)?;
+
+    Move::from_source(&new_exe)
+        .replace_using_temp(&tmp_dir.path().join("fishnet.old"))
+        .to_dest(&env::current_exe()?)?;
+
+    Ok(Status::Updated(release.version))
+}
+
+fn download_verified(release: &Release, target: &str, verbose: bool) -> Result<Vec<u8>, Error> {
+    let public_key = UPDATE_PUBLIC_KEY
+        .and_then(decode_hex)
+        .ok_or_else(|| Error::Config("built without update signing key".to_owned()))?;
+
+    // Select by exact name. Matching by target alone could select the
+    // signature instead of the binary.
+    let asset_name = format!("fishnet-{}{}", target, env::consts::EXE_SUFFIX);
+    let asset = release
+        .assets
+        .iter()
+        .find(|a| a.name == asset_name)
+        .ok_or_else(|| Error::Release(format!("no asset {}", asset_name)))?;
+    let signature_name = format!("{}.sig", asset.name);
+    let signature_asset = release
+        .assets
+        .iter()
+        .find(|a| a.name == signature_name)
+        .ok_or_else(|| Error::Release(format!("no signature for {}", asset.name)))?;
+
+    let binary = download(&asset.download_url, atty::is(Stream::Stdout) && verbose)?;
+    let signature = download(&signature_asset.download_url, false)?;
+
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(&binary, &signature)
+        .map_err(|_| Error::Update(format!("bad signature for {}", asset.name)))?;
+
+    Ok(binary)
+}
+
+fn download(url: &str, show_progress: bool) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    Download::from_url(url)
+        .show_progress(show_progress)
+        .set_header(ACCEPT, HeaderValue::from_static("application/octet-stream"))
+        .download_to(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Downloads newer engine builds from the configured manifest into the
+/// asset cache. They are used from the next start.
+pub async fn update_engines(opt: &Opt, logger: &Logger) {
+    let url = match opt.engine_manifest {
+        Some(ref url) => url,
+        None => {
+            logger.error("No engine manifest configured (--engine-manifest).");
+            process::exit(1);
+        }
+    };
+    let mut assets =
+        Assets::prepare(opt.cpu(), opt.enabled_engines()).expect("prepared bundled stockfish");
+    assets.download_rate_limit = opt.download_rate_limit.map(u64::from);
+    logger.fishnet_info(&format!("Checking for engine updates from {} ...", url));
+    match assets.fetch_engine_update(url).await {
+        Ok(Some(update)) => logger.fishnet_info(&format!(
+            "Engines updated to {}. Will be used from the next start",
+            update.version
+        )),
+        Ok(None) => logger.fishnet_info(&format!(
+            "Engines are up to date ({})",
+            assets
+                .engine_update
+                .as_ref()
+                .map_or("bundled", |update| update.version.as_str())
+        )),
+        Err(err) => {
+            logger.error(&format!("Failed to update engines: {}", err));
+            process::exit(1);
+        }
+    }
+}
@@ -40,6 +40,16 @@ impl Default for RandomizedBackoff {
     }
 }
 
+pub fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
 pub trait NevermindExt: Sized {
     fn nevermind(self, _msg: &str) {}
 }
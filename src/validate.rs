@@ -0,0 +1,355 @@
+use std::{collections::BTreeMap, error::Error, fmt};
+
+use shakmaty::{
+    fen::{Fen, ParseFenError},
+    uci::Uci,
+    variant::VariantPosition,
+    CastlingMode, Position as _, PositionError,
+};
+
+use crate::protocol::LichessVariant;
+
+/// A game with a legal root position and legal moves.
+#[derive(Debug, Clone)]
+pub struct ValidGame {
+    pub root: VariantPosition,
+    /// Moves in UCI notation with Chess960 castling, as sent to the engines.
+    pub moves: Vec<Uci>,
+    /// The root position is only legal when ignoring impossible material.
+    /// Only Fairy-Stockfish can analyse these positions.
+    pub impossible_material: bool,
+}
+
+#[derive(Debug)]
+pub enum ValidationError {
+    /// Rejected by the quick checks, before parsing.
+    Malformed(&'static str),
+    Fen(ParseFenError),
+    Position(PositionError<VariantPosition>),
+    IllegalUci(IllegalMove),
+    EngineOption(InvalidEngineOption),
+}
+
+/// A move of the game that is not legal in the position reached so far.
+#[derive(Debug)]
+pub struct IllegalMove {
+    /// Number of moves played from the root position before this one.
+    pub ply: usize,
+    pub uci: Uci,
+    /// FEN of the position in which the move is illegal.
+    pub fen: String,
+    /// Legal moves in that position, in UCI notation with Chess960
+    /// castling.
+    pub legal: Vec<Uci>,
+}
+
+impl fmt::Display for IllegalMove {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at ply {} in {} (legal: {})",
+            self.uci,
+            self.ply,
+            self.fen,
+            self.legal
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::Malformed(reason) => write!(f, "malformed fen: {}", reason),
+            ValidationError::Fen(err) => write!(f, "invalid fen: {}", err),
+            ValidationError::Position(err) => write!(f, "illegal position: {}", err),
+            ValidationError::IllegalUci(err) => write!(f, "illegal move: {}", err),
+            ValidationError::EngineOption(err) => write!(f, "engine option not allowed: {}", err),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+impl From<ParseFenError> for ValidationError {
+    fn from(err: ParseFenError) -> ValidationError {
+        ValidationError::Fen(err)
+    }
+}
+
+impl From<PositionError<VariantPosition>> for ValidationError {
+    fn from(err: PositionError<VariantPosition>) -> ValidationError {
+        ValidationError::Position(err)
+    }
+}
+
+impl From<IllegalMove> for ValidationError {
+    fn from(err: IllegalMove) -> ValidationError {
+        ValidationError::IllegalUci(err)
+    }
+}
+
+/// Values that an engine option can take.
+#[derive(Debug, Copy, Clone)]
+pub enum EngineOptionKind {
+    Check,
+    Spin { min: i64, max: i64 },
+    Combo(&'static [&'static str]),
+}
+
+/// Engine option that the server may override for a batch.
+#[derive(Debug)]
+pub struct EngineOption {
+    pub name: &'static str,
+    pub kind: EngineOptionKind,
+    /// Restored for later batches without override. `None` if the option
+    /// is set for every search anyway.
+    pub default: Option<&'static str>,
+}
+
+impl EngineOption {
+    fn accepts(&self, value: &str) -> bool {
+        match self.kind {
+            EngineOptionKind::Check => matches!(value, "true" | "false"),
+            EngineOptionKind::Spin { min, max } => value
+                .parse::<i64>()
+                .map_or(false, |v| (min..=max).contains(&v)),
+            EngineOptionKind::Combo(values) => values.contains(&value),
+        }
+    }
+}
+
+/// Engine options that the server may override for a batch, with the
+/// values allowed for them. Anything that could touch files, like
+/// networks or tablebases, or change the resources of the engine, like
+/// threads or hash, is reserved for the client.
+pub const ENGINE_OPTIONS: &[EngineOption] = &[
+    EngineOption {
+        name: "UCI_AnalyseMode",
+        kind: EngineOptionKind::Check,
+        default: None,
+    },
+    EngineOption {
+        name: "Contempt",
+        kind: EngineOptionKind::Spin {
+            min: -100,
+            max: 100,
+        },
+        default: Some("24"),
+    },
+    EngineOption {
+        name: "Analysis Contempt",
+        kind: EngineOptionKind::Combo(&["Off", "White", "Black", "Both"]),
+        default: Some("Both"),
+    },
+    EngineOption {
+        name: "Move Overhead",
+        kind: EngineOptionKind::Spin { min: 0, max: 5000 },
+        default: Some("10"),
+    },
+    EngineOption {
+        name: "Slow Mover",
+        kind: EngineOptionKind::Spin { min: 10, max: 1000 },
+        default: Some("100"),
+    },
+    // Fairy-Stockfish only.
+    EngineOption {
+        name: "TsumeMode",
+        kind: EngineOptionKind::Check,
+        default: Some("false"),
+    },
+];
+
+/// An engine option override that is not in [`ENGINE_OPTIONS`], or has a
+/// value that is not allowed.
+#[derive(Debug)]
+pub struct InvalidEngineOption {
+    pub name: String,
+    pub value: String,
+}
+
+impl fmt::Display for InvalidEngineOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
+/// Checks engine option overrides of the server against the allowlist,
+/// before they are sent to the engines.
+pub fn validate_engine_options(options: &BTreeMap<String, String>) -> Result<(), ValidationError> {
+    for (name, value) in options {
+        if !ENGINE_OPTIONS
+            .iter()
+            .any(|option| option.name == name && option.accepts(value))
+        {
+            return Err(ValidationError::EngineOption(InvalidEngineOption {
+                name: name.clone(),
+                value: value.clone(),
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// Parses and validates a game as received from the server. FENs of
+/// standard chess are first scanned for obvious defects, which is much
+/// cheaper than parsing them.
+pub fn validate_game_fen(
+    variant: LichessVariant,
+    fen: &str,
+    moves: Vec<Uci>,
+) -> Result<ValidGame, ValidationError> {
+    if matches!(
+        variant,
+        LichessVariant::Standard | LichessVariant::Chess960 | LichessVariant::FromPosition
+    ) {
+        precheck_chess_fen(fen.as_bytes())?;
+    }
+    validate_game(variant, &Fen::from_ascii(fen.as_bytes())?, moves)
+}
+
+/// Requires 8 ranks of 8 squares, exactly one king of each color, and a
+/// valid side to move, if given. The byte scans compile to vectorized loops.
+fn precheck_chess_fen(fen: &[u8]) -> Result<(), ValidationError> {
+    let mut fields = fen.split(|&b| b == b' ').filter(|field| !field.is_empty());
+    let board = fields.next().ok_or(ValidationError::Malformed("empty"))?;
+
+    let white_kings = board.iter().filter(|&&b| b == b'K').count();
+    let black_kings = board.iter().filter(|&&b| b == b'k').count();
+    if white_kings != 1 || black_kings != 1 {
+        return Err(ValidationError::Malformed(
+            "expected one king of each color",
+        ));
+    }
+
+    let mut ranks = 0;
+    for rank in board.split(|&b| b == b'/') {
+        let squares: u32 = rank
+            .iter()
+            .map(|&b| match b {
+                b'1'..=b'8' => u32::from(b - b'0'),
+                _ => 1,
+            })
+            .sum();
+        if squares != 8 {
+            return Err(ValidationError::Malformed("expected 8 squares per rank"));
+        }
+        ranks += 1;
+    }
+    if ranks != 8 {
+        return Err(ValidationError::Malformed("expected 8 ranks"));
+    }
+
+    match fields.next() {
+        None | Some(b"w" | b"b") => Ok(()),
+        Some(_) => Err(ValidationError::Malformed("expected w or b to move")),
+    }
+}
+
+/// Validates a game as the queue does before distributing its positions to
+/// the engines. Moves may use either castling notation, and are normalized
+/// in place.
+pub fn validate_game(
+    variant: LichessVariant,
+    fen: &Fen,
+    mut moves: Vec<Uci>,
+) -> Result<ValidGame, ValidationError> {
+    let (root, impossible_material) =
+        match VariantPosition::from_setup(variant.into(), fen, CastlingMode::Chess960) {
+            Ok(pos) => (pos, false),
+            Err(err) => (err.ignore_impossible_material()?, true),
+        };
+
+    let mut pos = root.clone();
+    for (ply, uci) in moves.iter_mut().enumerate() {
+        let m = uci.to_move(&pos).map_err(|_| IllegalMove {
+            ply,
+            uci: uci.clone(),
+            fen: Fen::from_setup(&pos).to_string(),
+            legal: pos
+                .legal_moves()
+                .iter()
+                .map(|m| m.to_uci(CastlingMode::Chess960))
+                .collect(),
+        })?;
+        *uci = m.to_uci(CastlingMode::Chess960);
+        pos.play_unchecked(&m);
+    }
+
+    Ok(ValidGame {
+        root,
+        moves,
+        impossible_material,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    fn ucis(moves: &str) -> Vec<Uci> {
+        moves.split(' ').map(|m| m.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_engine_options() {
+        let options = |name: &str, value: &str| {
+            let mut options = BTreeMap::new();
+            options.insert(name.to_owned(), value.to_owned());
+            options
+        };
+        assert!(validate_engine_options(&BTreeMap::new()).is_ok());
+        assert!(validate_engine_options(&options("Contempt", "-100")).is_ok());
+        assert!(validate_engine_options(&options("Contempt", "101")).is_err());
+        assert!(validate_engine_options(&options("Analysis Contempt", "White")).is_ok());
+        assert!(validate_engine_options(&options("Analysis Contempt", "white")).is_err());
+        assert!(validate_engine_options(&options("UCI_AnalyseMode", "1")).is_err());
+        assert!(validate_engine_options(&options("Hash", "1024")).is_err());
+    }
+
+    #[test]
+    fn test_precheck_chess_fen() {
+        assert!(precheck_chess_fen(START.as_bytes()).is_ok());
+        assert!(precheck_chess_fen(b"4k3/8/8/8/8/8/8/4K3").is_ok());
+        assert!(precheck_chess_fen(b"").is_err());
+        assert!(precheck_chess_fen(b"4k3/8/8/8/8/8/8/4KK2 w - - 0 1").is_err());
+        assert!(precheck_chess_fen(b"4k3/8/8/8/8/8/8/4K4 w - - 0 1").is_err());
+        assert!(precheck_chess_fen(b"4k3/8/8/8/8/8/4K3 w - - 0 1").is_err());
+        assert!(precheck_chess_fen(b"4k3/8/8/8/8/8/8/4K3 x - - 0 1").is_err());
+    }
+
+    #[test]
+    fn test_validate_game_fen() {
+        // Castling is normalized to Chess960 notation.
+        let game = validate_game_fen(
+            LichessVariant::Standard,
+            START,
+            ucis("e2e4 e7e5 g1f3 b8c6 f1c4 g8f6 e1g1"),
+        )
+        .unwrap();
+        assert_eq!(game.moves.last().unwrap().to_string(), "e1h1");
+        assert!(!game.impossible_material);
+
+        match validate_game_fen(LichessVariant::Standard, START, ucis("e2e4 e2e4")) {
+            Err(ValidationError::IllegalUci(illegal)) => {
+                assert_eq!(illegal.ply, 1);
+                assert_eq!(illegal.legal.len(), 20);
+            }
+            res => panic!("expected illegal move, got {:?}", res),
+        }
+
+        assert!(matches!(
+            validate_game_fen(
+                LichessVariant::Standard,
+                "8/8/8/8/8/8/8/8 w - - 0 1",
+                Vec::new()
+            ),
+            Err(ValidationError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,168 @@
+use std::sync::Arc;
+
+use rand::Rng as _;
+use shakmaty::{fen::fen, variant::Variant};
+use tokio::task::JoinHandle;
+
+use crate::{
+    accuracy::capped_cp,
+    anomaly::{Anomaly, Webhook},
+    api::{NodeLimit, Score, Work},
+    assets::{Assets, EngineFlavor},
+    configure::VerifyOpt,
+    ipc::{Position, PositionResponse},
+    logger::Logger,
+    metrics::METRICS,
+    pool::EnginePool,
+};
+
+/// Report evaluations that differ by more than this many centipawns, unless
+/// configured otherwise.
+const DEFAULT_THRESHOLD: u32 = 150;
+
+/// Analyses a random sample of positions a second time, with the same
+/// number of nodes on a separate engine process, and reports evaluations
+/// that do not match. Servers can use the reports to judge how much to
+/// trust the analysis of a provider.
+pub struct Verifier {
+    pool: EnginePool,
+    assets: Arc<Assets>,
+    fraction: f64,
+    threshold: i64,
+    flavor: Option<EngineFlavor>,
+    webhook: Option<Webhook>,
+    logger: Logger,
+}
+
+impl Verifier {
+    /// Starts a single engine worker for verification, unless verification
+    /// is disabled. The returned handle completes when the verifier is
+    /// dropped and the engines have shut down.
+    pub fn spawn(
+        opt: &VerifyOpt,
+        assets: Arc<Assets>,
+        webhook: Option<Webhook>,
+        logger: Logger,
+    ) -> Option<(Verifier, JoinHandle<()>)> {
+        let fraction = opt.verify_fraction.filter(|f| *f > 0.0)?.min(1.0);
+        let (pool, join_handle) = EnginePool::spawn(assets.clone(), 1, logger.clone());
+        Some((
+            Verifier {
+                pool,
+                assets,
+                fraction,
+                threshold: i64::from(opt.verify_threshold.unwrap_or(DEFAULT_THRESHOLD)),
+                flavor: opt.verify_flavor,
+                webhook,
+                logger,
+            },
+            join_handle,
+        ))
+    }
+
+    /// Decides if an analysis position should be verified, before it is
+    /// handed to the engine.
+    pub fn sample(&self, position: &Position) -> Option<Position> {
+        (position.work.is_analysis() && rand::thread_rng().gen_bool(self.fraction))
+            .then(|| position.clone())
+    }
+
+    /// Verifies the result of a sampled position in the background.
+    pub fn check(self: &Arc<Self>, position: Position, res: &PositionResponse) {
+        let expected = match res.scores.best() {
+            Some(score) => *score,
+            None => return,
+        };
+        let nodes = res.nodes;
+        let verifier = Arc::clone(self);
+        tokio::spawn(async move {
+            verifier.verify(position, nodes, expected).await;
+        });
+    }
+
+    async fn verify(&self, mut position: Position, nodes: u64, expected: Score) {
+        position.work = match position.work {
+            Work::Analysis {
+                id,
+                timeout,
+                options,
+                ..
+            } => Work::Analysis {
+                id,
+                nodes: NodeLimit::fixed(nodes),
+                depth: None,
+                multipv: None,
+                timeout,
+                movetime: None,
+                wdl: false,
+                annotations: false,
+                tier: None,
+                options,
+            },
+            Work::Move { .. } | Work::Playout { .. } => return,
+        };
+        let original = position.flavor;
+        let flavor = self.flavor_for(&position);
+        position.flavor = flavor;
+        let context = format!(
+            "{} {:?} ({})",
+            fen(&position.root_fen),
+            position.moves,
+            position.work.id()
+        );
+
+        let res = match self.pool.go(position).await {
+            Ok(res) => res,
+            Err(_) => {
+                self.logger
+                    .debug(&format!("Failed to verify position. Context: {}", context));
+                return;
+            }
+        };
+        let actual = match res.scores.best() {
+            Some(score) => *score,
+            None => return,
+        };
+        METRICS.positions_verified.inc();
+
+        let difference = (capped_cp(expected) - capped_cp(actual)).abs();
+        if difference <= self.threshold {
+            self.logger.debug(&format!(
+                "Verified {} with {} at {} nodes. Context: {}",
+                self.logger.score(&expected),
+                self.logger.score(&actual),
+                nodes,
+                context
+            ));
+            return;
+        }
+
+        METRICS.verify_mismatches.inc();
+        let detail = format!(
+            "{} by {}, but {} by {} at {} nodes. Context: {}",
+            self.logger.score(&expected),
+            original.name(),
+            self.logger.score(&actual),
+            flavor.name(),
+            nodes,
+            context
+        );
+        self.logger
+            .warn(&format!("Verification mismatch: {}", detail));
+        if let Some(ref webhook) = self.webhook {
+            webhook.notify(Anomaly::new("verify-mismatch", original, detail));
+        }
+    }
+
+    /// The flavor of the second engine. Official Stockfish can only verify
+    /// standard chess.
+    fn flavor_for(&self, position: &Position) -> EngineFlavor {
+        match self.flavor {
+            Some(EngineFlavor::Official) if Variant::from(position.variant) != Variant::Chess => {
+                position.flavor
+            }
+            Some(flavor) if self.assets.stockfish.get(flavor).is_some() => flavor,
+            _ => position.flavor,
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::{collections::BTreeSet, process};
+
+use shakmaty::{fen::fen, CastlingMode, Position as _};
+use tokio::io::AsyncReadExt as _;
+
+use crate::{
+    analyse::parse_games,
+    assets::Assets,
+    configure::{Opt, VerifyNotationOpt},
+    logger::Logger,
+    stockfish::PerftSession,
+};
+
+/// Replays every game, and compares the legal moves of each position, as
+/// generated by shakmaty in the notation sent to the engines, with the moves
+/// that Fairy-Stockfish accepts. Disagreements are printed one per line.
+pub async fn verify_notation(opt: &Opt, verify_opt: &VerifyNotationOpt, logger: &Logger) {
+    let mut text = String::new();
+    let read = if verify_opt.file.as_os_str() == "-" {
+        tokio::io::stdin().read_to_string(&mut text).await
+    } else {
+        match tokio::fs::File::open(&verify_opt.file).await {
+            Ok(mut file) => file.read_to_string(&mut text).await,
+            Err(err) => Err(err),
+        }
+    };
+    if let Err(err) = read {
+        logger.error(&format!("Failed to read {:?}: {}", verify_opt.file, err));
+        process::exit(1);
+    }
+
+    let assets =
+        Assets::prepare(opt.cpu(), opt.enabled_engines()).expect("prepared bundled stockfish");
+    let exe = match assets.stockfish.multi_variant {
+        Some(ref exe) => exe,
+        None => {
+            logger.error("Verifying notation requires Fairy-Stockfish.");
+            process::exit(1);
+        }
+    };
+    let mut engine = match PerftSession::start(exe).await {
+        Ok(engine) => engine,
+        Err(err) => {
+            logger.error(&format!("Failed to start Fairy-Stockfish: {}", err));
+            process::exit(1);
+        }
+    };
+
+    let mut games = 0;
+    let mut positions = 0;
+    let mut disagreements = 0;
+    for game in parse_games(&text, verify_opt.variant) {
+        let game = match game {
+            Ok(game) => game,
+            Err((id, err)) => {
+                println!("{} invalid: {}", id, err);
+                disagreements += 1;
+                continue;
+            }
+        };
+        games += 1;
+        let root_fen = fen(&game.valid.root);
+        let mut pos = game.valid.root.clone();
+        for ply in 0..=game.valid.moves.len() {
+            let ours: BTreeSet<String> = pos
+                .legal_moves()
+                .iter()
+                .map(|m| m.to_uci(CastlingMode::Chess960).to_string())
+                .collect();
+            let theirs: BTreeSet<String> = match engine
+                .legal_moves(game.variant, &root_fen, &game.valid.moves[..ply])
+                .await
+            {
+                Ok(moves) => moves.into_iter().collect(),
+                Err(err) => {
+                    logger.error(&format!("Fairy-Stockfish failed: {}", err));
+                    process::exit(1);
+                }
+            };
+            positions += 1;
+            if ours != theirs {
+                disagreements += 1;
+                println!(
+                    "{} ply {}: {} only ours: {} only engine: {}",
+                    game.id,
+                    ply,
+                    fen(&pos),
+                    ours.difference(&theirs)
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                    theirs
+                        .difference(&ours)
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                );
+                break;
+            }
+            if let Some(uci) = game.valid.moves.get(ply) {
+                match uci.to_move(&pos) {
+                    Ok(m) => pos.play_unchecked(&m),
+                    Err(_) => break, // Validated before
+                }
+            }
+        }
+    }
+
+    if let Err(err) = engine.quit().await {
+        logger.debug(&format!("Failed to quit Fairy-Stockfish: {}", err));
+    }
+    logger.info(&format!(
+        "Verified {} positions of {} games: {} disagreements",
+        positions, games, disagreements
+    ));
+    if disagreements > 0 {
+        process::exit(1);
+    }
+}